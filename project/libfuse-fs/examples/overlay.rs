@@ -3,7 +3,12 @@
 // Example binary to mount overlay filesystem implemented by libfuse-fs.
 // Used by xfstests for overlayfs validation.
 
-use libfuse_fs::overlayfs::OverlayArgs;
+use std::time::Duration;
+
+use libfuse_fs::overlayfs::{Concurrency, FuseTuning, OverlayArgs};
+use libfuse_fs::sandbox::Sandbox;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Default)]
 struct Args {
@@ -13,11 +18,16 @@ struct Args {
     upperdir: String,
     workdir: String,
     log_level: String,
+    force: bool,
+    passthrough: bool,
+    concurrency: Concurrency,
+    tuning: FuseTuning,
+    sandbox: bool,
 }
 
 fn help() {
     println!(
-        "Usage:\n   overlay -o lowerdir=<lower1>:<lower2>:<more>,upperdir=<upper>,workdir=<work> <name> <mountpoint> [-l log_level]\n"
+        "Usage:\n   overlay -o lowerdir=<lower1>:<lower2>:<more>,upperdir=<upper>,workdir=<work> <name> <mountpoint> [-l log_level] [--force] [--passthrough] [--sandbox] [--workers <n>] [--max-background <n>] [--max-write <bytes>] [--max-readahead <bytes>]\n"
     );
 }
 
@@ -72,6 +82,52 @@ fn parse_args() -> Result<Args, std::io::Error> {
             continue;
         }
 
+        if args[i].as_str() == "--force" {
+            cmd_args.force = true;
+            continue;
+        }
+
+        if args[i].as_str() == "--passthrough" {
+            cmd_args.passthrough = true;
+            continue;
+        }
+
+        if args[i].as_str() == "--sandbox" {
+            cmd_args.sandbox = true;
+            continue;
+        }
+
+        if args[i].as_str() == "--workers" || args[i].as_str() == "--max-background" {
+            let value = args
+                .get(i + 1)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+            if args[i].as_str() == "--workers" {
+                cmd_args.concurrency.workers = value;
+            } else {
+                cmd_args.concurrency.max_background = value;
+            }
+            i += 1;
+            continue;
+        }
+
+        if args[i].as_str() == "--max-write" || args[i].as_str() == "--max-readahead" {
+            let value = args
+                .get(i + 1)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+            if args[i].as_str() == "--max-write" {
+                cmd_args.tuning.max_write = Some(
+                    std::num::NonZeroU32::new(value)
+                        .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?,
+                );
+            } else {
+                cmd_args.tuning.max_readahead = Some(value);
+            }
+            i += 1;
+            continue;
+        }
+
         if args[i].as_str() == "-l" {
             i += 1;
             cmd_args.log_level = args[i].clone();
@@ -106,10 +162,26 @@ fn set_log(args: &Args) {
     tracing_subscriber::fmt().with_env_filter(filter).init();
 }
 
-#[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<(), std::io::Error> {
     let args = parse_args()?;
 
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if args.sandbox {
+        // The threads of the runtime are confined as they start, the main thread by
+        // `mount_fs_with_shutdown` once mounted. The mount point is read to check it's empty.
+        let mut sandbox = Sandbox::new()
+            .read_only(&args.mountpoint)
+            .read_write(&args.upperdir);
+        for lower in &args.lowerdir {
+            sandbox = sandbox.read_only(lower);
+        }
+        runtime.on_thread_start(sandbox.on_thread_start()?);
+    }
+    runtime.build()?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<(), std::io::Error> {
     // set_log(&args);
 
     // This is commented out because some testcase(fsstress) may output huge logs, exhausting disk space.
@@ -124,11 +196,11 @@ async fn main() -> Result<(), std::io::Error> {
     //     libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO);
     //     libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO);
     // }
-    let mut mount_handle = libfuse_fs::overlayfs::mount_fs(OverlayArgs {
+    let (mut mount_handle, shutdown) = libfuse_fs::overlayfs::mount_fs_with_shutdown(OverlayArgs {
         name: Some(args.name),
         mountpoint: args.mountpoint,
         lowerdir: args.lowerdir,
-        upperdir: args.upperdir,
+        upperdir: Some(args.upperdir),
         mapping: None::<&str>,
         privileged: true,
         // SECURITY: allow_other permits all users to access this filesystem.
@@ -136,8 +208,15 @@ async fn main() -> Result<(), std::io::Error> {
         // In production, set to false unless you specifically need multi-user access
         // and have proper permission checks in place.
         allow_other: true,
+        force: args.force,
+        layer_limit: Default::default(),
+        passthrough: args.passthrough,
+        concurrency: args.concurrency,
+        tuning: args.tuning,
+        sandbox: args.sandbox,
     })
-    .await;
+    .await
+    .map_err(std::io::Error::other)?;
     println!("Mounted");
 
     let handle = &mut mount_handle;
@@ -148,11 +227,18 @@ async fn main() -> Result<(), std::io::Error> {
     let mut sighup = signal(SignalKind::hangup())?;
 
     tokio::select! {
-        res = handle => res?,
-        _ = sigint.recv() => mount_handle.unmount().await?,
-        _ = sigterm.recv() => mount_handle.unmount().await?,
-        _ = sighup.recv() => mount_handle.unmount().await?,
+        res = handle => return res,
+        _ = sigint.recv() => {},
+        _ = sigterm.recv() => {},
+        _ = sighup.recv() => {},
     }
 
+    // Let running requests and copy-ups finish before the kernel connection goes away.
+    let report = shutdown.shutdown(SHUTDOWN_TIMEOUT).await;
+    mount_handle.unmount().await?;
+    if !report.is_clean() {
+        eprintln!("Unclean shutdown: {report}");
+        std::process::exit(1);
+    }
     Ok(())
 }
@@ -0,0 +1,538 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Example binary comparing libfuse-fs overlayfs against kernel overlayfs.
+//
+// The same generated layer stack is mounted with both, the same workloads run in each mount
+// and the resulting upper directories are compared. Whiteouts and opaque directories are
+// compared by meaning, not by how they are stored, as the two write them differently. The
+// kernel side needs root and is skipped otherwise. Exits with 1 when the upper directories
+// differ, so it can gate CI-like runs.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::{Error, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use libfuse_fs::overlayfs::OverlayArgs;
+use serde::Serialize;
+
+const OPAQUE_XATTRS: [&str; 3] = [
+    "trusted.overlay.opaque",
+    "user.overlay.opaque",
+    "user.fuseoverlayfs.opaque",
+];
+const WHITEOUT_XATTR: &str = "user.overlay.whiteout";
+
+struct Args {
+    scratch: PathBuf,
+    files: usize,
+    rounds: usize,
+    json: Option<PathBuf>,
+}
+
+fn help() {
+    println!(
+        "Usage:\n   overlay_compare <scratch_dir> [--files N] [--rounds N] [--json <report>]\n"
+    );
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = Args {
+        scratch: PathBuf::new(),
+        files: 200,
+        rounds: 3,
+        json: None,
+    };
+    let invalid = || {
+        help();
+        Error::from_raw_os_error(libc::EINVAL)
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--files" => {
+                parsed.files = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(invalid)?
+            }
+            "--rounds" => {
+                parsed.rounds = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(invalid)?
+            }
+            "--json" => parsed.json = Some(args.next().ok_or_else(invalid)?.into()),
+            "-h" | "--help" => return Err(invalid()),
+            _ if parsed.scratch.as_os_str().is_empty() => parsed.scratch = arg.into(),
+            _ => return Err(invalid()),
+        }
+    }
+    if parsed.scratch.as_os_str().is_empty() || parsed.rounds == 0 {
+        return Err(invalid());
+    }
+    Ok(parsed)
+}
+
+// Two lower layers: a bottom one with directories of files, and a top one shadowing some of
+// them and adding symlinks.
+fn build_layers(root: &Path, files: usize) -> Result<Vec<PathBuf>> {
+    let (top, bottom) = (root.join("lower-top"), root.join("lower-bottom"));
+    for (layer, dirs) in [
+        (&bottom, ["data", "logs", "tree"]),
+        (&top, ["data", "conf", "tree"]),
+    ] {
+        for dir in dirs {
+            std::fs::create_dir_all(layer.join(dir))?;
+            for i in 0..files / 4 {
+                let content = format!("{dir} {i} from {}\n", layer.display()).repeat(i % 32 + 1);
+                std::fs::write(layer.join(dir).join(format!("f{i}")), content)?;
+            }
+        }
+    }
+    std::os::unix::fs::symlink("../data/f0", top.join("conf/link"))?;
+    Ok(vec![top, bottom])
+}
+
+type Workload = fn(&Path, usize) -> Result<()>;
+
+// Run in this order, each relying on what the previous ones left behind.
+const WORKLOADS: [(&str, Workload); 9] = [
+    ("stat_tree", stat_tree),
+    ("read_lower", read_lower),
+    ("create", create),
+    ("copy_up", copy_up),
+    ("rename", rename),
+    ("unlink_lower", unlink_lower),
+    ("replace_dir", replace_dir),
+    ("metadata", metadata),
+    ("readdir", readdir),
+];
+
+fn walk(dir: &Path, f: &mut impl FnMut(&Path, &std::fs::Metadata)) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = std::fs::symlink_metadata(&path)?;
+        f(&path, &meta);
+        if meta.is_dir() {
+            walk(&path, f)?;
+        }
+    }
+    Ok(())
+}
+
+fn stat_tree(mnt: &Path, _files: usize) -> Result<()> {
+    walk(mnt, &mut |_, _| {})
+}
+
+fn read_lower(mnt: &Path, files: usize) -> Result<()> {
+    for dir in ["data", "logs", "conf"] {
+        for i in 0..files / 4 {
+            std::fs::read(mnt.join(dir).join(format!("f{i}")))?;
+        }
+    }
+    Ok(())
+}
+
+fn create(mnt: &Path, files: usize) -> Result<()> {
+    std::fs::create_dir_all(mnt.join("new/nested"))?;
+    for i in 0..files {
+        std::fs::write(
+            mnt.join("new/nested").join(format!("n{i}")),
+            vec![i as u8; i * 16],
+        )?;
+    }
+    Ok(())
+}
+
+fn copy_up(mnt: &Path, files: usize) -> Result<()> {
+    use std::io::Write;
+    for i in (0..files / 4).step_by(2) {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(mnt.join("data").join(format!("f{i}")))?;
+        file.write_all(b"appended\n")?;
+    }
+    Ok(())
+}
+
+fn rename(mnt: &Path, files: usize) -> Result<()> {
+    for i in (0..files).step_by(3) {
+        let dir = mnt.join("new/nested");
+        std::fs::rename(dir.join(format!("n{i}")), dir.join(format!("r{i}")))?;
+    }
+    // A lower file is copied up under its new name and whited out under the old one.
+    std::fs::rename(mnt.join("logs/f1"), mnt.join("logs/moved"))
+}
+
+fn unlink_lower(mnt: &Path, files: usize) -> Result<()> {
+    for i in (1..files / 4).step_by(3) {
+        std::fs::remove_file(mnt.join("conf").join(format!("f{i}")))?;
+    }
+    std::fs::remove_file(mnt.join("conf/link"))
+}
+
+fn replace_dir(mnt: &Path, _files: usize) -> Result<()> {
+    std::fs::remove_dir_all(mnt.join("tree"))?;
+    std::fs::create_dir(mnt.join("tree"))?;
+    std::fs::write(mnt.join("tree/fresh"), b"fresh\n")
+}
+
+fn metadata(mnt: &Path, files: usize) -> Result<()> {
+    for i in (0..files / 4).step_by(5) {
+        let path = mnt.join("logs").join(format!("f{i}"));
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::os::unix::fs::symlink("nested/r0", mnt.join("new/link"))
+}
+
+fn readdir(mnt: &Path, _files: usize) -> Result<()> {
+    for _ in 0..4 {
+        walk(mnt, &mut |_, _| {})?;
+    }
+    Ok(())
+}
+
+/// What an upper directory entry means to the overlay, however it was written.
+#[derive(Debug, PartialEq, Eq)]
+enum UpperEntry {
+    Dir { mode: u32, opaque: bool },
+    File { mode: u32, size: u64, hash: String },
+    Symlink(PathBuf),
+    Whiteout,
+    Other(u32),
+}
+
+fn xattr_is(path: &Path, name: &str, value: &[u8]) -> bool {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let mut buf = [0u8; 16];
+    let n = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), 16) };
+    n >= 0 && &buf[..n as usize] == value
+}
+
+fn snapshot_upper(upper: &Path) -> Result<BTreeMap<PathBuf, UpperEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut err = None;
+    walk(upper, &mut |path, meta| {
+        let rel = path.strip_prefix(upper).unwrap().to_path_buf();
+        let mode = meta.mode() & 0o7777;
+        let ft = meta.file_type();
+        let whiteout = if ft.is_file() {
+            meta.len() == 0 && xattr_is(path, WHITEOUT_XATTR, b"y")
+        } else {
+            ft.is_char_device() && meta.rdev() == 0
+        };
+        let entry = if whiteout {
+            UpperEntry::Whiteout
+        } else if ft.is_dir() {
+            let opaque = OPAQUE_XATTRS.iter().any(|x| xattr_is(path, x, b"y"));
+            UpperEntry::Dir { mode, opaque }
+        } else if ft.is_file() {
+            match std::fs::read(path) {
+                Ok(data) => UpperEntry::File {
+                    mode,
+                    size: meta.len(),
+                    hash: blake3::hash(&data).to_hex().to_string(),
+                },
+                Err(e) => {
+                    err.get_or_insert(e);
+                    return;
+                }
+            }
+        } else if ft.is_symlink() {
+            match std::fs::read_link(path) {
+                Ok(target) => UpperEntry::Symlink(target),
+                Err(e) => {
+                    err.get_or_insert(e);
+                    return;
+                }
+            }
+        } else {
+            UpperEntry::Other(meta.mode())
+        };
+        entries.insert(rel, entry);
+    })?;
+    err.map_or(Ok(entries), Err)
+}
+
+fn diff_uppers(
+    kernel: &BTreeMap<PathBuf, UpperEntry>,
+    fuse: &BTreeMap<PathBuf, UpperEntry>,
+) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for (path, k) in kernel {
+        match fuse.get(path) {
+            None => diffs.push(format!("{}: only in kernel upper ({k:?})", path.display())),
+            Some(f) if f != k => {
+                diffs.push(format!("{}: kernel {k:?}, fuse {f:?}", path.display()))
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, f) in fuse {
+        if !kernel.contains_key(path) {
+            diffs.push(format!("{}: only in fuse upper ({f:?})", path.display()));
+        }
+    }
+    diffs
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+    Kernel,
+    Fuse,
+}
+
+fn mount_kernel(lowers: &[PathBuf], upper: &Path, work: &Path, mnt: &Path) -> Result<()> {
+    let lowerdir = lowers
+        .iter()
+        .map(|l| l.display().to_string())
+        .collect::<Vec<_>>()
+        .join(":");
+    let data = format!(
+        "lowerdir={lowerdir},upperdir={},workdir={}",
+        upper.display(),
+        work.display()
+    );
+    let (source, target) = (
+        CString::new("overlay")?,
+        CString::new(mnt.as_os_str().as_bytes())?,
+    );
+    let data = CString::new(data)?;
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            source.as_ptr(),
+            0,
+            data.as_ptr().cast(),
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn umount_kernel(mnt: &Path) -> Result<()> {
+    let target = CString::new(mnt.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(target.as_ptr(), 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Time a workload took, or why it failed.
+type Timing = std::result::Result<Duration, String>;
+
+// Mount `backend` on a fresh upper directory, run every workload and unmount again.
+// Returns the timing of each workload and the upper directory.
+async fn run_round(
+    backend: Backend,
+    dir: &Path,
+    lowers: &[PathBuf],
+    files: usize,
+) -> Result<(Vec<Timing>, PathBuf)> {
+    let (upper, work, mnt) = (dir.join("upper"), dir.join("work"), dir.join("mnt"));
+    let _ = std::fs::remove_dir_all(dir);
+    for d in [&upper, &work, &mnt] {
+        std::fs::create_dir_all(d)?;
+    }
+
+    let fuse = match backend {
+        Backend::Kernel => {
+            mount_kernel(lowers, &upper, &work, &mnt)?;
+            None
+        }
+        Backend::Fuse => Some(
+            libfuse_fs::overlayfs::mount_fs(OverlayArgs {
+                name: None::<String>,
+                mountpoint: &mnt,
+                lowerdir: lowers,
+                upperdir: Some(&upper),
+                mapping: None::<&str>,
+                privileged: unsafe { libc::geteuid() } == 0,
+                allow_other: false,
+                force: false,
+                layer_limit: Default::default(),
+                passthrough: false,
+                concurrency: Default::default(),
+                tuning: Default::default(),
+                sandbox: false,
+            })
+            .await
+            .map_err(Error::other)?,
+        ),
+    };
+
+    let run_mnt = mnt.clone();
+    // A failed workload is reported, the ones after it still run on what it left behind.
+    let times = tokio::task::spawn_blocking(move || {
+        WORKLOADS
+            .iter()
+            .map(|(_, workload)| {
+                let start = Instant::now();
+                workload(&run_mnt, files).map_err(|e| e.to_string())?;
+                Ok(start.elapsed())
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(Error::other);
+
+    match fuse {
+        Some(handle) => handle.unmount().await?,
+        None => umount_kernel(&mnt)?,
+    }
+    Ok((times?, upper))
+}
+
+#[derive(Serialize)]
+struct WorkloadReport {
+    name: &'static str,
+    fuse_ms: Option<f64>,
+    kernel_ms: Option<f64>,
+    /// FUSE time over kernel time, above 1 when FUSE is slower.
+    ratio: Option<f64>,
+    fuse_error: Option<String>,
+    kernel_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    files: usize,
+    rounds: usize,
+    /// Why the kernel baseline is missing, if it is.
+    kernel_skipped: Option<String>,
+    workloads: Vec<WorkloadReport>,
+    upper_diffs: Vec<String>,
+}
+
+async fn best_of(
+    backend: Backend,
+    args: &Args,
+    lowers: &[PathBuf],
+) -> Result<(Vec<Timing>, BTreeMap<PathBuf, UpperEntry>)> {
+    let name = match backend {
+        Backend::Kernel => "kernel",
+        Backend::Fuse => "fuse",
+    };
+    let dir = args.scratch.join(name);
+    let mut best: Vec<Timing> = Vec::new();
+    let mut upper = BTreeMap::new();
+    for round in 0..args.rounds {
+        let (times, upper_dir) = run_round(backend, &dir, lowers, args.files).await?;
+        if round == 0 {
+            upper = snapshot_upper(&upper_dir)?;
+            best = times;
+        } else {
+            for (best, time) in best.iter_mut().zip(times) {
+                if let (Ok(best), Ok(time)) = (best, time) {
+                    *best = (*best).min(time);
+                }
+            }
+        }
+    }
+    Ok((best, upper))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    std::fs::create_dir_all(&args.scratch)?;
+    let layers = args.scratch.join("layers");
+    let _ = std::fs::remove_dir_all(&layers);
+    let lowers = build_layers(&layers, args.files)?;
+
+    let (fuse_times, fuse_upper) = best_of(Backend::Fuse, &args, &lowers).await?;
+    let kernel = if unsafe { libc::geteuid() } != 0 {
+        Err("not running as root".to_string())
+    } else {
+        best_of(Backend::Kernel, &args, &lowers)
+            .await
+            .map_err(|e| format!("kernel overlayfs failed: {e}"))
+    };
+
+    let ms = |t: &Timing| t.as_ref().ok().map(|d| d.as_secs_f64() * 1000.0);
+    let error = |t: &Timing| t.as_ref().err().cloned();
+    let (kernel_times, upper_diffs) = match &kernel {
+        Ok((times, upper)) => (Some(times), diff_uppers(upper, &fuse_upper)),
+        Err(_) => (None, Vec::new()),
+    };
+    let workloads: Vec<_> = WORKLOADS
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            let fuse_ms = ms(&fuse_times[i]);
+            let kernel_ms = kernel_times.and_then(|t| ms(&t[i]));
+            WorkloadReport {
+                name,
+                fuse_ms,
+                kernel_ms,
+                ratio: fuse_ms.zip(kernel_ms).map(|(f, k)| f / k.max(f64::EPSILON)),
+                fuse_error: error(&fuse_times[i]),
+                kernel_error: kernel_times.and_then(|t| error(&t[i])),
+            }
+        })
+        .collect();
+    // Failing where the kernel succeeds is a regression, failing like the kernel is not.
+    let regressed = workloads
+        .iter()
+        .any(|w| w.fuse_error.is_some() && w.kernel_error.is_none());
+    let report = Report {
+        files: args.files,
+        rounds: args.rounds,
+        kernel_skipped: kernel.err(),
+        workloads,
+        upper_diffs,
+    };
+
+    println!(
+        "{:<14} {:>12} {:>12} {:>8}",
+        "workload", "fuse ms", "kernel ms", "ratio"
+    );
+    let show = |ms: Option<f64>| ms.map_or("failed".to_string(), |ms| format!("{ms:.2}"));
+    for w in &report.workloads {
+        let kernel = match report.kernel_skipped {
+            Some(_) => "-".to_string(),
+            None => show(w.kernel_ms),
+        };
+        let ratio = w.ratio.map_or("-".to_string(), |r| format!("{r:.2}x"));
+        println!(
+            "{:<14} {:>12} {:>12} {:>8}",
+            w.name,
+            show(w.fuse_ms),
+            kernel,
+            ratio
+        );
+    }
+    for w in &report.workloads {
+        if let Some(e) = &w.fuse_error {
+            println!("{} failed on fuse: {e}", w.name);
+        }
+        if let Some(e) = &w.kernel_error {
+            println!("{} failed on kernel: {e}", w.name);
+        }
+    }
+    if let Some(reason) = &report.kernel_skipped {
+        println!("Kernel baseline skipped: {reason}");
+    } else if report.upper_diffs.is_empty() {
+        println!("Upper directories match");
+    } else {
+        println!("Upper directories differ:");
+        for diff in &report.upper_diffs {
+            println!("  {diff}");
+        }
+    }
+    if let Some(path) = &args.json {
+        std::fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+    }
+    if regressed || !report.upper_diffs.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
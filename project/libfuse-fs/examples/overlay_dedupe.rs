@@ -0,0 +1,53 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Example binary reporting upper layer content already in the lower layers, see
+// `overlayfs::dedupe`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libfuse_fs::overlayfs::dedupe::{ChunkParams, DedupeJob};
+
+fn help() {
+    println!("Usage:\n   overlay_dedupe upperdir=<upper> lowerdir=<lower1>:<lower2>:<more>\n");
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let mut upperdir = None;
+    let mut lowerdir = None;
+    for arg in std::env::args().skip(1) {
+        if let Some(upper) = arg.strip_prefix("upperdir=") {
+            upperdir = Some(PathBuf::from(upper));
+        } else if let Some(lower) = arg.strip_prefix("lowerdir=") {
+            lowerdir = Some(lower.split(":").map(PathBuf::from).collect::<Vec<_>>());
+        } else {
+            help();
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+    }
+    let (Some(upperdir), Some(lowerdir)) = (upperdir, lowerdir) else {
+        help();
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    };
+
+    let job = DedupeJob::spawn(upperdir, lowerdir, ChunkParams::default())?;
+    while !job.is_finished() {
+        std::thread::sleep(Duration::from_secs(1));
+        eprintln!("{} MiB scanned", job.bytes_scanned() >> 20);
+    }
+    let report = job.join()?;
+    for file in &report.duplicate_files {
+        println!(
+            "{:>12} of {:>12} bytes{} {}",
+            file.duplicate_bytes,
+            file.size,
+            if file.is_redundant() { " (all)" } else { "" },
+            file.path.display()
+        );
+    }
+    println!(
+        "{} of {} bytes in {} upper files are in the lower layers, {} more repeat within the upper layer",
+        report.duplicate_bytes, report.bytes, report.files, report.upper_duplicate_bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,46 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Example binary to check the layers of an unmounted overlay, see `overlayfs::check`.
+
+fn help() {
+    println!(
+        "Usage:\n   overlay_fsck [--repair] [upperdir=<upper>] lowerdir=<lower1>:<lower2>:<more>\n"
+    );
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let mut repair = false;
+    let mut upperdir = None;
+    let mut lowerdir = None;
+    for arg in std::env::args().skip(1) {
+        if arg == "--repair" {
+            repair = true;
+        } else if let Some(upper) = arg.strip_prefix("upperdir=") {
+            upperdir = Some(upper.to_string());
+        } else if let Some(lower) = arg.strip_prefix("lowerdir=") {
+            lowerdir = Some(lower.to_string());
+        } else {
+            help();
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+    }
+    let Some(lowerdir) = lowerdir else {
+        help();
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    };
+
+    let layers = upperdir
+        .iter()
+        .map(String::as_str)
+        .chain(lowerdir.split(":"))
+        .collect::<Vec<_>>();
+    let problems = libfuse_fs::overlayfs::check::scan(&layers, repair)?;
+    for problem in &problems {
+        println!("{} ({})", problem, layers[problem.layer]);
+    }
+    println!("{} problems in {} layers", problems.len(), layers.len());
+    if !repair && !problems.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Example binary to pre-build the lower index consumed by `Config::lower_index`.
+
+use std::path::Path;
+
+fn help() {
+    println!("Usage:\n   overlay_index lowerdir=<lower1>:<lower2>:<more> <output>\n");
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let lowerdir = args.get(1).and_then(|arg| arg.strip_prefix("lowerdir="));
+    let (Some(lowerdir), Some(output), 3) = (lowerdir, args.get(2), args.len()) else {
+        help();
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    };
+
+    let lowers = lowerdir.split(":").collect::<Vec<_>>();
+    libfuse_fs::overlayfs::lower_index::build(&lowers, Path::new(output))?;
+    println!("Indexed {} lower layers into {}", lowers.len(), output);
+    Ok(())
+}
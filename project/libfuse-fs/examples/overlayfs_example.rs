@@ -15,9 +15,9 @@ struct Args {
     /// Mount point path
     #[arg(long)]
     mountpoint: String,
-    /// Upper writable layer directory
+    /// Upper writable layer directory, omit for a read-only mount
     #[arg(long)]
-    upperdir: String,
+    upperdir: Option<String>,
     /// Lower read-only layer directories (repeatable)
     #[arg(long)]
     lowerdir: Vec<String>,
@@ -29,6 +29,9 @@ struct Args {
     mapping: Option<String>,
     #[arg(long)]
     allow_other: bool,
+    /// Mount even if another overlay holds the lock of the upper directory
+    #[arg(long)]
+    force: bool,
     /// Bind mounts in format "source:target" (repeatable)
     #[arg(long = "bind")]
     bind_mounts: Vec<String>,
@@ -74,8 +77,18 @@ async fn main() {
         mapping: args.mapping,
         privileged: args.privileged,
         allow_other: args.allow_other,
+        force: args.force,
+        layer_limit: Default::default(),
+        passthrough: false,
+        concurrency: Default::default(),
+        tuning: Default::default(),
+        sandbox: false,
     })
-    .await;
+    .await
+    .unwrap_or_else(|e| {
+        error!("Failed to mount overlay filesystem: {}", e);
+        std::process::exit(1);
+    });
 
     // Mount bind mounts after the overlay filesystem is mounted
     if !bind_specs.is_empty() {
@@ -3,7 +3,7 @@
 // Simple passthrough filesystem example for integration tests.
 
 use clap::Parser;
-use libfuse_fs::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+use libfuse_fs::passthrough::{IoEngine, PassthroughArgs, new_passthroughfs_layer};
 use libfuse_fs::util::bind_mount::{BindMount, BindMountManager};
 use rfuse3::raw::logfs::LoggingFileSystem;
 use rfuse3::{MountOptions, raw::Session};
@@ -35,6 +35,9 @@ struct Args {
     /// Bind mounts in format "source:target" (repeatable)
     #[arg(long = "bind")]
     bind_mounts: Vec<String>,
+    /// I/O engine for opened files: "syscall" or "io_uring"
+    #[arg(long, default_value = "syscall")]
+    io_engine: IoEngine,
 }
 
 fn set_log() {
@@ -72,6 +75,7 @@ async fn main() {
     let fs = new_passthroughfs_layer(PassthroughArgs {
         root_dir: args.rootdir,
         mapping: args.options,
+        io_engine: args.io_engine,
     })
     .await
     .expect("Failed to init passthrough fs");
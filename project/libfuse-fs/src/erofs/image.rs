@@ -0,0 +1,468 @@
+//! Reader for the on-disk EROFS format.
+//!
+//! See <https://erofs.docs.kernel.org/en/latest/core_ondisk.html> for a description of the
+//! layout. Files are read when stored uncompressed, flat or chunk-based, the data of
+//! compressed ones can't be read.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use rfuse3::FileType;
+
+const EROFS_MAGIC: u32 = 0xe0f5_e1e2;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 128;
+const COMPACT_INODE_SIZE: u64 = 32;
+const EXTENDED_INODE_SIZE: u64 = 64;
+// Inodes are addressed by their number of 32 byte slots from the metadata start.
+const INODE_SLOT_BITS: u32 = 5;
+const DIRENT_SIZE: usize = 12;
+const XATTR_IBODY_HEADER_SIZE: u64 = 12;
+const NULL_ADDR: u32 = 0xffff_ffff;
+
+const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x1;
+const FEATURE_INCOMPAT_COMPR_CFGS: u32 = 0x2;
+const FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x4;
+const FEATURE_INCOMPAT_ZTAILPACKING: u32 = 0x10;
+const FEATURE_INCOMPAT_FRAGMENTS: u32 = 0x20;
+// The rest only change how compressed files are stored, whose data isn't read anyway.
+const SUPPORTED_INCOMPAT: u32 = FEATURE_INCOMPAT_ZERO_PADDING
+    | FEATURE_INCOMPAT_COMPR_CFGS
+    | FEATURE_INCOMPAT_CHUNKED_FILE
+    | FEATURE_INCOMPAT_ZTAILPACKING
+    | FEATURE_INCOMPAT_FRAGMENTS;
+
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_COMPRESSED_FULL: u16 = 1;
+const LAYOUT_FLAT_INLINE: u16 = 2;
+const LAYOUT_COMPRESSED_COMPACT: u16 = 3;
+const LAYOUT_CHUNK_BASED: u16 = 4;
+
+// Chunk format: log2 of the chunk size in blocks, and 8 byte indexes instead of block
+// addresses.
+const CHUNK_FORMAT_BLKBITS_MASK: u16 = 0x1f;
+const CHUNK_FORMAT_INDEXES: u16 = 0x20;
+
+/// Where the data of an inode is stored.
+#[derive(Clone, Debug)]
+pub(super) enum Layout {
+    /// Contiguous blocks from `blkaddr`.
+    Plain {
+        blkaddr: u32,
+    },
+    /// Contiguous blocks from `blkaddr`, but the last one stored right after the inode, at
+    /// `tail`.
+    Inline {
+        blkaddr: u32,
+        tail: u64,
+    },
+    /// Chunks of `1 << chunk_bits` bytes, located by the table at `table`.
+    Chunked {
+        chunk_bits: u32,
+        indexes: bool,
+        table: u64,
+    },
+    Compressed,
+}
+
+/// An inode read from the metadata area.
+#[derive(Clone, Debug)]
+pub(super) struct InodeInfo {
+    pub kind: FileType,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub nlink: u32,
+    pub size: u64,
+    pub rdev: u32,
+    // Position and size of the inline xattr area.
+    xattrs: (u64, u64),
+    layout: Layout,
+}
+
+/// Full name and value of an xattr.
+pub(super) type Xattr = (Vec<u8>, Vec<u8>);
+
+/// One entry of a directory listing.
+#[derive(Clone, Debug)]
+pub(super) struct DirEntry {
+    pub name: OsString,
+    pub nid: u64,
+    pub kind: FileType,
+}
+
+struct Superblock {
+    blkszbits: u8,
+    root_nid: u64,
+    inos: u64,
+    build_time: u64,
+    blocks: u32,
+    meta_blkaddr: u32,
+    xattr_blkaddr: u32,
+    dirblkbits: u8,
+}
+
+fn le_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn corrupted(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted erofs image: {what}"),
+    )
+}
+
+impl Superblock {
+    fn parse(buf: &[u8; SUPERBLOCK_SIZE]) -> io::Result<Self> {
+        if le_u32(buf, 0) != EROFS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an erofs image",
+            ));
+        }
+        let blkszbits = buf[12];
+        if !(9..=16).contains(&blkszbits) {
+            return Err(corrupted("block size"));
+        }
+        let incompat = le_u32(buf, 80);
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "unsupported erofs features {:#x}",
+                    incompat & !SUPPORTED_INCOMPAT
+                ),
+            ));
+        }
+        Ok(Superblock {
+            blkszbits,
+            root_nid: le_u16(buf, 14) as u64,
+            inos: le_u64(buf, 16),
+            build_time: le_u64(buf, 24),
+            blocks: le_u32(buf, 36),
+            meta_blkaddr: le_u32(buf, 40),
+            xattr_blkaddr: le_u32(buf, 44),
+            dirblkbits: buf[90],
+        })
+    }
+}
+
+/// An opened EROFS image.
+pub(super) struct Image {
+    file: File,
+    sb: Superblock,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut buf = [0u8; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut buf, SUPERBLOCK_OFFSET)?;
+        let sb = Superblock::parse(&buf)?;
+        Ok(Image { file, sb })
+    }
+
+    pub fn block_size(&self) -> u32 {
+        1 << self.sb.blkszbits
+    }
+
+    pub fn root_nid(&self) -> u64 {
+        self.sb.root_nid
+    }
+
+    pub fn inode_count(&self) -> u64 {
+        self.sb.inos
+    }
+
+    pub fn bytes_used(&self) -> u64 {
+        (self.sb.blocks as u64) << self.sb.blkszbits
+    }
+
+    fn block_pos(&self, blkaddr: u32) -> u64 {
+        (blkaddr as u64) << self.sb.blkszbits
+    }
+
+    fn read_at(&self, len: usize, pos: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, pos)?;
+        Ok(buf)
+    }
+
+    /// Read the inode `nid`.
+    pub fn inode(&self, nid: u64) -> io::Result<InodeInfo> {
+        let pos = self.block_pos(self.sb.meta_blkaddr) + (nid << INODE_SLOT_BITS);
+        let mut buf = [0u8; EXTENDED_INODE_SIZE as usize];
+        self.file
+            .read_exact_at(&mut buf[..COMPACT_INODE_SIZE as usize], pos)?;
+        let format = le_u16(&buf, 0);
+        let extended = format & 1 != 0;
+        if extended {
+            self.file.read_exact_at(
+                &mut buf[COMPACT_INODE_SIZE as usize..],
+                pos + COMPACT_INODE_SIZE,
+            )?;
+        }
+        let xattr_count = le_u16(&buf, 2) as u64;
+        let mode = le_u16(&buf, 4) as u32;
+        let raw = le_u32(&buf, 16);
+        let (size, uid, gid, mtime, nlink, inode_size) = if extended {
+            (
+                le_u64(&buf, 8),
+                le_u32(&buf, 24),
+                le_u32(&buf, 28),
+                le_u64(&buf, 32),
+                le_u32(&buf, 44),
+                EXTENDED_INODE_SIZE,
+            )
+        } else {
+            // Compact inodes have their mtime relative to the build time, 0 before kernel
+            // 6.12.
+            (
+                le_u32(&buf, 8) as u64,
+                le_u16(&buf, 24) as u32,
+                le_u16(&buf, 26) as u32,
+                self.sb.build_time + le_u32(&buf, 12) as u64,
+                le_u16(&buf, 6) as u32,
+                COMPACT_INODE_SIZE,
+            )
+        };
+        let xattr_size = match xattr_count {
+            0 => 0,
+            n => XATTR_IBODY_HEADER_SIZE + (n - 1) * 4,
+        };
+        // Inline data and chunk tables follow the inode and its xattrs.
+        let after = pos + inode_size + xattr_size;
+
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFDIR => FileType::Directory,
+            libc::S_IFREG => FileType::RegularFile,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFIFO => FileType::NamedPipe,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => return Err(corrupted("inode mode")),
+        };
+        let (rdev, layout) = match kind {
+            FileType::CharDevice | FileType::BlockDevice => {
+                // Encoded like the kernel's new_encode_dev().
+                let (major, minor) = ((raw & 0xfff00) >> 8, (raw & 0xff) | ((raw >> 12) & 0xfff00));
+                (libc::makedev(major, minor) as u32, Layout::Compressed)
+            }
+            FileType::NamedPipe | FileType::Socket => (0, Layout::Compressed),
+            _ => (0, self.layout((format >> 1) & 0x7, raw, after)?),
+        };
+        Ok(InodeInfo {
+            kind,
+            perm: (mode & 0o7777) as u16,
+            uid,
+            gid,
+            mtime,
+            nlink,
+            size,
+            rdev,
+            xattrs: (pos + inode_size, xattr_size),
+            layout,
+        })
+    }
+
+    fn layout(&self, layout: u16, raw: u32, after: u64) -> io::Result<Layout> {
+        Ok(match layout {
+            LAYOUT_FLAT_PLAIN => Layout::Plain { blkaddr: raw },
+            LAYOUT_FLAT_INLINE => Layout::Inline {
+                blkaddr: raw,
+                tail: after,
+            },
+            LAYOUT_CHUNK_BASED => {
+                let format = raw as u16;
+                let indexes = format & CHUNK_FORMAT_INDEXES != 0;
+                let unit = if indexes { 8 } else { 4 };
+                Layout::Chunked {
+                    chunk_bits: self.sb.blkszbits as u32
+                        + (format & CHUNK_FORMAT_BLKBITS_MASK) as u32,
+                    indexes,
+                    table: after.next_multiple_of(unit),
+                }
+            }
+            LAYOUT_COMPRESSED_FULL | LAYOUT_COMPRESSED_COMPACT => Layout::Compressed,
+            _ => return Err(corrupted("data layout")),
+        })
+    }
+
+    /// Read up to `len` bytes of the data of `inode` starting at `offset`.
+    pub fn read(&self, inode: &InodeInfo, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if offset >= inode.size {
+            return Ok(Vec::new());
+        }
+        let end = inode.size.min(offset + len as u64);
+        match inode.layout {
+            Layout::Plain { blkaddr } => {
+                self.read_at((end - offset) as usize, self.block_pos(blkaddr) + offset)
+            }
+            Layout::Inline { blkaddr, tail } => {
+                // Every block but the last is stored out of line.
+                let block_size = self.block_size() as u64;
+                let tail_start = (inode.size.div_ceil(block_size) - 1) * block_size;
+                let mut out = Vec::with_capacity((end - offset) as usize);
+                if offset < tail_start {
+                    let len = (end.min(tail_start) - offset) as usize;
+                    out = self.read_at(len, self.block_pos(blkaddr) + offset)?;
+                }
+                if end > tail_start {
+                    let from = offset.max(tail_start);
+                    let len = (end - from) as usize;
+                    out.extend_from_slice(&self.read_at(len, tail + from - tail_start)?);
+                }
+                Ok(out)
+            }
+            Layout::Chunked {
+                chunk_bits,
+                indexes,
+                table,
+            } => {
+                let mut out = Vec::with_capacity((end - offset) as usize);
+                while offset + (out.len() as u64) < end {
+                    let pos = offset + out.len() as u64;
+                    let chunk = pos >> chunk_bits;
+                    let within = pos & ((1 << chunk_bits) - 1);
+                    let len = ((1 << chunk_bits) - within).min(end - pos) as usize;
+                    let blkaddr = if indexes {
+                        le_u32(&self.read_at(8, table + chunk * 8)?, 4)
+                    } else {
+                        le_u32(&self.read_at(4, table + chunk * 4)?, 0)
+                    };
+                    if blkaddr == NULL_ADDR {
+                        // Hole.
+                        out.resize(out.len() + len, 0);
+                    } else {
+                        out.extend_from_slice(
+                            &self.read_at(len, self.block_pos(blkaddr) + within)?,
+                        );
+                    }
+                }
+                Ok(out)
+            }
+            Layout::Compressed => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "reading compressed erofs files is not supported",
+            )),
+        }
+    }
+
+    /// List the directory `dir`, without `.` and `..`.
+    pub fn read_dir(&self, dir: &InodeInfo) -> io::Result<Vec<DirEntry>> {
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let data = self.read(dir, 0, dir.size as usize)?;
+        let block_size = (self.block_size() as usize) << self.sb.dirblkbits;
+        let mut entries = Vec::new();
+        for block in data.chunks(block_size) {
+            if block.len() < DIRENT_SIZE {
+                return Err(corrupted("directory block"));
+            }
+            // The name of the first entry follows the last dirent.
+            let count = le_u16(block, 8) as usize / DIRENT_SIZE;
+            if count == 0 || count * DIRENT_SIZE > block.len() {
+                return Err(corrupted("directory block"));
+            }
+            for i in 0..count {
+                let dirent = &block[i * DIRENT_SIZE..];
+                let start = le_u16(dirent, 8) as usize;
+                let end = if i + 1 < count {
+                    le_u16(block, (i + 1) * DIRENT_SIZE + 8) as usize
+                } else {
+                    block.len()
+                };
+                let mut name = block
+                    .get(start..end)
+                    .ok_or_else(|| corrupted("directory entry name"))?;
+                // Only the last name of a block may be padded.
+                if let Some(nul) = name.iter().position(|&b| b == 0) {
+                    name = &name[..nul];
+                }
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    name: OsString::from_vec(name.to_vec()),
+                    nid: le_u64(dirent, 0),
+                    kind: match dirent[10] {
+                        1 => FileType::RegularFile,
+                        2 => FileType::Directory,
+                        3 => FileType::CharDevice,
+                        4 => FileType::BlockDevice,
+                        5 => FileType::NamedPipe,
+                        6 => FileType::Socket,
+                        7 => FileType::Symlink,
+                        _ => return Err(corrupted("directory entry type")),
+                    },
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// All xattrs of `inode`, with their full names.
+    pub fn xattrs(&self, inode: &InodeInfo) -> io::Result<Vec<Xattr>> {
+        let (pos, size) = inode.xattrs;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let area = self.read_at(size as usize, pos)?;
+        let shared = area[4] as usize;
+        let inline_start = XATTR_IBODY_HEADER_SIZE as usize + shared * 4;
+        if inline_start > area.len() {
+            return Err(corrupted("shared xattr count"));
+        }
+        let mut xattrs = Vec::new();
+        for i in 0..shared {
+            let id = le_u32(&area, XATTR_IBODY_HEADER_SIZE as usize + i * 4) as u64;
+            let pos = self.block_pos(self.sb.xattr_blkaddr) + id * 4;
+            let header = self.read_at(4, pos)?;
+            let len = header[0] as usize + le_u16(&header, 2) as usize;
+            let entry = [header, self.read_at(len, pos + 4)?].concat();
+            xattrs.push(parse_xattr(&entry)?.0);
+        }
+        let mut rest = &area[inline_start..];
+        while rest.len() >= 4 {
+            let (xattr, len) = parse_xattr(rest)?;
+            xattrs.push(xattr);
+            rest = &rest[len.next_multiple_of(4).min(rest.len())..];
+        }
+        Ok(xattrs)
+    }
+}
+
+// Parse the xattr entry `entry` starts with, returning it and its unpadded length.
+fn parse_xattr(entry: &[u8]) -> io::Result<(Xattr, usize)> {
+    let (name_len, value_len) = (entry[0] as usize, le_u16(entry, 2) as usize);
+    let len = 4 + name_len + value_len;
+    if entry.len() < len {
+        return Err(corrupted("xattr entry"));
+    }
+    let prefix: &[u8] = match entry[1] {
+        1 => b"user.",
+        2 => b"system.posix_acl_access",
+        3 => b"system.posix_acl_default",
+        4 => b"trusted.",
+        6 => b"security.",
+        _ => return Err(corrupted("xattr name index")),
+    };
+    let name = [prefix, &entry[4..4 + name_len]].concat();
+    Ok(((name, entry[4 + name_len..len].to_vec()), len))
+}
@@ -0,0 +1,435 @@
+//! Read-only layer serving the content of an EROFS image file.
+//!
+//! Like [`squashfs`](crate::squashfs), the image is parsed in userspace so it can be stacked
+//! as a lower layer of a [`unionfs`](crate::unionfs) without a loop mount. Metadata of any
+//! image can be read, but only the data of files stored uncompressed, which is what
+//! `mkfs.erofs` writes without `-z`.
+
+mod async_io;
+mod image;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rfuse3::Inode;
+use rfuse3::raw::reply::FileAttr;
+
+use crate::util::convert_stat64_to_file_attr;
+use image::{Image, InodeInfo};
+
+#[cfg(target_os = "macos")]
+type Stat64 = libc::stat;
+#[cfg(target_os = "linux")]
+type Stat64 = libc::stat64;
+
+const ROOT_INODE: Inode = 1;
+// The image never changes, so the kernel may cache attributes and entries for long.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// A read-only filesystem backed by an EROFS image file.
+///
+/// Inode numbers are derived from the inode numbers (nids) of the image, so they are stable
+/// across mounts of the same image.
+pub struct ErofsLayer {
+    image: Image,
+    // Parent of every directory looked up so far, to answer `..`.
+    parents: Mutex<HashMap<Inode, Inode>>,
+}
+
+impl ErofsLayer {
+    /// Open the EROFS image at `path`.
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let image = Image::open(path.as_ref())?;
+        // Make sure the root directory can be read before mounting anything on top.
+        image.inode(image.root_nid())?;
+        Ok(ErofsLayer {
+            image,
+            parents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Nids start at 0, so offsetting them by 2 keeps clear of the root inode number.
+    fn inode_of(&self, nid: u64) -> Inode {
+        if nid == self.image.root_nid() {
+            ROOT_INODE
+        } else {
+            nid + 2
+        }
+    }
+
+    fn nid(&self, inode: Inode) -> io::Result<u64> {
+        match inode {
+            ROOT_INODE => Ok(self.image.root_nid()),
+            0 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            _ => Ok(inode - 2),
+        }
+    }
+
+    fn get(&self, inode: Inode) -> io::Result<InodeInfo> {
+        self.image.inode(self.nid(inode)?)
+    }
+
+    fn parent_of(&self, inode: Inode) -> Inode {
+        self.parents
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .copied()
+            .unwrap_or(ROOT_INODE)
+    }
+
+    fn stat(&self, inode: Inode, info: &InodeInfo) -> Stat64 {
+        let mut st: Stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = (info.kind.const_into_mode_t() | info.perm as u32) as _;
+        st.st_nlink = info.nlink as _;
+        st.st_uid = info.uid;
+        st.st_gid = info.gid;
+        st.st_rdev = info.rdev as _;
+        st.st_size = info.size as _;
+        st.st_blksize = self.image.block_size() as _;
+        st.st_blocks = info.size.div_ceil(512) as _;
+        st.st_atime = info.mtime as _;
+        st.st_mtime = info.mtime as _;
+        st.st_ctime = info.mtime as _;
+        st
+    }
+
+    fn attr(&self, inode: Inode, info: &InodeInfo) -> FileAttr {
+        convert_stat64_to_file_attr(self.stat(inode, info))
+    }
+
+    pub(crate) fn getattr_stat(&self, inode: Inode) -> io::Result<(Stat64, Duration)> {
+        let info = self.get(inode)?;
+        Ok((self.stat(inode, &info), TTL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use rfuse3::FileType;
+    use rfuse3::raw::reply::ReplyXAttr;
+    use rfuse3::raw::{Filesystem, Request};
+
+    use super::*;
+    use crate::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+    use crate::unionfs::layer::Layer;
+    use crate::unionfs::{BoxedLayer, OverlayFs, config::Config};
+    use std::sync::Arc;
+
+    const BLOCK_SIZE: usize = 4096;
+    const MTIME: u64 = 1_700_000_000;
+    const FLAT_PLAIN: u16 = 0;
+    const FLAT_INLINE: u16 = 2;
+    const CHUNK_BASED: u16 = 4;
+
+    // A 32 byte compact inode owned by root.
+    fn compact(layout: u16, xattr_count: u16, mode: u32, size: u32, raw: u32) -> Vec<u8> {
+        [
+            &(layout << 1).to_le_bytes()[..],
+            &xattr_count.to_le_bytes(),
+            &(mode as u16).to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &size.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &raw.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &[0u8; 8],
+        ]
+        .concat()
+    }
+
+    // Directory data holding `entries`, which must be sorted and start with `.` and `..`.
+    fn dirents(entries: &[(&str, u64, u8)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut nameoff = entries.len() * 12;
+        for (name, nid, file_type) in entries {
+            data.extend_from_slice(&nid.to_le_bytes());
+            data.extend_from_slice(&(nameoff as u16).to_le_bytes());
+            data.extend_from_slice(&[*file_type, 0]);
+            nameoff += name.len();
+        }
+        for (name, _, _) in entries {
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    // Append `inode` to the metadata area at the next inode slot, returning its nid.
+    fn push_inode(meta: &mut Vec<u8>, inode: &[u8]) -> u64 {
+        meta.resize(meta.len().next_multiple_of(32), 0);
+        let nid = meta.len() as u64 / 32;
+        meta.extend_from_slice(inode);
+        nid
+    }
+
+    // Build an uncompressed image with 4KiB blocks holding:
+    //   /file.txt  10000 bytes, two full blocks and a tail inline, `user.test` shared
+    //   /dir/      opaque via `trusted.overlay.opaque`
+    //   /dir/small "hello", in a block of its own
+    //   /link      -> file.txt
+    //   /sparse    two one block chunks, the second a hole, and 100 more bytes
+    //   /wh        0/0 char device whiteout
+    // The superblock is in block 0, the inodes in block 1, shared xattrs in block 2 and file
+    // data from block 3 on.
+    fn build_image(content: &[u8]) -> Vec<u8> {
+        assert!((2 * BLOCK_SIZE..3 * BLOCK_SIZE).contains(&content.len()));
+        let tail = &content[2 * BLOCK_SIZE..];
+        let mut meta = Vec::new();
+
+        // The root goes first, its dirents have the same size whatever the nids.
+        let root_entries = |file, dir, link, sparse, wh| {
+            dirents(&[
+                (".", 0, 2),
+                ("..", 0, 2),
+                ("dir", dir, 2),
+                ("file.txt", file, 1),
+                ("link", link, 7),
+                ("sparse", sparse, 1),
+                ("wh", wh, 3),
+            ])
+        };
+        let root_size = root_entries(0, 0, 0, 0, 0).len();
+        let root = push_inode(&mut meta, &vec![0; 32 + root_size]);
+
+        // Extended inode with one shared xattr, so a 16 byte xattr area.
+        let file = push_inode(
+            &mut meta,
+            &[
+                &(1 | FLAT_INLINE << 1).to_le_bytes()[..],
+                &2u16.to_le_bytes(),
+                &(libc::S_IFREG as u16 | 0o644).to_le_bytes(),
+                &0u16.to_le_bytes(),
+                &(content.len() as u64).to_le_bytes(),
+                &3u32.to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &1000u32.to_le_bytes(),
+                &2000u32.to_le_bytes(),
+                &MTIME.to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &1u32.to_le_bytes(),
+                &[0u8; 16],
+                &[0u8; 4],
+                &[1, 0, 0, 0, 0, 0, 0, 0],
+                &0u32.to_le_bytes(),
+                tail,
+            ]
+            .concat(),
+        );
+        let small = push_inode(
+            &mut meta,
+            &compact(FLAT_PLAIN, 0, libc::S_IFREG | 0o600, 5, 5),
+        );
+        let link = push_inode(
+            &mut meta,
+            &[
+                compact(FLAT_INLINE, 0, libc::S_IFLNK | 0o777, 8, 0),
+                b"file.txt".to_vec(),
+            ]
+            .concat(),
+        );
+        let sparse = push_inode(
+            &mut meta,
+            &[
+                compact(
+                    CHUNK_BASED,
+                    0,
+                    libc::S_IFREG | 0o644,
+                    2 * BLOCK_SIZE as u32 + 100,
+                    0,
+                ),
+                6u32.to_le_bytes().to_vec(),
+                u32::MAX.to_le_bytes().to_vec(),
+                7u32.to_le_bytes().to_vec(),
+            ]
+            .concat(),
+        );
+        let wh = push_inode(&mut meta, &compact(0, 0, libc::S_IFCHR, 0, 0));
+        // One inline xattr entry, padded to 20 bytes, after the 12 byte header.
+        let dir_entries = dirents(&[(".", 0, 2), ("..", root, 2), ("small", small, 1)]);
+        let dir = push_inode(
+            &mut meta,
+            &[
+                &compact(
+                    FLAT_INLINE,
+                    6,
+                    libc::S_IFDIR | 0o755,
+                    dir_entries.len() as u32,
+                    0,
+                )[..],
+                &[0u8; 12],
+                &[14, 4],
+                &1u16.to_le_bytes(),
+                b"overlay.opaque",
+                b"y",
+                &[0],
+                &dir_entries,
+            ]
+            .concat(),
+        );
+        let root_inode = [
+            compact(FLAT_INLINE, 0, libc::S_IFDIR | 0o755, root_size as u32, 0),
+            root_entries(file, dir, link, sparse, wh),
+        ]
+        .concat();
+        meta[..root_inode.len()].copy_from_slice(&root_inode);
+        assert!(meta.len() <= BLOCK_SIZE);
+
+        let mut image = vec![0u8; 8 * BLOCK_SIZE];
+        let superblock = [
+            &0xe0f5_e1e2u32.to_le_bytes()[..],
+            &0u32.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &[12, 0],
+            &(root as u16).to_le_bytes(),
+            &7u64.to_le_bytes(),
+            &MTIME.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &8u32.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &2u32.to_le_bytes(),
+            &[0u8; 32],
+            &0x4u32.to_le_bytes(),
+        ]
+        .concat();
+        image[1024..1024 + superblock.len()].copy_from_slice(&superblock);
+        image[BLOCK_SIZE..BLOCK_SIZE + meta.len()].copy_from_slice(&meta);
+        let shared = [&[4, 1][..], &6u16.to_le_bytes(), b"test", b"shared"].concat();
+        image[2 * BLOCK_SIZE..2 * BLOCK_SIZE + shared.len()].copy_from_slice(&shared);
+        image[3 * BLOCK_SIZE..5 * BLOCK_SIZE].copy_from_slice(&content[..2 * BLOCK_SIZE]);
+        image[5 * BLOCK_SIZE..5 * BLOCK_SIZE + 5].copy_from_slice(b"hello");
+        image[6 * BLOCK_SIZE..7 * BLOCK_SIZE].fill(b'a');
+        image[7 * BLOCK_SIZE..8 * BLOCK_SIZE].fill(b'c');
+        image
+    }
+
+    #[tokio::test]
+    async fn test_erofs_layer() {
+        let content: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.erofs");
+        std::fs::write(&path, build_image(&content)).unwrap();
+        let fs = ErofsLayer::new(&path).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let attr = fs
+            .lookup(req, 1, OsStr::new("file.txt"))
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!((attr.size, attr.perm), (10000, 0o644));
+        assert_eq!((attr.uid, attr.gid), (1000, 2000));
+        let file = attr.ino;
+        let data = fs.read(req, file, 0, 0, 65536).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[..]);
+        let data = fs.read(req, file, 0, 4000, 5000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[4000..9000]);
+        let err = fs.open(req, file, libc::O_RDWR as u32).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::EROFS));
+        let value = fs
+            .getxattr(req, file, OsStr::new("user.test"), 64)
+            .await
+            .unwrap();
+        assert!(matches!(value, ReplyXAttr::Data(v) if v.as_ref() == b"shared"));
+
+        let dir = fs.lookup(req, 1, OsStr::new("dir")).await.unwrap().attr.ino;
+        let small = fs.lookup(req, dir, OsStr::new("small")).await.unwrap().attr;
+        assert_eq!(small.perm, 0o600);
+        let data = fs.read(req, small.ino, 0, 0, 100).await.unwrap().data;
+        assert_eq!(data.as_ref(), b"hello");
+        assert!(fs.is_opaque(req, dir).await.unwrap());
+        assert!(!fs.is_opaque(req, 1).await.unwrap());
+
+        let sparse = fs
+            .lookup(req, 1, OsStr::new("sparse"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let data = fs.read(req, sparse, 0, 4000, 8192).await.unwrap().data;
+        assert_eq!(data.len(), 2 * BLOCK_SIZE + 100 - 4000);
+        assert!(data[..96].iter().all(|&b| b == b'a'));
+        assert!(data[96..96 + BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(data[96 + BLOCK_SIZE..].iter().all(|&b| b == b'c'));
+
+        let link = fs
+            .lookup(req, 1, OsStr::new("link"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let target = fs.readlink(req, link).await.unwrap().data;
+        assert_eq!(target.as_ref(), b"file.txt");
+        let wh = fs.lookup(req, 1, OsStr::new("wh")).await.unwrap().attr.ino;
+        assert!(fs.is_whiteout(req, wh).await.unwrap());
+        let err = fs.lookup(req, 1, OsStr::new("missing")).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+
+        let names: Vec<_> =
+            futures::StreamExt::collect::<Vec<_>>(fs.readdir(req, 1, 0, 0).await.unwrap().entries)
+                .await
+                .into_iter()
+                .map(|e| e.unwrap().name)
+                .collect();
+        assert_eq!(
+            names,
+            [".", "..", "dir", "file.txt", "link", "sparse", "wh"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_erofs_lower_layer() {
+        let content = b"image".repeat(2000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.erofs");
+        std::fs::write(&path, build_image(&content)).unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let upper_layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: upper.path(),
+            mapping: None::<&str>,
+            io_engine: Default::default(),
+        })
+        .await
+        .unwrap();
+        let lower: Arc<BoxedLayer> = Arc::new(ErofsLayer::new(&path).unwrap());
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(Some(Arc::new(upper_layer)), vec![lower], config, 1).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let file = fs.lookup(req, 1, OsStr::new("file.txt")).await.unwrap();
+        let fh = fs
+            .open(req, file.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let data = fs
+            .read(req, file.attr.ino, fh, 0, 65536)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(data.as_ref(), &content[..]);
+        // The whiteout in the image hides nothing but itself.
+        let err = fs.lookup(req, 1, OsStr::new("wh")).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_not_erofs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.erofs");
+        std::fs::write(&path, [0u8; 4096]).unwrap();
+        let err = ErofsLayer::new(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
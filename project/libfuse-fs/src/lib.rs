@@ -2,9 +2,14 @@
 // extern crate log;
 
 pub mod context;
+pub mod erofs;
+pub mod memfs;
 pub mod overlayfs;
 pub mod passthrough;
+pub mod sandbox;
 mod server;
+pub mod squashfs;
+pub mod tarfs;
 pub mod unionfs;
 pub mod util;
 
@@ -0,0 +1,580 @@
+#![allow(clippy::unnecessary_cast)]
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStrExt;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::stream;
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result, Timestamp};
+
+use super::{BLOCK_SIZE, MemoryLayer, NAME_MAX, TTL};
+
+#[cfg(target_os = "linux")]
+fn free_memory() -> u64 {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    match unsafe { libc::sysinfo(&mut info) } {
+        0 => info.freeram as u64 * info.mem_unit as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn free_memory() -> u64 {
+    0
+}
+
+impl Filesystem for MemoryLayer {
+    /// initialize filesystem. Called before any other filesystem method.
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    /// clean up filesystem. The content goes with the layer.
+    async fn destroy(&self, _req: Request) {}
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let mut tree = self.tree();
+        let inode = tree.child(parent, name)?;
+        tree.node_mut(inode)?.lookups += 1;
+        Ok(Self::entry(&tree, inode)?)
+    }
+
+    /// forget an inode, dropping it if it has no name left.
+    async fn forget(&self, _req: Request, inode: Inode, nlookup: u64) {
+        self.tree().forget(inode, nlookup);
+    }
+
+    /// forget more than one inode.
+    async fn batch_forget(&self, _req: Request, inodes: &[(Inode, u64)]) {
+        let mut tree = self.tree();
+        for &(inode, nlookup) in inodes {
+            tree.forget(inode, nlookup);
+        }
+    }
+
+    /// get file attributes.
+    async fn getattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        let tree = self.tree();
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: Self::attr(inode, tree.node(inode)?),
+        })
+    }
+
+    /// set file attributes.
+    async fn setattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.set_attr(inode, &set_attr)?,
+        })
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
+        let tree = self.tree();
+        let node = tree.node(inode)?;
+        if node.kind != FileType::Symlink {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+        Ok(ReplyData {
+            data: Bytes::copy_from_slice(&node.data),
+        })
+    }
+
+    /// create a symbolic link.
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        self.do_symlink_helper(req, parent, name, link, req.uid, req.gid)
+            .await
+    }
+
+    /// create file node. Device nodes and whiteouts need no privilege here.
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        if mode & libc::S_IFMT as u32 == libc::S_IFDIR as u32 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+        Ok(self.make_node(parent, name, mode, rdev, &[], req.uid, req.gid)?)
+    }
+
+    /// create a directory.
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        self.do_mkdir_helper(req, parent, name, mode, umask, req.uid, req.gid)
+            .await
+    }
+
+    /// remove a file.
+    async fn unlink(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let mut tree = self.tree();
+        let inode = tree.child(parent, name)?;
+        if tree.node(inode)?.kind == FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+        }
+        Ok(tree.remove(parent, name)?)
+    }
+
+    /// remove a directory.
+    async fn rmdir(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let mut tree = self.tree();
+        let inode = tree.child(parent, name)?;
+        if !tree.dir(inode)?.children.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY).into());
+        }
+        Ok(tree.remove(parent, name)?)
+    }
+
+    /// rename a file or directory.
+    async fn rename(
+        &self,
+        _req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        Ok(self.tree().rename(parent, name, new_parent, new_name, 0)?)
+    }
+
+    /// rename a file or directory with flags.
+    async fn rename2(
+        &self,
+        _req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        Ok(self
+            .tree()
+            .rename(parent, name, new_parent, new_name, flags)?)
+    }
+
+    /// create a hard link.
+    async fn link(
+        &self,
+        _req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let mut tree = self.tree();
+        tree.link(inode, new_parent, new_name)?;
+        Ok(Self::entry(&tree, inode)?)
+    }
+
+    /// open a file. Data is reached through the inode, so no handle is allocated.
+    async fn open(&self, _req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        let write = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        if node.kind == FileType::Directory && write {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+        }
+        if node.kind == FileType::RegularFile && write && flags as i32 & libc::O_TRUNC != 0 {
+            node.data.clear();
+            node.touch();
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read data.
+    async fn read(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        match node.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(io::Error::from_raw_os_error(libc::EISDIR).into()),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL).into()),
+        }
+        let start = (offset as usize).min(node.data.len());
+        let end = start.saturating_add(size as usize).min(node.data.len());
+        node.atime = Timestamp::from(SystemTime::now());
+        Ok(ReplyData {
+            data: Bytes::copy_from_slice(&node.data[start..end]),
+        })
+    }
+
+    /// write data.
+    async fn write(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: u32,
+    ) -> Result<ReplyWrite> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        match node.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(io::Error::from_raw_os_error(libc::EISDIR).into()),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL).into()),
+        }
+        let start =
+            usize::try_from(offset).map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+        let end = start + data.len();
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[start..end].copy_from_slice(data);
+        node.touch();
+        Ok(ReplyWrite {
+            written: data.len() as u32,
+        })
+    }
+
+    /// get filesystem statistics. The free space is the free memory of the host.
+    async fn statfs(&self, _req: Request, _inode: Inode) -> Result<ReplyStatFs> {
+        let (bytes, files) = {
+            let tree = self.tree();
+            let bytes: u64 = tree.nodes.values().map(|n| n.data.len() as u64).sum();
+            (bytes, tree.nodes.len() as u64)
+        };
+        let used = bytes.div_ceil(BLOCK_SIZE as u64);
+        let free = free_memory() / BLOCK_SIZE as u64;
+        Ok(ReplyStatFs {
+            blocks: used + free,
+            bfree: free,
+            bavail: free,
+            files: files + free,
+            ffree: free,
+            bsize: BLOCK_SIZE,
+            namelen: NAME_MAX as u32,
+            frsize: BLOCK_SIZE,
+        })
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// synchronize file contents, nothing is ever buffered.
+    async fn fsync(&self, _req: Request, _inode: Inode, _fh: u64, _datasync: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// set an extended attribute.
+    async fn setxattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+    ) -> Result<()> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        let exists = node.xattrs.contains_key(name);
+        if flags as i32 & libc::XATTR_CREATE != 0 && exists {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST).into());
+        }
+        if flags as i32 & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA).into());
+        }
+        node.xattrs.insert(name.to_owned(), value.to_vec());
+        node.ctime = Timestamp::from(SystemTime::now());
+        Ok(())
+    }
+
+    /// get an extended attribute.
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let tree = self.tree();
+        let value = tree
+            .node(inode)?
+            .xattrs
+            .get(name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(value.len() as u32));
+        }
+        if value.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::copy_from_slice(value)))
+    }
+
+    /// list extended attribute names.
+    async fn listxattr(&self, _req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        let tree = self.tree();
+        let mut names = Vec::new();
+        for name in tree.node(inode)?.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(names.len() as u32));
+        }
+        if names.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(names)))
+    }
+
+    /// remove an extended attribute.
+    async fn removexattr(&self, _req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        if node.xattrs.remove(name).is_none() {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA).into());
+        }
+        node.ctime = Timestamp::from(SystemTime::now());
+        Ok(())
+    }
+
+    /// flush method, nothing is ever buffered.
+    async fn flush(&self, _req: Request, _inode: Inode, _fh: u64, _lock_owner: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// open a directory.
+    async fn opendir(&self, _req: Request, inode: Inode, _flags: u32) -> Result<ReplyOpen> {
+        self.tree().dir(inode)?;
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read directory.
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        let tree = self.tree();
+        let dir = tree.dir(parent)?;
+        let mut entries = vec![
+            (parent, FileType::Directory, ".".into()),
+            (dir.parent, FileType::Directory, "..".into()),
+        ];
+        for (name, &inode) in &dir.children {
+            entries.push((inode, tree.nodes[&inode].kind, name.clone()));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name,
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, _req: Request, _inode: Inode, _fh: u64, _flags: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// synchronize directory contents, nothing is ever buffered.
+    async fn fsyncdir(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// check file access permissions. Permission bits are left to the kernel.
+    async fn access(&self, _req: Request, inode: Inode, _mask: u32) -> Result<()> {
+        self.tree().node(inode)?;
+        Ok(())
+    }
+
+    /// create and open a file.
+    async fn create(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        self.do_create_helper(req, parent, name, mode, flags, req.uid, req.gid)
+            .await
+    }
+
+    /// test for a POSIX file lock. Locks are not supported.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// acquire, modify or release a POSIX file lock. Locks are not supported.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+        _block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// allocate space for an open file. Without holes, only growing the file is supported.
+    async fn fallocate(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        if mode as i32 & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP).into());
+        }
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        if node.kind != FileType::RegularFile {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV).into());
+        }
+        let end = offset.saturating_add(length) as usize;
+        if mode == 0 && node.data.len() < end {
+            node.data.resize(end, 0);
+            node.touch();
+        }
+        Ok(())
+    }
+
+    /// read directory entries, but with their attribute, like [`readdir`][Filesystem::readdir]
+    /// + [`lookup`][Filesystem::lookup] at the same time.
+    async fn readdirplus<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        let mut tree = self.tree();
+        let dir = tree.dir(parent)?;
+        let mut inodes = vec![(parent, ".".into()), (dir.parent, "..".into())];
+        for (name, &inode) in &dir.children {
+            inodes.push((inode, name.clone()));
+        }
+        let mut entries = Vec::new();
+        for (i, (inode, name)) in inodes.into_iter().enumerate().skip(offset as usize) {
+            let node = tree.node_mut(inode)?;
+            // The kernel forgets the entries it gets, but for "." and "..".
+            if i >= 2 {
+                node.lookups += 1;
+            }
+            entries.push(Ok(DirectoryEntryPlus {
+                inode,
+                generation: 0,
+                kind: node.kind,
+                name,
+                offset: i as i64 + 1,
+                attr: Self::attr(inode, node),
+                entry_ttl: TTL,
+                attr_ttl: TTL,
+            }));
+        }
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+}
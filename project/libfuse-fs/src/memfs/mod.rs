@@ -0,0 +1,760 @@
+//! Writable layer keeping everything in memory, like a tmpfs.
+//!
+//! [`MemoryLayer`] needs neither disk nor privileges: whiteout char devices and `trusted.*`
+//! xattrs are just entries of the tree. It serves as a scratch upper layer of the
+//! [`unionfs`](crate::unionfs), whose content is gone once the layer is dropped, and lets
+//! overlay tests run without touching the host filesystem.
+
+#![allow(clippy::unnecessary_cast)]
+mod async_io;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use rfuse3::raw::Request;
+use rfuse3::raw::reply::{FileAttr, ReplyCreated, ReplyEntry};
+use rfuse3::{FileType, Inode, Result, SetAttr, Timestamp};
+
+use crate::util::convert_stat64_to_file_attr;
+
+#[cfg(target_os = "macos")]
+type Stat64 = libc::stat;
+#[cfg(target_os = "linux")]
+type Stat64 = libc::stat64;
+
+const ROOT_INODE: Inode = 1;
+// Nothing changes the tree behind the kernel's back, but keep it short like passthrough.
+const TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u32 = 4096;
+const NAME_MAX: usize = 255;
+
+struct Node {
+    kind: FileType,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    nlink: u32,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp,
+    // File content, or the target of a symlink.
+    data: Vec<u8>,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+    // Only meaningful for directories, which can't be hard linked.
+    parent: Inode,
+    children: BTreeMap<OsString, Inode>,
+    // Lookups the kernel hasn't forgotten yet, an unlinked node is kept until they are.
+    lookups: u64,
+}
+
+impl Node {
+    fn new(kind: FileType, perm: u16, uid: u32, gid: u32, parent: Inode) -> Self {
+        let now = Timestamp::from(SystemTime::now());
+        Node {
+            kind,
+            perm: perm & 0o7777,
+            uid,
+            gid,
+            rdev: 0,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            atime: now,
+            mtime: now,
+            ctime: now,
+            data: Vec::new(),
+            xattrs: BTreeMap::new(),
+            parent,
+            children: BTreeMap::new(),
+            lookups: 0,
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = Timestamp::from(SystemTime::now());
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+struct Tree {
+    nodes: HashMap<Inode, Node>,
+    next_inode: Inode,
+}
+
+impl Tree {
+    fn node(&self, inode: Inode) -> io::Result<&Node> {
+        self.nodes
+            .get(&inode)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn node_mut(&mut self, inode: Inode) -> io::Result<&mut Node> {
+        self.nodes
+            .get_mut(&inode)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn dir(&self, inode: Inode) -> io::Result<&Node> {
+        let node = self.node(inode)?;
+        if node.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(node)
+    }
+
+    fn child(&self, parent: Inode, name: &OsStr) -> io::Result<Inode> {
+        self.dir(parent)?
+            .children
+            .get(name)
+            .copied()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    // Link the new `node` as `name` in `parent` and count the lookup of the returned entry.
+    fn insert(&mut self, parent: Inode, name: &OsStr, mut node: Node) -> io::Result<Inode> {
+        check_name(name)?;
+        if self.dir(parent)?.children.contains_key(name) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        let is_dir = node.kind == FileType::Directory;
+        node.lookups = 1;
+        self.nodes.insert(inode, node);
+        let dir = self.node_mut(parent)?;
+        dir.children.insert(name.to_owned(), inode);
+        if is_dir {
+            dir.nlink += 1;
+        }
+        dir.touch();
+        Ok(inode)
+    }
+
+    // Drop the entry `name` of `parent`, the node itself goes once it is forgotten too.
+    fn remove(&mut self, parent: Inode, name: &OsStr) -> io::Result<()> {
+        let inode = self.child(parent, name)?;
+        let dir = self.node_mut(parent)?;
+        dir.children.remove(name);
+        dir.touch();
+        let node = self.node_mut(inode)?;
+        if node.kind == FileType::Directory {
+            node.nlink = 0;
+            self.node_mut(parent)?.nlink -= 1;
+        } else {
+            node.nlink -= 1;
+            node.ctime = Timestamp::from(SystemTime::now());
+        }
+        self.reclaim(inode);
+        Ok(())
+    }
+
+    // Move `name` of `parent` to `new_name` of `new_parent`, following rename(2) and the
+    // `RENAME_NOREPLACE` and `RENAME_EXCHANGE` flags of renameat2(2).
+    fn rename(
+        &mut self,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE) != 0
+            || flags == libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        check_name(new_name)?;
+        let inode = self.child(parent, name)?;
+        let target = self.dir(new_parent)?.children.get(new_name).copied();
+        let is_dir = |tree: &Self, inode| tree.nodes[&inode].kind == FileType::Directory;
+        // A directory can't be moved below itself.
+        if is_dir(self, inode) && self.is_within(new_parent, inode) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let target = target.ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+            if is_dir(self, target) && self.is_within(parent, target) {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.node_mut(parent)?
+                .children
+                .insert(name.to_owned(), target);
+            self.node_mut(new_parent)?
+                .children
+                .insert(new_name.to_owned(), inode);
+            self.reparent(target, new_parent, parent)?;
+            self.reparent(inode, parent, new_parent)?;
+            return Ok(());
+        }
+        match target {
+            Some(target) if target == inode => return Ok(()),
+            Some(_) if flags & libc::RENAME_NOREPLACE != 0 => {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            Some(target) => {
+                match (is_dir(self, inode), is_dir(self, target)) {
+                    (true, false) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                    (false, true) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                    (true, true) if !self.nodes[&target].children.is_empty() => {
+                        return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+                    }
+                    _ => {}
+                }
+                self.remove(new_parent, new_name)?;
+            }
+            None => {}
+        }
+        self.node_mut(parent)?.children.remove(name);
+        self.node_mut(new_parent)?
+            .children
+            .insert(new_name.to_owned(), inode);
+        self.reparent(inode, parent, new_parent)
+    }
+
+    // Account for `inode` having moved from directory `from` to `to`.
+    fn reparent(&mut self, inode: Inode, from: Inode, to: Inode) -> io::Result<()> {
+        let node = self.node_mut(inode)?;
+        node.ctime = Timestamp::from(SystemTime::now());
+        let is_dir = node.kind == FileType::Directory;
+        if is_dir {
+            node.parent = to;
+        }
+        for (dir, delta) in [(from, -1i32), (to, 1)] {
+            let dir = self.node_mut(dir)?;
+            if is_dir {
+                dir.nlink = dir.nlink.wrapping_add_signed(delta);
+            }
+            dir.touch();
+        }
+        Ok(())
+    }
+
+    // Add `new_name` in `new_parent` as another name of `inode`.
+    fn link(&mut self, inode: Inode, new_parent: Inode, new_name: &OsStr) -> io::Result<()> {
+        check_name(new_name)?;
+        if self.node(inode)?.kind == FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if self.dir(new_parent)?.children.contains_key(new_name) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        let dir = self.node_mut(new_parent)?;
+        dir.children.insert(new_name.to_owned(), inode);
+        dir.touch();
+        let node = self.node_mut(inode)?;
+        node.nlink += 1;
+        node.lookups += 1;
+        node.ctime = Timestamp::from(SystemTime::now());
+        Ok(())
+    }
+
+    fn forget(&mut self, inode: Inode, nlookup: u64) {
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.lookups = node.lookups.saturating_sub(nlookup);
+            self.reclaim(inode);
+        }
+    }
+
+    fn reclaim(&mut self, inode: Inode) {
+        if inode != ROOT_INODE
+            && let Some(node) = self.nodes.get(&inode)
+            && node.nlink == 0
+            && node.lookups == 0
+        {
+            self.nodes.remove(&inode);
+        }
+    }
+
+    // Whether `inode` is `ancestor` or somewhere below it.
+    fn is_within(&self, mut inode: Inode, ancestor: Inode) -> bool {
+        loop {
+            if inode == ancestor {
+                return true;
+            }
+            match self.nodes.get(&inode) {
+                Some(node) if inode != ROOT_INODE => inode = node.parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+fn check_name(name: &OsStr) -> io::Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(())
+}
+
+/// Layer backed by a tree in memory, see the [module docs](self).
+pub struct MemoryLayer {
+    tree: Mutex<Tree>,
+}
+
+impl Default for MemoryLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryLayer {
+    /// Empty layer, with a root directory owned by the current user.
+    pub fn new() -> Self {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let root = Node::new(FileType::Directory, 0o755, uid, gid, ROOT_INODE);
+        MemoryLayer {
+            tree: Mutex::new(Tree {
+                nodes: HashMap::from([(ROOT_INODE, root)]),
+                next_inode: ROOT_INODE + 1,
+            }),
+        }
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().unwrap()
+    }
+
+    /// Total size of the file content held, in bytes.
+    pub fn used_bytes(&self) -> u64 {
+        self.tree()
+            .nodes
+            .values()
+            .map(|node| node.data.len() as u64)
+            .sum()
+    }
+
+    fn stat(inode: Inode, node: &Node) -> Stat64 {
+        let mut st: Stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = (node.kind.const_into_mode_t() | node.perm as u32) as _;
+        st.st_nlink = node.nlink as _;
+        st.st_uid = node.uid;
+        st.st_gid = node.gid;
+        st.st_rdev = node.rdev as _;
+        st.st_size = match node.kind {
+            FileType::Directory => BLOCK_SIZE as _,
+            _ => node.data.len() as _,
+        };
+        st.st_blksize = BLOCK_SIZE as _;
+        st.st_blocks = (node.data.len() as u64).div_ceil(512) as _;
+        st.st_atime = node.atime.sec as _;
+        st.st_atime_nsec = node.atime.nsec as _;
+        st.st_mtime = node.mtime.sec as _;
+        st.st_mtime_nsec = node.mtime.nsec as _;
+        st.st_ctime = node.ctime.sec as _;
+        st.st_ctime_nsec = node.ctime.nsec as _;
+        st
+    }
+
+    fn attr(inode: Inode, node: &Node) -> FileAttr {
+        convert_stat64_to_file_attr(Self::stat(inode, node))
+    }
+
+    fn entry(tree: &Tree, inode: Inode) -> io::Result<ReplyEntry> {
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: Self::attr(inode, tree.node(inode)?),
+            generation: 0,
+        })
+    }
+
+    pub(crate) fn getattr_stat(&self, inode: Inode) -> io::Result<(Stat64, Duration)> {
+        let tree = self.tree();
+        Ok((Self::stat(inode, tree.node(inode)?), TTL))
+    }
+
+    // Create `name` in `parent` from the type bits of `mode`.
+    #[allow(clippy::too_many_arguments)]
+    fn make_node(
+        &self,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        data: &[u8],
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<ReplyEntry> {
+        let kind = match mode & libc::S_IFMT as u32 {
+            // mknod(2) makes a regular file when no type is given.
+            0 => FileType::RegularFile,
+            t if t == libc::S_IFREG as u32 => FileType::RegularFile,
+            t if t == libc::S_IFDIR as u32 => FileType::Directory,
+            t if t == libc::S_IFLNK as u32 => FileType::Symlink,
+            t if t == libc::S_IFCHR as u32 => FileType::CharDevice,
+            t if t == libc::S_IFBLK as u32 => FileType::BlockDevice,
+            t if t == libc::S_IFIFO as u32 => FileType::NamedPipe,
+            t if t == libc::S_IFSOCK as u32 => FileType::Socket,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let mut tree = self.tree();
+        let mut node = Node::new(kind, mode as u16, uid, gid, parent);
+        node.rdev = rdev;
+        node.data = data.to_vec();
+        // Set-group-ID directories pass their group on, like other filesystems.
+        let dir = tree.dir(parent)?;
+        if dir.perm as u32 & libc::S_ISGID as u32 != 0 {
+            node.gid = dir.gid;
+            if kind == FileType::Directory {
+                node.perm |= libc::S_ISGID as u16;
+            }
+        }
+        let inode = tree.insert(parent, name, node)?;
+        Self::entry(&tree, inode)
+    }
+
+    /// Create a regular file owned by `uid`/`gid` rather than the requester, so copy-up keeps
+    /// the ownership of the lower file.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn do_create_helper(
+        &self,
+        _req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        _flags: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<ReplyCreated> {
+        let mode = (mode & !(libc::S_IFMT as u32)) | libc::S_IFREG as u32;
+        let entry = self.make_node(parent, name, mode, 0, &[], uid, gid)?;
+        Ok(ReplyCreated {
+            ttl: entry.ttl,
+            attr: entry.attr,
+            generation: entry.generation,
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// Create a directory owned by `uid`/`gid` rather than the requester.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn do_mkdir_helper(
+        &self,
+        _req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<ReplyEntry> {
+        let mode = (mode & !umask & 0o7777) | libc::S_IFDIR as u32;
+        Ok(self.make_node(parent, name, mode, 0, &[], uid, gid)?)
+    }
+
+    /// Create a symbolic link owned by `uid`/`gid` rather than the requester.
+    pub async fn do_symlink_helper(
+        &self,
+        _req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<ReplyEntry> {
+        let mode = libc::S_IFLNK as u32 | 0o777;
+        Ok(self.make_node(parent, name, mode, 0, link.as_bytes(), uid, gid)?)
+    }
+
+    fn set_attr(&self, inode: Inode, set_attr: &SetAttr) -> io::Result<FileAttr> {
+        let mut tree = self.tree();
+        let node = tree.node_mut(inode)?;
+        if let Some(size) = set_attr.size {
+            match node.kind {
+                FileType::RegularFile => node.data.resize(size as usize, 0),
+                FileType::Directory => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            }
+            node.touch();
+        }
+        if let Some(mode) = set_attr.mode {
+            node.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = set_attr.uid {
+            node.uid = uid;
+        }
+        if let Some(gid) = set_attr.gid {
+            node.gid = gid;
+        }
+        if let Some(atime) = set_attr.atime {
+            node.atime = atime;
+        }
+        if let Some(mtime) = set_attr.mtime {
+            node.mtime = mtime;
+        }
+        node.ctime = set_attr
+            .ctime
+            .unwrap_or_else(|| Timestamp::from(SystemTime::now()));
+        Ok(Self::attr(inode, node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::sync::Arc;
+
+    use futures::StreamExt as _;
+    use rfuse3::raw::reply::ReplyXAttr;
+    use rfuse3::raw::{Filesystem, Request};
+
+    use super::*;
+    use crate::context::OperationContext;
+    use crate::unionfs::layer::{Layer, OPAQUE_XATTR};
+    use crate::unionfs::{BoxedLayer, OverlayFs, config::Config};
+
+    fn errno<T>(res: Result<T>) -> Option<i32> {
+        io::Error::from(res.err().unwrap()).raw_os_error()
+    }
+
+    #[tokio::test]
+    async fn test_memory_layer() {
+        let fs = MemoryLayer::new();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let dir = fs
+            .mkdir(req, 1, OsStr::new("dir"), 0o755, 0o022)
+            .await
+            .unwrap();
+        assert_eq!(dir.attr.kind, FileType::Directory);
+        assert_eq!(dir.attr.nlink, 2);
+        let file = fs
+            .create(req, dir.attr.ino, OsStr::new("file"), 0o644, 0)
+            .await
+            .unwrap();
+        let written = fs
+            .write(req, file.attr.ino, file.fh, 4, b"data", 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(written.written, 4);
+        let data = fs.read(req, file.attr.ino, file.fh, 0, 64).await.unwrap();
+        assert_eq!(&data.data[..], b"\0\0\0\0data");
+        assert_eq!(fs.used_bytes(), 8);
+        assert_eq!(
+            errno(
+                fs.mkdir(req, dir.attr.ino, OsStr::new("file"), 0o755, 0)
+                    .await
+            ),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            errno(fs.rmdir(req, 1, OsStr::new("dir")).await),
+            Some(libc::ENOTEMPTY)
+        );
+
+        // Hard links share the data and survive the removal of the first name.
+        let link = fs
+            .link(req, file.attr.ino, 1, OsStr::new("link"))
+            .await
+            .unwrap();
+        assert_eq!(link.attr.nlink, 2);
+        fs.unlink(req, dir.attr.ino, OsStr::new("file"))
+            .await
+            .unwrap();
+        let data = fs.read(req, link.attr.ino, 0, 4, 4).await.unwrap();
+        assert_eq!(&data.data[..], b"data");
+
+        fs.rename(
+            req,
+            1,
+            OsStr::new("link"),
+            dir.attr.ino,
+            OsStr::new("moved"),
+        )
+        .await
+        .unwrap();
+        let moved = fs
+            .lookup(req, dir.attr.ino, OsStr::new("moved"))
+            .await
+            .unwrap();
+        assert_eq!(moved.attr.ino, file.attr.ino);
+        assert_eq!(
+            errno(fs.lookup(req, 1, OsStr::new("link")).await),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            errno(
+                fs.rename(req, 1, OsStr::new("dir"), dir.attr.ino, OsStr::new("sub"))
+                    .await
+            ),
+            Some(libc::EINVAL)
+        );
+
+        let names: Vec<_> = fs
+            .readdir(req, dir.attr.ino, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|entry| entry.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names, [".", "..", "moved"]);
+
+        fs.setxattr(req, dir.attr.ino, OsStr::new("user.a"), b"1", 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            errno(
+                fs.setxattr(
+                    req,
+                    dir.attr.ino,
+                    OsStr::new("user.a"),
+                    b"2",
+                    libc::XATTR_CREATE as u32,
+                    0
+                )
+                .await
+            ),
+            Some(libc::EEXIST)
+        );
+        let ReplyXAttr::Data(names) = fs.listxattr(req, dir.attr.ino, 64).await.unwrap() else {
+            panic!("expected xattr names");
+        };
+        assert_eq!(&names[..], b"user.a\0");
+        fs.removexattr(req, dir.attr.ino, OsStr::new("user.a"))
+            .await
+            .unwrap();
+        assert_eq!(
+            errno(
+                fs.getxattr(req, dir.attr.ino, OsStr::new("user.a"), 0)
+                    .await
+            ),
+            Some(libc::ENODATA)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_layer_helpers() {
+        let fs = MemoryLayer::new();
+        let req = Request::default();
+        let name = OsStr::new("gone");
+
+        let whiteout = fs.create_whiteout(req, 1, name).await.unwrap();
+        assert!(fs.is_whiteout(req, whiteout.attr.ino).await.unwrap());
+        fs.delete_whiteout(req, 1, name).await.unwrap();
+        assert_eq!(errno(fs.lookup(req, 1, name).await), Some(libc::ENOENT));
+
+        let dir = fs.mkdir(req, 1, OsStr::new("dir"), 0o755, 0).await.unwrap();
+        assert!(!fs.is_opaque(req, dir.attr.ino).await.unwrap());
+        fs.set_opaque(req, dir.attr.ino).await.unwrap();
+        assert!(fs.is_opaque(req, dir.attr.ino).await.unwrap());
+        let ReplyXAttr::Data(value) = fs
+            .getxattr(req, dir.attr.ino, OsStr::new(OPAQUE_XATTR), 16)
+            .await
+            .unwrap()
+        else {
+            panic!("expected the opaque xattr");
+        };
+        assert_eq!(&value[..], b"y");
+
+        let ctx = OperationContext::with_credentials(req, 1234, 5678);
+        let file = fs
+            .create_with_context(ctx, dir.attr.ino, OsStr::new("file"), 0o640, 0)
+            .await
+            .unwrap();
+        assert_eq!((file.attr.uid, file.attr.gid), (1234, 5678));
+        let (st, _) = fs
+            .getattr_with_mapping(file.attr.ino, None, false)
+            .await
+            .unwrap();
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o640);
+        let sub = fs
+            .mkdir_with_context(ctx, dir.attr.ino, OsStr::new("sub"), 0o750, 0)
+            .await
+            .unwrap();
+        assert_eq!((sub.attr.uid, sub.attr.perm), (1234, 0o750));
+        let link = fs
+            .symlink_with_context(ctx, 1, OsStr::new("ln"), OsStr::new("dir/file"))
+            .await
+            .unwrap();
+        assert_eq!(link.attr.gid, 5678);
+        let target = fs.readlink(req, link.attr.ino).await.unwrap();
+        assert_eq!(&target.data[..], b"dir/file");
+    }
+
+    #[tokio::test]
+    async fn test_memory_upper_layer() {
+        let lower = Arc::new(MemoryLayer::new());
+        let req = Request::default();
+        let lower_etc = lower
+            .mkdir(req, 1, OsStr::new("etc"), 0o755, 0)
+            .await
+            .unwrap();
+        for name in ["conf", "gone"] {
+            let file = lower
+                .create(req, lower_etc.attr.ino, OsStr::new(name), 0o644, 0)
+                .await
+                .unwrap();
+            lower
+                .write(req, file.attr.ino, file.fh, 0, b"lower", 0, 0)
+                .await
+                .unwrap();
+        }
+
+        let upper = Arc::new(MemoryLayer::new());
+        let lowers: Vec<Arc<BoxedLayer>> = vec![lower.clone()];
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(Some(upper.clone()), lowers, config, 1).unwrap();
+        fs.init(req).await.unwrap();
+
+        // Writing to a lower file copies it up and leaves the lower layer alone.
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        let conf = fs.lookup(req, etc, OsStr::new("conf")).await.unwrap();
+        let fh = fs
+            .open(req, conf.attr.ino, libc::O_WRONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        fs.write(req, conf.attr.ino, fh, 0, b"UPPER", 0, 0)
+            .await
+            .unwrap();
+        fs.release(req, conf.attr.ino, fh, 0, 0, true)
+            .await
+            .unwrap();
+        let upper_etc = upper.lookup(req, 1, OsStr::new("etc")).await.unwrap();
+        let upper_conf = upper
+            .lookup(req, upper_etc.attr.ino, OsStr::new("conf"))
+            .await
+            .unwrap();
+        let data = upper
+            .read(req, upper_conf.attr.ino, 0, 0, 64)
+            .await
+            .unwrap();
+        assert_eq!(&data.data[..], b"UPPER");
+        let lower_conf = lower
+            .lookup(req, lower_etc.attr.ino, OsStr::new("conf"))
+            .await
+            .unwrap();
+        let data = lower
+            .read(req, lower_conf.attr.ino, 0, 0, 64)
+            .await
+            .unwrap();
+        assert_eq!(&data.data[..], b"lower");
+
+        // Removing a lower file leaves a whiteout in the upper layer.
+        fs.unlink(req, etc, OsStr::new("gone")).await.unwrap();
+        assert_eq!(
+            errno(fs.lookup(req, etc, OsStr::new("gone")).await),
+            Some(libc::ENOENT)
+        );
+        let whiteout = upper
+            .lookup(req, upper_etc.attr.ino, OsStr::new("gone"))
+            .await
+            .unwrap();
+        assert!(upper.is_whiteout(req, whiteout.attr.ino).await.unwrap());
+    }
+}
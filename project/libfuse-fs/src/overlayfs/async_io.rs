@@ -1,5 +1,7 @@
 use super::Inode;
 use super::OverlayFs;
+use super::backing::Passthrough;
+use super::layer::{OPAQUE_XATTR, PRIVILEGED_OPAQUE_XATTR, UNPRIVILEGED_OPAQUE_XATTR};
 use super::utils;
 use crate::overlayfs::HandleData;
 use crate::overlayfs::RealHandle;
@@ -35,12 +37,9 @@ impl Filesystem for OverlayFs {
         if !self.config.do_import || self.config.writeback {
             self.writeback.store(true, Ordering::Relaxed);
         }
-        if !self.config.do_import || self.config.no_open {
-            self.no_open.store(true, Ordering::Relaxed);
-        }
-        if !self.config.do_import || self.config.no_opendir {
-            self.no_opendir.store(true, Ordering::Relaxed);
-        }
+        let (no_open, no_opendir) = self.handle_less();
+        self.no_open.store(no_open, Ordering::Relaxed);
+        self.no_opendir.store(no_opendir, Ordering::Relaxed);
         if !self.config.do_import || self.config.killpriv_v2 {
             self.killpriv_v2.store(true, Ordering::Relaxed);
         }
@@ -49,7 +48,11 @@ impl Filesystem for OverlayFs {
         }
 
         Ok(ReplyInit {
-            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+            max_write: self
+                .config
+                .tuning
+                .max_write
+                .unwrap_or(NonZeroU32::new(128 * 1024).unwrap()),
         })
     }
 
@@ -61,8 +64,9 @@ impl Filesystem for OverlayFs {
 
     /// look up a directory entry by name and get its attributes.
     async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
-        let tmp = name.to_string_lossy().to_string();
-        let result = self.do_lookup(req, parent, tmp.as_str()).await;
+        let _op = self.drain.enter()?;
+        self.apply_lower_changes().await;
+        let result = self.do_lookup(req, parent, name).await;
         match result {
             Ok(e) => Ok(e),
             Err(err) => Err(err.into()),
@@ -90,6 +94,8 @@ impl Filesystem for OverlayFs {
         fh: Option<u64>,
         flags: u32,
     ) -> Result<ReplyAttr> {
+        let _op = self.drain.enter()?;
+        self.apply_lower_changes().await;
         if !self.no_open.load(Ordering::Relaxed)
             && let Some(h) = fh
         {
@@ -101,15 +107,56 @@ impl Filesystem for OverlayFs {
                     .layer
                     .getattr(req, rh.inode, Some(rh.handle.load(Ordering::Relaxed)), 0)
                     .await?;
-                rep.attr.ino = inode;
+                self.reply_attr(req, &hd.node, &mut rep.attr).await?;
                 return Ok(rep);
             }
         }
 
-        let node: Arc<super::OverlayInode> = self.lookup_node(req, inode, "").await?;
+        let node: Arc<super::OverlayInode> = self.lookup_node(req, inode, OsStr::new("")).await?;
         let (layer, _, lower_inode) = node.first_layer_inode().await;
         let mut re = layer.getattr(req, lower_inode, None, flags).await?;
-        re.attr.ino = inode;
+        self.reply_attr(req, &node, &mut re.attr).await?;
+        Ok(re)
+    }
+
+    /// get extended file attributes. The birth time and attribute flags are those of the
+    /// file in the top layer holding it, the rest is adjusted like in `getattr`.
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        let _op = self.drain.enter()?;
+        self.apply_lower_changes().await;
+        if !self.no_open.load(Ordering::Relaxed)
+            && let Some(h) = fh
+        {
+            let handles = self.handles.lock().await;
+            if let Some(hd) = handles.get(&h)
+                && let Some(ref rh) = hd.real_handle
+            {
+                let mut rep = rh
+                    .layer
+                    .statx(
+                        req,
+                        rh.inode,
+                        Some(rh.handle.load(Ordering::Relaxed)),
+                        flags,
+                        mask,
+                    )
+                    .await?;
+                self.reply_attr(req, &hd.node, &mut rep.attr).await?;
+                return Ok(rep);
+            }
+        }
+
+        let node: Arc<super::OverlayInode> = self.lookup_node(req, inode, OsStr::new("")).await?;
+        let (layer, _, lower_inode) = node.first_layer_inode().await;
+        let mut re = layer.statx(req, lower_inode, None, flags, mask).await?;
+        self.reply_attr(req, &node, &mut re.attr).await?;
         Ok(re)
     }
 
@@ -121,11 +168,13 @@ impl Filesystem for OverlayFs {
         fh: Option<u64>,
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
+        let _op = self.drain.enter()?;
         // Check if upper layer exists.
         self.upper_layer
             .as_ref()
             .cloned()
             .ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
+        let set_mtime = set_attr.mtime.is_some();
 
         // deal with handle first
         if !self.no_open.load(Ordering::Relaxed)
@@ -146,30 +195,37 @@ impl Filesystem for OverlayFs {
                             set_attr,
                         )
                         .await?;
-                    rep.attr.ino = inode;
+                    self.setattr_dir_times(req, &hd.node, set_mtime, &mut rep.attr)
+                        .await?;
                     return Ok(rep);
                 }
             }
         }
 
-        let mut node = self.lookup_node(req, inode, "").await?;
+        let mut node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if !node.in_upper_layer().await {
-            node = self.copy_node_up(req, node.clone()).await?
+            // Data cut off by a truncation needn't be copied up first.
+            node = match set_attr.size {
+                Some(size) => self.copy_node_up_truncated(req, node.clone(), size).await?,
+                None => self.copy_node_up(req, node.clone()).await?,
+            };
         }
 
         let (layer, _, real_inode) = node.first_layer_inode().await;
         // layer.setattr(req, real_inode, None, set_attr).await
         let mut rep = layer.setattr(req, real_inode, None, set_attr).await?;
-        rep.attr.ino = inode;
+        self.setattr_dir_times(req, &node, set_mtime, &mut rep.attr)
+            .await?;
         Ok(rep)
     }
 
     /// read symbolic link.
     async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        let _op = self.drain.enter()?;
         trace!("READLINK: inode: {inode}\n");
 
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -187,15 +243,14 @@ impl Filesystem for OverlayFs {
         name: &OsStr,
         link: &OsStr,
     ) -> Result<ReplyEntry> {
+        let _op = self.drain.enter()?;
         // soft link
-        let sname = name.to_string_lossy().into_owned().to_owned();
-        let slinkname = link.to_string_lossy().into_owned().to_owned();
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
+        let (create_req, _, _) = self.squash_create(req, 0, 0);
+        self.do_symlink(create_req, link, &pnode, name).await?;
+        self.touch_dir(parent).await;
 
-        let pnode = self.lookup_node(req, parent, "").await?;
-        self.do_symlink(req, slinkname.as_str(), &pnode, sname.as_str())
-            .await?;
-
-        self.do_lookup(req, parent, sname.as_str())
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
@@ -211,17 +266,18 @@ impl Filesystem for OverlayFs {
         mode: u32,
         rdev: u32,
     ) -> Result<ReplyEntry> {
-        let sname = name.to_string_lossy().to_string();
-
+        let _op = self.drain.enter()?;
         // Check if parent exists.
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        self.do_mknod(req, &pnode, sname.as_str(), mode, rdev, 0)
+        let (create_req, mode, umask) = self.squash_create(req, mode, 0);
+        self.do_mknod(create_req, &pnode, name, mode, rdev, umask)
             .await?;
-        self.do_lookup(req, parent, sname.as_str())
+        self.touch_dir(parent).await;
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
@@ -235,33 +291,35 @@ impl Filesystem for OverlayFs {
         mode: u32,
         umask: u32,
     ) -> Result<ReplyEntry> {
-        let sname = name.to_string_lossy().to_string();
-
+        let _op = self.drain.enter()?;
         // no entry or whiteout
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        self.do_mkdir(req, pnode, sname.as_str(), mode, umask)
-            .await?;
-        self.do_lookup(req, parent, sname.as_str())
+        let (create_req, mode, umask) = self.squash_create(req, mode, umask);
+        self.do_mkdir(create_req, pnode, name, mode, umask).await?;
+        self.touch_dir(parent).await;
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
 
     /// remove a file.
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        self.do_rm(req, parent, name, false)
-            .await
-            .map_err(|e| e.into())
+        let _op = self.drain.enter()?;
+        self.do_rm(req, parent, name, false).await?;
+        self.touch_dir(parent).await;
+        Ok(())
     }
 
     /// remove a directory.
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        self.do_rm(req, parent, name, true)
-            .await
-            .map_err(|e| e.into())
+        let _op = self.drain.enter()?;
+        self.do_rm(req, parent, name, true).await?;
+        self.touch_dir(parent).await;
+        Ok(())
     }
 
     /// rename a file or directory.
@@ -273,9 +331,30 @@ impl Filesystem for OverlayFs {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<()> {
-        self.do_rename(req, parent, name, new_parent, new_name)
-            .await
-            .map_err(|e| e.into())
+        let _op = self.drain.enter()?;
+        self.do_rename(req, parent, name, new_parent, new_name, 0)
+            .await?;
+        self.touch_dir(parent).await;
+        self.touch_dir(new_parent).await;
+        Ok(())
+    }
+
+    /// rename a file or directory with flags.
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let _op = self.drain.enter()?;
+        self.do_rename(req, parent, name, new_parent, new_name, flags)
+            .await?;
+        self.touch_dir(parent).await;
+        self.touch_dir(new_parent).await;
+        Ok(())
     }
 
     /// create a hard link.
@@ -286,21 +365,22 @@ impl Filesystem for OverlayFs {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<ReplyEntry> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        let newpnode = self.lookup_node(req, new_parent, "").await?;
+        let newpnode = self.lookup_node(req, new_parent, OsStr::new("")).await?;
         if newpnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
-        let new_name = new_name.to_str().unwrap();
         // trace!(
         //     "LINK: inode: {}, new_parent: {}, trying to do_link: src_inode: {}, newpnode: {}",
         //     inode, new_parent, node.inode, newpnode.inode
         // );
         self.do_link(req, &node, &newpnode, new_name).await?;
+        self.touch_dir(new_parent).await;
         // trace!("LINK: done, looking up new entry");
         self.do_lookup(req, new_parent, new_name)
             .await
@@ -322,6 +402,8 @@ impl Filesystem for OverlayFs {
     /// [fuse_common.h](https://libfuse.github.io/doxygen/include_2fuse__common_8h_source.html) for
     /// more details.
     async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        let _op = self.drain.enter()?;
+        self.apply_lower_changes().await;
         if self.no_open.load(Ordering::Relaxed) {
             info!("fuse: open is not supported.");
             return Err(Error::from_raw_os_error(libc::ENOSYS).into());
@@ -347,16 +429,41 @@ impl Filesystem for OverlayFs {
             }
         }
         // lookup node
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         // whiteout node
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
+        // Emulated devices never reach the layers, so there is nothing to copy up either.
+        let device = self.config.emulated_devices.get(&node.path.read().await);
+        if let Some(device) = device {
+            let hd = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            let handle_data = HandleData {
+                node,
+                real_handle: None,
+                device: Some(device),
+                dir_snapshot: Mutex::new(None),
+                plain_snapshot: Mutex::new(None),
+                snapshot_version: AtomicU64::new(0),
+            };
+            self.handles.lock().await.insert(hd, Arc::new(handle_data));
+            // Never cache, reads of the same range don't have to return the same data.
+            return Ok(ReplyOpen {
+                fh: hd,
+                flags: OpenOptions::DIRECT_IO.bits(),
+                backing_id: None,
+            });
+        }
+
         if !readonly {
-            // copy up to upper layer
-            self.copy_node_up(req, node.clone()).await?;
+            // copy up to upper layer, without the data O_TRUNC is about to drop
+            if flags & libc::O_TRUNC != 0 {
+                self.copy_node_up_truncated(req, node.clone(), 0).await?;
+            } else {
+                self.copy_node_up(req, node.clone()).await?;
+            }
         }
 
         // assign a handle in overlayfs and open it
@@ -372,7 +479,10 @@ impl Filesystem for OverlayFs {
                 inode,
                 handle: AtomicU64::new(h.fh),
             }),
+            device: None,
             dir_snapshot: Mutex::new(None),
+            plain_snapshot: Mutex::new(None),
+            snapshot_version: AtomicU64::new(0),
         };
 
         self.handles.lock().await.insert(hd, Arc::new(handle_data));
@@ -383,12 +493,19 @@ impl Filesystem for OverlayFs {
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
         }
+        let mut backing_id = None;
+        match self.open_passthrough(node.inode, hd).await {
+            Passthrough::Backed(id) => backing_id = Some(id),
+            Passthrough::DirectIo => opts = OpenOptions::DIRECT_IO,
+            Passthrough::Cached => {}
+        }
 
         // trace!("OPEN: returning handle: {hd}");
 
         Ok(ReplyOpen {
             fh: hd,
             flags: opts.bits(),
+            backing_id,
         })
     }
 
@@ -405,22 +522,69 @@ impl Filesystem for OverlayFs {
         offset: u64,
         size: u32,
     ) -> Result<ReplyData> {
+        let _op = self.drain.enter()?;
         let data = self.get_data(req, Some(fh), inode, 0).await?;
+        if let Some(device) = data.device {
+            return Ok(ReplyData {
+                data: device.read(size)?.into(),
+            });
+        }
 
-        match data.real_handle {
+        let result = match data.real_handle {
             None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
             Some(ref hd) => {
-                hd.layer
-                    .read(
-                        req,
-                        hd.inode,
-                        hd.handle.load(Ordering::Relaxed),
-                        offset,
-                        size,
-                    )
-                    .await
+                let read = hd.layer.read(
+                    req,
+                    hd.inode,
+                    hd.handle.load(Ordering::Relaxed),
+                    offset,
+                    size,
+                );
+                self.interrupts.run(req.unique, read).await
             }
+        };
+        self.put_data(req, &data).await;
+        let reply = result?;
+        if let Some(acct) = &self.io_accounting {
+            acct.account_read(req.pid, reply.data.len()).await;
         }
+        Ok(reply)
+    }
+
+    /// read data from the file of the real handle, to be spliced to the kernel.
+    async fn read_fd(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Option<ReplyReadFd>> {
+        // Accounted reads need the data.
+        if self.io_accounting.is_some() {
+            return Ok(None);
+        }
+        let _op = self.drain.enter()?;
+        let data = self.get_data(req, Some(fh), inode, 0).await?;
+        if data.device.is_some() {
+            return Ok(None);
+        }
+
+        let result = match data.real_handle {
+            None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
+            Some(ref hd) => {
+                let read = hd.layer.read_fd(
+                    req,
+                    hd.inode,
+                    hd.handle.load(Ordering::Relaxed),
+                    offset,
+                    size,
+                );
+                self.interrupts.run(req.unique, read).await
+            }
+        };
+        self.put_data(req, &data).await;
+        result
     }
 
     /// write data. Write should return exactly the number of bytes requested except on error. An
@@ -441,9 +605,15 @@ impl Filesystem for OverlayFs {
         write_flags: u32,
         flags: u32,
     ) -> Result<ReplyWrite> {
+        let _op = self.drain.enter()?;
         let handle_data: Arc<HandleData> = self.get_data(req, Some(fh), inode, flags).await?;
+        if let Some(device) = handle_data.device {
+            return Ok(ReplyWrite {
+                written: device.write(data)?,
+            });
+        }
 
-        match handle_data.real_handle {
+        let result = match handle_data.real_handle {
             None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
             Some(ref hd) => {
                 hd.layer
@@ -458,7 +628,14 @@ impl Filesystem for OverlayFs {
                     )
                     .await
             }
+        };
+        self.put_data(req, &handle_data).await;
+        let reply = result?;
+        self.copy_ups.add_written(reply.written.into());
+        if let Some(acct) = &self.io_accounting {
+            acct.account_write(req.pid, reply.written as usize).await;
         }
+        Ok(reply)
     }
 
     /// Copy a range of data from one file to another. This can improve performance because it
@@ -478,45 +655,57 @@ impl Filesystem for OverlayFs {
         length: u64,
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
+        let _op = self.drain.enter()?;
         // Get handle data for source file
         let data_in = self.get_data(req, Some(fh_in), inode_in, 0).await?;
-        let handle_in = match data_in.real_handle {
-            None => return Err(Error::from_raw_os_error(libc::ENOENT).into()),
-            Some(ref hd) => hd,
+        // Get handle data for destination file, a handle-less one is opened for writing.
+        let data_out = match self
+            .get_data(req, Some(fh_out), inode_out, libc::O_WRONLY as u32)
+            .await
+        {
+            Ok(data) => data,
+            Err(e) => {
+                self.put_data(req, &data_in).await;
+                return Err(e.into());
+            }
         };
 
-        // Get handle data for destination file
-        let data_out = self.get_data(req, Some(fh_out), inode_out, 0).await?;
-        let handle_out = match data_out.real_handle {
-            None => return Err(Error::from_raw_os_error(libc::ENOENT).into()),
-            Some(ref hd) => hd,
+        let result = match (&data_in.real_handle, &data_out.real_handle) {
+            (Some(handle_in), Some(handle_out)) => {
+                // Both files must be on the same layer for copy_file_range to work
+                if !Arc::ptr_eq(&handle_in.layer, &handle_out.layer) {
+                    // Different layers - return EXDEV to trigger fallback to read/write
+                    Err(Error::from_raw_os_error(libc::EXDEV).into())
+                } else {
+                    // Delegate to the underlying PassthroughFs layer
+                    handle_in
+                        .layer
+                        .copy_file_range(
+                            req,
+                            handle_in.inode,
+                            handle_in.handle.load(Ordering::Relaxed),
+                            offset_in,
+                            handle_out.inode,
+                            handle_out.handle.load(Ordering::Relaxed),
+                            offset_out,
+                            length,
+                            flags,
+                        )
+                        .await
+                }
+            }
+            _ => Err(Error::from_raw_os_error(libc::ENOENT).into()),
         };
-
-        // Both files must be on the same layer for copy_file_range to work
-        if !Arc::ptr_eq(&handle_in.layer, &handle_out.layer) {
-            // Different layers - return EXDEV to trigger fallback to read/write
-            return Err(Error::from_raw_os_error(libc::EXDEV).into());
-        }
-
-        // Delegate to the underlying PassthroughFs layer
-        handle_in
-            .layer
-            .copy_file_range(
-                req,
-                handle_in.inode,
-                handle_in.handle.load(Ordering::Relaxed),
-                offset_in,
-                handle_out.inode,
-                handle_out.handle.load(Ordering::Relaxed),
-                offset_out,
-                length,
-                flags,
-            )
-            .await
+        self.put_data(req, &data_in).await;
+        self.put_data(req, &data_out).await;
+        let reply = result?;
+        self.copy_ups.add_written(reply.copied);
+        Ok(reply)
     }
 
     /// get filesystem statistics.
     async fn statfs(&self, req: Request, inode: Inode) -> Result<ReplyStatFs> {
+        let _op = self.drain.enter()?;
         self.do_statvfs(req, inode).await.map_err(|e| e.into())
     }
 
@@ -536,14 +725,24 @@ impl Filesystem for OverlayFs {
         lock_owner: u64,
         flush: bool,
     ) -> Result<()> {
+        // Handle-less, nothing was opened.
         if self.no_open.load(Ordering::Relaxed) {
-            info!("fuse: release is not supported.");
-            return Err(Error::from_raw_os_error(libc::ENOSYS).into());
+            return Ok(());
         }
 
-        if let Some(hd) = self.handles.lock().await.get(&fh) {
+        let hd = self.handles.lock().await.get(&fh).cloned();
+        if let Some(hd) = hd {
+            if flush {
+                self.release_posix_locks(&hd.node, lock_owner).await;
+            }
+            if let Some(backings) = &self.backings {
+                backings.release(hd.node.inode, fh);
+            }
             let rh = if let Some(ref h) = hd.real_handle {
                 h
+            } else if hd.device.is_some() {
+                self.handles.lock().await.remove(&fh);
+                return Ok(());
             } else {
                 return Err(
                     Error::other(format!("no real handle found for file handle {fh}")).into(),
@@ -579,12 +778,27 @@ impl Filesystem for OverlayFs {
         flags: u32,
         position: u32,
     ) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
+        // Marking a directory opaque has to hide its lower entries right away.
+        if value == b"y"
+            && [
+                OPAQUE_XATTR,
+                UNPRIVILEGED_OPAQUE_XATTR,
+                PRIVILEGED_OPAQUE_XATTR,
+            ]
+            .iter()
+            .any(|n| name == OsStr::new(n))
+            && node.is_dir(req).await?
+        {
+            return Ok(self.make_opaque(req, node).await?);
+        }
+
         if !node.in_upper_layer().await {
             // Copy node up.
             self.copy_node_up(req, node.clone()).await?;
@@ -607,7 +821,8 @@ impl Filesystem for OverlayFs {
         name: &OsStr,
         size: u32,
     ) -> Result<ReplyXAttr> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -623,7 +838,8 @@ impl Filesystem for OverlayFs {
     /// If `size` is too small, return `Err<ERANGE>`.  Otherwise, use
     /// [`ReplyXAttr::Data`] to send the attribute list, or return an error.
     async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
@@ -633,7 +849,8 @@ impl Filesystem for OverlayFs {
 
     /// remove an extended attribute.
     async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -664,40 +881,28 @@ impl Filesystem for OverlayFs {
     /// [`getlk`][Filesystem::getlk]) it should remove all locks belonging to `lock_owner`.
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
         if self.no_open.load(Ordering::Relaxed) {
-            return Err(Error::from_raw_os_error(libc::ENOSYS).into());
+            // Nothing is buffered without a handle, only the locks of the owner go away.
+            let node = self.lookup_node(req, inode, OsStr::new("")).await?;
+            self.release_posix_locks(&node, lock_owner).await;
+            return Ok(());
         }
 
-        let node = self.lookup_node(req, inode, "").await;
-        match node {
-            Ok(n) => {
-                if n.whiteout.load(Ordering::Relaxed) {
-                    return Err(Error::from_raw_os_error(libc::ENOENT).into());
-                }
-            }
-            Err(e) => {
-                if e.raw_os_error() == Some(libc::ENOENT) {
-                    trace!("flush: inode {inode} is stale");
-                } else {
-                    return Err(e.into());
-                }
-            }
+        // Go by the handle rather than the node, a file unlinked while open is still flushed
+        // so the write-back errors of its layer reach close(2).
+        let hd = self
+            .handles
+            .lock()
+            .await
+            .get(&fh)
+            .cloned()
+            .filter(|h| h.node.inode == inode)
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))?;
+        self.release_posix_locks(&hd.node, lock_owner).await;
+        if hd.device.is_some() {
+            return Ok(());
         }
 
         let (layer, real_inode, real_handle) = self.find_real_info_from_handle(fh).await?;
-
-        // FIXME: need to test if inode matches corresponding handle?
-        if inode
-            != self
-                .handles
-                .lock()
-                .await
-                .get(&fh)
-                .map(|h| h.node.inode)
-                .unwrap_or(0)
-        {
-            return Err(Error::other("inode does not match handle").into());
-        }
-
         trace!("flushing, real_inode: {real_inode}, real_handle: {real_handle}");
         layer.flush(req, real_inode, real_handle, lock_owner).await
     }
@@ -710,13 +915,15 @@ impl Filesystem for OverlayFs {
     /// sets [`MountOptions::no_open_dir_support`][crate::MountOptions::no_open_dir_support] and
     /// if the kernel supports `FUSE_NO_OPENDIR_SUPPORT`.
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        let _op = self.drain.enter()?;
+        self.apply_lower_changes().await;
         if self.no_opendir.load(Ordering::Relaxed) {
             info!("fuse: opendir is not supported.");
             return Err(Error::from_raw_os_error(libc::ENOSYS).into());
         }
 
         // lookup node
-        let node = self.lookup_node(req, inode, ".").await?;
+        let node = self.lookup_node(req, inode, OsStr::new(".")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -742,11 +949,18 @@ impl Filesystem for OverlayFs {
                     inode: real_inode,
                     handle: AtomicU64::new(reply.fh),
                 }),
+                device: None,
                 dir_snapshot: Mutex::new(None),
+                plain_snapshot: Mutex::new(None),
+                snapshot_version: AtomicU64::new(0),
             }),
         );
 
-        Ok(ReplyOpen { fh: handle, flags })
+        Ok(ReplyOpen {
+            fh: handle,
+            flags,
+            backing_id: None,
+        })
     }
 
     /// read directory. `offset` is used to track the offset of the directory entries. `fh` will
@@ -763,6 +977,7 @@ impl Filesystem for OverlayFs {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
         >,
     > {
+        let _op = self.drain.enter()?;
         if self.config.no_readdir {
             info!("fuse: readdir is not supported.");
             return Err(Error::from_raw_os_error(libc::ENOTDIR).into());
@@ -787,6 +1002,7 @@ impl Filesystem for OverlayFs {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
         >,
     > {
+        let _op = self.drain.enter()?;
         if self.config.no_readdir {
             info!("fuse: readdir is not supported.");
             return Err(Error::from_raw_os_error(libc::ENOTDIR).into());
@@ -809,9 +1025,9 @@ impl Filesystem for OverlayFs {
     /// [`opendir`][Filesystem::opendir] method, or will be undefined if the
     /// [`opendir`][Filesystem::opendir] method didn't set any value.
     async fn releasedir(&self, req: Request, _inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        // Handle-less, nothing was opened.
         if self.no_opendir.load(Ordering::Relaxed) {
-            info!("fuse: releasedir is not supported.");
-            return Err(Error::from_raw_os_error(libc::ENOSYS).into());
+            return Ok(());
         }
 
         if let Some(hd) = self.handles.lock().await.get(&fh) {
@@ -843,48 +1059,123 @@ impl Filesystem for OverlayFs {
             .map_err(|e| e.into())
     }
 
+    /// test for a POSIX file lock.
     #[allow(clippy::too_many_arguments)]
     async fn getlk(
         &self,
-        _req: Request,
-        _inode: Inode,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _type: u32,
-        _pid: u32,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
     ) -> Result<ReplyLock> {
-        Err(libc::ENOSYS.into())
+        let _op = self.drain.enter()?;
+        let node = self.lock_node(req, inode, fh).await?;
+        self.do_getlk(req, &node, lock_owner, start, end, r#type, pid)
+            .await
+            .map_err(|e| e.into())
     }
 
+    /// acquire, modify or release a POSIX file lock.
+    ///
+    /// Locks live on the node's topmost real inode and are moved to the upper layer when the
+    /// file is copied up, so a lock taken through a lower layer handle stays in force.
     #[allow(clippy::too_many_arguments)]
     async fn setlk(
         &self,
-        _req: Request,
-        _inode: Inode,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _type: u32,
-        _pid: u32,
-        _block: bool,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
     ) -> Result<()> {
-        Err(libc::ENOSYS.into())
+        let _op = self.drain.enter()?;
+        let node = self.lock_node(req, inode, fh).await?;
+        self.do_setlk(req, &node, lock_owner, start, end, r#type, pid, block)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    /// acquire, convert or release a `flock(2)` lock on the open file `fh`.
+    ///
+    /// The lock is taken on the real file of the handle, which stays on the layer it was opened
+    /// on, so it only conflicts with handles opened on the same layer.
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        let _op = self.drain.enter()?;
+        let data = self.get_data(req, Some(fh), inode, 0).await?;
+        let result = match data.real_handle {
+            None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
+            Some(ref hd) => {
+                let lock = hd.layer.flock(
+                    req,
+                    hd.inode,
+                    hd.handle.load(Ordering::Relaxed),
+                    lock_owner,
+                    r#type,
+                    block,
+                );
+                if block {
+                    self.interrupts.run(req.unique, lock).await
+                } else {
+                    lock.await
+                }
+            }
+        };
+        self.put_data(req, &data).await;
+        result
     }
     /// check file access permissions. This will be called for the `access()` system call. If the
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        let (layer, real_inode) = self.find_real_inode(inode).await?;
-        layer.access(req, real_inode, mask).await
+        // Check against the attributes the kernel sees, with ownership and mode squashed, as
+        // the real files are opened with the credentials of the daemon anyway.
+        let mut st = node.stat64(req).await?;
+        self.reply_attr(req, &node, &mut st.attr).await?;
+        let attr = st.attr;
+        if mask & libc::W_OK as u32 != 0
+            && self.upper_layer.is_none()
+            && matches!(
+                attr.kind,
+                FileType::RegularFile | FileType::Directory | FileType::Symlink
+            )
+        {
+            return Err(Error::from_raw_os_error(libc::EROFS).into());
+        }
+        let in_group =
+            |group| group == req.gid || crate::util::process_groups(req.pid).contains(&group);
+        crate::util::check_access(
+            req.uid,
+            in_group,
+            attr.uid,
+            attr.gid,
+            attr.perm as u32,
+            attr.kind == FileType::Directory,
+            mask,
+        )?;
+        Ok(())
     }
 
     /// create and open a file. If the file does not exist, first create it with the specified
@@ -911,8 +1202,9 @@ impl Filesystem for OverlayFs {
         mode: u32,
         flags: u32,
     ) -> Result<ReplyCreated> {
+        let _op = self.drain.enter()?;
         // Parent doesn't exist.
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
@@ -934,12 +1226,20 @@ impl Filesystem for OverlayFs {
             }
         }
 
+        let (create_req, mode, _) = self.squash_create(req, mode, 0);
         let final_handle = self
-            .do_create(req, &pnode, name, mode, flags.try_into().unwrap())
+            .do_create(create_req, &pnode, name, mode, flags.try_into().unwrap())
             .await?;
-        let entry = self.do_lookup(req, parent, name.to_str().unwrap()).await?;
-        let fh = final_handle
-            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "Handle not found"))?;
+        self.touch_dir(parent).await;
+        let entry = self.do_lookup(req, parent, name).await?;
+        let fh = match final_handle {
+            Some(fh) => fh,
+            // Handle-less files are opened by each request.
+            None if self.no_open.load(Ordering::Relaxed) => 0,
+            None => {
+                return Err(std::io::Error::new(ErrorKind::NotFound, "Handle not found").into());
+            }
+        };
 
         let mut opts = OpenOptions::empty();
         match self.config.cache_policy {
@@ -947,6 +1247,14 @@ impl Filesystem for OverlayFs {
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
         }
+        let mut backing_id = None;
+        if fh != 0 {
+            match self.open_passthrough(entry.attr.ino, fh).await {
+                Passthrough::Backed(id) => backing_id = Some(id),
+                Passthrough::DirectIo => opts = OpenOptions::DIRECT_IO,
+                Passthrough::Cached => {}
+            }
+        }
 
         Ok(ReplyCreated {
             ttl: entry.ttl,
@@ -954,10 +1262,13 @@ impl Filesystem for OverlayFs {
             generation: entry.generation,
             fh,
             flags: opts.bits(),
+            backing_id,
         })
     }
 
-    /// forget more than one inode. This is a batch version [`forget`][Filesystem::forget]
+    /// forget more than one inode. This is a batch version [`forget`][Filesystem::forget],
+    /// sent by the kernel when it shrinks its dentry cache or unmounts, so that thousands of
+    /// forgets take one round trip.
     async fn batch_forget(&self, _req: Request, inodes: &[(Inode, u64)]) {
         for inode in inodes {
             self.forget_one(inode.0, inode.1).await;
@@ -979,18 +1290,20 @@ impl Filesystem for OverlayFs {
         length: u64,
         mode: u32,
     ) -> Result<()> {
-        // Use O_RDONLY flags which indicates no copy up.
+        let _op = self.drain.enter()?;
+        // An open handle was copied up by its open already, a handle-less file is copied up
+        // and opened for writing here.
         let data = self
-            .get_data(req, Some(fh), inode, libc::O_RDONLY as u32)
+            .get_data(req, Some(fh), inode, libc::O_WRONLY as u32)
             .await?;
 
-        match data.real_handle {
+        let result = match data.real_handle {
             None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
+            // TODO: in lower layer, error out or just success?
+            Some(ref rhd) if !rhd.in_upper_layer => {
+                Err(Error::from_raw_os_error(libc::EROFS).into())
+            }
             Some(ref rhd) => {
-                if !rhd.in_upper_layer {
-                    // TODO: in lower layer, error out or just success?
-                    return Err(Error::from_raw_os_error(libc::EROFS).into());
-                }
                 rhd.layer
                     .fallocate(
                         req,
@@ -1002,7 +1315,9 @@ impl Filesystem for OverlayFs {
                     )
                     .await
             }
-        }
+        };
+        self.put_data(req, &data).await;
+        result
     }
 
     /// find next data or hole after the specified offset.
@@ -1014,7 +1329,8 @@ impl Filesystem for OverlayFs {
         offset: u64,
         whence: u32,
     ) -> Result<ReplyLSeek> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let _op = self.drain.enter()?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -1096,6 +1412,33 @@ impl Filesystem for OverlayFs {
                 _ => Err(Error::from_raw_os_error(libc::EINVAL).into()),
             }
         } else {
+            // Like the kernel's memory devices, emulated ones always seek to 0.
+            if let Some(hd) = self.handles.lock().await.get(&fh)
+                && hd.device.is_some()
+            {
+                return Ok(ReplyLSeek { offset: 0 });
+            }
+            if self.no_open.load(Ordering::Relaxed) {
+                let data = self
+                    .get_data(req, None, inode, libc::O_RDONLY as u32)
+                    .await?;
+                let result = match data.real_handle {
+                    Some(ref rh) => {
+                        rh.layer
+                            .lseek(
+                                req,
+                                rh.inode,
+                                rh.handle.load(Ordering::Relaxed),
+                                offset,
+                                whence,
+                            )
+                            .await
+                    }
+                    None => Err(Error::from_raw_os_error(libc::ENOENT).into()),
+                };
+                self.put_data(req, &data).await;
+                return result;
+            }
             // Keep the original lseek behavior for regular files
             // Delegate directly to the underlying layer
             let (layer, real_inode, real_handle) = self.find_real_info_from_handle(fh).await?;
@@ -1105,7 +1448,10 @@ impl Filesystem for OverlayFs {
         }
     }
 
-    async fn interrupt(&self, _req: Request, _unique: u64) -> Result<()> {
+    async fn interrupt(&self, _req: Request, unique: u64) -> Result<()> {
+        // The process gave up on the request, a copy-up it triggered would be wasted I/O.
+        self.copy_ups.cancel_request(unique);
+        self.interrupts.interrupt(unique);
         Ok(())
     }
 }
@@ -1141,6 +1487,7 @@ mod tests {
             let layer = new_passthroughfs_layer(PassthroughArgs {
                 root_dir: lower.clone(),
                 mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await
             .unwrap();
@@ -1151,6 +1498,7 @@ mod tests {
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: upperdir,
                 mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await
             .unwrap(),
@@ -0,0 +1,127 @@
+//! Kernel FUSE passthrough of opened files, see [`Config::passthrough`](super::config::Config).
+//!
+//! The kernel serves reads, writes and mmap of a passed through handle from the file registered
+//! for it, without sending requests to the overlay. It allows a single backing file per inode
+//! at a time and refuses cached opens next to passed through ones, so all handles of an overlay
+//! inode share the backing file of the first one. An open landing on another real file of the
+//! inode, the upper copy of a file still open from a lower layer, bypasses the page cache
+//! instead until the older handles are released.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use rfuse3::Inode;
+use rfuse3::passthrough::{BackingFiles, BackingId};
+use tracing::{debug, warn};
+
+use crate::passthrough::PassthroughFs;
+
+/// How an open of the overlay is served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Passthrough {
+    /// By the kernel, from this backing file.
+    Backed(BackingId),
+    /// By the overlay, bypassing the page cache since another real file of the inode is
+    /// passed through.
+    DirectIo,
+    /// By the overlay as usual.
+    Cached,
+}
+
+struct Shared {
+    // Layer and inode of the real file.
+    real: (usize, Inode),
+    id: BackingId,
+    // Overlay handles using the backing file.
+    handles: HashSet<u64>,
+}
+
+pub(crate) struct Backings {
+    files: BackingFiles,
+    inodes: Mutex<HashMap<Inode, Shared>>,
+}
+
+impl Backings {
+    pub fn new(files: BackingFiles) -> Self {
+        Self {
+            files,
+            inodes: Mutex::default(),
+        }
+    }
+
+    /// Passthrough of the overlay handle `fh` of `inode`, opened as `real_fh` of `real_inode` in
+    /// `layer`.
+    pub async fn open(
+        &self,
+        inode: Inode,
+        fh: u64,
+        layer: &Arc<PassthroughFs>,
+        real_inode: Inode,
+        real_fh: u64,
+    ) -> Passthrough {
+        let real = (Arc::as_ptr(layer) as usize, real_inode);
+        if let Some(shared) = self.inodes.lock().unwrap().get_mut(&inode) {
+            return Self::share(shared, real, fh);
+        }
+        if !self.files.is_enabled() {
+            return Passthrough::Cached;
+        }
+
+        let id = match layer.open_backing(real_inode, real_fh, &self.files).await {
+            Ok(id) => id,
+            Err(e) => {
+                debug!("passthrough: no backing file for inode {inode}: {e}");
+                return Passthrough::Cached;
+            }
+        };
+        let mut inodes = self.inodes.lock().unwrap();
+        if let Some(shared) = inodes.get_mut(&inode) {
+            // Another open of the inode registered one meanwhile.
+            let passthrough = Self::share(shared, real, fh);
+            drop(inodes);
+            self.close(id);
+            return passthrough;
+        }
+        inodes.insert(
+            inode,
+            Shared {
+                real,
+                id,
+                handles: HashSet::from([fh]),
+            },
+        );
+        Passthrough::Backed(id)
+    }
+
+    fn share(shared: &mut Shared, real: (usize, Inode), fh: u64) -> Passthrough {
+        if shared.real != real {
+            return Passthrough::DirectIo;
+        }
+        shared.handles.insert(fh);
+        Passthrough::Backed(shared.id)
+    }
+
+    /// Drop the overlay handle `fh` of `inode`, and the backing file once no handle uses it.
+    pub fn release(&self, inode: Inode, fh: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        let Some(shared) = inodes.get_mut(&inode) else {
+            return;
+        };
+        if !shared.handles.remove(&fh) || !shared.handles.is_empty() {
+            return;
+        }
+        let id = shared.id;
+        inodes.remove(&inode);
+        drop(inodes);
+        self.close(id);
+    }
+
+    fn close(&self, id: BackingId) {
+        if let Err(e) = self.files.close(id) {
+            warn!(
+                "passthrough: failed to close backing file {}: {e}",
+                id.get()
+            );
+        }
+    }
+}
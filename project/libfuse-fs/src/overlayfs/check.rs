@@ -0,0 +1,361 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Offline consistency check of overlay layers.
+//!
+//! [`scan`] walks the merged view of unmounted layers the same way the overlay merges them
+//! and reports markers that don't do what they were written for, typically left behind by a
+//! crash in the middle of a copy-up or a removal. With `repair` set, each problem is fixed by
+//! removing the offending marker, which never changes what the merged view shows.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::layer::{
+    OPAQUE_XATTR, PRIVILEGED_OPAQUE_XATTR, UNPRIVILEGED_OPAQUE_XATTR, WHITEOUT_XATTR,
+};
+use super::lower_index::{get_xattr, is_opaque};
+
+/// Redirect xattrs of kernel overlayfs. This overlay doesn't follow them, but layers shared
+/// with kernel mounts can carry them.
+const REDIRECT_XATTRS: [&str; 2] = ["trusted.overlay.redirect", "user.overlay.redirect"];
+const OCI_WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// Kind of a [`Problem`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// A whiteout with nothing to hide in the layers below it.
+    DanglingWhiteout,
+    /// An opaque marker on a non-directory, or on a directory with no directory of the same
+    /// path in the layers below it.
+    OrphanedOpaque,
+    /// A directory whose redirect, followed through other redirects, leads back to itself.
+    RedirectLoop,
+    /// An OCI `.wh.<name>` whiteout next to `<name>` in the same layer directory.
+    DuplicateWhiteout,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ProblemKind::DanglingWhiteout => "whiteout shadowing nothing",
+            ProblemKind::OrphanedOpaque => "opaque marker hiding nothing",
+            ProblemKind::RedirectLoop => "redirect loop",
+            ProblemKind::DuplicateWhiteout => "OCI whiteout next to the entry it removes",
+        })
+    }
+}
+
+/// One problem found by [`scan`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// Index of the layer in the list passed to [`scan`].
+    pub layer: usize,
+    /// Path of the offending entry, relative to the root of the layer.
+    pub path: PathBuf,
+    pub kind: ProblemKind,
+    /// Whether the problem was fixed.
+    pub repaired: bool,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "layer {}: /{}: {}",
+            self.layer,
+            self.path.display(),
+            self.kind
+        )?;
+        if self.repaired {
+            f.write_str(" (repaired)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the unmounted overlay made of `layers` and return the problems found.
+///
+/// `layers` are given top-most first, the upper layer (if any) first and then the lower
+/// layers in the order they are passed to the overlay. With `repair` set the problems are
+/// fixed as they are found. Lower layers are often shared by several overlays, so only
+/// repair them when none of those is mounted.
+pub fn scan<P: AsRef<Path>>(layers: &[P], repair: bool) -> Result<Vec<Problem>> {
+    let mut checker = Checker {
+        repair,
+        problems: Vec::new(),
+        redirects: BTreeMap::new(),
+    };
+    let roots = layers
+        .iter()
+        .enumerate()
+        .map(|(layer, root)| Candidate::new(layer, root.as_ref().to_path_buf()))
+        .collect::<Result<Vec<_>>>()?;
+    let dirs = checker.entry(Path::new(""), &roots)?;
+    checker.walk(Path::new(""), &dirs)?;
+    checker.redirect_loops()?;
+    Ok(checker.problems)
+}
+
+// One layer's entry at a path of the merged view.
+struct Candidate {
+    layer: usize,
+    path: PathBuf,
+    dir: bool,
+    whiteout: bool,
+    opaque: bool,
+}
+
+impl Candidate {
+    fn new(layer: usize, path: PathBuf) -> Result<Self> {
+        let md = fs::symlink_metadata(&path)?;
+        Ok(Candidate {
+            layer,
+            dir: md.is_dir(),
+            whiteout: is_whiteout(&path, &md),
+            opaque: is_opaque(&path),
+            path,
+        })
+    }
+}
+
+struct Checker {
+    repair: bool,
+    problems: Vec<Problem>,
+    // Redirect target of every redirected directory of the merged view, with the layer
+    // entries carrying it.
+    redirects: BTreeMap<PathBuf, (PathBuf, Vec<(usize, PathBuf)>)>,
+}
+
+impl Checker {
+    // Check the entries of the layers at merged path `rel`, top-most first, and return the
+    // directories merged into it like `OverlayInode::lookup_node` does.
+    fn entry(&mut self, rel: &Path, candidates: &[Candidate]) -> Result<Vec<(usize, PathBuf)>> {
+        for (i, c) in candidates.iter().enumerate() {
+            let below = &candidates[i + 1..];
+            if c.whiteout && below.iter().all(|b| b.whiteout) {
+                self.report(c.layer, rel, ProblemKind::DanglingWhiteout, || {
+                    fs::remove_file(&c.path)
+                })?;
+            }
+            if c.opaque && (!c.dir || !below.iter().any(|b| b.dir)) {
+                self.report(c.layer, rel, ProblemKind::OrphanedOpaque, || {
+                    remove_xattrs(
+                        &c.path,
+                        &[
+                            OPAQUE_XATTR,
+                            PRIVILEGED_OPAQUE_XATTR,
+                            UNPRIVILEGED_OPAQUE_XATTR,
+                        ],
+                    )
+                })?;
+            }
+        }
+
+        let mut dirs = Vec::new();
+        for c in candidates {
+            if !c.dir {
+                break;
+            }
+            dirs.push((c.layer, c.path.clone()));
+            if let Some(target) = redirect_target(rel, &c.path) {
+                let (_, entries) = self
+                    .redirects
+                    .entry(rel.to_path_buf())
+                    .or_insert_with(|| (target, Vec::new()));
+                entries.push((c.layer, c.path.clone()));
+            }
+            if c.opaque {
+                break;
+            }
+        }
+        Ok(dirs)
+    }
+
+    // Check the children of merged directory `rel` made of `dirs`, recursively.
+    fn walk(&mut self, rel: &Path, dirs: &[(usize, PathBuf)]) -> Result<()> {
+        let mut children: BTreeMap<OsString, Vec<Candidate>> = BTreeMap::new();
+        for (layer, dir) in dirs {
+            let names = fs::read_dir(dir)?
+                .map(|dirent| dirent.map(|dirent| dirent.file_name()))
+                .collect::<Result<HashSet<_>>>()?;
+            for name in &names {
+                let path = dir.join(name);
+                if let Some(removed) = name.as_bytes().strip_prefix(OCI_WHITEOUT_PREFIX)
+                    && names.contains(OsStr::from_bytes(removed))
+                {
+                    self.report(
+                        *layer,
+                        &rel.join(name),
+                        ProblemKind::DuplicateWhiteout,
+                        || remove_all(&path),
+                    )?;
+                    continue;
+                }
+                children
+                    .entry(name.clone())
+                    .or_default()
+                    .push(Candidate::new(*layer, path)?);
+            }
+        }
+
+        for (name, candidates) in children {
+            let child = rel.join(&name);
+            let dirs = self.entry(&child, &candidates)?;
+            if !dirs.is_empty() {
+                self.walk(&child, &dirs)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Report every redirected directory whose chain of redirects comes back to it.
+    fn redirect_loops(&mut self) -> Result<()> {
+        let mut looping = BTreeSet::new();
+        for start in self.redirects.keys() {
+            let mut cur = start;
+            for _ in 0..self.redirects.len() {
+                let Some((target, _)) = self.redirects.get(cur) else {
+                    break;
+                };
+                if target == start {
+                    looping.insert(start.clone());
+                    break;
+                }
+                cur = target;
+            }
+        }
+
+        for rel in looping {
+            let (_, entries) = self.redirects[&rel].clone();
+            for (layer, path) in entries {
+                self.report(layer, &rel, ProblemKind::RedirectLoop, || {
+                    remove_xattrs(&path, &REDIRECT_XATTRS)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        layer: usize,
+        path: &Path,
+        kind: ProblemKind,
+        fix: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        if self.repair {
+            fix()?;
+        }
+        self.problems.push(Problem {
+            layer,
+            path: path.to_path_buf(),
+            kind,
+            repaired: self.repair,
+        });
+        Ok(())
+    }
+}
+
+/// Remove the whiteouts of the upper layer `upper` that hide nothing in `lowers`, and return
+/// their paths relative to `upper`.
+///
+/// Lower layers are looked up by path, so a whiteout stays as long as any of them has an
+/// entry at its path, even one hidden in the merged view. Below an opaque upper directory
+/// nothing of the lower layers shows, so all whiteouts there go. Entries removed by
+/// concurrent operations are skipped.
+pub(crate) fn remove_dangling_whiteouts(upper: &Path, lowers: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    collect_dir(upper, Path::new(""), lowers, &mut removed)?;
+    Ok(removed)
+}
+
+fn collect_dir(
+    upper: &Path,
+    rel: &Path,
+    lowers: &[PathBuf],
+    removed: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let dir = upper.join(rel);
+    let lowers = if is_opaque(&dir) { &[] } else { lowers };
+    let dirents = match fs::read_dir(&dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        dirents => dirents?,
+    };
+    for dirent in dirents {
+        let dirent = dirent?;
+        let (path, child) = (dirent.path(), rel.join(dirent.file_name()));
+        let md = match fs::symlink_metadata(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            md => md?,
+        };
+        if md.is_dir() {
+            collect_dir(upper, &child, lowers, removed)?;
+            continue;
+        }
+        if !is_whiteout(&path, &md)
+            || lowers
+                .iter()
+                .any(|lower| fs::symlink_metadata(lower.join(&child)).is_ok())
+        {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            res => {
+                res?;
+                removed.push(child);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn is_whiteout(path: &Path, md: &fs::Metadata) -> bool {
+    (md.file_type().is_char_device() && md.rdev() == 0)
+        || (md.is_file() && md.len() == 0 && get_xattr(path, WHITEOUT_XATTR).is_some())
+}
+
+// Merged path the redirect xattr of directory `path` at merged path `rel` points to: from
+// the root when absolute, otherwise a sibling of `rel`.
+fn redirect_target(rel: &Path, path: &Path) -> Option<PathBuf> {
+    let value = REDIRECT_XATTRS
+        .iter()
+        .find_map(|name| get_xattr(path, name))?;
+    let value = Path::new(OsStr::from_bytes(&value));
+    Some(match value.strip_prefix("/") {
+        Ok(absolute) => absolute.to_path_buf(),
+        Err(_) => rel.parent().unwrap_or(Path::new("")).join(value),
+    })
+}
+
+fn remove_all(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// Remove the xattrs `names` of `path` that are set, without following symlinks.
+fn remove_xattrs(path: &Path, names: &[&str]) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    for name in names {
+        let cname = CString::new(*name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        // SAFETY: both strings are NUL-terminated.
+        if unsafe { libc::lremovexattr(cpath.as_ptr(), cname.as_ptr()) } < 0 {
+            let e = Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ENODATA) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
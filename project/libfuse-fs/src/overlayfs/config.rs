@@ -3,7 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use self::super::CachePolicy;
-use std::{fmt, path::PathBuf};
+use super::FuseTuning;
+use super::device::DevicePolicy;
+use super::layer::{OPAQUE_XATTR, PRIVILEGED_OPAQUE_XATTR, UNPRIVILEGED_OPAQUE_XATTR};
+use super::shared_attrs::SharedAttrCache;
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
 
 #[derive(Default, Clone, Debug)]
 pub struct Config {
@@ -17,6 +21,137 @@ pub struct Config {
     pub no_readdir: bool,
     pub perfile_dax: bool,
     pub cache_policy: CachePolicy,
+    /// Index of the lower layers built by [`lower_index::build`](super::lower_index::build).
+    /// Lookups and plain readdir in directories that only exist in lower layers are then
+    /// served from it instead of loading every entry of the directory.
+    pub lower_index: Option<PathBuf>,
+    /// Write whiteouts as empty files marked with the `user.overlay.whiteout` xattr instead of
+    /// 0/0 char devices, for unprivileged user namespaces without `CAP_MKNOD`. Both forms are
+    /// always recognized when reading layers.
+    pub xattr_whiteout: bool,
+    /// Xattr written to mark new opaque directories. All known names are honored when
+    /// reading layers, so this only decides which implementations can read them back.
+    pub opaque_xattr: OpaqueXattr,
+    /// What copy-up does when the upper layer refuses to set an xattr with `EPERM`, which is
+    /// common for `security.*` and `trusted.*` in unprivileged mounts.
+    pub xattr_eperm: XattrEpermPolicy,
+    /// Account reads and writes to the cgroup of the requesting process, see
+    /// [`OverlayFs::cgroup_io_stats`](super::OverlayFs::cgroup_io_stats).
+    pub cgroup_io_accounting: bool,
+    /// Report every file as owned by this uid, and create new files with it.
+    pub squash_to_uid: Option<u32>,
+    /// Report every file as owned by this gid, and create new files with it.
+    pub squash_to_gid: Option<u32>,
+    /// Report these permission bits (including setuid, setgid and sticky) for every file,
+    /// and create new files with them.
+    pub forced_mode: Option<u32>,
+    /// Device nodes whose opens are served in-process instead of by the layers, for
+    /// rootless containers that can't create real ones.
+    pub emulated_devices: DevicePolicy,
+    /// Keep the mtime and ctime of directories in the overlay instead of reporting the ones of
+    /// the top-most layer. They start at the newest time among the directory's layers and are
+    /// bumped whenever an entry is created, removed or renamed through the overlay, so tools
+    /// relying on directory mtimes see every change of the merged view.
+    pub strict_dir_times: bool,
+    /// Serve attributes and symlink targets of the lower layers from this cache, shared with
+    /// the other overlays given the same one. See [`SharedAttrCache`].
+    pub shared_attr_cache: Option<Arc<SharedAttrCache>>,
+    /// Watch the lower layers with inotify and pick up changes made to them while mounted,
+    /// instead of serving the directories loaded before. Every directory of every lower layer
+    /// takes a watch, so this is meant for small layers such as development trees. Cannot be
+    /// combined with `lower_index`, which describes the layers as they were when it was built.
+    pub watch_lower_layers: bool,
+    /// Probe the mounted overlay with a tiny create, write, read and unlink this often, see
+    /// [`OverlayFs::self_test`](super::OverlayFs::self_test). Needs an upper layer.
+    pub self_test_interval: Option<Duration>,
+    /// Always copy the data of regular files on copy-up. By default it is cloned with
+    /// `FICLONE` when the lower and upper layers share a filesystem supporting reflinks, so
+    /// small changes to large lower files don't duplicate their extents.
+    pub no_reflink: bool,
+    /// Hand regular files opened through the overlay to the kernel with FUSE passthrough, so
+    /// their reads, writes and mmap go straight to the upper or lower file while everything else
+    /// still goes through the overlay. Needs Linux 6.9 or newer, `CAP_SYS_ADMIN`, and
+    /// [`OverlayFs::set_backing_files`](super::OverlayFs::set_backing_files). Not used with
+    /// `writeback`, `CachePolicy::Never` or `cgroup_io_accounting`, which depend on seeing
+    /// reads and writes. Bytes written through passed through handles aren't counted in
+    /// [`OverlayFs::write_amplification`](super::OverlayFs::write_amplification).
+    pub passthrough: bool,
+    /// Move the data of reads from the upper or lower file to the kernel with `splice(2)`
+    /// instead of copying it through the overlay, see [`MountOptions::splice_read`]. Not used
+    /// with `cgroup_io_accounting`, which counts the bytes read. Writes are still copied
+    /// through the overlay.
+    ///
+    /// [`MountOptions::splice_read`]: rfuse3::MountOptions::splice_read
+    pub splice_read: bool,
+    /// Request sizes negotiated with the kernel, applied by
+    /// [`OverlayFs::apply_mount_options`](super::OverlayFs::apply_mount_options).
+    pub tuning: FuseTuning,
+}
+
+/// Name of the xattr marking a directory opaque.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpaqueXattr {
+    /// `user.fuseoverlayfs.opaque`, only understood by this implementation.
+    #[default]
+    FuseOverlayfs,
+    /// `trusted.overlay.opaque`, as used by kernel overlayfs. Needs `CAP_SYS_ADMIN` to set.
+    Trusted,
+    /// `user.overlay.opaque`, as used by kernel overlayfs with `userxattr` and fuse-overlayfs.
+    User,
+}
+
+impl OpaqueXattr {
+    pub fn name(self) -> &'static str {
+        match self {
+            OpaqueXattr::FuseOverlayfs => OPAQUE_XATTR,
+            OpaqueXattr::Trusted => PRIVILEGED_OPAQUE_XATTR,
+            OpaqueXattr::User => UNPRIVILEGED_OPAQUE_XATTR,
+        }
+    }
+}
+
+/// Action taken for an xattr that can't be copied up because of `EPERM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XattrEpermAction {
+    /// Abort the copy-up with `EPERM`.
+    Fail,
+    /// Log a warning and copy up the file without the xattr.
+    Skip,
+    /// Retry in the `user.*` namespace, e.g. `trusted.foo` is stored as `user.foo`. For `user.*`
+    /// xattrs this is the same as [`Fail`](Self::Fail).
+    MapToUser,
+}
+
+/// [`XattrEpermAction`] per xattr namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XattrEpermPolicy {
+    pub security: XattrEpermAction,
+    pub system: XattrEpermAction,
+    pub trusted: XattrEpermAction,
+    pub user: XattrEpermAction,
+}
+
+impl Default for XattrEpermPolicy {
+    fn default() -> Self {
+        Self {
+            security: XattrEpermAction::Skip,
+            system: XattrEpermAction::Fail,
+            trusted: XattrEpermAction::Skip,
+            user: XattrEpermAction::Fail,
+        }
+    }
+}
+
+impl XattrEpermPolicy {
+    /// Action for the xattr `name`, by its namespace prefix.
+    pub fn action(&self, name: &[u8]) -> XattrEpermAction {
+        match name.split(|&b| b == b'.').next() {
+            Some(b"security") => self.security,
+            Some(b"system") => self.system,
+            Some(b"trusted") => self.trusted,
+            _ => self.user,
+        }
+    }
 }
 
 impl Clone for CachePolicy {
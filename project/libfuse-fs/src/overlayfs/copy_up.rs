@@ -0,0 +1,237 @@
+//! Progress and cancellation of in-flight copy-ups.
+//!
+//! Copying a large regular file up can take long enough for users to notice, so every
+//! running copy is registered by overlay inode. Its progress can be polled, and it can be
+//! cancelled, which the overlay also does when the request that triggered it is interrupted.
+//!
+//! Finished copies are added up with the writes served, into the [`WriteAmplification`] of
+//! the overlay: an image whose layout makes containers copy large files up to change a few
+//! bytes of them shows a high ratio of bytes copied up to bytes written.
+//!
+//! When the upper layer is on the same filesystem as a lower one and that filesystem supports
+//! reflinks, such as btrfs or xfs, the copy is a clone of the lower file that shares its data
+//! blocks. Filesystems found unable to clone are remembered by [`ReflinkProbe`], so later
+//! copy-ups from them go straight to copying.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::Inode;
+
+/// State of one running copy-up, see [`super::OverlayFs::copy_up_progress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CopyUpProgress {
+    pub inode: Inode,
+    /// Bytes of data written to the upper layer so far.
+    pub copied: u64,
+    /// Size of the lower file when the copy started, or the size it is being truncated to
+    /// when smaller.
+    pub total: u64,
+    pub started_at: SystemTime,
+}
+
+/// Bytes written through an overlay against bytes its copy-ups wrote to the upper layer, see
+/// [`super::OverlayFs::write_amplification`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteAmplification {
+    /// Bytes written by the requests served, with `write` and `copy_file_range`.
+    pub written: u64,
+    /// Bytes of file data written by copy-ups that ended, cancelled and failed ones included.
+    pub copied_up: u64,
+    /// Copy-ups of regular files that ended.
+    pub copy_ups: u64,
+    /// Bytes of file data copy-ups cloned from the lower file instead of writing them, which
+    /// takes no space until either file is changed.
+    pub cloned_up: u64,
+}
+
+impl WriteAmplification {
+    /// Bytes physically written to the upper layer per byte written, None before any write.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.written > 0).then(|| (self.written + self.copied_up) as f64 / self.written as f64)
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    written: AtomicU64,
+    copied_up: AtomicU64,
+    copy_ups: AtomicU64,
+    cloned_up: AtomicU64,
+}
+
+pub(crate) struct CopyUpState {
+    inode: Inode,
+    // `unique` of the FUSE request the copy runs for.
+    request: u64,
+    total: u64,
+    copied: AtomicU64,
+    cloned: AtomicU64,
+    started_at: SystemTime,
+    cancelled: AtomicBool,
+}
+
+impl CopyUpState {
+    pub fn add_copied(&self, bytes: u64) {
+        self.copied.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Account `bytes` shared with the lower file by cloning it.
+    pub fn add_cloned(&self, bytes: u64) {
+        self.cloned.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn progress(&self) -> CopyUpProgress {
+        CopyUpProgress {
+            inode: self.inode,
+            copied: self.copied.load(Ordering::Relaxed),
+            total: self.total,
+            started_at: self.started_at,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct CopyUpTracker {
+    running: Arc<Mutex<HashMap<Inode, Arc<CopyUpState>>>>,
+    totals: Arc<Totals>,
+}
+
+impl CopyUpTracker {
+    /// Register the copy-up of `inode`, which stays listed until the guard is dropped.
+    pub fn begin(&self, inode: Inode, request: u64, total: u64) -> CopyUpGuard {
+        let state = Arc::new(CopyUpState {
+            inode,
+            request,
+            total,
+            copied: AtomicU64::new(0),
+            cloned: AtomicU64::new(0),
+            started_at: SystemTime::now(),
+            cancelled: AtomicBool::new(false),
+        });
+        self.running
+            .lock()
+            .unwrap()
+            .insert(inode, Arc::clone(&state));
+        CopyUpGuard {
+            running: Arc::clone(&self.running),
+            totals: Arc::clone(&self.totals),
+            state,
+        }
+    }
+
+    /// Account `bytes` written by a request.
+    pub fn add_written(&self, bytes: u64) {
+        self.totals.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn amplification(&self) -> WriteAmplification {
+        WriteAmplification {
+            written: self.totals.written.load(Ordering::Relaxed),
+            copied_up: self.totals.copied_up.load(Ordering::Relaxed),
+            copy_ups: self.totals.copy_ups.load(Ordering::Relaxed),
+            cloned_up: self.totals.cloned_up.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn progress(&self, inode: Inode) -> Option<CopyUpProgress> {
+        self.running
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .map(|state| state.progress())
+    }
+
+    pub fn all(&self) -> Vec<CopyUpProgress> {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|state| state.progress())
+            .collect()
+    }
+
+    /// Ask the copy-up of `inode` to stop, false if none is running.
+    pub fn cancel(&self, inode: Inode) -> bool {
+        match self.running.lock().unwrap().get(&inode) {
+            Some(state) => {
+                state.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel the copy-ups running for the FUSE request `unique`.
+    pub fn cancel_request(&self, unique: u64) {
+        for state in self.running.lock().unwrap().values() {
+            if state.request == unique {
+                state.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub(crate) struct CopyUpGuard {
+    running: Arc<Mutex<HashMap<Inode, Arc<CopyUpState>>>>,
+    totals: Arc<Totals>,
+    state: Arc<CopyUpState>,
+}
+
+impl std::ops::Deref for CopyUpGuard {
+    type Target = CopyUpState;
+
+    fn deref(&self) -> &CopyUpState {
+        &self.state
+    }
+}
+
+impl Drop for CopyUpGuard {
+    fn drop(&mut self) {
+        let copied = self.state.copied.load(Ordering::Relaxed);
+        self.totals.copied_up.fetch_add(copied, Ordering::Relaxed);
+        self.totals.copy_ups.fetch_add(1, Ordering::Relaxed);
+        let cloned = self.state.cloned.load(Ordering::Relaxed);
+        self.totals.cloned_up.fetch_add(cloned, Ordering::Relaxed);
+        let mut running = self.running.lock().unwrap();
+        if running
+            .get(&self.state.inode)
+            .is_some_and(|state| Arc::ptr_eq(state, &self.state))
+        {
+            running.remove(&self.state.inode);
+        }
+    }
+}
+
+/// Lower filesystems, by device number, that files could not be cloned from into the upper
+/// layer.
+#[derive(Default)]
+pub(crate) struct ReflinkProbe {
+    unsupported: Mutex<HashSet<u64>>,
+}
+
+impl ReflinkProbe {
+    /// Whether cloning files of the lower filesystem `dev` may work.
+    pub fn worth_trying(&self, dev: u64) -> bool {
+        !self.unsupported.lock().unwrap().contains(&dev)
+    }
+
+    /// Record that cloning a file of `dev` failed with `err`. Only errors saying the
+    /// filesystems can't clone rule `dev` out, others may be down to the file.
+    pub fn failed(&self, dev: u64, err: &io::Error) {
+        let unsupported = matches!(
+            err.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL | libc::ENOSYS)
+        );
+        if unsupported {
+            self.unsupported.lock().unwrap().insert(dev);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Content of the upper layer that is already in the lower layers.
+//!
+//! Copy-ups and tools rewriting files unchanged leave the upper layer full of data the
+//! lower layers already hold. [`scan`] cuts every regular file into content-defined chunks,
+//! so data shifted by an insertion still lines up, and reports how many bytes of the upper
+//! layer match chunks of the lower layers. Image builders can use the report to drop
+//! redundant files before committing the upper layer. [`DedupeJob`] runs the scan on a
+//! background thread next to a mounted overlay.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+
+use tracing::{debug, warn};
+
+/// Bounds of the content-defined chunks, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkParams {
+    pub min_size: usize,
+    /// Chunks are cut on average this far apart, must be a power of two.
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        Self {
+            min_size: 2 * 1024,
+            avg_size: 8 * 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
+/// Duplicate content of one upper layer file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileDedupe {
+    /// Path relative to the root of the upper layer.
+    pub path: PathBuf,
+    pub size: u64,
+    /// Bytes in chunks also found in a lower layer.
+    pub duplicate_bytes: u64,
+}
+
+impl FileDedupe {
+    /// Every byte of the file is in the lower layers.
+    pub fn is_redundant(&self) -> bool {
+        self.duplicate_bytes == self.size
+    }
+}
+
+/// Result of [`scan`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupeReport {
+    /// Regular files of the upper layer.
+    pub files: u64,
+    /// Bytes in those files.
+    pub bytes: u64,
+    /// Bytes of the upper layer in chunks also found in a lower layer.
+    pub duplicate_bytes: u64,
+    /// Bytes of the upper layer in chunks seen before in the upper layer itself, and not in
+    /// the lower layers.
+    pub upper_duplicate_bytes: u64,
+    /// Upper files with duplicate content, most duplicate bytes first.
+    pub duplicate_files: Vec<FileDedupe>,
+}
+
+/// Compare the content of the `upper` layer with the `lowers`. Entries that can't be read
+/// are skipped with a warning.
+pub fn scan<P: AsRef<Path>>(
+    upper: &Path,
+    lowers: &[P],
+    params: ChunkParams,
+) -> Result<DedupeReport> {
+    scan_with(upper, lowers, params, &Progress::default())
+}
+
+/// Progress of a scan, shared with the [`DedupeJob`] running it.
+#[derive(Default)]
+struct Progress {
+    bytes: AtomicU64,
+    cancel: AtomicBool,
+}
+
+fn scan_with<P: AsRef<Path>>(
+    upper: &Path,
+    lowers: &[P],
+    params: ChunkParams,
+    progress: &Progress,
+) -> Result<DedupeReport> {
+    if !params.avg_size.is_power_of_two()
+        || params.avg_size < 2
+        || params.min_size > params.avg_size
+        || params.avg_size > params.max_size
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid chunk sizes {params:?}"),
+        ));
+    }
+
+    let mut lower_chunks = HashSet::new();
+    for lower in lowers {
+        walk_files(lower.as_ref(), progress, &mut |_, file| {
+            chunk_file(file, params, progress, |_, hash| {
+                lower_chunks.insert(hash);
+            })
+        })?;
+    }
+    debug!("lower layers hold {} distinct chunks", lower_chunks.len());
+
+    let mut report = DedupeReport::default();
+    let mut upper_chunks = HashSet::new();
+    walk_files(upper, progress, &mut |path, file| {
+        let mut entry = FileDedupe {
+            path: path.to_path_buf(),
+            size: 0,
+            duplicate_bytes: 0,
+        };
+        chunk_file(file, params, progress, |len, hash| {
+            entry.size += len;
+            if lower_chunks.contains(&hash) {
+                entry.duplicate_bytes += len;
+            } else if !upper_chunks.insert(hash) {
+                report.upper_duplicate_bytes += len;
+            }
+        })?;
+        report.files += 1;
+        report.bytes += entry.size;
+        report.duplicate_bytes += entry.duplicate_bytes;
+        if entry.duplicate_bytes > 0 {
+            report.duplicate_files.push(entry);
+        }
+        Ok(())
+    })?;
+    report.duplicate_files.sort_by(|a, b| {
+        b.duplicate_bytes
+            .cmp(&a.duplicate_bytes)
+            .then(a.path.cmp(&b.path))
+    });
+    Ok(report)
+}
+
+// Call `f` with the path, relative to `root`, and the open file of every regular file below
+// `root`. Symlinks are not followed.
+fn walk_files(
+    root: &Path,
+    progress: &Progress,
+    f: &mut dyn FnMut(&Path, File) -> Result<()>,
+) -> Result<()> {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        let entries = match fs::read_dir(root.join(&rel)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("skipping {}: {e}", root.join(&rel).display());
+                continue;
+            }
+        };
+        for entry in entries {
+            if progress.cancel.load(Ordering::Relaxed) {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let entry = entry?;
+            let path = rel.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                let file = match File::open(entry.path()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        warn!("skipping {}: {e}", entry.path().display());
+                        continue;
+                    }
+                };
+                f(&path, file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Cut `file` into content-defined chunks and call `f` with the length and hash of each.
+fn chunk_file(
+    mut file: File,
+    params: ChunkParams,
+    progress: &Progress,
+    mut f: impl FnMut(u64, [u8; 32]),
+) -> Result<()> {
+    // The top bits of the gear hash depend on the most bytes.
+    let mask = (params.avg_size as u64 - 1) << (64 - params.avg_size.trailing_zeros());
+    let mut buf = vec![0u8; params.max_size.max(64 * 1024)];
+    let mut chunk = blake3::Hasher::new();
+    let mut len = 0usize;
+    let mut gear = 0u64;
+    loop {
+        if progress.cancel.load(Ordering::Relaxed) {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        progress.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        let mut start = 0;
+        for (i, &byte) in buf[..n].iter().enumerate() {
+            gear = (gear << 1).wrapping_add(GEAR[byte as usize]);
+            len += 1;
+            if (len >= params.min_size && gear & mask == 0) || len >= params.max_size {
+                chunk.update(&buf[start..=i]);
+                f(len as u64, *chunk.finalize().as_bytes());
+                chunk.reset();
+                start = i + 1;
+                len = 0;
+                gear = 0;
+            }
+        }
+        chunk.update(&buf[start..n]);
+    }
+    if len > 0 {
+        f(len as u64, *chunk.finalize().as_bytes());
+    }
+    Ok(())
+}
+
+/// A [`scan`] running on a background thread, cancelled when dropped.
+pub struct DedupeJob {
+    progress: Arc<Progress>,
+    handle: Option<JoinHandle<Result<DedupeReport>>>,
+}
+
+impl DedupeJob {
+    pub fn spawn(upper: PathBuf, lowers: Vec<PathBuf>, params: ChunkParams) -> Result<Self> {
+        let progress = Arc::new(Progress::default());
+        let thread_progress = progress.clone();
+        let handle = std::thread::Builder::new()
+            .name("overlay-dedupe".into())
+            .spawn(move || scan_with(&upper, &lowers, params, &thread_progress))?;
+        Ok(Self {
+            progress,
+            handle: Some(handle),
+        })
+    }
+
+    /// Bytes read so far, from the lower layers and then the upper one.
+    pub fn bytes_scanned(&self) -> u64 {
+        self.progress.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Wait for the scan to finish.
+    pub fn join(mut self) -> Result<DedupeReport> {
+        let handle = self.handle.take().unwrap();
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("dedupe scan panicked")))
+    }
+}
+
+impl Drop for DedupeJob {
+    fn drop(&mut self) {
+        self.progress.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Random values per byte for the rolling gear hash, from a fixed splitmix64 sequence so
+/// chunk boundaries are stable between runs.
+static GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
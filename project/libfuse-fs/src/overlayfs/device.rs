@@ -0,0 +1,100 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! User-space emulation of the standard character devices.
+//!
+//! Rootless containers can't `mknod` real devices, so images usually end up with
+//! placeholders (or nothing usable) under `/dev`. With a [`DevicePolicy`] in the config,
+//! opens of the listed paths are served in-process: the node still has to exist in a layer,
+//! but its content on the layer is never touched.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, Result};
+
+/// Behaviour of an emulated device, following the Linux memory devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmulatedDevice {
+    /// Reads hit EOF, writes are discarded.
+    Null,
+    /// Reads return zeros, writes are discarded.
+    Zero,
+    /// Reads return random bytes, writes are discarded.
+    Urandom,
+    /// Reads return zeros, writes fail with `ENOSPC`.
+    Full,
+}
+
+impl EmulatedDevice {
+    pub(crate) fn read(self, size: u32) -> Result<Vec<u8>> {
+        let mut buf = vec![0; size as usize];
+        match self {
+            EmulatedDevice::Null => buf.clear(),
+            EmulatedDevice::Zero | EmulatedDevice::Full => {}
+            EmulatedDevice::Urandom => {
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let rest = &mut buf[filled..];
+                    let ret = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+                    if ret < 0 {
+                        let e = Error::last_os_error();
+                        if e.raw_os_error() == Some(libc::EINTR) {
+                            continue;
+                        }
+                        return Err(e);
+                    }
+                    filled += ret as usize;
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    pub(crate) fn write(self, data: &[u8]) -> Result<u32> {
+        match self {
+            EmulatedDevice::Full => Err(Error::from_raw_os_error(libc::ENOSPC)),
+            _ => Ok(data.len() as u32),
+        }
+    }
+}
+
+/// Paths inside the mount whose opens are emulated, see [`EmulatedDevice`].
+///
+/// Paths are absolute from the root of the mount, e.g. `/dev/null`. The default policy is
+/// empty, so nothing is emulated unless asked for.
+#[derive(Default, Clone, Debug)]
+pub struct DevicePolicy {
+    devices: HashMap<OsString, EmulatedDevice>,
+}
+
+impl DevicePolicy {
+    /// `/dev/null`, `/dev/zero`, `/dev/urandom` and `/dev/full`.
+    pub fn standard() -> Self {
+        let mut policy = Self::default();
+        policy
+            .insert("/dev/null", EmulatedDevice::Null)
+            .insert("/dev/zero", EmulatedDevice::Zero)
+            .insert("/dev/urandom", EmulatedDevice::Urandom)
+            .insert("/dev/full", EmulatedDevice::Full);
+        policy
+    }
+
+    /// Emulate `device` for opens of `path`.
+    pub fn insert(&mut self, path: impl AsRef<OsStr>, device: EmulatedDevice) -> &mut Self {
+        self.devices.insert(path.as_ref().to_os_string(), device);
+        self
+    }
+
+    pub fn remove(&mut self, path: impl AsRef<OsStr>) -> Option<EmulatedDevice> {
+        self.devices.remove(path.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Device emulated for the overlay path `path`, if any.
+    pub fn get(&self, path: &OsStr) -> Option<EmulatedDevice> {
+        self.devices.get(path).copied()
+    }
+}
@@ -0,0 +1,158 @@
+//! Forgets owed to the layers by dropped [`super::RealInode`]s.
+//!
+//! Every `RealInode` holds one lookup of its layer inode, given back when it is dropped.
+//! Evicting a large directory drops thousands of them at once, so instead of a task per
+//! inode the forgets are coalesced per layer and sent with `batch_forget` by a single
+//! drain task, spawned when the queue of the layer becomes non-empty.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rfuse3::raw::{Filesystem, Request};
+
+use super::Inode;
+use crate::passthrough::PassthroughFs;
+
+/// Most forgets sent in one `batch_forget` call.
+const FORGET_BATCH: usize = 1024;
+
+/// Forgets of one layer not sent yet, by layer inode.
+#[derive(Default)]
+pub(crate) struct ForgetQueue {
+    pending: Mutex<HashMap<Inode, u64>>,
+    // A drain task is running or about to.
+    draining: AtomicBool,
+}
+
+impl ForgetQueue {
+    /// Queue `count` forgets of `inode`. True if the caller must start draining the queue.
+    fn push(&self, inode: Inode, count: u64) -> bool {
+        *self.pending.lock().unwrap().entry(inode).or_default() += count;
+        !self.draining.swap(true, Ordering::AcqRel)
+    }
+
+    /// Inodes with forgets queued.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Take up to [`FORGET_BATCH`] queued forgets.
+    fn take_batch(&self) -> Vec<(Inode, u64)> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() <= FORGET_BATCH {
+            return pending.drain().collect();
+        }
+        let batch: Vec<_> = pending
+            .iter()
+            .take(FORGET_BATCH)
+            .map(|(&inode, &count)| (inode, count))
+            .collect();
+        for (inode, _) in &batch {
+            pending.remove(inode);
+        }
+        batch
+    }
+
+    /// Stop draining, unless forgets were queued meanwhile and this call took the drain
+    /// over again.
+    fn finish_drain(&self) -> bool {
+        self.draining.store(false, Ordering::Release);
+        !self.pending.lock().unwrap().is_empty() && !self.draining.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// Forget `count` lookups of `inode` in `layer` soon.
+pub(crate) fn forget_later(layer: &Arc<PassthroughFs>, inode: Inode, count: u64) {
+    if !layer.forget_queue().push(inode, count) {
+        return;
+    }
+    let layer = Arc::clone(layer);
+    tokio::spawn(async move {
+        // Let the rest of a burst of drops land in the queue first.
+        tokio::task::yield_now().await;
+        drain(&layer).await;
+    });
+}
+
+/// Send the forgets queued for `layer` now, returns how many inodes they were for.
+pub(crate) async fn flush(layer: &PassthroughFs) -> usize {
+    let queue = layer.forget_queue();
+    let mut flushed = 0;
+    loop {
+        let batch = queue.take_batch();
+        if batch.is_empty() {
+            return flushed;
+        }
+        flushed += batch.len();
+        layer.batch_forget(Request::default(), &batch).await;
+    }
+}
+
+/// Send every queued forget of `layer`.
+async fn drain(layer: &PassthroughFs) {
+    let queue = layer.forget_queue();
+    loop {
+        let batch = queue.take_batch();
+        if !batch.is_empty() {
+            layer.batch_forget(Request::default(), &batch).await;
+            continue;
+        }
+        if !queue.finish_drain() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forget_queue_coalesces() {
+        let queue = ForgetQueue::default();
+        assert!(queue.push(10, 1));
+        assert!(!queue.push(10, 2));
+        assert!(!queue.push(11, 1));
+        let mut batch = queue.take_batch();
+        batch.sort();
+        assert_eq!(batch, vec![(10, 3), (11, 1)]);
+        assert!(!queue.finish_drain());
+
+        for inode in 0..FORGET_BATCH as u64 + 5 {
+            queue.push(inode, 1);
+        }
+        assert_eq!(queue.take_batch().len(), FORGET_BATCH);
+        assert_eq!(queue.pending.lock().unwrap().len(), 5);
+        // Forgets queued while draining keep the drain going.
+        assert!(queue.finish_drain());
+        assert_eq!(queue.take_batch().len(), 5);
+        assert!(!queue.finish_drain());
+        assert!(queue.push(1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        use crate::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("f"), b"x").unwrap();
+        let layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: dir.path(),
+            mapping: None::<&str>,
+            io_engine: Default::default(),
+        })
+        .await
+        .unwrap();
+        let inode = layer
+            .lookup(Request::default(), 1, std::ffi::OsStr::new("f"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        layer.forget_queue().push(inode, 1);
+        assert_eq!(flush(&layer).await, 1);
+        assert!(layer.forget_queue().pending.lock().unwrap().is_empty());
+        assert_eq!(flush(&layer).await, 0);
+    }
+}
@@ -2,114 +2,202 @@
 // 2024 From [fuse_backend_rs](https://github.com/cloud-hypervisor/fuse-backend-rs)
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::hash::{BuildHasher, RandomState};
 use std::io::{Error, Result};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{Arc, Mutex, RwLock};
 
 use crate::passthrough::VFS_MAX_INO;
 
 use super::{Inode, OverlayInode};
 
 use futures::future::join_all;
-use radix_trie::Trie;
 use tracing::{error, trace};
 
-pub struct InodeStore {
+/// Number of shards of each map. Lookups and directory loads only lock the shards of the
+/// inodes and paths they touch, so loading a large directory doesn't stall unrelated lookups.
+const SHARDS: usize = 64;
+
+#[derive(Default)]
+struct InodeShard {
     // Active inodes.
     inodes: HashMap<Inode, Arc<OverlayInode>>,
     // Deleted inodes which were unlinked but have non zero lookup count.
     deleted: HashMap<Inode, Arc<OverlayInode>>,
-    // Path to inode mapping, used to reserve inode number for same path.
-    path_mapping: Trie<String, Inode>,
-    next_inode: u64,
-    inode_limit: u64,
+    // Inode numbers handed out for paths that have no OverlayInode yet.
+    reserved: HashSet<Inode>,
     // FUSE inode to nlink mapping
     nlinks: HashMap<Inode, Arc<AtomicU64>>,
 }
 
+impl InodeShard {
+    fn in_use(&self, ino: Inode) -> bool {
+        self.inodes.contains_key(&ino)
+            || self.deleted.contains_key(&ino)
+            || self.reserved.contains(&ino)
+    }
+}
+
+struct Allocator {
+    next_inode: u64,
+    inode_limit: u64,
+}
+
+/// All inodes of an overlay, sharded by inode number, and the inode numbers of paths, sharded
+/// by path. Every method locks what it needs itself; locks are taken in the order path shard,
+/// allocator, inode shard and never held across an await.
+pub struct InodeStore {
+    shards: Box<[RwLock<InodeShard>]>,
+    // Path to inode mapping, used to reserve inode number for same path.
+    // Only exact-path lookups are needed, so a flat map is used instead of a trie:
+    // it has no per-component recursion and stays cheap for paths beyond PATH_MAX.
+    path_mapping: Box<[Mutex<HashMap<OsString, Inode>>]>,
+    path_hasher: RandomState,
+    alloc: Mutex<Allocator>,
+}
+
 impl InodeStore {
     pub(crate) fn new() -> Self {
         Self {
-            inodes: HashMap::new(),
-            deleted: HashMap::new(),
-            path_mapping: Trie::new(),
-            next_inode: 1,
-            inode_limit: VFS_MAX_INO,
-            nlinks: HashMap::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            path_mapping: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            path_hasher: RandomState::new(),
+            alloc: Mutex::new(Allocator {
+                next_inode: 1,
+                inode_limit: VFS_MAX_INO,
+            }),
         }
     }
 
-    pub(crate) fn alloc_unique_inode(&mut self) -> Result<Inode> {
+    fn shard(&self, ino: Inode) -> &RwLock<InodeShard> {
+        &self.shards[ino as usize % SHARDS]
+    }
+
+    fn paths(&self, path: &OsStr) -> &Mutex<HashMap<OsString, Inode>> {
+        &self.path_mapping[self.path_hasher.hash_one(path) as usize % SHARDS]
+    }
+
+    pub(crate) fn alloc_unique_inode(&self) -> Result<Inode> {
+        self.alloc_with(|_, _| {})
+    }
+
+    // Find a free inode number and call `claim` with its shard before anyone else can take it.
+    fn alloc_with(&self, claim: impl FnOnce(&mut InodeShard, Inode)) -> Result<Inode> {
+        let mut alloc = self.alloc.lock().unwrap();
         // Iter VFS_MAX_INO times to find a free inode number.
-        let mut ino = self.next_inode;
-        for _ in 0..self.inode_limit {
-            if ino > self.inode_limit {
+        let mut ino = alloc.next_inode;
+        for _ in 0..alloc.inode_limit {
+            if ino > alloc.inode_limit {
                 ino = 1;
             }
-            if !self.inodes.contains_key(&ino) && !self.deleted.contains_key(&ino) {
-                self.next_inode = ino + 1;
+            let mut shard = self.shard(ino).write().unwrap();
+            if !shard.in_use(ino) {
+                claim(&mut shard, ino);
+                alloc.next_inode = ino + 1;
                 return Ok(ino);
             }
             ino += 1;
         }
-        error!("reached maximum inode number: {}", self.inode_limit);
+        error!("reached maximum inode number: {}", alloc.inode_limit);
         Err(Error::other(format!(
             "maximum inode number {} reached",
-            self.inode_limit
+            alloc.inode_limit
         )))
     }
 
-    pub(crate) fn alloc_inode(&mut self, path: &str) -> Result<Inode> {
-        match self.path_mapping.get(path) {
+    pub(crate) fn alloc_inode(&self, path: &OsStr) -> Result<Inode> {
+        let reserved = self.paths(path).lock().unwrap().get(path).copied();
+        match reserved {
             // If the path is already in the mapping, return the reserved inode number.
-            Some(v) => Ok(*v),
+            Some(v) => Ok(v),
             // Or allocate a new inode number.
             None => self.alloc_unique_inode(),
         }
     }
 
-    pub(crate) async fn insert_inode(&mut self, inode: Inode, node: Arc<OverlayInode>) {
-        self.path_mapping
-            .insert(node.path.read().await.clone(), inode);
-        self.nlinks
-            .entry(inode)
-            .or_insert_with(|| Arc::new(AtomicU64::new(0)))
-            .fetch_add(1, Ordering::Relaxed);
-        self.inodes.entry(inode).or_insert(node);
+    /// Pick the inode number `path` will get once it is looked up, without creating it.
+    pub(crate) fn reserve_inode(&self, path: &OsStr) -> Result<Inode> {
+        let mut paths = self.paths(path).lock().unwrap();
+        if let Some(v) = paths.get(path) {
+            return Ok(*v);
+        }
+        let ino = self.alloc_with(|shard, ino| {
+            shard.reserved.insert(ino);
+        })?;
+        paths.insert(path.to_os_string(), ino);
+        Ok(ino)
+    }
+
+    pub(crate) async fn insert_inode(&self, inode: Inode, node: Arc<OverlayInode>) {
+        let path = node.path.read().await.clone();
+        {
+            let mut shard = self.shard(inode).write().unwrap();
+            shard.reserved.remove(&inode);
+            shard
+                .nlinks
+                .entry(inode)
+                .or_insert_with(|| Arc::new(AtomicU64::new(0)))
+                .fetch_add(1, Ordering::Relaxed);
+            shard.inodes.entry(inode).or_insert(node);
+        }
+        self.paths(&path).lock().unwrap().insert(path, inode);
     }
 
     pub(crate) fn get_inode(&self, inode: Inode) -> Option<Arc<OverlayInode>> {
-        self.inodes.get(&inode).cloned()
+        self.shard(inode)
+            .read()
+            .unwrap()
+            .inodes
+            .get(&inode)
+            .cloned()
     }
 
-    pub(crate) fn get_deleted_inode(&self, inode: Inode) -> Option<Arc<OverlayInode>> {
-        self.deleted.get(&inode).cloned()
+    /// The inode, active or deleted.
+    pub(crate) fn get_any_inode(&self, inode: Inode) -> Option<Arc<OverlayInode>> {
+        let shard = self.shard(inode).read().unwrap();
+        shard
+            .inodes
+            .get(&inode)
+            .or_else(|| shard.deleted.get(&inode))
+            .cloned()
     }
 
     // Return the inode only if it's permanently deleted from both self.inodes and self.deleted_inodes.
-    pub(crate) async fn remove_inode(
-        &mut self,
+    pub(crate) fn remove_inode(
+        &self,
         inode: Inode,
-        path_removed: Option<String>,
+        path_removed: Option<OsString>,
     ) -> Option<Arc<OverlayInode>> {
-        let old_nlink = self.nlinks.get(&inode)?.fetch_sub(1, Ordering::Relaxed);
+        let old_nlink = self
+            .shard(inode)
+            .read()
+            .unwrap()
+            .nlinks
+            .get(&inode)?
+            .fetch_sub(1, Ordering::Relaxed);
 
         if let Some(path) = path_removed {
-            self.path_mapping.remove(&path);
+            let removed = self.paths(&path).lock().unwrap().remove(&path);
+            if let Some(ino) = removed {
+                self.shard(ino).write().unwrap().reserved.remove(&ino);
+            }
         }
 
+        let mut shard = self.shard(inode).write().unwrap();
         if old_nlink == 1
-            && let Some(inode_data) = self.inodes.remove(&inode)
+            && let Some(inode_data) = shard.inodes.remove(&inode)
         {
             if inode_data.lookups.load(Ordering::Relaxed) > 0 {
                 trace!(
                     "InodeStore: inode {inode} unlinked but still in use, moving to deleted map."
                 );
-                self.deleted.insert(inode, inode_data);
+                shard.deleted.insert(inode, inode_data);
                 return None;
             } else {
                 trace!("InodeStore: inode {inode} permanently removed (nlink=0, lookups=0).");
-                self.nlinks.remove(&inode);
+                shard.nlinks.remove(&inode);
                 return Some(inode_data);
             }
         }
@@ -121,9 +209,14 @@ impl InodeStore {
     // This function consumes quite lots of memory, so it's disabled by default.
     #[allow(dead_code)]
     pub(crate) async fn debug_print_all_inodes(&self) {
+        let (mut active, mut deleted) = (Vec::new(), Vec::new());
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            active.extend(shard.inodes.iter().map(|(i, n)| (*i, n.clone())));
+            deleted.extend(shard.deleted.iter().map(|(i, n)| (*i, n.clone())));
+        }
         // Convert the HashMap to Vector<(inode, pathname)>
-        let all_inodes_f = self
-            .inodes
+        let all_inodes_f = active
             .iter()
             .map(|(inode, ovi)| {
                 async move {
@@ -136,8 +229,7 @@ impl InodeStore {
         all_inodes.sort_by(|a, b| a.0.cmp(b.0));
         trace!("all active inodes: {all_inodes:?}");
 
-        let to_delete = self
-            .deleted
+        let to_delete = deleted
             .iter()
             .map(|(inode, ovi)| async move {
                 (
@@ -152,9 +244,10 @@ impl InodeStore {
         trace!("all deleted inodes: {delete_to:?}");
     }
 
-    pub fn extend_inode_number(&mut self, next_inode: u64, limit_inode: u64) {
-        self.next_inode = next_inode;
-        self.inode_limit = limit_inode;
+    pub fn extend_inode_number(&self, next_inode: u64, limit_inode: u64) {
+        let mut alloc = self.alloc.lock().unwrap();
+        alloc.next_inode = next_inode;
+        alloc.inode_limit = limit_inode;
     }
 }
 
@@ -164,7 +257,7 @@ mod test {
 
     #[tokio::test]
     async fn test_alloc_unique() {
-        let mut store = InodeStore::new();
+        let store = InodeStore::new();
         let empty_node = Arc::new(OverlayInode::new());
         store.insert_inode(1, empty_node.clone()).await;
         store.insert_inode(2, empty_node.clone()).await;
@@ -174,9 +267,9 @@ mod test {
 
         let inode = store.alloc_unique_inode().unwrap();
         assert_eq!(inode, 3);
-        assert_eq!(store.next_inode, 4);
+        assert_eq!(store.alloc.lock().unwrap().next_inode, 4);
 
-        store.next_inode = VFS_MAX_INO - 1;
+        store.alloc.lock().unwrap().next_inode = VFS_MAX_INO - 1;
         let inode = store.alloc_unique_inode().unwrap();
         assert_eq!(inode, VFS_MAX_INO);
 
@@ -186,27 +279,111 @@ mod test {
 
     #[tokio::test]
     async fn test_alloc_existing_path() {
-        let mut store = InodeStore::new();
+        let store = InodeStore::new();
         let mut node_a = OverlayInode::new();
-        node_a.path = tokio::sync::RwLock::new("/a".to_string());
+        node_a.path = tokio::sync::RwLock::new("/a".into());
         store.insert_inode(1, Arc::new(node_a)).await;
         let mut node_b = OverlayInode::new();
-        node_b.path = tokio::sync::RwLock::new("/b".to_string());
+        node_b.path = tokio::sync::RwLock::new("/b".into());
         store.insert_inode(2, Arc::new(node_b)).await;
         let mut node_c = OverlayInode::new();
-        node_c.path = tokio::sync::RwLock::new("/c".to_string());
+        node_c.path = tokio::sync::RwLock::new("/c".into());
         store.insert_inode(VFS_MAX_INO - 1, Arc::new(node_c)).await;
 
-        let inode = store.alloc_inode("/a").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/a")).unwrap();
         assert_eq!(inode, 1);
 
-        let inode = store.alloc_inode("/b").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/b")).unwrap();
         assert_eq!(inode, 2);
 
-        let inode = store.alloc_inode("/c").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/c")).unwrap();
         assert_eq!(inode, VFS_MAX_INO - 1);
 
-        let inode = store.alloc_inode("/notexist").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/notexist")).unwrap();
         assert_eq!(inode, 3);
     }
+
+    #[tokio::test]
+    async fn test_alloc_deep_paths() {
+        // Deeply nested paths far beyond PATH_MAX must not exhaust the stack.
+        let store = InodeStore::new();
+        let mut path = OsString::new();
+        let mut paths = Vec::new();
+        for ino in 1..=3_000 {
+            path.push("/d");
+            let mut node = OverlayInode::new();
+            node.path = tokio::sync::RwLock::new(path.clone());
+            store.insert_inode(ino, Arc::new(node)).await;
+            paths.push(path.clone());
+        }
+        assert!(path.len() > 4096);
+
+        assert_eq!(store.alloc_inode(&paths[0]).unwrap(), 1);
+        assert_eq!(store.alloc_inode(&path).unwrap(), 3_000);
+
+        for (ino, p) in paths.into_iter().enumerate().rev() {
+            store.remove_inode(ino as u64 + 1, Some(p));
+        }
+        // Mappings are gone, so the path no longer reserves its old inode.
+        assert!(
+            store
+                .path_mapping
+                .iter()
+                .all(|m| m.lock().unwrap().is_empty())
+        );
+        assert_eq!(store.alloc_inode(&path).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_inode() {
+        let store = InodeStore::new();
+        let reserved = store.reserve_inode(OsStr::new("/lazy")).unwrap();
+        assert_eq!(store.reserve_inode(OsStr::new("/lazy")).unwrap(), reserved);
+        // Reserved numbers are neither reused for other paths nor lost on lookup.
+        assert_ne!(store.alloc_unique_inode().unwrap(), reserved);
+        assert_eq!(store.alloc_inode(OsStr::new("/lazy")).unwrap(), reserved);
+        let mut node = OverlayInode::new();
+        node.path = tokio::sync::RwLock::new("/lazy".into());
+        store.insert_inode(reserved, Arc::new(node)).await;
+        assert!(
+            store
+                .shards
+                .iter()
+                .all(|s| s.read().unwrap().reserved.is_empty())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_alloc() {
+        let store = Arc::new(InodeStore::new());
+        let tasks = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let mut inos = Vec::new();
+                    for i in 0..500 {
+                        let path = OsString::from(format!("/t{t}/f{i}"));
+                        let ino = if i % 2 == 0 {
+                            store.reserve_inode(&path).unwrap()
+                        } else {
+                            store.alloc_inode(&path).unwrap()
+                        };
+                        let mut node = OverlayInode::new();
+                        node.path = tokio::sync::RwLock::new(path);
+                        store.insert_inode(ino, Arc::new(node)).await;
+                        inos.push(ino);
+                    }
+                    inos
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut all = HashSet::new();
+        for task in tasks {
+            for ino in task.await.unwrap() {
+                assert!(all.insert(ino), "inode {ino} handed out twice");
+                assert!(store.get_inode(ino).is_some());
+            }
+        }
+        assert_eq!(all.len(), 8 * 500);
+    }
 }
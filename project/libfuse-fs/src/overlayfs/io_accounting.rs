@@ -0,0 +1,150 @@
+//! Per-cgroup accounting of the IO served through the overlay.
+//!
+//! The kernel charges FUSE IO to the daemon, not to the process that issued it, so the
+//! requester pid from the FUSE header is resolved to its cgroup here instead.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Requester pid to cgroup cache size, expired entries are dropped once full.
+const PID_CACHE_SIZE: usize = 4096;
+/// How long the cgroup of a pid is trusted. Bounds how long the IO of a reused pid, or of a
+/// process moved to another cgroup, is charged to the cgroup it was looked up in.
+const PID_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// IO counters of one cgroup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CgroupIoStats {
+    pub read_bytes: u64,
+    pub read_ops: u64,
+    pub write_bytes: u64,
+    pub write_ops: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct IoAccounting {
+    stats: Mutex<HashMap<String, CgroupIoStats>>,
+    cgroups: Mutex<HashMap<u32, (Instant, String)>>,
+}
+
+impl IoAccounting {
+    pub async fn account_read(&self, pid: u32, bytes: usize) {
+        let cgroup = self.cgroup_of(pid).await;
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(cgroup).or_default();
+        entry.read_bytes += bytes as u64;
+        entry.read_ops += 1;
+    }
+
+    pub async fn account_write(&self, pid: u32, bytes: usize) {
+        let cgroup = self.cgroup_of(pid).await;
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(cgroup).or_default();
+        entry.write_bytes += bytes as u64;
+        entry.write_ops += 1;
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, CgroupIoStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Requests the kernel issues on its own (pid 0, e.g. writeback) and requesters that
+    // exited before they could be looked up are accounted to the empty cgroup name.
+    async fn cgroup_of(&self, pid: u32) -> String {
+        if pid == 0 {
+            return String::new();
+        }
+        if let Some((looked_up, cgroup)) = self.cgroups.lock().unwrap().get(&pid)
+            && looked_up.elapsed() < PID_CACHE_TTL
+        {
+            return cgroup.clone();
+        }
+        // procfs reads may block, on the locks of a process being set up or torn down.
+        let cgroup = tokio::task::spawn_blocking(move || {
+            let content = std::fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+            Some(parse_cgroup(&content).unwrap_or_default().to_string())
+        })
+        .await;
+        let Ok(Some(cgroup)) = cgroup else {
+            return String::new();
+        };
+        let mut cgroups = self.cgroups.lock().unwrap();
+        if cgroups.len() >= PID_CACHE_SIZE {
+            cgroups.retain(|_, (looked_up, _)| looked_up.elapsed() < PID_CACHE_TTL);
+            if cgroups.len() >= PID_CACHE_SIZE {
+                cgroups.clear();
+            }
+        }
+        cgroups.insert(pid, (Instant::now(), cgroup.clone()));
+        cgroup
+    }
+}
+
+/// Pick the cgroup path from `/proc/<pid>/cgroup`: the unified hierarchy, or the v1
+/// hierarchy holding the `blkio` controller.
+fn parse_cgroup(content: &str) -> Option<&str> {
+    let mut blkio = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(id), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if id == "0" && controllers.is_empty() {
+            return Some(path);
+        }
+        if controllers.split(',').any(|c| c == "blkio") {
+            blkio = Some(path);
+        }
+    }
+    blkio
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup("0::/kubepods/pod1/c1\n"),
+            Some("/kubepods/pod1/c1")
+        );
+        let v1 = "12:memory:/docker/a\n4:blkio:/docker/b\n1:name=systemd:/init.scope\n";
+        assert_eq!(parse_cgroup(v1), Some("/docker/b"));
+        assert_eq!(parse_cgroup("3:cpu,cpuacct:/x\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_account_by_cgroup() {
+        let acct = IoAccounting::default();
+        acct.account_read(std::process::id(), 10).await;
+        acct.account_read(std::process::id(), 5).await;
+        acct.account_write(0, 7).await;
+        let stats = acct.snapshot();
+        let own = parse_cgroup(&std::fs::read_to_string("/proc/self/cgroup").unwrap())
+            .unwrap_or_default()
+            .to_string();
+        assert_eq!(stats[&own].read_bytes, 15);
+        assert_eq!(stats[&own].read_ops, 2);
+        assert_eq!(stats[""].write_bytes, 7);
+    }
+
+    #[tokio::test]
+    async fn test_expired_pid_looked_up_again() {
+        let acct = IoAccounting::default();
+        let pid = std::process::id();
+        // As if the pid belonged to an exited process of another cgroup.
+        let looked_up = Instant::now() - 2 * PID_CACHE_TTL;
+        acct.cgroups
+            .lock()
+            .unwrap()
+            .insert(pid, (looked_up, "/exited".to_string()));
+        acct.account_write(pid, 3).await;
+        let stats = acct.snapshot();
+        assert!(!stats.contains_key("/exited"));
+        assert_eq!(stats.values().map(|s| s.write_bytes).sum::<u64>(), 3);
+    }
+}
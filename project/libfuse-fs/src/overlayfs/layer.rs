@@ -6,18 +6,43 @@ use rfuse3::{
 use std::ffi::OsStr;
 use std::io::Error;
 
-use crate::passthrough::PassthroughFs;
+use tracing::warn;
+
+use super::config::{Config, OpaqueXattr};
+use crate::passthrough::{PassthroughFs, util};
 pub const OPAQUE_XATTR_LEN: u32 = 16;
 pub const OPAQUE_XATTR: &str = "user.fuseoverlayfs.opaque";
 pub const UNPRIVILEGED_OPAQUE_XATTR: &str = "user.overlay.opaque";
 pub const PRIVILEGED_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// Marks a zero-length regular file as whiteout where a 0/0 char device can't be created.
+pub const WHITEOUT_XATTR: &str = "user.overlay.whiteout";
 
 /// A filesystem must implement Layer trait, or it cannot be used as an OverlayFS layer.
 pub trait Layer: Filesystem {
     /// Return the root inode number
     fn root_inode(&self) -> Inode;
+
+    /// Whether 0/0 char device whiteouts can be created in this layer.
+    fn supports_whiteout(&self) -> bool {
+        true
+    }
+
+    /// Whether this layer holds xattrs, needed to mark opaque directories and xattr
+    /// whiteouts. Without them no directory of the layer is considered opaque.
+    fn supports_opaque(&self) -> bool {
+        true
+    }
+
+    /// Whether `trusted.*` xattrs can be read and written in this layer.
+    fn supports_trusted_xattr(&self) -> bool {
+        true
+    }
+
     /// Create whiteout file with name <name>.
     ///
+    /// With `xattr` set, the whiteout is a zero-length file marked with [`WHITEOUT_XATTR`]
+    /// instead of a 0/0 char device, which needs no `CAP_MKNOD`.
+    ///
     /// If this call is successful then the lookup count of the `Inode` associated with the returned
     /// `Entry` must be increased by 1.
     async fn create_whiteout(
@@ -25,13 +50,14 @@ pub trait Layer: Filesystem {
         ctx: Request,
         parent: Inode,
         name: &OsStr,
+        xattr: bool,
     ) -> Result<ReplyEntry> {
         // Use temp value to avoid moved 'parent'.
         let ino: u64 = parent;
         match self.lookup(ctx, ino, name).await {
             Ok(v) => {
-                // Find whiteout char dev.
-                if is_whiteout(&v.attr) {
+                // Find whiteout in either form.
+                if self.is_whiteout_attr(ctx, v.attr.ino, &v.attr).await? {
                     return Ok(v);
                 }
                 // Non-negative entry with inode larger than 0 indicates file exists.
@@ -56,6 +82,22 @@ pub trait Layer: Filesystem {
             }
         }
 
+        if xattr {
+            let mode = libc::S_IFREG | 0o644;
+            #[allow(clippy::unnecessary_cast)]
+            let entry = self.mknod(ctx, ino, name, mode as u32, 0).await?;
+            let marked = self
+                .setxattr(ctx, entry.attr.ino, OsStr::new(WHITEOUT_XATTR), b"y", 0, 0)
+                .await;
+            if let Err(e) = marked {
+                // Don't leave a plain empty file behind, it would show up in the overlay.
+                self.forget(ctx, entry.attr.ino, 1).await;
+                let _ = self.unlink(ctx, ino, name).await;
+                return Err(e);
+            }
+            return Ok(entry);
+        }
+
         // Try to create whiteout char device with 0/0 device number.
         let dev = libc::makedev(0, 0);
         let mode = libc::S_IFCHR | 0o777;
@@ -74,9 +116,13 @@ pub trait Layer: Filesystem {
                     self.forget(ctx, v.attr.ino, 1).await;
                 }
 
-                // Find whiteout so we can safely delete it.
-                if is_whiteout(&v.attr) {
-                    return self.unlink(ctx, ino, name).await;
+                // Find whiteout so we can safely delete it. It may be gone already when the
+                // whiteout collector got to it first.
+                if self.is_whiteout_attr(ctx, v.attr.ino, &v.attr).await? {
+                    return match self.unlink(ctx, ino, name).await {
+                        Err(e) if e.is_not_exist() => Ok(()),
+                        res => res,
+                    };
                 }
                 //  Non-negative entry with inode larger than 0 indicates file exists.
                 if v.attr.ino != 0 {
@@ -84,21 +130,49 @@ pub trait Layer: Filesystem {
                     return Err(Error::from_raw_os_error(libc::EINVAL).into());
                 }
             }
+            Err(e) if e.is_not_exist() => {}
             Err(e) => return Err(e),
         }
         Ok(())
     }
 
+    /// Remove the directory `name` of `parent` together with the whiteouts in it, failing with
+    /// `ENOTEMPTY` if it holds anything else.
+    async fn remove_whiteout_dir(&self, ctx: Request, parent: Inode, name: &OsStr) -> Result<()>;
+
     /// Check if the Inode is a whiteout file
     async fn is_whiteout(&self, ctx: Request, inode: Inode) -> Result<bool> {
         let rep = self.getattr(ctx, inode, None, 0).await?;
+        self.is_whiteout_attr(ctx, inode, &rep.attr).await
+    }
 
-        // Check attributes of the inode to see if it's a whiteout char device.
-        Ok(is_whiteout(&rep.attr))
+    /// Check if `inode` with attributes `attr` is a whiteout of either form.
+    async fn is_whiteout_attr(&self, ctx: Request, inode: Inode, attr: &FileAttr) -> Result<bool> {
+        // A whiteout char device is recognized from its attributes alone.
+        if is_whiteout(attr) {
+            return Ok(true);
+        }
+        if attr.kind.const_into_mode_t() & libc::S_IFMT != libc::S_IFREG || attr.size != 0 {
+            return Ok(false);
+        }
+        // An empty regular file is a whiteout only when it carries the marker xattr.
+        match self
+            .getxattr(ctx, inode, OsStr::new(WHITEOUT_XATTR), 0)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let ioerror: std::io::Error = e.into();
+                match ioerror.raw_os_error() {
+                    Some(libc::ENODATA) | Some(libc::ENOTSUP) | Some(libc::ENOSYS) => Ok(false),
+                    _ => Err(e),
+                }
+            }
+        }
     }
 
-    /// Set the directory to opaque.
-    async fn set_opaque(&self, ctx: Request, inode: Inode) -> Result<()> {
+    /// Set the directory to opaque by setting `xattr` to "y".
+    async fn set_opaque(&self, ctx: Request, inode: Inode, xattr: &str) -> Result<()> {
         // Use temp value to avoid moved 'parent'.
         let ino: u64 = inode;
 
@@ -110,8 +184,7 @@ pub trait Layer: Filesystem {
         }
         // A directory is made opaque by setting the xattr "trusted.overlay.opaque" to "y".
         // See ref: https://docs.kernel.org/filesystems/overlayfs.html#whiteouts-and-opaque-directories
-        self.setxattr(ctx, ino, OsStr::new(OPAQUE_XATTR), b"y", 0, 0)
-            .await
+        self.setxattr(ctx, ino, OsStr::new(xattr), b"y", 0, 0).await
     }
 
     /// Check if the directory is opaque.
@@ -124,6 +197,9 @@ pub trait Layer: Filesystem {
         if !is_dir(&attr.attr) {
             return Err(Error::from_raw_os_error(libc::ENOTDIR).into());
         }
+        if !self.supports_opaque() {
+            return Ok(false);
+        }
 
         // Return Result<is_opaque>.
         let check_attr = |inode: Inode, attr_name: &'static str, attr_size: u32| async move {
@@ -167,9 +243,10 @@ pub trait Layer: Filesystem {
             return Ok(true);
         }
 
-        // Also check for the unprivileged version of the xattr "trusted.overlay.opaque".
-        let is_opaque = check_attr(ino, PRIVILEGED_OPAQUE_XATTR, OPAQUE_XATTR_LEN).await?;
-        if is_opaque {
+        // Also check for the privileged version of the xattr "trusted.overlay.opaque".
+        if self.supports_trusted_xattr()
+            && check_attr(ino, PRIVILEGED_OPAQUE_XATTR, OPAQUE_XATTR_LEN).await?
+        {
             return Ok(true);
         }
 
@@ -186,6 +263,78 @@ impl Layer for PassthroughFs {
     fn root_inode(&self) -> Inode {
         1
     }
+
+    fn supports_whiteout(&self) -> bool {
+        // CAP_MKNOD
+        util::has_capability(27)
+    }
+
+    fn supports_opaque(&self) -> bool {
+        self.xattr_enabled()
+    }
+
+    fn supports_trusted_xattr(&self) -> bool {
+        // CAP_SYS_ADMIN
+        self.xattr_enabled() && util::has_capability(21)
+    }
+
+    async fn remove_whiteout_dir(&self, ctx: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        self.do_remove_whiteout_dir(ctx, parent, name, OsStr::new(WHITEOUT_XATTR))
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// How whiteouts and opaque directories are written to the upper layer, picked at mount from
+/// what the layer supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UpperStrategy {
+    /// Write whiteouts as empty files marked with [`WHITEOUT_XATTR`].
+    pub xattr_whiteout: bool,
+    /// Xattr marking new opaque directories, None if the layer can't have any.
+    pub opaque_xattr: Option<&'static str>,
+}
+
+impl UpperStrategy {
+    /// Follow `config` where `layer` supports it, and fall back to the other form where it
+    /// doesn't. Fails with `EOPNOTSUPP` if the layer can hold neither form of whiteout.
+    pub(crate) fn negotiate(layer: &impl Layer, config: &Config) -> std::io::Result<Self> {
+        let xattrs = layer.supports_opaque();
+        let xattr_whiteout = match (config.xattr_whiteout, layer.supports_whiteout(), xattrs) {
+            (_, false, false) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "upper layer supports neither char device nor xattr whiteouts",
+                ));
+            }
+            (false, false, true) => {
+                warn!("upper layer can't hold whiteout char devices, using xattr whiteouts");
+                true
+            }
+            (true, true, false) => {
+                warn!("upper layer has no xattrs, using whiteout char devices");
+                false
+            }
+            (configured, _, _) => configured,
+        };
+        let opaque_xattr = match config.opaque_xattr {
+            _ if !xattrs => {
+                warn!("upper layer has no xattrs, directories can't be made opaque");
+                None
+            }
+            OpaqueXattr::Trusted if !layer.supports_trusted_xattr() => {
+                warn!(
+                    "upper layer can't hold trusted xattrs, marking opaque directories with user xattrs"
+                );
+                Some(OpaqueXattr::User.name())
+            }
+            opaque => Some(opaque.name()),
+        };
+        Ok(Self {
+            xattr_whiteout,
+            opaque_xattr,
+        })
+    }
 }
 pub(crate) fn is_dir(st: &FileAttr) -> bool {
     st.kind.const_into_mode_t() & libc::S_IFMT == libc::S_IFDIR
@@ -210,7 +359,7 @@ mod test {
     use rfuse3::raw::{Filesystem as _, Request};
 
     use crate::{
-        overlayfs::layer::Layer,
+        overlayfs::layer::{Layer, OPAQUE_XATTR},
         passthrough::{PassthroughArgs, new_passthroughfs_layer},
         unwrap_or_skip_eperm,
     };
@@ -229,7 +378,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
@@ -237,7 +387,8 @@ mod test {
         let _ = unwrap_or_skip_eperm!(fs.init(Request::default()).await, "fs init");
         let white_name = OsStr::new(&"test");
         let res = unwrap_or_skip_eperm!(
-            fs.create_whiteout(Request::default(), 1, white_name).await,
+            fs.create_whiteout(Request::default(), 1, white_name, false)
+                .await,
             "create whiteout"
         );
 
@@ -261,7 +412,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
@@ -306,7 +458,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
@@ -328,7 +481,9 @@ mod test {
         let file_inode = entry.attr.ino;
 
         // set_opaque should return ENOTDIR error
-        let res = fs.set_opaque(Request::default(), file_inode).await;
+        let res = fs
+            .set_opaque(Request::default(), file_inode, OPAQUE_XATTR)
+            .await;
         assert!(res.is_err());
         let err = res.err().unwrap();
         let ioerr: std::io::Error = err.into();
@@ -0,0 +1,119 @@
+//! Bookkeeping for POSIX locks taken through the overlay.
+
+use std::collections::HashMap;
+
+/// One locked byte range, `end` is inclusive like in FUSE lock requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LockRange {
+    pub start: u64,
+    pub end: u64,
+    pub r#type: u32,
+}
+
+/// Byte-range locks granted on one overlay inode, grouped by lock owner.
+///
+/// The real layer decides about conflicts, this table only remembers what every owner was
+/// granted so the locks can be taken again on the upper inode when the file is copied up.
+#[derive(Default)]
+pub(crate) struct PosixLocks {
+    owners: HashMap<u64, Vec<LockRange>>,
+}
+
+impl PosixLocks {
+    /// Record a successful `setlk`, with the same split and replace semantics as `fcntl`.
+    pub fn apply(&mut self, owner: u64, start: u64, end: u64, r#type: u32) {
+        let ranges = self.owners.remove(&owner).unwrap_or_default();
+        let mut kept = Vec::with_capacity(ranges.len() + 2);
+        for r in ranges {
+            if r.end < start || r.start > end {
+                kept.push(r);
+                continue;
+            }
+            if r.start < start {
+                kept.push(LockRange {
+                    end: start - 1,
+                    ..r
+                });
+            }
+            if r.end > end {
+                kept.push(LockRange {
+                    start: end + 1,
+                    ..r
+                });
+            }
+        }
+        if r#type != libc::F_UNLCK as u32 {
+            kept.push(LockRange { start, end, r#type });
+        }
+        if !kept.is_empty() {
+            self.owners.insert(owner, kept);
+        }
+    }
+
+    /// Forget all locks of `owner`, returns whether it had any.
+    pub fn remove_owner(&mut self, owner: u64) -> bool {
+        self.owners.remove(&owner).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[LockRange])> {
+        self.owners.iter().map(|(o, r)| (*o, r.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET_MAX: u64 = i64::MAX as u64;
+    const RD: u32 = libc::F_RDLCK as u32;
+    const WR: u32 = libc::F_WRLCK as u32;
+    const UN: u32 = libc::F_UNLCK as u32;
+
+    fn ranges(locks: &PosixLocks, owner: u64) -> Vec<(u64, u64, u32)> {
+        let mut v: Vec<_> = locks
+            .iter()
+            .filter(|(o, _)| *o == owner)
+            .flat_map(|(_, r)| r.iter().map(|r| (r.start, r.end, r.r#type)))
+            .collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_apply_split_and_replace() {
+        let mut locks = PosixLocks::default();
+        locks.apply(1, 0, OFFSET_MAX, RD);
+        locks.apply(1, 10, 19, WR);
+        assert_eq!(
+            ranges(&locks, 1),
+            vec![(0, 9, RD), (10, 19, WR), (20, OFFSET_MAX, RD)]
+        );
+
+        locks.apply(1, 5, 14, UN);
+        assert_eq!(
+            ranges(&locks, 1),
+            vec![(0, 4, RD), (15, 19, WR), (20, OFFSET_MAX, RD)]
+        );
+
+        locks.apply(2, 0, 0, WR);
+        assert_eq!(ranges(&locks, 2), vec![(0, 0, WR)]);
+        assert_eq!(ranges(&locks, 1).len(), 3);
+    }
+
+    #[test]
+    fn test_unlock_all_forgets_owner() {
+        let mut locks = PosixLocks::default();
+        locks.apply(1, 0, 99, WR);
+        locks.apply(1, 0, OFFSET_MAX, UN);
+        assert!(locks.is_empty());
+
+        locks.apply(1, 0, 99, WR);
+        assert!(locks.remove_owner(1));
+        assert!(!locks.remove_owner(1));
+        assert!(locks.is_empty());
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Pre-built, memory-mapped index of the merged lower layers.
+//!
+//! Lower layers never change while mounted, so their merged view (whiteouts and opaque
+//! directories already applied) can be computed once by [`build`] and mapped read-only
+//! at mount time. The overlay then answers lookups and plain readdir of directories that
+//! only exist in lower layers straight from the index, and only allocates an
+//! `OverlayInode` for the entries that are actually looked up.
+//!
+//! All integers are little-endian. The file is laid out as:
+//!
+//! ```text
+//! header   magic "OVLIDX01", layers: u32, reserved: u32,
+//!          dir_count: u64, entry_count: u64, names_len: u64
+//! dirs     dir_count records sorted by path bytes:
+//!          path_off: u64, path_len: u32, reserved: u32, first_entry: u64, entry_count: u64
+//! entries  entry_count records, grouped by directory and sorted by name bytes:
+//!          name_off: u64, name_len: u32, mode: u32 (S_IFMT bits), layers: u64 (bit i = lower i)
+//! names    names_len bytes holding every path and name
+//! ```
+//!
+//! Directory paths use the overlay's own form: the root is the empty path, and every
+//! other directory is `/` followed by its components joined with `/`.
+
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+use rfuse3::FileType;
+
+use super::layer::{
+    OPAQUE_XATTR, PRIVILEGED_OPAQUE_XATTR, UNPRIVILEGED_OPAQUE_XATTR, WHITEOUT_XATTR,
+};
+use crate::util::filetype_from_mode;
+
+const MAGIC: &[u8; 8] = b"OVLIDX01";
+const HEADER_LEN: usize = 40;
+const DIR_LEN: usize = 32;
+const ENTRY_LEN: usize = 24;
+/// Layer membership is a bitmask, so at most this many lower layers can be indexed.
+pub const MAX_LAYERS: usize = 64;
+
+/// Build an index of the merged view of `lowers` and write it to `output`.
+///
+/// `lowers` are given top-most first, in the same order they are passed to the overlay.
+pub fn build<P: AsRef<Path>>(lowers: &[P], output: &Path) -> Result<()> {
+    if lowers.len() > MAX_LAYERS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("at most {MAX_LAYERS} lower layers can be indexed"),
+        ));
+    }
+    let mut builder = Builder::default();
+    let sources = lowers
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (i, p.as_ref().to_path_buf()))
+        .collect();
+    builder.walk(Vec::new(), sources)?;
+    builder.write(lowers.len(), output)
+}
+
+struct BuildEntry {
+    mode: u32,
+    layers: u64,
+    // Layer directories still merged into this entry, empty for non-directories.
+    sources: Vec<(usize, PathBuf)>,
+    // No further layer can contribute: non-directory, opaque directory or whited out.
+    closed: bool,
+    whiteout: bool,
+}
+
+// Name, file type bits and layer mask of one merged entry.
+type Entry = (Vec<u8>, u32, u64);
+
+#[derive(Default)]
+struct Builder {
+    // Every merged directory with its entries sorted by name.
+    dirs: Vec<(Vec<u8>, Vec<Entry>)>,
+}
+
+impl Builder {
+    // Merge one directory exactly like `OverlayInode::scan_childrens` does, then recurse.
+    fn walk(&mut self, path: Vec<u8>, sources: Vec<(usize, PathBuf)>) -> Result<()> {
+        let mut merged: BTreeMap<Vec<u8>, BuildEntry> = BTreeMap::new();
+        for (layer, dir) in sources {
+            for dirent in std::fs::read_dir(&dir)? {
+                let dirent = dirent?;
+                let name = dirent.file_name().as_bytes().to_vec();
+                let child = dirent.path();
+                let md = std::fs::symlink_metadata(&child)?;
+                let whiteout = (md.file_type().is_char_device() && md.rdev() == 0)
+                    || (md.is_file()
+                        && md.len() == 0
+                        && get_xattr(&child, WHITEOUT_XATTR).is_some());
+                let is_dir = md.is_dir();
+                let opaque = is_dir && is_opaque(&child);
+                match merged.get_mut(&name) {
+                    None => {
+                        let mut entry = BuildEntry {
+                            mode: md.mode() & libc::S_IFMT,
+                            layers: 1 << layer,
+                            sources: vec![],
+                            closed: whiteout || !is_dir || opaque,
+                            whiteout,
+                        };
+                        if is_dir {
+                            entry.sources.push((layer, child));
+                        }
+                        merged.insert(name, entry);
+                    }
+                    Some(entry) if !entry.closed => {
+                        if whiteout || !is_dir {
+                            entry.closed = true;
+                            continue;
+                        }
+                        entry.layers |= 1 << layer;
+                        entry.sources.push((layer, child));
+                        entry.closed = opaque;
+                    }
+                    Some(_) => {}
+                }
+            }
+            if is_opaque(&dir) {
+                break;
+            }
+        }
+
+        let mut entries = Vec::with_capacity(merged.len());
+        let mut subdirs = Vec::new();
+        for (name, entry) in merged {
+            if entry.whiteout {
+                continue;
+            }
+            entries.push((name.clone(), entry.mode, entry.layers));
+            if entry.mode == libc::S_IFDIR {
+                let mut sub = path.clone();
+                sub.push(b'/');
+                sub.extend_from_slice(&name);
+                subdirs.push((sub, entry.sources));
+            }
+        }
+        self.dirs.push((path, entries));
+        for (sub, sources) in subdirs {
+            self.walk(sub, sources)?;
+        }
+        Ok(())
+    }
+
+    fn write(mut self, layers: usize, output: &Path) -> Result<()> {
+        self.dirs.sort_by(|a, b| a.0.cmp(&b.0));
+        let entry_count: usize = self.dirs.iter().map(|d| d.1.len()).sum();
+
+        let mut names = Vec::new();
+        let mut dirs = Vec::with_capacity(self.dirs.len() * DIR_LEN);
+        let mut entries = Vec::with_capacity(entry_count * ENTRY_LEN);
+        let mut first = 0u64;
+        for (path, children) in &self.dirs {
+            dirs.extend_from_slice(&(names.len() as u64).to_le_bytes());
+            dirs.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            dirs.extend_from_slice(&0u32.to_le_bytes());
+            dirs.extend_from_slice(&first.to_le_bytes());
+            dirs.extend_from_slice(&(children.len() as u64).to_le_bytes());
+            names.extend_from_slice(path);
+            for (name, mode, layers) in children {
+                entries.extend_from_slice(&(names.len() as u64).to_le_bytes());
+                entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                entries.extend_from_slice(&mode.to_le_bytes());
+                entries.extend_from_slice(&layers.to_le_bytes());
+                names.extend_from_slice(name);
+            }
+            first += children.len() as u64;
+        }
+
+        let mut out = BufWriter::new(File::create(output)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&(layers as u32).to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&(self.dirs.len() as u64).to_le_bytes())?;
+        out.write_all(&(entry_count as u64).to_le_bytes())?;
+        out.write_all(&(names.len() as u64).to_le_bytes())?;
+        out.write_all(&dirs)?;
+        out.write_all(&entries)?;
+        out.write_all(&names)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+pub(super) fn is_opaque(path: &Path) -> bool {
+    [
+        OPAQUE_XATTR,
+        PRIVILEGED_OPAQUE_XATTR,
+        UNPRIVILEGED_OPAQUE_XATTR,
+    ]
+    .iter()
+    .any(|xattr| get_xattr(path, xattr).is_some_and(|v| v.eq_ignore_ascii_case(b"y")))
+}
+
+// Read xattr `name` of `path` without following symlinks, None if it isn't set.
+pub(super) fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let cname = CString::new(name).ok()?;
+    // SAFETY: both strings are NUL-terminated, a null buffer of size 0 only queries the size.
+    let len = unsafe { libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), std::ptr::null_mut(), 0) };
+    let mut value = vec![0u8; usize::try_from(len).ok()?];
+    // SAFETY: as above, and the buffer length is passed along.
+    let len = unsafe {
+        libc::lgetxattr(
+            cpath.as_ptr(),
+            cname.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    value.truncate(usize::try_from(len).ok()?);
+    Some(value)
+}
+
+/// A mapped index file, see the module documentation for the format.
+pub(crate) struct LowerIndex {
+    map: Mmap,
+    dir_count: usize,
+    entry_count: usize,
+    entries_off: usize,
+    names_off: usize,
+}
+
+/// One directory of the merged lower view.
+#[derive(Clone, Copy)]
+pub(crate) struct IndexDir<'a> {
+    index: &'a LowerIndex,
+    first: usize,
+    count: usize,
+}
+
+/// One entry of an indexed directory.
+pub(crate) struct IndexEntry<'a> {
+    pub name: &'a OsStr,
+    pub kind: FileType,
+    /// Bit `i` is set when lower layer `i` contributes to this entry.
+    pub layers: u64,
+}
+
+impl LowerIndex {
+    /// Map the index at `path`, which must have been built from exactly `layers` lowers.
+    pub(crate) fn open(path: &Path, layers: usize) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: like the lower layers it describes, the index must not be modified while
+        // mounted. Every access below is bounds checked against the mapped length.
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{path:?}: {msg}"));
+        if map.len() < HEADER_LEN || &map[..8] != MAGIC {
+            return Err(invalid("not a lower index"));
+        }
+        if read_u32(&map, 8) as usize != layers {
+            return Err(invalid("built for a different number of lower layers"));
+        }
+        let dir_count = read_u64(&map, 16) as usize;
+        let entry_count = read_u64(&map, 24) as usize;
+        let names_len = read_u64(&map, 32) as usize;
+        let entries_off = dir_count
+            .checked_mul(DIR_LEN)
+            .and_then(|n| n.checked_add(HEADER_LEN));
+        let names_off = entry_count
+            .checked_mul(ENTRY_LEN)
+            .zip(entries_off)
+            .and_then(|(n, off)| n.checked_add(off));
+        match (entries_off, names_off) {
+            (Some(entries_off), Some(names_off))
+                if names_off.checked_add(names_len) == Some(map.len()) =>
+            {
+                Ok(Self {
+                    map,
+                    dir_count,
+                    entry_count,
+                    entries_off,
+                    names_off,
+                })
+            }
+            _ => Err(invalid("truncated or corrupted")),
+        }
+    }
+
+    fn name(&self, off: usize, len: usize) -> Option<&OsStr> {
+        let start = self.names_off.checked_add(off)?;
+        let bytes = self.map.get(start..start.checked_add(len)?)?;
+        Some(OsStr::from_bytes(bytes))
+    }
+
+    fn dir_path(&self, i: usize) -> Option<&OsStr> {
+        let rec = HEADER_LEN + i * DIR_LEN;
+        self.name(
+            read_u64(&self.map, rec) as usize,
+            read_u32(&self.map, rec + 8) as usize,
+        )
+    }
+
+    fn entry(&self, i: usize) -> Option<IndexEntry<'_>> {
+        let rec = self.entries_off + i * ENTRY_LEN;
+        let name = self.name(
+            read_u64(&self.map, rec) as usize,
+            read_u32(&self.map, rec + 8) as usize,
+        )?;
+        Some(IndexEntry {
+            name,
+            kind: filetype_from_mode(read_u32(&self.map, rec + 12)),
+            layers: read_u64(&self.map, rec + 16),
+        })
+    }
+
+    /// Find the merged listing of the lower directory at overlay path `path`.
+    pub(crate) fn dir(&self, path: &OsStr) -> Option<IndexDir<'_>> {
+        let i = bsearch(self.dir_count, path.as_bytes(), |i| self.dir_path(i))?;
+        let rec = HEADER_LEN + i * DIR_LEN;
+        let first = read_u64(&self.map, rec + 16) as usize;
+        let count = read_u64(&self.map, rec + 24) as usize;
+        (first.checked_add(count)? <= self.entry_count).then_some(IndexDir {
+            index: self,
+            first,
+            count,
+        })
+    }
+}
+
+impl<'a> IndexDir<'a> {
+    pub(crate) fn lookup(&self, name: &OsStr) -> Option<IndexEntry<'a>> {
+        let i = bsearch(self.count, name.as_bytes(), |i| {
+            self.index.entry(self.first + i).map(|e| e.name)
+        })?;
+        self.index.entry(self.first + i)
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = IndexEntry<'a>> + 'a {
+        let (index, first, count) = (self.index, self.first, self.count);
+        (first..first + count).filter_map(move |i| index.entry(i))
+    }
+}
+
+// Binary search over `len` records ordered by their raw name bytes.
+fn bsearch<'a>(len: usize, key: &[u8], name: impl Fn(usize) -> Option<&'a OsStr>) -> Option<usize> {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match name(mid)?.as_bytes().cmp(key) {
+            std::cmp::Ordering::Less => lo = mid + 1,
+            std::cmp::Ordering::Greater => hi = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    buf.get(off..off + 4)
+        .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u64(buf: &[u8], off: usize) -> u64 {
+    buf.get(off..off + 8)
+        .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+}
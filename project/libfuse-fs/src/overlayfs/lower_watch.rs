@@ -0,0 +1,224 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Watching lower layers for changes made underneath the mount.
+//!
+//! Lower layers are expected to stay untouched while mounted, and the overlay keeps serving
+//! the directories it loaded from them. When a layer is updated anyway, e.g. a development
+//! tree mounted as a lower layer, [`LowerWatcher`] notices through inotify and records which
+//! directories changed. The overlay applies those changes when it next serves a request:
+//! changed directories are resolved against the layers again and the kernel is told to drop
+//! its cached entries and attributes.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use tracing::{debug, warn};
+
+use super::shared_attrs::SharedAttrCache;
+
+/// How long the watcher thread sleeps when no event is queued.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Changes seen in one directory of a lower layer, by its path relative to the layer root.
+#[derive(Debug, Default)]
+pub(crate) struct DirChange {
+    /// Entries were created, removed or renamed, or the directory itself changed.
+    pub entries: bool,
+    /// Entries whose content or attributes changed.
+    pub modified: HashSet<OsString>,
+}
+
+/// Changes recorded since they were last taken.
+#[derive(Debug, Default)]
+pub(crate) struct LowerChanges {
+    /// Events were lost, every loaded directory has to be resolved again.
+    pub rescan: bool,
+    pub dirs: HashMap<PathBuf, DirChange>,
+}
+
+impl LowerChanges {
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.rescan && self.dirs.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    stop: AtomicBool,
+    dirty: AtomicBool,
+    changes: Mutex<LowerChanges>,
+}
+
+/// Inotify watches on every directory of the lower layers, stopped when dropped.
+pub(crate) struct LowerWatcher {
+    shared: Arc<Shared>,
+    // Held while changes are applied, so concurrent requests don't resolve the same
+    // directories twice.
+    pub(crate) applying: tokio::sync::Mutex<()>,
+}
+
+impl LowerWatcher {
+    /// Watch the layers rooted at `roots`. Attributes cached for a changed layer in `cache`
+    /// are dropped as well.
+    pub(crate) fn start(roots: Vec<PathBuf>, cache: Option<Arc<SharedAttrCache>>) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let mut state = WatchState {
+            inotify,
+            watches: HashMap::new(),
+        };
+        for (layer, root) in roots.iter().enumerate() {
+            state.watch_tree(layer, root, Path::new(""))?;
+        }
+        debug!("watching {} lower directories", state.watches.len());
+
+        let shared = Arc::new(Shared::default());
+        let thread_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("overlay-lower-watch".into())
+            .spawn(move || state.run(&roots, cache.as_deref(), &thread_shared))?;
+        Ok(Self {
+            shared,
+            applying: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Changes recorded since the last call, empty if there are none.
+    pub(crate) fn take(&self) -> LowerChanges {
+        if !self.shared.dirty.swap(false, Ordering::AcqRel) {
+            return LowerChanges::default();
+        }
+        std::mem::take(&mut *self.shared.changes.lock().unwrap())
+    }
+}
+
+impl Drop for LowerWatcher {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+struct WatchState {
+    inotify: Inotify,
+    // Layer index and directory path, relative to the layer root, of every watch.
+    watches: HashMap<WatchDescriptor, (usize, PathBuf)>,
+}
+
+impl WatchState {
+    fn mask() -> AddWatchFlags {
+        AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_ONLYDIR
+            | AddWatchFlags::IN_DONT_FOLLOW
+    }
+
+    // Watch `rel` below `root` and every directory beneath it, without following symlinks.
+    fn watch_tree(&mut self, layer: usize, root: &Path, rel: &Path) -> Result<()> {
+        let mut pending = vec![rel.to_path_buf()];
+        while let Some(rel) = pending.pop() {
+            let dir = root.join(&rel);
+            let wd = match self.inotify.add_watch(&dir, Self::mask()) {
+                Ok(wd) => wd,
+                // Removed again before we got to it.
+                Err(Errno::ENOENT | Errno::ENOTDIR) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.watches.insert(wd, (layer, rel.clone()));
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    pending.push(rel.join(entry.file_name()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self, roots: &[PathBuf], cache: Option<&SharedAttrCache>, shared: &Shared) {
+        while !shared.stop.load(Ordering::Relaxed) {
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => {
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("lower layer watch failed, changes are no longer seen: {e}");
+                    return;
+                }
+            };
+
+            let mut changes = shared.changes.lock().unwrap();
+            let mut changed_layers = HashSet::new();
+            for event in events {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    changes.rescan = true;
+                    changed_layers.extend(0..roots.len());
+                    continue;
+                }
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    self.watches.remove(&event.wd);
+                    continue;
+                }
+                let Some((layer, rel)) = self.watches.get(&event.wd).cloned() else {
+                    continue;
+                };
+                changed_layers.insert(layer);
+
+                let dir = changes.dirs.entry(rel.clone()).or_default();
+                match event.name {
+                    Some(name)
+                        if event.mask.intersects(
+                            AddWatchFlags::IN_CREATE
+                                | AddWatchFlags::IN_DELETE
+                                | AddWatchFlags::IN_MOVED_FROM
+                                | AddWatchFlags::IN_MOVED_TO,
+                        ) =>
+                    {
+                        dir.entries = true;
+                        if event
+                            .mask
+                            .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                            && event.mask.contains(AddWatchFlags::IN_ISDIR)
+                            && let Err(e) = self.watch_tree(layer, &roots[layer], &rel.join(&name))
+                        {
+                            warn!("failed to watch new lower directory {name:?}: {e}");
+                        }
+                    }
+                    Some(name) => {
+                        dir.modified.insert(name);
+                    }
+                    // The directory itself changed, e.g. it was marked opaque.
+                    None => dir.entries = true,
+                }
+            }
+            drop(changes);
+            shared.dirty.store(true, Ordering::Release);
+
+            if let Some(cache) = cache {
+                for layer in changed_layers {
+                    if let Err(e) = cache.invalidate_layer(&roots[layer]) {
+                        warn!("failed to drop cached attributes of changed layer: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Supervision of the overlays served by one daemon.
+//!
+//! A daemon serving the root filesystems of many containers must not go down with one of
+//! them. [`OverlayManager`] mounts each overlay under an id and watches its session: a
+//! request that panics, or a session task that dies, only affects that mount, which is then
+//! failed, remounted or escalated according to the [`PanicPolicy`]. Every step is reported
+//! as a [`MountEvent`] to the receivers of [`OverlayManager::subscribe`], e.g. the node
+//! agent.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::FutureExt;
+use rfuse3::raw::MountHandle;
+use tokio::sync::{Notify, broadcast};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use super::{MountError, OverlayArgs, ShutdownHandle, mount_fs_with_shutdown};
+
+/// Events kept for subscribers that lag behind.
+const EVENT_CAPACITY: usize = 256;
+
+/// Arguments of a managed mount, kept to mount it again.
+pub type ManagedArgs = OverlayArgs<PathBuf, PathBuf, PathBuf, String, String, Vec<PathBuf>>;
+
+/// What [`OverlayManager`] does with a mount whose request or session panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Unmount it, the other mounts keep running.
+    FailMount,
+    /// Unmount it and mount it again, waiting `backoff` times the attempt number before
+    /// each attempt. Failed after `max_attempts`.
+    Remount {
+        max_attempts: u32,
+        backoff: Duration,
+    },
+    /// Unmount every mount of the manager, for the node agent to restart the daemon.
+    Escalate,
+}
+
+/// State changes of managed mounts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountEvent {
+    /// A request or the session of the mount panicked.
+    Panicked { id: String, message: String },
+    /// The session ended with an error.
+    SessionFailed { id: String, error: String },
+    /// The mount is served again after `attempt` remounts.
+    Remounted { id: String, attempt: u32 },
+    /// The mount was given up.
+    Failed { id: String, error: String },
+    /// The mount was unmounted by [`OverlayManager::unmount`] or from outside.
+    Unmounted { id: String },
+    /// A panic of the mount brought down every mount, see [`PanicPolicy::Escalate`].
+    Escalated { id: String },
+}
+
+struct Managed {
+    stop: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+struct Shared {
+    policy: PanicPolicy,
+    shutdown_timeout: Duration,
+    mounts: Mutex<HashMap<String, Managed>>,
+    events: broadcast::Sender<MountEvent>,
+    #[cfg(feature = "prometheus")]
+    metrics: super::metrics::MetricsRegistry,
+}
+
+impl Shared {
+    fn emit(&self, event: MountEvent) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    fn export(&self, id: &str, shutdown: &ShutdownHandle) {
+        #[cfg(feature = "prometheus")]
+        self.metrics.register(id, shutdown.clone().into());
+    }
+
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    fn unexport(&self, id: &str) {
+        #[cfg(feature = "prometheus")]
+        self.metrics.unregister(id);
+    }
+}
+
+/// Mounts overlays and contains their panics, see the module documentation.
+#[derive(Clone)]
+pub struct OverlayManager {
+    shared: Arc<Shared>,
+}
+
+impl OverlayManager {
+    /// `shutdown_timeout` bounds how long a mount being unmounted waits for its requests.
+    pub fn new(policy: PanicPolicy, shutdown_timeout: Duration) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            shared: Arc::new(Shared {
+                policy,
+                shutdown_timeout,
+                mounts: Mutex::new(HashMap::new()),
+                events,
+                #[cfg(feature = "prometheus")]
+                metrics: Default::default(),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MountEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// Registry exporting the statistics of every managed mount, labeled by id.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(&self) -> super::metrics::MetricsRegistry {
+        self.shared.metrics.clone()
+    }
+
+    /// Ids of the mounts being supervised.
+    pub fn mounts(&self) -> Vec<String> {
+        self.shared.mounts.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Mount `args` as `id` and supervise it until [`Self::unmount`].
+    pub async fn mount(&self, id: impl Into<String>, args: ManagedArgs) -> Result<(), MountError> {
+        let id = id.into();
+        if self.shared.mounts.lock().unwrap().contains_key(&id) {
+            return Err(MountError::Setup(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("mount {id} exists"),
+            )));
+        }
+        let (handle, shutdown) = mount_fs_with_shutdown(args.clone()).await?;
+        self.shared.export(&id, &shutdown);
+        let stop = Arc::new(Notify::new());
+        let task = tokio::spawn(supervise(
+            Arc::downgrade(&self.shared),
+            id.clone(),
+            args,
+            handle,
+            shutdown,
+            Arc::clone(&stop),
+        ));
+        self.shared
+            .mounts
+            .lock()
+            .unwrap()
+            .insert(id, Managed { stop, task });
+        Ok(())
+    }
+
+    /// Drain and unmount `id`, false if it is not managed.
+    pub async fn unmount(&self, id: &str) -> bool {
+        let Some(managed) = self.shared.mounts.lock().unwrap().remove(id) else {
+            return false;
+        };
+        managed.stop.notify_one();
+        let _ = managed.task.await;
+        true
+    }
+}
+
+/// How a session stopped being served.
+enum Outcome {
+    Stop,
+    Ended,
+    SessionFailed(std::io::Error),
+    // The session task itself panicked, the mount is left behind.
+    SessionPanicked(String),
+    RequestPanicked,
+}
+
+async fn supervise(
+    shared: std::sync::Weak<Shared>,
+    id: String,
+    args: ManagedArgs,
+    mut handle: MountHandle,
+    mut shutdown: ShutdownHandle,
+    stop: Arc<Notify>,
+) {
+    let mut attempt = 0;
+    loop {
+        let outcome = tokio::select! {
+            _ = stop.notified() => Outcome::Stop,
+            _ = shutdown.panicked() => Outcome::RequestPanicked,
+            res = AssertUnwindSafe(&mut handle).catch_unwind() => match res {
+                Ok(Ok(())) => Outcome::Ended,
+                Ok(Err(e)) => Outcome::SessionFailed(e),
+                Err(panic) => Outcome::SessionPanicked(panic_message(&*panic)),
+            },
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        match outcome {
+            Outcome::Stop => {
+                if let Err(e) = shutdown.unmount(handle, shared.shutdown_timeout).await {
+                    warn!("failed to unmount {id}: {e}");
+                }
+                shared.unexport(&id);
+                shared.emit(MountEvent::Unmounted { id });
+                return;
+            }
+            Outcome::Ended => {
+                info!("overlay {id} was unmounted");
+                forget(&shared, &id);
+                shared.emit(MountEvent::Unmounted { id });
+                return;
+            }
+            Outcome::SessionFailed(e) => {
+                error!("session of overlay {id} failed: {e}");
+                force_unmount(&args.mountpoint, args.privileged);
+                shared.emit(MountEvent::SessionFailed {
+                    id: id.clone(),
+                    error: e.to_string(),
+                });
+            }
+            Outcome::SessionPanicked(message) => {
+                error!("session of overlay {id} panicked: {message}");
+                shutdown.shutdown(shared.shutdown_timeout).await;
+                force_unmount(&args.mountpoint, args.privileged);
+                shared.emit(MountEvent::Panicked {
+                    id: id.clone(),
+                    message,
+                });
+            }
+            Outcome::RequestPanicked => {
+                error!("a request of overlay {id} panicked");
+                if let Err(e) = shutdown.unmount(handle, shared.shutdown_timeout).await {
+                    warn!("failed to unmount {id}: {e}");
+                }
+                shared.emit(MountEvent::Panicked {
+                    id: id.clone(),
+                    message: "request panicked".into(),
+                });
+            }
+        }
+
+        let (max_attempts, backoff) = match shared.policy {
+            PanicPolicy::Remount {
+                max_attempts,
+                backoff,
+            } => (max_attempts, backoff),
+            PanicPolicy::FailMount => {
+                forget(&shared, &id);
+                shared.emit(MountEvent::Failed {
+                    id,
+                    error: "failed by policy".into(),
+                });
+                return;
+            }
+            PanicPolicy::Escalate => {
+                forget(&shared, &id);
+                shared.emit(MountEvent::Escalated { id: id.clone() });
+                let others: Vec<_> = shared.mounts.lock().unwrap().drain().collect();
+                for (_, managed) in others {
+                    managed.stop.notify_one();
+                }
+                return;
+            }
+        };
+        let mut last_error = None;
+        let remounted = loop {
+            if attempt >= max_attempts {
+                break None;
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff * attempt).await;
+            // The upper layer is still locked if requests of the old overlay are stuck.
+            let args = ManagedArgs {
+                force: true,
+                ..args.clone()
+            };
+            match mount_fs_with_shutdown(args).await {
+                Ok(mounted) => break Some(mounted),
+                Err(e) => {
+                    warn!("remount {attempt} of overlay {id} failed: {e}");
+                    last_error = Some(e.to_string());
+                }
+            }
+        };
+        match remounted {
+            Some((new_handle, new_shutdown)) => {
+                handle = new_handle;
+                shutdown = new_shutdown;
+                shared.export(&id, &shutdown);
+                shared.emit(MountEvent::Remounted {
+                    id: id.clone(),
+                    attempt,
+                });
+            }
+            None => {
+                forget(&shared, &id);
+                shared.emit(MountEvent::Failed {
+                    id,
+                    error: last_error.unwrap_or_else(|| "out of remount attempts".into()),
+                });
+                return;
+            }
+        }
+    }
+}
+
+// Drop a mount the supervisor ends on its own from the manager.
+fn forget(shared: &Shared, id: &str) {
+    shared.mounts.lock().unwrap().remove(id);
+    shared.unexport(id);
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".into()
+    }
+}
+
+// Detach a mount whose session is gone, its handle can't unmount it anymore.
+fn force_unmount(mountpoint: &Path, privileged: bool) {
+    let result = if privileged {
+        CString::new(mountpoint.as_os_str().as_bytes())
+            .map_err(std::io::Error::from)
+            .and_then(|path| {
+                // SAFETY: `path` is a valid C string.
+                if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            })
+    } else {
+        std::process::Command::new("fusermount3")
+            .arg("-uz")
+            .arg(mountpoint)
+            .status()
+            .and_then(|status| {
+                status
+                    .success()
+                    .then_some(())
+                    .ok_or_else(|| std::io::Error::other(format!("fusermount3 {status}")))
+            })
+    };
+    if let Err(e) = result {
+        warn!("failed to detach {}: {e}", mountpoint.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_message() {
+        let panic = std::panic::catch_unwind(|| panic!("dangling {}", 1)).unwrap_err();
+        assert_eq!(panic_message(&*panic), "dangling 1");
+        let panic = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*panic), "static");
+    }
+
+    #[tokio::test]
+    async fn test_mount_failure_is_not_managed() {
+        let manager = OverlayManager::new(PanicPolicy::FailMount, Duration::from_secs(1));
+        let dir = tempfile::tempdir().unwrap();
+        let args = ManagedArgs {
+            mountpoint: dir.path().join("missing"),
+            upperdir: None,
+            lowerdir: vec![dir.path().join("missing-lower")],
+            privileged: false,
+            mapping: None,
+            name: None,
+            allow_other: false,
+            force: false,
+            layer_limit: Default::default(),
+            passthrough: false,
+            concurrency: Default::default(),
+            tuning: Default::default(),
+            sandbox: false,
+        };
+        assert!(manager.mount("c1", args).await.is_err());
+        assert!(manager.mounts().is_empty());
+        assert!(!manager.unmount("c1").await);
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Export of overlay and layer statistics in the Prometheus text format.
+//!
+//! Overlays are added to a [`MetricsRegistry`] under a mount name, which becomes the
+//! `mount` label of their samples. The registry renders the samples of all its mounts with
+//! [`MetricsRegistry::render`], for an existing exporter to include, or serves them itself
+//! on `/metrics` with [`MetricsRegistry::serve`].
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+use rfuse3::raw::{Filesystem, Request};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use super::io_accounting::{CgroupIoStats, IoAccounting};
+use super::layer::Layer;
+use super::{ShutdownHandle, WriteAmplification};
+
+/// Longest request header read from a scraper.
+const MAX_REQUEST: usize = 8192;
+
+/// Statistics source of one overlay, see [`super::OverlayFs::metrics`]. It stays usable
+/// after the overlay was moved into a session.
+#[derive(Clone)]
+pub struct OverlayMetrics {
+    pub(super) shutdown: ShutdownHandle,
+    pub(super) has_upper: bool,
+    pub(super) io_accounting: Option<Arc<IoAccounting>>,
+}
+
+impl From<ShutdownHandle> for OverlayMetrics {
+    /// Metrics of the overlay `handle` was taken from, e.g. by
+    /// [`super::mount_fs_with_shutdown`], without per-cgroup IO.
+    fn from(handle: ShutdownHandle) -> Self {
+        Self {
+            has_upper: handle.has_upper,
+            shutdown: handle,
+            io_accounting: None,
+        }
+    }
+}
+
+struct LayerSample {
+    layer: String,
+    inodes: usize,
+    handles: usize,
+    queued_forgets: usize,
+    // Bytes in total, free and available to unprivileged users, None if statfs failed.
+    space: Option<(u64, u64, u64)>,
+    files: Option<(u64, u64)>,
+}
+
+struct MountSample {
+    in_flight: usize,
+    panics: usize,
+    open_handles: usize,
+    copy_ups: usize,
+    copy_up_bytes: u64,
+    amplification: WriteAmplification,
+    cgroups: Vec<(String, CgroupIoStats)>,
+    layers: Vec<LayerSample>,
+}
+
+impl OverlayMetrics {
+    async fn sample(&self) -> MountSample {
+        let shutdown = &self.shutdown;
+        let copy_ups = shutdown.copy_ups.all();
+        let mut cgroups = self
+            .io_accounting
+            .as_ref()
+            .map(|acct| acct.snapshot().into_iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        cgroups.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut layers = Vec::new();
+        for (i, layer) in shutdown.layers.iter().enumerate() {
+            let name = match (self.has_upper, i) {
+                (true, 0) => "upper".to_string(),
+                (true, i) => format!("lower{}", i - 1),
+                (false, i) => format!("lower{i}"),
+            };
+            let statfs = layer
+                .statfs(Request::default(), layer.root_inode())
+                .await
+                .inspect_err(|e| debug!("statfs of layer {name} failed: {e}"))
+                .ok();
+            layers.push(LayerSample {
+                layer: name,
+                inodes: layer.inode_count().await,
+                handles: layer.handle_count().await,
+                queued_forgets: layer.queued_forgets(),
+                space: statfs.as_ref().map(|st| {
+                    let frsize = u64::from(st.frsize);
+                    (st.blocks * frsize, st.bfree * frsize, st.bavail * frsize)
+                }),
+                files: statfs.as_ref().map(|st| (st.files, st.ffree)),
+            });
+        }
+        MountSample {
+            in_flight: shutdown.drain.in_flight(),
+            panics: shutdown.panics(),
+            open_handles: shutdown.handles.lock().await.len(),
+            copy_ups: copy_ups.len(),
+            copy_up_bytes: copy_ups.iter().map(|c| c.copied).sum(),
+            amplification: shutdown.write_amplification(),
+            cgroups,
+            layers,
+        }
+    }
+}
+
+/// Overlays whose statistics are exported, by mount name.
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    mounts: Arc<Mutex<BTreeMap<String, OverlayMetrics>>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Export `metrics` as `mount`, replacing what was registered under that name.
+    pub fn register(&self, mount: impl Into<String>, metrics: OverlayMetrics) {
+        self.mounts.lock().unwrap().insert(mount.into(), metrics);
+    }
+
+    /// Stop exporting `mount`, false if it wasn't registered.
+    pub fn unregister(&self, mount: &str) -> bool {
+        self.mounts.lock().unwrap().remove(mount).is_some()
+    }
+
+    /// Current samples of every registered overlay in the Prometheus text format.
+    pub async fn render(&self) -> String {
+        let mounts = self.mounts.lock().unwrap().clone();
+        let mut samples = Vec::with_capacity(mounts.len());
+        for (mount, metrics) in mounts {
+            samples.push((mount, metrics.sample().await));
+        }
+        render(&samples)
+    }
+
+    /// Answer `GET /metrics` on `listener` until the task is dropped.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let registry = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = registry.answer(stream).await {
+                    warn!("metrics request of {peer} failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn answer(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+        let mut fields = line.split(|&b| b == b' ');
+        let (status, body) = match (fields.next(), fields.next()) {
+            (Some(b"GET"), Some(b"/metrics")) => ("200 OK", self.render().await),
+            _ => ("404 Not Found", String::new()),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+// Writes the families one after the other, each with the samples of every mount.
+struct Writer<'a> {
+    out: String,
+    samples: &'a [(String, MountSample)],
+}
+
+impl Writer<'_> {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP libfuse_{name} {help}");
+        let _ = writeln!(self.out, "# TYPE libfuse_{name} {kind}");
+    }
+
+    fn mounts<V: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: impl Fn(&MountSample) -> V,
+    ) {
+        self.family(name, kind, help);
+        for (mount, sample) in self.samples {
+            let _ = writeln!(
+                self.out,
+                "libfuse_{name}{{mount=\"{}\"}} {}",
+                escape(mount),
+                value(sample)
+            );
+        }
+    }
+
+    fn layers<V: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: impl Fn(&LayerSample) -> Option<V>,
+    ) {
+        self.family(name, kind, help);
+        for (mount, sample) in self.samples {
+            for layer in &sample.layers {
+                if let Some(v) = value(layer) {
+                    let _ = writeln!(
+                        self.out,
+                        "libfuse_{name}{{mount=\"{}\",layer=\"{}\"}} {v}",
+                        escape(mount),
+                        layer.layer
+                    );
+                }
+            }
+        }
+    }
+
+    fn cgroups(&mut self, name: &str, help: &str, value: impl Fn(&CgroupIoStats) -> u64) {
+        self.family(name, "counter", help);
+        for (mount, sample) in self.samples {
+            for (cgroup, stats) in &sample.cgroups {
+                let _ = writeln!(
+                    self.out,
+                    "libfuse_{name}{{mount=\"{}\",cgroup=\"{}\"}} {}",
+                    escape(mount),
+                    escape(cgroup),
+                    value(stats)
+                );
+            }
+        }
+    }
+}
+
+fn render(samples: &[(String, MountSample)]) -> String {
+    let mut w = Writer {
+        out: String::new(),
+        samples,
+    };
+    w.mounts(
+        "overlay_requests_in_flight",
+        "gauge",
+        "Requests being served.",
+        |s| s.in_flight,
+    );
+    w.mounts(
+        "overlay_request_panics_total",
+        "counter",
+        "Requests that panicked.",
+        |s| s.panics,
+    );
+    w.mounts(
+        "overlay_open_handles",
+        "gauge",
+        "Files and directories open through the overlay.",
+        |s| s.open_handles,
+    );
+    w.mounts(
+        "overlay_copy_ups_in_progress",
+        "gauge",
+        "Files being copied up.",
+        |s| s.copy_ups,
+    );
+    w.mounts(
+        "overlay_copy_up_bytes",
+        "gauge",
+        "Bytes copied so far by the running copy-ups.",
+        |s| s.copy_up_bytes,
+    );
+    w.mounts(
+        "overlay_request_written_bytes_total",
+        "counter",
+        "Bytes written by requests, copy-ups excluded.",
+        |s| s.amplification.written,
+    );
+    w.mounts(
+        "overlay_copied_up_bytes_total",
+        "counter",
+        "Bytes written to the upper layer by copy-ups that ended.",
+        |s| s.amplification.copied_up,
+    );
+    w.mounts(
+        "overlay_cloned_up_bytes_total",
+        "counter",
+        "Bytes cloned from lower files by copy-ups that ended.",
+        |s| s.amplification.cloned_up,
+    );
+    w.mounts(
+        "overlay_copy_ups_total",
+        "counter",
+        "Copy-ups of regular files that ended.",
+        |s| s.amplification.copy_ups,
+    );
+    w.cgroups(
+        "overlay_read_bytes_total",
+        "Bytes read, by cgroup of the requester.",
+        |s| s.read_bytes,
+    );
+    w.cgroups(
+        "overlay_reads_total",
+        "Read requests, by cgroup of the requester.",
+        |s| s.read_ops,
+    );
+    w.cgroups(
+        "overlay_written_bytes_total",
+        "Bytes written, by cgroup of the requester.",
+        |s| s.write_bytes,
+    );
+    w.cgroups(
+        "overlay_writes_total",
+        "Write requests, by cgroup of the requester.",
+        |s| s.write_ops,
+    );
+    w.layers(
+        "passthrough_inodes",
+        "gauge",
+        "Inodes known to the layer.",
+        |l| Some(l.inodes),
+    );
+    w.layers(
+        "passthrough_open_handles",
+        "gauge",
+        "Files and directories open in the layer.",
+        |l| Some(l.handles),
+    );
+    w.layers(
+        "passthrough_queued_forgets",
+        "gauge",
+        "Inodes with forgets waiting to be sent to the layer.",
+        |l| Some(l.queued_forgets),
+    );
+    w.layers(
+        "passthrough_size_bytes",
+        "gauge",
+        "Size of the filesystem holding the layer.",
+        |l| l.space.map(|s| s.0),
+    );
+    w.layers(
+        "passthrough_free_bytes",
+        "gauge",
+        "Free space of the filesystem holding the layer.",
+        |l| l.space.map(|s| s.1),
+    );
+    w.layers(
+        "passthrough_avail_bytes",
+        "gauge",
+        "Space of the filesystem holding the layer available to unprivileged users.",
+        |l| l.space.map(|s| s.2),
+    );
+    w.layers(
+        "passthrough_files",
+        "gauge",
+        "Inodes of the filesystem holding the layer.",
+        |l| l.files.map(|f| f.0),
+    );
+    w.layers(
+        "passthrough_files_free",
+        "gauge",
+        "Free inodes of the filesystem holding the layer.",
+        |l| l.files.map(|f| f.1),
+    );
+    w.out
+}
+
+// Label values escape backslashes, quotes and line feeds.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let sample = MountSample {
+            in_flight: 2,
+            panics: 0,
+            open_handles: 1,
+            copy_ups: 0,
+            copy_up_bytes: 0,
+            amplification: WriteAmplification {
+                written: 10,
+                copied_up: 4096,
+                copy_ups: 1,
+                cloned_up: 0,
+            },
+            cgroups: vec![(
+                "/kubepods/pod\"a\"".into(),
+                CgroupIoStats {
+                    read_bytes: 4096,
+                    read_ops: 1,
+                    ..Default::default()
+                },
+            )],
+            layers: vec![LayerSample {
+                layer: "upper".into(),
+                inodes: 3,
+                handles: 1,
+                queued_forgets: 0,
+                space: None,
+                files: Some((100, 40)),
+            }],
+        };
+        let text = render(&[("c1".into(), sample)]);
+        assert!(text.contains(
+            "# TYPE libfuse_overlay_requests_in_flight gauge\n\
+             libfuse_overlay_requests_in_flight{mount=\"c1\"} 2\n"
+        ));
+        assert!(text.contains(
+            "libfuse_overlay_read_bytes_total{mount=\"c1\",cgroup=\"/kubepods/pod\\\"a\\\"\"} 4096\n"
+        ));
+        assert!(text.contains("libfuse_overlay_copied_up_bytes_total{mount=\"c1\"} 4096\n"));
+        assert!(text.contains("libfuse_passthrough_inodes{mount=\"c1\",layer=\"upper\"} 3\n"));
+        assert!(text.contains("libfuse_passthrough_files_free{mount=\"c1\",layer=\"upper\"} 40\n"));
+        // Failed statfs leaves the family without samples.
+        assert!(!text.contains("libfuse_passthrough_size_bytes{"));
+    }
+}
@@ -4,9 +4,28 @@
 
 #![allow(missing_docs)]
 mod async_io;
+mod backing;
+pub mod check;
 pub mod config;
+mod copy_up;
+pub mod dedupe;
+pub mod device;
+pub(crate) mod forget;
 mod inode_store;
+mod io_accounting;
 mod layer;
+mod lock;
+pub mod lower_index;
+mod lower_watch;
+pub mod manager;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+mod mount_args;
+pub mod oci_layer;
+mod self_test;
+pub mod shared_attrs;
+mod shutdown;
+pub mod squash;
 mod utils;
 
 //mod tempfile;
@@ -15,12 +34,19 @@ use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::future::Future;
 use std::io::{Error, Result};
-use std::path::Path;
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 
-use config::Config;
+use config::{Config, XattrEpermAction, XattrEpermPolicy};
+use device::EmulatedDevice;
 use futures::StreamExt as _;
+use rfuse3::notify::Notify;
+use rfuse3::passthrough::BackingFiles;
 use rfuse3::raw::reply::{
-    DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyEntry, ReplyOpen, ReplyStatFs,
+    DirectoryEntry, DirectoryEntryPlus, FileAttr, ReplyAttr, ReplyEntry, ReplyLock, ReplyOpen,
+    ReplyStatFs, ReplyXAttr,
 };
 use rfuse3::raw::{Filesystem, Request, Session};
 use std::sync::{Arc, Weak};
@@ -28,17 +54,37 @@ use tracing::debug;
 use tracing::error;
 use tracing::info;
 use tracing::trace;
+use tracing::warn;
 
-use rfuse3::{Errno, FileType, MountOptions, mode_from_kind_and_perm};
-const SLASH_ASCII: char = '/';
+use rfuse3::{Errno, FileType, MountOptions, SetAttr, Timestamp, mode_from_kind_and_perm};
+const SLASH_ASCII: u8 = b'/';
+// Flags of rename2, same values as the Linux `RENAME_*` flags.
+const RENAME_NOREPLACE: u32 = 1;
+const RENAME_EXCHANGE: u32 = 2;
 use futures::future::join_all;
 use futures::stream::iter;
 
 use crate::passthrough::{PassthroughArgs, PassthroughFs, new_passthroughfs_layer};
+use crate::sandbox::Sandbox;
 use crate::util::convert_stat64_to_file_attr;
+use crate::util::interrupt::Interrupts;
+use backing::{Backings, Passthrough};
+pub use copy_up::{CopyUpProgress, WriteAmplification};
+use copy_up::{CopyUpTracker, ReflinkProbe};
 use inode_store::InodeStore;
-use layer::Layer;
+pub use io_accounting::CgroupIoStats;
+use io_accounting::IoAccounting;
+use layer::{Layer, UpperStrategy};
+use lock::PosixLocks;
+use lower_index::LowerIndex;
+use lower_watch::LowerWatcher;
+pub use mount_args::MountError;
 use rfuse3::raw::logfs::LoggingFileSystem;
+pub use self_test::{SELF_TEST_DIR, SelfTest, SelfTestResult, SelfTestStatus};
+pub use shared_attrs::SharedAttrCache;
+use shutdown::Drain;
+pub use shutdown::{ShutdownHandle, ShutdownReport};
+pub use squash::LayerLimit;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use tokio::sync::{Mutex, RwLock};
@@ -49,6 +95,11 @@ pub type Handle = u64;
 type BoxedLayer = PassthroughFs;
 //type BoxedFileSystem = Box<dyn FileSystem<Inode = Inode, Handle = Handle> + Send + Sync>;
 const INODE_ALLOC_BATCH: u64 = 0x1_0000_0000;
+// Directories `apply_deletions` works in at the same time.
+const DELETION_PARALLELISM: usize = 16;
+
+// File capabilities, see `capabilities(7)`.
+const CAPABILITY_XATTR: &[u8] = b"security.capability";
 // RealInode represents one inode object in specific layer.
 // Also, each RealInode maps to one Entry, which should be 'forgotten' after drop.
 // Important note: do not impl Clone trait for it or refcount will be messed up.
@@ -67,19 +118,30 @@ pub(crate) struct RealInode {
 // #[derive(Default)]
 pub(crate) struct OverlayInode {
     // Inode hash table, map from 'name' to 'OverlayInode'.
-    pub childrens: Mutex<HashMap<String, Arc<OverlayInode>>>,
+    pub childrens: Mutex<HashMap<OsString, Arc<OverlayInode>>>,
     pub parent: Mutex<Weak<OverlayInode>>,
     // Backend inodes from all layers.
     pub real_inodes: Mutex<Vec<Arc<RealInode>>>,
     // Inode number.
     pub inode: u64,
-    pub path: RwLock<String>,
-    pub name: RwLock<String>,
+    pub path: RwLock<OsString>,
+    pub name: RwLock<OsString>,
     pub lookups: AtomicU64,
     // Node is whiteout-ed.
     pub whiteout: AtomicBool,
     // Directory is loaded.
     pub loaded: AtomicBool,
+    // POSIX locks granted on this node, kept to move them along on copy-up.
+    pub posix_locks: Mutex<PosixLocks>,
+    // Overlay-level mtime and ctime of a directory with `Config::strict_dir_times`, set on
+    // first use.
+    pub dir_times: Mutex<Option<(Timestamp, Timestamp)>>,
+    // Bumped when an entry of the directory is created, removed or renamed, tells listings
+    // of open handles that they are stale.
+    pub dir_version: AtomicU64,
+    // Type of the top-most real inode, when known from the layer that found it. Plain
+    // readdir lists children with it instead of a getattr each, see `kind`.
+    pub kind: std::sync::Mutex<Option<FileType>>,
 }
 
 #[derive(Default)]
@@ -93,10 +155,11 @@ pub struct OverlayFs {
     config: Config,
     lower_layers: Vec<Arc<PassthroughFs>>,
     upper_layer: Option<Arc<PassthroughFs>>,
+    lower_index: Option<LowerIndex>,
     // All inodes in FS.
-    inodes: RwLock<InodeStore>,
+    inodes: InodeStore,
     // Open file handles.
-    handles: Mutex<HashMap<u64, Arc<HandleData>>>,
+    handles: Arc<Mutex<HashMap<u64, Arc<HandleData>>>>,
     next_handle: AtomicU64,
     writeback: AtomicBool,
     no_open: AtomicBool,
@@ -104,6 +167,103 @@ pub struct OverlayFs {
     killpriv_v2: AtomicBool,
     perfile_dax: AtomicBool,
     root_inodes: u64,
+    // Random per instance, tells mounts apart in names and per-layer statistics.
+    fsid: u64,
+    // Set when `Config::cgroup_io_accounting` is enabled.
+    io_accounting: Option<Arc<IoAccounting>>,
+    // Kernel cache invalidations after layer changes, see `set_notify`.
+    notify: Option<Notify>,
+    // Backing files of passed through opens, see `set_backing_files`.
+    backings: Option<Backings>,
+    // Lock of the upper directory taken by `mount_fs`, held as long as the overlay lives.
+    upper_lock: Option<std::fs::File>,
+    // Regular files being copied up.
+    copy_ups: CopyUpTracker,
+    // Reads waiting on their layer, given up on when interrupted.
+    interrupts: Interrupts,
+    // Upper devices known not to clone files, see `Config::no_reflink`.
+    reflinks: ReflinkProbe,
+    // Requests being served, refused once `ShutdownHandle::shutdown` was called.
+    drain: Arc<Drain>,
+    // Set with `Config::watch_lower_layers`.
+    lower_watcher: Option<LowerWatcher>,
+    // How whiteouts and opaque directories are written to the upper layer.
+    upper_strategy: Option<UpperStrategy>,
+    // Set with `Config::self_test_interval`.
+    self_test: Option<SelfTest>,
+}
+
+/// Entry of the list given to [`OverlayFs::apply_deletions`], paths are relative to the
+/// overlay root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deletion {
+    /// Remove the entry and, for a directory, everything below it.
+    Remove(PathBuf),
+    /// Keep the directory but hide everything the lower layers hold below it.
+    Clear(PathBuf),
+}
+
+impl<P: Into<PathBuf>> From<P> for Deletion {
+    fn from(path: P) -> Self {
+        Deletion::Remove(path.into())
+    }
+}
+
+/// Outcome of [`OverlayFs::apply_deletions`].
+#[derive(Debug, Default)]
+pub struct DeletionReport {
+    /// Entries removed or cleared.
+    pub applied: usize,
+    /// Paths that didn't exist, including below removed directories.
+    pub absent: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+/// Callback of [`CopyUpPathOptions::progress`], given the path of an entry just copied up,
+/// relative to the overlay root, and the totals so far.
+pub type CopyUpPathCallback = Box<dyn Fn(&Path, &CopyUpPathReport) + Send + Sync>;
+
+/// Limits and progress reporting of [`OverlayFs::copy_up_path`].
+pub struct CopyUpPathOptions {
+    /// Files copied up at the same time.
+    pub parallelism: usize,
+    pub progress: Option<CopyUpPathCallback>,
+}
+
+impl Default for CopyUpPathOptions {
+    fn default() -> Self {
+        Self {
+            parallelism: 4,
+            progress: None,
+        }
+    }
+}
+
+/// Outcome of [`OverlayFs::copy_up_path`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CopyUpPathReport {
+    /// Entries copied up, the ones already in the upper layer aren't counted.
+    pub entries: u64,
+    /// Size of the regular files copied up.
+    pub bytes: u64,
+}
+
+/// Which part of the overlay a [`LayerStatFs`] describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerRole {
+    Upper,
+    /// Lower layer by position, 0 being the top-most one.
+    Lower(usize),
+}
+
+/// Usage of the filesystem backing one layer, see [`OverlayFs::layer_statfs`].
+#[derive(Debug, Clone)]
+pub struct LayerStatFs {
+    pub role: LayerRole,
+    pub root: PathBuf,
+    /// `f_fsid` of the backing filesystem, shared by layers living on the same one.
+    pub fsid: u64,
+    pub statfs: ReplyStatFs,
 }
 
 // This is a wrapper of one inode in specific layer, It can't impl Clone trait.
@@ -118,9 +278,15 @@ struct HandleData {
     node: Arc<OverlayInode>,
     //offset: libc::off_t,
     real_handle: Option<RealHandle>,
+    // Set for opens served by an emulated device, `real_handle` is None then.
+    device: Option<EmulatedDevice>,
     // Cache the directory entries for stable readdir offsets.
     // The snapshot contains all necessary info to avoid re-accessing childrens map.
-    dir_snapshot: Mutex<Option<Vec<DirectoryEntryPlus>>>,
+    dir_snapshot: Mutex<Option<Arc<Vec<DirectoryEntryPlus>>>>,
+    // Plain readdir listing, served from the lower index when possible, see `do_readdir`.
+    plain_snapshot: Mutex<Option<Arc<Vec<DirectoryEntry>>>>,
+    // `dir_version` of the node the snapshots were taken at.
+    snapshot_version: AtomicU64,
 }
 
 // RealInode is a wrapper of one inode in specific layer.
@@ -171,10 +337,9 @@ impl RealInode {
             Ok(v1) => Ok(Some(v1)),
             Err(e) => match e.raw_os_error() {
                 Some(raw_error) => {
-                    if raw_error == libc::ENOENT
-                        || raw_error == libc::ENAMETOOLONG
-                        || raw_error == libc::ESTALE
-                    {
+                    // ENAMETOOLONG is a real error here: stat works on an inode, not on a name,
+                    // so it must not be mistaken for a missing entry.
+                    if raw_error == libc::ENOENT || raw_error == libc::ESTALE {
                         return Ok(None);
                     }
                     Err(e)
@@ -188,12 +353,11 @@ impl RealInode {
     async fn lookup_child_ignore_enoent(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
     ) -> Result<Option<ReplyEntry>> {
-        let cname = OsStr::new(name);
         // Real inode must have a layer.
         let layer = self.layer.as_ref();
-        match layer.lookup(ctx, self.inode, cname).await {
+        match layer.lookup(ctx, self.inode, name).await {
             Ok(v) => {
                 // Negative entry also indicates missing entry.
                 if v.attr.ino == 0 {
@@ -216,7 +380,7 @@ impl RealInode {
 
     // Find child inode in same layer under this directory(Self).
     // Return None if not found.
-    async fn lookup_child(&self, ctx: Request, name: &str) -> Result<Option<RealInode>> {
+    async fn lookup_child(&self, ctx: Request, name: &OsStr) -> Result<Option<RealInode>> {
         if self.whiteout {
             return Ok(None);
         }
@@ -250,7 +414,7 @@ impl RealInode {
     }
 
     // Read directory entries from specific RealInode, error out if it's not directory.
-    async fn readdir(&self, ctx: Request) -> Result<HashMap<String, RealInode>> {
+    async fn readdir(&self, ctx: Request) -> Result<HashMap<OsString, RealInode>> {
         // Deleted inode should not be read.
         if self.whiteout {
             return Err(Error::from_raw_os_error(libc::ENOENT));
@@ -282,7 +446,11 @@ impl RealInode {
                     Some(raw_error) => {
                         if raw_error == libc::ENOSYS {
                             // We can still call readdir with inode if opendir is not supported in this layer.
-                            ReplyOpen { fh: 0, flags: 0 }
+                            ReplyOpen {
+                                fh: 0,
+                                flags: 0,
+                                backing_id: None,
+                            }
                         } else {
                             return Err(e.into());
                         }
@@ -309,7 +477,7 @@ impl RealInode {
         let a_map = child_names.entries.map(|entery| async {
             match entery {
                 Ok(dire) => {
-                    let dname = dire.name.into_string().unwrap();
+                    let dname = dire.name;
                     if dname == "." || dname == ".." {
                         // Skip . and .. entries.
                         return Ok(());
@@ -333,16 +501,14 @@ impl RealInode {
         Ok(re)
     }
 
-    async fn create_whiteout(&self, ctx: Request, name: &str) -> Result<RealInode> {
+    async fn create_whiteout(&self, ctx: Request, name: &OsStr, xattr: bool) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
 
-        // from &str to &OsStr
-        let name_osstr = OsStr::new(name);
         let entry = self
             .layer
-            .create_whiteout(ctx, self.inode, name_osstr)
+            .create_whiteout(ctx, self.inode, name, xattr)
             .await?;
 
         // Wrap whiteout to RealInode.
@@ -359,16 +525,12 @@ impl RealInode {
         })
     }
 
-    async fn mkdir(&self, ctx: Request, name: &str, mode: u32, umask: u32) -> Result<RealInode> {
+    async fn mkdir(&self, ctx: Request, name: &OsStr, mode: u32, umask: u32) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
 
-        let name_osstr = OsStr::new(name);
-        let entry = self
-            .layer
-            .mkdir(ctx, self.inode, name_osstr, mode, umask)
-            .await?;
+        let entry = self.layer.mkdir(ctx, self.inode, name, mode, umask).await?;
 
         // update node's first_layer
         Ok(RealInode {
@@ -387,14 +549,13 @@ impl RealInode {
     async fn create(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         flags: u32,
     ) -> Result<(RealInode, Option<u64>)> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let create_rep = self
             .layer
             .create(ctx, self.inode, name, mode, flags)
@@ -419,7 +580,7 @@ impl RealInode {
     async fn mknod(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         rdev: u32,
         _umask: u32,
@@ -427,7 +588,6 @@ impl RealInode {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let rep = self.layer.mknod(ctx, self.inode, name, mode, rdev).await?;
         Ok(RealInode {
             layer: self.layer.clone(),
@@ -442,11 +602,10 @@ impl RealInode {
         })
     }
 
-    async fn link(&self, ctx: Request, ino: u64, name: &str) -> Result<RealInode> {
+    async fn link(&self, ctx: Request, ino: u64, name: &OsStr) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let entry = self.layer.link(ctx, ino, self.inode, name).await?;
 
         let opaque = if utils::is_dir(&entry.attr.kind) {
@@ -468,12 +627,15 @@ impl RealInode {
     }
 
     // Create a symlink in self directory.
-    async fn symlink(&self, ctx: Request, link_name: &str, filename: &str) -> Result<RealInode> {
+    async fn symlink(
+        &self,
+        ctx: Request,
+        link_name: &OsStr,
+        filename: &OsStr,
+    ) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let link_name = OsStr::new(link_name);
-        let filename = OsStr::new(filename);
         let entry = self
             .layer
             .symlink(ctx, self.inode, filename, link_name)
@@ -495,12 +657,7 @@ impl RealInode {
 
 impl Drop for RealInode {
     fn drop(&mut self) {
-        let layer = Arc::clone(&self.layer);
-        let inode = self.inode;
-        tokio::spawn(async move {
-            let ctx = Request::default();
-            layer.forget(ctx, inode, 1).await;
-        });
+        forget::forget_later(&self.layer, self.inode, 1);
     }
 }
 
@@ -511,35 +668,40 @@ impl OverlayInode {
             parent: Mutex::new(Weak::new()),
             real_inodes: Mutex::new(vec![]),
             inode: 0,
-            path: RwLock::new(String::new()),
-            name: RwLock::new(String::new()),
+            path: RwLock::new(OsString::new()),
+            name: RwLock::new(OsString::new()),
             lookups: AtomicU64::new(0),
             whiteout: AtomicBool::new(false),
             loaded: AtomicBool::new(false),
+            posix_locks: Mutex::new(PosixLocks::default()),
+            dir_times: Mutex::new(None),
+            dir_version: AtomicU64::new(0),
+            kind: std::sync::Mutex::new(None),
         }
     }
     // Allocate new OverlayInode based on one RealInode,
     // inode number is always 0 since only OverlayFs has global unique inode allocator.
     pub async fn new_from_real_inode(
-        name: &str,
+        name: &OsStr,
         ino: u64,
-        path: String,
+        path: OsString,
         real_inode: RealInode,
     ) -> Self {
         let mut new = OverlayInode::new();
         new.inode = ino;
         new.path = path.into();
-        new.name = name.to_string().into();
+        new.name = name.to_os_string().into();
         new.whiteout.store(real_inode.whiteout, Ordering::Relaxed);
+        new.kind = std::sync::Mutex::new(real_inode.stat.as_ref().map(|st| st.attr.kind));
         new.lookups = AtomicU64::new(1);
         new.real_inodes = Mutex::new(vec![real_inode.into()]);
         new
     }
 
     pub async fn new_from_real_inodes(
-        name: &str,
+        name: &OsStr,
         ino: u64,
-        path: String,
+        path: OsString,
         real_inodes: Vec<RealInode>,
     ) -> Result<Self> {
         if real_inodes.is_empty() {
@@ -548,6 +710,7 @@ impl OverlayInode {
         }
 
         let mut first = true;
+        let mut path = Some(path);
         let mut new = Self::new();
         for ri in real_inodes {
             let whiteout = ri.whiteout;
@@ -559,7 +722,8 @@ impl OverlayInode {
 
             if first {
                 first = false;
-                new = Self::new_from_real_inode(name, ino, path.clone(), ri).await;
+                new =
+                    Self::new_from_real_inode(name, ino, path.take().unwrap_or_default(), ri).await;
 
                 // This is whiteout, no need to check lower layers.
                 if whiteout {
@@ -611,6 +775,16 @@ impl OverlayInode {
         Err(Error::from_raw_os_error(libc::ENOENT))
     }
 
+    /// Type of the node, only stat-ing it when the layer that found it didn't tell.
+    pub async fn kind(&self, ctx: Request) -> Result<FileType> {
+        if let Some(kind) = *self.kind.lock().unwrap() {
+            return Ok(kind);
+        }
+        let kind = self.stat64(ctx).await?.attr.kind;
+        *self.kind.lock().unwrap() = Some(kind);
+        Ok(kind)
+    }
+
     pub async fn is_dir(&self, ctx: Request) -> Result<bool> {
         let st = self.stat64(ctx).await?;
         Ok(utils::is_dir(&st.attr.kind))
@@ -648,14 +822,18 @@ impl OverlayInode {
         Ok((layer, ro))
     }
 
-    // Self is directory, fill all childrens.
-    pub async fn scan_childrens(self: &Arc<Self>, ctx: Request) -> Result<Vec<OverlayInode>> {
+    // Self is directory, fill all childrens. Each child comes with its name, to be used as
+    // its key in `childrens`.
+    pub async fn scan_childrens(
+        self: &Arc<Self>,
+        ctx: Request,
+    ) -> Result<Vec<(OsString, OverlayInode)>> {
         let st = self.stat64(ctx).await?;
         if !utils::is_dir(&st.attr.kind) {
             return Err(Error::from_raw_os_error(libc::ENOTDIR));
         }
 
-        let mut all_layer_inodes: HashMap<String, Vec<RealInode>> = HashMap::new();
+        let mut all_layer_inodes: HashMap<OsString, Vec<RealInode>> = HashMap::new();
         // read out directories from each layer
         // Scan from upper layer to lower layer.
         for ri in self.real_inodes.lock().await.iter() {
@@ -671,13 +849,13 @@ impl OverlayInode {
             };
 
             if !utils::is_dir(&stat.attr.kind) {
-                debug!("{} is not a directory", self.path.read().await);
+                debug!("{:?} is not a directory", self.path.read().await);
                 // not directory
                 break;
             }
 
             // Read all entries from one layer.
-            let entries: HashMap<String, RealInode> = ri.readdir(ctx).await?;
+            let entries: HashMap<OsString, RealInode> = ri.readdir(ctx).await?;
 
             // Merge entries from one layer to all_layer_inodes.
             for (name, inode) in entries {
@@ -694,18 +872,19 @@ impl OverlayInode {
 
             // if opaque, stop here
             if ri.opaque {
-                debug!("directory {} is opaque", self.path.read().await);
+                debug!("directory {:?} is opaque", self.path.read().await);
                 break;
             }
         }
 
         // Construct OverlayInode for each entry.
-        let mut childrens = vec![];
+        let parent_path = self.path.read().await.clone();
+        let mut childrens = Vec::with_capacity(all_layer_inodes.len());
         for (name, real_inodes) in all_layer_inodes {
             // Inode numbers are not allocated yet.
-            let path = format!("{}/{}", self.path.read().await, name);
-            let new = Self::new_from_real_inodes(name.as_str(), 0, path, real_inodes).await?;
-            childrens.push(new);
+            let path = utils::join_path(&parent_path, &name);
+            let new = Self::new_from_real_inodes(&name, 0, path, real_inodes).await?;
+            childrens.push((name, new));
         }
 
         Ok(childrens)
@@ -726,10 +905,12 @@ impl OverlayInode {
     /// Crucially, it preserves the original directory's ownership (UID/GID) and permissions
     /// by using the [`do_getattr_helper`][crate::passthrough::PassthroughFs::do_getattr_helper] and
     /// [`do_mkdir_helper`][crate::passthrough::PassthroughFs::do_mkdir_helper] functions.
+    /// Its xattrs are copied too, with `xattr_eperm` deciding what to do about refused ones.
     pub async fn create_upper_dir(
         self: Arc<Self>,
         ctx: Request,
         mode_umask: Option<(u32, u32)>,
+        xattr_eperm: &XattrEpermPolicy,
     ) -> Result<()> {
         // To preserve original ownership, we must get the raw, unmapped host attributes.
         // We achieve this by calling `do_getattr_helper`, which is specifically designed
@@ -760,7 +941,7 @@ impl OverlayInode {
         };
 
         if !pnode.in_upper_layer().await {
-            Box::pin(pnode.clone().create_upper_dir(ctx, None)).await?; // recursive call
+            Box::pin(pnode.clone().create_upper_dir(ctx, None, xattr_eperm)).await?; // recursive call
         }
         let child: Arc<Mutex<Option<RealInode>>> = Arc::new(Mutex::new(None));
         let c_name = self.name.read().await.clone();
@@ -830,6 +1011,21 @@ impl OverlayInode {
                                 }
                             }
                         };
+                        if let Err(e) = copy_up_xattrs(
+                            ctx,
+                            xattr_eperm,
+                            (&self_layer, self_inode),
+                            (&ri.layer, ri.inode),
+                        )
+                        .await
+                        {
+                            // Don't leave a copy without its xattrs behind, a retry would hit EEXIST.
+                            let _ = parent_ri
+                                .layer
+                                .rmdir(ctx, parent_ri.inode, OsStr::new(&c_name))
+                                .await;
+                            return Err(e);
+                        }
                         // create directory here
                         child.lock().await.replace(ri);
                     }
@@ -858,6 +1054,8 @@ impl OverlayInode {
         let mut inodes = self.real_inodes.lock().await;
         // Update self according to upper attribute.
         self.whiteout.store(ri.whiteout, Ordering::Relaxed);
+        // A whiteout can be replaced by an entry of any type.
+        *self.kind.lock().unwrap() = ri.stat.as_ref().map(|st| st.attr.kind);
 
         // Push the new real inode to the front of vector.
         let mut new = vec![Arc::new(ri)];
@@ -895,6 +1093,17 @@ impl OverlayInode {
         }
     }
 
+    /// Real inodes of this node in the lower layers.
+    pub async fn lower_inodes(&self) -> Vec<Arc<RealInode>> {
+        self.real_inodes
+            .lock()
+            .await
+            .iter()
+            .filter(|ri| !ri.in_upper_layer)
+            .cloned()
+            .collect()
+    }
+
     pub async fn first_layer_inode(&self) -> (Arc<BoxedLayer>, bool, u64) {
         let all_inodes = self.real_inodes.lock().await;
         let first = all_inodes.first();
@@ -904,16 +1113,19 @@ impl OverlayInode {
         }
     }
 
-    pub async fn child(&self, name: &str) -> Option<Arc<OverlayInode>> {
+    pub async fn child(&self, name: &OsStr) -> Option<Arc<OverlayInode>> {
         self.childrens.lock().await.get(name).cloned()
     }
 
-    pub async fn remove_child(&self, name: &str) -> Option<Arc<OverlayInode>> {
+    pub async fn remove_child(&self, name: &OsStr) -> Option<Arc<OverlayInode>> {
         self.childrens.lock().await.remove(name)
     }
 
-    pub async fn insert_child(&self, name: &str, node: Arc<OverlayInode>) {
-        self.childrens.lock().await.insert(name.to_string(), node);
+    pub async fn insert_child(&self, name: &OsStr, node: Arc<OverlayInode>) {
+        self.childrens
+            .lock()
+            .await
+            .insert(name.to_os_string(), node);
     }
 
     /// Handles operations on the upper layer inode of an `OverlayInode` in a thread-safe manner.
@@ -978,6 +1190,183 @@ fn entry_type_from_mode(mode: libc::mode_t) -> u8 {
         _ => libc::DT_UNKNOWN,
     }
 }
+
+/// Copy the xattrs of a lower inode to its freshly created upper copy.
+///
+/// Overlay-private xattrs (opaque and whiteout markers) are left behind. When the upper layer
+/// refuses an xattr with `EPERM`, `policy` decides per namespace whether to fail, skip it or
+/// store it under `user.*` instead.
+///
+/// File capabilities must be copied after the data, writes drop them. The kernel converts
+/// them between the v2 and the v3 (namespaced root) encodings as they are read and set, so
+/// they keep working in the user namespace of the daemon. Capabilities of a root outside of
+/// it can't be read and are skipped, as are capabilities the upper layer refuses, their copy
+/// under `user.*` would grant nothing.
+async fn copy_up_xattrs(
+    ctx: Request,
+    policy: &XattrEpermPolicy,
+    (lower_layer, lower_inode): (&BoxedLayer, Inode),
+    (upper_layer, upper_inode): (&BoxedLayer, Inode),
+) -> Result<()> {
+    let names = match lower_layer.listxattr(ctx, lower_inode, 0).await {
+        Ok(ReplyXAttr::Size(0)) => return Ok(()),
+        Ok(ReplyXAttr::Size(size)) => match lower_layer.listxattr(ctx, lower_inode, size).await? {
+            ReplyXAttr::Data(data) => data,
+            ReplyXAttr::Size(_) => return Err(Error::from_raw_os_error(libc::EIO)),
+        },
+        Ok(ReplyXAttr::Data(data)) => data,
+        Err(e) => {
+            let e: std::io::Error = e.into();
+            // Nothing to copy when the lower layer has no xattr support.
+            if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::ENOTSUP)) {
+                return Ok(());
+            }
+            return Err(e);
+        }
+    };
+
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        if [
+            b"trusted.overlay.".as_slice(),
+            b"user.overlay.",
+            b"user.fuseoverlayfs.",
+        ]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        let is_capability = name == CAPABILITY_XATTR;
+        let name = OsStr::from_bytes(name);
+        let value = match get_xattr_value(ctx, lower_layer, lower_inode, name).await {
+            Ok(value) => value,
+            // EOVERFLOW when owned by a root not mapped in our user namespace.
+            Err(e) if is_capability => {
+                warn!("copy up: skipping file capabilities of inode {lower_inode}: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let e = match upper_layer
+            .do_setxattr_helper(upper_inode, name, &value, 0)
+            .await
+        {
+            Ok(()) => continue,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => e,
+            // The upper layer can't store xattrs at all.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+                debug!("copy up: upper layer doesn't support xattr {name:?}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let user_name = match policy.action(name.as_bytes()) {
+            XattrEpermAction::Skip | XattrEpermAction::MapToUser if is_capability => {
+                warn!("copy up: skipping file capabilities of inode {lower_inode}: {e}");
+                continue;
+            }
+            XattrEpermAction::Skip => {
+                warn!("copy up: skipping xattr {name:?} of inode {lower_inode}: {e}");
+                continue;
+            }
+            XattrEpermAction::MapToUser if !name.as_bytes().starts_with(b"user.") => {
+                let rest = name.as_bytes().splitn(2, |&b| b == b'.').nth(1);
+                let mut user_name = OsString::from("user.");
+                user_name.push(OsStr::from_bytes(rest.unwrap_or_default()));
+                user_name
+            }
+            _ => return Err(e),
+        };
+        trace!("copy up: storing xattr {name:?} as {user_name:?}");
+        upper_layer
+            .do_setxattr_helper(upper_inode, &user_name, &value, 0)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Value of the xattr `name` of `inode` in `layer`.
+async fn get_xattr_value(
+    ctx: Request,
+    layer: &BoxedLayer,
+    inode: Inode,
+    name: &OsStr,
+) -> Result<Vec<u8>> {
+    Ok(match layer.getxattr(ctx, inode, name, 0).await? {
+        ReplyXAttr::Size(0) => Vec::new(),
+        ReplyXAttr::Size(size) => match layer.getxattr(ctx, inode, name, size).await? {
+            ReplyXAttr::Data(data) => data.to_vec(),
+            ReplyXAttr::Size(_) => return Err(Error::from_raw_os_error(libc::EIO)),
+        },
+        ReplyXAttr::Data(data) => data.to_vec(),
+    })
+}
+
+/// Next range of `inode` holding data at or after `offset`, None when only a hole is left.
+/// Fails when the layer can't tell holes apart, see `lseek(2)` for `SEEK_DATA`.
+async fn next_data_range(
+    ctx: Request,
+    layer: &BoxedLayer,
+    inode: Inode,
+    fh: u64,
+    offset: u64,
+) -> Result<Option<Range<u64>>> {
+    let start = match layer
+        .lseek(ctx, inode, fh, offset, libc::SEEK_DATA as u32)
+        .await
+    {
+        Ok(reply) => reply.offset,
+        Err(e) => {
+            let e: std::io::Error = e.into();
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+    };
+    let end = layer
+        .lseek(ctx, inode, fh, start, libc::SEEK_HOLE as u32)
+        .await?
+        .offset;
+    Ok(Some(start..end))
+}
+
+/// Readdir offset of the entry `name`, hashed from the name so it is the same in every
+/// listing of the directory. "." and ".." come first with offsets 1 and 2.
+fn name_cookie(name: &OsStr) -> i64 {
+    // FNV-1a, leaving room below for "." and ".." and above for collisions.
+    let hash = name
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash >> 2) as i64 + 3
+}
+
+/// Order `entries` by their `name_cookie` and set it as their offset. A listing resumed
+/// after an entry thus continues with the ones ordered after it, even if the directory
+/// changed in between, like with the hash ordered directories of ext4. Colliding names get
+/// the next free cookie, in name order.
+fn sort_by_cookie<T>(
+    entries: &mut [T],
+    name: impl Fn(&T) -> &OsString,
+    offset: impl Fn(&mut T) -> &mut i64,
+) {
+    entries.sort_by_cached_key(|e| (name_cookie(name(e)), name(e).clone()));
+    let mut last = 2;
+    for e in entries {
+        last = name_cookie(name(e)).max(last + 1);
+        *offset(e) = last;
+    }
+}
+
+/// Index in `entries` sorted by offset to resume a listing at `offset` from.
+fn resume_at<T>(entries: &[T], offset: u64, entry_offset: impl Fn(&T) -> i64) -> usize {
+    entries.partition_point(|e| entry_offset(e) as u64 <= offset)
+}
+
 impl OverlayFs {
     pub fn new(
         upper: Option<Arc<BoxedLayer>>,
@@ -985,12 +1374,50 @@ impl OverlayFs {
         params: Config,
         root_inode: u64,
     ) -> Result<Self> {
+        let lower_index = params
+            .lower_index
+            .as_deref()
+            .map(|path| LowerIndex::open(path, lowers.len()))
+            .transpose()?;
+        let io_accounting = params.cgroup_io_accounting.then(Arc::default);
+        let upper_strategy = upper
+            .as_deref()
+            .map(|layer| UpperStrategy::negotiate(layer, &params))
+            .transpose()?;
+        if let Some(cache) = &params.shared_attr_cache {
+            for layer in &lowers {
+                layer.share_attrs(Some(cache.clone()))?;
+            }
+        }
+        let lower_watcher = if params.watch_lower_layers {
+            if lower_index.is_some() {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "lower layers can't be watched with a lower index",
+                ));
+            }
+            let roots = lowers.iter().map(|l| l.root_dir().to_path_buf()).collect();
+            Some(LowerWatcher::start(
+                roots,
+                params.shared_attr_cache.clone(),
+            )?)
+        } else {
+            None
+        };
+        let fsid = uuid::Uuid::new_v4().as_u128() as u64;
+        let self_test = match params.self_test_interval {
+            Some(interval) if upper.is_some() => {
+                Some(SelfTest::start(params.mountpoint.clone(), interval, fsid)?)
+            }
+            _ => None,
+        };
         Ok(OverlayFs {
             config: params,
             lower_layers: lowers,
             upper_layer: upper,
-            inodes: RwLock::new(InodeStore::new()),
-            handles: Mutex::new(HashMap::new()),
+            lower_index,
+            inodes: InodeStore::new(),
+            handles: Arc::new(Mutex::new(HashMap::new())),
             next_handle: AtomicU64::new(1),
             writeback: AtomicBool::new(false),
             no_open: AtomicBool::new(false),
@@ -998,6 +1425,18 @@ impl OverlayFs {
             killpriv_v2: AtomicBool::new(false),
             perfile_dax: AtomicBool::new(false),
             root_inodes: root_inode,
+            fsid,
+            io_accounting,
+            notify: None,
+            backings: None,
+            upper_lock: None,
+            copy_ups: CopyUpTracker::default(),
+            interrupts: Interrupts::default(),
+            reflinks: ReflinkProbe::default(),
+            drain: Arc::default(),
+            lower_watcher,
+            upper_strategy,
+            self_test,
         })
     }
 
@@ -1005,981 +1444,1823 @@ impl OverlayFs {
         self.root_inodes
     }
 
-    async fn alloc_inode(&self, path: &str) -> Result<u64> {
-        self.inodes.write().await.alloc_inode(path)
+    /// Send kernel cache invalidations through `notify`, taken from the session with
+    /// [`Session::get_notify`], when layers are removed at runtime.
+    pub fn set_notify(&mut self, notify: Notify) {
+        self.notify = Some(notify);
     }
 
-    /// Add a file layer and stack and merge the previous file layers.
-    pub async fn push_layer(&mut self, layer: Arc<BoxedLayer>) -> Result<()> {
-        let upper = self.upper_layer.take();
-        if let Some(upper) = upper {
-            self.lower_layers.push(upper);
-        }
-        self.upper_layer = Some(layer);
-        // TODO: merge previous file layers. need optimization
-        self.import().await?;
-        Ok(())
+    // Whether whiteouts are written to the upper layer in their xattr form.
+    fn xattr_whiteout(&self) -> bool {
+        self.upper_strategy.is_some_and(|s| s.xattr_whiteout)
     }
 
-    pub async fn import(&self) -> Result<()> {
-        let mut root = OverlayInode::new();
-        root.inode = self.root_inode();
-        root.path = String::from("").into();
-        root.name = String::from("").into();
-        root.lookups = AtomicU64::new(2);
-        root.real_inodes = Mutex::new(vec![]);
-        let ctx = Request::default();
+    // Xattr marking new opaque directories in the upper layer, `EOPNOTSUPP` if it can't
+    // have any.
+    fn opaque_xattr(&self) -> Result<&'static str> {
+        self.upper_strategy
+            .and_then(|s| s.opaque_xattr)
+            .ok_or_else(|| Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
 
-        // Update upper inode
-        if let Some(layer) = self.upper_layer.as_ref() {
-            let ino = layer.root_inode();
-            let real = RealInode::new(
-                layer.clone(),
-                true,
-                ino,
-                false,
-                layer.is_opaque(ctx, ino).await?,
-            )
-            .await;
-            root.real_inodes.lock().await.push(real.into());
+    /// Whether files and directories are served without handles, `no_open` and
+    /// `no_opendir`. Both are implied when nothing is imported.
+    fn handle_less(&self) -> (bool, bool) {
+        (
+            !self.config.do_import || self.config.no_open,
+            !self.config.do_import || self.config.no_opendir,
+        )
+    }
+
+    /// Advertise the handle-less modes of this overlay in `options`, so that the kernel
+    /// stops sending open, release, opendir and releasedir it would otherwise have to fail.
+    pub fn apply_mount_options(&self, options: &mut MountOptions) {
+        let (no_open, no_opendir) = self.handle_less();
+        options
+            .no_open_support(no_open)
+            .no_open_dir_support(no_opendir)
+            .passthrough(self.passthrough_possible())
+            .splice_read(self.config.splice_read && self.io_accounting.is_none())
+            .flock_locks(true)
+            .max_readahead(self.config.tuning.max_readahead)
+            .max_background(self.config.tuning.max_background)
+            .congestion_threshold(self.config.tuning.congestion_threshold);
+        if let Some(max_write) = self.config.tuning.max_write {
+            options.max_write(max_write);
         }
+    }
 
-        // Update lower inodes.
-        for layer in self.lower_layers.iter() {
-            let ino = layer.root_inode();
-            let real: RealInode = RealInode::new(
-                layer.clone(),
-                false,
-                ino,
-                false,
-                layer.is_opaque(ctx, ino).await?,
-            )
-            .await;
-            root.real_inodes.lock().await.push(real.into());
+    /// Pass opened files through to the kernel with the backing files of `files`, taken from
+    /// the session with [`Session::get_backing_files`], when `Config::passthrough` is set.
+    pub fn set_backing_files(&mut self, files: BackingFiles) {
+        if self.passthrough_possible() {
+            self.backings = Some(Backings::new(files));
         }
-        let root_node = Arc::new(root);
+    }
 
-        // insert root inode into hash
-        self.insert_inode(self.root_inode(), Arc::clone(&root_node))
-            .await;
+    // Passed through reads and writes never reach the overlay, so the features depending on
+    // them rule it out.
+    fn passthrough_possible(&self) -> bool {
+        self.config.passthrough
+            && !self.config.writeback
+            && !matches!(self.config.cache_policy, CachePolicy::Never)
+            && self.io_accounting.is_none()
+    }
 
-        info!("loading root directory");
-        self.load_directory(ctx, &root_node).await?;
-        info!("loaded root directory");
+    // Serve the new handle `fh` of `inode` in the kernel if possible.
+    async fn open_passthrough(&self, inode: Inode, fh: u64) -> Passthrough {
+        let Some(backings) = &self.backings else {
+            return Passthrough::Cached;
+        };
+        match self.find_real_info_from_handle(fh).await {
+            Ok((layer, real_inode, handle)) => {
+                backings.open(inode, fh, &layer, real_inode, handle).await
+            }
+            Err(_) => Passthrough::Cached,
+        }
+    }
 
-        Ok(())
+    /// Identifier of this overlay instance, distinct for every mount.
+    pub fn fsid(&self) -> u64 {
+        self.fsid
     }
 
-    async fn root_node(&self) -> Arc<OverlayInode> {
-        // Root node must exist.
-        self.get_active_inode(self.root_inode()).await.unwrap()
+    /// Bytes and operations read and written through this overlay, by the cgroup of the
+    /// requesting process. Empty unless `Config::cgroup_io_accounting` is set.
+    pub fn cgroup_io_stats(&self) -> HashMap<String, CgroupIoStats> {
+        self.io_accounting
+            .as_ref()
+            .map(|acct| acct.snapshot())
+            .unwrap_or_default()
     }
 
-    async fn insert_inode(&self, inode: u64, node: Arc<OverlayInode>) {
-        self.inodes.write().await.insert_inode(inode, node).await;
+    /// Handle of the self-test probing this overlay, None unless `Config::self_test_interval`
+    /// is set. It stays usable after the overlay was moved into a session.
+    pub fn self_test(&self) -> Option<SelfTest> {
+        self.self_test.clone()
     }
 
-    async fn get_active_inode(&self, inode: u64) -> Option<Arc<OverlayInode>> {
-        self.inodes.read().await.get_inode(inode)
+    /// Progress of the copy-up of `inode`, None if none is running.
+    pub fn copy_up_progress(&self, inode: Inode) -> Option<CopyUpProgress> {
+        self.copy_ups.progress(inode)
     }
 
-    // Get inode which is active or deleted.
-    async fn get_all_inode(&self, inode: u64) -> Option<Arc<OverlayInode>> {
-        let inode_store = self.inodes.read().await;
-        match inode_store.get_inode(inode) {
-            Some(n) => Some(n),
-            None => inode_store.get_deleted_inode(inode),
-        }
+    /// All copy-ups currently running.
+    pub fn copy_ups_in_progress(&self) -> Vec<CopyUpProgress> {
+        self.copy_ups.all()
     }
 
-    // Return the inode only if it's permanently deleted from both self.inodes and self.deleted_inodes.
-    async fn remove_inode(
+    /// Bytes written through this overlay and bytes its copy-ups wrote to the upper layer
+    /// since it was created.
+    pub fn write_amplification(&self) -> WriteAmplification {
+        self.copy_ups.amplification()
+    }
+
+    /// Stop the copy-up of `inode`. The partial copy is removed and the request that
+    /// triggered it fails with `ECANCELED`, the file stays in its lower layer. Returns false
+    /// if no copy-up of `inode` is running.
+    pub fn cancel_copy_up(&self, inode: Inode) -> bool {
+        self.copy_ups.cancel(inode)
+    }
+
+    /// Forward `posix_fadvise` `advice`, one of `libc::POSIX_FADV_*`, for `len` bytes at
+    /// `offset` of the file open as `fh` to the backing file in its layer. A zero `len`
+    /// extends to the end of the file.
+    ///
+    /// The kernel neither forwards `posix_fadvise` nor `madvise` to FUSE, this is for
+    /// callers that drive the overlay directly or relay the hints out of band.
+    pub async fn fadvise(
         &self,
-        inode: u64,
-        path_removed: Option<String>,
-    ) -> Option<Arc<OverlayInode>> {
-        self.inodes
-            .write()
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        len: u64,
+        advice: libc::c_int,
+    ) -> Result<()> {
+        let hd = self
+            .handles
+            .lock()
             .await
-            .remove_inode(inode, path_removed)
+            .get(&fh)
+            .filter(|hd| hd.node.inode == inode)
+            .cloned()
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))?;
+        // Emulated devices have no backing file.
+        let Some(rh) = &hd.real_handle else {
+            return Ok(());
+        };
+        rh.layer
+            .fadvise(
+                rh.inode,
+                rh.handle.load(Ordering::Relaxed),
+                offset,
+                len,
+                advice,
+            )
             .await
     }
 
-    // Lookup child OverlayInode with <name> under <parent> directory.
-    // If name is empty, return parent itself.
-    // Parent dir will be loaded, but returned OverlayInode won't.
-    async fn lookup_node(
+    /// Handle to shut the overlay down cleanly, it stays usable after the overlay was moved
+    /// into a session.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            drain: Arc::clone(&self.drain),
+            copy_ups: self.copy_ups.clone(),
+            handles: Arc::clone(&self.handles),
+            layers: self
+                .upper_layer
+                .iter()
+                .chain(&self.lower_layers)
+                .cloned()
+                .collect(),
+            #[cfg(feature = "prometheus")]
+            has_upper: self.upper_layer.is_some(),
+        }
+    }
+
+    /// Statistics of this overlay for a [`metrics::MetricsRegistry`], it stays usable after
+    /// the overlay was moved into a session.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(&self) -> metrics::OverlayMetrics {
+        metrics::OverlayMetrics {
+            io_accounting: self.io_accounting.clone(),
+            ..self.shutdown_handle().into()
+        }
+    }
+
+    /// Shut the overlay down, see [`ShutdownHandle::shutdown`].
+    pub async fn shutdown(&self, timeout: std::time::Duration) -> ShutdownReport {
+        self.shutdown_handle().shutdown(timeout).await
+    }
+
+    // Apply `squash_to_uid`, `squash_to_gid` and `forced_mode` to attributes replied to the
+    // kernel.
+    fn squash_attr(&self, attr: &mut FileAttr) {
+        if let Some(uid) = self.config.squash_to_uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.config.squash_to_gid {
+            attr.gid = gid;
+        }
+        if let Some(mode) = self.config.forced_mode {
+            attr.perm = (mode & 0o7777) as u16;
+        }
+    }
+
+    // Turn `attr` of a real file of `node` into the attributes replied to the kernel. Every
+    // reply carrying attributes goes through here, so the inode number is always the overlay
+    // one that readdir hands out as well, never that of a layer.
+    async fn reply_attr(
         &self,
         ctx: Request,
-        parent: Inode,
-        name: &str,
-    ) -> Result<Arc<OverlayInode>> {
-        if name.contains(SLASH_ASCII) {
-            return Err(Error::from_raw_os_error(libc::EINVAL));
-        }
+        node: &OverlayInode,
+        attr: &mut FileAttr,
+    ) -> Result<()> {
+        attr.ino = node.inode;
+        self.squash_attr(attr);
+        self.apply_dir_attr(ctx, node, attr).await
+    }
 
-        // Parent inode is expected to be loaded before this function is called.
-        // TODO: Is this correct?
-        let pnode = match self.get_active_inode(parent).await {
-            Some(v) => v,
+    // Report the overlay-level link count of directory `node` in `attr`, and its times with
+    // `strict_dir_times`.
+    async fn apply_dir_attr(
+        &self,
+        ctx: Request,
+        node: &OverlayInode,
+        attr: &mut FileAttr,
+    ) -> Result<()> {
+        if attr.kind != FileType::Directory {
+            return Ok(());
+        }
+        // The subdirectories of a merged directory are spread over its layers and may be
+        // hidden by whiteouts, so no layer's count is right. Like kernel overlayfs, report 1,
+        // which tools such as find take as an unknown count instead of pruning with it.
+        if node.real_inodes.lock().await.len() > 1 {
+            attr.nlink = 1;
+        }
+        if !self.config.strict_dir_times {
+            return Ok(());
+        }
+        let mut times = node.dir_times.lock().await;
+        let (mtime, ctime) = match *times {
+            Some(times) => times,
             None => {
-                match self.get_all_inode(parent).await {
-                    Some(v) => {
-                        trace!(
-                            "overlayfs:mod.rs:1031:lookup_node: parent inode {parent} is deleted"
-                        );
-                        v
-                    }
-                    None => {
-                        trace!(
-                            "overlayfs:mod.rs:1034:lookup_node: parent inode {parent} not found"
-                        );
-                        // Parent inode is not found, return ENOENT.
-                        return Err(Error::from_raw_os_error(libc::ENOENT));
+                // A merged directory changed last when the newest of its layers did.
+                let (mut mtime, mut ctime) = (attr.mtime, attr.ctime);
+                for ri in node.real_inodes.lock().await.iter() {
+                    if let Some(st) = ri.stat64_ignore_enoent(&ctx).await? {
+                        mtime = mtime.max(st.attr.mtime);
+                        ctime = ctime.max(st.attr.ctime);
                     }
                 }
+                *times.insert((mtime, ctime))
             }
         };
+        attr.mtime = mtime;
+        attr.ctime = ctime;
+        Ok(())
+    }
 
-        // Parent is whiteout-ed, return ENOENT.
-        if pnode.whiteout.load(Ordering::Relaxed) {
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+    // With `strict_dir_times`, set the overlay-level times of directory `node`.
+    async fn set_dir_times(&self, node: &OverlayInode, mtime: Timestamp, ctime: Timestamp) {
+        if self.config.strict_dir_times {
+            *node.dir_times.lock().await = Some((mtime, ctime));
         }
+    }
 
-        let st = pnode.stat64(ctx).await?;
-        if utils::is_dir(&st.attr.kind) && !pnode.loaded.load(Ordering::Relaxed) {
-            // Parent is expected to be directory, load it first.
-            self.load_directory(ctx, &pnode).await?;
+    // After a setattr of `node` replied with `attr`, keep an explicitly set mtime of a
+    // directory and report its new ctime, see `reply_attr`.
+    async fn setattr_dir_times(
+        &self,
+        ctx: Request,
+        node: &OverlayInode,
+        set_mtime: bool,
+        attr: &mut FileAttr,
+    ) -> Result<()> {
+        if set_mtime && attr.kind == FileType::Directory {
+            self.set_dir_times(node, attr.mtime, attr.ctime).await;
         }
-
-        // Current file or dir.
-        if name.eq(".")  
-            // Root directory has no parent.
-            || (parent == self.root_inode() && name.eq("..")) 
-            // Special convention: empty name indicates current dir.
-            || name.is_empty()
-        {
-            return Ok(Arc::clone(&pnode));
+        let ctime = attr.ctime;
+        self.reply_attr(ctx, node, attr).await?;
+        if let Some(times) = node.dir_times.lock().await.as_mut() {
+            times.1 = times.1.max(ctime);
+            attr.ctime = times.1;
         }
+        Ok(())
+    }
 
-        match pnode.child(name).await {
-            // Child is found.
-            Some(v) => Ok(v),
-            None => {
-                trace!("lookup_node: child {name} not found");
-                Err(Error::from_raw_os_error(libc::ENOENT))
-            }
+    // Bump the listing version of directory `inode` after an entry of it was created, removed
+    // or renamed, and its times with `strict_dir_times`.
+    async fn touch_dir(&self, inode: Inode) {
+        let Some(node) = self.get_active_inode(inode).await else {
+            return;
+        };
+        node.dir_version.fetch_add(1, Ordering::Relaxed);
+        if self.config.strict_dir_times {
+            let now = Timestamp::from(std::time::SystemTime::now());
+            self.set_dir_times(&node, now, now).await;
         }
     }
 
-    async fn lookup_node_ignore_enoent(
-        &self,
-        ctx: Request,
-        parent: u64,
-        name: &str,
-    ) -> Result<Option<Arc<OverlayInode>>> {
-        match self.lookup_node(ctx, parent, name).await {
-            Ok(n) => Ok(Some(Arc::clone(&n))),
-            Err(e) => {
-                if let Some(raw_error) = e.raw_os_error()
-                    && raw_error == libc::ENOENT
-                {
-                    return Ok(None);
-                }
-                Err(e)
-            }
+    // Request, mode and umask to create a new entry with, so that what ends up in the upper
+    // layer matches what `squash_attr` reports.
+    fn squash_create(&self, mut req: Request, mode: u32, umask: u32) -> (Request, u32, u32) {
+        if let Some(uid) = self.config.squash_to_uid {
+            req.uid = uid;
+        }
+        if let Some(gid) = self.config.squash_to_gid {
+            req.gid = gid;
+        }
+        match self.config.forced_mode {
+            Some(forced) => (req, (mode & libc::S_IFMT) | (forced & 0o7777), 0),
+            None => (req, mode, umask),
         }
     }
 
-    // Load entries of the directory from all layers, if node is not directory, return directly.
-    async fn load_directory(&self, ctx: Request, node: &Arc<OverlayInode>) -> Result<()> {
-        if node.loaded.load(Ordering::Relaxed) {
-            return Ok(());
+    /// Report space and inode usage of every layer separately, upper first.
+    ///
+    /// `statfs` on the mountpoint only describes the layer holding the queried inode,
+    /// so a full upper layer and a full lower image store look the same from there.
+    pub async fn layer_statfs(&self) -> Result<Vec<LayerStatFs>> {
+        let layers = self
+            .upper_layer
+            .iter()
+            .map(|l| (LayerRole::Upper, l))
+            .chain(
+                self.lower_layers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| (LayerRole::Lower(i), l)),
+            );
+        let mut stats = Vec::new();
+        for (role, layer) in layers {
+            let root = layer.root_dir().to_path_buf();
+            let statfs = layer.statfs(Request::default(), layer.root_inode()).await?;
+            let croot = std::ffi::CString::new(root.as_os_str().as_bytes())
+                .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let mut out = std::mem::MaybeUninit::<libc::statvfs>::zeroed();
+            // SAFETY: the path is NUL-terminated and `out` is only read after success.
+            if unsafe { libc::statvfs(croot.as_ptr(), out.as_mut_ptr()) } != 0 {
+                return Err(Error::last_os_error());
+            }
+            #[allow(clippy::unnecessary_cast)]
+            let fsid = unsafe { out.assume_init() }.f_fsid as u64;
+            stats.push(LayerStatFs {
+                role,
+                root,
+                fsid,
+                statfs,
+            });
         }
+        Ok(stats)
+    }
 
-        // We got all childrens without inode.
-        // info!("before scan childrens, ctx: {:?}, node: {:?}", ctx, node.inode);
-        let childrens = node.scan_childrens(ctx).await?;
-        // info!("scanned children");
-
-        // =============== Start Lock Area ===================
-        // Lock OverlayFs inodes.
-        let mut inode_store = self.inodes.write().await;
-        // Lock the OverlayInode and its childrens.
-        let mut node_children = node.childrens.lock().await;
+    /// Remove the whiteouts of the upper layer that no longer hide anything because no lower
+    /// layer has an entry at their path, and return how many were removed.
+    ///
+    /// Upper layers of long-lived mounts pile up whiteouts of lower entries that later
+    /// layer updates dropped. The merged view doesn't change, so this can run at any time,
+    /// on demand or from a periodic task.
+    pub async fn gc_whiteouts(&self) -> Result<usize> {
+        let Some(upper) = &self.upper_layer else {
+            return Ok(0);
+        };
+        let upper_root = upper.root_dir().to_path_buf();
+        let lowers = self
+            .lower_layers
+            .iter()
+            .map(|l| l.root_dir().to_path_buf())
+            .collect::<Vec<_>>();
+        let removed = tokio::task::spawn_blocking(move || {
+            check::remove_dangling_whiteouts(&upper_root, &lowers)
+        })
+        .await
+        .map_err(Error::other)??;
+        for path in &removed {
+            self.forget_whiteout(path).await;
+        }
+        Ok(removed.len())
+    }
 
-        // Check again in case another 'load_directory' function call gets locks and want to do duplicated work.
-        if node.loaded.load(Ordering::Relaxed) {
-            return Ok(());
+    // Drop the loaded node of the whiteout at `path`, relative to the overlay root, after it
+    // was removed from the upper layer.
+    async fn forget_whiteout(&self, path: &Path) {
+        let (Some(parent_path), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+        let mut parent = self.root_node().await;
+        for component in parent_path.iter() {
+            let child = parent.childrens.lock().await.get(component).cloned();
+            match child {
+                Some(child) => parent = child,
+                None => return,
+            }
+        }
+        let child = parent.childrens.lock().await.get(name).cloned();
+        if let Some(child) = child
+            && child.whiteout.load(Ordering::Relaxed)
+        {
+            parent.remove_child(name).await;
+            let path = child.path.read().await.clone();
+            self.remove_inode(child.inode, Some(path)).await;
         }
+    }
 
-        // Now we have two locks' protection, Fs inodes lock and OverlayInode's childrens lock.
-        // info!("before iter childrens");
-        for mut child in childrens.into_iter() {
-            // Allocate inode for each child.
-            let ino = inode_store.alloc_inode(&child.path.read().await)?;
+    /// Copy the directory at `path`, relative to the overlay root, up and mark it opaque.
+    ///
+    /// Everything lower layers hold below it disappears at once, which gives `rm -rf` and
+    /// recreate semantics without a whiteout per child. Entries already in the upper layer
+    /// stay. Setting any opaque xattr to `y` through the mount does the same.
+    pub async fn set_dir_opaque(&self, path: &Path) -> Result<()> {
+        let ctx = Request::default();
+        let node = self.lookup_path(ctx, path).await?;
+        self.make_opaque(ctx, node).await
+    }
 
-            let name = child.name.read().await.clone();
-            child.inode = ino;
-            // Create bi-directional link between parent and child.
-            child.parent = Mutex::new(Arc::downgrade(node));
+    // Node at `path`, relative to the overlay root.
+    async fn lookup_path(&self, ctx: Request, path: &Path) -> Result<Arc<OverlayInode>> {
+        let mut node = self.root_node().await;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => {
+                    node = self.lookup_node(ctx, node.inode, name).await?;
+                    if node.whiteout.load(Ordering::Relaxed) {
+                        return Err(Error::from_raw_os_error(libc::ENOENT));
+                    }
+                }
+                _ => return Err(Error::from_raw_os_error(libc::EINVAL)),
+            }
+        }
+        Ok(node)
+    }
 
-            let arc_child = Arc::new(child);
-            node_children.insert(name, arc_child.clone());
-            // Record overlay inode in whole OverlayFs.
-            inode_store.insert_inode(ino, arc_child).await;
+    /// Apply a list of deletions known up front, e.g. by image layer tooling, writing the
+    /// whiteouts and opaque markers into the upper layer directly instead of through one
+    /// `unlink` per path on the mount.
+    ///
+    /// Paths below a removed directory are covered by its whiteout and skipped. Directories
+    /// are processed in parallel, the entries of one directory in a batch. Only a missing
+    /// upper layer fails the whole call, other errors are reported per path.
+    pub async fn apply_deletions<I>(&self, deletions: I) -> Result<DeletionReport>
+    where
+        I: IntoIterator,
+        I::Item: Into<Deletion>,
+    {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+        let ctx = Request::default();
+        let mut report = DeletionReport::default();
+        let mut clears = Vec::new();
+        let mut removes = Vec::new();
+        for deletion in deletions {
+            match deletion.into() {
+                Deletion::Clear(path) => clears.push(path),
+                Deletion::Remove(path) => removes.push(path),
+            }
         }
-        // info!("after iter childrens");
 
-        node.loaded.store(true, Ordering::Relaxed);
+        // Clearing first spares the whiteouts of removed entries below cleared directories.
+        for path in clears {
+            match self.lookup_path(ctx, &path).await {
+                Ok(node) => match self.make_opaque(ctx, node).await {
+                    Ok(()) => report.applied += 1,
+                    Err(e) => report.failed.push((path, e)),
+                },
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => report.absent.push(path),
+                Err(e) => report.failed.push((path, e)),
+            }
+        }
 
-        Ok(())
-    }
+        // Sorted, a path comes right after its ancestors.
+        removes.sort();
+        removes.dedup();
+        let mut batches: HashMap<PathBuf, Vec<OsString>> = HashMap::new();
+        let mut removed: Option<PathBuf> = None;
+        for path in removes {
+            if removed.as_ref().is_some_and(|dir| path.starts_with(dir)) {
+                report.absent.push(path);
+                continue;
+            }
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                report
+                    .failed
+                    .push((path, Error::from_raw_os_error(libc::EINVAL)));
+                continue;
+            };
+            batches
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(name.to_os_string());
+            removed = Some(path);
+        }
 
-    async fn forget_one(&self, inode: Inode, count: u64) {
-        if inode == self.root_inode() || inode == 0 {
-            return;
+        let mut results = iter(batches)
+            .map(|(parent, names)| self.delete_batch(ctx, parent, names))
+            .buffer_unordered(DELETION_PARALLELISM);
+        while let Some(batch) = results.next().await {
+            for (path, result) in batch {
+                match result {
+                    Ok(true) => report.applied += 1,
+                    Ok(false) => report.absent.push(path),
+                    Err(e) => report.failed.push((path, e)),
+                }
+            }
         }
+        Ok(report)
+    }
 
-        let v = match self.get_all_inode(inode).await {
-            Some(n) => n,
-            None => {
-                trace!("forget unknown inode: {inode}");
-                return;
+    /// Copy `path`, relative to the overlay root, and everything below it up to the upper
+    /// layer, e.g. to warm it before a write-heavy phase such as `npm install` instead of
+    /// paying for each copy-up on first write.
+    ///
+    /// Directories are copied up first, as they are walked, then up to
+    /// `options.parallelism` files at a time. `options.progress` is called after each entry
+    /// copied up. The first error stops the copy, what was copied up until then stays.
+    pub async fn copy_up_path(
+        &self,
+        path: impl AsRef<Path>,
+        options: CopyUpPathOptions,
+    ) -> Result<CopyUpPathReport> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+        let ctx = Request::default();
+        let report = std::sync::Mutex::new(CopyUpPathReport::default());
+        let copied = |path: &Path, bytes: u64| {
+            let mut report = report.lock().unwrap();
+            report.entries += 1;
+            report.bytes += bytes;
+            if let Some(progress) = &options.progress {
+                progress(path, &report);
             }
         };
 
-        // Use fetch_update to atomically update lookups in a loop until it succeeds
-        v.lookups
-            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
-                // If count is larger than current lookups, return 0
-                // Otherwise subtract count from current lookups
-                if current < count {
-                    Some(0)
-                } else {
-                    Some(current - count)
+        let path = path.as_ref();
+        let mut dirs = vec![(path.to_path_buf(), self.lookup_path(ctx, path).await?)];
+        let mut files = Vec::new();
+        while let Some((path, node)) = dirs.pop() {
+            if node.kind(ctx).await? != FileType::Directory {
+                if !node.in_upper_layer().await {
+                    files.push((path, node));
+                }
+                continue;
+            }
+            if !node.in_upper_layer().await {
+                self.copy_node_up(ctx, Arc::clone(&node)).await?;
+                copied(&path, 0);
+            }
+            self.load_directory(ctx, &node).await?;
+            for (name, child) in node.childrens.lock().await.iter() {
+                if !child.whiteout.load(Ordering::Relaxed) {
+                    dirs.push((path.join(name), Arc::clone(child)));
                 }
-            })
-            .expect("fetch_update failed");
-
-        let lookups = v.lookups.load(Ordering::Relaxed);
-        trace!(
-            "forget inode: {}, name {}, lookups: {}",
-            inode,
-            v.name.read().await,
-            lookups
-        );
-        if lookups == 0 {
-            debug!(
-                "inode is forgotten: {}, name {}",
-                inode,
-                v.name.read().await
-            );
-            let _ = self.remove_inode(inode, None).await;
-            let parent = v.parent.lock().await;
-
-            if let Some(p) = parent.upgrade() {
-                // remove it from hashmap
-                p.remove_child(&v.name.read().await).await;
             }
         }
-    }
 
-    async fn do_lookup(&self, ctx: Request, parent: Inode, name: &str) -> Result<ReplyEntry> {
-        let node = self.lookup_node(ctx, parent, name).await?;
-        debug!("do_lookup: {name:?}, found");
+        let mut results = iter(files)
+            .map(|(path, node)| async move {
+                let st = node.stat64(ctx).await?;
+                self.copy_node_up(ctx, node).await?;
+                let bytes = match st.attr.kind {
+                    FileType::RegularFile => st.attr.size,
+                    _ => 0,
+                };
+                Ok::<_, Error>((path, bytes))
+            })
+            .buffer_unordered(options.parallelism.max(1));
+        while let Some(result) = results.next().await {
+            let (path, bytes) = result?;
+            copied(&path, bytes);
+        }
+        Ok(report.into_inner().unwrap())
+    }
 
-        if node.whiteout.load(Ordering::Relaxed) {
-            eprintln!("Error: node.whiteout.load() called.");
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+    // Remove `names` from the directory at `parent`, whether each existed.
+    async fn delete_batch(
+        &self,
+        ctx: Request,
+        parent: PathBuf,
+        names: Vec<OsString>,
+    ) -> Vec<(PathBuf, Result<bool>)> {
+        let pnode = match self.lookup_path(ctx, &parent).await {
+            Ok(pnode) => Ok(pnode),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                return names
+                    .into_iter()
+                    .map(|name| (parent.join(name), Ok(false)))
+                    .collect();
+            }
+            Err(e) => Err(e),
+        };
+        let mut results = Vec::with_capacity(names.len());
+        for name in names {
+            let result = match &pnode {
+                Ok(pnode) => self.delete_tree(ctx, pnode, &name).await,
+                Err(e) => Err(Error::new(e.kind(), e.to_string())),
+            };
+            results.push((parent.join(name), result));
         }
+        results
+    }
 
-        let mut st = node.stat64(ctx).await?;
-        st.attr.ino = node.inode;
-        if utils::is_dir(&st.attr.kind) && !node.loaded.load(Ordering::Relaxed) {
+    // Remove `name` of `pnode` and everything below it, false if it doesn't exist.
+    async fn delete_tree(&self, ctx: Request, pnode: &OverlayInode, name: &OsStr) -> Result<bool> {
+        let node = match self.lookup_node(ctx, pnode.inode, name).await {
+            Ok(node) if !node.whiteout.load(Ordering::Relaxed) => node,
+            Ok(_) => return Ok(false),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let dir = node.is_dir(ctx).await?;
+        if dir && node.in_upper_layer().await {
+            // Hide the lower entries in one go where the layer allows it, then empty what
+            // the upper layer holds.
+            match self.make_opaque(ctx, Arc::clone(&node)).await {
+                Err(e) if e.raw_os_error() != Some(libc::EOPNOTSUPP) => return Err(e),
+                _ => {}
+            }
             self.load_directory(ctx, &node).await?;
+            let children = node
+                .childrens
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, child)| !child.whiteout.load(Ordering::Relaxed))
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            for child in children {
+                Box::pin(self.delete_tree(ctx, &node, &child)).await?;
+            }
         }
+        // A directory only in the lower layers is hidden by its whiteout as a whole.
+        self.do_rm_checked(
+            ctx,
+            pnode.inode,
+            name,
+            dir,
+            dir && node.in_upper_layer().await,
+        )
+        .await?;
+        Ok(true)
+    }
 
-        // FIXME: can forget happen between found and increase reference counter?
-        let tmp = node.lookups.fetch_add(1, Ordering::Relaxed);
-        trace!("lookup count: {}", tmp + 1);
-        Ok(ReplyEntry {
-            ttl: st.ttl,
-            attr: st.attr,
-            generation: 0,
-        })
+    async fn make_opaque(&self, ctx: Request, node: Arc<OverlayInode>) -> Result<()> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+        if !node.is_dir(ctx).await? {
+            return Err(Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        // Load the directory first so lower entries are known and can be dropped below.
+        self.lookup_node(ctx, node.inode, OsStr::new("")).await?;
+        let node = self.copy_node_up(ctx, node).await?;
+        let (layer, _, inode) = node.first_layer_inode().await;
+        layer.set_opaque(ctx, inode, self.opaque_xattr()?).await?;
+        self.hide_lower_entries(&node).await;
+        Ok(())
     }
 
-    async fn do_statvfs(&self, ctx: Request, inode: Inode) -> Result<ReplyStatFs> {
-        match self.get_active_inode(inode).await {
-            Some(ovi) => {
-                let all_inodes = ovi.real_inodes.lock().await;
-                let real_inode = all_inodes
-                    .first()
-                    .ok_or(Error::other("backend inode not found"))?;
-                Ok(real_inode.layer.statfs(ctx, real_inode.inode).await?)
+    // Forget the lower layers below `node`, as if it had been read from an opaque directory.
+    async fn hide_lower_entries(&self, node: &Arc<OverlayInode>) {
+        node.real_inodes.lock().await.retain(|ri| ri.in_upper_layer);
+        let children = node
+            .childrens
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for child in children {
+            if child.in_upper_layer().await {
+                Box::pin(self.hide_lower_entries(&child)).await;
+            } else {
+                let name = child.name.read().await.clone();
+                let path = child.path.read().await.clone();
+                self.remove_inode(child.inode, Some(path)).await;
+                node.remove_child(&name).await;
             }
-            None => Err(Error::from_raw_os_error(libc::ENOENT)),
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    async fn do_readdir<'a>(
-        &self,
-        ctx: Request,
-        inode: Inode,
-        handle: u64,
-        offset: u64,
-    ) -> Result<
-        impl futures_util::stream::Stream<Item = std::result::Result<DirectoryEntry, Errno>> + Send + 'a,
-    > {
-        let snapshot = self.get_or_create_dir_snapshot(ctx, inode, handle).await?;
+    async fn alloc_inode(&self, path: &OsStr) -> Result<u64> {
+        self.inodes.alloc_inode(path)
+    }
 
-        let entries: Vec<std::result::Result<DirectoryEntry, Errno>> =
-            if offset < snapshot.len() as u64 {
-                snapshot
-                    .iter()
-                    .skip(offset as usize)
-                    .map(|entry| {
-                        Ok(DirectoryEntry {
-                            inode: entry.inode,
-                            kind: entry.kind,
-                            name: entry.name.clone(),
-                            offset: entry.offset,
-                        })
-                    })
-                    .collect()
-            } else {
-                vec![]
-            };
+    /// Add a file layer on top, the previous upper layer becoming the top-most lower layer.
+    ///
+    /// Inodes already handed out keep their numbers: the new layer is merged into the loaded
+    /// part of the tree instead of rebuilding it, so open handles stay valid.
+    pub async fn push_layer(&mut self, layer: Arc<BoxedLayer>) -> Result<()> {
+        let ctx = Request::default();
+        let strategy = UpperStrategy::negotiate(layer.as_ref(), &self.config)?;
+        let prev = self.upper_layer.take();
+        if let Some(prev) = prev.as_ref() {
+            self.lower_layers.insert(0, prev.clone());
+        }
+        self.upper_layer = Some(layer.clone());
+        self.upper_strategy = Some(strategy);
+        // The index describes the previous lower layers only.
+        self.lower_index = None;
 
-        Ok(iter(entries))
+        let Some(root) = self.get_active_inode(self.root_inode()).await else {
+            // Nothing imported yet.
+            return Ok(());
+        };
+        let demoted = match prev {
+            Some(prev) => {
+                let ino = prev.root_inode();
+                let opaque = prev.is_opaque(ctx, ino).await?;
+                Some(RealInode::new(prev, false, ino, false, opaque).await)
+            }
+            None => None,
+        };
+        let ino = layer.root_inode();
+        let opaque = layer.is_opaque(ctx, ino).await?;
+        let top = RealInode::new(layer, true, ino, false, opaque).await;
+        self.merge_layer(ctx, &root, demoted, Some(top)).await
     }
 
-    #[allow(clippy::too_many_arguments)]
-    async fn do_readdirplus<'a>(
+    // Merge a pushed layer into `node`. Its real inode in the previous upper layer is replaced
+    // by `demoted`, the same inode as seen from a lower layer, and `top`, its counterpart in
+    // the new upper layer, is stacked on the remaining ones. Loaded directories are merged
+    // recursively, everything else is resolved on the next lookup.
+    async fn merge_layer(
         &self,
         ctx: Request,
-        inode: Inode,
-        handle: u64,
-        offset: u64,
-    ) -> Result<
-        impl futures_util::stream::Stream<Item = std::result::Result<DirectoryEntryPlus, Errno>>
-        + Send
-        + 'a,
-    > {
-        let snapshot = self.get_or_create_dir_snapshot(ctx, inode, handle).await?;
-
-        let mut entries = Vec::new();
-        if offset < snapshot.len() as u64 {
-            for entry in snapshot.iter().skip(offset as usize) {
-                // Increment lookup count for readdirplus as we are handing out a reference to the kernel.
-                // We must do this here, not in snapshot creation, and we must NOT decrement it in HandleData drop.
-                // The kernel will send a FORGET request when it's done with the entry.
-                if let Some(node) = self.get_all_inode(entry.inode).await {
-                    node.lookups.fetch_add(1, Ordering::Relaxed);
+        node: &Arc<OverlayInode>,
+        demoted: Option<RealInode>,
+        top: Option<RealInode>,
+    ) -> Result<()> {
+        let was_dir = node.is_dir(ctx).await.unwrap_or(false);
+        let was_whiteout = node.whiteout.load(Ordering::Relaxed);
+        let mut real_inodes = node.real_inodes.lock().await;
+        if real_inodes.first().is_some_and(|ri| ri.in_upper_layer) {
+            match demoted {
+                Some(ri) => real_inodes[0] = Arc::new(ri),
+                None => {
+                    real_inodes.remove(0);
                 }
-                entries.push(Ok(entry.clone()));
             }
         }
+        // Only the layers below stay visible if the new one has nothing here.
+        let Some(top) = top else {
+            drop(real_inodes);
+            if was_dir && !was_whiteout && node.loaded.load(Ordering::Relaxed) {
+                self.merge_children(ctx, node, HashMap::new(), false)
+                    .await?;
+            }
+            return Ok(());
+        };
 
-        Ok(iter(entries))
+        let top_dir = utils::is_dir(&top.stat64(&ctx).await?.attr.kind);
+        let mut stack = vec![];
+        if top_dir && !top.opaque {
+            for ri in real_inodes.iter() {
+                if ri.whiteout || !utils::is_dir(&ri.stat64(&ctx).await?.attr.kind) {
+                    break;
+                }
+                stack.push(ri.clone());
+                if ri.opaque {
+                    break;
+                }
+            }
+        }
+        // Entries of the layers dropped from the stack are hidden now.
+        let hidden = stack.len() < real_inodes.len();
+        let whiteout = top.whiteout;
+        let children = if top_dir && !whiteout {
+            top.readdir(ctx).await?
+        } else {
+            HashMap::new()
+        };
+        stack.insert(0, Arc::new(top));
+        *real_inodes = stack;
+        drop(real_inodes);
+        node.whiteout.store(whiteout, Ordering::Relaxed);
+
+        if !was_dir || was_whiteout || !top_dir || whiteout {
+            // The node changed kind, any children are stale and the directory is read again.
+            self.merge_children(ctx, node, HashMap::new(), true).await?;
+            node.loaded.store(false, Ordering::Relaxed);
+        } else if node.loaded.load(Ordering::Relaxed) {
+            self.merge_children(ctx, node, children, hidden).await?;
+        }
+        Ok(())
     }
 
-    async fn get_or_create_dir_snapshot(
+    // Merge the children of a loaded directory, `tops` being its entries in the new layer.
+    // With `hidden` set, children only found in the lower layers are dropped.
+    async fn merge_children(
         &self,
         ctx: Request,
-        inode: Inode,
-        handle: u64,
-    ) -> Result<Vec<DirectoryEntryPlus>> {
-        let handle_data = match self.handles.lock().await.get(&handle) {
-            Some(hd) if hd.node.inode == inode => hd.clone(),
-            _ => {
-                // Fallback for cases without a valid handle (e.g. no-opendir)
-                let node = self.lookup_node(ctx, inode, ".").await?;
-                let st = node.stat64(ctx).await?;
-                if !utils::is_dir(&st.attr.kind) {
-                    return Err(Error::from_raw_os_error(libc::ENOTDIR));
-                }
-                // Create a temporary HandleData for this call only.
-                Arc::new(HandleData {
-                    node,
-                    real_handle: None,
-                    dir_snapshot: Mutex::new(None),
-                })
+        node: &Arc<OverlayInode>,
+        mut tops: HashMap<OsString, RealInode>,
+        hidden: bool,
+    ) -> Result<()> {
+        // The real inode the previous upper layer has for this directory, if any.
+        let prev_dir = node
+            .real_inodes
+            .lock()
+            .await
+            .iter()
+            .find(|ri| {
+                !ri.in_upper_layer
+                    && self
+                        .lower_layers
+                        .first()
+                        .is_some_and(|l| Arc::ptr_eq(l, &ri.layer))
+            })
+            .cloned();
+        let children = node
+            .childrens
+            .lock()
+            .await
+            .iter()
+            .map(|(name, child)| (name.clone(), child.clone()))
+            .collect::<Vec<_>>();
+        for (name, child) in children {
+            let top = tops.remove(&name);
+            if top.is_none() && hidden {
+                let path = child.path.read().await.clone();
+                self.remove_inode(child.inode, Some(path)).await;
+                node.remove_child(&name).await;
+                continue;
             }
-        };
+            let demoted = match prev_dir.as_ref() {
+                Some(dir) if child.in_upper_layer().await => dir.lookup_child(ctx, &name).await?,
+                _ => None,
+            };
+            Box::pin(self.merge_layer(ctx, &child, demoted, top)).await?;
+        }
 
-        // Optimistic check
-        if let Some(snapshot) = handle_data.dir_snapshot.lock().await.as_ref() {
-            return Ok(snapshot.clone());
+        // Names only the new layer has.
+        let path = node.path.read().await.clone();
+        for (name, top) in tops {
+            let child_path = utils::join_path(&path, &name);
+            let ino = self.alloc_inode(&child_path).await?;
+            let mut child =
+                OverlayInode::new_from_real_inodes(&name, ino, child_path, vec![top]).await?;
+            child.parent = Mutex::new(Arc::downgrade(node));
+            let child = Arc::new(child);
+            node.insert_child(&name, child.clone()).await;
+            self.insert_inode(ino, child).await;
         }
+        Ok(())
+    }
 
-        // Snapshot doesn't exist, create it.
-        let ovl_inode = &handle_data.node;
-        self.load_directory(ctx, ovl_inode).await?;
-
-        let mut entries = Vec::new();
+    /// Remove the upper layer at runtime, the top-most lower layer becoming the upper one.
+    /// Without an upper layer the top-most lower layer is removed instead.
+    ///
+    /// The inverse of [`push_layer`](Self::push_layer): loaded inodes are resolved again
+    /// against the remaining layers and keep their numbers, while entries only the removed
+    /// layer had go away. Handles opened in the removed layer keep reading from it.
+    pub async fn pop_layer(&mut self) -> Result<Arc<BoxedLayer>> {
+        if self.lower_layers.is_empty()
+            || self.upper_layer.is_none() && self.lower_layers.len() == 1
+        {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let layer = match self.upper_layer.take() {
+            Some(upper) => {
+                let strategy =
+                    match UpperStrategy::negotiate(self.lower_layers[0].as_ref(), &self.config) {
+                        Ok(strategy) => strategy,
+                        Err(e) => {
+                            self.upper_layer = Some(upper);
+                            return Err(e);
+                        }
+                    };
+                let lower = self.lower_layers.remove(0);
+                lower.share_attrs(None)?;
+                self.upper_layer = Some(lower);
+                self.upper_strategy = Some(strategy);
+                upper
+            }
+            None => self.lower_layers.remove(0),
+        };
+        self.lower_index = None;
+        self.resolve_layers().await?;
+        Ok(layer)
+    }
 
-        // 1. Add "." entry
-        let mut st_self = ovl_inode.stat64(ctx).await?;
-        st_self.attr.ino = ovl_inode.inode;
-        entries.push(DirectoryEntryPlus {
-            inode: ovl_inode.inode,
-            generation: 0,
-            kind: st_self.attr.kind,
-            name: ".".into(),
-            offset: 1,
-            attr: st_self.attr,
-            entry_ttl: st_self.ttl,
-            attr_ttl: st_self.ttl,
-        });
+    /// Remove the lower layer whose root directory is `root` at runtime, see
+    /// [`pop_layer`](Self::pop_layer).
+    pub async fn remove_lower_layer(&mut self, root: &Path) -> Result<Arc<BoxedLayer>> {
+        let root = root.canonicalize()?;
+        let pos = self
+            .lower_layers
+            .iter()
+            .position(|l| l.root_dir().canonicalize().is_ok_and(|dir| dir == root))
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))?;
+        if self.upper_layer.is_none() && self.lower_layers.len() == 1 {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let layer = self.lower_layers.remove(pos);
+        self.lower_index = None;
+        self.resolve_layers().await?;
+        Ok(layer)
+    }
 
-        // 2. Add ".." entry
-        let parent_node = match ovl_inode.parent.lock().await.upgrade() {
-            Some(node) => node,
-            None => self.root_node().await,
+    // Resolve the loaded tree again after the layers changed, keeping inode numbers.
+    async fn resolve_layers(&self) -> Result<()> {
+        let ctx = Request::default();
+        let Some(root) = self.get_active_inode(self.root_inode()).await else {
+            return Ok(());
         };
-        let mut st_parent = parent_node.stat64(ctx).await?;
-        st_parent.attr.ino = parent_node.inode;
-        entries.push(DirectoryEntryPlus {
-            inode: parent_node.inode,
-            generation: 0,
-            kind: st_parent.attr.kind,
-            name: "..".into(),
-            offset: 2,
-            attr: st_parent.attr,
-            entry_ttl: st_parent.ttl,
-            attr_ttl: st_parent.ttl,
-        });
+        *root.real_inodes.lock().await = self.root_real_inodes(ctx).await?;
+        if root.loaded.load(Ordering::Relaxed) {
+            self.resolve_children(ctx, &root).await?;
+        }
+        self.invalidate_inode(root.inode).await;
+        Ok(())
+    }
 
-        // 3. Add children entries
-        let children = ovl_inode.childrens.lock().await;
-        for (name, child) in children.iter() {
-            if child.whiteout.load(Ordering::Relaxed) {
+    // Resolve the children of the loaded directory `node` against its real inodes: existing
+    // children get their new real inodes, vanished ones are dropped and new ones added.
+    async fn resolve_children(&self, ctx: Request, node: &Arc<OverlayInode>) -> Result<()> {
+        let mut fresh: HashMap<_, _> = node.scan_childrens(ctx).await?.into_iter().collect();
+        let children = node
+            .childrens
+            .lock()
+            .await
+            .iter()
+            .map(|(name, child)| (name.clone(), child.clone()))
+            .collect::<Vec<_>>();
+        for (name, child) in children {
+            let Some(new) = fresh.remove(&name) else {
+                self.forget_subtree(&child).await;
+                let path = child.path.read().await.clone();
+                self.remove_inode(child.inode, Some(path)).await;
+                node.remove_child(&name).await;
+                self.invalidate_entry(node.inode, &name).await;
                 continue;
+            };
+
+            let was_dir = child.is_dir(ctx).await.unwrap_or(false);
+            let real_inodes = new.real_inodes.into_inner();
+            let changed = {
+                let mut old = child.real_inodes.lock().await;
+                let changed = match (old.first(), real_inodes.first()) {
+                    (Some(a), Some(b)) => !Arc::ptr_eq(&a.layer, &b.layer) || a.inode != b.inode,
+                    _ => true,
+                };
+                *old = real_inodes;
+                changed
+            };
+            let whiteout = new.whiteout.load(Ordering::Relaxed);
+            child.whiteout.store(whiteout, Ordering::Relaxed);
+            if changed {
+                self.invalidate_inode(child.inode).await;
+                self.invalidate_entry(node.inode, &name).await;
+            }
+
+            let is_dir = !whiteout && child.is_dir(ctx).await.unwrap_or(false);
+            if was_dir && !is_dir {
+                self.forget_subtree(&child).await;
+                child.loaded.store(false, Ordering::Relaxed);
+            } else if is_dir && child.loaded.load(Ordering::Relaxed) {
+                Box::pin(self.resolve_children(ctx, &child)).await?;
             }
-            let mut st_child = child.stat64(ctx).await?;
-            st_child.attr.ino = child.inode;
-            entries.push(DirectoryEntryPlus {
-                inode: child.inode,
-                generation: 0,
-                kind: st_child.attr.kind,
-                name: name.clone().into(),
-                offset: (entries.len() + 1) as i64,
-                attr: st_child.attr,
-                entry_ttl: st_child.ttl,
-                attr_ttl: st_child.ttl,
-            });
         }
-        drop(children);
 
-        let mut snapshot_guard = handle_data.dir_snapshot.lock().await;
-        if snapshot_guard.is_none() {
-            // We won the race, install our prepared snapshot.
-            *snapshot_guard = Some(entries.clone());
-            Ok(entries)
-        } else {
-            // Another thread won the race while we were preparing.
-            // Discard our work and use the existing snapshot.
-            Ok(snapshot_guard.as_ref().unwrap().clone())
+        // Names uncovered by the removed layer.
+        for (name, mut child) in fresh {
+            let ino = self.alloc_inode(&child.path.read().await).await?;
+            child.inode = ino;
+            child.parent = Mutex::new(Arc::downgrade(node));
+            let child = Arc::new(child);
+            node.insert_child(&name, child.clone()).await;
+            self.insert_inode(ino, child).await;
+            self.invalidate_entry(node.inode, &name).await;
         }
+        self.invalidate_inode(node.inode).await;
+        Ok(())
     }
 
-    async fn do_mkdir(
-        &self,
-        ctx: Request,
-        parent_node: Arc<OverlayInode>,
-        name: &str,
-        mode: u32,
-        umask: u32,
-    ) -> Result<()> {
-        if self.upper_layer.is_none() {
-            return Err(Error::from_raw_os_error(libc::EROFS));
+    // Drop all descendants of `node` from the inode store.
+    async fn forget_subtree(&self, node: &Arc<OverlayInode>) {
+        let children = std::mem::take(&mut *node.childrens.lock().await);
+        for (_, child) in children {
+            Box::pin(self.forget_subtree(&child)).await;
+            let path = child.path.read().await.clone();
+            self.remove_inode(child.inode, Some(path)).await;
         }
+    }
 
-        // Parent node was deleted.
-        if parent_node.whiteout.load(Ordering::Relaxed) {
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+    // Resolve the directories changed in the lower layers since the last request, see
+    // `lower_watch`.
+    async fn apply_lower_changes(&self) {
+        let Some(watcher) = &self.lower_watcher else {
+            return;
+        };
+        let _applying = watcher.applying.lock().await;
+        let changes = watcher.take();
+        if changes.is_empty() {
+            return;
         }
-
-        let mut delete_whiteout = false;
-        let mut set_opaque = false;
-        if let Some(n) = self
-            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
-            .await?
-        {
-            // Node with same name exists, let's check if it's whiteout.
-            if !n.whiteout.load(Ordering::Relaxed) {
-                return Err(Error::from_raw_os_error(libc::EEXIST));
+        if changes.rescan {
+            if let Err(e) = self.resolve_layers().await {
+                warn!("failed to resolve changed lower layers: {e}");
             }
+            return;
+        }
 
-            if n.in_upper_layer().await {
-                delete_whiteout = true;
+        let ctx = Request::default();
+        'dirs: for (path, change) in changes.dirs {
+            let mut node = self.root_node().await;
+            for component in path.iter() {
+                let child = node.childrens.lock().await.get(component).cloned();
+                match child {
+                    Some(child) => node = child,
+                    // Not loaded, it will be read from the layers when needed.
+                    None => continue 'dirs,
+                }
             }
-
-            // Set opaque if child dir has lower layers.
-            if !n.upper_layer_only().await {
-                set_opaque = true;
+            for name in &change.modified {
+                let child = node.childrens.lock().await.get(name.as_os_str()).cloned();
+                if let Some(child) = child {
+                    self.invalidate_inode(child.inode).await;
+                }
+            }
+            if change.entries {
+                node.dir_version.fetch_add(1, Ordering::Relaxed);
+            }
+            if change.entries
+                && node.loaded.load(Ordering::Relaxed)
+                && let Err(e) = self.resolve_children(ctx, &node).await
+            {
+                warn!("failed to resolve changed lower directory {path:?}: {e}");
             }
         }
+    }
 
-        // Copy parent node up if necessary.
-        let pnode = self.copy_node_up(ctx, parent_node).await?;
+    async fn invalidate_inode(&self, inode: Inode) {
+        if let Some(notify) = self.notify.clone() {
+            notify.invalid_inode(inode, 0, 0).await;
+        }
+    }
 
-        let path = format!("{}/{}", pnode.path.read().await, name);
-        let path_ref = &path;
-        let new_node = Arc::new(Mutex::new(None));
-        pnode
-            .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                let parent_real_inode = match parent_real_inode {
-                    Some(inode) => inode,
-                    None => {
-                        error!("BUG: parent doesn't have upper inode after copied up");
-                        return Err(Error::from_raw_os_error(libc::EINVAL));
-                    }
-                };
-                let osstr = OsStr::new(name);
-                if delete_whiteout {
-                    let _ = parent_real_inode
-                        .layer
-                        .delete_whiteout(ctx, parent_real_inode.inode, osstr)
-                        .await;
-                }
+    async fn invalidate_entry(&self, parent: Inode, name: &OsStr) {
+        if let Some(notify) = self.notify.clone() {
+            notify.invalid_entry(parent, name.to_os_string()).await;
+        }
+    }
 
-                // Allocate inode number.
-                let ino = self.alloc_inode(path_ref).await?;
-                let child_dir = parent_real_inode.mkdir(ctx, name, mode, umask).await?;
-                // Set opaque if child dir has lower layers.
-                if set_opaque {
-                    parent_real_inode
-                        .layer
-                        .set_opaque(ctx, child_dir.inode)
-                        .await?;
-                }
-                let ovi =
-                    OverlayInode::new_from_real_inode(name, ino, path_ref.clone(), child_dir).await;
-                new_node.lock().await.replace(ovi);
-                Ok(false)
-            })
-            .await?;
+    pub async fn import(&self) -> Result<()> {
+        let mut root = OverlayInode::new();
+        root.inode = self.root_inode();
+        root.path = OsString::new().into();
+        root.name = OsString::new().into();
+        root.lookups = AtomicU64::new(2);
+        let ctx = Request::default();
+        root.real_inodes = Mutex::new(self.root_real_inodes(ctx).await?);
+        let root_node = Arc::new(root);
+
+        // insert root inode into hash
+        self.insert_inode(self.root_inode(), Arc::clone(&root_node))
+            .await;
+
+        info!("loading root directory");
+        self.load_directory(ctx, &root_node).await?;
+        info!("loaded root directory");
 
-        // new_node is always 'Some'
-        let nn = new_node.lock().await.take();
-        let arc_node = Arc::new(nn.unwrap());
-        self.insert_inode(arc_node.inode, arc_node.clone()).await;
-        pnode.insert_child(name, arc_node).await;
         Ok(())
     }
 
-    async fn do_mknod(
+    // Real inodes of the root directory, one per layer from the upper one down.
+    async fn root_real_inodes(&self, ctx: Request) -> Result<Vec<Arc<RealInode>>> {
+        let layers = self
+            .upper_layer
+            .iter()
+            .map(|l| (l, true))
+            .chain(self.lower_layers.iter().map(|l| (l, false)));
+        let mut real_inodes = vec![];
+        for (layer, in_upper_layer) in layers {
+            let ino = layer.root_inode();
+            let opaque = layer.is_opaque(ctx, ino).await?;
+            let real = RealInode::new(layer.clone(), in_upper_layer, ino, false, opaque).await;
+            real_inodes.push(Arc::new(real));
+        }
+        Ok(real_inodes)
+    }
+
+    async fn root_node(&self) -> Arc<OverlayInode> {
+        // Root node must exist.
+        self.get_active_inode(self.root_inode()).await.unwrap()
+    }
+
+    async fn insert_inode(&self, inode: u64, node: Arc<OverlayInode>) {
+        self.inodes.insert_inode(inode, node).await;
+    }
+
+    async fn get_active_inode(&self, inode: u64) -> Option<Arc<OverlayInode>> {
+        self.inodes.get_inode(inode)
+    }
+
+    // Get inode which is active or deleted.
+    async fn get_all_inode(&self, inode: u64) -> Option<Arc<OverlayInode>> {
+        self.inodes.get_any_inode(inode)
+    }
+
+    // Return the inode only if it's permanently deleted from both self.inodes and self.deleted_inodes.
+    async fn remove_inode(
+        &self,
+        inode: u64,
+        path_removed: Option<OsString>,
+    ) -> Option<Arc<OverlayInode>> {
+        self.inodes.remove_inode(inode, path_removed)
+    }
+
+    // Lookup child OverlayInode with <name> under <parent> directory.
+    // If name is empty, return parent itself.
+    // Parent dir will be loaded, but returned OverlayInode won't.
+    async fn lookup_node(
         &self,
         ctx: Request,
-        parent_node: &Arc<OverlayInode>,
-        name: &str,
-        mode: u32,
-        rdev: u32,
-        umask: u32,
-    ) -> Result<()> {
-        if self.upper_layer.is_none() {
-            return Err(Error::from_raw_os_error(libc::EROFS));
+        parent: Inode,
+        name: &OsStr,
+    ) -> Result<Arc<OverlayInode>> {
+        if name.as_bytes().contains(&SLASH_ASCII) {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
         }
+        utils::check_name_len(name)?;
 
-        // Parent node was deleted.
-        if parent_node.whiteout.load(Ordering::Relaxed) {
+        // Parent inode is expected to be loaded before this function is called.
+        // TODO: Is this correct?
+        let pnode = match self.get_active_inode(parent).await {
+            Some(v) => v,
+            None => {
+                match self.get_all_inode(parent).await {
+                    Some(v) => {
+                        trace!(
+                            "overlayfs:mod.rs:1031:lookup_node: parent inode {parent} is deleted"
+                        );
+                        v
+                    }
+                    None => {
+                        trace!(
+                            "overlayfs:mod.rs:1034:lookup_node: parent inode {parent} not found"
+                        );
+                        // Parent inode is not found, return ENOENT.
+                        return Err(Error::from_raw_os_error(libc::ENOENT));
+                    }
+                }
+            }
+        };
+
+        // Parent is whiteout-ed, return ENOENT.
+        if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT));
         }
 
-        match self
-            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
-            .await?
-        {
-            Some(n) => {
-                // Node with same name exists, let's check if it's whiteout.
-                if !n.whiteout.load(Ordering::Relaxed) {
-                    return Err(Error::from_raw_os_error(libc::EEXIST));
-                }
+        let st = pnode.stat64(ctx).await?;
+        let indexed = utils::is_dir(&st.attr.kind) && self.is_indexed(&pnode).await;
+        if utils::is_dir(&st.attr.kind) && !indexed && !pnode.loaded.load(Ordering::Relaxed) {
+            // Parent is expected to be directory, load it first.
+            self.load_directory(ctx, &pnode).await?;
+        }
 
-                // Copy parent node up if necessary.
-                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
-                pnode
-                    .handle_upper_inode_locked(
-                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                            let parent_real_inode = match parent_real_inode {
-                                Some(inode) => inode,
-                                None => {
-                                    error!("BUG: parent doesn't have upper inode after copied up");
-                                    return Err(Error::from_raw_os_error(libc::EINVAL));
-                                }
-                            };
-                            let osstr = OsStr::new(name);
-                            if n.in_upper_layer().await {
-                                let _ = parent_real_inode
-                                    .layer
-                                    .delete_whiteout(ctx, parent_real_inode.inode, osstr)
-                                    .await;
-                            }
+        // Current file or dir.
+        if name.eq(".")  
+            // Root directory has no parent.
+            || (parent == self.root_inode() && name.eq("..")) 
+            // Special convention: empty name indicates current dir.
+            || name.is_empty()
+        {
+            return Ok(Arc::clone(&pnode));
+        }
 
-                            let child_ri = parent_real_inode
-                                .mknod(ctx, name, mode, rdev, umask)
-                                .await?;
+        if indexed {
+            return self.lookup_indexed_child(ctx, &pnode, name).await;
+        }
 
-                            // Replace existing real inodes with new one.
-                            n.add_upper_inode(child_ri, true).await;
-                            Ok(false)
-                        },
-                    )
-                    .await?;
-            }
+        match pnode.child(name).await {
+            // Child is found.
+            Some(v) => Ok(v),
             None => {
-                // Copy parent node up if necessary.
-                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
-                let new_node = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name);
-                pnode
-                    .handle_upper_inode_locked(
-                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                            let parent_real_inode = match parent_real_inode {
-                                Some(inode) => inode,
-                                None => {
-                                    error!("BUG: parent doesn't have upper inode after copied up");
-                                    return Err(Error::from_raw_os_error(libc::EINVAL));
-                                }
-                            };
-
-                            // Allocate inode number.
-                            let ino = self.alloc_inode(&path).await?;
-                            let child_ri = parent_real_inode
-                                .mknod(ctx, name, mode, rdev, umask)
-                                .await?;
-                            let ovi = OverlayInode::new_from_real_inode(
-                                name,
-                                ino,
-                                path.clone(),
-                                child_ri,
-                            )
-                            .await;
-
-                            new_node.lock().await.replace(ovi);
-                            Ok(false)
-                        },
-                    )
-                    .await?;
+                trace!("lookup_node: child {name:?} not found");
+                Err(Error::from_raw_os_error(libc::ENOENT))
+            }
+        }
+    }
 
-                let nn = new_node.lock().await.take();
-                let arc_node = Arc::new(nn.unwrap());
-                self.insert_inode(arc_node.inode, arc_node.clone()).await;
-                pnode.insert_child(name, arc_node).await;
+    async fn lookup_node_ignore_enoent(
+        &self,
+        ctx: Request,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<Option<Arc<OverlayInode>>> {
+        match self.lookup_node(ctx, parent, name).await {
+            Ok(n) => Ok(Some(Arc::clone(&n))),
+            Err(e) => {
+                if let Some(raw_error) = e.raw_os_error()
+                    && raw_error == libc::ENOENT
+                {
+                    return Ok(None);
+                }
+                Err(e)
             }
         }
+    }
 
-        Ok(())
+    // A lower-only directory listed in the lower index is not loaded: its children are
+    // materialized one by one on lookup, until a mutation copies it up and loads it.
+    async fn is_indexed(&self, node: &OverlayInode) -> bool {
+        let Some(index) = self.lower_index.as_ref() else {
+            return false;
+        };
+        if node.loaded.load(Ordering::Relaxed) || node.in_upper_layer().await {
+            return false;
+        }
+        index.dir(&node.path.read().await).is_some()
     }
 
-    async fn do_create(
+    // Lookup child <name> of an indexed directory, only touching the layers holding it.
+    async fn lookup_indexed_child(
         &self,
         ctx: Request,
-        parent_node: &Arc<OverlayInode>,
+        pnode: &Arc<OverlayInode>,
         name: &OsStr,
-        mode: u32,
-        flags: u32,
-    ) -> Result<Option<u64>> {
-        let name_str = name.to_str().unwrap();
-        let upper = self
-            .upper_layer
+    ) -> Result<Arc<OverlayInode>> {
+        if let Some(v) = pnode.child(name).await {
+            return Ok(v);
+        }
+        let path = pnode.path.read().await.clone();
+        let layers = self
+            .lower_index
             .as_ref()
-            .cloned()
-            .ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
-
-        // Parent node was deleted.
-        if parent_node.whiteout.load(Ordering::Relaxed) {
+            .and_then(|index| index.dir(&path)?.lookup(name))
+            .map(|entry| entry.layers)
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))?;
+
+        let mut real_inodes = vec![];
+        for ri in pnode.real_inodes.lock().await.iter() {
+            let Some(i) = self
+                .lower_layers
+                .iter()
+                .position(|l| Arc::ptr_eq(l, &ri.layer))
+            else {
+                continue;
+            };
+            if layers & (1 << i) == 0 {
+                continue;
+            }
+            if let Some(child) = ri.lookup_child(ctx, name).await? {
+                real_inodes.push(child);
+            }
+        }
+        if real_inodes.is_empty() {
+            error!("lower index is stale: {path:?} has no {name:?} in any layer");
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        let child_path = utils::join_path(&path, name);
+        let mut child =
+            OverlayInode::new_from_real_inodes(name, 0, child_path, real_inodes).await?;
+
+        // The children lock of the parent keeps `load_directory` from adding it meanwhile.
+        let mut node_children = pnode.childrens.lock().await;
+        if let Some(v) = node_children.get(name) {
+            return Ok(Arc::clone(v));
+        }
+        if pnode.loaded.load(Ordering::Relaxed) {
+            // Loaded meanwhile without this child, so it was removed.
             return Err(Error::from_raw_os_error(libc::ENOENT));
         }
+        child.inode = self.inodes.alloc_inode(&child.path.read().await)?;
+        child.parent = Mutex::new(Arc::downgrade(pnode));
+        let child = Arc::new(child);
+        node_children.insert(name.to_os_string(), Arc::clone(&child));
+        self.inodes
+            .insert_inode(child.inode, Arc::clone(&child))
+            .await;
+        Ok(child)
+    }
 
-        let handle: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
-        let real_ino: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
-        let new_ovi = match self
-            .lookup_node_ignore_enoent(ctx, parent_node.inode, name_str)
-            .await?
-        {
-            Some(n) => {
-                // Node with same name exists, let's check if it's whiteout.
-                if !n.whiteout.load(Ordering::Relaxed) {
-                    return Err(Error::from_raw_os_error(libc::EEXIST));
-                }
+    // Load entries of the directory from all layers, if node is not directory, return directly.
+    async fn load_directory(&self, ctx: Request, node: &Arc<OverlayInode>) -> Result<()> {
+        if node.loaded.load(Ordering::Relaxed) {
+            return Ok(());
+        }
 
-                // Copy parent node up if necessary.
-                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
-                pnode
-                    .handle_upper_inode_locked(
-                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                            let parent_real_inode = match parent_real_inode {
-                                Some(inode) => inode,
-                                None => {
-                                    error!("BUG: parent doesn't have upper inode after copied up");
-                                    return Err(Error::from_raw_os_error(libc::EINVAL));
-                                }
-                            };
+        // We got all childrens without inode.
+        // info!("before scan childrens, ctx: {:?}, node: {:?}", ctx, node.inode);
+        let childrens = node.scan_childrens(ctx).await?;
+        // info!("scanned children");
 
-                            if n.in_upper_layer().await {
-                                let _ = parent_real_inode
-                                    .layer
-                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
-                                    .await;
-                            }
+        // =============== Start Lock Area ===================
+        // Lock the OverlayInode and its childrens. The inode store locks only the shards it
+        // touches, so lookups elsewhere go on while a large directory is linked in.
+        let mut node_children = node.childrens.lock().await;
 
-                            let (child_ri, hd) =
-                                parent_real_inode.create(ctx, name_str, mode, flags).await?;
-                            real_ino.lock().await.replace(child_ri.inode);
-                            handle.lock().await.replace(hd.unwrap());
+        // Check again in case another 'load_directory' function call gets locks and want to do duplicated work.
+        if node.loaded.load(Ordering::Relaxed) {
+            return Ok(());
+        }
 
-                            // Replace existing real inodes with new one.
-                            n.add_upper_inode(child_ri, true).await;
-                            Ok(false)
-                        },
-                    )
-                    .await?;
-                n.clone()
+        // Now we have the protection of the OverlayInode's childrens lock.
+        // info!("before iter childrens");
+        for (name, mut child) in childrens {
+            // Children materialized from the lower index are already linked.
+            if node_children.contains_key(&name) {
+                continue;
             }
-            None => {
-                // Copy parent node up if necessary.
-                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
-                let new_node = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name_str);
-                pnode
-                    .handle_upper_inode_locked(
-                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                            let parent_real_inode = match parent_real_inode {
-                                Some(inode) => inode,
-                                None => {
-                                    error!("BUG: parent doesn't have upper inode after copied up");
-                                    return Err(Error::from_raw_os_error(libc::EINVAL));
-                                }
-                            };
+            // Allocate inode for each child.
+            let ino = self.inodes.alloc_inode(&child.path.read().await)?;
 
-                            let (child_ri, hd) =
-                                parent_real_inode.create(ctx, name_str, mode, flags).await?;
-                            real_ino.lock().await.replace(child_ri.inode);
-                            handle.lock().await.replace(hd.unwrap());
-                            // Allocate inode number.
-                            let ino = self.alloc_inode(&path).await?;
-                            let ovi = OverlayInode::new_from_real_inode(
-                                name_str,
-                                ino,
-                                path.clone(),
-                                child_ri,
-                            )
-                            .await;
+            child.inode = ino;
+            // Create bi-directional link between parent and child.
+            child.parent = Mutex::new(Arc::downgrade(node));
 
-                            new_node.lock().await.replace(ovi);
-                            Ok(false)
-                        },
-                    )
-                    .await?;
+            let arc_child = Arc::new(child);
+            node_children.insert(name, arc_child.clone());
+            // Record overlay inode in whole OverlayFs.
+            self.inodes.insert_inode(ino, arc_child).await;
+        }
+        // info!("after iter childrens");
 
-                // new_node is always 'Some'
-                let nn = new_node.lock().await.take();
-                let arc_node = Arc::new(nn.unwrap());
-                self.insert_inode(arc_node.inode, arc_node.clone()).await;
-                pnode.insert_child(name_str, arc_node.clone()).await;
-                arc_node
+        node.loaded.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    async fn forget_one(&self, inode: Inode, count: u64) {
+        if inode == self.root_inode() || inode == 0 {
+            return;
+        }
+
+        let v = match self.get_all_inode(inode).await {
+            Some(n) => n,
+            None => {
+                trace!("forget unknown inode: {inode}");
+                return;
             }
         };
 
-        let final_handle = match *handle.lock().await {
-            Some(hd) => {
-                if self.no_open.load(Ordering::Relaxed) {
-                    None
+        // Use fetch_update to atomically update lookups in a loop until it succeeds
+        v.lookups
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                // If count is larger than current lookups, return 0
+                // Otherwise subtract count from current lookups
+                if current < count {
+                    Some(0)
                 } else {
-                    let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-                    let handle_data = HandleData {
-                        node: new_ovi,
-                        real_handle: Some(RealHandle {
-                            layer: upper.clone(),
-                            in_upper_layer: true,
-                            inode: real_ino.lock().await.unwrap(),
-                            handle: AtomicU64::new(hd),
-                        }),
-                        dir_snapshot: Mutex::new(None),
-                    };
-                    self.handles
-                        .lock()
-                        .await
-                        .insert(handle, Arc::new(handle_data));
-                    Some(handle)
+                    Some(current - count)
                 }
-            }
-            None => None,
-        };
-        Ok(final_handle)
-    }
-
-    async fn do_rename(
-        &self,
-        req: Request,
-        parent: Inode,
-        name: &OsStr,
-        new_parent: Inode,
-        new_name: &OsStr,
-    ) -> Result<()> {
-        let name_str = name.to_str().unwrap();
-        let new_name_str = new_name.to_str().unwrap();
+            })
+            .expect("fetch_update failed");
 
-        let parent_node = self.lookup_node(req, parent, "").await?;
-        let new_parent_node = self.lookup_node(req, new_parent, "").await?;
-        let src_node = self.lookup_node(req, parent, name_str).await?;
-        let dest_node_opt = self
-            .lookup_node_ignore_enoent(req, new_parent, new_name_str)
-            .await?;
-        // trace!("parent_node: {}, new_parent_node: {}, src_node: {}, dest_node_opt: {:?}", parent_node.inode, new_parent_node.inode, src_node.inode, dest_node_opt.as_ref().map(|n| n.inode));
+        let lookups = v.lookups.load(Ordering::Relaxed);
+        trace!(
+            "forget inode: {}, name {:?}, lookups: {}",
+            inode,
+            v.name.read().await,
+            lookups
+        );
+        if lookups == 0 {
+            debug!(
+                "inode is forgotten: {}, name {:?}",
+                inode,
+                v.name.read().await
+            );
+            let _ = self.remove_inode(inode, None).await;
+            let parent = v.parent.lock().await;
 
-        if let Some(dest_node) = &dest_node_opt {
-            let src_is_dir = src_node.is_dir(req).await?;
-            let dest_is_dir = dest_node.is_dir(req).await?;
-            if src_is_dir != dest_is_dir {
-                return Err(Error::from_raw_os_error(libc::EISDIR));
-            }
-            if dest_is_dir {
-                self.copy_directory_up(req, dest_node.clone()).await?;
-                let (count, _) = dest_node.count_entries_and_whiteout(req).await?;
-                if count > 0 {
-                    return Err(Error::from_raw_os_error(libc::ENOTEMPTY));
-                }
+            if let Some(p) = parent.upgrade() {
+                // remove it from hashmap
+                p.remove_child(&v.name.read().await).await;
             }
         }
+    }
 
-        let pnode = self.copy_node_up(req, parent_node).await?;
-        let new_pnode = self.copy_node_up(req, new_parent_node).await?;
-        let s_node = self.copy_node_up(req, src_node).await?;
+    async fn do_lookup(&self, ctx: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let node = self.lookup_node(ctx, parent, name).await?;
+        debug!("do_lookup: {name:?}, found");
 
-        let need_whiteout = !s_node.upper_layer_only().await;
+        if node.whiteout.load(Ordering::Relaxed) {
+            eprintln!("Error: node.whiteout.load() called.");
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
 
-        let (p_layer, _, p_inode) = pnode.first_layer_inode().await;
-        let (new_p_layer, _, new_p_inode) = new_pnode.first_layer_inode().await;
-        assert!(Arc::ptr_eq(&p_layer, &new_p_layer));
+        let mut st = node.stat64(ctx).await?;
+        self.reply_attr(ctx, &node, &mut st.attr).await?;
+        if utils::is_dir(&st.attr.kind)
+            && !node.loaded.load(Ordering::Relaxed)
+            && !self.is_indexed(&node).await
+        {
+            self.load_directory(ctx, &node).await?;
+        }
 
-        p_layer
-            .rename(req, p_inode, name, new_p_inode, new_name)
-            .await?;
+        // FIXME: can forget happen between found and increase reference counter?
+        let tmp = node.lookups.fetch_add(1, Ordering::Relaxed);
+        trace!("lookup count: {}", tmp + 1);
+        Ok(ReplyEntry {
+            ttl: st.ttl,
+            attr: st.attr,
+            generation: 0,
+        })
+    }
 
-        // Handle the replaced destination node (if any).
-        if let Some(dest_node) = dest_node_opt {
-            let path = dest_node.path.read().await.clone();
-            self.remove_inode(dest_node.inode, Some(path)).await;
+    async fn do_statvfs(&self, ctx: Request, inode: Inode) -> Result<ReplyStatFs> {
+        match self.get_active_inode(inode).await {
+            Some(ovi) => {
+                let all_inodes = ovi.real_inodes.lock().await;
+                let real_inode = all_inodes
+                    .first()
+                    .ok_or(Error::other("backend inode not found"))?;
+                Ok(real_inode.layer.statfs(ctx, real_inode.inode).await?)
+            }
+            None => Err(Error::from_raw_os_error(libc::ENOENT)),
         }
+    }
 
-        // Update the moved source node's state.
+    #[allow(clippy::too_many_arguments)]
+    async fn do_readdir<'a>(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        handle: u64,
+        offset: u64,
+    ) -> Result<
+        impl futures_util::stream::Stream<Item = std::result::Result<DirectoryEntry, Errno>> + Send + 'a,
+    > {
+        // Entries are cloned as the reply consumes them, a reply holds only part of a large
+        // directory.
+        if let Some(snapshot) = self
+            .get_or_create_index_snapshot(ctx, inode, handle)
+            .await?
+        {
+            let start = resume_at(&snapshot, offset, |e| e.offset);
+            let entries = (start..snapshot.len()).map(move |i| Ok(snapshot[i].clone()));
+            return Ok(iter(entries).left_stream());
+        }
 
-        // Remove from old parent.
-        pnode.remove_child(name_str).await;
-        self.remove_inode(s_node.inode, s_node.path.read().await.clone().into())
-            .await;
-        let new_path = format!("{}/{}", new_pnode.path.read().await, new_name_str);
-        *s_node.path.write().await = new_path;
-        *s_node.name.write().await = new_name_str.to_string();
-        *s_node.parent.lock().await = Arc::downgrade(&new_pnode);
-        new_pnode.insert_child(new_name_str, s_node.clone()).await;
-        self.insert_inode(s_node.inode, s_node).await;
+        let snapshot = self
+            .get_or_create_plain_snapshot(ctx, inode, handle)
+            .await?;
+        let start = resume_at(&snapshot, offset, |e| e.offset);
+        let entries = (start..snapshot.len()).map(move |i| Ok(snapshot[i].clone()));
+        Ok(iter(entries).right_stream())
+    }
 
-        // Create whiteout at the old location if necessary.
-        if need_whiteout {
-            p_layer.create_whiteout(req, p_inode, name).await?;
+    #[allow(clippy::too_many_arguments)]
+    async fn do_readdirplus<'a>(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        handle: u64,
+        offset: u64,
+    ) -> Result<
+        impl futures_util::stream::Stream<Item = std::result::Result<DirectoryEntryPlus, Errno>>
+        + Send
+        + 'a,
+    > {
+        let snapshot = self.get_or_create_dir_snapshot(ctx, inode, handle).await?;
+        let start = resume_at(&snapshot, offset, |e| e.offset);
+
+        for entry in &snapshot[start..] {
+            // Increment lookup count for readdirplus as we are handing out a reference to the kernel.
+            // We must do this here, not in snapshot creation, and we must NOT decrement it in HandleData drop.
+            // The kernel will send a FORGET request when it's done with the entry.
+            if let Some(node) = self.get_all_inode(entry.inode).await {
+                node.lookups.fetch_add(1, Ordering::Relaxed);
+            }
         }
 
-        Ok(())
+        // Entries are cloned as the reply consumes them.
+        let entries = (start..snapshot.len()).map(move |i| Ok(snapshot[i].clone()));
+        Ok(iter(entries))
     }
 
-    async fn do_link(
+    async fn dir_handle_data(
         &self,
         ctx: Request,
-        src_node: &Arc<OverlayInode>,
-        new_parent: &Arc<OverlayInode>,
-        name: &str,
-    ) -> Result<()> {
-        if self.upper_layer.is_none() {
-            return Err(Error::from_raw_os_error(libc::EROFS));
+        inode: Inode,
+        handle: u64,
+    ) -> Result<Arc<HandleData>> {
+        match self.handles.lock().await.get(&handle) {
+            Some(hd) if hd.node.inode == inode => return Ok(hd.clone()),
+            _ => {}
+        }
+        // Fallback for cases without a valid handle (e.g. no-opendir)
+        let node = self.lookup_node(ctx, inode, OsStr::new(".")).await?;
+        let st = node.stat64(ctx).await?;
+        if !utils::is_dir(&st.attr.kind) {
+            return Err(Error::from_raw_os_error(libc::ENOTDIR));
         }
+        // Create a temporary HandleData for this call only.
+        Ok(Arc::new(HandleData {
+            node,
+            real_handle: None,
+            device: None,
+            dir_snapshot: Mutex::new(None),
+            plain_snapshot: Mutex::new(None),
+            snapshot_version: AtomicU64::new(0),
+        }))
+    }
 
-        // Node is whiteout.
-        if src_node.whiteout.load(Ordering::Relaxed) || new_parent.whiteout.load(Ordering::Relaxed)
+    // Drop the listings of `handle_data` taken before an entry of the directory changed. The
+    // offsets are cookies of the entries, so a listing resumes right after the last entry
+    // read from the new one.
+    async fn drop_stale_snapshots(&self, handle_data: &HandleData) {
+        let version = handle_data.node.dir_version.load(Ordering::Relaxed);
+        if handle_data
+            .snapshot_version
+            .swap(version, Ordering::Relaxed)
+            == version
         {
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+            return;
         }
+        *handle_data.dir_snapshot.lock().await = None;
+        *handle_data.plain_snapshot.lock().await = None;
+    }
 
-        let st = src_node.stat64(ctx).await?;
-        if utils::is_dir(&st.attr.kind) {
-            // Directory can't be hardlinked.
-            return Err(Error::from_raw_os_error(libc::EPERM));
+    // List an indexed directory for plain readdir without materializing its children.
+    // Entries get the inode number they will have once looked up.
+    async fn get_or_create_index_snapshot(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        handle: u64,
+    ) -> Result<Option<Arc<Vec<DirectoryEntry>>>> {
+        if self.lower_index.is_none() {
+            return Ok(None);
         }
-
-        let src_node = self.copy_node_up(ctx, Arc::clone(src_node)).await?;
-        let new_parent = self.copy_node_up(ctx, Arc::clone(new_parent)).await?;
-        let src_ino = src_node.first_layer_inode().await.2;
-
-        if let Some(existing_node) = self
-            .lookup_node_ignore_enoent(ctx, new_parent.inode, name)
-            .await?
-        {
-            // If it's not a whiteout, it's an error
-            if !existing_node.whiteout.load(Ordering::Relaxed) {
-                return Err(Error::from_raw_os_error(libc::EEXIST));
-            }
-            // If it is a whiteout, we will overwrite it.
-            // First, remove the physical whiteout file in the upper layer.
-            new_parent
-                .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                    let parent_ri = parent_real_inode.ok_or_else(|| {
-                        error!("BUG: parent doesn't have upper inode after copied up");
-                        Error::from_raw_os_error(libc::EINVAL)
-                    })?;
-                    // Only delete if the whiteout is in the upper layer
-                    if existing_node.in_upper_layer().await {
-                        let _ = parent_ri
-                            .layer
-                            .delete_whiteout(ctx, parent_ri.inode, OsStr::new(name))
-                            .await;
-                    }
-                    Ok(false)
-                })
-                .await?;
+        let handle_data = self.dir_handle_data(ctx, inode, handle).await?;
+        self.drop_stale_snapshots(&handle_data).await;
+        let mut snapshot_guard = handle_data.plain_snapshot.lock().await;
+        if let Some(snapshot) = snapshot_guard.as_ref() {
+            return Ok(Some(Arc::clone(snapshot)));
         }
+        let node = &handle_data.node;
+        // Keep offsets stable: a handle that already listed the loaded directory sticks to it.
+        if handle_data.dir_snapshot.lock().await.is_some() || !self.is_indexed(node).await {
+            return Ok(None);
+        }
+        let parent_ino = match node.parent.lock().await.upgrade() {
+            Some(p) => p.inode,
+            None => self.root_inode(),
+        };
 
-        new_parent
-            .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
-                let parent_real_inode = match parent_real_inode {
-                    Some(inode) => inode,
-                    None => {
-                        error!("BUG: parent doesn't have upper inode after copied up");
-                        return Err(Error::from_raw_os_error(libc::EINVAL));
-                    }
-                };
-
-                parent_real_inode.link(ctx, src_ino, name).await?;
+        let path = node.path.read().await.clone();
+        let Some(dir) = self.lower_index.as_ref().and_then(|index| index.dir(&path)) else {
+            return Ok(None);
+        };
+        let mut entries = vec![
+            DirectoryEntry {
+                inode: node.inode,
+                kind: FileType::Directory,
+                name: ".".into(),
+                offset: 1,
+            },
+            DirectoryEntry {
+                inode: parent_ino,
+                kind: FileType::Directory,
+                name: "..".into(),
+                offset: 2,
+            },
+        ];
+        let children = node.childrens.lock().await;
+        for entry in dir.entries() {
+            let ino = match children.get(entry.name) {
+                Some(child) => child.inode,
+                None => self
+                    .inodes
+                    .reserve_inode(&utils::join_path(&path, entry.name))?,
+            };
+            entries.push(DirectoryEntry {
+                inode: ino,
+                kind: entry.kind,
+                name: entry.name.to_os_string(),
+                offset: 0,
+            });
+        }
+        sort_by_cookie(&mut entries[2..], |e| &e.name, |e| &mut e.offset);
+        let entries = Arc::new(entries);
+        *snapshot_guard = Some(Arc::clone(&entries));
+        Ok(Some(entries))
+    }
 
-                Ok(false)
-            })
-            .await?;
+    // List the loaded directory for plain readdir. Children are listed with the type their
+    // layer reported when the directory was scanned, only readdirplus stats each of them.
+    async fn get_or_create_plain_snapshot(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        handle: u64,
+    ) -> Result<Arc<Vec<DirectoryEntry>>> {
+        let handle_data = self.dir_handle_data(ctx, inode, handle).await?;
+        self.drop_stale_snapshots(&handle_data).await;
+        if let Some(snapshot) = handle_data.plain_snapshot.lock().await.as_ref() {
+            return Ok(Arc::clone(snapshot));
+        }
 
-        self.insert_inode(src_node.inode, src_node.clone()).await;
-        new_parent.insert_child(name, src_node).await;
+        let node = &handle_data.node;
+        self.load_directory(ctx, node).await?;
+        let parent_ino = match node.parent.lock().await.upgrade() {
+            Some(p) => p.inode,
+            None => self.root_inode(),
+        };
+        let mut entries = vec![
+            DirectoryEntry {
+                inode: node.inode,
+                kind: FileType::Directory,
+                name: ".".into(),
+                offset: 1,
+            },
+            DirectoryEntry {
+                inode: parent_ino,
+                kind: FileType::Directory,
+                name: "..".into(),
+                offset: 2,
+            },
+        ];
+        let is_root = node.inode == self.root_inode();
+        let children = node.childrens.lock().await;
+        for (name, child) in children.iter() {
+            if child.whiteout.load(Ordering::Relaxed) || (is_root && name == SELF_TEST_DIR) {
+                continue;
+            }
+            entries.push(DirectoryEntry {
+                inode: child.inode,
+                kind: child.kind(ctx).await?,
+                name: name.clone(),
+                offset: 0,
+            });
+        }
+        drop(children);
+        sort_by_cookie(&mut entries[2..], |e| &e.name, |e| &mut e.offset);
 
-        Ok(())
+        let mut snapshot_guard = handle_data.plain_snapshot.lock().await;
+        Ok(Arc::clone(
+            snapshot_guard.get_or_insert_with(|| Arc::new(entries)),
+        ))
     }
 
-    async fn do_symlink(
+    async fn get_or_create_dir_snapshot(
         &self,
         ctx: Request,
-        linkname: &str,
-        parent_node: &Arc<OverlayInode>,
-        name: &str,
-    ) -> Result<()> {
-        let name_os = OsStr::new(name);
-        if self.upper_layer.is_none() {
-            return Err(Error::from_raw_os_error(libc::EROFS));
+        inode: Inode,
+        handle: u64,
+    ) -> Result<Arc<Vec<DirectoryEntryPlus>>> {
+        let handle_data = self.dir_handle_data(ctx, inode, handle).await?;
+        self.drop_stale_snapshots(&handle_data).await;
+
+        // Optimistic check
+        if let Some(snapshot) = handle_data.dir_snapshot.lock().await.as_ref() {
+            return Ok(Arc::clone(snapshot));
         }
 
-        // parent was deleted.
-        if parent_node.whiteout.load(Ordering::Relaxed) {
+        // Snapshot doesn't exist, create it.
+        let ovl_inode = &handle_data.node;
+        self.load_directory(ctx, ovl_inode).await?;
+
+        let mut entries = Vec::new();
+
+        // 1. Add "." entry
+        let mut st_self = ovl_inode.stat64(ctx).await?;
+        self.reply_attr(ctx, ovl_inode, &mut st_self.attr).await?;
+        entries.push(DirectoryEntryPlus {
+            inode: ovl_inode.inode,
+            generation: 0,
+            kind: st_self.attr.kind,
+            name: ".".into(),
+            offset: 1,
+            attr: st_self.attr,
+            entry_ttl: st_self.ttl,
+            attr_ttl: st_self.ttl,
+        });
+
+        // 2. Add ".." entry
+        let parent_node = match ovl_inode.parent.lock().await.upgrade() {
+            Some(node) => node,
+            None => self.root_node().await,
+        };
+        let mut st_parent = parent_node.stat64(ctx).await?;
+        self.reply_attr(ctx, &parent_node, &mut st_parent.attr)
+            .await?;
+        entries.push(DirectoryEntryPlus {
+            inode: parent_node.inode,
+            generation: 0,
+            kind: st_parent.attr.kind,
+            name: "..".into(),
+            offset: 2,
+            attr: st_parent.attr,
+            entry_ttl: st_parent.ttl,
+            attr_ttl: st_parent.ttl,
+        });
+
+        // 3. Add children entries
+        let is_root = ovl_inode.inode == self.root_inode();
+        let children = ovl_inode.childrens.lock().await;
+        for (name, child) in children.iter() {
+            if child.whiteout.load(Ordering::Relaxed) || (is_root && name == SELF_TEST_DIR) {
+                continue;
+            }
+            let mut st_child = child.stat64(ctx).await?;
+            self.reply_attr(ctx, child, &mut st_child.attr).await?;
+            entries.push(DirectoryEntryPlus {
+                inode: child.inode,
+                generation: 0,
+                kind: st_child.attr.kind,
+                name: name.clone(),
+                offset: 0,
+                attr: st_child.attr,
+                entry_ttl: st_child.ttl,
+                attr_ttl: st_child.ttl,
+            });
+        }
+        drop(children);
+        sort_by_cookie(&mut entries[2..], |e| &e.name, |e| &mut e.offset);
+
+        let mut snapshot_guard = handle_data.dir_snapshot.lock().await;
+        // If another thread won the race while we were preparing, discard our work and use
+        // the existing snapshot.
+        Ok(Arc::clone(
+            snapshot_guard.get_or_insert_with(|| Arc::new(entries)),
+        ))
+    }
+
+    async fn do_mkdir(
+        &self,
+        ctx: Request,
+        parent_node: Arc<OverlayInode>,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<()> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        // Parent node was deleted.
+        if parent_node.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let mut delete_whiteout = false;
+        let mut set_opaque = false;
+        if let Some(n) = self
+            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
+            .await?
+        {
+            // Node with same name exists, let's check if it's whiteout.
+            if !n.whiteout.load(Ordering::Relaxed) {
+                return Err(Error::from_raw_os_error(libc::EEXIST));
+            }
+
+            if n.in_upper_layer().await {
+                delete_whiteout = true;
+            }
+
+            // The whiteout hides whatever lower layers have under this name, and so must
+            // the new directory, like kernel overlayfs does when creating over a whiteout.
+            set_opaque = true;
+        }
+
+        // Copy parent node up if necessary.
+        let pnode = self.copy_node_up(ctx, parent_node).await?;
+
+        let path = utils::join_path(&pnode.path.read().await, name);
+        let path_ref = &path;
+        let new_node = Arc::new(Mutex::new(None));
+        pnode
+            .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                let parent_real_inode = match parent_real_inode {
+                    Some(inode) => inode,
+                    None => {
+                        error!("BUG: parent doesn't have upper inode after copied up");
+                        return Err(Error::from_raw_os_error(libc::EINVAL));
+                    }
+                };
+                if delete_whiteout {
+                    let _ = parent_real_inode
+                        .layer
+                        .delete_whiteout(ctx, parent_real_inode.inode, name)
+                        .await;
+                }
+
+                // Allocate inode number.
+                let ino = self.alloc_inode(path_ref).await?;
+                let child_dir = parent_real_inode.mkdir(ctx, name, mode, umask).await?;
+                // Set opaque if child dir has lower layers.
+                if set_opaque {
+                    parent_real_inode
+                        .layer
+                        .set_opaque(ctx, child_dir.inode, self.opaque_xattr()?)
+                        .await?;
+                }
+                let ovi =
+                    OverlayInode::new_from_real_inode(name, ino, path_ref.clone(), child_dir).await;
+                new_node.lock().await.replace(ovi);
+                Ok(false)
+            })
+            .await?;
+
+        // new_node is always 'Some'
+        let nn = new_node.lock().await.take();
+        let arc_node = Arc::new(nn.unwrap());
+        self.insert_inode(arc_node.inode, arc_node.clone()).await;
+        pnode.insert_child(name, arc_node).await;
+        Ok(())
+    }
+
+    async fn do_mknod(
+        &self,
+        ctx: Request,
+        parent_node: &Arc<OverlayInode>,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> Result<()> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        // Parent node was deleted.
+        if parent_node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT));
         }
 
@@ -2005,15 +3286,16 @@ impl OverlayFs {
                                     return Err(Error::from_raw_os_error(libc::EINVAL));
                                 }
                             };
-
                             if n.in_upper_layer().await {
                                 let _ = parent_real_inode
                                     .layer
-                                    .delete_whiteout(ctx, parent_real_inode.inode, name_os)
+                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
                                     .await;
                             }
 
-                            let child_ri = parent_real_inode.symlink(ctx, linkname, name).await?;
+                            let child_ri = parent_real_inode
+                                .mknod(ctx, name, mode, rdev, umask)
+                                .await?;
 
                             // Replace existing real inodes with new one.
                             n.add_upper_inode(child_ri, true).await;
@@ -2025,8 +3307,8 @@ impl OverlayFs {
             None => {
                 // Copy parent node up if necessary.
                 let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
-                let new_node: Arc<Mutex<Option<OverlayInode>>> = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name);
+                let new_node = Arc::new(Mutex::new(None));
+                let path = utils::join_path(&pnode.path.read().await, name);
                 pnode
                     .handle_upper_inode_locked(
                         &mut |parent_real_inode: Option<Arc<RealInode>>| async {
@@ -2040,7 +3322,9 @@ impl OverlayFs {
 
                             // Allocate inode number.
                             let ino = self.alloc_inode(&path).await?;
-                            let child_ri = parent_real_inode.symlink(ctx, linkname, name).await?;
+                            let child_ri = parent_real_inode
+                                .mknod(ctx, name, mode, rdev, umask)
+                                .await?;
                             let ovi = OverlayInode::new_from_real_inode(
                                 name,
                                 ino,
@@ -2055,8 +3339,8 @@ impl OverlayFs {
                     )
                     .await?;
 
-                // new_node is always 'Some'
-                let arc_node = Arc::new(new_node.lock().await.take().unwrap());
+                let nn = new_node.lock().await.take();
+                let arc_node = Arc::new(nn.unwrap());
                 self.insert_inode(arc_node.inode, arc_node.clone()).await;
                 pnode.insert_child(name, arc_node).await;
             }
@@ -2065,774 +3349,4588 @@ impl OverlayFs {
         Ok(())
     }
 
-    /// Copies a symbolic link from a lower layer to the upper layer.
-    ///
-    /// This function is a part of the copy-up process, triggered when a symlink that
-    /// only exists in a lower layer is modified. It reads the link target and attributes
-    /// from the lower layer and creates an identical symlink in the upper layer, crucially
-    /// preserving the original host UID and GID.
-    async fn copy_symlink_up(
+    async fn do_create(
         &self,
         ctx: Request,
-        node: Arc<OverlayInode>,
-    ) -> Result<Arc<OverlayInode>> {
-        if node.in_upper_layer().await {
-            return Ok(node);
+        parent_node: &Arc<OverlayInode>,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<Option<u64>> {
+        let upper = self
+            .upper_layer
+            .as_ref()
+            .cloned()
+            .ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
+
+        // Parent node was deleted.
+        if parent_node.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
         }
 
-        let parent_node = if let Some(ref n) = node.parent.lock().await.upgrade() {
-            Arc::clone(n)
-        } else {
-            return Err(Error::other("no parent?"));
-        };
+        let handle: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+        let real_ino: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+        let new_ovi = match self
+            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
+            .await?
+        {
+            Some(n) => {
+                // Node with same name exists, let's check if it's whiteout.
+                if !n.whiteout.load(Ordering::Relaxed) {
+                    return Err(Error::from_raw_os_error(libc::EEXIST));
+                }
 
-        // To preserve original ownership, we must get the raw, unmapped host attributes.
-        // We achieve this by calling `do_getattr_helper`, which is specifically designed
-        // to bypass the ID mapping logic. This is safe and does not affect other
-        // functionalities because `do_getattr_helper` and the standard `stat64()` call
-        // both rely on the same underlying `stat` system call; they only differ in
-        // whether the resulting `uid` and `gid` are mapped.
-        let (self_layer, _, self_inode) = node.first_layer_inode().await;
-        let re = self_layer.do_getattr_helper(self_inode, None).await?;
-        let st = ReplyAttr {
-            ttl: re.1,
-            attr: convert_stat64_to_file_attr(re.0),
-        };
+                // Copy parent node up if necessary.
+                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
+                pnode
+                    .handle_upper_inode_locked(
+                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                            let parent_real_inode = match parent_real_inode {
+                                Some(inode) => inode,
+                                None => {
+                                    error!("BUG: parent doesn't have upper inode after copied up");
+                                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                                }
+                            };
 
-        if !parent_node.in_upper_layer().await {
-            parent_node.clone().create_upper_dir(ctx, None).await?;
-        }
+                            if n.in_upper_layer().await {
+                                let _ = parent_real_inode
+                                    .layer
+                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
+                                    .await;
+                            }
 
-        // Read the linkname from lower layer.
-        let reply_data = self_layer.readlink(ctx, self_inode).await?;
-        // Convert path to &str.
-        let path = std::str::from_utf8(&reply_data.data)
-            .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+                            let (child_ri, hd) =
+                                parent_real_inode.create(ctx, name, mode, flags).await?;
+                            real_ino.lock().await.replace(child_ri.inode);
+                            handle.lock().await.replace(hd.unwrap());
 
-        let new_upper_real: Arc<Mutex<Option<RealInode>>> = Arc::new(Mutex::new(None));
-        parent_node
-            .handle_upper_inode_locked(&mut |parent_upper_inode: Option<Arc<RealInode>>| async {
-                // We already create upper dir for parent_node above.
-                let parent_real_inode =
-                    parent_upper_inode.ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
-                // We manually unfold the `symlink` logic here instead of calling the `symlink` method directly.
-                // This is necessary to preserve the original file's UID and GID during the copy-up process.
-                if !parent_real_inode.in_upper_layer {
-                    return Err(Error::from_raw_os_error(libc::EROFS));
-                }
-                let link_name = OsStr::new(path);
-                let filename = node.name.read().await;
-                let filename = OsStr::new(filename.as_str());
-                let entry = parent_real_inode
-                    .layer
-                    .do_symlink_helper(
-                        ctx,
-                        parent_real_inode.inode,
-                        filename,
-                        link_name,
-                        st.attr.uid,
-                        st.attr.gid,
+                            // Replace existing real inodes with new one.
+                            n.add_upper_inode(child_ri, true).await;
+                            Ok(false)
+                        },
                     )
                     .await?;
-                let ri = RealInode {
-                    layer: parent_real_inode.layer.clone(),
-                    in_upper_layer: true,
-                    inode: entry.attr.ino,
-                    whiteout: false,
-                    opaque: false,
-                    stat: Some(ReplyAttr {
-                        ttl: entry.ttl,
-                        attr: entry.attr,
-                    }),
-                };
-                new_upper_real.lock().await.replace(ri);
-                Ok(false)
-            })
+                n.clone()
+            }
+            None => {
+                // Copy parent node up if necessary.
+                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
+                let new_node = Arc::new(Mutex::new(None));
+                let path = utils::join_path(&pnode.path.read().await, name);
+                pnode
+                    .handle_upper_inode_locked(
+                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                            let parent_real_inode = match parent_real_inode {
+                                Some(inode) => inode,
+                                None => {
+                                    error!("BUG: parent doesn't have upper inode after copied up");
+                                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                                }
+                            };
+
+                            let (child_ri, hd) =
+                                parent_real_inode.create(ctx, name, mode, flags).await?;
+                            real_ino.lock().await.replace(child_ri.inode);
+                            handle.lock().await.replace(hd.unwrap());
+                            // Allocate inode number.
+                            let ino = self.alloc_inode(&path).await?;
+                            let ovi = OverlayInode::new_from_real_inode(
+                                name,
+                                ino,
+                                path.clone(),
+                                child_ri,
+                            )
+                            .await;
+
+                            new_node.lock().await.replace(ovi);
+                            Ok(false)
+                        },
+                    )
+                    .await?;
+
+                // new_node is always 'Some'
+                let nn = new_node.lock().await.take();
+                let arc_node = Arc::new(nn.unwrap());
+                self.insert_inode(arc_node.inode, arc_node.clone()).await;
+                pnode.insert_child(name, arc_node.clone()).await;
+                arc_node
+            }
+        };
+
+        let final_handle = match *handle.lock().await {
+            Some(hd) => {
+                if self.no_open.load(Ordering::Relaxed) {
+                    // Handle-less, the file was only opened to be created.
+                    let real_ino = real_ino.lock().await.unwrap();
+                    upper.release(ctx, real_ino, hd, flags, 0, false).await?;
+                    None
+                } else {
+                    let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+                    let handle_data = HandleData {
+                        node: new_ovi,
+                        real_handle: Some(RealHandle {
+                            layer: upper.clone(),
+                            in_upper_layer: true,
+                            inode: real_ino.lock().await.unwrap(),
+                            handle: AtomicU64::new(hd),
+                        }),
+                        device: None,
+                        dir_snapshot: Mutex::new(None),
+                        plain_snapshot: Mutex::new(None),
+                        snapshot_version: AtomicU64::new(0),
+                    };
+                    self.handles
+                        .lock()
+                        .await
+                        .insert(handle, Arc::new(handle_data));
+                    Some(handle)
+                }
+            }
+            None => None,
+        };
+        Ok(final_handle)
+    }
+
+    async fn do_rename(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        // RENAME_WHITEOUT is only meant for overlay implementations themselves.
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0
+            || flags & RENAME_NOREPLACE != 0 && flags & RENAME_EXCHANGE != 0
+        {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        let parent_node = self.lookup_node(req, parent, OsStr::new("")).await?;
+        let new_parent_node = self.lookup_node(req, new_parent, OsStr::new("")).await?;
+        let src_node = self.lookup_node(req, parent, name).await?;
+        if src_node.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        let dest_node_opt = self
+            .lookup_node_ignore_enoent(req, new_parent, new_name)
             .await?;
+        // trace!("parent_node: {}, new_parent_node: {}, src_node: {}, dest_node_opt: {:?}", parent_node.inode, new_parent_node.inode, src_node.inode, dest_node_opt.as_ref().map(|n| n.inode));
 
-        if let Some(real_inode) = new_upper_real.lock().await.take() {
-            // update upper_inode and first_inode()
-            node.add_upper_inode(real_inode, true).await;
+        // A whiteout at the destination means the name is free.
+        let dest_exists = dest_node_opt
+            .as_ref()
+            .is_some_and(|n| !n.whiteout.load(Ordering::Relaxed));
+
+        if flags & RENAME_EXCHANGE != 0 {
+            let dest_node = match dest_node_opt {
+                Some(n) if dest_exists => n,
+                _ => return Err(Error::from_raw_os_error(libc::ENOENT)),
+            };
+            if Arc::ptr_eq(&src_node, &dest_node) {
+                return Ok(());
+            }
+            return self
+                .do_rename_exchange(
+                    req,
+                    (parent_node, name, src_node),
+                    (new_parent_node, new_name, dest_node),
+                )
+                .await;
+        }
+        if flags & RENAME_NOREPLACE != 0 && dest_exists {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        // Lower entries are found by name and redirects aren't written, so like kernel
+        // overlayfs with redirect_dir=off, only directories that exist in the upper layer
+        // alone can move. Callers like mv copy the others instead.
+        let src_is_dir = src_node.is_dir(req).await?;
+        if src_is_dir && !src_node.upper_layer_only().await {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        if let Some(dest_node) = &dest_node_opt {
+            let dest_is_dir = dest_node.is_dir(req).await?;
+            if src_is_dir != dest_is_dir {
+                return Err(Error::from_raw_os_error(libc::EISDIR));
+            }
+            if dest_is_dir {
+                self.copy_directory_up(req, dest_node.clone()).await?;
+                let (count, _) = dest_node.count_entries_and_whiteout(req).await?;
+                if count > 0 {
+                    return Err(Error::from_raw_os_error(libc::ENOTEMPTY));
+                }
+            }
+        }
+
+        let pnode = self.copy_node_up(req, parent_node).await?;
+        let new_pnode = self.copy_node_up(req, new_parent_node).await?;
+        let s_node = self.copy_node_up(req, src_node).await?;
+
+        let need_whiteout = !s_node.upper_layer_only().await;
+
+        let (p_layer, p_upper, p_inode) = pnode.first_layer_inode().await;
+        let (new_p_layer, new_p_upper, new_p_inode) = new_pnode.first_layer_inode().await;
+        // Both parents were copied up above, still a rename can't cross layers.
+        if !p_upper || !new_p_upper || !Arc::ptr_eq(&p_layer, &new_p_layer) {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        p_layer
+            .rename(req, p_inode, name, new_p_inode, new_name)
+            .await?;
+
+        // Handle the replaced destination node (if any).
+        if let Some(dest_node) = dest_node_opt {
+            let path = dest_node.path.read().await.clone();
+            self.remove_inode(dest_node.inode, Some(path)).await;
+        }
+
+        // Update the moved source node's state.
+
+        // Remove from old parent.
+        pnode.remove_child(name).await;
+        self.remove_inode(s_node.inode, s_node.path.read().await.clone().into())
+            .await;
+        let new_path = utils::join_path(&new_pnode.path.read().await, new_name);
+        *s_node.path.write().await = new_path;
+        *s_node.name.write().await = new_name.to_os_string();
+        *s_node.parent.lock().await = Arc::downgrade(&new_pnode);
+        new_pnode.insert_child(new_name, s_node.clone()).await;
+        self.insert_inode(s_node.inode, s_node).await;
+
+        // Create whiteout at the old location if necessary.
+        if need_whiteout {
+            p_layer
+                .create_whiteout(req, p_inode, name, self.xattr_whiteout())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Atomically swap two entries for `RENAME_EXCHANGE`.
+    ///
+    /// Both entries end up in the upper layer, so no whiteouts are needed. What moves is only
+    /// the upper inode: each node takes over the lower inodes found under its new name, which
+    /// later decide whether removing it needs a whiteout. Merged directories are fully copied
+    /// up first, and a directory landing on a name that exists in a lower layer is made opaque,
+    /// otherwise the lower entries of its new place would show through.
+    async fn do_rename_exchange(
+        &self,
+        req: Request,
+        src: (Arc<OverlayInode>, &OsStr, Arc<OverlayInode>),
+        dest: (Arc<OverlayInode>, &OsStr, Arc<OverlayInode>),
+    ) -> Result<()> {
+        let (parent_node, name, src_node) = src;
+        let (new_parent_node, new_name, dest_node) = dest;
+
+        // Copy-up of regular files drops the lower inodes, so remember them first.
+        let src_lowers = src_node.lower_inodes().await;
+        let dest_lowers = dest_node.lower_inodes().await;
+
+        let src_is_dir = src_node.is_dir(req).await?;
+        let dest_is_dir = dest_node.is_dir(req).await?;
+        // A merged directory has to take its lower entries along, they stay behind by name.
+        if src_is_dir && !src_lowers.is_empty() {
+            self.copy_directory_up(req, Arc::clone(&src_node)).await?;
+        }
+        if dest_is_dir && !dest_lowers.is_empty() {
+            self.copy_directory_up(req, Arc::clone(&dest_node)).await?;
+        }
+
+        let pnode = self.copy_node_up(req, parent_node).await?;
+        let new_pnode = self.copy_node_up(req, new_parent_node).await?;
+        let s_node = self.copy_node_up(req, src_node).await?;
+        let d_node = self.copy_node_up(req, dest_node).await?;
+
+        let (p_layer, _, p_inode) = pnode.first_layer_inode().await;
+        let (_, _, new_p_inode) = new_pnode.first_layer_inode().await;
+        p_layer
+            .rename2(req, p_inode, name, new_p_inode, new_name, RENAME_EXCHANGE)
+            .await?;
+
+        for node in [&s_node, &d_node] {
+            let path = node.path.read().await.clone();
+            self.remove_inode(node.inode, Some(path)).await;
+        }
+        self.place_exchanged(req, &new_pnode, new_name, &s_node, src_is_dir, dest_lowers)
+            .await?;
+        self.place_exchanged(req, &pnode, name, &d_node, dest_is_dir, src_lowers)
+            .await
+    }
+
+    /// Move `node` to `name` under `pnode` after its upper entry was exchanged there.
+    async fn place_exchanged(
+        &self,
+        req: Request,
+        pnode: &Arc<OverlayInode>,
+        name: &OsStr,
+        node: &Arc<OverlayInode>,
+        is_dir: bool,
+        lowers: Vec<Arc<RealInode>>,
+    ) -> Result<()> {
+        let mut upper = node.real_inodes.lock().await[0].clone();
+        if is_dir && !lowers.is_empty() {
+            upper
+                .layer
+                .set_opaque(req, upper.inode, self.opaque_xattr()?)
+                .await?;
+            // Look the directory up again so the cached real inode knows it is opaque.
+            let parent_upper = pnode.real_inodes.lock().await[0].clone();
+            upper = parent_upper
+                .lookup_child(req, name)
+                .await?
+                .map(Arc::new)
+                .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))?;
+        }
+        {
+            let mut inodes = node.real_inodes.lock().await;
+            inodes.clear();
+            inodes.push(upper);
+            inodes.extend(lowers);
+        }
+
+        *node.path.write().await = utils::join_path(&pnode.path.read().await, name);
+        *node.name.write().await = name.to_os_string();
+        *node.parent.lock().await = Arc::downgrade(pnode);
+        pnode.insert_child(name, Arc::clone(node)).await;
+        self.insert_inode(node.inode, Arc::clone(node)).await;
+        Ok(())
+    }
+
+    async fn do_link(
+        &self,
+        ctx: Request,
+        src_node: &Arc<OverlayInode>,
+        new_parent: &Arc<OverlayInode>,
+        name: &OsStr,
+    ) -> Result<()> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        // Node is whiteout.
+        if src_node.whiteout.load(Ordering::Relaxed) || new_parent.whiteout.load(Ordering::Relaxed)
+        {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let st = src_node.stat64(ctx).await?;
+        if utils::is_dir(&st.attr.kind) {
+            // Directory can't be hardlinked.
+            return Err(Error::from_raw_os_error(libc::EPERM));
+        }
+
+        let src_node = self.copy_node_up(ctx, Arc::clone(src_node)).await?;
+        let new_parent = self.copy_node_up(ctx, Arc::clone(new_parent)).await?;
+        let src_ino = src_node.first_layer_inode().await.2;
+
+        if let Some(existing_node) = self
+            .lookup_node_ignore_enoent(ctx, new_parent.inode, name)
+            .await?
+        {
+            // If it's not a whiteout, it's an error
+            if !existing_node.whiteout.load(Ordering::Relaxed) {
+                return Err(Error::from_raw_os_error(libc::EEXIST));
+            }
+            // If it is a whiteout, we will overwrite it.
+            // First, remove the physical whiteout file in the upper layer.
+            new_parent
+                .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                    let parent_ri = parent_real_inode.ok_or_else(|| {
+                        error!("BUG: parent doesn't have upper inode after copied up");
+                        Error::from_raw_os_error(libc::EINVAL)
+                    })?;
+                    // Only delete if the whiteout is in the upper layer
+                    if existing_node.in_upper_layer().await {
+                        let _ = parent_ri
+                            .layer
+                            .delete_whiteout(ctx, parent_ri.inode, name)
+                            .await;
+                    }
+                    Ok(false)
+                })
+                .await?;
+        }
+
+        new_parent
+            .handle_upper_inode_locked(&mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                let parent_real_inode = match parent_real_inode {
+                    Some(inode) => inode,
+                    None => {
+                        error!("BUG: parent doesn't have upper inode after copied up");
+                        return Err(Error::from_raw_os_error(libc::EINVAL));
+                    }
+                };
+
+                parent_real_inode.link(ctx, src_ino, name).await?;
+
+                Ok(false)
+            })
+            .await?;
+
+        self.insert_inode(src_node.inode, src_node.clone()).await;
+        new_parent.insert_child(name, src_node).await;
+
+        Ok(())
+    }
+
+    async fn do_symlink(
+        &self,
+        ctx: Request,
+        linkname: &OsStr,
+        parent_node: &Arc<OverlayInode>,
+        name: &OsStr,
+    ) -> Result<()> {
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        // parent was deleted.
+        if parent_node.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        match self
+            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
+            .await?
+        {
+            Some(n) => {
+                // Node with same name exists, let's check if it's whiteout.
+                if !n.whiteout.load(Ordering::Relaxed) {
+                    return Err(Error::from_raw_os_error(libc::EEXIST));
+                }
+
+                // Copy parent node up if necessary.
+                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
+                pnode
+                    .handle_upper_inode_locked(
+                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                            let parent_real_inode = match parent_real_inode {
+                                Some(inode) => inode,
+                                None => {
+                                    error!("BUG: parent doesn't have upper inode after copied up");
+                                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                                }
+                            };
+
+                            if n.in_upper_layer().await {
+                                let _ = parent_real_inode
+                                    .layer
+                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
+                                    .await;
+                            }
+
+                            let child_ri = parent_real_inode.symlink(ctx, linkname, name).await?;
+
+                            // Replace existing real inodes with new one.
+                            n.add_upper_inode(child_ri, true).await;
+                            Ok(false)
+                        },
+                    )
+                    .await?;
+            }
+            None => {
+                // Copy parent node up if necessary.
+                let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
+                let new_node: Arc<Mutex<Option<OverlayInode>>> = Arc::new(Mutex::new(None));
+                let path = utils::join_path(&pnode.path.read().await, name);
+                pnode
+                    .handle_upper_inode_locked(
+                        &mut |parent_real_inode: Option<Arc<RealInode>>| async {
+                            let parent_real_inode = match parent_real_inode {
+                                Some(inode) => inode,
+                                None => {
+                                    error!("BUG: parent doesn't have upper inode after copied up");
+                                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                                }
+                            };
+
+                            // Allocate inode number.
+                            let ino = self.alloc_inode(&path).await?;
+                            let child_ri = parent_real_inode.symlink(ctx, linkname, name).await?;
+                            let ovi = OverlayInode::new_from_real_inode(
+                                name,
+                                ino,
+                                path.clone(),
+                                child_ri,
+                            )
+                            .await;
+
+                            new_node.lock().await.replace(ovi);
+                            Ok(false)
+                        },
+                    )
+                    .await?;
+
+                // new_node is always 'Some'
+                let arc_node = Arc::new(new_node.lock().await.take().unwrap());
+                self.insert_inode(arc_node.inode, arc_node.clone()).await;
+                pnode.insert_child(name, arc_node).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies a symbolic link from a lower layer to the upper layer.
+    ///
+    /// This function is a part of the copy-up process, triggered when a symlink that
+    /// only exists in a lower layer is modified. It reads the link target and attributes
+    /// from the lower layer and creates an identical symlink in the upper layer, crucially
+    /// preserving the original host UID and GID.
+    async fn copy_symlink_up(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+    ) -> Result<Arc<OverlayInode>> {
+        if node.in_upper_layer().await {
+            return Ok(node);
+        }
+
+        let parent_node = if let Some(ref n) = node.parent.lock().await.upgrade() {
+            Arc::clone(n)
+        } else {
+            return Err(Error::other("no parent?"));
+        };
+
+        // To preserve original ownership, we must get the raw, unmapped host attributes.
+        // We achieve this by calling `do_getattr_helper`, which is specifically designed
+        // to bypass the ID mapping logic. This is safe and does not affect other
+        // functionalities because `do_getattr_helper` and the standard `stat64()` call
+        // both rely on the same underlying `stat` system call; they only differ in
+        // whether the resulting `uid` and `gid` are mapped.
+        let (self_layer, _, self_inode) = node.first_layer_inode().await;
+        let re = self_layer.do_getattr_helper(self_inode, None).await?;
+        let st = ReplyAttr {
+            ttl: re.1,
+            attr: convert_stat64_to_file_attr(re.0),
+        };
+
+        if !parent_node.in_upper_layer().await {
+            parent_node
+                .clone()
+                .create_upper_dir(ctx, None, &self.config.xattr_eperm)
+                .await?;
+        }
+
+        // Read the linkname from lower layer.
+        let reply_data = self_layer.readlink(ctx, self_inode).await?;
+        // Link targets are raw bytes just like names.
+        let link_name = OsStr::from_bytes(&reply_data.data);
+
+        let new_upper_real: Arc<Mutex<Option<RealInode>>> = Arc::new(Mutex::new(None));
+        parent_node
+            .handle_upper_inode_locked(&mut |parent_upper_inode: Option<Arc<RealInode>>| async {
+                // We already create upper dir for parent_node above.
+                let parent_real_inode =
+                    parent_upper_inode.ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
+                // We manually unfold the `symlink` logic here instead of calling the `symlink` method directly.
+                // This is necessary to preserve the original file's UID and GID during the copy-up process.
+                if !parent_real_inode.in_upper_layer {
+                    return Err(Error::from_raw_os_error(libc::EROFS));
+                }
+                let filename = node.name.read().await;
+                let filename = filename.as_os_str();
+                let entry = parent_real_inode
+                    .layer
+                    .do_symlink_helper(
+                        ctx,
+                        parent_real_inode.inode,
+                        filename,
+                        link_name,
+                        st.attr.uid,
+                        st.attr.gid,
+                    )
+                    .await?;
+                let ri = RealInode {
+                    layer: parent_real_inode.layer.clone(),
+                    in_upper_layer: true,
+                    inode: entry.attr.ino,
+                    whiteout: false,
+                    opaque: false,
+                    stat: Some(ReplyAttr {
+                        ttl: entry.ttl,
+                        attr: entry.attr,
+                    }),
+                };
+                new_upper_real.lock().await.replace(ri);
+                Ok(false)
+            })
+            .await?;
+
+        if let Some(real_inode) = new_upper_real.lock().await.take() {
+            // update upper_inode and first_inode()
+            node.add_upper_inode(real_inode, true).await;
+        }
+
+        Ok(node)
+    }
+
+    /// Copies a FIFO, socket or device node from a lower layer to the upper layer.
+    ///
+    /// Such files have no content, the node is created again with `mknod` in the upper
+    /// layer with the mode, device number and host UID and GID of the lower one. Device
+    /// nodes need `CAP_MKNOD` to be copied up.
+    async fn copy_special_up(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+    ) -> Result<Arc<OverlayInode>> {
+        if node.in_upper_layer().await {
+            return Ok(node);
+        }
+
+        let parent_node = if let Some(ref n) = node.parent.lock().await.upgrade() {
+            Arc::clone(n)
+        } else {
+            return Err(Error::other("no parent?"));
+        };
+
+        // Raw host attributes, see `copy_symlink_up`.
+        let (self_layer, _, self_inode) = node.first_layer_inode().await;
+        let re = self_layer.do_getattr_helper(self_inode, None).await?;
+        let st = convert_stat64_to_file_attr(re.0);
+        let mode = mode_from_kind_and_perm(st.kind, st.perm);
+
+        if !parent_node.in_upper_layer().await {
+            parent_node
+                .clone()
+                .create_upper_dir(ctx, None, &self.config.xattr_eperm)
+                .await?;
+        }
+
+        let new_upper_real: Arc<Mutex<Option<RealInode>>> = Arc::new(Mutex::new(None));
+        parent_node
+            .handle_upper_inode_locked(&mut |parent_upper_inode: Option<Arc<RealInode>>| async {
+                let parent_real_inode =
+                    parent_upper_inode.ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
+                if !parent_real_inode.in_upper_layer {
+                    return Err(Error::from_raw_os_error(libc::EROFS));
+                }
+                let filename = node.name.read().await;
+                let entry = parent_real_inode
+                    .layer
+                    .do_mknod_helper(
+                        ctx,
+                        parent_real_inode.inode,
+                        filename.as_os_str(),
+                        mode,
+                        st.rdev,
+                        st.uid,
+                        st.gid,
+                    )
+                    .await?;
+                new_upper_real.lock().await.replace(RealInode {
+                    layer: parent_real_inode.layer.clone(),
+                    in_upper_layer: true,
+                    inode: entry.attr.ino,
+                    whiteout: false,
+                    opaque: false,
+                    stat: Some(ReplyAttr {
+                        ttl: entry.ttl,
+                        attr: entry.attr,
+                    }),
+                });
+                Ok(false)
+            })
+            .await?;
+
+        if let Some(real_inode) = new_upper_real.lock().await.take() {
+            node.add_upper_inode(real_inode, true).await;
+        }
+
+        Ok(node)
+    }
+
+    /// Copies a regular file and its contents from a lower layer to the upper layer.
+    ///
+    /// This function is a core part of the copy-up process, triggered when a regular file
+    /// that only exists in a lower layer is written to. It creates an empty file in the
+    /// upper layer with the original file's attributes (mode, UID, GID), and then copies
+    /// the entire content from the lower layer file to the new upper layer file.
+    ///
+    /// When the copy-up is for a truncation to `truncate` bytes, only the data before it is
+    /// copied, so truncating a large lower file doesn't copy what is dropped right after.
+    async fn copy_regfile_up(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+        truncate: Option<u64>,
+    ) -> Result<Arc<OverlayInode>> {
+        if node.in_upper_layer().await {
+            return Ok(node);
+        }
+
+        // Hold the lock table across the whole copy-up so held POSIX locks can be moved to the
+        // upper inode before anyone else locks it.
+        let locks = node.posix_locks.lock().await;
+        if node.in_upper_layer().await {
+            return Ok(Arc::clone(&node));
+        }
+
+        let parent_node = if let Some(ref n) = node.parent.lock().await.upgrade() {
+            Arc::clone(n)
+        } else {
+            return Err(Error::other("no parent?"));
+        };
+
+        // To preserve original ownership, we must get the raw, unmapped host attributes.
+        // We achieve this by calling `do_getattr_helper`, which is specifically designed
+        // to bypass the ID mapping logic. This is safe and does not affect other
+        // functionalities because `do_getattr_helper` and the standard `stat64()` call
+        // both rely on the same underlying `stat` system call; they only differ in
+        // whether the resulting `uid` and `gid` are mapped.
+        let (lower_layer, _, lower_inode) = node.first_layer_inode().await;
+        let re = lower_layer.do_getattr_helper(lower_inode, None).await?;
+        let lower_dev = re.0.st_dev;
+        let st = ReplyAttr {
+            ttl: re.1,
+            attr: convert_stat64_to_file_attr(re.0),
+        };
+        trace!(
+            "copy_regfile_up: node {} in lower layer's inode {}",
+            node.inode, lower_inode
+        );
+
+        if !parent_node.in_upper_layer().await {
+            parent_node
+                .clone()
+                .create_upper_dir(ctx, None, &self.config.xattr_eperm)
+                .await?;
+        }
+
+        // create the file in upper layer using information from lower layer
+
+        let flags = libc::O_WRONLY;
+        let mode = mode_from_kind_and_perm(st.attr.kind, st.attr.perm);
+
+        let upper_handle = Arc::new(Mutex::new(0));
+        let upper_real_inode = Arc::new(Mutex::new(None));
+        parent_node
+            .handle_upper_inode_locked(&mut |parent_upper_inode: Option<Arc<RealInode>>| async {
+                // We already create upper dir for parent_node.
+                let parent_real_inode = parent_upper_inode.ok_or_else(|| {
+                    error!("parent {} has no upper inode", parent_node.inode);
+                    Error::from_raw_os_error(libc::EINVAL)
+                })?;
+                // We manually unfold the `create` logic here instead of calling the `create` method directly.
+                // This is necessary to preserve the original file's UID and GID during the copy-up process.
+                if !parent_real_inode.in_upper_layer {
+                    return Err(Error::from_raw_os_error(libc::EROFS));
+                }
+                let name = node.name.read().await;
+                let name = name.as_os_str();
+                let create_rep = parent_real_inode
+                    .layer
+                    .do_create_helper(
+                        ctx,
+                        parent_real_inode.inode,
+                        name,
+                        mode,
+                        flags.try_into().unwrap(),
+                        st.attr.uid,
+                        st.attr.gid,
+                    )
+                    .await?;
+
+                let (inode, h) = (
+                    RealInode {
+                        layer: parent_real_inode.layer.clone(),
+                        in_upper_layer: true,
+                        inode: create_rep.attr.ino,
+                        whiteout: false,
+                        opaque: false,
+                        stat: Some(ReplyAttr {
+                            ttl: create_rep.ttl,
+                            attr: create_rep.attr,
+                        }),
+                    },
+                    Some(create_rep.fh),
+                );
+                trace!(
+                    "copy_regfile_up: created upper file {name:?} with inode {}",
+                    inode.inode
+                );
+                *upper_handle.lock().await = h.unwrap_or(0);
+                upper_real_inode.lock().await.replace(inode);
+                Ok(false)
+            })
+            .await?;
+
+        let rep = lower_layer
+            .open(ctx, lower_inode, libc::O_RDONLY as u32)
+            .await?;
+
+        let lower_handle = rep.fh;
+
+        // need to use work directory and then rename file to
+        // final destination for atomic reasons.. not deal with it for now,
+        // use stupid copy at present.
+        // FIXME: this need a lot of work here, ntimes, etc.
+
+        // Copy from lower real inode to upper real inode.
+        // TODO: use sendfile here.
+
+        let u_handle = *upper_handle.lock().await;
+        let ri = upper_real_inode.lock().await.take();
+        if let Some(ri) = ri {
+            let mut offset: usize = 0;
+            let size = 4 * 1024 * 1024;
+            // Bytes of the lower file that survive the truncation the copy-up is for.
+            let data_size = truncate.map_or(st.attr.size, |t| t.min(st.attr.size));
+            let copy = self.copy_ups.begin(node.inode, ctx.unique, data_size);
+            let mut cancelled = false;
+
+            // Share the extents of the lower file when both layers sit on a filesystem able
+            // to clone files, the copy loop below then has nothing left to do.
+            let mut cloned = false;
+            if !self.config.no_reflink && data_size > 0 && self.reflinks.worth_trying(lower_dev) {
+                match ri
+                    .layer
+                    .clone_file_from(ri.inode, u_handle, &lower_layer, lower_inode, lower_handle)
+                    .await
+                {
+                    Ok(()) => {
+                        copy.add_cloned(data_size);
+                        cloned = true;
+                    }
+                    Err(e) => {
+                        debug!(
+                            "copy_regfile_up: cloning node {} failed, copying: {e}",
+                            node.inode
+                        );
+                        self.reflinks.failed(lower_dev, &e);
+                    }
+                }
+            }
+
+            if cloned {
+                // The clone took the whole file, drop what the truncation would.
+                if data_size < st.attr.size {
+                    let attr = SetAttr {
+                        size: Some(data_size),
+                        ..Default::default()
+                    };
+                    ri.layer
+                        .setattr(ctx, ri.inode, Some(u_handle), attr)
+                        .await?;
+                }
+            } else if data_size > 0 {
+                // Only the data ranges of sparse lower files are copied, the holes between
+                // them stay holes in the upper file.
+                let mut data = Some(0..0);
+                loop {
+                    if copy.is_cancelled() {
+                        cancelled = true;
+                        break;
+                    }
+                    if data.as_ref().is_some_and(|r| offset as u64 >= r.end) {
+                        let at = offset as u64;
+                        match next_data_range(ctx, &lower_layer, lower_inode, lower_handle, at)
+                            .await
+                        {
+                            Ok(Some(range)) => {
+                                offset = range.start as usize;
+                                data = Some(range);
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                debug!("copy_regfile_up: can't find holes, copying all: {e}");
+                                data = None;
+                            }
+                        }
+                    }
+                    if offset as u64 >= data_size {
+                        break;
+                    }
+                    let end = data.as_ref().map_or(data_size, |r| r.end.min(data_size));
+                    let len = (end - offset as u64).min(size as u64) as u32;
+                    let ret = lower_layer
+                        .read(ctx, lower_inode, lower_handle, offset as u64, len)
+                        .await?;
+
+                    let len = ret.data.len();
+                    if len == 0 {
+                        break;
+                    }
+
+                    let ret = ri
+                        .layer
+                        .write(ctx, ri.inode, u_handle, offset as u64, &ret.data, 0, 0)
+                        .await?;
+
+                    assert_eq!(ret.written as usize, len);
+                    offset += ret.written as usize;
+                    copy.add_copied(len as u64);
+                }
+                // A trailing hole only shows in the size.
+                if !cancelled && (offset as u64) < data_size {
+                    let attr = SetAttr {
+                        size: Some(data_size),
+                        ..Default::default()
+                    };
+                    ri.layer
+                        .setattr(ctx, ri.inode, Some(u_handle), attr)
+                        .await?;
+                }
+            }
+
+            if let Err(e) = ri.layer.release(ctx, ri.inode, u_handle, 0, 0, true).await {
+                let e: std::io::Error = e.into();
+                // Ignore ENOSYS.
+                if e.raw_os_error() != Some(libc::ENOSYS) {
+                    return Err(e);
+                }
+            }
+            let copied = if cancelled {
+                debug!("copy_regfile_up: copy-up of node {} cancelled", node.inode);
+                Err(Error::from_raw_os_error(libc::ECANCELED))
+            } else {
+                // Copied after the data, writes drop security.capability.
+                copy_up_xattrs(
+                    ctx,
+                    &self.config.xattr_eperm,
+                    (&lower_layer, lower_inode),
+                    (&ri.layer, ri.inode),
+                )
+                .await
+            };
+            if let Err(e) = copied {
+                // Don't leave a partial copy or one without its xattrs behind, it would
+                // shadow the lower file.
+                let name = node.name.read().await.clone();
+                parent_node
+                    .handle_upper_inode_locked(&mut |parent_upper: Option<Arc<RealInode>>| async {
+                        if let Some(p) = parent_upper {
+                            let _ = p.layer.unlink(ctx, p.inode, &name).await;
+                        }
+                        Ok(false)
+                    })
+                    .await?;
+                let _ = lower_layer
+                    .release(ctx, lower_inode, lower_handle, 0, 0, true)
+                    .await;
+                return Err(e);
+            }
+            let (upper_layer, upper_inode) = (ri.layer.clone(), ri.inode);
+            node.add_upper_inode(ri, true).await;
+            if !locks.is_empty() {
+                self.migrate_posix_locks(
+                    ctx,
+                    &locks,
+                    (&lower_layer, lower_inode),
+                    (&upper_layer, upper_inode),
+                )
+                .await;
+            }
+        } else {
+            error!("BUG: upper real inode is None after copy up");
+        }
+
+        lower_layer
+            .release(ctx, lower_inode, lower_handle, 0, 0, true)
+            .await?;
+
+        Ok(Arc::clone(&node))
+    }
+
+    /// Copies the specified node to the upper layer of the filesystem
+    ///
+    /// Performs different operations based on the node type:
+    /// - **Directory**: Creates a corresponding directory in the upper layer
+    /// - **Symbolic link**: Recursively copies to the upper layer
+    /// - **Regular file**: Copies file content to the upper layer
+    /// - **FIFO, socket or device node**: Creates the node again in the upper layer
+    ///
+    /// # Parameters
+    /// * `ctx`: FUSE request context
+    /// * `node`: Reference to the node to be copied
+    ///
+    /// # Returns
+    /// Returns a reference to the upper-layer node on success, or an error on failure
+    async fn copy_node_up(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+    ) -> Result<Arc<OverlayInode>> {
+        if node.in_upper_layer().await {
+            return Ok(node);
+        }
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        let st = node.stat64(ctx).await?;
+        match st.attr.kind {
+            FileType::Directory => {
+                node.clone()
+                    .create_upper_dir(ctx, None, &self.config.xattr_eperm)
+                    .await?;
+                Ok(node)
+            }
+            FileType::Symlink => {
+                // For symlink.
+                self.copy_symlink_up(ctx, node).await
+            }
+            FileType::RegularFile => {
+                // For regular file.
+                self.copy_regfile_up(ctx, node, None).await
+            }
+            FileType::NamedPipe
+            | FileType::Socket
+            | FileType::CharDevice
+            | FileType::BlockDevice => self.copy_special_up(ctx, node).await,
+        }
+    }
+
+    /// Like [`Self::copy_node_up`], for a node about to be truncated to `size` bytes. A lower
+    /// regular file then only has its data before `size` copied, none at all for 0.
+    async fn copy_node_up_truncated(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+        size: u64,
+    ) -> Result<Arc<OverlayInode>> {
+        if node.in_upper_layer().await || self.upper_layer.is_none() {
+            return self.copy_node_up(ctx, node).await;
+        }
+        if node.stat64(ctx).await?.attr.kind == FileType::RegularFile {
+            return self.copy_regfile_up(ctx, node, Some(size)).await;
+        }
+        self.copy_node_up(ctx, node).await
+    }
+
+    /// recursively copy directory and all its contents to upper layer
+    async fn copy_directory_up(
+        &self,
+        ctx: Request,
+        node: Arc<OverlayInode>,
+    ) -> Result<Arc<OverlayInode>> {
+        // Ensure the directory itself is copied up first
+        self.copy_node_up(ctx, node.clone()).await?;
+
+        // load directory to cache
+        self.load_directory(ctx, &node).await?;
+
+        // go through all children
+        let children = node.childrens.lock().await.clone();
+        for (_name, child) in children.iter() {
+            if _name == "." || _name == ".." {
+                continue;
+            }
+            // jump over whiteout
+            if child.whiteout.load(Ordering::Relaxed) {
+                continue;
+            }
+            let st = child.stat64(ctx).await?;
+            if !child.in_upper_layer().await {
+                match st.attr.kind {
+                    FileType::Directory => {
+                        // recursively copy subdirectory
+                        Box::pin(self.copy_directory_up(ctx, child.clone())).await?;
+                    }
+                    _ => {
+                        // copy node up symlink, regular or special file
+                        Box::pin(self.copy_node_up(ctx, child.clone())).await?;
+                    }
+                }
+            } else if utils::is_dir(&st.attr.kind) {
+                // If it is already in the upper layer, but the directory is not loaded,
+                // ensure that its contents are also copied up recursively.
+                Box::pin(self.copy_directory_up(ctx, child.clone())).await?;
+            }
+        }
+
+        Ok(node)
+    }
+
+    async fn do_rm(&self, ctx: Request, parent: u64, name: &OsStr, dir: bool) -> Result<()> {
+        self.do_rm_checked(ctx, parent, name, dir, dir).await
+    }
+
+    // Like `do_rm`, not checking that the directory is empty unless `check_empty`.
+    async fn do_rm_checked(
+        &self,
+        ctx: Request,
+        parent: u64,
+        name: &OsStr,
+        dir: bool,
+        check_empty: bool,
+    ) -> Result<()> {
+        // 1. Read-only mount guard
+        if self.upper_layer.is_none() {
+            return Err(Error::from_raw_os_error(libc::EROFS));
+        }
+
+        // 2. Locate the parent Overlay Inode.
+        // Find parent Overlay Inode.
+        let pnode = self.lookup_node(ctx, parent, OsStr::new("")).await?;
+        if pnode.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        let to_name = name;
+
+        // 3. Locate the child Overlay Inode for the given name
+        // Find the Overlay Inode for child with <name>.
+        let node = self.lookup_node(ctx, parent, to_name).await?;
+        if node.whiteout.load(Ordering::Relaxed) {
+            // already deleted.
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        // 4. If removing a directory, ensure it is empty of real entries
+        let mut whiteouts_only = false;
+        if check_empty {
+            self.load_directory(ctx, &node).await?;
+            let (count, whiteouts) = node.count_entries_and_whiteout(ctx).await?;
+            trace!("entries: {count}, whiteouts: {whiteouts}\n");
+            if count > 0 {
+                return Err(Error::from_raw_os_error(libc::ENOTEMPTY));
+            }
+
+            // The upper directory goes with its whiteouts, see `df`.
+            whiteouts_only = whiteouts > 0 && node.in_upper_layer().await;
+        }
+
+        // 5. Decide whether we need to create a whiteout entry
+        // We'll filp this off if upper-layer unlink suffices or parent is opaque
+        let need_whiteout = AtomicBool::new(true);
+        let pnode = self.copy_node_up(ctx, Arc::clone(&pnode)).await?;
+
+        if node.upper_layer_only().await {
+            need_whiteout.store(false, Ordering::Relaxed);
+        }
+
+        let mut df = |parent_upper_inode: Option<Arc<RealInode>>| async {
+            let parent_real_inode = parent_upper_inode.ok_or_else(|| {
+                error!(
+                    "BUG: parent {} has no upper inode after copy up",
+                    pnode.inode
+                );
+                Error::from_raw_os_error(libc::EINVAL)
+            })?;
+
+            // Parent is opaque, it shadows everything in lower layers so no need to create extra whiteouts.
+            if parent_real_inode.opaque {
+                need_whiteout.store(false, Ordering::Relaxed);
+            }
+            if whiteouts_only {
+                // A merged directory emptied through the mount holds a whiteout for every
+                // lower entry, the layer removes them together with the directory.
+                parent_real_inode
+                    .layer
+                    .remove_whiteout_dir(ctx, parent_real_inode.inode, name)
+                    .await?;
+            } else if dir {
+                parent_real_inode
+                    .layer
+                    .rmdir(ctx, parent_real_inode.inode, name)
+                    .await?;
+            } else {
+                parent_real_inode
+                    .layer
+                    .unlink(ctx, parent_real_inode.inode, name)
+                    .await?;
+            }
+
+            Ok(false)
+        };
+
+        // 6. Perform the unlink/rmdir operation and memory cleanup
+        if node.in_upper_layer().await {
+            pnode.handle_upper_inode_locked(&mut df).await?;
+        }
+        pnode.remove_child(name).await;
+        if whiteouts_only {
+            let children = std::mem::take(&mut *node.childrens.lock().await);
+            for child in children.into_values() {
+                let cpath = child.path.read().await.clone();
+                self.remove_inode(child.inode, Some(cpath)).await;
+            }
+        }
+        let path = node.path.read().await.clone();
+        self.remove_inode(node.inode, Some(path)).await;
+
+        // 7. If needed, create a entry in the upper layer to mask lower-layer files
+        if need_whiteout.load(Ordering::Relaxed) {
+            trace!("do_rm: creating whiteout\n");
+            // pnode is copied up, so it has upper layer.
+            pnode
+                .handle_upper_inode_locked(
+                    &mut |parent_upper_inode: Option<Arc<RealInode>>| async {
+                        let parent_real_inode = parent_upper_inode.ok_or_else(|| {
+                            error!(
+                                "BUG: parent {} has no upper inode after copy up",
+                                pnode.inode
+                            );
+                            Error::from_raw_os_error(libc::EINVAL)
+                        })?;
+
+                        let child_ri = parent_real_inode
+                            .create_whiteout(ctx, to_name, self.xattr_whiteout())
+                            .await?; //FIXME..............
+                        let path = utils::join_path(&pnode.path.read().await, to_name);
+                        let ino: u64 = self.alloc_inode(&path).await?;
+                        let ovi = Arc::new(
+                            OverlayInode::new_from_real_inode(to_name, ino, path.clone(), child_ri)
+                                .await,
+                        );
+
+                        self.insert_inode(ino, ovi.clone()).await;
+                        pnode.insert_child(to_name, ovi.clone()).await;
+                        Ok(false)
+                    },
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn do_fsync(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        datasync: bool,
+        handle: Handle,
+        syncdir: bool,
+    ) -> Result<()> {
+        if syncdir && self.no_opendir.load(Ordering::Relaxed) {
+            return self.fsync_dir_handle_less(ctx, inode, datasync).await;
+        }
+
+        // Use O_RDONLY flags which indicates no copy up.
+        let data = self
+            .get_data(ctx, Some(handle), inode, libc::O_RDONLY as u32)
+            .await?;
+
+        trace!("do_fsync: got data for handle: {handle}, inode:{inode}");
+
+        let result = match data.real_handle {
+            // Nothing to sync for an emulated device.
+            None if data.device.is_some() => Ok(()),
+            // FIXME: need to test if inode matches corresponding handle?
+            None => {
+                trace!("do_fsync: no real handle found for handle: {handle}, inode:{inode}");
+                Err(Error::from_raw_os_error(libc::ENOENT))
+            }
+            Some(ref rh) => {
+                let real_handle = rh.handle.load(Ordering::Relaxed);
+                // TODO: check if it's in upper layer? @weizhang555
+                if syncdir {
+                    trace!(
+                        "do_fsync: layer.fsyncdir called for handle: {}, inode:{}; rh.inode: {}, real_handle: {}",
+                        handle, inode, rh.inode, real_handle
+                    );
+                    rh.layer
+                        .fsyncdir(ctx, rh.inode, real_handle, datasync)
+                        .await
+                        .map_err(|e| e.into())
+                } else {
+                    rh.layer
+                        .fsync(ctx, rh.inode, real_handle, datasync)
+                        .await
+                        .map_err(|e| e.into())
+                }
+            }
+        };
+        self.put_data(ctx, &data).await;
+        result
+    }
+
+    // fsyncdir without a directory handle: the directory is opened on its top layer just to
+    // sync it.
+    async fn fsync_dir_handle_less(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        datasync: bool,
+    ) -> Result<()> {
+        let node = self.lookup_node(ctx, inode, OsStr::new("")).await?;
+        if node.whiteout.load(Ordering::Relaxed) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        let (layer, _, real_inode) = node.first_layer_inode().await;
+        let reply = match layer.opendir(ctx, real_inode, libc::O_RDONLY as u32).await {
+            Ok(reply) => reply,
+            // The layer is handle-less as well.
+            Err(e) if Error::from(e).raw_os_error() == Some(libc::ENOSYS) => ReplyOpen {
+                fh: 0,
+                flags: 0,
+                backing_id: None,
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let result = layer.fsyncdir(ctx, real_inode, reply.fh, datasync).await;
+        if reply.fh != 0 {
+            layer
+                .releasedir(ctx, real_inode, reply.fh, reply.flags)
+                .await?;
+        }
+        Ok(result?)
+    }
+
+    // Find the node a lock request is for, the handle keeps it alive even after unlink.
+    async fn lock_node(&self, ctx: Request, inode: Inode, fh: Handle) -> Result<Arc<OverlayInode>> {
+        if let Some(hd) = self.handles.lock().await.get(&fh)
+            && hd.node.inode == inode
+        {
+            return Ok(hd.node.clone());
+        }
+        self.lookup_node(ctx, inode, OsStr::new("")).await
+    }
+
+    // POSIX locks are always taken on the node's topmost real inode, whichever layer the
+    // handle was opened on, so that every handle of the node sees the same locks.
+    #[allow(clippy::too_many_arguments)]
+    async fn do_getlk(
+        &self,
+        ctx: Request,
+        node: &OverlayInode,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        let _locks = node.posix_locks.lock().await;
+        let (layer, _, real_inode) = node.first_layer_inode().await;
+        layer
+            .getlk(ctx, real_inode, 0, lock_owner, start, end, r#type, pid)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn do_setlk(
+        &self,
+        ctx: Request,
+        node: &OverlayInode,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        loop {
+            let mut locks = node.posix_locks.lock().await;
+            let (layer, _, real_inode) = node.first_layer_inode().await;
+            let res: Result<()> = layer
+                .setlk(
+                    ctx, real_inode, 0, lock_owner, start, end, r#type, pid, false,
+                )
+                .await
+                .map_err(|e| e.into());
+            match res {
+                Ok(()) => {
+                    locks.apply(lock_owner, start, end, r#type);
+                    return Ok(());
+                }
+                Err(e) if block && e.raw_os_error() == Some(libc::EAGAIN) => drop(locks),
+                Err(e) => return Err(e),
+            }
+
+            // Wait without holding the lock table, the owner of the conflicting lock needs it to
+            // unlock and copy-up needs it to move the locks.
+            let wait = layer.setlk(
+                ctx, real_inode, 0, lock_owner, start, end, r#type, pid, true,
+            );
+            self.interrupts.run(ctx.unique, wait).await?;
+
+            let mut locks = node.posix_locks.lock().await;
+            let (cur_layer, _, cur_inode) = node.first_layer_inode().await;
+            if Arc::ptr_eq(&cur_layer, &layer) && cur_inode == real_inode {
+                locks.apply(lock_owner, start, end, r#type);
+                return Ok(());
+            }
+            // The node was copied up while waiting, so the lock was granted on the stale lower
+            // inode. Give it back there and try again on the new one.
+            drop(locks);
+            let _ = layer
+                .setlk(
+                    ctx,
+                    real_inode,
+                    0,
+                    lock_owner,
+                    start,
+                    end,
+                    libc::F_UNLCK as u32,
+                    pid,
+                    false,
+                )
+                .await;
+        }
+    }
+
+    // Drop the locks of `lock_owner` on the node, called when the owner closes the file.
+    async fn release_posix_locks(&self, node: &OverlayInode, lock_owner: u64) {
+        let mut locks = node.posix_locks.lock().await;
+        locks.remove_owner(lock_owner);
+        let (layer, _, real_inode) = node.first_layer_inode().await;
+        layer.release_posix_locks(real_inode, lock_owner).await;
+    }
+
+    // Take the locks recorded on the node again on its new upper inode after copy-up and
+    // release them on the lower one. The caller holds the lock table for the whole copy-up,
+    // so no lock request can slip in between.
+    async fn migrate_posix_locks(
+        &self,
+        ctx: Request,
+        locks: &PosixLocks,
+        lower: (&BoxedLayer, Inode),
+        upper: (&BoxedLayer, Inode),
+    ) {
+        for (owner, ranges) in locks.iter() {
+            for r in ranges {
+                // Owners held these locks together on the lower inode, so they can't conflict.
+                if let Err(e) = upper
+                    .0
+                    .setlk(ctx, upper.1, 0, owner, r.start, r.end, r.r#type, 0, false)
+                    .await
+                {
+                    error!(
+                        "failed to move lock [{}, {}] of owner {owner} to upper inode {}: {e:?}",
+                        r.start, r.end, upper.1
+                    );
+                }
+            }
+            lower.0.release_posix_locks(lower.1, owner).await;
+        }
+    }
+
+    async fn find_real_info_from_handle(
+        &self,
+        handle: Handle,
+    ) -> Result<(Arc<BoxedLayer>, Inode, Handle)> {
+        match self.handles.lock().await.get(&handle) {
+            Some(h) => match h.real_handle {
+                Some(ref rhd) => {
+                    trace!(
+                        "find_real_info_from_handle: layer in upper: {}",
+                        rhd.in_upper_layer
+                    );
+                    Ok((
+                        rhd.layer.clone(),
+                        rhd.inode,
+                        rhd.handle.load(Ordering::Relaxed),
+                    ))
+                }
+                None => Err(Error::from_raw_os_error(libc::ENOENT)),
+            },
+
+            None => Err(Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    async fn find_real_inode(&self, inode: Inode) -> Result<(Arc<BoxedLayer>, Inode)> {
+        if let Some(n) = self.get_active_inode(inode).await {
+            let (first_layer, _, first_inode) = n.first_layer_inode().await;
+            return Ok((first_layer, first_inode));
+        } else if let Some(n) = self.get_all_inode(inode).await {
+            trace!("find_real_inode: found inode by get_all_inode: {}", n.inode);
+            let (first_layer, _, first_inode) = n.first_layer_inode().await;
+            return Ok((first_layer, first_inode));
+        }
+
+        Err(Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    async fn get_data(
+        &self,
+        ctx: Request,
+        handle: Option<Handle>,
+        inode: Inode,
+        flags: u32,
+    ) -> Result<Arc<HandleData>> {
+        let no_open = self.no_open.load(Ordering::Relaxed);
+        if !no_open {
+            if let Some(h) = handle
+                && let Some(v) = self.handles.lock().await.get(&h)
+                && v.node.inode == inode
+            {
+                // trace!("get_data: found handle");
+                return Ok(Arc::clone(v));
+            }
+        } else {
+            let readonly: bool = flags
+                & (libc::O_APPEND | libc::O_CREAT | libc::O_TRUNC | libc::O_RDWR | libc::O_WRONLY)
+                    as u32
+                == 0;
+
+            // lookup node
+            let node = self.lookup_node(ctx, inode, OsStr::new("")).await?;
+
+            // whiteout node
+            if node.whiteout.load(Ordering::Relaxed) {
+                return Err(Error::from_raw_os_error(libc::ENOENT));
+            }
+
+            if !readonly {
+                // Check if upper layer exists, return EROFS is not exists.
+                self.upper_layer
+                    .as_ref()
+                    .cloned()
+                    .ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
+                // copy up to upper layer
+                self.copy_node_up(ctx, Arc::clone(&node)).await?;
+            }
+
+            // Without handles the real inode is opened for this request only, `put_data`
+            // releases it again.
+            let (layer, in_upper_layer, inode) = node.first_layer_inode().await;
+            let handle = match layer.open(ctx, inode, flags & libc::O_ACCMODE as u32).await {
+                Ok(reply) => reply.fh,
+                // The layer is handle-less as well.
+                Err(e) if Error::from(e).raw_os_error() == Some(libc::ENOSYS) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let handle_data = HandleData {
+                node: Arc::clone(&node),
+                real_handle: Some(RealHandle {
+                    layer,
+                    in_upper_layer,
+                    inode,
+                    handle: AtomicU64::new(handle),
+                }),
+                device: None,
+                dir_snapshot: Mutex::new(None),
+                plain_snapshot: Mutex::new(None),
+                snapshot_version: AtomicU64::new(0),
+            };
+            return Ok(Arc::new(handle_data));
+        }
+
+        Err(Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    // Release what `get_data` opened for a single request of a handle-less mount.
+    async fn put_data(&self, ctx: Request, data: &HandleData) {
+        if !self.no_open.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(rh) = &data.real_handle {
+            let handle = rh.handle.load(Ordering::Relaxed);
+            if handle != 0
+                && let Err(e) = rh.layer.release(ctx, rh.inode, handle, 0, 0, false).await
+            {
+                warn!(
+                    "failed to release handle {handle} of inode {}: {e:?}",
+                    rh.inode
+                );
+            }
+        }
+    }
+
+    // extend or init the inodes number to one overlay if the current number is done.
+    pub async fn extend_inode_alloc(&self, key: u64) {
+        let next_inode = key * INODE_ALLOC_BATCH;
+        let limit_inode = next_inode + INODE_ALLOC_BATCH - 1;
+        self.inodes.extend_inode_number(next_inode, limit_inode);
+    }
+}
+
+/// Default of [`Concurrency::max_background`], the queue depth of an rfuse3 session.
+pub const DEFAULT_MAX_BACKGROUND: usize = 12;
+
+/// How many requests the FUSE session of a mount serves at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Concurrency {
+    /// Tasks processing requests. 0 or 1 serves them from the session loop, as before, more
+    /// spread them over a pool so concurrent readers don't queue behind each other.
+    pub workers: usize,
+    /// Requests in flight before the session stops reading new ones, also the number of
+    /// background requests, like readahead, the kernel keeps pending.
+    pub max_background: usize,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self {
+            workers: 0,
+            max_background: DEFAULT_MAX_BACKGROUND,
+        }
+    }
+}
+
+/// Sizes of the requests the kernel sends, told to it at FUSE init. Each is left to the
+/// kernel or rfuse3 default when `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FuseTuning {
+    /// Largest write request, 128 KiB by default. Large sequential writes take fewer requests
+    /// with a bigger one, up to the kernel limit of 256 pages unless `max_pages_limit` of the
+    /// fuse module is raised.
+    pub max_write: Option<NonZeroU32>,
+    /// Largest readahead, the kernel default for the mount by default.
+    pub max_readahead: Option<u32>,
+    /// Background requests, like readahead, the kernel keeps pending. Defaults to
+    /// [`Concurrency::max_background`] with workers, and 12 otherwise.
+    pub max_background: Option<u16>,
+    /// Pending background requests at which the kernel throttles readahead and writeback,
+    /// 3/4 of `max_background` by default.
+    pub congestion_threshold: Option<u16>,
+}
+
+/// Wrap the parameters for mounting overlay filesystem.
+#[derive(Debug, Clone)]
+pub struct OverlayArgs<P, Q, R, M, N, I>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    M: AsRef<str>,
+    N: Into<String>,
+    I: IntoIterator<Item = R>,
+{
+    pub mountpoint: P,
+    /// Writable layer, `None` mounts a read-only merged view of the lower layers.
+    pub upperdir: Option<Q>,
+    pub lowerdir: I,
+    pub privileged: bool,
+    pub mapping: Option<M>,
+    pub name: Option<N>,
+    pub allow_other: bool,
+    /// Mount even if another overlay holds the lock of `upperdir`, to recover from a mount
+    /// that hung instead of exiting. Two live mounts sharing an upper layer corrupt it.
+    pub force: bool,
+    /// Most lower layers merged, and what happens to deeper stacks.
+    pub layer_limit: LayerLimit,
+    /// Let the kernel serve reads and writes of opened files, see `Config::passthrough`.
+    pub passthrough: bool,
+    /// Request workers and queue depth of the FUSE session.
+    pub concurrency: Concurrency,
+    /// Request sizes negotiated with the kernel.
+    pub tuning: FuseTuning,
+    /// Confine the calling thread to the layers once mounted, see [`Sandbox`]. The other
+    /// threads of the process must have confined themselves as they started, see
+    /// [`Sandbox::on_thread_start`]. Only for a process serving this mount alone, which can't
+    /// unmount it anymore.
+    pub sandbox: bool,
+}
+
+/// Mounts an overlay of `lowerdir` and `upperdir` on `mountpoint` and returns the mount handle.
+///
+/// # Parameters
+/// - `mountpoint`: Path to the mount point.
+/// - `upperdir`: Path to the upper directory.
+/// - `lowerdir`: Paths to the lower directories.
+/// - `privileged`: If true, use privileged mount; otherwise, unprivileged mount.
+/// - `mapping`: Optional user/group ID mapping for unprivileged mounts.
+/// - `name`: Optional name for the filesystem, a unique `overlay-<fsid>` name is used when unset.
+/// - `allow_other`: If true, allows other users to access the filesystem.
+/// - `force`: If true, mounts even when another overlay holds the lock of `upperdir`.
+/// - `layer_limit`: Most lower layers merged, and what happens to deeper stacks.
+/// - `passthrough`: If true, lets the kernel serve reads and writes of opened files.
+/// - `concurrency`: Request workers and queue depth of the FUSE session.
+/// - `tuning`: Request sizes negotiated with the kernel.
+/// - `sandbox`: If true, confines the calling thread to the layers once mounted.
+///
+/// # Returns
+/// The mount handle on success, or a [`MountError`] otherwise.
+///
+/// # Errors
+/// The directories are checked before anything is set up, so a bad argument fails naming
+/// the offending path:
+/// - [`MountError::NoLowerDir`] if `lowerdir` is empty.
+/// - [`MountError::Inaccessible`] or [`MountError::NotADirectory`] if the mountpoint or a
+///   layer can't be used.
+/// - [`MountError::DuplicateLayer`] or [`MountError::NestedLayers`] if the layers overlap.
+/// - [`MountError::UpperInUse`] if another overlay holds `upperdir` and `force` is unset.
+/// - [`MountError::TooManyLayers`] if `lowerdir` exceeds `layer_limit` and it has no `squash_dir`.
+///
+/// Later failures are [`MountError::Setup`] for the layers and the overlay,
+/// [`MountError::Mount`] for the FUSE mount and [`MountError::Sandbox`] for `sandbox`, after
+/// which the overlay is unmounted again.
+pub async fn mount_fs<P, Q, R, M, N, I>(
+    args: OverlayArgs<P, Q, R, M, N, I>,
+) -> std::result::Result<rfuse3::raw::MountHandle, MountError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    M: AsRef<str>,
+    N: Into<String>,
+    I: IntoIterator<Item = R>,
+{
+    mount_fs_with_shutdown(args).await.map(|(handle, _)| handle)
+}
+
+/// Like [`mount_fs`], also returning the [`ShutdownHandle`] of the overlay so that the
+/// caller can drain it before unmounting.
+pub async fn mount_fs_with_shutdown<P, Q, R, M, N, I>(
+    args: OverlayArgs<P, Q, R, M, N, I>,
+) -> std::result::Result<(rfuse3::raw::MountHandle, ShutdownHandle), MountError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    M: AsRef<str>,
+    N: Into<String>,
+    I: IntoIterator<Item = R>,
+{
+    let lowerdirs = args
+        .lowerdir
+        .into_iter()
+        .map(|l| l.as_ref().to_path_buf())
+        .collect::<Vec<_>>();
+    mount_args::validate(
+        args.mountpoint.as_ref(),
+        args.upperdir.as_ref().map(|u| u.as_ref()),
+        &lowerdirs.iter().map(|l| l.as_path()).collect::<Vec<_>>(),
+    )?;
+    let upper_lock = match &args.upperdir {
+        Some(upperdir) => match mount_args::lock_upper(upperdir.as_ref()) {
+            Ok(lock) => Some(lock),
+            Err(MountError::UpperInUse(path)) if args.force => {
+                warn!("forcing mount of {}, which is in use", path.display());
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    let lowerdirs = args
+        .layer_limit
+        .apply(lowerdirs, args.mapping.as_ref().map(|m| m.as_ref()))
+        .await?;
+
+    let mut sandbox = Sandbox::new();
+    for lower in &lowerdirs {
+        sandbox = sandbox.read_only(lower);
+    }
+    if let Some(upperdir) = &args.upperdir {
+        sandbox = sandbox.read_write(upperdir);
+    }
+
+    // Create lower layers
+    let mut lower_layers = Vec::new();
+    for lower in lowerdirs {
+        let layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: lower,
+            mapping: args.mapping.as_ref().map(|m| m.as_ref()),
+            io_engine: Default::default(),
+        })
+        .await
+        .map_err(MountError::Setup)?;
+        lower_layers.push(Arc::new(layer));
+    }
+    // Create upper layer
+    let upper_layer = match args.upperdir {
+        Some(upperdir) => Some(Arc::new(
+            new_passthroughfs_layer(PassthroughArgs {
+                root_dir: upperdir,
+                mapping: args.mapping.as_ref().map(|m| m.as_ref()),
+                io_engine: Default::default(),
+            })
+            .await
+            .map_err(MountError::Setup)?,
+        )),
+        None => None,
+    };
+    let read_only = upper_layer.is_none();
+
+    // Configure overlay filesystem
+    let config = Config {
+        mountpoint: args.mountpoint.as_ref().to_path_buf(),
+        do_import: true,
+        passthrough: args.passthrough,
+        tuning: args.tuning,
+        ..Default::default()
+    };
+    let mut overlayfs =
+        OverlayFs::new(upper_layer, lower_layers, config, 1).map_err(MountError::Setup)?;
+    overlayfs.upper_lock = upper_lock;
+    let fs_name = match args.name {
+        Some(name) => name.into(),
+        None => format!("overlay-{:016x}", overlayfs.fsid()),
+    };
+    let shutdown = overlayfs.shutdown_handle();
+    let mut mount_options = MountOptions::default();
+    overlayfs.apply_mount_options(&mut mount_options);
+
+    let mount_path: OsString = OsString::from(args.mountpoint.as_ref().as_os_str());
+
+    // Obtain the current user's uid and gid
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    #[cfg(target_os = "linux")]
+    mount_options.force_readdir_plus(true);
+
+    mount_options
+        .uid(uid)
+        .gid(gid)
+        .allow_other(args.allow_other)
+        .read_only(read_only);
+    mount_options.fs_name(fs_name);
+
+    let session = Session::new(mount_options)
+        .with_workers(args.concurrency.workers, args.concurrency.max_background);
+    overlayfs.set_backing_files(session.get_backing_files());
+    let logfs = LoggingFileSystem::new(overlayfs);
+
+    // Mount filesystem based on privilege flag and return the mount handle
+    let handle = if !args.privileged {
+        debug!("Mounting with unprivileged mode");
+        session.mount_with_unprivileged(logfs, mount_path).await
+    } else {
+        debug!("Mounting with privileged mode");
+        session.mount(logfs, mount_path).await
+    };
+    let handle = handle.map_err(MountError::Mount)?;
+
+    if args.sandbox
+        && let Err(e) = sandbox.apply()
+    {
+        if let Err(e) = handle.unmount().await {
+            warn!("failed to unmount overlay after sandboxing failed: {e}");
         }
+        return Err(MountError::Sandbox(e));
+    }
 
-        Ok(node)
+    Ok((handle, shutdown))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build an overlay on top of plain directories without mounting it, so the
+    // overlay logic can be driven directly through the `Filesystem` trait.
+    async fn new_overlay(lowers: &[&Path], upper: &Path) -> OverlayFs {
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        new_overlay_with(lowers, upper, config).await
     }
 
-    /// Copies a regular file and its contents from a lower layer to the upper layer.
-    ///
-    /// This function is a core part of the copy-up process, triggered when a regular file
-    /// that only exists in a lower layer is written to. It creates an empty file in the
-    /// upper layer with the original file's attributes (mode, UID, GID), and then copies
-    /// the entire content from the lower layer file to the new upper layer file.
-    async fn copy_regfile_up(
-        &self,
-        ctx: Request,
-        node: Arc<OverlayInode>,
-    ) -> Result<Arc<OverlayInode>> {
-        if node.in_upper_layer().await {
-            return Ok(node);
+    async fn new_overlay_with(lowers: &[&Path], upper: &Path, config: Config) -> OverlayFs {
+        let mut lower_layers = Vec::new();
+        for lower in lowers {
+            let layer = new_passthroughfs_layer(PassthroughArgs {
+                root_dir: lower,
+                mapping: None::<&str>,
+                io_engine: Default::default(),
+            })
+            .await
+            .unwrap();
+            lower_layers.push(Arc::new(layer));
         }
+        let upper_layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: upper,
+            mapping: None::<&str>,
+            io_engine: Default::default(),
+        })
+        .await
+        .unwrap();
+        let fs = OverlayFs::new(Some(Arc::new(upper_layer)), lower_layers, config, 1).unwrap();
+        fs.init(Request::default()).await.unwrap();
+        fs
+    }
 
-        let parent_node = if let Some(ref n) = node.parent.lock().await.upgrade() {
-            Arc::clone(n)
-        } else {
-            return Err(Error::other("no parent?"));
-        };
+    fn raw_os_error(e: Errno) -> Option<i32> {
+        let e: std::io::Error = e.into();
+        e.raw_os_error()
+    }
 
-        // To preserve original ownership, we must get the raw, unmapped host attributes.
-        // We achieve this by calling `do_getattr_helper`, which is specifically designed
-        // to bypass the ID mapping logic. This is safe and does not affect other
-        // functionalities because `do_getattr_helper` and the standard `stat64()` call
-        // both rely on the same underlying `stat` system call; they only differ in
-        // whether the resulting `uid` and `gid` are mapped.
-        let (lower_layer, _, lower_inode) = node.first_layer_inode().await;
-        let re = lower_layer.do_getattr_helper(lower_inode, None).await?;
-        let st = ReplyAttr {
-            ttl: re.1,
-            attr: convert_stat64_to_file_attr(re.0),
+    #[tokio::test]
+    async fn test_name_max_enforced() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let longest = "l".repeat(utils::NAME_MAX);
+        let too_long = "t".repeat(utils::NAME_MAX + 1);
+        std::fs::write(lower.path().join(&longest), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        // A 255-byte name from a lower layer is visible and can be copied up.
+        let entry = fs.lookup(req, 1, OsStr::new(&longest)).await.unwrap();
+        assert_eq!(entry.attr.size, 5);
+        let attr = SetAttr {
+            size: Some(0),
+            ..Default::default()
         };
-        trace!(
-            "copy_regfile_up: node {} in lower layer's inode {}",
-            node.inode, lower_inode
+        fs.setattr(req, entry.attr.ino, None, attr).await.unwrap();
+        assert_eq!(
+            std::fs::metadata(upper.path().join(&longest))
+                .unwrap()
+                .len(),
+            0
         );
 
-        if !parent_node.in_upper_layer().await {
-            parent_node.clone().create_upper_dir(ctx, None).await?;
+        // 256 bytes is rejected up front with the same errno by every operation.
+        let name = OsStr::new(&too_long);
+        let err = fs.lookup(req, 1, name).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+        let err = fs.mkdir(req, 1, name, 0o755, 0).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+        let err = fs
+            .create(req, 1, name, libc::S_IFREG | 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+        let err = fs
+            .symlink(req, 1, name, OsStr::new("target"))
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+        let err = fs
+            .rename(req, 1, OsStr::new(&longest), 1, name)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+        let err = fs.unlink(req, 1, name).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENAMETOOLONG));
+    }
+
+    #[tokio::test]
+    async fn test_path_beyond_path_max() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        // Nest directories until the overlay path is well past PATH_MAX.
+        let component = "d".repeat(200);
+        let mut parent = fs.root_inode();
+        for _ in 0..30 {
+            let entry = fs
+                .mkdir(req, parent, OsStr::new(&component), 0o755, 0)
+                .await
+                .unwrap();
+            parent = entry.attr.ino;
         }
+        let created = fs
+            .create(
+                req,
+                parent,
+                OsStr::new("leaf"),
+                libc::S_IFREG | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+        let written = fs
+            .write(req, created.attr.ino, created.fh, 0, b"deep", 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(written.written, 4);
+        fs.release(req, created.attr.ino, created.fh, 0, 0, true)
+            .await
+            .unwrap();
 
-        // create the file in upper layer using information from lower layer
+        let node = fs
+            .lookup_node(req, created.attr.ino, OsStr::new(""))
+            .await
+            .unwrap();
+        assert!(node.path.read().await.len() > libc::PATH_MAX as usize);
+
+        // Walk back down by name and read the file through a fresh handle.
+        let mut ino = fs.root_inode();
+        for _ in 0..30 {
+            ino = fs
+                .lookup(req, ino, OsStr::new(&component))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+        }
+        let leaf = fs.lookup(req, ino, OsStr::new("leaf")).await.unwrap();
+        assert_eq!(leaf.attr.ino, created.attr.ino);
+        let opened = fs
+            .open(req, leaf.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap();
+        let data = fs.read(req, leaf.attr.ino, opened.fh, 0, 16).await.unwrap();
+        assert_eq!(&data.data[..], b"deep");
+    }
 
-        let flags = libc::O_WRONLY;
-        let mode = mode_from_kind_and_perm(st.attr.kind, st.attr.perm);
+    #[tokio::test]
+    async fn test_non_utf8_names() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let latin1 = OsStr::from_bytes(b"caf\xe9");
+        let renamed = OsStr::from_bytes(b"\xff\xfe");
+        let dir = OsStr::from_bytes(b"d\x80");
+        let target = OsStr::from_bytes(b"t\xe9");
+        std::fs::write(lower.path().join(latin1), b"lower").unwrap();
+        std::fs::create_dir(lower.path().join(dir)).unwrap();
+        std::os::unix::fs::symlink(target, lower.path().join("link")).unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        // Entries merged from a lower layer keep their raw names.
+        let entry = fs.lookup(req, 1, latin1).await.unwrap();
+        assert_eq!(entry.attr.size, 5);
+        let d = fs.lookup(req, 1, dir).await.unwrap();
+
+        // Renaming copies the file up under another non-UTF-8 name and hides the old one.
+        fs.rename(req, 1, latin1, d.attr.ino, renamed)
+            .await
+            .unwrap();
+        assert!(upper.path().join(dir).join(renamed).exists());
+        let err = fs.lookup(req, 1, latin1).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        let moved = fs.lookup(req, d.attr.ino, renamed).await.unwrap();
+        assert_eq!(moved.attr.size, 5);
+
+        // Symlink copy-up keeps a target that isn't valid UTF-8.
+        fs.rename(req, 1, OsStr::new("link"), 1, OsStr::new("link2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_link(upper.path().join("link2")).unwrap(),
+            Path::new(target)
+        );
+    }
 
-        let upper_handle = Arc::new(Mutex::new(0));
-        let upper_real_inode = Arc::new(Mutex::new(None));
-        parent_node
-            .handle_upper_inode_locked(&mut |parent_upper_inode: Option<Arc<RealInode>>| async {
-                // We already create upper dir for parent_node.
-                let parent_real_inode = parent_upper_inode.ok_or_else(|| {
-                    error!("parent {} has no upper inode", parent_node.inode);
-                    Error::from_raw_os_error(libc::EINVAL)
-                })?;
-                // We manually unfold the `create` logic here instead of calling the `create` method directly.
-                // This is necessary to preserve the original file's UID and GID during the copy-up process.
-                if !parent_real_inode.in_upper_layer {
-                    return Err(Error::from_raw_os_error(libc::EROFS));
+    #[tokio::test]
+    async fn test_rename_across_parents() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        for dir in ["src", "dst", "lower_dir", "merged"] {
+            std::fs::create_dir(lower.path().join(dir)).unwrap();
+        }
+        std::fs::write(lower.path().join("src/f"), b"lower").unwrap();
+        std::fs::write(lower.path().join("lower_dir/f"), b"").unwrap();
+        std::fs::create_dir(upper.path().join("merged")).unwrap();
+        std::fs::create_dir(upper.path().join("upper_dir")).unwrap();
+        std::fs::write(upper.path().join("upper_dir/f"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let src = fs.lookup(req, 1, OsStr::new("src")).await.unwrap().attr.ino;
+        let dst = fs.lookup(req, 1, OsStr::new("dst")).await.unwrap().attr.ino;
+
+        // Both parents only exist below and are copied up first.
+        fs.rename(req, src, OsStr::new("f"), dst, OsStr::new("g"))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(upper.path().join("dst/g")).unwrap(), b"lower");
+        let err = fs.lookup(req, src, OsStr::new("f")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // Directories with lower entries would leave them behind.
+        for dir in ["lower_dir", "merged"] {
+            let err = fs
+                .rename(req, 1, OsStr::new(dir), dst, OsStr::new(dir))
+                .await
+                .unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::EXDEV));
+            fs.lookup(req, 1, OsStr::new(dir)).await.unwrap();
+        }
+        fs.rename(req, 1, OsStr::new("upper_dir"), dst, OsStr::new("d"))
+            .await
+            .unwrap();
+        let d = fs.lookup(req, dst, OsStr::new("d")).await.unwrap().attr.ino;
+        fs.lookup(req, d, OsStr::new("f")).await.unwrap();
+        assert!(upper.path().join("dst/d/f").exists());
+    }
+
+    #[tokio::test]
+    async fn test_merged_dir_nlink() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        for dir in ["merged/a", "merged/b", "lower_only/a"] {
+            std::fs::create_dir_all(lower.path().join(dir)).unwrap();
+        }
+        for dir in ["merged/c", "upper_only/a", "upper_only/b"] {
+            std::fs::create_dir_all(upper.path().join(dir)).unwrap();
+        }
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        let merged = fs.lookup(req, 1, OsStr::new("merged")).await.unwrap().attr;
+        assert_eq!(merged.nlink, 1);
+        let attr = fs.getattr(req, merged.ino, None, 0).await.unwrap().attr;
+        assert_eq!(attr.nlink, 1);
+
+        // A directory from a single layer keeps its real count.
+        let lower_only = fs.lookup(req, 1, OsStr::new("lower_only")).await.unwrap();
+        assert_eq!(lower_only.attr.nlink, 3);
+        let upper_only = fs.lookup(req, 1, OsStr::new("upper_only")).await.unwrap();
+        assert_eq!(upper_only.attr.nlink, 4);
+    }
+
+    #[tokio::test]
+    async fn test_stable_inode_numbers() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/lower"), b"").unwrap();
+        std::fs::write(upper.path().join("upper"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+
+        // Entries replied by the calls creating them.
+        let mut created = vec![
+            fs.mkdir(req, d, OsStr::new("dir"), 0o755, 0)
+                .await
+                .unwrap()
+                .attr,
+            fs.mknod(req, d, OsStr::new("fifo"), libc::S_IFIFO | 0o644, 0)
+                .await
+                .unwrap()
+                .attr,
+            fs.symlink(req, d, OsStr::new("sym"), OsStr::new("lower"))
+                .await
+                .unwrap()
+                .attr,
+            fs.create(req, d, OsStr::new("file"), 0o644, libc::O_RDWR as u32)
+                .await
+                .unwrap()
+                .attr,
+        ];
+        let lower_ino = fs
+            .lookup(req, d, OsStr::new("lower"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let link = fs
+            .link(req, lower_ino, 1, OsStr::new("link"))
+            .await
+            .unwrap();
+        assert_eq!(link.attr.ino, lower_ino);
+        created.push(fs.lookup(req, 1, OsStr::new("upper")).await.unwrap().attr);
+
+        // Every other reply agrees with readdir on the inode numbers.
+        let fs = &fs;
+        let check = |parent, attrs: Vec<FileAttr>| async move {
+            let fh = fs.opendir(req, parent, 0).await.unwrap().fh;
+            let plain: HashMap<OsString, u64> = fs
+                .readdir(req, parent, fh, 0)
+                .await
+                .unwrap()
+                .entries
+                .map(|e| e.map(|e| (e.name, e.inode)).unwrap())
+                .collect()
+                .await;
+            let fh = fs.opendir(req, parent, 0).await.unwrap().fh;
+            let plus: Vec<_> = fs
+                .readdirplus(req, parent, fh, 0, 0)
+                .await
+                .unwrap()
+                .entries
+                .collect()
+                .await;
+            assert_eq!(plain[OsStr::new(".")], parent);
+            for e in plus {
+                let e = e.unwrap();
+                assert_eq!(e.inode, e.attr.ino);
+                assert_eq!(plain[&e.name], e.inode);
+                let ino = fs.getattr(req, e.inode, None, 0).await.unwrap().attr.ino;
+                assert_eq!(ino, e.inode);
+                let mask = libc::STATX_BASIC_STATS;
+                let statx = fs.statx(req, e.inode, None, 0, mask).await.unwrap();
+                assert_eq!(statx.attr.ino, e.inode);
+                if e.name != "." && e.name != ".." {
+                    let entry = fs.lookup(req, parent, &e.name).await.unwrap();
+                    assert_eq!(entry.attr.ino, e.inode);
                 }
-                let name = node.name.read().await;
-                let name = OsStr::new(name.as_str());
-                let create_rep = parent_real_inode
-                    .layer
-                    .do_create_helper(
-                        ctx,
-                        parent_real_inode.inode,
-                        name,
-                        mode,
-                        flags.try_into().unwrap(),
-                        st.attr.uid,
-                        st.attr.gid,
-                    )
-                    .await?;
+            }
+            for attr in attrs {
+                assert!(plain.values().any(|&ino| ino == attr.ino));
+            }
+        };
+        let upper_attr = created.pop().unwrap();
+        check(d, created).await;
+        check(1, vec![upper_attr, link.attr]).await;
+    }
 
-                let (inode, h) = (
-                    RealInode {
-                        layer: parent_real_inode.layer.clone(),
-                        in_upper_layer: true,
-                        inode: create_rep.attr.ino,
-                        whiteout: false,
-                        opaque: false,
-                        stat: Some(ReplyAttr {
-                            ttl: create_rep.ttl,
-                            attr: create_rep.attr,
-                        }),
-                    },
-                    Some(create_rep.fh),
-                );
-                trace!(
-                    "copy_regfile_up: created upper file {name:?} with inode {}",
-                    inode.inode
-                );
-                *upper_handle.lock().await = h.unwrap_or(0);
-                upper_real_inode.lock().await.replace(inode);
-                Ok(false)
-            })
-            .await?;
+    #[tokio::test]
+    async fn test_dir_snapshot_invalidation() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/a"), b"").unwrap();
+        std::fs::write(lower.path().join("d/b"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let fs = &fs;
+        let list = |fh, offset| async move {
+            let entries: Vec<_> = fs
+                .readdir(req, d, fh, offset)
+                .await
+                .unwrap()
+                .entries
+                .map(|e| e.map(|e| (e.name, e.offset)).unwrap())
+                .collect()
+                .await;
+            entries
+        };
+        let list_plus = |fh| async move {
+            let entries: Vec<_> = fs
+                .readdirplus(req, d, fh, 0, 0)
+                .await
+                .unwrap()
+                .entries
+                .map(|e| e.unwrap().name)
+                .collect()
+                .await;
+            entries
+        };
+        let fh = fs.opendir(req, d, 0).await.unwrap().fh;
+        let plus_fh = fs.opendir(req, d, 0).await.unwrap().fh;
+        let before = list(fh, 0).await;
+        assert_eq!(before.len(), 4);
+        assert_eq!(list_plus(plus_fh).await.len(), 4);
 
-        let rep = lower_layer
-            .open(ctx, lower_inode, libc::O_RDONLY as u32)
-            .await?;
+        fs.create(req, d, OsStr::new("c"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        fs.unlink(req, d, OsStr::new("a")).await.unwrap();
+        // Reading the handle again sees the changes.
+        let after = list(fh, 0).await;
+        let names: Vec<_> = after.iter().map(|(name, _)| name.clone()).collect();
+        for names in [names, list_plus(plus_fh).await] {
+            assert!(names.contains(&OsString::from("c")));
+            assert!(!names.contains(&OsString::from("a")));
+        }
+        // Entries keep their offsets, a listing resumed after any of them, even a removed
+        // one, goes on with the entries ordered after it.
+        let b = before.iter().find(|(name, _)| name == "b").unwrap();
+        assert!(after.contains(b));
+        for &(_, offset) in &before {
+            let rest: Vec<_> = after.iter().filter(|e| e.1 > offset).cloned().collect();
+            assert_eq!(list(fh, offset).await, rest);
+        }
+    }
 
-        let lower_handle = rep.fh;
+    #[tokio::test]
+    async fn test_rename_flags() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"lower-a").unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/inner"), b"").unwrap();
+        std::fs::write(upper.path().join("b"), b"b").unwrap();
+        std::fs::create_dir(upper.path().join("e")).unwrap();
+        std::fs::write(upper.path().join("e/x"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let (a, b, c) = (OsStr::new("a"), OsStr::new("b"), OsStr::new("c"));
+        let (noreplace, exchange) = (RENAME_NOREPLACE, RENAME_EXCHANGE);
+
+        let err = fs.rename2(req, 1, a, 1, b, noreplace).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EEXIST));
+        let err = fs.rename2(req, 1, a, 1, c, exchange).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        let err = fs
+            .rename2(req, 1, a, 1, b, noreplace | exchange)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EINVAL));
+        fs.rename2(req, 1, b, 1, c, noreplace).await.unwrap();
+
+        // Swap a lower file with an upper-only one.
+        fs.rename2(req, 1, a, 1, c, exchange).await.unwrap();
+        assert_eq!(fs.lookup(req, 1, a).await.unwrap().attr.size, 1);
+        assert_eq!(fs.lookup(req, 1, c).await.unwrap().attr.size, 7);
+        // "a" still exists below, so removing it must leave a whiteout, "c" does not.
+        fs.unlink(req, 1, a).await.unwrap();
+        fs.unlink(req, 1, c).await.unwrap();
+        for name in [a, c] {
+            let err = fs.lookup(req, 1, name).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        }
 
-        // need to use work directory and then rename file to
-        // final destination for atomic reasons.. not deal with it for now,
-        // use stupid copy at present.
-        // FIXME: this need a lot of work here, ntimes, xattr, etc.
+        // Swapped directories take their contents along and hide the lower entries of their new place.
+        let (d, e) = (OsStr::new("d"), OsStr::new("e"));
+        fs.rename2(req, 1, d, 1, e, exchange).await.unwrap();
+        let check = async |fs: &OverlayFs| {
+            let d_ino = fs.lookup(req, 1, d).await.unwrap().attr.ino;
+            let e_ino = fs.lookup(req, 1, e).await.unwrap().attr.ino;
+            fs.lookup(req, d_ino, OsStr::new("x")).await.unwrap();
+            let err = fs
+                .lookup(req, d_ino, OsStr::new("inner"))
+                .await
+                .unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+            fs.lookup(req, e_ino, OsStr::new("inner")).await.unwrap();
+        };
+        check(&fs).await;
+        check(&new_overlay(&[lower.path()], upper.path()).await).await;
+    }
 
-        // Copy from lower real inode to upper real inode.
-        // TODO: use sendfile here.
+    #[tokio::test]
+    async fn test_posix_locks_survive_copy_up() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("db"), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let (wr, un) = (libc::F_WRLCK as u32, libc::F_UNLCK as u32);
+        let eof = i64::MAX as u64;
+
+        let ino = fs.lookup(req, 1, OsStr::new("db")).await.unwrap().attr.ino;
+        let ro = fs.open(req, ino, libc::O_RDONLY as u32).await.unwrap().fh;
+
+        // Owner 1 locks the file while it only exists in the lower layer.
+        fs.setlk(req, ino, ro, 1, 0, eof, wr, 0, false)
+            .await
+            .unwrap();
+        let err = fs
+            .setlk(req, ino, ro, 2, 0, 9, wr, 0, false)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EAGAIN));
+
+        // Opening for write copies the file up, the lock must move along with it.
+        let rw = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        assert!(upper.path().join("db").exists());
+        let err = fs
+            .setlk(req, ino, rw, 2, 0, 9, wr, 0, false)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EAGAIN));
+        let lk = fs.getlk(req, ino, rw, 2, 0, 9, wr, 0).await.unwrap();
+        assert_eq!(lk.r#type, wr);
+
+        // Closing any descriptor of owner 1 drops its locks.
+        fs.flush(req, ino, ro, 1).await.unwrap();
+        let lk = fs.getlk(req, ino, rw, 2, 0, 9, wr, 0).await.unwrap();
+        assert_eq!(lk.r#type, un);
+        fs.setlk(req, ino, rw, 2, 0, 9, wr, 0, false).await.unwrap();
+
+        // A blocked waiter is woken up once the holder unlocks.
+        let fs = Arc::new(fs);
+        let waiter = {
+            let fs = fs.clone();
+            tokio::spawn(async move { fs.setlk(req, ino, rw, 3, 0, eof, wr, 0, true).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        fs.setlk(req, ino, rw, 2, 0, 9, un, 0, false).await.unwrap();
+        waiter.await.unwrap().unwrap();
+    }
 
-        let u_handle = *upper_handle.lock().await;
-        let ri = upper_real_inode.lock().await.take();
-        if let Some(ri) = ri {
-            let mut offset: usize = 0;
-            let size = 4 * 1024 * 1024;
+    #[tokio::test]
+    async fn test_flock_and_interrupted_lock_waits() {
+        use std::os::fd::AsRawFd;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(upper.path().join("f"), b"").unwrap();
+        let fs = Arc::new(new_overlay(&[lower.path()], upper.path()).await);
+        let req = Request::default();
+        let (rd, wr, un) = (
+            libc::F_RDLCK as u32,
+            libc::F_WRLCK as u32,
+            libc::F_UNLCK as u32,
+        );
 
-            loop {
-                let ret = lower_layer
-                    .read(ctx, lower_inode, lower_handle, offset as u64, size)
-                    .await?;
+        let ino = fs.lookup(req, 1, OsStr::new("f")).await.unwrap().attr.ino;
+        let a = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        let b = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+
+        // The lock is taken on the real file, the host and other handles see it.
+        fs.flock(req, ino, a, 1, wr, false).await.unwrap();
+        let host = std::fs::File::open(upper.path().join("f")).unwrap();
+        let res = unsafe { libc::flock(host.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+        assert_eq!(res, -1);
+        let err = fs.flock(req, ino, b, 2, rd, false).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EAGAIN));
+
+        // Blocked requests give up once interrupted, flock and POSIX locks alike.
+        fs.setlk(req, ino, a, 1, 0, 9, wr, 0, false).await.unwrap();
+        let flock_waiter = {
+            let (fs, req) = (fs.clone(), Request { unique: 7, ..req });
+            tokio::spawn(async move { fs.flock(req, ino, b, 2, wr, true).await })
+        };
+        let setlk_waiter = {
+            let (fs, req) = (fs.clone(), Request { unique: 8, ..req });
+            tokio::spawn(async move { fs.setlk(req, ino, b, 2, 0, 9, wr, 0, true).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!flock_waiter.is_finished() && !setlk_waiter.is_finished());
+        fs.interrupt(req, 7).await.unwrap();
+        fs.interrupt(req, 8).await.unwrap();
+        let err = flock_waiter.await.unwrap().unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EINTR));
+        let err = setlk_waiter.await.unwrap().unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EINTR));
+        // Their blocked calls are woken up too, and don't take the lock once it is released.
+        fs.flock(req, ino, a, 1, un, false).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let res = unsafe { libc::flock(host.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(res, 0);
+        unsafe { libc::flock(host.as_raw_fd(), libc::LOCK_UN) };
+        fs.flock(req, ino, a, 1, wr, false).await.unwrap();
+
+        // A waiter left alone gets the lock once it is released.
+        let waiter = {
+            let fs = fs.clone();
+            tokio::spawn(async move { fs.flock(req, ino, b, 2, wr, true).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        fs.flock(req, ino, a, 1, un, false).await.unwrap();
+        waiter.await.unwrap().unwrap();
+    }
 
-                let len = ret.data.len();
-                if len == 0 {
-                    break;
+    #[tokio::test]
+    async fn test_apply_oci_layer() {
+        fn add(tar: &mut tar::Builder<Vec<u8>>, path: &str, data: Option<&[u8]>) {
+            let mut header = tar::Header::new_gnu();
+            match data {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(data.len() as u64);
                 }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                }
+            }
+            header.set_mtime(1_000_000);
+            header.set_uid(unsafe { libc::geteuid() } as u64);
+            header.set_gid(unsafe { libc::getegid() } as u64);
+            tar.append_data(&mut header, path, data.unwrap_or_default())
+                .unwrap();
+        }
 
-                let ret = ri
-                    .layer
-                    .write(ctx, ri.inode, u_handle, offset as u64, &ret.data, 0, 0)
-                    .await?;
+        let bottom = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        add(&mut tar, "d/", None);
+        add(&mut tar, "d/a", Some(b"a"));
+        add(&mut tar, "d/b", Some(b"b"));
+        add(&mut tar, "e/", None);
+        add(&mut tar, "e/x", Some(b"x"));
+        let layer = tar.into_inner().unwrap();
+        oci_layer::apply_layer(layer.as_slice(), bottom.path()).unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        add(&mut tar, "d/", None);
+        add(&mut tar, "d/.wh.a", Some(b""));
+        add(&mut tar, "e/", None);
+        add(&mut tar, "e/.wh..wh..opq", Some(b""));
+        add(&mut tar, "e/y", Some(b"y"));
+        // An entry escaping the layer directory is skipped, tar::Builder refuses to write one.
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..9].copy_from_slice(b"../escape");
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(0);
+        header.set_cksum();
+        tar.append(&header, &[][..]).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gz, &tar.into_inner().unwrap()).unwrap();
+        let format = oci_layer::LayerFormat {
+            xattr_whiteout: true,
+            ..Default::default()
+        };
+        let target = top.path().join("layer");
+        oci_layer::apply_layer_with(gz.finish().unwrap().as_slice(), &target, format).unwrap();
+        assert!(!top.path().join("escape").exists());
+        let whiteout = target.join("d/a");
+        assert_eq!(std::fs::metadata(&whiteout).unwrap().len(), 0);
+        assert!(get_xattr(&whiteout, layer::WHITEOUT_XATTR).is_some());
+        let mtime = std::fs::metadata(target.join("d"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            mtime,
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000)
+        );
 
-                assert_eq!(ret.written as usize, len);
-                offset += ret.written as usize;
-            }
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[&target, bottom.path()], upper.path(), config).await;
+        let req = Request::default();
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, d, OsStr::new("a")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        fs.lookup(req, d, OsStr::new("b")).await.unwrap();
+        let e = fs.lookup(req, 1, OsStr::new("e")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, e, OsStr::new("x")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        fs.lookup(req, e, OsStr::new("y")).await.unwrap();
+    }
 
-            if let Err(e) = ri.layer.release(ctx, ri.inode, u_handle, 0, 0, true).await {
-                let e: std::io::Error = e.into();
-                // Ignore ENOSYS.
-                if e.raw_os_error() != Some(libc::ENOSYS) {
-                    return Err(e);
-                }
+    #[test]
+    fn test_apply_oci_layer_through_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        fn add(tar: &mut tar::Builder<Vec<u8>>, path: &str, kind: tar::EntryType, link: &str) {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(0o700);
+            header.set_size(0);
+            header.set_uid(unsafe { libc::geteuid() } as u64);
+            header.set_gid(unsafe { libc::getegid() } as u64);
+            if !link.is_empty() {
+                header.set_link_name(link).unwrap();
             }
-            node.add_upper_inode(ri, true).await;
-        } else {
-            error!("BUG: upper real inode is None after copy up");
+            tar.append_data(&mut header, path, &[][..]).unwrap();
         }
 
-        lower_layer
-            .release(ctx, lower_inode, lower_handle, 0, 0, true)
-            .await?;
+        let host = tempfile::tempdir().unwrap();
+        std::fs::write(host.path().join("passwd"), b"root").unwrap();
+        std::fs::set_permissions(host.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let host_dir = host.path().to_str().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        add(&mut tar, "a", tar::EntryType::Symlink, host_dir);
+        add(&mut tar, "a/.wh.passwd", tar::EntryType::Regular, "");
+        add(&mut tar, "a/.wh..wh..opq", tar::EntryType::Regular, "");
+        add(&mut tar, "a/planted", tar::EntryType::Regular, "");
+        add(&mut tar, "h", tar::EntryType::Link, "a/passwd");
+        add(&mut tar, "b", tar::EntryType::Symlink, host_dir);
+        add(&mut tar, "b/", tar::EntryType::Directory, "");
+        let format = oci_layer::LayerFormat {
+            xattr_whiteout: true,
+            ..Default::default()
+        };
+        oci_layer::apply_layer_with(tar.into_inner().unwrap().as_slice(), target.path(), format)
+            .unwrap();
+
+        // Nothing went through the symlinks to the host directory.
+        assert_eq!(std::fs::read(host.path().join("passwd")).unwrap(), b"root");
+        assert!(!host.path().join("planted").exists());
+        assert!(get_xattr(host.path(), layer::OPAQUE_XATTR).is_none());
+        let mode = std::fs::metadata(host.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(std::fs::symlink_metadata(target.path().join("h")).is_err());
+        let a = std::fs::symlink_metadata(target.path().join("a")).unwrap();
+        assert!(a.file_type().is_symlink());
+        // A directory entry replaces the symlink instead.
+        let b = std::fs::symlink_metadata(target.path().join("b")).unwrap();
+        assert!(b.is_dir());
+    }
 
-        Ok(Arc::clone(&node))
+    #[test]
+    fn test_export_upper_diff() {
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(upper.path().join("f"), b"data").unwrap();
+        std::fs::create_dir(upper.path().join("d")).unwrap();
+        set_xattr(&upper.path().join("d"), layer::OPAQUE_XATTR, b"y");
+        std::fs::write(upper.path().join("d/g"), b"g").unwrap();
+        xattr_whiteout(&upper.path().join("gone"));
+        std::os::unix::fs::symlink("f", upper.path().join("l")).unwrap();
+
+        let mut layer = Vec::new();
+        let digest =
+            oci_layer::export_upper_diff(upper.path(), &mut layer, Default::default()).unwrap();
+        assert_eq!(digest.algorithm, oci_layer::DigestAlgorithm::Sha256);
+        digest.verify(layer.as_slice()).unwrap();
+        assert_eq!(
+            digest
+                .to_string()
+                .parse::<oci_layer::LayerDigest>()
+                .unwrap(),
+            digest
+        );
+        let mut tampered = layer.clone();
+        tampered[0] ^= 1;
+        let err = digest.verify(tampered.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!("md5:00".parse::<oci_layer::LayerDigest>().is_err());
+
+        let mut archive = tar::Archive::new(layer.as_slice());
+        let names = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["d", "d/.wh..wh..opq", "d/g", "f", ".wh.gone", "l"]);
+
+        let mut again = Vec::new();
+        let blake3 = oci_layer::export_upper_diff(
+            upper.path(),
+            &mut again,
+            oci_layer::DigestAlgorithm::Blake3,
+        )
+        .unwrap();
+        assert_eq!(again, layer);
+        assert!(blake3.to_string().starts_with("blake3:"));
+        assert_eq!(
+            oci_layer::LayerDigest::compute(layer.as_slice(), oci_layer::DigestAlgorithm::Blake3)
+                .unwrap(),
+            blake3
+        );
+
+        // Unpacking the export gives back the same layer.
+        let restored = tempfile::tempdir().unwrap();
+        let format = oci_layer::LayerFormat {
+            xattr_whiteout: true,
+            ..Default::default()
+        };
+        oci_layer::apply_layer_with(layer.as_slice(), restored.path(), format).unwrap();
+        assert_eq!(std::fs::read(restored.path().join("f")).unwrap(), b"data");
+        assert!(get_xattr(&restored.path().join("d"), layer::OPAQUE_XATTR).is_some());
+        assert!(get_xattr(&restored.path().join("gone"), layer::WHITEOUT_XATTR).is_some());
+        assert_eq!(
+            std::fs::read_link(restored.path().join("l")).unwrap(),
+            Path::new("f")
+        );
     }
 
-    /// Copies the specified node to the upper layer of the filesystem
-    ///
-    /// Performs different operations based on the node type:
-    /// - **Directory**: Creates a corresponding directory in the upper layer
-    /// - **Symbolic link**: Recursively copies to the upper layer
-    /// - **Regular file**: Copies file content to the upper layer
-    ///
-    /// # Parameters
-    /// * `ctx`: FUSE request context
-    /// * `node`: Reference to the node to be copied
-    ///
-    /// # Returns
-    /// Returns a reference to the upper-layer node on success, or an error on failure
-    async fn copy_node_up(
-        &self,
-        ctx: Request,
-        node: Arc<OverlayInode>,
-    ) -> Result<Arc<OverlayInode>> {
-        if node.in_upper_layer().await {
-            return Ok(node);
+    #[test]
+    fn test_check_layers() {
+        let upper = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        std::fs::write(bottom.path().join("a"), b"a").unwrap();
+        std::fs::create_dir(bottom.path().join("d")).unwrap();
+        std::fs::write(bottom.path().join("d/x"), b"x").unwrap();
+        // Hides `a` of the bottom layer.
+        xattr_whiteout(&top.path().join("a"));
+        xattr_whiteout(&top.path().join("b"));
+        std::fs::create_dir(top.path().join("e")).unwrap();
+        set_xattr(&top.path().join("e"), layer::OPAQUE_XATTR, b"y");
+        std::fs::write(top.path().join("f"), b"f").unwrap();
+        std::fs::write(top.path().join(".wh.f"), b"").unwrap();
+        std::fs::create_dir(top.path().join("r1")).unwrap();
+        std::fs::create_dir(top.path().join("r2")).unwrap();
+        set_xattr(&top.path().join("r1"), "user.overlay.redirect", b"r2");
+        set_xattr(&top.path().join("r2"), "user.overlay.redirect", b"/r1");
+        // Hides `d/x` of the bottom layer.
+        std::fs::create_dir(upper.path().join("d")).unwrap();
+        set_xattr(&upper.path().join("d"), layer::OPAQUE_XATTR, b"y");
+
+        let layers = [upper.path(), top.path(), bottom.path()];
+        let mut problems = check::scan(&layers, false)
+            .unwrap()
+            .into_iter()
+            .map(|p| {
+                (
+                    p.layer,
+                    p.path.to_str().unwrap().to_string(),
+                    p.kind,
+                    p.repaired,
+                )
+            })
+            .collect::<Vec<_>>();
+        problems.sort_by(|a, b| a.1.cmp(&b.1));
+        let expected = [
+            (1, ".wh.f", check::ProblemKind::DuplicateWhiteout),
+            (1, "b", check::ProblemKind::DanglingWhiteout),
+            (1, "e", check::ProblemKind::OrphanedOpaque),
+            (1, "r1", check::ProblemKind::RedirectLoop),
+            (1, "r2", check::ProblemKind::RedirectLoop),
+        ]
+        .map(|(layer, path, kind)| (layer, path.to_string(), kind, false));
+        assert_eq!(problems, expected);
+
+        let repaired = check::scan(&layers, true).unwrap();
+        assert_eq!(repaired.len(), expected.len());
+        assert!(repaired.iter().all(|p| p.repaired));
+        assert!(check::scan(&layers, false).unwrap().is_empty());
+        assert!(top.path().join("a").exists());
+        assert!(!top.path().join("b").exists());
+        assert!(!top.path().join(".wh.f").exists());
+        assert!(top.path().join("f").exists());
+        assert!(get_xattr(&upper.path().join("d"), layer::OPAQUE_XATTR).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_gc_whiteouts() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"a").unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/x"), b"x").unwrap();
+        xattr_whiteout(&upper.path().join("a"));
+        xattr_whiteout(&upper.path().join("gone"));
+        // Nothing below an opaque directory shows, so its whiteouts hide nothing either.
+        std::fs::create_dir(upper.path().join("d")).unwrap();
+        set_xattr(&upper.path().join("d"), layer::OPAQUE_XATTR, b"y");
+        xattr_whiteout(&upper.path().join("d/x"));
+
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let err = fs.lookup(req, 1, OsStr::new("gone")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        assert_eq!(fs.gc_whiteouts().await.unwrap(), 2);
+        assert!(upper.path().join("a").exists());
+        assert!(!upper.path().join("gone").exists());
+        assert!(!upper.path().join("d/x").exists());
+        assert_eq!(fs.gc_whiteouts().await.unwrap(), 0);
+
+        // The merged view is unchanged, and the names can be reused.
+        for name in ["a", "gone"] {
+            let err = fs.lookup(req, 1, OsStr::new(name)).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        }
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, d, OsStr::new("x")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        fs.create(req, 1, OsStr::new("gone"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        fs.lookup(req, 1, OsStr::new("gone")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_unavailable() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"lower").unwrap();
+        let config = Config {
+            do_import: true,
+            passthrough: true,
+            ..Default::default()
+        };
+        let mut fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        // Never negotiated outside a session, opens are served by the overlay.
+        fs.set_backing_files(BackingFiles::default());
+        let req = Request::default();
+
+        let ino = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino;
+        let opened = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap();
+        assert_eq!(opened.backing_id, None);
+        assert_eq!(opened.flags, 0);
+        fs.write(req, ino, opened.fh, 0, b"upper", 0, 0)
+            .await
+            .unwrap();
+        let data = fs.read(req, ino, opened.fh, 0, 16).await.unwrap().data;
+        assert_eq!(&data[..], b"upper");
+        fs.release(req, ino, opened.fh, 0, 0, true).await.unwrap();
+
+        let created = fs
+            .create(req, 1, OsStr::new("b"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        assert_eq!(created.backing_id, None);
+        fs.release(req, created.attr.ino, created.fh, 0, 0, true)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_fd() {
+        use std::os::unix::fs::FileExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        let ino = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino;
+        let read = async |fh| {
+            let reply = fs.read_fd(req, ino, fh, 1, 16).await.unwrap().unwrap();
+            let mut buf = [0; 16];
+            let n = std::fs::File::from(reply.fd)
+                .read_at(&mut buf, reply.offset)
+                .unwrap();
+            buf[..n].to_vec()
+        };
+        let lower_fh = fs.open(req, ino, libc::O_RDONLY as u32).await.unwrap().fh;
+        assert_eq!(read(lower_fh).await, b"ower");
+
+        // Handles opened after the copy-up read the upper file.
+        let upper_fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, ino, upper_fh, 0, b"upper", 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(read(upper_fh).await, b"pper");
+        assert_eq!(read(lower_fh).await, b"ower");
+
+        for fh in [lower_fh, upper_fh] {
+            fs.release(req, ino, fh, 0, 0, true).await.unwrap();
         }
+        let err = fs.read_fd(req, ino, upper_fh, 0, 16).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+    }
 
-        let st = node.stat64(ctx).await?;
-        match st.attr.kind {
-            FileType::Directory => {
-                node.clone().create_upper_dir(ctx, None).await?;
-                Ok(node)
-            }
-            FileType::Symlink => {
-                // For symlink.
-                self.copy_symlink_up(ctx, node).await
-            }
-            FileType::RegularFile => {
-                // For regular file.
-                self.copy_regfile_up(ctx, node).await
+    #[tokio::test]
+    async fn test_flush() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let ino = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino;
+        let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, ino, fh, 0, b"upper", 0, 0).await.unwrap();
+
+        // A file unlinked while open is still flushed through its handle.
+        fs.unlink(req, 1, OsStr::new("a")).await.unwrap();
+        fs.flush(req, ino, fh, 0).await.unwrap();
+        fs.flush(req, ino, fh, 0).await.unwrap();
+        let err = fs.flush(req, ino + 1, fh, 0).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EBADF));
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+        let err = fs.flush(req, ino, fh, 0).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::EBADF));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cancel_copy_up() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let size = 64 << 20;
+        // Written out, the holes of a sparse file would be skipped by the copy.
+        std::fs::write(lower.path().join("big"), vec![1u8; size as usize]).unwrap();
+        let fs = Arc::new(new_overlay(&[lower.path()], upper.path()).await);
+        let req = Request::default();
+        let ino = fs.lookup(req, 1, OsStr::new("big")).await.unwrap().attr.ino;
+        assert!(fs.copy_up_progress(ino).is_none());
+        assert!(!fs.cancel_copy_up(ino));
+
+        let open = {
+            let fs = fs.clone();
+            tokio::spawn(async move { fs.open(req, ino, libc::O_RDWR as u32).await })
+        };
+        let progress = loop {
+            if let Some(progress) = fs.copy_up_progress(ino) {
+                break progress;
             }
-            _ => {
-                // For other file types. return error.
-                Err(Error::from_raw_os_error(libc::EINVAL))
+            assert!(!open.is_finished(), "copy-up finished before it was seen");
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(progress.total, size);
+        assert_eq!(fs.copy_ups_in_progress(), [progress]);
+        assert!(fs.cancel_copy_up(ino));
+
+        let err = open.await.unwrap().unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ECANCELED));
+        assert!(fs.copy_up_progress(ino).is_none());
+        assert!(!upper.path().join("big").exists());
+
+        // The file is still served from the lower layer and can be copied up again.
+        let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+        assert_eq!(
+            std::fs::metadata(upper.path().join("big")).unwrap().len(),
+            size
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_amplification() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("big"), vec![7u8; 1 << 20]).unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        assert_eq!(fs.write_amplification(), WriteAmplification::default());
+        assert_eq!(fs.write_amplification().ratio(), None);
+
+        // Changing a few bytes copies the whole file up first.
+        let ino = fs.lookup(req, 1, OsStr::new("big")).await.unwrap().attr.ino;
+        let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, ino, fh, 0, b"patched", 0, 0).await.unwrap();
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+        let stats = fs.shutdown_handle().write_amplification();
+        assert_eq!(
+            stats,
+            WriteAmplification {
+                written: 7,
+                copied_up: 1 << 20,
+                copy_ups: 1,
+                cloned_up: 0,
             }
-        }
+        );
+        assert_eq!(stats.ratio(), Some(((1 << 20) + 7) as f64 / 7.0));
     }
 
-    /// recursively copy directory and all its contents to upper layer
-    async fn copy_directory_up(
-        &self,
-        ctx: Request,
-        node: Arc<OverlayInode>,
-    ) -> Result<Arc<OverlayInode>> {
-        // Ensure the directory itself is copied up first
-        self.copy_node_up(ctx, node.clone()).await?;
+    #[tokio::test]
+    async fn test_copy_up_truncated() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("log"), vec![7u8; 1 << 20]).unwrap();
+        std::fs::write(lower.path().join("data"), vec![7u8; 1 << 20]).unwrap();
+        let config = Config {
+            do_import: true,
+            no_reflink: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        // Opening with O_TRUNC copies no data up.
+        let ino = fs.lookup(req, 1, OsStr::new("log")).await.unwrap().attr.ino;
+        let flags = (libc::O_WRONLY | libc::O_TRUNC) as u32;
+        let fh = fs.open(req, ino, flags).await.unwrap().fh;
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+        assert_eq!(
+            std::fs::metadata(upper.path().join("log")).unwrap().len(),
+            0
+        );
+        assert_eq!(fs.write_amplification().copied_up, 0);
 
-        // load directory to cache
-        self.load_directory(ctx, &node).await?;
+        // Truncating copies only the data kept.
+        let ino = fs
+            .lookup(req, 1, OsStr::new("data"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let attr = SetAttr {
+            size: Some(10),
+            ..Default::default()
+        };
+        let reply = fs.setattr(req, ino, None, attr).await.unwrap();
+        assert_eq!(reply.attr.size, 10);
+        assert_eq!(std::fs::read(upper.path().join("data")).unwrap(), [7u8; 10]);
+        assert_eq!(fs.write_amplification().copied_up, 10);
+    }
 
-        // go through all children
-        let children = node.childrens.lock().await.clone();
-        for (_name, child) in children.iter() {
-            if _name == "." || _name == ".." {
-                continue;
-            }
-            // jump over whiteout
-            if child.whiteout.load(Ordering::Relaxed) {
-                continue;
-            }
-            let st = child.stat64(ctx).await?;
-            if !child.in_upper_layer().await {
-                match st.attr.kind {
-                    FileType::Directory => {
-                        // recursively copy subdirectory
-                        Box::pin(self.copy_directory_up(ctx, child.clone())).await?;
-                    }
-                    FileType::Symlink | FileType::RegularFile => {
-                        // copy node up symlink or regular file
-                        Box::pin(self.copy_node_up(ctx, child.clone())).await?;
-                    }
-                    _ => {
-                        // other file types are ignored
-                    }
+    #[tokio::test]
+    async fn test_copy_up_path() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(lower.path().join("app/node_modules/dep")).unwrap();
+        std::fs::write(lower.path().join("app/package.json"), b"{}").unwrap();
+        std::fs::write(lower.path().join("app/node_modules/dep/index.js"), b"1;").unwrap();
+        std::os::unix::fs::symlink("index.js", lower.path().join("app/node_modules/dep/main"))
+            .unwrap();
+        std::fs::write(lower.path().join("other"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = CopyUpPathOptions {
+            parallelism: 2,
+            progress: Some(Box::new({
+                let seen = Arc::clone(&seen);
+                move |path: &Path, _: &CopyUpPathReport| {
+                    seen.lock().unwrap().push(path.to_path_buf())
                 }
-            } else if utils::is_dir(&st.attr.kind) {
-                // If it is already in the upper layer, but the directory is not loaded,
-                // ensure that its contents are also copied up recursively.
-                Box::pin(self.copy_directory_up(ctx, child.clone())).await?;
+            })),
+        };
+        let report = fs.copy_up_path("app", options).await.unwrap();
+        // app, node_modules, dep, package.json, index.js and main.
+        assert_eq!(
+            report,
+            CopyUpPathReport {
+                entries: 6,
+                bytes: 4
             }
+        );
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen[0], Path::new("app"));
+        assert_eq!(seen.len(), 6);
+        let dep = upper.path().join("app/node_modules/dep");
+        assert_eq!(std::fs::read(dep.join("index.js")).unwrap(), b"1;");
+        assert_eq!(
+            std::fs::read_link(dep.join("main")).unwrap(),
+            Path::new("index.js")
+        );
+        assert!(!upper.path().join("other").exists());
+
+        // Nothing is left to copy the second time.
+        let report = fs.copy_up_path("app", Default::default()).await.unwrap();
+        assert_eq!(report, CopyUpPathReport::default());
+    }
+
+    #[tokio::test]
+    async fn test_copy_up_sparse() {
+        use std::os::unix::fs::{FileExt, MetadataExt};
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let image = std::fs::File::create(lower.path().join("image")).unwrap();
+        image.set_len(64 << 20).unwrap();
+        image.write_all_at(b"head", 0).unwrap();
+        image.write_all_at(b"middle", 32 << 20).unwrap();
+        drop(image);
+        let config = Config {
+            do_import: true,
+            no_reflink: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        let ino = fs
+            .lookup(req, 1, OsStr::new("image"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, ino, fh, 0, b"H", 0, 0).await.unwrap();
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+
+        // Data and size match, the holes and the trailing one aren't allocated.
+        let copy = std::fs::File::open(upper.path().join("image")).unwrap();
+        let meta = copy.metadata().unwrap();
+        assert_eq!(meta.len(), 64 << 20);
+        assert!(meta.blocks() * 512 < 4 << 20, "{} blocks", meta.blocks());
+        let mut buf = [0u8; 6];
+        copy.read_exact_at(&mut buf[..4], 0).unwrap();
+        assert_eq!(&buf[..4], b"Head");
+        copy.read_exact_at(&mut buf, 32 << 20).unwrap();
+        assert_eq!(&buf, b"middle");
+        copy.read_exact_at(&mut buf, 16 << 20).unwrap();
+        assert_eq!(buf, [0; 6]);
+        assert!(fs.write_amplification().copied_up < 4 << 20);
+    }
+
+    #[tokio::test]
+    async fn test_copy_up_reflink_fallback() {
+        use std::os::unix::fs::MetadataExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"first").unwrap();
+        std::fs::write(lower.path().join("b"), b"second").unwrap();
+        let dev = std::fs::metadata(lower.path()).unwrap().dev();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        // Whether or not the filesystem can clone, the upper copies hold the lower data.
+        for (name, data) in [("a", &b"first"[..]), ("b", &b"second"[..])] {
+            let ino = fs.lookup(req, 1, OsStr::new(name)).await.unwrap().attr.ino;
+            let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+            fs.write(req, ino, fh, 0, b"x", 0, 0).await.unwrap();
+            fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+            let mut expected = data.to_vec();
+            expected[0] = b'x';
+            assert_eq!(std::fs::read(upper.path().join(name)).unwrap(), expected);
+        }
+        let stats = fs.write_amplification();
+        assert_eq!(stats.copied_up + stats.cloned_up, 11);
+        // A filesystem found unable to clone isn't tried again.
+        if stats.cloned_up == 0 {
+            assert!(!fs.reflinks.worth_trying(dev));
         }
 
-        Ok(node)
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"first").unwrap();
+        let config = Config {
+            do_import: true,
+            no_reflink: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let ino = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino;
+        let fh = fs.open(req, ino, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, ino, fh, 0, b"x", 0, 0).await.unwrap();
+        fs.release(req, ino, fh, 0, 0, true).await.unwrap();
+        assert_eq!(fs.write_amplification().cloned_up, 0);
+        assert_eq!(fs.write_amplification().copied_up, 5);
     }
 
-    async fn do_rm(&self, ctx: Request, parent: u64, name: &OsStr, dir: bool) -> Result<()> {
-        // 1. Read-only mount guard
-        if self.upper_layer.is_none() {
-            return Err(Error::from_raw_os_error(libc::EROFS));
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("file"), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let ino = fs
+            .lookup(req, 1, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        fs.open(req, ino, libc::O_RDONLY as u32).await.unwrap();
+
+        let registry = metrics::MetricsRegistry::new();
+        registry.register("c1", fs.metrics());
+        let text = registry.render().await;
+        assert!(text.contains("libfuse_overlay_open_handles{mount=\"c1\"} 1\n"));
+        for layer in ["upper", "lower0"] {
+            let line = format!("libfuse_passthrough_files{{mount=\"c1\",layer=\"{layer}\"}} ");
+            assert!(text.contains(&line), "{text}");
         }
 
-        // 2. Locate the parent Overlay Inode.
-        // Find parent Overlay Inode.
-        let pnode = self.lookup_node(ctx, parent, "").await?;
-        if pnode.whiteout.load(Ordering::Relaxed) {
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(registry.clone().serve(listener));
+        for (path, status) in [("/metrics", "200 OK"), ("/", "404 Not Found")] {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {status}\r\n")));
         }
-        let to_name = name.to_str().unwrap();
+        server.abort();
 
-        // 3. Locate the child Overlay Inode for the given name
-        // Find the Overlay Inode for child with <name>.
-        let node = self.lookup_node(ctx, parent, to_name).await?;
-        if node.whiteout.load(Ordering::Relaxed) {
-            // already deleted.
-            return Err(Error::from_raw_os_error(libc::ENOENT));
+        assert!(registry.unregister("c1"));
+        assert!(registry.render().await.contains("# TYPE"));
+        assert!(!registry.render().await.contains("mount=\"c1\""));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shutdown() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("small"), b"lower").unwrap();
+        std::fs::write(lower.path().join("big"), vec![1u8; 64 << 20]).unwrap();
+        let fs = Arc::new(new_overlay(&[lower.path()], upper.path()).await);
+        let req = Request::default();
+        let small = fs
+            .lookup(req, 1, OsStr::new("small"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let big = fs.lookup(req, 1, OsStr::new("big")).await.unwrap().attr.ino;
+        let fh = fs.open(req, small, libc::O_RDWR as u32).await.unwrap().fh;
+        fs.write(req, small, fh, 0, b"upper", 0, 0).await.unwrap();
+
+        let open = {
+            let fs = fs.clone();
+            tokio::spawn(async move { fs.open(req, big, libc::O_RDWR as u32).await })
+        };
+        while fs.copy_up_progress(big).is_none() {
+            assert!(!open.is_finished(), "copy-up finished before it was seen");
+            tokio::task::yield_now().await;
         }
 
-        // 4. If removing a directory, ensure it is empty of real entries
-        if dir {
-            self.load_directory(ctx, &node).await?;
-            let (count, whiteouts) = node.count_entries_and_whiteout(ctx).await?;
-            trace!("entries: {count}, whiteouts: {whiteouts}\n");
-            if count > 0 {
-                return Err(Error::from_raw_os_error(libc::ENOTEMPTY));
-            }
+        // The copy-up doesn't finish in time and is cancelled, the open handle is closed
+        // with its data in the upper layer.
+        let report = fs
+            .shutdown_handle()
+            .shutdown(std::time::Duration::ZERO)
+            .await;
+        assert_eq!(report.abandoned_requests, 0);
+        assert_eq!(report.cancelled_copy_ups.len(), 1);
+        assert_eq!(report.cancelled_copy_ups[0].inode, big);
+        assert_eq!(report.closed_handles, 1);
+        assert_eq!(report.failed_handles, 0);
+        assert!(!report.is_clean());
+        let err = open.await.unwrap().unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ECANCELED));
+        assert_eq!(std::fs::read(upper.path().join("small")).unwrap(), b"upper");
+        assert!(!upper.path().join("big").exists());
+
+        // New requests are refused, releases of the closed handles still succeed.
+        let err = fs.lookup(req, 1, OsStr::new("small")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOTCONN));
+        fs.release(req, small, fh, 0, 0, true).await.unwrap();
+        assert!(fs.shutdown(std::time::Duration::ZERO).await.is_clean());
+    }
+
+    #[tokio::test]
+    async fn test_shared_attr_cache() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper1 = tempfile::tempdir().unwrap();
+        let upper2 = tempfile::tempdir().unwrap();
+        let file = lower.path().join("f");
+        std::fs::write(&file, b"lower").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink("f", lower.path().join("l")).unwrap();
+        let cache = SharedAttrCache::new(shared_attrs::DEFAULT_CAPACITY, shared_attrs::DEFAULT_TTL);
+        let config = || Config {
+            do_import: true,
+            shared_attr_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let req = Request::default();
+        let mode = async |fs: &OverlayFs| {
+            let entry = fs.lookup(req, 1, OsStr::new("f")).await.unwrap();
+            let attr = fs.getattr(req, entry.attr.ino, None, 0).await.unwrap();
+            attr.attr.perm & 0o7777
+        };
+
+        let fs1 = new_overlay_with(&[lower.path()], upper1.path(), config()).await;
+        assert_eq!(mode(&fs1).await, 0o644);
+        let link = fs1.lookup(req, 1, OsStr::new("l")).await.unwrap();
+        let target = fs1.readlink(req, link.attr.ino).await.unwrap();
+        assert_eq!(&target.data[..], b"f");
+
+        // The second overlay is served what the first one cached.
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let fs2 = new_overlay_with(&[lower.path()], upper2.path(), config()).await;
+        assert_eq!(mode(&fs2).await, 0o644);
+        let link = fs2.lookup(req, 1, OsStr::new("l")).await.unwrap();
+        let target = fs2.readlink(req, link.attr.ino).await.unwrap();
+        assert_eq!(&target.data[..], b"f");
+
+        cache.invalidate_layer(lower.path()).unwrap();
+        assert_eq!(mode(&fs2).await, 0o600);
+
+        // Copied up files are not cached anymore.
+        fs2.setattr(
+            req,
+            fs2.lookup(req, 1, OsStr::new("f")).await.unwrap().attr.ino,
+            None,
+            SetAttr {
+                mode: Some(0o640),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(mode(&fs2).await, 0o640);
+        assert_eq!(mode(&fs1).await, 0o600);
+    }
 
-            // Delete all whiteouts.
-            if whiteouts > 0 && node.in_upper_layer().await {
-                self.empty_node_directory(ctx, Arc::clone(&node)).await?;
+    #[tokio::test]
+    async fn test_watch_lower_layers() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/old"), b"old").unwrap();
+        let config = Config {
+            do_import: true,
+            watch_lower_layers: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+        let dir = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        fs.lookup(req, dir, OsStr::new("old")).await.unwrap();
+        let err = fs.lookup(req, dir, OsStr::new("new")).await.err().unwrap();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        std::fs::write(lower.path().join("d/new"), b"new").unwrap();
+        std::fs::remove_file(lower.path().join("d/old")).unwrap();
+        // Changes are picked up asynchronously.
+        let eventually = async |parent: Inode, name: &str| {
+            for _ in 0..50 {
+                if let Ok(entry) = fs.lookup(req, parent, OsStr::new(name)).await {
+                    return Some(entry.attr.ino);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             }
+            None
+        };
+        eventually(dir, "new")
+            .await
+            .expect("new lower file not seen");
+        let err = fs.lookup(req, dir, OsStr::new("old")).await.err().unwrap();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // Directories created later are watched too.
+        std::fs::create_dir(lower.path().join("d/sub")).unwrap();
+        let sub = eventually(dir, "sub")
+            .await
+            .expect("new lower dir not seen");
+        fs.lookup(req, sub, OsStr::new("x")).await.err().unwrap();
+        std::fs::write(lower.path().join("d/sub/x"), b"x").unwrap();
+        eventually(sub, "x")
+            .await
+            .expect("file in new lower dir not seen");
+    }
 
-            trace!("whiteouts deleted!\n");
+    #[tokio::test]
+    async fn test_fadvise() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("f"), vec![1u8; 64 * 1024]).unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let ino = fs.lookup(req, 1, OsStr::new("f")).await.unwrap().attr.ino;
+        let fh = fs.open(req, ino, libc::O_RDONLY as u32).await.unwrap().fh;
+
+        for advice in [
+            libc::POSIX_FADV_WILLNEED,
+            libc::POSIX_FADV_DONTNEED,
+            libc::POSIX_FADV_SEQUENTIAL,
+        ] {
+            fs.fadvise(ino, fh, 0, 0, advice).await.unwrap();
         }
+        let err = fs.fadvise(ino, fh, 0, 4096, 1000).await.err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .fadvise(ino, fh + 1, 0, 0, libc::POSIX_FADV_WILLNEED)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
 
-        // 5. Decide whether we need to create a whiteout entry
-        // We'll filp this off if upper-layer unlink suffices or parent is opaque
-        let need_whiteout = AtomicBool::new(true);
-        let pnode = self.copy_node_up(ctx, Arc::clone(&pnode)).await?;
+    #[tokio::test]
+    async fn test_statx() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("f"), b"lower").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let ino = fs.lookup(req, 1, OsStr::new("f")).await.unwrap().attr.ino;
+
+        let rep = fs.statx(req, ino, None, 0, libc::STATX_ALL).await.unwrap();
+        let attr = fs.getattr(req, ino, None, 0).await.unwrap().attr;
+        assert_eq!(rep.attr, attr);
+        let host = std::fs::metadata(lower.path().join("f")).unwrap();
+        let btime = host.created().ok().map(|t| {
+            let d = t.duration_since(std::time::UNIX_EPOCH).unwrap();
+            Timestamp::new(d.as_secs() as i64, d.subsec_nanos())
+        });
+        assert_eq!(rep.btime, btime);
 
-        if node.upper_layer_only().await {
-            need_whiteout.store(false, Ordering::Relaxed);
-        }
+        // Through a handle as well.
+        let fh = fs.open(req, ino, libc::O_RDONLY as u32).await.unwrap().fh;
+        let rep = fs
+            .statx(req, ino, Some(fh), 0, libc::STATX_ALL)
+            .await
+            .unwrap();
+        assert_eq!(rep.btime, btime);
+        assert_eq!(rep.attr.ino, ino);
+    }
 
-        let mut df = |parent_upper_inode: Option<Arc<RealInode>>| async {
-            let parent_real_inode = parent_upper_inode.ok_or_else(|| {
-                error!(
-                    "BUG: parent {} has no upper inode after copy up",
-                    pnode.inode
-                );
-                Error::from_raw_os_error(libc::EINVAL)
-            })?;
+    #[tokio::test]
+    async fn test_upper_strategy() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: dir.path(),
+            mapping: None::<&str>,
+            io_engine: Default::default(),
+        })
+        .await
+        .unwrap();
+        let config = Config {
+            opaque_xattr: config::OpaqueXattr::Trusted,
+            ..Default::default()
+        };
+        let strategy = UpperStrategy::negotiate(&layer, &config).unwrap();
+        // Char device whiteouts are kept where they can be created.
+        assert_eq!(strategy.xattr_whiteout, !layer.supports_whiteout());
+        let expected = if layer.supports_trusted_xattr() {
+            layer::PRIVILEGED_OPAQUE_XATTR
+        } else {
+            layer::UNPRIVILEGED_OPAQUE_XATTR
+        };
+        assert_eq!(strategy.opaque_xattr, Some(expected));
 
-            // Parent is opaque, it shadows everything in lower layers so no need to create extra whiteouts.
-            if parent_real_inode.opaque {
-                need_whiteout.store(false, Ordering::Relaxed);
-            }
-            if dir {
-                parent_real_inode
-                    .layer
-                    .rmdir(ctx, parent_real_inode.inode, name)
-                    .await?;
-            } else {
-                parent_real_inode
-                    .layer
-                    .unlink(ctx, parent_real_inode.inode, name)
-                    .await?;
-            }
+        let config = Config {
+            xattr_whiteout: true,
+            ..Default::default()
+        };
+        let strategy = UpperStrategy::negotiate(&layer, &config).unwrap();
+        assert!(strategy.xattr_whiteout);
+        assert_eq!(strategy.opaque_xattr, Some(layer::OPAQUE_XATTR));
+    }
 
-            Ok(false)
+    #[tokio::test]
+    async fn test_handle_less() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/a"), b"lower").unwrap();
+        let config = Config {
+            do_import: true,
+            no_open: true,
+            no_opendir: true,
+            ..Default::default()
         };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let a = fs.lookup(req, d, OsStr::new("a")).await.unwrap().attr.ino;
+
+        let err = fs.open(req, a, libc::O_RDONLY as u32).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOSYS));
+        let err = fs.opendir(req, d, libc::O_RDONLY as u32).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOSYS));
+
+        // Every request opens the real file for itself.
+        let data = fs.read(req, a, 0, 0, 16).await.unwrap();
+        assert_eq!(&data.data[..], b"lower");
+        fs.write(req, a, 0, 0, b"upper", 0, libc::O_WRONLY as u32)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(upper.path().join("d/a")).unwrap(), b"upper");
+        fs.fallocate(req, a, 0, 0, 4096, 0).await.unwrap();
+        fs.fsync(req, a, 0, false).await.unwrap();
+        fs.flush(req, a, 0, 0).await.unwrap();
+        fs.release(req, a, 0, 0, 0, true).await.unwrap();
+
+        let created = fs
+            .create(req, d, OsStr::new("b"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        assert_eq!(created.fh, 0);
+        fs.write(req, created.attr.ino, 0, 0, b"new", 0, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        let data = fs.read(req, created.attr.ino, 0, 0, 16).await.unwrap();
+        assert_eq!(&data.data[..], b"new");
 
-        // 6. Perform the unlink/rmdir operation and memory cleanup
-        if node.in_upper_layer().await {
-            pnode.handle_upper_inode_locked(&mut df).await?;
-        }
-        pnode.remove_child(name.to_str().unwrap()).await;
-        let path = node.path.read().await.clone();
-        self.remove_inode(node.inode, Some(path)).await;
+        let names: Vec<_> = fs
+            .readdir(req, d, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names.len(), 4);
+        fs.fsyncdir(req, d, 0, false).await.unwrap();
+        fs.releasedir(req, d, 0, 0).await.unwrap();
 
-        // 7. If needed, create a entry in the upper layer to mask lower-layer files
-        if need_whiteout.load(Ordering::Relaxed) {
-            trace!("do_rm: creating whiteout\n");
-            // pnode is copied up, so it has upper layer.
-            pnode
-                .handle_upper_inode_locked(
-                    &mut |parent_upper_inode: Option<Arc<RealInode>>| async {
-                        let parent_real_inode = parent_upper_inode.ok_or_else(|| {
-                            error!(
-                                "BUG: parent {} has no upper inode after copy up",
-                                pnode.inode
-                            );
-                            Error::from_raw_os_error(libc::EINVAL)
-                        })?;
+        // No overlay handle was ever allocated.
+        assert!(fs.handles.lock().await.is_empty());
+        assert_eq!(fs.next_handle.load(Ordering::Relaxed), 1);
+    }
 
-                        let child_ri = parent_real_inode.create_whiteout(ctx, to_name).await?; //FIXME..............
-                        let path = format!("{}/{}", pnode.path.read().await, to_name);
-                        let ino: u64 = self.alloc_inode(&path).await?;
-                        let ovi = Arc::new(
-                            OverlayInode::new_from_real_inode(to_name, ino, path.clone(), child_ri)
-                                .await,
-                        );
+    #[test]
+    fn test_dedupe_scan() {
+        let upper = tempfile::tempdir().unwrap();
+        let lower = tempfile::tempdir().unwrap();
+        let mut state = 1u32;
+        let mut random = |len: usize| {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 16) as u8
+                })
+                .collect::<Vec<_>>()
+        };
+        let base = random(256 * 1024);
+        let unique = random(64 * 1024);
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/base"), &base).unwrap();
+        std::fs::create_dir(upper.path().join("d")).unwrap();
+        // Copied up without changes.
+        std::fs::write(upper.path().join("d/base"), &base).unwrap();
+        // Shifted by an insertion, only the chunk around it differs.
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&base);
+        std::fs::write(upper.path().join("shifted"), &shifted).unwrap();
+        std::fs::write(upper.path().join("u1"), &unique).unwrap();
+        std::fs::write(upper.path().join("u2"), &unique).unwrap();
+
+        let params = dedupe::ChunkParams::default();
+        let report = dedupe::scan(upper.path(), &[lower.path()], params).unwrap();
+        assert_eq!(report.files, 4);
+        assert_eq!(report.bytes, (2 * base.len() + 8 + 2 * unique.len()) as u64);
+        assert_eq!(report.upper_duplicate_bytes, unique.len() as u64);
+        let paths = report
+            .duplicate_files
+            .iter()
+            .map(|f| f.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["d/base", "shifted"]);
+        assert!(report.duplicate_files[0].is_redundant());
+        let shifted = &report.duplicate_files[1];
+        assert!(!shifted.is_redundant());
+        assert!(shifted.size - shifted.duplicate_bytes <= params.max_size as u64 + 8);
+        assert_eq!(
+            report.duplicate_bytes,
+            report.duplicate_files[0].duplicate_bytes + shifted.duplicate_bytes
+        );
 
-                        self.insert_inode(ino, ovi.clone()).await;
-                        pnode.insert_child(to_name, ovi.clone()).await;
-                        Ok(false)
-                    },
-                )
-                .await?;
-        }
+        let job = dedupe::DedupeJob::spawn(
+            upper.path().to_path_buf(),
+            vec![lower.path().to_path_buf()],
+            params,
+        )
+        .unwrap();
+        assert_eq!(job.join().unwrap(), report);
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn test_self_test_dir_hidden() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let mountpoint = tempfile::tempdir().unwrap();
+        std::fs::create_dir(upper.path().join(SELF_TEST_DIR)).unwrap();
+        std::fs::write(upper.path().join("x"), b"").unwrap();
+        let config = Config {
+            mountpoint: mountpoint.path().to_path_buf(),
+            do_import: true,
+            self_test_interval: Some(std::time::Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        assert!(fs.self_test().is_some());
+        let req = Request::default();
+
+        let fh = fs.opendir(req, 1, libc::O_RDONLY as u32).await.unwrap().fh;
+        let names: Vec<_> = fs
+            .readdir(req, 1, fh, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap().name)
+            .collect()
+            .await;
+        assert!(names.contains(&OsString::from("x")));
+        assert!(!names.contains(&OsString::from(SELF_TEST_DIR)));
+        fs.lookup(req, 1, OsStr::new(SELF_TEST_DIR)).await.unwrap();
+        // The probe leaves the unmounted directory alone.
+        assert!(!mountpoint.path().join(SELF_TEST_DIR).exists());
     }
 
-    async fn do_fsync(
-        &self,
-        ctx: Request,
-        inode: Inode,
-        datasync: bool,
-        handle: Handle,
-        syncdir: bool,
-    ) -> Result<()> {
-        // Use O_RDONLY flags which indicates no copy up.
-        let data = self
-            .get_data(ctx, Some(handle), inode, libc::O_RDONLY as u32)
-            .await?;
+    #[tokio::test]
+    async fn test_readdir_kinds() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("f"), b"").unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::os::unix::fs::symlink("f", lower.path().join("l")).unwrap();
+        std::fs::write(lower.path().join("replaced"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        // The whiteout left by unlink is replaced by a directory.
+        fs.unlink(req, 1, OsStr::new("replaced")).await.unwrap();
+        fs.mkdir(req, 1, OsStr::new("replaced"), 0o755, 0)
+            .await
+            .unwrap();
 
-        trace!("do_fsync: got data for handle: {handle}, inode:{inode}");
+        let fh = fs.opendir(req, 1, libc::O_RDONLY as u32).await.unwrap().fh;
+        let kinds: HashMap<_, _> = fs
+            .readdir(req, 1, fh, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| {
+                let e = e.unwrap();
+                (e.name, e.kind)
+            })
+            .collect()
+            .await;
+        assert_eq!(kinds[OsStr::new("f")], FileType::RegularFile);
+        assert_eq!(kinds[OsStr::new("d")], FileType::Directory);
+        assert_eq!(kinds[OsStr::new("l")], FileType::Symlink);
+        assert_eq!(kinds[OsStr::new("replaced")], FileType::Directory);
+    }
 
-        match data.real_handle {
-            // FIXME: need to test if inode matches corresponding handle?
-            None => {
-                trace!("do_fsync: no real handle found for handle: {handle}, inode:{inode}");
-                Err(Error::from_raw_os_error(libc::ENOENT))
-            }
-            Some(ref rh) => {
-                let real_handle = rh.handle.load(Ordering::Relaxed);
-                // TODO: check if it's in upper layer? @weizhang555
-                if syncdir {
-                    trace!(
-                        "do_fsync: layer.fsyncdir called for handle: {}, inode:{}; rh.inode: {}, real_handle: {}",
-                        handle, inode, rh.inode, real_handle
-                    );
-                    rh.layer
-                        .fsyncdir(ctx, rh.inode, real_handle, datasync)
-                        .await
-                        .map_err(|e| e.into())
-                } else {
-                    rh.layer
-                        .fsync(ctx, rh.inode, real_handle, datasync)
-                        .await
-                        .map_err(|e| e.into())
-                }
-            }
-        }
+    #[tokio::test]
+    async fn test_batch_forget() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("a"), b"").unwrap();
+        std::fs::write(lower.path().join("b"), b"").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+        let a = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino;
+        fs.lookup(req, 1, OsStr::new("a")).await.unwrap();
+        let b = fs.lookup(req, 1, OsStr::new("b")).await.unwrap().attr.ino;
+        let lookups = |ino| {
+            fs.inodes
+                .get_inode(ino)
+                .map(|n| n.lookups.load(Ordering::Relaxed))
+        };
+        let (la, lb) = (lookups(a).unwrap(), lookups(b).unwrap());
+
+        fs.batch_forget(req, &[(a, la - 1), (b, lb), (1, 5), (12345, 1)])
+            .await;
+        assert_eq!(lookups(a), Some(1));
+        assert_eq!(lookups(b), None);
+        let root = fs.root_node().await;
+        assert!(root.child(OsStr::new("a")).await.is_some());
+        assert!(root.child(OsStr::new("b")).await.is_none());
+        assert!(fs.get_active_inode(1).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_request_panic_recorded() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let fs = Arc::new(new_overlay(&[lower.path()], upper.path()).await);
+        let shutdown = fs.shutdown_handle();
+        let task = {
+            let fs = fs.clone();
+            tokio::spawn(async move {
+                let _op = fs.drain.enter().unwrap();
+                std::panic!("BUG: dangling OverlayInode");
+            })
+        };
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(shutdown.panics(), 1);
+        tokio::time::timeout(std::time::Duration::from_secs(1), shutdown.panicked())
+            .await
+            .unwrap();
+        // The overlay keeps serving other requests.
+        fs.lookup(Request::default(), 1, OsStr::new(".")).await.ok();
+        assert!(fs.drain.enter().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lower_index() {
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(bottom.path().join("d/sub")).unwrap();
+        std::fs::write(bottom.path().join("d/b"), b"bottom").unwrap();
+        std::fs::write(bottom.path().join("d/sub/x"), b"x").unwrap();
+        std::fs::create_dir(top.path().join("d")).unwrap();
+        std::fs::write(top.path().join("d/a"), b"a").unwrap();
+        std::fs::write(top.path().join("d/b"), b"top").unwrap();
+        let index = work.path().join("lower.idx");
+        lower_index::build(&[top.path(), bottom.path()], &index).unwrap();
+
+        // An index built for other layers is refused.
+        let err = LowerIndex::open(&index, 1).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let config = Config {
+            do_import: true,
+            lower_index: Some(index),
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[top.path(), bottom.path()], upper.path(), config).await;
+        let req = Request::default();
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let dnode = fs.get_active_inode(d).await.unwrap();
+
+        // Lookups only materialize the requested entry, and the top layer still wins.
+        let b = fs.lookup(req, d, OsStr::new("b")).await.unwrap();
+        assert_eq!(b.attr.size, 3);
+        let err = fs.lookup(req, d, OsStr::new("missing")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        assert!(!dnode.loaded.load(Ordering::Relaxed));
+        assert_eq!(dnode.childrens.lock().await.len(), 1);
+
+        // Plain readdir lists the merged view with the inode numbers lookups hand out.
+        let fs = &fs;
+        let list = |fh| async move {
+            let entries: Vec<_> = fs
+                .readdir(req, d, fh, 0)
+                .await
+                .unwrap()
+                .entries
+                .collect()
+                .await;
+            let mut entries: Vec<(OsString, u64)> = entries
+                .into_iter()
+                .map(|e| e.map(|e| (e.name, e.inode)).unwrap())
+                .collect();
+            entries.sort();
+            entries
+        };
+        let fh = fs.opendir(req, d, 0).await.unwrap().fh;
+        let entries = list(fh).await;
+        let names: Vec<_> = entries.iter().map(|(n, _)| n.as_os_str()).collect();
+        assert_eq!(names, [".", "..", "a", "b", "sub"]);
+        let sub = fs.lookup(req, d, OsStr::new("sub")).await.unwrap().attr.ino;
+        assert!(entries.contains(&("b".into(), b.attr.ino)));
+        assert!(entries.contains(&("sub".into(), sub)));
+        assert_eq!(dnode.childrens.lock().await.len(), 2);
+
+        // A mutation copies the directory up, after which it is fully loaded.
+        fs.create(req, d, OsStr::new("new"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        let fh = fs.opendir(req, d, 0).await.unwrap().fh;
+        let entries = list(fh).await;
+        let names: Vec<_> = entries.iter().map(|(n, _)| n.as_os_str()).collect();
+        assert_eq!(names, [".", "..", "a", "b", "new", "sub"]);
+        assert!(entries.contains(&("sub".into(), sub)));
+    }
+
+    #[tokio::test]
+    async fn test_xattr_whiteout() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("f"), b"lower").unwrap();
+        std::fs::write(lower.path().join("g"), b"lower").unwrap();
+        let config = Config {
+            do_import: true,
+            xattr_whiteout: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        // Removing lower files leaves marked empty files instead of char devices.
+        fs.unlink(req, 1, OsStr::new("f")).await.unwrap();
+        fs.unlink(req, 1, OsStr::new("g")).await.unwrap();
+        let md = std::fs::metadata(upper.path().join("f")).unwrap();
+        assert!(md.is_file() && md.len() == 0);
+        let err = fs.lookup(req, 1, OsStr::new("f")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // Creating the name again replaces the whiteout.
+        fs.create(req, 1, OsStr::new("g"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs.lookup(req, 1, OsStr::new("g")).await.unwrap().attr.size,
+            0
+        );
+        drop(fs);
+
+        // Both forms are recognized regardless of the configured one.
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let err = fs.lookup(req, 1, OsStr::new("f")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        assert!(fs.lookup(req, 1, OsStr::new("g")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_layer_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = |name: &str| {
+            let path = tmp.path().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            path
+        };
+        let (top, mid, bottom) = (dir("top"), dir("mid"), dir("bottom"));
+        std::fs::write(top.join("c"), b"c").unwrap();
+        xattr_whiteout(&mid.join("a"));
+        std::fs::create_dir(mid.join("d")).unwrap();
+        set_xattr(&mid.join("d"), layer::OPAQUE_XATTR, b"y");
+        std::fs::write(mid.join("d/z"), b"z").unwrap();
+        std::fs::write(mid.join("b"), b"b").unwrap();
+        std::fs::write(bottom.join("a"), b"a").unwrap();
+        std::fs::create_dir(bottom.join("d")).unwrap();
+        std::fs::write(bottom.join("d/x"), b"x").unwrap();
+        std::fs::create_dir(bottom.join("e")).unwrap();
+        std::fs::write(bottom.join("e/y"), b"y").unwrap();
+        let lowers = vec![top.clone(), mid, bottom];
+
+        let limit = LayerLimit {
+            max_lower: 2,
+            squash_dir: None,
+        };
+        let err = limit.apply(lowers.clone(), None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            MountError::TooManyLayers { count: 3, max: 2 }
+        ));
+        let within = LayerLimit::default().apply(lowers.clone(), None).await;
+        assert_eq!(within.unwrap(), lowers);
+
+        let limit = LayerLimit {
+            squash_dir: Some(dir("squashed")),
+            ..limit
+        };
+        let squashed = limit.apply(lowers.clone(), None).await.unwrap();
+        assert_eq!(squashed.len(), 2);
+        assert_eq!(squashed[0], top);
+        let merged = &squashed[1];
+        assert_eq!(std::fs::read(merged.join("b")).unwrap(), b"b");
+        assert_eq!(std::fs::read(merged.join("d/z")).unwrap(), b"z");
+        assert_eq!(std::fs::read(merged.join("e/y")).unwrap(), b"y");
+        assert!(!merged.join("a").exists());
+        assert!(!merged.join("d/x").exists());
+
+        // Mounting the same image again reuses the squashed layer.
+        std::fs::write(merged.join("marker"), b"").unwrap();
+        let again = limit.apply(lowers, None).await.unwrap();
+        assert_eq!(&again[1], merged);
+        assert!(merged.join("marker").exists());
     }
 
-    // Delete everything in the directory only on upper layer, ignore lower layers.
-    async fn empty_node_directory(&self, ctx: Request, node: Arc<OverlayInode>) -> Result<()> {
-        let st = node.stat64(ctx).await?;
-        if !utils::is_dir(&st.attr.kind) {
-            // This function can only be called on directories.
-            return Err(Error::from_raw_os_error(libc::ENOTDIR));
-        }
+    fn set_xattr(path: &Path, name: &str, value: &[u8]) {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        let ret = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
 
-        let (layer, in_upper, inode) = node.first_layer_inode().await;
-        if !in_upper {
-            return Ok(());
-        }
+    // Create a whiteout in its xattr form, which needs no `CAP_MKNOD`.
+    fn xattr_whiteout(path: &Path) {
+        std::fs::write(path, b"").unwrap();
+        set_xattr(path, layer::WHITEOUT_XATTR, b"y");
+    }
 
-        // Copy node.childrens Hashmap to Vector, the Vector is also used as temp storage,
-        // Without this, Rust won't allow us to remove them from node.childrens.
-        let iter = node
-            .childrens
-            .lock()
-            .await
-            .values()
-            .cloned()
-            .collect::<Vec<_>>();
+    fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        let mut value = [0u8; 64];
+        let n = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        (n >= 0).then(|| value[..n as usize].to_vec())
+    }
 
-        for child in iter {
-            // We only care about upper layer, ignore lower layers.
-            if child.in_upper_layer().await {
-                let child_name = child.name.read().await.clone();
-                let child_name_os = OsStr::new(&child_name);
-                if child.whiteout.load(Ordering::Relaxed) {
-                    layer.delete_whiteout(ctx, inode, child_name_os).await?
-                } else {
-                    let s = child.stat64(ctx).await?;
-                    let cname: &OsStr = OsStr::new(&child_name_os);
-                    if utils::is_dir(&s.attr.kind) {
-                        let (count, whiteouts) = child.count_entries_and_whiteout(ctx).await?;
-                        if count + whiteouts > 0 {
-                            let cb = child.clone();
-                            Box::pin(async move { self.empty_node_directory(ctx, cb).await })
-                                .await?;
-                        }
-                        layer.rmdir(ctx, inode, cname).await?
-                    } else {
-                        layer.unlink(ctx, inode, cname).await?;
-                    }
-                }
+    #[tokio::test]
+    async fn test_opaque_xattr_names() {
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(bottom.path().join("d")).unwrap();
+        std::fs::write(bottom.path().join("d/old"), b"old").unwrap();
+        std::fs::create_dir(bottom.path().join("e")).unwrap();
+        std::fs::create_dir(top.path().join("d")).unwrap();
+        std::fs::write(top.path().join("d/new"), b"new").unwrap();
+        // Marked the way kernel overlayfs does it with `userxattr`.
+        let d = std::ffi::CString::new(top.path().join("d").as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(layer::UNPRIVILEGED_OPAQUE_XATTR).unwrap();
+        let ret = unsafe { libc::setxattr(d.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+        assert_eq!(ret, 0);
+        let config = Config {
+            do_import: true,
+            opaque_xattr: config::OpaqueXattr::User,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[top.path(), bottom.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        // A foreign opaque marker hides the lower directory's entries.
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        assert!(fs.lookup(req, d, OsStr::new("new")).await.is_ok());
+        let err = fs.lookup(req, d, OsStr::new("old")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // Replacing a lower directory writes the configured marker.
+        fs.rmdir(req, 1, OsStr::new("e")).await.unwrap();
+        fs.mkdir(req, 1, OsStr::new("e"), 0o755, 0).await.unwrap();
+        let e = upper.path().join("e");
+        assert_eq!(
+            get_xattr(&e, layer::UNPRIVILEGED_OPAQUE_XATTR).as_deref(),
+            Some(b"y".as_slice())
+        );
+        assert_eq!(get_xattr(&e, layer::OPAQUE_XATTR), None);
+    }
 
-                let cpath = child.path.read().await.clone();
-                // delete the child
-                self.remove_inode(child.inode, Some(cpath)).await;
-                node.remove_child(&child_name).await;
-            }
+    #[tokio::test]
+    async fn test_rmdir_emptied_merged_dir() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        for i in 0..32 {
+            std::fs::write(lower.path().join(format!("d/f{i}")), b"").unwrap();
         }
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        for i in 0..32 {
+            fs.unlink(req, d, OsStr::new(&format!("f{i}")))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            std::fs::read_dir(upper.path().join("d")).unwrap().count(),
+            32
+        );
 
-        Ok(())
+        // The upper directory goes with its whiteouts, a single whiteout replaces it.
+        fs.rmdir(req, 1, OsStr::new("d")).await.unwrap();
+        let meta = std::fs::symlink_metadata(upper.path().join("d")).unwrap();
+        assert!(!meta.is_dir());
+        let err = fs.lookup(req, 1, OsStr::new("d")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // The new directory hides the lower entries by being opaque, with no whiteout left.
+        fs.mkdir(req, 1, OsStr::new("d"), 0o755, 0).await.unwrap();
+        assert_eq!(
+            std::fs::read_dir(upper.path().join("d")).unwrap().count(),
+            0
+        );
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, d, OsStr::new("f0")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
     }
 
-    async fn find_real_info_from_handle(
-        &self,
-        handle: Handle,
-    ) -> Result<(Arc<BoxedLayer>, Inode, Handle)> {
-        match self.handles.lock().await.get(&handle) {
-            Some(h) => match h.real_handle {
-                Some(ref rhd) => {
-                    trace!(
-                        "find_real_info_from_handle: layer in upper: {}",
-                        rhd.in_upper_layer
-                    );
-                    Ok((
-                        rhd.layer.clone(),
-                        rhd.inode,
-                        rhd.handle.load(Ordering::Relaxed),
-                    ))
-                }
-                None => Err(Error::from_raw_os_error(libc::ENOENT)),
-            },
+    #[tokio::test]
+    async fn test_layer_statfs() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let other = new_overlay(&[lower.path()], upper.path()).await;
+        assert_ne!(fs.fsid(), other.fsid());
+
+        let stats = fs.layer_statfs().await.unwrap();
+        let roles: Vec<_> = stats.iter().map(|s| s.role).collect();
+        assert_eq!(roles, [LayerRole::Upper, LayerRole::Lower(0)]);
+        assert_eq!(stats[0].root, upper.path());
+        assert_eq!(stats[1].root, lower.path());
+        // Both temp dirs live on the same filesystem.
+        assert_eq!(stats[0].fsid, stats[1].fsid);
+        assert!(stats.iter().all(|s| s.statfs.blocks > 0));
+    }
 
-            None => Err(Error::from_raw_os_error(libc::ENOENT)),
+    #[tokio::test]
+    async fn test_lower_layer_whiteouts() {
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        for name in ["dev", "marked", "kept"] {
+            std::fs::write(bottom.path().join(name), b"bottom").unwrap();
+        }
+        // Whiteouts left in a lower layer by another tool, in both forms.
+        let marked = top.path().join("marked");
+        std::fs::write(&marked, b"").unwrap();
+        let path = std::ffi::CString::new(marked.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(layer::WHITEOUT_XATTR).unwrap();
+        let ret =
+            unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+        assert_eq!(ret, 0);
+        let dev = std::ffi::CString::new(top.path().join("dev").as_os_str().as_bytes()).unwrap();
+        let chardev = unsafe { libc::mknod(dev.as_ptr(), libc::S_IFCHR | 0o644, 0) } == 0;
+        if !chardev {
+            eprintln!("no CAP_MKNOD, only checking xattr whiteouts");
         }
+        let fs = new_overlay(&[top.path(), bottom.path()], upper.path()).await;
+        let req = Request::default();
+
+        let err = fs.lookup(req, 1, OsStr::new("marked")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        if chardev {
+            let err = fs.lookup(req, 1, OsStr::new("dev")).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        }
+        assert_eq!(
+            fs.lookup(req, 1, OsStr::new("kept"))
+                .await
+                .unwrap()
+                .attr
+                .size,
+            6
+        );
     }
 
-    async fn find_real_inode(&self, inode: Inode) -> Result<(Arc<BoxedLayer>, Inode)> {
-        if let Some(n) = self.get_active_inode(inode).await {
-            let (first_layer, _, first_inode) = n.first_layer_inode().await;
-            return Ok((first_layer, first_inode));
-        } else if let Some(n) = self.get_all_inode(inode).await {
-            trace!("find_real_inode: found inode by get_all_inode: {}", n.inode);
-            let (first_layer, _, first_inode) = n.first_layer_inode().await;
-            return Ok((first_layer, first_inode));
+    #[tokio::test]
+    async fn test_copy_up_xattrs() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("d")).unwrap();
+        std::fs::write(lower.path().join("d/f"), b"data").unwrap();
+        for (path, name) in [
+            ("d", "user.test"),
+            ("d", layer::UNPRIVILEGED_OPAQUE_XATTR),
+            ("d/f", "user.test"),
+        ] {
+            let path = lower.path().join(path);
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+            let name = std::ffi::CString::new(name).unwrap();
+            let ret =
+                unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+            assert_eq!(ret, 0);
         }
-
-        Err(Error::from_raw_os_error(libc::ENOENT))
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        // Writing the file copies it up along with its parent, xattrs included.
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let f = fs.lookup(req, d, OsStr::new("f")).await.unwrap().attr.ino;
+        fs.open(req, f, libc::O_RDWR as u32).await.unwrap();
+        let (d, f) = (upper.path().join("d"), upper.path().join("d/f"));
+        assert_eq!(std::fs::read(&f).unwrap(), b"data");
+        assert_eq!(get_xattr(&f, "user.test").as_deref(), Some(b"y".as_slice()));
+        assert_eq!(get_xattr(&d, "user.test").as_deref(), Some(b"y".as_slice()));
+        // Overlay markers describe the lower layer and stay behind.
+        assert_eq!(get_xattr(&d, layer::UNPRIVILEGED_OPAQUE_XATTR), None);
+
+        let policy = config::XattrEpermPolicy {
+            trusted: config::XattrEpermAction::MapToUser,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.action(b"trusted.foo"),
+            config::XattrEpermAction::MapToUser
+        );
+        assert_eq!(
+            policy.action(b"security.capability"),
+            config::XattrEpermAction::Skip
+        );
+        assert_eq!(policy.action(b"user.foo"), config::XattrEpermAction::Fail);
     }
 
-    async fn get_data(
-        &self,
-        ctx: Request,
-        handle: Option<Handle>,
-        inode: Inode,
-        flags: u32,
-    ) -> Result<Arc<HandleData>> {
-        let no_open = self.no_open.load(Ordering::Relaxed);
-        if !no_open {
-            if let Some(h) = handle
-                && let Some(v) = self.handles.lock().await.get(&h)
-                && v.node.inode == inode
-            {
-                // trace!("get_data: found handle");
-                return Ok(Arc::clone(v));
+    #[tokio::test]
+    async fn test_copy_up_capabilities() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        // v2 capabilities with CAP_NET_RAW (13) permitted and effective, like ping has.
+        let mut caps = Vec::new();
+        for word in [0x0200_0001_u32, 1 << 13, 0, 0, 0] {
+            caps.extend_from_slice(&word.to_le_bytes());
+        }
+        for name in ["ping", "chmod"] {
+            let path = lower.path().join(name);
+            std::fs::write(&path, b"binary").unwrap();
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+            let name = std::ffi::CString::new(CAPABILITY_XATTR).unwrap();
+            let ret = unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    caps.as_ptr().cast(),
+                    caps.len(),
+                    0,
+                )
+            };
+            if ret != 0 {
+                let e = std::io::Error::last_os_error();
+                assert_eq!(e.raw_os_error(), Some(libc::EPERM), "{e}");
+                eprintln!("skip test_copy_up_capabilities: {e}");
+                return;
             }
-        } else {
-            let readonly: bool = flags
-                & (libc::O_APPEND | libc::O_CREAT | libc::O_TRUNC | libc::O_RDWR | libc::O_WRONLY)
-                    as u32
-                == 0;
+        }
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
 
-            // lookup node
-            let node = self.lookup_node(ctx, inode, "").await?;
+        // Whether copied up to be written or for a metadata change.
+        let ping = fs
+            .lookup(req, 1, OsStr::new("ping"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        fs.open(req, ping, libc::O_RDWR as u32).await.unwrap();
+        let chmod = fs
+            .lookup(req, 1, OsStr::new("chmod"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let attr = SetAttr {
+            mode: Some(0o755),
+            ..Default::default()
+        };
+        fs.setattr(req, chmod, None, attr).await.unwrap();
+        for name in ["ping", "chmod"] {
+            let path = upper.path().join(name);
+            assert_eq!(std::fs::read(&path).unwrap(), b"binary");
+            assert_eq!(get_xattr(&path, "security.capability"), Some(caps.clone()));
+        }
+    }
 
-            // whiteout node
-            if node.whiteout.load(Ordering::Relaxed) {
-                return Err(Error::from_raw_os_error(libc::ENOENT));
-            }
+    #[tokio::test]
+    async fn test_copy_up_fifo() {
+        use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
 
-            if !readonly {
-                // Check if upper layer exists, return EROFS is not exists.
-                self.upper_layer
-                    .as_ref()
-                    .cloned()
-                    .ok_or_else(|| Error::from_raw_os_error(libc::EROFS))?;
-                // copy up to upper layer
-                self.copy_node_up(ctx, Arc::clone(&node)).await?;
-            }
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let fifo = lower.path().join("pipe");
+        let fifo = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
 
-            let (layer, in_upper_layer, inode) = node.first_layer_inode().await;
-            let handle_data = HandleData {
-                node: Arc::clone(&node),
-                real_handle: Some(RealHandle {
-                    layer,
-                    in_upper_layer,
-                    inode,
-                    handle: AtomicU64::new(0),
-                }),
-                dir_snapshot: Mutex::new(None),
-            };
-            return Ok(Arc::new(handle_data));
+        let ino = fs
+            .lookup(req, 1, OsStr::new("pipe"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let attr = SetAttr {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let reply = fs.setattr(req, ino, None, attr).await.unwrap();
+        assert_eq!(reply.attr.kind, FileType::NamedPipe);
+        assert_eq!(reply.attr.perm, 0o600);
+
+        let meta = std::fs::symlink_metadata(upper.path().join("pipe")).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_set_dir_opaque() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(lower.path().join("d/sub")).unwrap();
+        std::fs::write(lower.path().join("d/a"), b"a").unwrap();
+        std::fs::write(lower.path().join("d/sub/x"), b"x").unwrap();
+        std::fs::create_dir(lower.path().join("e")).unwrap();
+        std::fs::write(lower.path().join("e/c"), b"c").unwrap();
+        std::fs::create_dir(upper.path().join("d")).unwrap();
+        std::fs::write(upper.path().join("d/b"), b"b").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        fs.lookup(req, d, OsStr::new("a")).await.unwrap();
+        fs.set_dir_opaque(Path::new("/d")).await.unwrap();
+        for name in ["a", "sub"] {
+            let err = fs.lookup(req, d, OsStr::new(name)).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
         }
+        fs.lookup(req, d, OsStr::new("b")).await.unwrap();
+        assert!(get_xattr(&upper.path().join("d"), layer::OPAQUE_XATTR).is_some());
+
+        // Through the mount, any opaque xattr name triggers the same.
+        let e = fs.lookup(req, 1, OsStr::new("e")).await.unwrap().attr.ino;
+        fs.setxattr(
+            req,
+            e,
+            OsStr::new(layer::PRIVILEGED_OPAQUE_XATTR),
+            b"y",
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+        let err = fs.lookup(req, e, OsStr::new("c")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // The markers persist for the next mount.
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        assert!(fs.lookup(req, d, OsStr::new("a")).await.is_err());
+        fs.lookup(req, d, OsStr::new("b")).await.unwrap();
+        let e = fs.lookup(req, 1, OsStr::new("e")).await.unwrap().attr.ino;
+        assert!(fs.lookup(req, e, OsStr::new("c")).await.is_err());
+
+        let err = fs.set_dir_opaque(Path::new("d/b")).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    }
 
-        Err(Error::from_raw_os_error(libc::ENOENT))
+    #[tokio::test]
+    async fn test_apply_deletions() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(lower.path().join("etc/ssl/certs")).unwrap();
+        std::fs::write(lower.path().join("etc/ssl/certs/ca"), b"ca").unwrap();
+        std::fs::write(lower.path().join("etc/passwd"), b"root").unwrap();
+        std::fs::write(lower.path().join("etc/hosts"), b"localhost").unwrap();
+        std::fs::create_dir_all(lower.path().join("var/cache")).unwrap();
+        std::fs::write(lower.path().join("var/cache/old"), b"old").unwrap();
+        std::fs::create_dir(upper.path().join("var")).unwrap();
+        std::fs::create_dir(upper.path().join("var/log")).unwrap();
+        std::fs::write(upper.path().join("var/log/app"), b"log").unwrap();
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let req = Request::default();
+
+        let report = fs
+            .apply_deletions([
+                Deletion::from(Path::new("etc/passwd")),
+                Deletion::from("etc/ssl"),
+                Deletion::from("etc/ssl/certs/ca"),
+                Deletion::from("var/log"),
+                Deletion::Clear("var/cache".into()),
+                Deletion::from("missing/file"),
+                Deletion::from("etc/missing"),
+            ])
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.applied, 4);
+        let mut absent = report.absent;
+        absent.sort();
+        assert_eq!(
+            absent,
+            [
+                PathBuf::from("etc/missing"),
+                PathBuf::from("etc/ssl/certs/ca"),
+                PathBuf::from("missing/file"),
+            ]
+        );
+
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        for name in ["passwd", "ssl"] {
+            let err = fs.lookup(req, etc, OsStr::new(name)).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        }
+        fs.lookup(req, etc, OsStr::new("hosts")).await.unwrap();
+        let var = fs.lookup(req, 1, OsStr::new("var")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, var, OsStr::new("log")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        let cache = fs
+            .lookup(req, var, OsStr::new("cache"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let err = fs.lookup(req, cache, OsStr::new("old")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        // Upper entries are gone, lower ones whited out, for the next mount as well.
+        assert!(!upper.path().join("var/log").exists());
+        let fs = new_overlay(&[lower.path()], upper.path()).await;
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        assert!(fs.lookup(req, etc, OsStr::new("ssl")).await.is_err());
+        assert!(fs.lookup(req, etc, OsStr::new("passwd")).await.is_err());
+        let var = fs.lookup(req, 1, OsStr::new("var")).await.unwrap().attr.ino;
+        let cache = fs
+            .lookup(req, var, OsStr::new("cache"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        assert!(fs.lookup(req, cache, OsStr::new("old")).await.is_err());
     }
 
-    // extend or init the inodes number to one overlay if the current number is done.
-    pub async fn extend_inode_alloc(&self, key: u64) {
-        let next_inode = key * INODE_ALLOC_BATCH;
-        let limit_inode = next_inode + INODE_ALLOC_BATCH - 1;
-        self.inodes
-            .write()
+    #[tokio::test]
+    async fn test_read_only_overlay() {
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        std::fs::create_dir(bottom.path().join("d")).unwrap();
+        std::fs::write(bottom.path().join("d/f"), b"bottom").unwrap();
+        std::fs::write(top.path().join("f"), b"top").unwrap();
+        let mut lower_layers = Vec::new();
+        for lower in [top.path(), bottom.path()] {
+            let layer = new_passthroughfs_layer(PassthroughArgs {
+                root_dir: lower,
+                mapping: None::<&str>,
+                io_engine: Default::default(),
+            })
+            .await
+            .unwrap();
+            lower_layers.push(Arc::new(layer));
+        }
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(None, lower_layers, config, 1).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        let f = fs.lookup(req, d, OsStr::new("f")).await.unwrap().attr.ino;
+        let fh = fs.open(req, f, libc::O_RDONLY as u32).await.unwrap().fh;
+        let data = fs.read(req, f, fh, 0, 16).await.unwrap().data;
+        assert_eq!(data.as_ref(), b"bottom");
+
+        let erofs = Some(libc::EROFS);
+        let err = fs.open(req, f, libc::O_RDWR as u32).await.unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .mkdir(req, d, OsStr::new("x"), 0o755, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .create(req, d, OsStr::new("x"), 0o644, libc::O_RDWR as u32)
             .await
-            .extend_inode_number(next_inode, limit_inode);
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs.unlink(req, d, OsStr::new("f")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs.rmdir(req, 1, OsStr::new("d")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .rename(req, 1, OsStr::new("f"), d, OsStr::new("g"))
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .symlink(req, d, OsStr::new("l"), OsStr::new("f"))
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs.link(req, f, 1, OsStr::new("hard")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs.access(req, f, libc::W_OK as u32).await.unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        fs.access(req, f, libc::R_OK as u32).await.unwrap();
+        let err = fs
+            .setxattr(req, f, OsStr::new("user.test"), b"y", 0, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .removexattr(req, f, OsStr::new("user.test"))
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        let err = fs
+            .setattr(req, f, None, SetAttr::default())
+            .await
+            .unwrap_err();
+        assert_eq!(raw_os_error(err), erofs);
+        assert_eq!(std::fs::read(bottom.path().join("d/f")).unwrap(), b"bottom");
     }
-}
 
-/// Wrap the parameters for mounting overlay filesystem.
-#[derive(Debug, Clone)]
-pub struct OverlayArgs<P, Q, R, M, N, I>
-where
-    P: AsRef<Path>,
-    Q: AsRef<Path>,
-    R: AsRef<Path>,
-    M: AsRef<str>,
-    N: Into<String>,
-    I: IntoIterator<Item = R>,
-{
-    pub mountpoint: P,
-    pub upperdir: Q,
-    pub lowerdir: I,
-    pub privileged: bool,
-    pub mapping: Option<M>,
-    pub name: Option<N>,
-    pub allow_other: bool,
-}
+    #[tokio::test]
+    async fn test_squash_ownership() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(lower.path().join("f"), b"lower").unwrap();
+        std::fs::set_permissions(upper.path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        let config = Config {
+            do_import: true,
+            squash_to_uid: Some(1234),
+            squash_to_gid: Some(4321),
+            forced_mode: Some(0o750),
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        let attr = fs.lookup(req, 1, OsStr::new("f")).await.unwrap().attr;
+        assert_eq!((attr.uid, attr.gid, attr.perm), (1234, 4321, 0o750));
+        let attr = fs.getattr(req, attr.ino, None, 0).await.unwrap().attr;
+        assert_eq!((attr.uid, attr.gid, attr.perm), (1234, 4321, 0o750));
+
+        // access() goes by the squashed attributes too.
+        let (r, w, x) = (libc::R_OK as u32, libc::W_OK as u32, libc::X_OK as u32);
+        let caller = |uid, gid| Request {
+            uid,
+            gid,
+            ..Default::default()
+        };
+        fs.access(caller(1234, 1), attr.ino, r | w | x)
+            .await
+            .unwrap();
+        fs.access(caller(1, 4321), attr.ino, r | x).await.unwrap();
+        for (req, mask) in [(caller(1, 4321), w), (caller(1, 1), r)] {
+            let err = fs.access(req, attr.ino, mask).await.unwrap_err();
+            assert_eq!(raw_os_error(err), Some(libc::EACCES));
+        }
 
-/// Mounts the filesystem using the given parameters and returns the mount handle.
-///
-/// # Parameters
-/// - `mountpoint`: Path to the mount point.
-/// - `upperdir`: Path to the upper directory.
-/// - `lowerdir`: Paths to the lower directories.
-/// - `privileged`: If true, use privileged mount; otherwise, unprivileged mount.
-/// - `mapping`: Optional user/group ID mapping for unprivileged mounts.
-/// - `name`: Optional name for the filesystem.
-/// - `allow_other`: If true, allows other users to access the filesystem.
-///
-/// # Returns
-/// A mount handle on success.
-pub async fn mount_fs<P, Q, R, M, N, I>(
-    args: OverlayArgs<P, Q, R, M, N, I>,
-) -> rfuse3::raw::MountHandle
-where
-    P: AsRef<Path>,
-    Q: AsRef<Path>,
-    R: AsRef<Path>,
-    M: AsRef<str>,
-    N: Into<String>,
-    I: IntoIterator<Item = R>,
-{
-    // Create lower layers
-    let mut lower_layers = Vec::new();
-    for lower in args.lowerdir {
-        let layer = new_passthroughfs_layer(PassthroughArgs {
-            root_dir: lower,
-            mapping: args.mapping.as_ref().map(|m| m.as_ref()),
-        })
-        .await
-        .expect("Failed to create lower filesystem layer");
-        lower_layers.push(Arc::new(layer));
+        let attr = fs
+            .mkdir(req, 1, OsStr::new("d"), 0o700, 0o022)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!((attr.uid, attr.gid, attr.perm), (1234, 4321, 0o750));
+        let meta = std::fs::metadata(upper.path().join("d")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 4321));
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert!(meta.is_dir());
     }
-    // Create upper layer
-    let upper_layer = Arc::new(
-        new_passthroughfs_layer(PassthroughArgs {
-            root_dir: args.upperdir,
-            mapping: args.mapping.as_ref().map(|m| m.as_ref()),
+
+    #[tokio::test]
+    async fn test_push_layer_keeps_inodes() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let pushed = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("dir")).unwrap();
+        std::fs::write(lower.path().join("dir/a"), b"lower").unwrap();
+        std::fs::write(lower.path().join("dir/b"), b"b").unwrap();
+        std::fs::create_dir(upper.path().join("dir")).unwrap();
+        std::fs::write(upper.path().join("dir/c"), b"upper").unwrap();
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let mut fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        let dir = fs.lookup(req, 1, OsStr::new("dir")).await.unwrap().attr.ino;
+        let a = fs.lookup(req, dir, OsStr::new("a")).await.unwrap().attr.ino;
+        let c = fs.lookup(req, dir, OsStr::new("c")).await.unwrap().attr.ino;
+        let opened = fs.open(req, a, libc::O_RDONLY as u32).await.unwrap();
+
+        std::fs::create_dir(pushed.path().join("dir")).unwrap();
+        std::fs::write(pushed.path().join("dir/c"), b"top").unwrap();
+        std::fs::write(pushed.path().join("dir/n"), b"new").unwrap();
+        let hidden = pushed.path().join("dir/b");
+        std::fs::write(&hidden, b"").unwrap();
+        let path = std::ffi::CString::new(hidden.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(layer::WHITEOUT_XATTR).unwrap();
+        let ret =
+            unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+        assert_eq!(ret, 0);
+        let layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: pushed.path(),
+            mapping: None::<&str>,
+            io_engine: Default::default(),
         })
         .await
-        .expect("Failed to create upper filesystem layer"),
-    );
-
-    // Configure overlay filesystem
-    let config = Config {
-        mountpoint: args.mountpoint.as_ref().to_path_buf(),
-        do_import: true,
-        ..Default::default()
-    };
-    let overlayfs = OverlayFs::new(Some(upper_layer), lower_layers, config, 1)
-        .expect("Failed to initialize OverlayFs");
-    let logfs = LoggingFileSystem::new(overlayfs);
+        .unwrap();
+        fs.push_layer(Arc::new(layer)).await.unwrap();
 
-    let mount_path: OsString = OsString::from(args.mountpoint.as_ref().as_os_str());
+        assert_eq!(
+            fs.lookup(req, 1, OsStr::new("dir")).await.unwrap().attr.ino,
+            dir
+        );
+        assert_eq!(
+            fs.lookup(req, dir, OsStr::new("a")).await.unwrap().attr.ino,
+            a
+        );
+        let entry = fs.lookup(req, dir, OsStr::new("c")).await.unwrap();
+        assert_eq!((entry.attr.ino, entry.attr.size), (c, 3));
+        assert_eq!(
+            fs.lookup(req, dir, OsStr::new("n"))
+                .await
+                .unwrap()
+                .attr
+                .size,
+            3
+        );
+        let err = fs.lookup(req, dir, OsStr::new("b")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        let data = fs.read(req, a, opened.fh, 0, 16).await.unwrap();
+        assert_eq!(&data.data[..], b"lower");
 
-    // Obtain the current user's uid and gid
-    let uid = unsafe { libc::getuid() };
-    let gid = unsafe { libc::getgid() };
+        // Writes go to the pushed layer now.
+        fs.mkdir(req, dir, OsStr::new("d"), 0o755, 0o022)
+            .await
+            .unwrap();
+        assert!(pushed.path().join("dir/d").is_dir());
+        assert!(!upper.path().join("dir/d").exists());
+    }
 
-    let mut mount_options = MountOptions::default();
-    #[cfg(target_os = "linux")]
-    mount_options.force_readdir_plus(true);
+    #[tokio::test]
+    async fn test_emulated_devices() {
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("dev")).unwrap();
+        for name in ["null", "zero", "urandom", "full", "other"] {
+            std::fs::write(lower.path().join("dev").join(name), b"placeholder").unwrap();
+        }
+        let config = Config {
+            do_import: true,
+            emulated_devices: device::DevicePolicy::standard(),
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[lower.path()], upper.path(), config).await;
+        let req = Request::default();
+        let dev = fs.lookup(req, 1, OsStr::new("dev")).await.unwrap().attr.ino;
+        let open = async |name: &str, flags: i32| {
+            let ino = fs
+                .lookup(req, dev, OsStr::new(name))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let fh = fs.open(req, ino, flags as u32).await.unwrap().fh;
+            (ino, fh)
+        };
 
-    mount_options
-        .uid(uid)
-        .gid(gid)
-        .allow_other(args.allow_other);
-    if let Some(name) = args.name {
-        mount_options.fs_name(name);
+        let (ino, fh) = open("null", libc::O_RDWR).await;
+        assert!(fs.read(req, ino, fh, 0, 16).await.unwrap().data.is_empty());
+        let written = fs.write(req, ino, fh, 0, b"gone", 0, 0).await.unwrap();
+        assert_eq!(written.written, 4);
+        fs.flush(req, ino, fh, 0).await.unwrap();
+        fs.release(req, ino, fh, 0, 0, false).await.unwrap();
+
+        let (ino, fh) = open("zero", libc::O_RDONLY).await;
+        assert_eq!(
+            &fs.read(req, ino, fh, 0, 8).await.unwrap().data[..],
+            &[0; 8]
+        );
+        let (ino, fh) = open("urandom", libc::O_RDONLY).await;
+        assert_eq!(fs.read(req, ino, fh, 0, 64).await.unwrap().data.len(), 64);
+        let (ino, fh) = open("full", libc::O_WRONLY).await;
+        let err = fs.write(req, ino, fh, 0, b"x", 0, 0).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOSPC));
+
+        // Nothing was copied up, and paths outside the table are opened as usual.
+        assert!(!upper.path().join("dev").exists());
+        let (ino, fh) = open("other", libc::O_RDONLY).await;
+        assert_eq!(
+            &fs.read(req, ino, fh, 0, 16).await.unwrap().data[..],
+            b"placeholder"
+        );
     }
 
-    // Mount filesystem based on privilege flag and return the mount handle
-    if !args.privileged {
-        debug!("Mounting with unprivileged mode");
-        Session::new(mount_options)
-            .mount_with_unprivileged(logfs, mount_path)
+    #[tokio::test]
+    async fn test_pop_layer() {
+        let bottom = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::write(bottom.path().join("a"), b"bottom").unwrap();
+        std::fs::write(bottom.path().join("x"), b"bottom").unwrap();
+        std::fs::write(top.path().join("a"), b"top").unwrap();
+        let hidden = top.path().join("x");
+        std::fs::write(&hidden, b"").unwrap();
+        let path = std::ffi::CString::new(hidden.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(layer::WHITEOUT_XATTR).unwrap();
+        let ret =
+            unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+        assert_eq!(ret, 0);
+        std::fs::write(upper.path().join("u"), b"upper").unwrap();
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let mut fs = new_overlay_with(&[top.path(), bottom.path()], upper.path(), config).await;
+        let req = Request::default();
+
+        let a = fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr;
+        assert_eq!(a.size, 3);
+        fs.lookup(req, 1, OsStr::new("u")).await.unwrap();
+        let err = fs.lookup(req, 1, OsStr::new("x")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+
+        let removed = fs.remove_lower_layer(top.path()).await.unwrap();
+        assert_eq!(removed.root_dir(), top.path());
+        let entry = fs.lookup(req, 1, OsStr::new("a")).await.unwrap();
+        assert_eq!((entry.attr.ino, entry.attr.size), (a.ino, 6));
+        fs.lookup(req, 1, OsStr::new("x")).await.unwrap();
+
+        let popped = fs.pop_layer().await.unwrap();
+        assert_eq!(popped.root_dir(), upper.path());
+        let err = fs.lookup(req, 1, OsStr::new("u")).await.unwrap_err();
+        assert_eq!(raw_os_error(err), Some(libc::ENOENT));
+        assert_eq!(
+            fs.lookup(req, 1, OsStr::new("a")).await.unwrap().attr.ino,
+            a.ino
+        );
+        // The remaining layer is the upper one now and can't be removed.
+        fs.mkdir(req, 1, OsStr::new("d"), 0o755, 0o022)
             .await
-            .expect("Unprivileged mount failed")
-    } else {
-        debug!("Mounting with privileged mode");
-        Session::new(mount_options)
-            .mount(logfs, mount_path)
+            .unwrap();
+        assert!(bottom.path().join("d").is_dir());
+        let err = fs.pop_layer().await.err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[tokio::test]
+    async fn test_strict_dir_times() {
+        let top = tempfile::tempdir().unwrap();
+        let bottom = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        for (layer, secs) in [(&top, 1000), (&bottom, 2000)] {
+            let d = layer.path().join("d");
+            std::fs::create_dir(&d).unwrap();
+            std::fs::write(d.join(format!("f{secs}")), b"x").unwrap();
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            std::fs::File::open(&d)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+        let req = Request::default();
+
+        // By default the top-most layer decides.
+        let fs = new_overlay(&[top.path(), bottom.path()], upper.path()).await;
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap().attr.ino;
+        assert_eq!(
+            fs.getattr(req, d, None, 0).await.unwrap().attr.mtime.sec,
+            1000
+        );
+
+        let config = Config {
+            do_import: true,
+            strict_dir_times: true,
+            ..Default::default()
+        };
+        let fs = new_overlay_with(&[top.path(), bottom.path()], upper.path(), config).await;
+        let d = fs.lookup(req, 1, OsStr::new("d")).await.unwrap();
+        assert_eq!(d.attr.mtime.sec, 2000);
+        let d = d.attr.ino;
+
+        // Removing a lower entry copies the directory up, but it still counts as a change.
+        let start = Timestamp::from(std::time::SystemTime::now());
+        fs.unlink(req, d, OsStr::new("f2000")).await.unwrap();
+        let attr = fs.getattr(req, d, None, 0).await.unwrap().attr;
+        assert!(attr.mtime >= start);
+        assert_eq!(attr.mtime, attr.ctime);
+
+        let before = attr.mtime;
+        fs.mkdir(req, d, OsStr::new("sub"), 0o755, 0).await.unwrap();
+        let sub = fs.lookup(req, d, OsStr::new("sub")).await.unwrap().attr.ino;
+        let attr = fs.getattr(req, d, None, 0).await.unwrap().attr;
+        assert!(attr.mtime >= before);
+        let before = attr.mtime;
+        fs.rename(req, d, OsStr::new("f1000"), sub, OsStr::new("f"))
             .await
-            .expect("Privileged mount failed")
+            .unwrap();
+        assert!(fs.getattr(req, d, None, 0).await.unwrap().attr.mtime >= before);
+        assert!(fs.getattr(req, sub, None, 0).await.unwrap().attr.mtime >= before);
+
+        // An explicitly set mtime sticks.
+        let set_attr = SetAttr {
+            mtime: Some(Timestamp::new(3000, 0)),
+            ..Default::default()
+        };
+        fs.setattr(req, d, None, set_attr).await.unwrap();
+        assert_eq!(
+            fs.getattr(req, d, None, 0).await.unwrap().attr.mtime.sec,
+            3000
+        );
     }
 }
@@ -0,0 +1,209 @@
+//! Up-front validation of the directories handed to [`mount_fs`](super::mount_fs).
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Why [`mount_fs`](super::mount_fs) could not mount the overlay.
+#[derive(Debug)]
+pub enum MountError {
+    /// No lower directory was given.
+    NoLowerDir,
+    /// A directory can't be accessed, e.g. because it doesn't exist.
+    Inaccessible { path: PathBuf, source: io::Error },
+    /// The mountpoint or a layer is not a directory.
+    NotADirectory(PathBuf),
+    /// The same directory is used for more than one layer.
+    DuplicateLayer(PathBuf),
+    /// The upper directory lies inside a lower one or the other way round, so writes
+    /// through the overlay would change the lower layer underneath it.
+    NestedLayers { upper: PathBuf, lower: PathBuf },
+    /// Another overlay holds the lock of the upper directory. Sharing an upper layer between
+    /// live mounts corrupts it, see [`OverlayArgs::force`](super::OverlayArgs::force).
+    UpperInUse(PathBuf),
+    /// More lower layers than [`LayerLimit::max_lower`](super::LayerLimit::max_lower) and no
+    /// directory to squash the deepest ones into.
+    TooManyLayers { count: usize, max: usize },
+    /// Setting up a layer or the overlay on top of them failed.
+    Setup(io::Error),
+    /// The FUSE mount itself failed.
+    Mount(io::Error),
+    /// Confining the calling thread failed, e.g. because other threads weren't confined, see
+    /// [`OverlayArgs::sandbox`](super::OverlayArgs::sandbox). The overlay was unmounted again.
+    Sandbox(io::Error),
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::NoLowerDir => write!(f, "at least one lowerdir is required"),
+            MountError::Inaccessible { path, source } => {
+                write!(f, "cannot access {}: {source}", path.display())
+            }
+            MountError::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            MountError::DuplicateLayer(path) => {
+                write!(f, "{} is used for more than one layer", path.display())
+            }
+            MountError::NestedLayers { upper, lower } => write!(
+                f,
+                "upperdir {} and lowerdir {} are nested in each other",
+                upper.display(),
+                lower.display()
+            ),
+            MountError::UpperInUse(path) => write!(
+                f,
+                "upperdir {} is in use by another overlay mount",
+                path.display()
+            ),
+            MountError::TooManyLayers { count, max } => write!(
+                f,
+                "{count} lower layers exceed the limit of {max}, squash the deepest ones"
+            ),
+            MountError::Setup(e) => write!(f, "failed to set up overlay: {e}"),
+            MountError::Mount(e) => write!(f, "mount failed: {e}"),
+            MountError::Sandbox(e) => write!(f, "failed to sandbox the overlay: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MountError::Inaccessible { source, .. } => Some(source),
+            MountError::Setup(e) | MountError::Mount(e) | MountError::Sandbox(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Resolve `path` and make sure it is an existing directory.
+fn existing_dir(path: &Path) -> Result<PathBuf, MountError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|source| MountError::Inaccessible {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    if !canonical.is_dir() {
+        return Err(MountError::NotADirectory(path.to_path_buf()));
+    }
+    Ok(canonical)
+}
+
+/// Check the mountpoint and layers of an overlay before anything is set up.
+pub(crate) fn validate(
+    mountpoint: &Path,
+    upperdir: Option<&Path>,
+    lowerdirs: &[&Path],
+) -> Result<(), MountError> {
+    existing_dir(mountpoint)?;
+    if lowerdirs.is_empty() {
+        return Err(MountError::NoLowerDir);
+    }
+
+    let mut lowers: Vec<PathBuf> = Vec::with_capacity(lowerdirs.len());
+    for lower in lowerdirs {
+        let canonical = existing_dir(lower)?;
+        if lowers.contains(&canonical) {
+            return Err(MountError::DuplicateLayer(lower.to_path_buf()));
+        }
+        lowers.push(canonical);
+    }
+
+    if let Some(upperdir) = upperdir {
+        let upper = existing_dir(upperdir)?;
+        for (lower, canonical) in lowerdirs.iter().zip(&lowers) {
+            if upper == *canonical {
+                return Err(MountError::DuplicateLayer(upperdir.to_path_buf()));
+            }
+            if upper.starts_with(canonical) || canonical.starts_with(&upper) {
+                return Err(MountError::NestedLayers {
+                    upper: upperdir.to_path_buf(),
+                    lower: lower.to_path_buf(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take the advisory lock marking `upperdir` as in use by a mount, held until the returned
+/// file is closed.
+///
+/// The lock is a `flock` on the directory itself, so nothing shows up in the merged view,
+/// and the kernel drops it when the holding process dies.
+pub(crate) fn lock_upper(upperdir: &Path) -> Result<File, MountError> {
+    let inaccessible = |source| MountError::Inaccessible {
+        path: upperdir.to_path_buf(),
+        source,
+    };
+    let dir = File::open(upperdir).map_err(inaccessible)?;
+    // SAFETY: the descriptor is owned by `dir` and stays open across the call.
+    if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Err(MountError::UpperInUse(upperdir.to_path_buf()));
+        }
+        return Err(inaccessible(e));
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = |name: &str| {
+            let path = tmp.path().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            path
+        };
+        let (mnt, upper, l1, l2) = (dir("mnt"), dir("upper"), dir("l1"), dir("l2"));
+        let file = tmp.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(validate(&mnt, Some(&upper), &[&l1, &l2]).is_ok());
+        assert!(validate(&mnt, None, &[&l1]).is_ok());
+        assert!(matches!(
+            validate(&mnt, Some(&upper), &[]),
+            Err(MountError::NoLowerDir)
+        ));
+        assert!(matches!(
+            validate(&tmp.path().join("missing"), Some(&upper), &[&l1]),
+            Err(MountError::Inaccessible { .. })
+        ));
+        assert!(matches!(
+            validate(&mnt, Some(&file), &[&l1]),
+            Err(MountError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            validate(&mnt, Some(&upper), &[&l1, &tmp.path().join("l1/../l1")]),
+            Err(MountError::DuplicateLayer(_))
+        ));
+        assert!(matches!(
+            validate(&mnt, Some(&l1), &[&l1]),
+            Err(MountError::DuplicateLayer(_))
+        ));
+        assert!(matches!(
+            validate(&mnt, Some(&dir("l2/upper")), &[&l1, &l2]),
+            Err(MountError::NestedLayers { .. })
+        ));
+    }
+
+    #[test]
+    fn test_lock_upper() {
+        let upper = tempfile::tempdir().unwrap();
+        let lock = lock_upper(upper.path()).unwrap();
+        assert!(matches!(
+            lock_upper(upper.path()),
+            Err(MountError::UpperInUse(_))
+        ));
+        drop(lock);
+        assert!(lock_upper(upper.path()).is_ok());
+    }
+}
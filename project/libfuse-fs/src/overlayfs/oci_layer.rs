@@ -0,0 +1,465 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Unpacking OCI image layers into overlay lower directories.
+//!
+//! OCI layer tarballs describe deletions with marker entries: `.wh.<name>` removes `<name>`
+//! from the layers below, and `.wh..wh..opq` hides everything below its directory. The
+//! overlay expects those as whiteouts and opaque xattrs on disk instead, so [`apply_layer`]
+//! converts the markers while unpacking. Each layer goes to its own directory, and the
+//! directories can be passed to the overlay as lower layers, top-most first.
+//!
+//! [`export_upper_diff`] goes the other way and packs an upper layer into a layer tarball,
+//! hashing it on the fly so committing a container doesn't need a second pass over the data.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use flate2::read::GzDecoder;
+use sha2::Digest as _;
+
+use super::check::is_whiteout;
+use super::config::{Config, OpaqueXattr};
+use super::layer::WHITEOUT_XATTR;
+use super::lower_index::is_opaque;
+use crate::passthrough::util::{openat, remove_tree, stat_fd};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// On-disk form of the whiteouts and opaque directories written by [`apply_layer_with`].
+///
+/// The fields mean the same as in [`Config`], and should match the config of the overlay
+/// the layers are mounted with.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerFormat {
+    /// Write whiteouts as empty files marked with `user.overlay.whiteout` instead of 0/0
+    /// char devices, which needs no `CAP_MKNOD`.
+    pub xattr_whiteout: bool,
+    /// Xattr set on opaque directories.
+    pub opaque_xattr: OpaqueXattr,
+}
+
+impl From<&Config> for LayerFormat {
+    fn from(config: &Config) -> Self {
+        LayerFormat {
+            xattr_whiteout: config.xattr_whiteout,
+            opaque_xattr: config.opaque_xattr,
+        }
+    }
+}
+
+/// Unpack the OCI layer `tar_stream` into `target_dir` with the default [`LayerFormat`].
+///
+/// The stream is either a plain or a gzip-compressed tarball.
+pub fn apply_layer<R: Read>(tar_stream: R, target_dir: &Path) -> Result<()> {
+    apply_layer_with(tar_stream, target_dir, LayerFormat::default())
+}
+
+/// Unpack the OCI layer `tar_stream` into `target_dir`, writing whiteouts and opaque
+/// directories in `format`.
+///
+/// `target_dir` is created if missing. Permissions, mtimes and xattrs are kept, and so are
+/// ownerships when running as root. Entries that would land outside `target_dir` are
+/// skipped, and so are entries whose parent, or hard link target, goes through a symlink or
+/// a non-directory: an earlier entry of the layer may have planted a symlink to anywhere on
+/// the host.
+pub fn apply_layer_with<R: Read>(
+    tar_stream: R,
+    target_dir: &Path,
+    format: LayerFormat,
+) -> Result<()> {
+    fs::create_dir_all(target_dir)?;
+    let root = File::open(target_dir)?;
+    let mut reader = BufReader::new(tar_stream);
+    let stream: Box<dyn Read> = if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+
+    let mut archive = tar::Archive::new(stream);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+    // SAFETY: geteuid has no preconditions.
+    archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+
+    // Unpacking children and markers touches the mtime of their directory, so directory
+    // mtimes are restored once everything is in place.
+    let mut dir_mtimes = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Some(rel) = relative_path(&path) else {
+            continue;
+        };
+        let Some(name) = rel.file_name() else {
+            continue;
+        };
+        let Some(parent) = open_beneath(&root, rel.parent().unwrap_or(Path::new("")), true)? else {
+            continue;
+        };
+
+        if name == OPAQUE_MARKER {
+            set_xattr(&parent, &path, format.opaque_xattr.name(), b"y")?;
+            continue;
+        }
+        if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            if hidden.is_empty() || hidden == b"." || hidden == b".." {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid whiteout entry {}", path.display()),
+                ));
+            }
+            create_whiteout(
+                &parent,
+                &cstring(OsStr::from_bytes(hidden))?,
+                format.xattr_whiteout,
+            )
+            .map_err(|e| Error::new(e.kind(), format!("whiteout {}: {e}", path.display())))?;
+            continue;
+        }
+
+        let kind = entry.header().entry_type();
+        if kind.is_hard_link() {
+            let target = entry.link_name()?.and_then(|target| relative_path(&target));
+            let Some(target) = target else {
+                continue;
+            };
+            if open_beneath(&root, target.parent().unwrap_or(Path::new("")), false)?.is_none() {
+                continue;
+            }
+        }
+        let name = cstring(name)?;
+        if kind.is_dir() {
+            // tar keeps whatever directory it finds in place and sets its permissions, through
+            // a symlink too, so a symlink is replaced here as a later entry replaces it anyway.
+            match stat_fd(&parent, Some(&name)) {
+                Ok(st) if st.st_mode & libc::S_IFMT == libc::S_IFLNK => {
+                    remove_tree(&parent, &name)?
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mtime = entry.header().mtime().ok();
+        if entry.unpack_in(target_dir)? && kind.is_dir() {
+            dir_mtimes.push((rel, mtime));
+        }
+    }
+
+    for (dir, mtime) in dir_mtimes.into_iter().rev() {
+        if let Some(mtime) = mtime
+            && let Some(dir) = open_beneath(&root, &dir, false)?
+        {
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime);
+            dir.set_modified(mtime)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hash algorithm of a [`LayerDigest`].
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Name used in OCI digests.
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Digest of a layer tarball, displayed and parsed in the OCI `<algorithm>:<hex>` form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerDigest {
+    pub algorithm: DigestAlgorithm,
+    /// Lowercase hex encoding of the hash.
+    pub hex: String,
+}
+
+impl LayerDigest {
+    /// Hash all of `reader` with `algorithm`.
+    pub fn compute<R: Read>(mut reader: R, algorithm: DigestAlgorithm) -> Result<Self> {
+        let mut writer = HashingWriter::new(io::sink(), algorithm);
+        io::copy(&mut reader, &mut writer)?;
+        Ok(writer.finish().1)
+    }
+
+    /// Check that `reader` yields a tarball with this digest, failing with
+    /// [`ErrorKind::InvalidData`] otherwise.
+    pub fn verify<R: Read>(&self, reader: R) -> Result<()> {
+        let actual = Self::compute(reader, self.algorithm)?;
+        if actual != *self {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("digest mismatch: expected {self}, got {actual}"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LayerDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.hex)
+    }
+}
+
+impl FromStr for LayerDigest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid digest {s:?}"));
+        let (name, hex) = s.split_once(':').ok_or_else(invalid)?;
+        let (algorithm, len) = match name {
+            "sha256" => (DigestAlgorithm::Sha256, 64),
+            "blake3" => (DigestAlgorithm::Blake3, 64),
+            _ => return Err(invalid()),
+        };
+        if hex.len() != len || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(invalid());
+        }
+        Ok(LayerDigest {
+            algorithm,
+            hex: hex.to_string(),
+        })
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+// Passes writes through to `inner` and hashes what was written.
+struct HashingWriter<W> {
+    inner: W,
+    algorithm: DigestAlgorithm,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W, algorithm: DigestAlgorithm) -> Self {
+        HashingWriter {
+            inner,
+            algorithm,
+            hasher: algorithm.hasher(),
+        }
+    }
+
+    fn finish(self) -> (W, LayerDigest) {
+        let hash: Vec<u8> = match self.hasher {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        let hex = hash.iter().map(|b| format!("{b:02x}")).collect();
+        let digest = LayerDigest {
+            algorithm: self.algorithm,
+            hex,
+        };
+        (self.inner, digest)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        match &mut self.hasher {
+            Hasher::Sha256(h) => h.update(&buf[..n]),
+            Hasher::Blake3(h) => {
+                h.update(&buf[..n]);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Pack the upper layer at `upper_dir` into an uncompressed OCI layer tarball written to
+/// `out`, returning its digest (the layer's diff ID) in `algorithm`.
+///
+/// Whiteouts of either form become `.wh.<name>` entries and opaque directories get a
+/// `.wh..wh..opq` entry, so [`apply_layer`] restores the same layer. Entries are written in
+/// name order, which makes the digest depend only on the layer contents and metadata.
+pub fn export_upper_diff<W: Write>(
+    upper_dir: &Path,
+    out: W,
+    algorithm: DigestAlgorithm,
+) -> Result<LayerDigest> {
+    let mut builder = tar::Builder::new(HashingWriter::new(out, algorithm));
+    builder.follow_symlinks(false);
+    export_dir(&mut builder, upper_dir, Path::new(""))?;
+    let mut writer = builder.into_inner()?;
+    writer.flush()?;
+    Ok(writer.finish().1)
+}
+
+fn export_dir<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, rel: &Path) -> Result<()> {
+    if !rel.as_os_str().is_empty() && is_opaque(dir) {
+        append_marker(builder, &rel.join(OPAQUE_MARKER))?;
+    }
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let rel = rel.join(&name);
+        let md = fs::symlink_metadata(&path)?;
+        if is_whiteout(&path, &md) {
+            let mut marker = OsStr::new(WHITEOUT_PREFIX).to_os_string();
+            marker.push(&name);
+            append_marker(builder, &rel.with_file_name(marker))?;
+        } else if md.is_dir() {
+            builder.append_dir(&rel, &path)?;
+            export_dir(builder, &path, &rel)?;
+        } else {
+            builder.append_path_with_name(&path, &rel)?;
+        }
+    }
+    Ok(())
+}
+
+// Empty regular file entry at `path`, the form OCI deletion markers take.
+fn append_marker<W: Write>(builder: &mut tar::Builder<W>, path: &Path) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(0);
+    header.set_mtime(0);
+    builder.append_data(&mut header, path, io::empty())
+}
+
+// `path` of an archive entry relative to the target directory, None if it would escape it
+// or names the target directory itself.
+fn relative_path(path: &Path) -> Option<PathBuf> {
+    let mut rel = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::Prefix(..) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => return None,
+            Component::Normal(part) => rel.push(part),
+        }
+    }
+    (!rel.as_os_str().is_empty()).then_some(rel)
+}
+
+// Open the directory `rel` of `root` without following symlinks, None if a component of
+// it is a symlink or not a directory. Missing directories are created when `create` is set.
+fn open_beneath(root: &File, rel: &Path, create: bool) -> Result<Option<File>> {
+    const FLAGS: libc::c_int =
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let mut dir = root.try_clone()?;
+    for part in rel.components() {
+        let name = cstring(part.as_os_str())?;
+        let mut opened = openat(&dir, &name, FLAGS, 0);
+        if create && matches!(&opened, Err(e) if e.kind() == ErrorKind::NotFound) {
+            // SAFETY: the name is NUL-terminated and the return value is checked.
+            if unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755) } < 0 {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+            opened = openat(&dir, &name, FLAGS, 0);
+        }
+        dir = match opened {
+            Ok(next) => next,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ELOOP | libc::ENOTDIR)) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(Some(dir))
+}
+
+// Replace whatever is at `name` in `parent` with a whiteout.
+fn create_whiteout(parent: &File, name: &CStr, xattr: bool) -> Result<()> {
+    match remove_tree(parent, name) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    if xattr {
+        let flags =
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let file = openat(parent, name, flags, 0o644)?;
+        let path = Path::new(OsStr::from_bytes(name.to_bytes()));
+        if let Err(e) = set_xattr(&file, path, WHITEOUT_XATTR, b"y") {
+            // Don't leave a plain empty file behind, it would show up in the overlay.
+            // SAFETY: the name is NUL-terminated.
+            unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) };
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    // SAFETY: the name is NUL-terminated and the return value is checked.
+    let ret = unsafe {
+        libc::mknodat(
+            parent.as_raw_fd(),
+            name.as_ptr(),
+            libc::S_IFCHR,
+            libc::makedev(0, 0),
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Set xattr `name` of the open `file`, found at `path` for error messages.
+fn set_xattr(file: &File, path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let cname = cstring(OsStr::new(name))?;
+    // SAFETY: the name is NUL-terminated, and the value length is passed along.
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            cname.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        let e = Error::last_os_error();
+        return Err(Error::new(
+            e.kind(),
+            format!("failed to set {name} on {}: {e}", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn cstring(s: &OsStr) -> Result<CString> {
+    CString::new(s.as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
@@ -0,0 +1,221 @@
+//! Periodic smoke IO through a mounted overlay.
+//!
+//! A wedged FUSE daemon looks healthy from the outside, its process runs and its mount is
+//! listed, until some workload hangs on it. With [`Config::self_test_interval`] set, a
+//! thread of the daemon regularly creates, writes, reads back and unlinks a small file below
+//! [`SELF_TEST_DIR`] of the mountpoint, going through the kernel like any user would. Node
+//! health controllers read the outcome and latency from [`SelfTest::status`], and a probe
+//! that never returns shows up in [`SelfTestStatus::in_flight`].
+//!
+//! [`SELF_TEST_DIR`] lives in the upper layer and is left out of listings of the overlay
+//! root, it can still be looked up.
+//!
+//! [`Config::self_test_interval`]: super::config::Config::self_test_interval
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Result, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::warn;
+
+/// Directory of the overlay root the probes run in.
+pub const SELF_TEST_DIR: &str = ".overlay-selftest";
+
+const PROBE_DATA: &[u8] = b"overlay self-test\n";
+
+/// Outcome of one probe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestResult {
+    pub started_at: SystemTime,
+    pub latency: Duration,
+    /// What failed, None if the probe succeeded.
+    pub error: Option<String>,
+}
+
+impl SelfTestResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// State of the self-test, see [`SelfTest::status`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestStatus {
+    /// Last probe that completed.
+    pub last: Option<SelfTestResult>,
+    pub runs: u64,
+    pub failures: u64,
+    /// How long the running probe has been going, None between probes.
+    pub in_flight: Option<Duration>,
+}
+
+impl SelfTestStatus {
+    /// The last probe succeeded and the running one, if any, has not taken longer than
+    /// `timeout`.
+    pub fn is_healthy(&self, timeout: Duration) -> bool {
+        self.last.as_ref().is_some_and(SelfTestResult::is_ok)
+            && self.in_flight.is_none_or(|elapsed| elapsed <= timeout)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    last: Option<SelfTestResult>,
+    runs: u64,
+    failures: u64,
+    in_flight_since: Option<Instant>,
+}
+
+/// Handle of a running self-test, it stops once the overlay and every handle are dropped.
+#[derive(Clone)]
+pub struct SelfTest {
+    state: Arc<Mutex<State>>,
+}
+
+impl SelfTest {
+    /// Probe the overlay mounted at `mountpoint` every `interval`.
+    pub(crate) fn start(mountpoint: PathBuf, interval: Duration, fsid: u64) -> Result<Self> {
+        Self::spawn(mountpoint, interval, fsid, true)
+    }
+
+    // With `fuse_only`, probes fail without touching `root` while it is not a FUSE mount,
+    // so the directory below an overlay not mounted yet is left alone.
+    fn spawn(root: PathBuf, interval: Duration, fsid: u64, fuse_only: bool) -> Result<Self> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let weak = Arc::downgrade(&state);
+        std::thread::Builder::new()
+            .name("overlay-selftest".into())
+            .spawn(move || run(weak, &root, interval, fsid, fuse_only))?;
+        Ok(Self { state })
+    }
+
+    pub fn status(&self) -> SelfTestStatus {
+        let state = self.state.lock().unwrap();
+        SelfTestStatus {
+            last: state.last.clone(),
+            runs: state.runs,
+            failures: state.failures,
+            in_flight: state.in_flight_since.map(|since| since.elapsed()),
+        }
+    }
+}
+
+fn run(state: Weak<Mutex<State>>, root: &Path, interval: Duration, fsid: u64, fuse_only: bool) {
+    let file = root.join(SELF_TEST_DIR).join(format!("probe-{fsid:016x}"));
+    loop {
+        let Some(shared) = state.upgrade() else {
+            return;
+        };
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        shared.lock().unwrap().in_flight_since = Some(start);
+        // Not holding the state while the probe runs, a hung probe must not hang readers.
+        drop(shared);
+
+        let outcome = probe(root, &file, fuse_only);
+
+        let Some(shared) = state.upgrade() else {
+            return;
+        };
+        let mut s = shared.lock().unwrap();
+        s.in_flight_since = None;
+        s.runs += 1;
+        if let Err(e) = &outcome {
+            s.failures += 1;
+            warn!("overlay self-test in {} failed: {e}", root.display());
+        }
+        s.last = Some(SelfTestResult {
+            started_at,
+            latency: start.elapsed(),
+            error: outcome.err().map(|e| e.to_string()),
+        });
+        drop(s);
+        drop(shared);
+        std::thread::sleep(interval);
+    }
+}
+
+fn probe(root: &Path, file: &Path, fuse_only: bool) -> Result<()> {
+    if fuse_only && !is_fuse(root)? {
+        return Err(std::io::Error::new(ErrorKind::NotConnected, "not mounted"));
+    }
+    match fs::create_dir(file.parent().unwrap()) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file)?;
+    f.write_all(PROBE_DATA)?;
+    f.sync_data()?;
+    f.rewind()?;
+    let mut read = Vec::with_capacity(PROBE_DATA.len());
+    f.read_to_end(&mut read)?;
+    drop(f);
+    fs::remove_file(file)?;
+    if read != PROBE_DATA {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "read back different data",
+        ));
+    }
+    Ok(())
+}
+
+fn is_fuse(path: &Path) -> Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` is a writable statfs.
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_type == libc::FUSE_SUPER_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test_probes() {
+        let root = tempfile::tempdir().unwrap();
+        let test = SelfTest::spawn(
+            root.path().to_path_buf(),
+            Duration::from_millis(10),
+            7,
+            false,
+        )
+        .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while test.status().runs < 2 {
+            assert!(Instant::now() < deadline, "no probe completed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let status = test.status();
+        assert_eq!(status.failures, 0);
+        assert!(status.is_healthy(Duration::from_secs(10)));
+        // Probe files don't pile up.
+        let dir = root.path().join(SELF_TEST_DIR);
+        let left = fs::read_dir(&dir).unwrap().count();
+        assert!(left <= 1);
+
+        // A plain directory is not a mounted overlay.
+        let unmounted =
+            SelfTest::start(root.path().to_path_buf(), Duration::from_secs(60), 8).unwrap();
+        while unmounted.status().runs < 1 {
+            assert!(Instant::now() < deadline, "no probe completed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let status = unmounted.status();
+        assert_eq!(status.failures, 1);
+        assert!(!status.is_healthy(Duration::from_secs(10)));
+        assert!(!dir.join(format!("probe-{:016x}", 8)).exists());
+    }
+}
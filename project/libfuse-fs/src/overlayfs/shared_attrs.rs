@@ -0,0 +1,135 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Attributes and symlink targets of lower layers, shared by all overlays of a process.
+//!
+//! Containers started from the same image mount the same lower directories, and every
+//! overlay used to stat the same files again. Overlays given the same [`SharedAttrCache`]
+//! through [`Config::shared_attr_cache`](super::config::Config::shared_attr_cache) serve
+//! those from one read-through store instead. Entries are keyed by the layer and the host
+//! inode, and hold the host attributes before any id mapping, so overlays with different
+//! mappings can share them.
+//!
+//! Lower layers are expected not to change while mounted. When one is updated anyway,
+//! [`SharedAttrCache::invalidate_layer`] drops what was cached for it.
+
+use std::fmt;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use moka::future::Cache;
+
+use crate::passthrough::util::stat64;
+
+/// Default number of cached attributes and symlink targets, each.
+pub const DEFAULT_CAPACITY: u64 = 64 * 1024;
+/// Default time entries are served before being read from the layer again.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// Identity of a layer directory, `(st_dev, st_ino)` of its root.
+pub(crate) type LayerId = (u64, u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    layer: LayerId,
+    dev: u64,
+    ino: u64,
+}
+
+/// Process-wide store of lower layer attributes, see the [module docs](self).
+pub struct SharedAttrCache {
+    attrs: Cache<Key, stat64>,
+    links: Cache<Key, Bytes>,
+}
+
+impl SharedAttrCache {
+    pub fn new(capacity: u64, ttl: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            attrs: build_cache(capacity, ttl),
+            links: build_cache(capacity, ttl),
+        })
+    }
+
+    /// Drop everything cached for the layer rooted at `root`, to be called after changing it.
+    pub fn invalidate_layer(&self, root: &Path) -> io::Result<()> {
+        let layer = layer_id(root)?;
+        self.attrs
+            .invalidate_entries_if(move |key: &Key, _| key.layer == layer)
+            .map_err(io::Error::other)?;
+        self.links
+            .invalidate_entries_if(move |key: &Key, _| key.layer == layer)
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn invalidate_all(&self) {
+        self.attrs.invalidate_all();
+        self.links.invalidate_all();
+    }
+}
+
+impl fmt::Debug for SharedAttrCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedAttrCache")
+            .field("attrs", &self.attrs.entry_count())
+            .field("links", &self.links.entry_count())
+            .finish()
+    }
+}
+
+/// [`SharedAttrCache`] as seen by one lower layer.
+#[derive(Clone)]
+pub(crate) struct LayerAttrs {
+    cache: Arc<SharedAttrCache>,
+    layer: LayerId,
+}
+
+impl LayerAttrs {
+    pub(crate) fn new(cache: Arc<SharedAttrCache>, root: &Path) -> io::Result<Self> {
+        Ok(Self {
+            cache,
+            layer: layer_id(root)?,
+        })
+    }
+
+    fn key(&self, dev: u64, ino: u64) -> Key {
+        Key {
+            layer: self.layer,
+            dev,
+            ino,
+        }
+    }
+
+    pub(crate) async fn stat(&self, dev: u64, ino: u64) -> Option<stat64> {
+        self.cache.attrs.get(&self.key(dev, ino)).await
+    }
+
+    pub(crate) async fn insert_stat(&self, dev: u64, ino: u64, st: stat64) {
+        self.cache.attrs.insert(self.key(dev, ino), st).await;
+    }
+
+    pub(crate) async fn readlink(&self, dev: u64, ino: u64) -> Option<Bytes> {
+        self.cache.links.get(&self.key(dev, ino)).await
+    }
+
+    pub(crate) async fn insert_readlink(&self, dev: u64, ino: u64, target: Bytes) {
+        self.cache.links.insert(self.key(dev, ino), target).await;
+    }
+}
+
+fn build_cache<V: Clone + Send + Sync + 'static>(capacity: u64, ttl: Duration) -> Cache<Key, V> {
+    Cache::builder()
+        .max_capacity(capacity)
+        .time_to_live(ttl)
+        .support_invalidation_closures()
+        .build()
+}
+
+fn layer_id(root: &Path) -> io::Result<LayerId> {
+    let meta = std::fs::metadata(root)?;
+    Ok((meta.dev(), meta.ino()))
+}
@@ -0,0 +1,220 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Cooperative shutdown of a mounted overlay.
+//!
+//! Killing the daemon in the middle of a request leaves half-written copy-ups and unsynced
+//! handles behind. [`ShutdownHandle::shutdown`] instead makes the overlay refuse new
+//! requests, waits a bounded time for the running ones, then syncs and closes the handles
+//! still open and sends the layer forgets still queued. The caller unmounts afterwards, or
+//! lets [`ShutdownHandle::unmount`] do both, and can tell from the [`ShutdownReport`]
+//! whether anything was abandoned.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rfuse3::raw::{Filesystem, MountHandle, Request};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Instant, timeout_at};
+use tracing::warn;
+
+use super::HandleData;
+use super::WriteAmplification;
+use super::copy_up::{CopyUpProgress, CopyUpTracker};
+use super::forget;
+use crate::passthrough::PassthroughFs;
+
+/// Time cancelled copy-ups get to clean up after the shutdown timeout, they check for
+/// cancellation between chunks.
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+/// Count of the requests being served, and whether new ones are still accepted.
+#[derive(Default)]
+pub(crate) struct Drain {
+    closing: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    // Requests that panicked, see `ShutdownHandle::panicked`.
+    panics: AtomicUsize,
+    panicked: Notify,
+}
+
+impl Drain {
+    /// Register a request for as long as the guard lives, `ENOTCONN` once shutting down.
+    pub fn enter(&self) -> Result<OpGuard<'_>> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.closing.load(Ordering::SeqCst) {
+            self.exit();
+            return Err(Error::from_raw_os_error(libc::ENOTCONN));
+        }
+        Ok(OpGuard(self))
+    }
+
+    fn exit(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Wait until no request is running, false if some still are at `deadline`.
+    async fn wait_idle(&self, deadline: Instant) -> bool {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.in_flight() == 0 {
+                return true;
+            }
+            if timeout_at(deadline, idle).await.is_err() {
+                return self.in_flight() == 0;
+            }
+        }
+    }
+}
+
+pub(crate) struct OpGuard<'a>(&'a Drain);
+
+impl Drop for OpGuard<'_> {
+    fn drop(&mut self) {
+        // The request unwinds, its reply is never sent.
+        if std::thread::panicking() {
+            self.0.panics.fetch_add(1, Ordering::SeqCst);
+            self.0.panicked.notify_one();
+        }
+        self.0.exit();
+    }
+}
+
+/// What [`ShutdownHandle::shutdown`] could not finish cleanly.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Requests still running when the shutdown gave up waiting.
+    pub abandoned_requests: usize,
+    /// Copy-ups cancelled because they didn't finish in time, their files stay in the lower
+    /// layers.
+    pub cancelled_copy_ups: Vec<CopyUpProgress>,
+    /// Handles closed on behalf of the kernel.
+    pub closed_handles: usize,
+    /// Handles whose data could not be synced or which failed to close.
+    pub failed_handles: usize,
+    /// Layer inodes whose queued forgets were sent.
+    pub flushed_forgets: usize,
+}
+
+impl ShutdownReport {
+    /// Whether nothing was abandoned or lost.
+    pub fn is_clean(&self) -> bool {
+        self.abandoned_requests == 0
+            && self.cancelled_copy_ups.is_empty()
+            && self.failed_handles == 0
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests abandoned, {} copy-ups cancelled, {} handles closed ({} failed)",
+            self.abandoned_requests,
+            self.cancelled_copy_ups.len(),
+            self.closed_handles,
+            self.failed_handles
+        )
+    }
+}
+
+/// Shuts down the overlay it was taken from, see [`super::OverlayFs::shutdown_handle`].
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    pub(super) drain: Arc<Drain>,
+    pub(super) copy_ups: CopyUpTracker,
+    pub(super) handles: Arc<Mutex<HashMap<u64, Arc<HandleData>>>>,
+    pub(super) layers: Vec<Arc<PassthroughFs>>,
+    // Whether `layers` starts with the upper layer.
+    #[cfg(feature = "prometheus")]
+    pub(super) has_upper: bool,
+}
+
+impl ShutdownHandle {
+    /// Stop accepting requests and wait up to `timeout` for the running ones. Copy-ups still
+    /// running then are cancelled, all open handles are synced and closed, and the forgets
+    /// queued for the layers are sent.
+    ///
+    /// New requests fail with `ENOTCONN` from the first call on, the overlay should be
+    /// unmounted once this returns.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.drain.closing.store(true, Ordering::SeqCst);
+        let mut report = ShutdownReport::default();
+
+        if !self.drain.wait_idle(Instant::now() + timeout).await {
+            report.cancelled_copy_ups = self.copy_ups.all();
+            for copy_up in &report.cancelled_copy_ups {
+                self.copy_ups.cancel(copy_up.inode);
+            }
+            self.drain.wait_idle(Instant::now() + CANCEL_GRACE).await;
+            report.abandoned_requests = self.drain.in_flight();
+        }
+
+        let ctx = Request::default();
+        let handles = std::mem::take(&mut *self.handles.lock().await);
+        for (fh, hd) in handles {
+            let Some(rh) = &hd.real_handle else {
+                continue;
+            };
+            let handle = rh.handle.load(Ordering::Relaxed);
+            let synced = if rh.in_upper_layer {
+                rh.layer.fsync(ctx, rh.inode, handle, false).await
+            } else {
+                Ok(())
+            };
+            let released = rh.layer.release(ctx, rh.inode, handle, 0, 0, true).await;
+            report.closed_handles += 1;
+            if let Err(e) = synced.and(released) {
+                let e: Error = e.into();
+                // Directory handles can't be fsynced through the file path.
+                if e.raw_os_error() != Some(libc::ENOSYS) {
+                    warn!("shutdown: failed to close handle {fh}: {e}");
+                    report.failed_handles += 1;
+                }
+            }
+        }
+        // Closing handles drops inodes, whose forgets are queued.
+        for layer in &self.layers {
+            report.flushed_forgets += forget::flush(layer).await;
+        }
+        report
+    }
+
+    /// [`super::OverlayFs::write_amplification`] of the overlay.
+    pub fn write_amplification(&self) -> WriteAmplification {
+        self.copy_ups.amplification()
+    }
+
+    /// Requests of the overlay that panicked so far.
+    pub fn panics(&self) -> usize {
+        self.drain.panics.load(Ordering::SeqCst)
+    }
+
+    /// Wait until a request panics, returns at once if one did since the last call.
+    pub(crate) async fn panicked(&self) {
+        self.drain.panicked.notified().await;
+    }
+
+    /// [`Self::shutdown`], then unmount `mount`, the session of the overlay.
+    pub async fn unmount(&self, mount: MountHandle, timeout: Duration) -> Result<ShutdownReport> {
+        let report = self.shutdown(timeout).await;
+        if !report.is_clean() {
+            warn!("unmounting after an unclean shutdown: {report}");
+        }
+        mount.unmount().await?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Limit on the number of lower layers merged by a mount.
+//!
+//! Every lookup of an entry missing from the upper layers walks the whole stack, so deep
+//! images get slower and use more descriptors with each layer, without a clear point where
+//! they stop working. [`LayerLimit`] makes that point explicit: a mount with more lower
+//! layers than allowed fails with [`MountError::TooManyLayers`], or, given a squash
+//! directory, gets the deepest layers flattened into a single synthesized one first.
+//!
+//! Squashing merges the deepest layers in-process, through an overlay of them whose merged
+//! view is copied up into the new layer, so whiteouts and opaque directories are resolved
+//! exactly like when mounting. Device nodes, fifos and sockets are not carried over, like
+//! with copy-ups of directories. The result is kept, keyed by the squashed directories, and
+//! reused by later mounts of the same image.
+
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rfuse3::raw::Request;
+use tracing::info;
+
+use super::config::Config;
+use super::{MountError, OverlayFs};
+use crate::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+
+/// Default of [`LayerLimit::max_lower`], the stacking limit of kernel overlayfs.
+pub const DEFAULT_MAX_LOWER_LAYERS: usize = 500;
+
+/// How many lower layers a mount may merge, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerLimit {
+    /// Most lower layers merged, at least 1.
+    pub max_lower: usize,
+    /// Directory to keep squashed layers in. Deeper stacks are then squashed instead of
+    /// refused.
+    pub squash_dir: Option<PathBuf>,
+}
+
+impl Default for LayerLimit {
+    fn default() -> Self {
+        Self {
+            max_lower: DEFAULT_MAX_LOWER_LAYERS,
+            squash_dir: None,
+        }
+    }
+}
+
+impl LayerLimit {
+    /// Check `lowerdirs`, top-most first, against the limit. Returns them unchanged when
+    /// within it, otherwise with the deepest ones replaced by their squashed layer.
+    pub async fn apply(
+        &self,
+        lowerdirs: Vec<PathBuf>,
+        mapping: Option<&str>,
+    ) -> std::result::Result<Vec<PathBuf>, MountError> {
+        let max = self.max_lower.max(1);
+        if lowerdirs.len() <= max {
+            return Ok(lowerdirs);
+        }
+        let Some(squash_dir) = &self.squash_dir else {
+            return Err(MountError::TooManyLayers {
+                count: lowerdirs.len(),
+                max,
+            });
+        };
+        let mut lowerdirs = lowerdirs;
+        let deepest = lowerdirs.split_off(max - 1);
+        let squashed = squash(&deepest, squash_dir, mapping)
+            .await
+            .map_err(MountError::Setup)?;
+        lowerdirs.push(squashed);
+        Ok(lowerdirs)
+    }
+}
+
+/// Flatten `lowerdirs`, top-most first, into a layer under `squash_dir`, reusing the one
+/// squashed from the same directories before. Returns its path.
+pub async fn squash(
+    lowerdirs: &[PathBuf],
+    squash_dir: &Path,
+    mapping: Option<&str>,
+) -> Result<PathBuf> {
+    let mut hasher = blake3::Hasher::new();
+    for lower in lowerdirs {
+        let lower = lower.canonicalize()?;
+        hasher.update(lower.as_os_str().as_encoded_bytes());
+        hasher.update(b"\0");
+    }
+    let name = hasher.finalize().to_hex();
+    let target = squash_dir.join(name.as_str());
+    if target.is_dir() {
+        return Ok(target);
+    }
+
+    // Built next to its final place and renamed, so an interrupted squash is never reused.
+    let partial = squash_dir.join(format!("{name}.partial"));
+    match std::fs::remove_dir_all(&partial) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    std::fs::create_dir_all(&partial)?;
+    info!(
+        "squashing {} lower layers into {}",
+        lowerdirs.len(),
+        target.display()
+    );
+    copy_merged(lowerdirs, &partial, mapping).await?;
+    std::fs::rename(&partial, &target)?;
+    Ok(target)
+}
+
+// Copy the merged view of `lowerdirs` into the empty directory `target`.
+async fn copy_merged(lowerdirs: &[PathBuf], target: &Path, mapping: Option<&str>) -> Result<()> {
+    let mut lower_layers = Vec::with_capacity(lowerdirs.len());
+    for lower in lowerdirs {
+        let layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: lower,
+            mapping,
+            io_engine: Default::default(),
+        })
+        .await?;
+        lower_layers.push(Arc::new(layer));
+    }
+    let upper_layer = new_passthroughfs_layer(PassthroughArgs {
+        root_dir: target,
+        mapping,
+        io_engine: Default::default(),
+    })
+    .await?;
+    let config = Config {
+        do_import: true,
+        ..Default::default()
+    };
+    let fs = OverlayFs::new(Some(Arc::new(upper_layer)), lower_layers, config, 1)?;
+    fs.import().await?;
+    let root = fs.root_node().await;
+    fs.copy_directory_up(Request::default(), root)
+        .await
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("squashing into {}: {e}", target.display()),
+            )
+        })?;
+    Ok(())
+}
@@ -2,7 +2,7 @@ use crate::util::open_options::OpenOptions;
 use bytes::Bytes;
 use futures::stream;
 use libc::{off_t, pread, size_t};
-use rfuse3::{Errno, Inode, Result, raw::prelude::*};
+use rfuse3::{Errno, Inode, Result, Timestamp, raw::prelude::*};
 use std::{
     ffi::{CStr, CString, OsStr, OsString},
     fs::File,
@@ -10,7 +10,7 @@ use std::{
     mem::MaybeUninit,
     num::NonZeroU32,
     os::{
-        fd::{AsRawFd, RawFd},
+        fd::{AsFd, AsRawFd, RawFd},
         raw::c_int,
         unix::ffi::OsStringExt,
     },
@@ -23,7 +23,7 @@ use vm_memory::{ByteValued, bitmap::BitmapSlice};
 
 use crate::{
     passthrough::{CURRENT_DIR_CSTR, EMPTY_CSTR, FileUniqueKey, PARENT_DIR_CSTR, statx::statx},
-    util::{convert_stat64_to_file_attr, filetype_from_mode},
+    util::{convert_stat64_to_file_attr, filetype_from_mode, interrupt},
 };
 
 use super::ebadf;
@@ -31,7 +31,9 @@ use super::util::{
     self, AT_EMPTY_PATH, SLASH_ASCII, einval, enosys, is_safe_inode, osstr_to_cstr, set_creds,
     stat_fd, stat64,
 };
-use super::{Handle, HandleData, PassthroughFs, config::CachePolicy, os_compat::LinuxDirent64};
+use super::{
+    Handle, HandleData, InodeData, PassthroughFs, config::CachePolicy, os_compat::LinuxDirent64,
+};
 #[cfg(target_os = "macos")]
 pub const O_DIRECT: libc::c_int = 0;
 #[cfg(target_os = "linux")]
@@ -52,6 +54,29 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
     }
 
+    /// The data of the open handle `handle` of `inode`, reopening its file if it was closed to
+    /// stay under `Config::max_open_files`.
+    pub(super) async fn get_handle(
+        &self,
+        handle: Handle,
+        inode: Inode,
+    ) -> io::Result<Arc<HandleData>> {
+        let err = match self.handle_map.get(handle, inode).await {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        let Some(closed) = self.handle_map.closed(handle, inode) else {
+            return Err(err);
+        };
+
+        // The file exists by now, it must not be created or truncated again.
+        let flags = closed.flags & !((libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC) as u32);
+        let file = self.open_inode(inode, flags as i32).await?;
+        let data = HandleData::new(inode, file, closed.flags);
+        data.dirty.store(closed.dirty, Ordering::Relaxed);
+        self.handle_map.reopened(handle, data).await
+    }
+
     /// Check the HandleData flags against the flags from the current request
     /// if these do not match update the file descriptor flags and store the new
     /// result in the HandleData entry
@@ -393,6 +418,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                 debug!("readdir:{}", name.to_str().unwrap());
                 let _entry = self.do_lookup(inode, &name).await?;
                 entry.inode = _entry.attr.ino;
+                let (_, attr_ttl) = self.timeouts(_entry.attr.kind == FileType::Directory);
 
                 entry_list.push(Ok(DirectoryEntryPlus {
                     inode: entry.inode,
@@ -402,7 +428,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     offset: entry.offset,
                     attr: _entry.attr,
                     entry_ttl: _entry.ttl,
-                    attr_ttl: _entry.ttl,
+                    attr_ttl,
                 }));
                 // add the offset.
                 offset += dirent64.d_reclen as usize;
@@ -470,12 +496,12 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let st = if !self.no_open.load(Ordering::Relaxed)
             && let Some(handle_id) = handle
         {
-            let hd = self.handle_map.get(handle_id, inode).await?;
+            let hd = self.get_handle(handle_id, inode).await?;
             // trace!("FS {} passthrough: do_getattr: before stat_fd", self.uuid);
             util::stat_fd(hd.get_file(), None)
         } else {
             // trace!("FS {} passthrough: do_getattr: before stat", self.uuid);
-            data.handle.stat()
+            self.shared_stat(&data, || data.handle.stat()).await
         };
         // trace!("FS {} passthrough: do_getattr: after stat", self.uuid);
 
@@ -493,7 +519,8 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             st.st_uid = self.cfg.mapping.find_mapping(st.st_uid, true, true);
             st.st_gid = self.cfg.mapping.find_mapping(st.st_gid, true, false);
         }
-        Ok((st, self.cfg.attr_timeout))
+        let (_, attr_timeout) = self.timeouts(util::is_dir(st.st_mode.into()));
+        Ok((st, attr_timeout))
     }
 
     /// Public `getattr` wrapper for FUSE clients.
@@ -504,13 +531,19 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     async fn do_getattr(&self, inode: Inode, fh: Option<u64>) -> io::Result<(stat64, Duration)> {
         let inode_data = self.inode_map.get(inode).await?;
         if let Some(handle) = fh {
-            let hd = self.handle_map.get(handle, inode).await?;
+            let hd = self.get_handle(handle, inode).await?;
             let file = hd.get_file();
-            return util::stat_fd(file, None).map(|st| (st, self.cfg.attr_timeout));
+            return util::stat_fd(file, None).map(|st| self.with_attr_timeout(st));
         }
 
-        let file = inode_data.get_file()?;
-        util::stat_fd(&file, None).map(|st| (st, self.cfg.attr_timeout))
+        self.shared_stat(&inode_data, || util::stat_fd(&inode_data.get_file()?, None))
+            .await
+            .map(|st| self.with_attr_timeout(st))
+    }
+
+    fn with_attr_timeout(&self, st: stat64) -> (stat64, Duration) {
+        let (_, attr_timeout) = self.timeouts(util::is_dir(st.st_mode.into()));
+        (st, attr_timeout)
     }
 
     /// Internal `getattr` helper that skips ID mapping.
@@ -556,7 +589,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     ) -> io::Result<Arc<HandleData>> {
         let no_open = self.no_opendir.load(Ordering::Relaxed);
         if !no_open {
-            self.handle_map.get(handle, inode).await
+            self.get_handle(handle, inode).await
         } else {
             let file = self.open_inode(inode, flags | libc::O_DIRECTORY).await?;
             Ok(Arc::new(HandleData::new(inode, file, flags as u32)))
@@ -571,7 +604,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     ) -> io::Result<Arc<HandleData>> {
         let no_open = self.no_open.load(Ordering::Relaxed);
         if !no_open {
-            self.handle_map.get(handle, inode).await
+            self.get_handle(handle, inode).await
         } else {
             let file = self.open_inode(inode, flags).await?;
             Ok(Arc::new(HandleData::new(inode, file, flags as u32)))
@@ -675,6 +708,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             generation: entry.generation,
             fh: ret_handle,
             flags: opts.bits(),
+            backing_id: None,
         })
     }
 
@@ -814,6 +848,224 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         self.do_symlink_inner(req, parent, name, link, Some(uid), Some(gid))
             .await
     }
+
+    /// Core implementation for `mknod`.
+    ///
+    /// It uses the provided `uid` and `gid` for credential switching if they are `Some`;
+    /// otherwise, it falls back to the credentials from the `Request`.
+    #[allow(clippy::too_many_arguments)]
+    async fn do_mknod_inner(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<ReplyEntry> {
+        let name = osstr_to_cstr(name).unwrap();
+        let name = name.as_ref();
+        self.validate_path_component(name)?;
+
+        let data = self.inode_map.get(parent).await?;
+        let file = data.get_file()?;
+
+        let res = {
+            let _guard = set_creds(
+                uid.unwrap_or(self.cfg.mapping.get_uid(req.uid)),
+                gid.unwrap_or(self.cfg.mapping.get_gid(req.gid)),
+            )?;
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe {
+                libc::mknodat(
+                    file.as_raw_fd(),
+                    name.as_ptr(),
+                    (mode) as libc::mode_t,
+                    rdev as libc::dev_t,
+                )
+            }
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            self.do_lookup(parent, name).await
+        }
+    }
+
+    /// A wrapper for `mknod`, used by [`copy_special_up`][crate::overlayfs::OverlayFs::copy_special_up] function.
+    ///
+    /// This helper is called during a copy-up operation to create a FIFO, socket or device
+    /// node in the upper layer while preserving the original host UID/GID from the lower layer.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn do_mknod_helper(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<ReplyEntry> {
+        self.do_mknod_inner(req, parent, name, mode, rdev, Some(uid), Some(gid))
+            .await
+    }
+
+    /// A wrapper for `setxattr` that reports failures, used by `overlayfs` to copy xattrs up.
+    ///
+    /// The `setxattr` handler fakes success so that clients which insist on setting xattrs keep
+    /// working, but copy-up needs the real error to apply its per-namespace policy.
+    pub async fn do_setxattr_helper(
+        &self,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        if !self.cfg.xattr {
+            return Err(enosys());
+        }
+        let name = osstr_to_cstr(name)?;
+        let name = name.as_ref();
+        let data = self.inode_map.get(inode).await?;
+        let file = data.get_file()?;
+        #[cfg(target_os = "linux")]
+        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
+        // need to use the {set,get,remove,list}xattr variants.
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = match () {
+            #[cfg(target_os = "linux")]
+            () => unsafe {
+                libc::setxattr(
+                    pathname.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    flags as libc::c_int,
+                )
+            },
+            #[cfg(target_os = "macos")]
+            () => unsafe {
+                libc::fsetxattr(
+                    file.as_raw_fd(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                    flags as libc::c_int,
+                )
+            },
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Open a new file description on `data` for holding POSIX locks.
+    ///
+    /// Locks are taken as open file description (OFD) locks, so each lock owner needs its own
+    /// description: owners then conflict with each other like separate processes on the host do.
+    #[cfg(target_os = "linux")]
+    fn open_lock_file(&self, data: &InodeData) -> io::Result<File> {
+        if !is_safe_inode(data.mode) {
+            return Err(ebadf());
+        }
+        match data.open_file(libc::O_RDWR | libc::O_CLOEXEC, &self.proc_self_fd) {
+            // Read locks still work on descriptions that can't be opened for writing.
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EACCES | libc::EROFS | libc::EISDIR | libc::ETXTBSY)
+                ) =>
+            {
+                data.open_file(libc::O_RDONLY | libc::O_CLOEXEC, &self.proc_self_fd)
+            }
+            res => res,
+        }
+    }
+
+    /// Remove the directory `name` of `parent` along with the whiteouts in it, used by
+    /// `overlayfs` to drop the upper copy of a merged directory emptied through the mount.
+    ///
+    /// The whiteouts are removed from one blocking task with the credentials of `req` rather
+    /// than a request each. Zero-length files are whiteouts when marked with `whiteout_xattr`.
+    /// Anything else in the directory fails the removal with `ENOTEMPTY` before any whiteout
+    /// is removed.
+    pub async fn do_remove_whiteout_dir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        whiteout_xattr: &OsStr,
+    ) -> io::Result<()> {
+        let name = osstr_to_cstr(name)?;
+        self.validate_path_component(&name)?;
+        let whiteout_xattr = osstr_to_cstr(whiteout_xattr)?;
+        let data = self.inode_map.get(parent).await?;
+        // The directory is opened from its parent by the blocking task, a rename of an
+        // ancestor can't make it remove another one.
+        let parent_file = File::from(data.get_file()?.as_fd().try_clone_to_owned()?);
+        let uid = self.cfg.mapping.get_uid(req.uid);
+        let gid = self.cfg.mapping.get_gid(req.gid);
+        tokio::task::spawn_blocking(move || {
+            let _guard = set_creds(uid, gid)?;
+            util::remove_whiteout_dir(&parent_file, &name, &whiteout_xattr)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// Drop every POSIX lock `lock_owner` holds on `inode`.
+    ///
+    /// Closing the owner's file description releases its locks, which matches the POSIX rule that
+    /// closing any descriptor of a file drops all locks the process holds on it. Used by `flush`
+    /// and by `overlayfs` when locks move to another inode during copy-up.
+    pub async fn release_posix_locks(&self, inode: Inode, lock_owner: u64) {
+        if let Ok(data) = self.inode_map.get(inode).await {
+            data.posix_locks.lock().await.remove(&lock_owner);
+        }
+    }
+}
+
+/// Largest offset the kernel uses for byte-range locks, `end == OFFSET_MAX` means "up to EOF".
+#[cfg(target_os = "linux")]
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// Convert a FUSE lock range, which has an inclusive end, to a `flock` for `fcntl`.
+#[cfg(target_os = "linux")]
+fn to_flock(start: u64, end: u64, r#type: u32) -> io::Result<libc::flock> {
+    if start > OFFSET_MAX || end > OFFSET_MAX || end < start {
+        return Err(einval());
+    }
+    // Safe because `flock` is plain old data and all-zero is a valid value for it.
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = r#type as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = start as libc::off_t;
+    fl.l_len = if end == OFFSET_MAX {
+        0
+    } else {
+        (end - start + 1) as libc::off_t
+    };
+    Ok(fl)
+}
+
+#[cfg(target_os = "linux")]
+fn fcntl_lock(file: &File, cmd: c_int, fl: &mut libc::flock) -> io::Result<()> {
+    // Safe because this only touches `fl` and we check the return value.
+    let res = unsafe { libc::fcntl(file.as_raw_fd(), cmd, fl as *mut libc::flock) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
 }
 
 impl Filesystem for PassthroughFs {
@@ -849,7 +1101,12 @@ impl Filesystem for PassthroughFs {
         }
         let name = osstr_to_cstr(name).unwrap();
         // trace!("lookup: parent={}, name={}", parent, name.to_str().unwrap());
-        self.do_lookup(parent, name.as_ref()).await
+        match self.do_lookup(parent, name.as_ref()).await {
+            Err(e) if e.is_not_exist() && self.cfg.negative_timeout.is_some() => {
+                Ok(self.negative_entry())
+            }
+            res => res,
+        }
     }
 
     /// forget an inode. The nlookup parameter indicates the number of lookups previously
@@ -882,6 +1139,43 @@ impl Filesystem for PassthroughFs {
         })
     }
 
+    /// get extended file attributes: what `getattr` returns, plus the birth time and the
+    /// `STATX_ATTR_*` flags of the host file.
+    async fn statx(
+        &self,
+        _req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        _flags: u32,
+        _mask: u32,
+    ) -> Result<ReplyStatx> {
+        let (st, ttl) = self.do_getattr(inode, fh).await?;
+        let ext = match (fh, &self.uring) {
+            (Some(handle), Some(uring)) => {
+                let hd = self.get_handle(handle, inode).await?;
+                uring.statx(hd.get_file()).await?
+            }
+            (Some(handle), None) => {
+                let hd = self.get_handle(handle, inode).await?;
+                statx(hd.get_file(), None)?
+            }
+            (None, _) => statx(&self.inode_map.get(inode).await?.get_file()?, None)?,
+        };
+        // A zero birth time means the host filesystem doesn't record one. macOS only has a
+        // stand-in for it.
+        let btime = ext
+            .btime
+            .filter(|t| cfg!(target_os = "linux") && (t.tv_sec != 0 || t.tv_nsec != 0))
+            .map(|t| Timestamp::new(t.tv_sec, t.tv_nsec));
+        Ok(ReplyStatx {
+            ttl,
+            attr: convert_stat64_to_file_attr(st),
+            btime,
+            attributes: ext.attributes,
+            attributes_mask: ext.attributes_mask,
+        })
+    }
+
     /// set file attributes. If `fh` is None, means `fh` is not set.
     async fn setattr(
         &self,
@@ -905,7 +1199,7 @@ impl Filesystem for PassthroughFs {
         } else {
             // If we have a handle then use it otherwise get a new fd from the inode.
             if let Some(handle) = fh {
-                let hd = self.handle_map.get(handle, inode).await?;
+                let hd = self.get_handle(handle, inode).await?;
                 Data::Handle(hd)
             } else {
                 let pathname = CString::new(format!("{}", file.as_raw_fd()))
@@ -1077,6 +1371,14 @@ impl Filesystem for PassthroughFs {
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
         let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
         let data = self.inode_map.get(inode).await?;
+        let shared = self.layer_attrs();
+        let (dev, ino) = data.host_id();
+        if let Some(target) = match &shared {
+            Some(shared) => shared.readlink(dev, ino).await,
+            None => None,
+        } {
+            return Ok(ReplyData { data: target });
+        }
 
         let file = data.get_file()?;
 
@@ -1096,9 +1398,11 @@ impl Filesystem for PassthroughFs {
         // Safe because we trust the value returned by kernel.
         unsafe { buf.set_len(res as usize) };
 
-        Ok(ReplyData {
-            data: Bytes::from(buf),
-        })
+        let target = Bytes::from(buf);
+        if let Some(shared) = shared {
+            shared.insert_readlink(dev, ino, target.clone()).await;
+        }
+        Ok(ReplyData { data: target })
     }
 
     /// create a symbolic link.
@@ -1124,34 +1428,8 @@ impl Filesystem for PassthroughFs {
         mode: u32,
         rdev: u32,
     ) -> Result<ReplyEntry> {
-        let name = osstr_to_cstr(name).unwrap();
-        let name = name.as_ref();
-        self.validate_path_component(name)?;
-
-        let data = self.inode_map.get(parent).await?;
-        let file = data.get_file()?;
-
-        let res = {
-            let (_uid, _gid) = set_creds(
-                self.cfg.mapping.get_uid(req.uid),
-                self.cfg.mapping.get_gid(req.gid),
-            )?;
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            unsafe {
-                libc::mknodat(
-                    file.as_raw_fd(),
-                    name.as_ptr(),
-                    (mode) as libc::mode_t,
-                    rdev as libc::dev_t,
-                )
-            }
-        };
-        if res < 0 {
-            Err(io::Error::last_os_error().into())
-        } else {
-            self.do_lookup(parent, name).await
-        }
+        self.do_mknod_inner(req, parent, name, mode, rdev, None, None)
+            .await
     }
 
     /// create a directory.
@@ -1266,6 +1544,7 @@ impl Filesystem for PassthroughFs {
             Ok(ReplyOpen {
                 fh: re.0.unwrap(),
                 flags: re.1.bits(),
+                backing_id: None,
             })
         }
     }
@@ -1336,6 +1615,17 @@ impl Filesystem for PassthroughFs {
                             .copy_from_slice(&aligned_buf[..bytes_read]);
                     }
                     ret
+                } else if let Some(uring) = &self.uring {
+                    match uring.read(file, size as usize, offset).await {
+                        Ok(data) => {
+                            buf = data;
+                            buf.len() as isize
+                        }
+                        Err(e) => {
+                            error!("read error: {e:?}");
+                            return Err(e.into());
+                        }
+                    }
                 } else {
                     unsafe {
                         pread(
@@ -1369,6 +1659,30 @@ impl Filesystem for PassthroughFs {
         })
     }
 
+    /// read data by handing a duplicate of the file descriptor to the session, which splices
+    /// it to the kernel.
+    async fn read_fd(
+        &self,
+        _req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        _size: u32,
+    ) -> Result<Option<ReplyReadFd>> {
+        // Mapped and O_DIRECT reads keep their own path.
+        if self.cfg.use_mmap {
+            return Ok(None);
+        }
+        let data = self.get_data(fh, inode, libc::O_RDONLY).await?;
+        #[allow(clippy::bad_bit_mask)]
+        if (data.get_flags().await as i32 & O_DIRECT) != 0 {
+            return Ok(None);
+        }
+        let fd = data.borrow_fd().try_clone_to_owned()?;
+
+        Ok(Some(ReplyReadFd { fd, offset }))
+    }
+
     /// write data. Write should return exactly the number of bytes requested except on error. An
     /// exception to this is when the file has been opened in `direct_io` mode, in which case the
     /// return value of the write system call will reflect the return value of this operation. `fh`
@@ -1407,13 +1721,22 @@ impl Filesystem for PassthroughFs {
                     return Err(Errno::from(libc::EOVERFLOW));
                 }
                 self.check_fd_flags(&handle_data, raw_fd, flags).await?;
-                let ret = unsafe {
-                    libc::pwrite(
-                        raw_fd as c_int,
-                        data.as_ptr() as *const libc::c_void,
-                        size as size_t,
-                        offset as off_t,
-                    )
+                let ret = match &self.uring {
+                    Some(uring) => match uring.write(file, data, offset).await {
+                        Ok(written) => written as isize,
+                        Err(e) => {
+                            error!("write error: {e:?}");
+                            return Err(e.into());
+                        }
+                    },
+                    None => unsafe {
+                        libc::pwrite(
+                            raw_fd as c_int,
+                            data.as_ptr() as *const libc::c_void,
+                            size as size_t,
+                            offset as off_t,
+                        )
+                    },
                 };
                 if ret >= 0 {
                     ret
@@ -1432,6 +1755,7 @@ impl Filesystem for PassthroughFs {
             }
         };
 
+        handle_data.dirty.store(true, Ordering::Relaxed);
         Ok(ReplyWrite {
             written: ret as u32,
         })
@@ -1488,9 +1812,12 @@ impl Filesystem for PassthroughFs {
         inode: Inode,
         fh: u64,
         _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
+        lock_owner: u64,
+        flush: bool,
     ) -> Result<()> {
+        if flush {
+            self.release_posix_locks(inode, lock_owner).await;
+        }
         if self.no_open.load(Ordering::Relaxed) {
             Err(enosys().into())
         } else {
@@ -1503,6 +1830,9 @@ impl Filesystem for PassthroughFs {
     async fn fsync(&self, _req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
         let data = self.get_data(fh, inode, libc::O_RDONLY).await?;
         let fd = data.borrow_fd();
+        if let Some(uring) = &self.uring {
+            return uring.fsync(&fd, datasync).await.map_err(Into::into);
+        }
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
@@ -1539,47 +1869,10 @@ impl Filesystem for PassthroughFs {
         if !self.cfg.xattr {
             return Err(enosys().into());
         }
-        let name = osstr_to_cstr(name).unwrap();
-        let name = name.as_ref();
-        let data = self.inode_map.get(inode).await?;
-        let file = data.get_file()?;
-        #[cfg(target_os = "linux")]
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = match () {
-            #[cfg(target_os = "linux")]
-            () => unsafe {
-                libc::setxattr(
-                    pathname.as_ptr(),
-                    name.as_ptr(),
-                    value.as_ptr() as *const libc::c_void,
-                    value.len(),
-                    flags as libc::c_int,
-                )
-            },
-            #[cfg(target_os = "macos")]
-            () => unsafe {
-                libc::fsetxattr(
-                    file.as_raw_fd(),
-                    name.as_ptr(),
-                    value.as_ptr() as *const libc::c_void,
-                    value.len(),
-                    0,
-                    flags as libc::c_int,
-                )
-            },
-        };
-        if res == 0 {
-            Ok(())
-        } else {
-            let e = io::Error::last_os_error();
+        if let Err(e) = self.do_setxattr_helper(inode, name, value, flags).await {
             error!("setxattr error: {:?}, faking success", e);
-            Ok(())
         }
+        Ok(())
     }
 
     /// Get an extended attribute. If `size` is too small, return `Err<ERANGE>`.
@@ -1736,12 +2029,13 @@ impl Filesystem for PassthroughFs {
     /// flush pending writes. One reason to flush data, is if the filesystem wants to return write
     /// errors. If the filesystem supports file locking operations ([`setlk`][Filesystem::setlk],
     /// [`getlk`][Filesystem::getlk]) it should remove all locks belonging to `lock_owner`.
-    async fn flush(&self, _req: Request, inode: Inode, fh: u64, _lock_owner: u64) -> Result<()> {
+    async fn flush(&self, _req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        self.release_posix_locks(inode, lock_owner).await;
         if self.no_open.load(Ordering::Relaxed) {
             return Err(enosys().into());
         }
 
-        let data = self.handle_map.get(fh, inode).await?;
+        let data = self.get_handle(fh, inode).await?;
         trace!("flush: data.inode={}", data.inode);
 
         // Since this method is called whenever an fd is closed in the client, we can emulate that
@@ -1754,11 +2048,25 @@ impl Filesystem for PassthroughFs {
             }
 
             if libc::close(newfd) < 0 {
-                Err(io::Error::last_os_error().into())
-            } else {
-                Ok(())
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        // Write-back errors are only reported by syncing, close(2) of the client would miss
+        // them otherwise.
+        if self.cfg.sync_on_flush && data.dirty.swap(false, Ordering::Relaxed) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            #[cfg(target_os = "linux")]
+            let res = unsafe { libc::fdatasync(data.borrow_fd().as_raw_fd()) };
+            #[cfg(target_os = "macos")]
+            let res = unsafe { libc::fsync(data.borrow_fd().as_raw_fd()) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                error!("flush: write-back of inode {inode} failed: {e}");
+                return Err(e.into());
             }
         }
+        Ok(())
         // if self.no_open.load(Ordering::Acquire) {
         //         return Err(enosys().into());
         //     }
@@ -1796,6 +2104,7 @@ impl Filesystem for PassthroughFs {
             Ok(ReplyOpen {
                 fh: fd,
                 flags: t.1.bits(),
+                backing_id: None,
             })
         }
     }
@@ -1871,6 +2180,56 @@ impl Filesystem for PassthroughFs {
         self.fsync(req, inode, fh, datasync).await
     }
 
+    /// test for a POSIX file lock.
+    ///
+    /// Locks are tracked per `lock_owner` on the inode, not on the handle `fh`.
+    #[cfg(target_os = "linux")]
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        let data = self.inode_map.get(inode).await?;
+        let held = data.posix_locks.lock().await.get(&lock_owner).cloned();
+        // An owner without a description holds no locks, a fresh one answers the same.
+        let file = match held {
+            Some(file) => file,
+            None => Arc::new(self.open_lock_file(&data)?),
+        };
+
+        let mut fl = to_flock(start, end, r#type)?;
+        fcntl_lock(&file, libc::F_OFD_GETLK, &mut fl)?;
+        if fl.l_type == libc::F_UNLCK as libc::c_short {
+            return Ok(ReplyLock {
+                start,
+                end,
+                r#type: libc::F_UNLCK as u32,
+                pid: 0,
+            });
+        }
+
+        let start = fl.l_start as u64;
+        Ok(ReplyLock {
+            start,
+            end: if fl.l_len == 0 {
+                OFFSET_MAX
+            } else {
+                start + fl.l_len as u64 - 1
+            },
+            r#type: fl.l_type as u32,
+            // OFD locks are not owned by a process, so the kernel reports no pid for them.
+            pid: fl.l_pid.max(0) as u32,
+        })
+    }
+
+    #[cfg(target_os = "macos")]
     #[allow(clippy::too_many_arguments)]
     async fn getlk(
         &self,
@@ -1886,6 +2245,55 @@ impl Filesystem for PassthroughFs {
         Err(libc::ENOSYS.into())
     }
 
+    /// acquire, modify or release a POSIX file lock.
+    ///
+    /// When `block` is set the call waits until the conflicting lock is released, or gives up
+    /// with `EINTR` when the request is interrupted.
+    #[cfg(target_os = "linux")]
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        _pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        let mut fl = to_flock(start, end, r#type)?;
+        let data = self.inode_map.get(inode).await?;
+        let file = {
+            let mut locks = data.posix_locks.lock().await;
+            match locks.get(&lock_owner) {
+                Some(file) => file.clone(),
+                // Nothing to release for an owner that never locked this inode.
+                None if r#type == libc::F_UNLCK as u32 => return Ok(()),
+                None => {
+                    let file = Arc::new(self.open_lock_file(&data)?);
+                    locks.insert(lock_owner, file.clone());
+                    file
+                }
+            }
+        };
+
+        if block {
+            // Queued in the kernel with the other waiters, until interrupted.
+            let wait = async {
+                interrupt::blocking(move || fcntl_lock(&file, libc::F_OFD_SETLKW, &mut fl))
+                    .await
+                    .map_err(Errno::from)
+            };
+            self.interrupts.run(req.unique, wait).await
+        } else {
+            fcntl_lock(&file, libc::F_OFD_SETLK, &mut fl)?;
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "macos")]
     #[allow(clippy::too_many_arguments)]
     async fn setlk(
         &self,
@@ -1902,50 +2310,74 @@ impl Filesystem for PassthroughFs {
         Err(libc::ENOSYS.into())
     }
 
+    /// acquire, convert or release a `flock(2)` lock on the open file `fh`.
+    ///
+    /// The lock is taken on the host file of the handle, so it conflicts with `flock` locks
+    /// taken on the host and goes away when the handle is released. Blocking requests wait
+    /// like [`setlk`](Self::setlk).
+    #[cfg(target_os = "linux")]
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        _lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        let op = match r#type as i32 {
+            libc::F_RDLCK => libc::LOCK_SH,
+            libc::F_WRLCK => libc::LOCK_EX,
+            libc::F_UNLCK => libc::LOCK_UN,
+            _ => return Err(einval().into()),
+        };
+        let data = self.get_handle(fh, inode).await?;
+        let lock = move |op| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::flock(data.borrow_fd().as_raw_fd(), op) };
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        if block && op != libc::LOCK_UN {
+            let wait = async {
+                interrupt::blocking(move || lock(op))
+                    .await
+                    .map_err(Errno::from)
+            };
+            self.interrupts.run(req.unique, wait).await
+        } else {
+            Ok(lock(op | libc::LOCK_NB)?)
+        }
+    }
+
     /// check file access permissions. This will be called for the `access()` system call. If the
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
         let data = self.inode_map.get(inode).await?;
         let st = stat_fd(&data.get_file()?, None)?;
-        let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
+        // The file is owned by host ids, the caller is mapped to them.
         let uid = self.cfg.mapping.get_uid(req.uid);
         let gid = self.cfg.mapping.get_gid(req.gid);
-
-        if mode == libc::F_OK {
-            // The file exists since we were able to call `stat(2)` on it.
-            return Ok(());
-        }
-
-        if (mode & libc::R_OK) != 0
-            && uid != 0
-            && (st.st_uid != uid || st.st_mode & 0o400 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o040 == 0)
-            && st.st_mode & 0o004 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES).into());
-        }
-
-        if (mode & libc::W_OK) != 0
-            && uid != 0
-            && (st.st_uid != uid || st.st_mode & 0o200 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o020 == 0)
-            && st.st_mode & 0o002 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES).into());
-        }
-
-        // root can only execute something if it is executable by one of the owner, the group, or
-        // everyone.
-        if (mode & libc::X_OK) != 0
-            && (uid != 0 || st.st_mode & 0o111 == 0)
-            && (st.st_uid != uid || st.st_mode & 0o100 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o010 == 0)
-            && st.st_mode & 0o001 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES).into());
-        }
+        let in_group = |group| {
+            group == gid
+                || crate::util::process_groups(req.pid)
+                    .into_iter()
+                    .any(|g| self.cfg.mapping.get_gid(g) == group)
+        };
+        crate::util::check_access(
+            uid,
+            in_group,
+            st.st_uid,
+            st.st_gid,
+            st.st_mode,
+            st.st_mode & libc::S_IFMT == libc::S_IFDIR,
+            mask,
+        )?;
 
         Ok(())
     }
@@ -1979,8 +2411,10 @@ impl Filesystem for PassthroughFs {
     }
 
     /// handle interrupt. When a operation is interrupted, an interrupt request will send to fuse
-    /// server with the unique id of the operation.
-    async fn interrupt(&self, _req: Request, _unique: u64) -> Result<()> {
+    /// server with the unique id of the operation. Lock requests waiting on a conflicting lock
+    /// give up with `EINTR`.
+    async fn interrupt(&self, _req: Request, unique: u64) -> Result<()> {
+        self.interrupts.interrupt(unique);
         Ok(())
     }
 
@@ -2179,7 +2613,7 @@ impl Filesystem for PassthroughFs {
         whence: u32,
     ) -> Result<ReplyLSeek> {
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
-        let data = self.handle_map.get(fh, inode).await?;
+        let data = self.get_handle(fh, inode).await?;
 
         // Check file type to determine appropriate lseek handling
         let st = stat_fd(data.get_file(), None)?;
@@ -2321,8 +2755,8 @@ impl Filesystem for PassthroughFs {
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
         // Get the handle data for both source and destination files
-        let data_in = self.handle_map.get(fh_in, inode_in).await?;
-        let data_out = self.handle_map.get(fh_out, inode_out).await?;
+        let data_in = self.get_handle(fh_in, inode_in).await?;
+        let data_out = self.get_handle(fh_out, inode_out).await?;
 
         // Get file descriptors
         let _fd_in = data_in.borrow_fd().as_raw_fd();
@@ -2373,6 +2807,7 @@ impl Filesystem for PassthroughFs {
         if res < 0 {
             Err(io::Error::last_os_error().into())
         } else {
+            data_out.dirty.store(true, Ordering::Relaxed);
             // res is guaranteed >= 0 here, safe to cast to usize then u64
             Ok(ReplyCopyFileRange {
                 copied: res as usize as u64,
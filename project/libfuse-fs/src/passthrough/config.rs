@@ -52,6 +52,33 @@ impl FromStr for CachePolicy {
     }
 }
 
+/// How the passthrough file system does the I/O on the files it opened.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum IoEngine {
+    /// A blocking system call per request, on the task serving it.
+    #[default]
+    Syscall,
+
+    /// Reads, writes, `fsync` and `statx` of opened files go through an io_uring shared by the
+    /// file system, so parallel requests cost a submission each instead of a blocking system
+    /// call, and wait for their completion without holding a runtime thread. Falls back to
+    /// `Syscall` with a warning where io_uring is unavailable, before Linux 5.6 or when
+    /// disabled by seccomp as in many container runtimes.
+    IoUring,
+}
+
+impl FromStr for IoEngine {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "syscall" => Ok(IoEngine::Syscall),
+            "io_uring" | "io-uring" => Ok(IoEngine::IoUring),
+            _ => Err("invalid io engine"),
+        }
+    }
+}
+
 /// Options that configure the behavior of the passthrough fuse file system.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Config {
@@ -78,6 +105,13 @@ pub struct Config {
     /// regular files.
     pub dir_entry_timeout: Option<Duration>,
 
+    /// How long the FUSE client may remember that a name doesn't exist. When specified, lookups
+    /// of missing names are answered with a negative entry valid for this long instead of
+    /// `ENOENT`. Leave unset when other clients create files in the shared directory.
+    ///
+    /// Not meant for the layers of an overlay, which expect `ENOENT` from lookups.
+    pub negative_timeout: Option<Duration>,
+
     /// The caching policy the file system should use. See the documentation of `CachePolicy` for
     /// more details.
     pub cache_policy: CachePolicy,
@@ -179,6 +213,27 @@ pub struct Config {
     /// The default is `1024 * 1024 * 1024` (1GB).
     pub max_mmap_size: u64,
 
+    /// Whether `flush` syncs the data written through the handle, so write-back errors such as
+    /// `ENOSPC` or `EIO` are returned by `close()` instead of only by a later `fsync()`, which
+    /// most programs never call. Handles that weren't written to are never synced.
+    ///
+    /// The default is `true`.
+    pub sync_on_flush: bool,
+
+    /// How the I/O on opened files is done, see [`IoEngine`].
+    ///
+    /// The default is `IoEngine::Syscall`.
+    pub io_engine: IoEngine,
+
+    /// Most files kept open for the handles of regular files. The least recently used files are
+    /// closed beyond it and reopened with the flags of their open when the handle is used again,
+    /// so a large working set doesn't exhaust the `RLIMIT_NOFILE` of the process. Files in use
+    /// by a request stay open, and reopening fails like an open would, for instance once the
+    /// file was unlinked when inodes are kept as file handles.
+    ///
+    /// The default is `None`, every handle keeps its file open.
+    pub max_open_files: Option<usize>,
+
     /// UID/GID mapping. Format: `uidmapping=H:T:L[:H2:T2:L2...],gidmapping=H:T:L[:H2:T2:L2...]`
     pub mapping: IdMappings,
 }
@@ -202,10 +257,14 @@ impl Default for Config {
             dax_file_size: None,
             dir_entry_timeout: None,
             dir_attr_timeout: None,
+            negative_timeout: None,
             use_host_ino: false,
             allow_direct_io: true,
             use_mmap: false,
             max_mmap_size: 1024 * 1024 * 1024,
+            sync_on_flush: true,
+            io_engine: IoEngine::Syscall,
+            max_open_files: None,
             mapping: IdMappings::default(),
         }
     }
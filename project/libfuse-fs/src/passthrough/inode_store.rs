@@ -114,6 +114,10 @@ impl InodeStore {
         data
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn clear(&mut self) {
         self.data.clear();
         self.by_handle.clear();
@@ -1,4 +1,5 @@
 #![allow(clippy::useless_conversion)]
+pub use config::IoEngine;
 use config::{CachePolicy, Config};
 use file_handle::{FileHandle, OpenableFileHandle};
 
@@ -10,11 +11,15 @@ use inode_store::{InodeId, InodeStore};
 use libc::{self, statx_timestamp};
 
 use moka::future::Cache;
+use rfuse3::passthrough::{BackingFiles, BackingId};
 use rfuse3::{Errno, raw::reply::ReplyEntry};
 use uuid::Uuid;
 
+use crate::overlayfs::forget::ForgetQueue;
+use crate::overlayfs::shared_attrs::{LayerAttrs, SharedAttrCache};
 use crate::passthrough::mmap::{MmapCachedValue, MmapChunkKey};
 use crate::util::convert_stat64_to_file_attr;
+use crate::util::interrupt::Interrupts;
 use mount_fd::MountFds;
 use statx::StatExt;
 use std::cmp;
@@ -24,10 +29,11 @@ use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use tracing::error;
 use tracing::{debug, warn};
+use uring::Uring;
 
 use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::{
-    collections::{BTreeMap, btree_map},
+    collections::{BTreeMap, HashMap, btree_map},
     ffi::{CStr, CString, OsString},
     fs::File,
     io::{self, Error},
@@ -42,7 +48,7 @@ use std::{
     time::Duration,
 };
 use util::{
-    UniqueInodeGenerator, ebadf, is_dir, openat, reopen_fd_through_proc, stat_fd,
+    UniqueInodeGenerator, ebadf, einval, is_dir, openat, reopen_fd_through_proc, stat_fd, stat64,
     validate_path_component,
 };
 
@@ -58,6 +64,7 @@ mod mmap;
 mod mount_fd;
 mod os_compat;
 mod statx;
+mod uring;
 pub mod util;
 
 /// Current directory
@@ -85,6 +92,8 @@ where
 {
     pub root_dir: P,
     pub mapping: Option<M>,
+    /// How the I/O on opened files is done.
+    pub io_engine: IoEngine,
 }
 
 pub async fn new_passthroughfs_layer<P: AsRef<Path>, M: AsRef<str>>(
@@ -95,6 +104,7 @@ pub async fn new_passthroughfs_layer<P: AsRef<Path>, M: AsRef<str>>(
         // enable xattr
         xattr: true,
         do_import: true,
+        io_engine: args.io_engine,
         ..Default::default()
     };
     if let Some(mapping) = args.mapping {
@@ -123,6 +133,9 @@ pub async fn new_passthroughfs_layer<P: AsRef<Path>, M: AsRef<str>>(
 type Inode = u64;
 type Handle = u64;
 
+/// Submission queue size of the io_uring engine.
+const URING_ENTRIES: u32 = 256;
+
 /// Maximum host inode number supported by passthroughfs
 const MAX_HOST_INO: u64 = 0x7fff_ffff_ffff;
 
@@ -241,6 +254,8 @@ pub struct InodeData {
     // File type and mode
     mode: u32,
     btime: statx_timestamp,
+    // Open file descriptions holding POSIX locks, one per lock owner.
+    posix_locks: Mutex<HashMap<u64, Arc<File>>>,
 }
 
 impl InodeData {
@@ -259,6 +274,7 @@ impl InodeData {
             refcount: AtomicU64::new(refcount),
             mode,
             btime,
+            posix_locks: Mutex::new(HashMap::new()),
         }
     }
 
@@ -269,6 +285,12 @@ impl InodeData {
     fn open_file(&self, flags: libc::c_int, proc_self_fd: &File) -> Result<File> {
         self.handle.open_file(flags, proc_self_fd)
     }
+
+    // Host `(st_dev, st_ino)`, as keyed in a shared attribute cache.
+    #[allow(clippy::unnecessary_cast)]
+    fn host_id(&self) -> (u64, u64) {
+        (self.id.dev as u64, self.id.ino as u64)
+    }
 }
 
 /// Data structures to manage accessed inodes.
@@ -357,6 +379,8 @@ struct HandleData {
     file: File,
     lock: Mutex<()>,
     open_flags: AtomicU32,
+    // Written to since the last flush.
+    dirty: AtomicBool,
 }
 
 impl HandleData {
@@ -366,6 +390,7 @@ impl HandleData {
             file,
             lock: Mutex::new(()),
             open_flags: AtomicU32::new(flags),
+            dirty: AtomicBool::new(false),
         }
     }
 
@@ -392,23 +417,126 @@ impl HandleData {
 
 struct HandleMap {
     handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    // Limit on the files kept open, `None` keeps them all open.
+    cache: Option<FdCache>,
+}
+
+// Least recently used files of handles, closed beyond `max` and reopened on demand.
+struct FdCache {
+    max: usize,
+    state: std::sync::Mutex<FdCacheState>,
+}
+
+#[derive(Default)]
+struct FdCacheState {
+    tick: u64,
+    // Last use of the open files that may be closed, oldest first.
+    lru: BTreeMap<u64, Handle>,
+    last_used: HashMap<Handle, u64>,
+    // Handles whose file was closed.
+    closed: HashMap<Handle, ClosedHandle>,
+}
+
+#[derive(Clone, Copy)]
+struct ClosedHandle {
+    inode: Inode,
+    flags: u32,
+    dirty: bool,
+}
+
+impl FdCacheState {
+    fn touch(&mut self, handle: Handle) {
+        self.tick += 1;
+        if let Some(tick) = self.last_used.insert(handle, self.tick) {
+            self.lru.remove(&tick);
+        }
+        self.lru.insert(self.tick, handle);
+    }
+
+    fn forget(&mut self, handle: Handle) {
+        if let Some(tick) = self.last_used.remove(&handle) {
+            self.lru.remove(&tick);
+        }
+        self.closed.remove(&handle);
+    }
+
+    // Close the least recently used files until at most `max` are open. Files in use by a
+    // request are skipped, so the limit may be exceeded while they are.
+    fn evict(&mut self, handles: &mut BTreeMap<Handle, Arc<HandleData>>, max: usize) {
+        let mut excess = self.lru.len().saturating_sub(max);
+        let mut victims = Vec::new();
+        for (&tick, &handle) in self.lru.iter() {
+            if excess == 0 {
+                break;
+            }
+            // Only the map holds it, and nothing can take it while the map is locked.
+            if handles
+                .get(&handle)
+                .is_some_and(|data| Arc::strong_count(data) == 1)
+            {
+                victims.push((tick, handle));
+                excess -= 1;
+            }
+        }
+        for (tick, handle) in victims {
+            self.lru.remove(&tick);
+            self.last_used.remove(&handle);
+            if let Some(data) = handles.remove(&handle) {
+                self.closed.insert(
+                    handle,
+                    ClosedHandle {
+                        inode: data.inode,
+                        flags: data.open_flags.load(Ordering::Relaxed),
+                        dirty: data.dirty.load(Ordering::Relaxed),
+                    },
+                );
+            }
+        }
+    }
 }
 
 impl HandleMap {
-    fn new() -> Self {
+    fn new(max_open_files: Option<usize>) -> Self {
         HandleMap {
             handles: RwLock::new(BTreeMap::new()),
+            cache: max_open_files.map(|max| FdCache {
+                max: max.max(1),
+                state: Default::default(),
+            }),
         }
     }
 
     async fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().await.clear();
+        let mut handles = self.handles.write().await;
+        handles.clear();
+        if let Some(cache) = &self.cache {
+            *cache.state.lock().unwrap() = Default::default();
+        }
     }
 
     async fn insert(&self, handle: Handle, data: HandleData) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().await.insert(handle, Arc::new(data));
+        let mut handles = self.handles.write().await;
+        Self::insert_locked(&mut handles, &self.cache, handle, Arc::new(data));
+    }
+
+    fn insert_locked(
+        handles: &mut BTreeMap<Handle, Arc<HandleData>>,
+        cache: &Option<FdCache>,
+        handle: Handle,
+        data: Arc<HandleData>,
+    ) {
+        // Directories keep their position for readdir, so they are never closed.
+        let evictable = data.open_flags.load(Ordering::Relaxed) & libc::O_DIRECTORY as u32 == 0;
+        handles.insert(handle, data);
+        if let Some(cache) = cache
+            && evictable
+        {
+            let mut state = cache.state.lock().unwrap();
+            state.touch(handle);
+            state.evict(handles, cache.max);
+        }
     }
 
     async fn release(&self, handle: Handle, inode: Inode) -> Result<()> {
@@ -421,22 +549,82 @@ impl HandleMap {
             // We don't need to close the file here because that will happen automatically when
             // the last `Arc` is dropped.
             e.remove();
+            if let Some(cache) = &self.cache {
+                cache.state.lock().unwrap().forget(handle);
+            }
 
             return Ok(());
         }
 
+        if let Some(cache) = &self.cache {
+            let mut state = cache.state.lock().unwrap();
+            if state
+                .closed
+                .get(&handle)
+                .is_some_and(|closed| closed.inode == inode)
+            {
+                state.forget(handle);
+                return Ok(());
+            }
+        }
+
         Err(ebadf())
     }
 
     async fn get(&self, handle: Handle, inode: Inode) -> Result<Arc<HandleData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles
-            .read()
-            .await
+        let handles = self.handles.read().await;
+        let data = handles
             .get(&handle)
             .filter(|hd| hd.inode == inode)
             .cloned()
-            .ok_or_else(ebadf)
+            .ok_or_else(ebadf)?;
+        if let Some(cache) = &self.cache {
+            let mut state = cache.state.lock().unwrap();
+            if state.last_used.contains_key(&handle) {
+                state.touch(handle);
+            }
+        }
+
+        Ok(data)
+    }
+
+    // The handle `handle` of `inode` if its file was closed to stay under the limit.
+    fn closed(&self, handle: Handle, inode: Inode) -> Option<ClosedHandle> {
+        let state = self.cache.as_ref()?.state.lock().unwrap();
+        state
+            .closed
+            .get(&handle)
+            .filter(|closed| closed.inode == inode)
+            .copied()
+    }
+
+    // Put back the reopened file of a closed handle, unless it was released or reopened by
+    // another request meanwhile.
+    async fn reopened(&self, handle: Handle, data: HandleData) -> Result<Arc<HandleData>> {
+        let mut handles = self.handles.write().await;
+        if let Some(data) = handles.get(&handle) {
+            return Ok(data.clone());
+        }
+        let Some(cache) = &self.cache else {
+            return Err(ebadf());
+        };
+        if cache.state.lock().unwrap().closed.remove(&handle).is_none() {
+            return Err(ebadf());
+        }
+        let data = Arc::new(data);
+        Self::insert_locked(&mut handles, &self.cache, handle, data.clone());
+
+        Ok(data)
+    }
+
+    async fn len(&self) -> usize {
+        let handles = self.handles.read().await;
+        let closed = self
+            .cache
+            .as_ref()
+            .map_or(0, |cache| cache.state.lock().unwrap().closed.len());
+        handles.len() + closed
     }
 }
 
@@ -508,6 +696,18 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     handle_cache: Cache<FileUniqueKey, Arc<FileHandle>>,
 
     mmap_chunks: Cache<MmapChunkKey, Arc<RwLock<mmap::MmapCachedValue>>>,
+
+    // Set on lower layers of overlays sharing attributes, see `overlayfs::shared_attrs`.
+    shared_attrs: std::sync::RwLock<Option<LayerAttrs>>,
+
+    // Forgets owed by overlays using this as a layer, see `overlayfs::forget`.
+    forget_queue: ForgetQueue,
+
+    // Engine of `IoEngine::IoUring`, when it could be set up.
+    uring: Option<Uring>,
+
+    // Lock requests waiting on a conflicting lock, given up on FUSE_INTERRUPT.
+    interrupts: Interrupts,
 }
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
@@ -549,6 +749,17 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             Err(_) => 65536,
         };
 
+        let uring = match cfg.io_engine {
+            IoEngine::Syscall => None,
+            IoEngine::IoUring => match Uring::new(URING_ENTRIES) {
+                Ok(uring) => Some(uring),
+                Err(e) => {
+                    warn!("passthroughfs: io_uring unavailable, using system calls: {e}");
+                    None
+                }
+            },
+        };
+
         let max_mmap_size = if cfg.use_mmap { cfg.max_mmap_size } else { 0 };
 
         let mmap_cache_builder = Cache::builder()
@@ -569,7 +780,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             next_inode: AtomicU64::new(ROOT_ID + 1),
             ino_allocator: UniqueInodeGenerator::new(),
 
-            handle_map: HandleMap::new(),
+            handle_map: HandleMap::new(cfg.max_open_files),
             next_handle: AtomicU64::new(1),
 
             mount_fds,
@@ -593,6 +804,11 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             handle_cache: moka::future::Cache::new(fd_limit),
 
             mmap_chunks: mmap_cache_builder.build(),
+
+            shared_attrs: std::sync::RwLock::new(None),
+            forget_queue: ForgetQueue::default(),
+            uring,
+            interrupts: Interrupts::default(),
         })
     }
 
@@ -632,6 +848,150 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(())
     }
 
+    /// Serve attributes and symlink targets through `cache`, for read-only lower layers.
+    /// `None` stops, for a layer that becomes writable.
+    pub(crate) fn share_attrs(&self, cache: Option<Arc<SharedAttrCache>>) -> io::Result<()> {
+        let attrs = cache
+            .map(|cache| LayerAttrs::new(cache, &self.cfg.root_dir))
+            .transpose()?;
+        *self.shared_attrs.write().unwrap() = attrs;
+        Ok(())
+    }
+
+    /// Inodes this layer currently knows of.
+    pub async fn inode_count(&self) -> usize {
+        self.inode_map.inodes.read().await.len()
+    }
+
+    /// Files and directories currently open, including those whose file was closed to stay
+    /// under [`Config::max_open_files`].
+    pub async fn handle_count(&self) -> usize {
+        self.handle_map.len().await
+    }
+
+    /// Inodes with forgets queued by overlays using this as a layer.
+    pub fn queued_forgets(&self) -> usize {
+        self.forget_queue.pending()
+    }
+
+    pub(crate) fn forget_queue(&self) -> &ForgetQueue {
+        &self.forget_queue
+    }
+
+    fn layer_attrs(&self) -> Option<LayerAttrs> {
+        self.shared_attrs.read().unwrap().clone()
+    }
+
+    // Host attributes of `data`, from `stat` unless cached for a shared lower layer.
+    async fn shared_stat(
+        &self,
+        data: &InodeData,
+        stat: impl FnOnce() -> io::Result<stat64>,
+    ) -> io::Result<stat64> {
+        let Some(shared) = self.layer_attrs() else {
+            return stat();
+        };
+        let (dev, ino) = data.host_id();
+        if let Some(st) = shared.stat(dev, ino).await {
+            return Ok(st);
+        }
+        let st = stat()?;
+        shared.insert_stat(dev, ino, st).await;
+        Ok(st)
+    }
+
+    /// Apply `posix_fadvise` `advice`, one of `libc::POSIX_FADV_*`, to `len` bytes at `offset`
+    /// of the file open as `handle`. A zero `len` extends to the end of the file.
+    ///
+    /// `POSIX_FADV_WILLNEED` also starts readahead explicitly, which backing filesystems that
+    /// ignore the hint, such as network ones, still honor.
+    pub async fn fadvise(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        len: u64,
+        advice: libc::c_int,
+    ) -> io::Result<()> {
+        let data = self.get_handle(handle, inode).await?;
+        let fd = data.borrow_fd().as_raw_fd();
+        let offset = libc::off_t::try_from(offset).map_err(|_| einval())?;
+        let len = libc::off_t::try_from(len).map_err(|_| einval())?;
+        #[cfg(target_os = "linux")]
+        {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::posix_fadvise(fd, offset, len, advice) };
+            if res != 0 {
+                return Err(io::Error::from_raw_os_error(res));
+            }
+            if advice == libc::POSIX_FADV_WILLNEED {
+                let count = match len {
+                    0 => data.get_file().metadata()?.len() as usize,
+                    len => len as usize,
+                };
+                // Safe for the same reason. Best effort: files without a page cache refuse it.
+                unsafe { libc::readahead(fd, offset, count) };
+            }
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let _ = (fd, offset, len, advice);
+        }
+        Ok(())
+    }
+
+    /// Directory this filesystem passes through to.
+    pub fn root_dir(&self) -> &Path {
+        &self.cfg.root_dir
+    }
+
+    /// Whether xattr requests are passed through, see [`Config::xattr`].
+    pub fn xattr_enabled(&self) -> bool {
+        self.cfg.xattr
+    }
+
+    /// Make the file open as `fh` of `inode` a reflink of the one open as `src_fh` of
+    /// `src_inode` in `src`, sharing its data blocks instead of copying them.
+    ///
+    /// Fails with `EXDEV` when the files are on different filesystems, and with what the
+    /// filesystem returns, usually `EOPNOTSUPP`, when it can't clone files.
+    pub(crate) async fn clone_file_from(
+        &self,
+        inode: Inode,
+        fh: Handle,
+        src: &PassthroughFs<S>,
+        src_inode: Inode,
+        src_fh: Handle,
+    ) -> Result<()> {
+        let dst = self.get_handle(fh, inode).await?;
+        let src = src.get_handle(src_fh, src_inode).await?;
+        if stat_fd(dst.get_file(), None)?.st_dev != stat_fd(src.get_file(), None)?.st_dev {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let (dst_fd, src_fd) = (dst.borrow_fd().as_raw_fd(), src.borrow_fd().as_raw_fd());
+            // Safe because FICLONE only reads the source fd number.
+            if unsafe { libc::ioctl(dst_fd, libc::FICLONE, src_fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Register the file of handle `fh` as a FUSE passthrough backing file.
+    pub(crate) async fn open_backing(
+        &self,
+        inode: Inode,
+        fh: Handle,
+        files: &BackingFiles,
+    ) -> Result<BackingId> {
+        let data = self.get_handle(fh, inode).await?;
+        files.open(data.borrow_fd())
+    }
+
     /// Get the list of file descriptors which should be reserved across live upgrade.
     pub fn keep_fds(&self) -> Vec<RawFd> {
         vec![self.proc_self_fd.as_raw_fd()]
@@ -806,6 +1166,28 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
     }
 
+    // Entry and attribute timeouts of a directory or another kind of file.
+    fn timeouts(&self, dir: bool) -> (Duration, Duration) {
+        if dir {
+            (self.dir_entry_timeout, self.dir_attr_timeout)
+        } else {
+            (self.cfg.entry_timeout, self.cfg.attr_timeout)
+        }
+    }
+
+    // Entry telling the kernel a name doesn't exist, for `negative_timeout`.
+    fn negative_entry(&self) -> ReplyEntry {
+        // Safe because stat64 is plain data and all zeroes is a valid value of it.
+        let mut st: stat64 = unsafe { std::mem::zeroed() };
+        st.st_mode = libc::S_IFREG;
+        let attr = convert_stat64_to_file_attr(st);
+        ReplyEntry {
+            ttl: self.cfg.negative_timeout.unwrap_or_default(),
+            attr,
+            generation: 0,
+        }
+    }
+
     async fn do_lookup(
         &self,
         parent: Inode,
@@ -907,11 +1289,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
         };
 
-        let (entry_timeout, _) = if is_dir(st.st.st_mode.into()) {
-            (self.dir_entry_timeout, self.dir_attr_timeout)
-        } else {
-            (self.cfg.entry_timeout, self.cfg.attr_timeout)
-        };
+        let (entry_timeout, _) = self.timeouts(is_dir(st.st.st_mode.into()));
 
         // // Whether to enable file DAX according to the value of dax_file_size
         // let mut attr_flags: u32 = 0;
@@ -1328,6 +1706,7 @@ mod tests {
         let args = PassthroughArgs {
             root_dir: source_dir.clone(),
             mapping: None::<&str>,
+            io_engine: Default::default(),
         };
         let fs = match super::new_passthroughfs_layer(args).await {
             Ok(fs) => fs,
@@ -1357,6 +1736,170 @@ mod tests {
         let _ = std::fs::remove_dir_all(&temp_dir);
     }
 
+    #[tokio::test]
+    async fn test_timeouts() {
+        use std::time::Duration;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"file").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            entry_timeout: Duration::from_secs(1),
+            attr_timeout: Duration::from_secs(2),
+            dir_entry_timeout: Some(Duration::from_secs(30)),
+            dir_attr_timeout: Some(Duration::from_secs(60)),
+            negative_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        let req = Request::default();
+
+        let dir = fs.lookup(req, ROOT_ID, OsStr::new("dir")).await.unwrap();
+        assert_eq!(dir.ttl, Duration::from_secs(30));
+        let attr = fs.getattr(req, dir.attr.ino, None, 0).await.unwrap();
+        assert_eq!(attr.ttl, Duration::from_secs(60));
+
+        let file = fs.lookup(req, ROOT_ID, OsStr::new("file")).await.unwrap();
+        assert_eq!(file.ttl, Duration::from_secs(1));
+        let attr = fs.getattr(req, file.attr.ino, None, 0).await.unwrap();
+        assert_eq!(attr.ttl, Duration::from_secs(2));
+
+        // A missing name is cached as a negative entry.
+        let missing = fs
+            .lookup(req, ROOT_ID, OsStr::new("missing"))
+            .await
+            .unwrap();
+        assert_eq!(missing.attr.ino, 0);
+        assert_eq!(missing.ttl, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_io_uring_engine() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            io_engine: super::IoEngine::IoUring,
+            ..Default::default()
+        };
+        // Without io_uring, as under seccomp, the same requests go through system calls.
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        let req = Request::default();
+
+        let created = fs
+            .create(req, ROOT_ID, OsStr::new("file"), 0o644, libc::O_RDWR as u32)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        let written = fs.write(req, ino, fh, 2, b"uring", 0, 0).await.unwrap();
+        assert_eq!(written.written, 5);
+        fs.fsync(req, ino, fh, true).await.unwrap();
+        fs.fsync(req, ino, fh, false).await.unwrap();
+
+        let data = fs.read(req, ino, fh, 0, 16).await.unwrap().data;
+        assert_eq!(&data[..], b"\0\0uring");
+        let data = fs.read(req, ino, fh, 16, 16).await.unwrap().data;
+        assert!(data.is_empty());
+
+        let statx = fs.statx(req, ino, Some(fh), 0, 0).await.unwrap();
+        assert_eq!(statx.attr.size, 7);
+        let err = fs.read(req, ino, fh + 1, 0, 16).await.unwrap_err();
+        assert_eq!(err, libc::EBADF.into());
+
+        fs.release(req, ino, fh, 0, 0, false).await.unwrap();
+    }
+
+    // Many more requests than the ring takes at once, from several threads, some of them
+    // dropped while in flight.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_io_uring_concurrent_requests() {
+        let uring = match super::uring::Uring::new(8) {
+            Ok(uring) => std::sync::Arc::new(uring),
+            Err(e) => {
+                eprintln!("skip test_io_uring_concurrent_requests: {e}");
+                return;
+            }
+        };
+        let file = std::sync::Arc::new(tempfile::tempfile().unwrap());
+        const BLOCK: usize = 4096;
+
+        let tasks = (0..256u64)
+            .map(|i| {
+                let (uring, file) = (uring.clone(), file.clone());
+                tokio::spawn(async move {
+                    let offset = i * BLOCK as u64;
+                    let block = vec![i as u8; BLOCK];
+                    assert_eq!(uring.write(&*file, &block, offset).await.unwrap(), BLOCK);
+                    let _ = tokio::time::timeout(
+                        std::time::Duration::ZERO,
+                        uring.read(&*file, BLOCK, offset),
+                    )
+                    .await;
+                    assert_eq!(uring.read(&*file, BLOCK, offset).await.unwrap(), block);
+                    uring.fsync(&*file, i % 2 == 0).await.unwrap();
+                    uring.statx(&*file).await.unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let size = uring.statx(&*file).await.unwrap().st.st_size;
+        assert_eq!(size, 256 * BLOCK as i64);
+    }
+
+    #[tokio::test]
+    async fn test_max_open_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            max_open_files: Some(2),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        let req = Request::default();
+
+        let dir = fs.opendir(req, ROOT_ID, 0).await.unwrap().fh;
+        let mut files = Vec::new();
+        for i in 0..4 {
+            let name = format!("file{i}");
+            let flags = (libc::O_RDWR | libc::O_TRUNC) as u32;
+            let created = fs
+                .create(req, ROOT_ID, OsStr::new(&name), 0o644, flags)
+                .await
+                .unwrap();
+            let (ino, fh) = (created.attr.ino, created.fh);
+            fs.write(req, ino, fh, 0, name.as_bytes(), 0, 0)
+                .await
+                .unwrap();
+            files.push((ino, fh, name));
+        }
+        // The directory stays open next to the two most recently used files.
+        assert_eq!(fs.handle_map.handles.read().await.len(), 3);
+        assert_eq!(fs.handle_count().await, 5);
+
+        // Closed files are reopened, without truncating them again.
+        for (ino, fh, name) in &files {
+            let data = fs.read(req, *ino, *fh, 0, 16).await.unwrap().data;
+            assert_eq!(&data[..], name.as_bytes());
+            assert_eq!(fs.handle_map.handles.read().await.len(), 3);
+        }
+        let (ino, fh, _) = files[0].clone();
+        fs.flush(req, ino, fh, 0).await.unwrap();
+
+        for (ino, fh, _) in files {
+            fs.release(req, ino, fh, 0, 0, false).await.unwrap();
+        }
+        fs.releasedir(req, ROOT_ID, dir, 0).await.unwrap();
+        assert_eq!(fs.handle_count().await, 0);
+        let err = fs.read(req, ino, fh, 0, 16).await.unwrap_err();
+        assert_eq!(err, libc::EBADF.into());
+    }
+
     // // Test for uid/gid mapping
     // async fn setup(
     //     mapping: Option<&str>,
@@ -39,6 +39,9 @@ pub struct StatExt {
     pub mnt_id: MountId,
     // Using Option<> for easier testing.
     pub btime: Option<statx_timestamp>,
+    /// `STATX_ATTR_*` flags set on the file, and those the filesystem supports.
+    pub attributes: u64,
+    pub attributes_mask: u64,
 }
 
 /*
@@ -134,6 +137,35 @@ fn do_statx(
         as libc::c_int
 }
 
+/// Flags of the `statx()` calls for [`statx`].
+#[cfg(target_os = "linux")]
+pub(crate) const STATX_FLAGS: libc::c_int = libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW;
+/// Fields asked from `statx()` for [`statx`].
+#[cfg(target_os = "linux")]
+pub(crate) const STATX_MASK: libc::c_uint = STATX_BASIC_STATS | STATX_MNT_ID | STATX_BTIME;
+
+/// Build the [`StatExt`] of `path` relative to `dir` from what `statx()` returned for it.
+#[cfg(target_os = "linux")]
+pub(crate) fn stat_ext(stx: statx_st, dir: &impl AsRawFd, path: &CStr) -> io::Result<StatExt> {
+    // if `statx()` doesn't provide the mount id (before kernel 5.8),
+    // let's try `name_to_handle_at()`, if everything fails just use 0
+    let mnt_id = stx
+        .mount_id()
+        .or_else(|| get_mount_id(dir, path))
+        .unwrap_or(0);
+    let st = stx
+        .stat64()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
+    let btime = Some(stx.stx_btime);
+    Ok(StatExt {
+        st,
+        mnt_id,
+        btime,
+        attributes: stx.stx_attributes,
+        attributes_mask: stx.stx_attributes_mask,
+    })
+}
+
 /// Execute `statx()` to get extended status with mount id.
 pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
     #[allow(unused)]
@@ -152,26 +184,14 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
         let res = do_statx(
             dir.as_raw_fd(),
             path.as_ptr(),
-            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
-            STATX_BASIC_STATS | STATX_MNT_ID | STATX_BTIME,
+            STATX_FLAGS,
+            STATX_MASK,
             stx_ui.as_mut_ptr(),
         );
         if res >= 0 {
             // Safe because we are only going to use the SafeStatXAccess
             // trait methods
-            let stx = unsafe { stx_ui.assume_init() };
-
-            // if `statx()` doesn't provide the mount id (before kernel 5.8),
-            // let's try `name_to_handle_at()`, if everything fails just use 0
-            let mnt_id = stx
-                .mount_id()
-                .or_else(|| get_mount_id(dir, path))
-                .unwrap_or(0);
-            let st = stx
-                .stat64()
-                .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
-            let btime = Some(stx.stx_btime);
-            Ok(StatExt { st, mnt_id, btime })
+            stat_ext(unsafe { stx_ui.assume_init() }, dir, path)
         } else {
             Err(io::Error::last_os_error())
         }
@@ -215,6 +235,8 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
                 st,
                 mnt_id,
                 btime: Some(btime),
+                attributes: 0,
+                attributes_mask: 0,
             })
         } else {
             Err(io::Error::last_os_error())
@@ -0,0 +1,307 @@
+//! io_uring engine for the I/O on opened files, see [`IoEngine::IoUring`](super::IoEngine).
+//!
+//! Requests are submitted by the task serving them and a thread reaps the completions, waking
+//! the tasks waiting for them. The buffers of a request belong to the ring until it completes,
+//! so dropping a request future never leaves the kernel writing to freed memory.
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::Uring;
+#[cfg(not(target_os = "linux"))]
+pub(crate) use other::Uring;
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    use super::super::statx::StatExt;
+
+    /// io_uring isn't available outside of Linux, the engine is never created there.
+    pub(crate) enum Uring {}
+
+    impl Uring {
+        pub fn new(_entries: u32) -> io::Result<Self> {
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+
+        pub async fn read(
+            &self,
+            _fd: &impl AsRawFd,
+            _len: usize,
+            _offset: u64,
+        ) -> io::Result<Vec<u8>> {
+            match *self {}
+        }
+
+        pub async fn write(
+            &self,
+            _fd: &impl AsRawFd,
+            _data: &[u8],
+            _offset: u64,
+        ) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub async fn fsync(&self, _fd: &impl AsRawFd, _datasync: bool) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub async fn statx(&self, _file: &impl AsRawFd) -> io::Result<StatExt> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::io;
+    use std::mem::{self, MaybeUninit};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use io_uring::{IoUring, opcode, squeue, types};
+    use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot};
+    use tracing::{error, warn};
+
+    use super::super::EMPTY_CSTR;
+    use super::super::os_compat::statx_st;
+    use super::super::statx::{STATX_FLAGS, STATX_MASK, StatExt, stat_ext};
+
+    // user_data of the request stopping the reaper.
+    const STOP: u64 = u64::MAX;
+
+    // Memory of a request the kernel uses until it completes.
+    enum Buf {
+        None,
+        Data(Vec<u8>),
+        Statx(Box<MaybeUninit<statx_st>>),
+    }
+
+    struct Op {
+        buf: Buf,
+        done: oneshot::Sender<(i32, Buf)>,
+        _slot: OwnedSemaphorePermit,
+    }
+
+    struct Ring {
+        ring: IoUring,
+        // Held while queueing and submitting a request, the reaper alone takes completions.
+        submit: Mutex<()>,
+        ops: Mutex<HashMap<u64, Op>>,
+        next_op: AtomicU64,
+        // A completion slot per request in flight.
+        slots: Arc<Semaphore>,
+    }
+
+    impl Ring {
+        fn new(entries: u32) -> io::Result<Self> {
+            let ring = IoUring::new(entries)?;
+            let params = ring.params();
+            // Read, write and statx came with the current position feature in Linux 5.6.
+            if !params.is_feature_nodrop() || !params.is_feature_rw_cur_pos() {
+                return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            }
+            let slots = Arc::new(Semaphore::new(params.cq_entries() as usize));
+
+            Ok(Self {
+                ring,
+                submit: Mutex::new(()),
+                ops: Mutex::default(),
+                next_op: AtomicU64::new(0),
+                slots,
+            })
+        }
+
+        // Queue `entry` and submit it. Fails without queueing it when the queue is full.
+        fn push(&self, entry: &squeue::Entry) -> io::Result<()> {
+            let _submit = self.submit.lock().unwrap();
+            // Safe because the queue is only used under `submit`, and the memory the entry
+            // points to is kept by `ops` until it completes.
+            unsafe { self.ring.submission_shared().push(entry) }
+                .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+            // Once queued, the kernel takes the entry the next time the ring is entered, which
+            // only fails before looking at the queue.
+            loop {
+                match self.ring.submit() {
+                    Ok(_) => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) => {
+                        thread::yield_now()
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        async fn submit(&self, entry: squeue::Entry, buf: Buf) -> io::Result<(i32, Buf)> {
+            let slot = self.slots.clone().acquire_owned().await.unwrap();
+            let (done, completed) = oneshot::channel();
+            let id = self.next_op.fetch_add(1, Ordering::Relaxed);
+            self.ops.lock().unwrap().insert(
+                id,
+                Op {
+                    buf,
+                    done,
+                    _slot: slot,
+                },
+            );
+            if let Err(e) = self.push(&entry.user_data(id)) {
+                // Still queued unless the queue was full, its memory is left to the ring then.
+                if e.raw_os_error() == Some(libc::EBUSY) {
+                    self.ops.lock().unwrap().remove(&id);
+                }
+                return Err(e);
+            }
+
+            completed
+                .await
+                .map_err(|_| io::Error::other("io_uring completion lost"))
+        }
+
+        // Complete requests until stopped with nothing in flight.
+        fn reap(&self) {
+            let mut stopping = false;
+            loop {
+                match self.ring.submitter().submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("io_uring: waiting for completions failed: {e}");
+                        // The kernel may still use the buffers of the requests in flight, leak
+                        // them and fail their waiters.
+                        for (_, op) in self.ops.lock().unwrap().drain() {
+                            mem::forget(op.buf);
+                        }
+                        return;
+                    }
+                }
+
+                // Safe because only this thread takes completions.
+                for cqe in unsafe { self.ring.completion_shared() } {
+                    if cqe.user_data() == STOP {
+                        stopping = true;
+                    } else if let Some(op) = self.ops.lock().unwrap().remove(&cqe.user_data()) {
+                        let _ = op.done.send((cqe.result(), op.buf));
+                    }
+                }
+
+                if stopping && self.ops.lock().unwrap().is_empty() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// An io_uring and the thread reaping its completions.
+    pub(crate) struct Uring {
+        ring: Arc<Ring>,
+    }
+
+    impl Uring {
+        /// Set up a ring of `entries` submissions.
+        pub fn new(entries: u32) -> io::Result<Self> {
+            let ring = Arc::new(Ring::new(entries)?);
+            let reaper = ring.clone();
+            thread::Builder::new()
+                .name("passthrough-uring".to_string())
+                .spawn(move || reaper.reap())?;
+
+            Ok(Self { ring })
+        }
+
+        /// Read up to `len` bytes of `fd` at `offset`.
+        pub async fn read(
+            &self,
+            fd: &impl AsRawFd,
+            len: usize,
+            offset: u64,
+        ) -> io::Result<Vec<u8>> {
+            let mut data = vec![0u8; len];
+            let entry = opcode::Read::new(types::Fd(fd.as_raw_fd()), data.as_mut_ptr(), len as u32)
+                .offset(offset)
+                .build();
+            let (res, buf) = self.ring.submit(entry, Buf::Data(data)).await?;
+            let Buf::Data(mut data) = buf else {
+                unreachable!()
+            };
+            data.truncate(result(res)? as usize);
+
+            Ok(data)
+        }
+
+        /// Write `data` to `fd` at `offset`.
+        pub async fn write(
+            &self,
+            fd: &impl AsRawFd,
+            data: &[u8],
+            offset: u64,
+        ) -> io::Result<usize> {
+            // Copied, the caller's buffer may go away before the write completes.
+            let data = data.to_vec();
+            let entry =
+                opcode::Write::new(types::Fd(fd.as_raw_fd()), data.as_ptr(), data.len() as u32)
+                    .offset(offset)
+                    .build();
+            let (res, _) = self.ring.submit(entry, Buf::Data(data)).await?;
+
+            Ok(result(res)? as usize)
+        }
+
+        /// Sync the data of `fd`, and its metadata unless `datasync`.
+        pub async fn fsync(&self, fd: &impl AsRawFd, datasync: bool) -> io::Result<()> {
+            let flags = if datasync {
+                types::FsyncFlags::DATASYNC
+            } else {
+                types::FsyncFlags::empty()
+            };
+            let entry = opcode::Fsync::new(types::Fd(fd.as_raw_fd()))
+                .flags(flags)
+                .build();
+            let (res, _) = self.ring.submit(entry, Buf::None).await?;
+            result(res)?;
+
+            Ok(())
+        }
+
+        /// Like [`statx`](super::super::statx::statx) on an opened file.
+        pub async fn statx(&self, file: &impl AsRawFd) -> io::Result<StatExt> {
+            // Safe because this is a constant value and a valid C string.
+            let path = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+            let mut stx = Box::new(MaybeUninit::<statx_st>::zeroed());
+            let entry = opcode::Statx::new(
+                types::Fd(file.as_raw_fd()),
+                path.as_ptr(),
+                stx.as_mut_ptr() as *mut types::statx,
+            )
+            .flags(STATX_FLAGS)
+            .mask(STATX_MASK)
+            .build();
+            let (res, buf) = self.ring.submit(entry, Buf::Statx(stx)).await?;
+            result(res)?;
+            let Buf::Statx(stx) = buf else { unreachable!() };
+
+            // Safe because the kernel filled it.
+            stat_ext(unsafe { stx.assume_init_read() }, file, path)
+        }
+    }
+
+    impl Drop for Uring {
+        fn drop(&mut self) {
+            if let Err(e) = self.ring.push(&opcode::Nop::new().build().user_data(STOP)) {
+                warn!("io_uring: failed to stop the reaper: {e}");
+            }
+        }
+    }
+
+    fn result(res: i32) -> io::Result<u32> {
+        if res < 0 {
+            Err(io::Error::from_raw_os_error(-res))
+        } else {
+            Ok(res as u32)
+        }
+    }
+}
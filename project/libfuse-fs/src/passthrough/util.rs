@@ -250,6 +250,146 @@ pub fn stat_fd(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<stat64> {
     }
 }
 
+// Names in the directory `dir`, other than `.` and `..`.
+fn dir_entries(dir: &File) -> io::Result<Vec<CString>> {
+    // Safe because we check the return value, the stream owns the duplicated fd from now on.
+    let fd = unsafe { libc::fcntl(dir.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    let mut names = Vec::new();
+    loop {
+        // Safe because the stream is valid until closed below, and the entry until the next
+        // call. The end of the stream and errors are told apart by errno.
+        unsafe { *errno_location() = 0 };
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            let errno = unsafe { *errno_location() };
+            unsafe { libc::closedir(stream) };
+            return match errno {
+                0 => Ok(names),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            };
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::__errno_location() }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::__error() }
+}
+
+// Whether `name` in `dir` is a 0/0 char device, or a zero-length file marked with the
+// `whiteout_xattr` xattr.
+fn is_whiteout_entry(dir: &File, name: &CStr, whiteout_xattr: &CStr) -> io::Result<bool> {
+    let st = stat_fd(dir, Some(name))?;
+    let kind = st.st_mode as u32 & libc::S_IFMT as u32;
+    if kind == libc::S_IFCHR as u32 {
+        return Ok(st.st_rdev == 0);
+    }
+    if kind != libc::S_IFREG as u32 || st.st_size != 0 {
+        return Ok(false);
+    }
+    let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    // Safe because a null buffer only asks for the size of the value.
+    #[cfg(target_os = "linux")]
+    let res = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            whiteout_xattr.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let res = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            whiteout_xattr.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            0,
+            0,
+        )
+    };
+    Ok(res >= 0)
+}
+
+/// Remove the directory `name` of `parent` with the whiteouts in it, see
+/// [`PassthroughFs::do_remove_whiteout_dir`](super::PassthroughFs::do_remove_whiteout_dir).
+pub fn remove_whiteout_dir(parent: &File, name: &CStr, whiteout_xattr: &CStr) -> io::Result<()> {
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let dir = unsafe { File::from_raw_fd(fd) };
+
+    // Everything is checked first, so nothing is uncovered when the directory isn't removed.
+    let entries = dir_entries(&dir)?;
+    for entry in &entries {
+        if !is_whiteout_entry(&dir, entry, whiteout_xattr)? {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+    }
+    for entry in &entries {
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::unlinkat(dir.as_raw_fd(), entry.as_ptr(), 0) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOENT) {
+                return Err(err);
+            }
+        }
+    }
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove `name` of `parent` and, if it is a directory, everything in it. Symlinks are
+/// removed, never followed.
+pub fn remove_tree(parent: &File, name: &CStr) -> io::Result<()> {
+    if is_dir(stat_fd(parent, Some(name))?.st_mode as u32) {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let dir = openat(parent, name, flags, 0)?;
+        for entry in dir_entries(&dir)? {
+            remove_tree(&dir, &entry)?;
+        }
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    } else if unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) } < 0 {
+        // Safe for the same reasons.
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// Returns true if it's safe to open this inode without O_PATH.
 pub fn is_safe_inode(mode: u32) -> bool {
     // Only regular files and directories are considered safe to be opened from the file
@@ -274,6 +414,19 @@ pub fn einval() -> io::Error {
 pub fn enosys() -> io::Error {
     io::Error::from_raw_os_error(libc::ENOSYS)
 }
+/// Whether the effective capability set of this process holds `cap`, e.g. 27 for
+/// `CAP_MKNOD`. False when it can't be read.
+pub fn has_capability(cap: u32) -> bool {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << cap) != 0)
+}
+
 #[allow(unused)]
 pub fn eperm() -> io::Error {
     io::Error::from_raw_os_error(libc::EPERM)
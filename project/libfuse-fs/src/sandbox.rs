@@ -0,0 +1,642 @@
+//! Confinement of a process serving a FUSE mount.
+//!
+//! Once its layers are open and the filesystem is mounted, a daemon only needs to read and
+//! write beneath its layer roots. A [`Sandbox`] makes sure that's all a compromised daemon
+//! can do:
+//!
+//! - Landlock rules limit opening and changing files to the configured directories. Files
+//!   opened before, like `/dev/fuse` and the layer roots themselves, keep working.
+//! - A seccomp filter only lets through the system calls needed to serve files, threads,
+//!   memory, timers and sockets included, and refuses the others with `EPERM`: running
+//!   programs, tracing other processes, loading modules or BPF, changing mounts, entering or
+//!   creating namespaces, and io_uring, whose operations would bypass the filter.
+//!
+//! Both restrict a thread and the threads it starts afterwards, and can't be lifted. They
+//! can't be added to a running thread, so every thread has to confine itself before it runs
+//! anything: [`Sandbox::on_thread_start`] does so for the threads of a tokio runtime, and
+//! [`Sandbox::apply`] for the thread that mounted the filesystem, once the mount is up.
+//! Mounting and unmounting are refused as well, so the sandbox is meant for a process
+//! dedicated to serving a mount, which is left to whoever started the daemon to unmount.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tracing::error;
+
+/// Directories a sandboxed process keeps access to, see the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow reading the files beneath `path`.
+    pub fn read_only(mut self, path: impl AsRef<Path>) -> Self {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allow reading, writing, creating and removing files beneath `path`.
+    pub fn read_write(mut self, path: impl AsRef<Path>) -> Self {
+        self.read_write.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Confine the calling thread, and the threads it starts from now on.
+    ///
+    /// Fails when another thread of the process isn't confined yet, as it would be left
+    /// unrestricted, when the kernel lacks Landlock, before Linux 5.13 or when disabled, when
+    /// a directory can't be opened, or when the kernel refuses the seccomp filter. The calling
+    /// thread is left unconfined in all these cases.
+    pub fn apply(&self) -> io::Result<()> {
+        // Everything that can fail for lack of support is checked before the filter, which
+        // can't be removed, is installed.
+        let confinement = Confinement::new(self)?;
+        let unconfined = unconfined_threads()?;
+        if !unconfined.is_empty() {
+            return Err(io::Error::other(format!(
+                "threads {unconfined:?} were started before the sandbox"
+            )));
+        }
+
+        confinement.enforce()
+    }
+
+    /// A hook for `tokio::runtime::Builder::on_thread_start`, confining every thread of the
+    /// runtime before it runs anything. Fails like [`Sandbox::apply`] when the sandbox isn't
+    /// available. A thread failing to confine itself later on aborts the process rather
+    /// than serving unconfined.
+    pub fn on_thread_start(&self) -> io::Result<impl Fn() + Send + Sync + 'static> {
+        let confinement = Arc::new(Confinement::new(self)?);
+        Ok(move || {
+            if let Err(e) = confinement.enforce() {
+                error!("failed to sandbox thread: {e}");
+                std::process::abort();
+            }
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Confinement;
+
+#[cfg(not(target_os = "linux"))]
+impl Confinement {
+    fn new(_: &Sandbox) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sandboxing is only available on Linux",
+        ))
+    }
+
+    fn enforce(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unconfined_threads() -> io::Result<Vec<i32>> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "linux")]
+use linux::{Confinement, unconfined_threads};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io;
+
+    use landlock::{
+        ABI, Access, AccessFs, CompatLevel, Compatible, PathBeneath, PathFd, Ruleset, RulesetAttr,
+        RulesetCreated, RulesetCreatedAttr,
+    };
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule, TargetArch,
+    };
+
+    use super::Sandbox;
+
+    // Newest Landlock rights handled, on kernels knowing them.
+    const LANDLOCK_ABI: ABI = ABI::V5;
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+
+    const NAMESPACE_FLAGS: u64 = (libc::CLONE_NEWNS
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWCGROUP) as u64;
+
+    // Everything else is refused, `clone` only without namespace flags.
+    const ALLOWED: &[libc::c_long] = &[
+        // Files.
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_preadv,
+        libc::SYS_pwritev,
+        libc::SYS_preadv2,
+        libc::SYS_pwritev2,
+        libc::SYS_lseek,
+        libc::SYS_close,
+        libc::SYS_close_range,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_fcntl,
+        libc::SYS_flock,
+        libc::SYS_ioctl,
+        libc::SYS_openat,
+        libc::SYS_openat2,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_newfstatat,
+        libc::SYS_fstat,
+        libc::SYS_statx,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_mkdirat,
+        libc::SYS_mknodat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_linkat,
+        libc::SYS_symlinkat,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_utimensat,
+        libc::SYS_truncate,
+        libc::SYS_ftruncate,
+        libc::SYS_fallocate,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_sync_file_range,
+        libc::SYS_syncfs,
+        libc::SYS_fadvise64,
+        libc::SYS_readahead,
+        libc::SYS_copy_file_range,
+        libc::SYS_splice,
+        libc::SYS_tee,
+        libc::SYS_sendfile,
+        libc::SYS_getxattr,
+        libc::SYS_lgetxattr,
+        libc::SYS_fgetxattr,
+        libc::SYS_setxattr,
+        libc::SYS_lsetxattr,
+        libc::SYS_fsetxattr,
+        libc::SYS_listxattr,
+        libc::SYS_llistxattr,
+        libc::SYS_flistxattr,
+        libc::SYS_removexattr,
+        libc::SYS_lremovexattr,
+        libc::SYS_fremovexattr,
+        libc::SYS_getcwd,
+        libc::SYS_chdir,
+        libc::SYS_fchdir,
+        libc::SYS_umask,
+        // Memory.
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_msync,
+        libc::SYS_mincore,
+        libc::SYS_membarrier,
+        // Threads, signals and the credentials switched to create files on behalf of callers.
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_get_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_set_tid_address,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_wait4,
+        libc::SYS_gettid,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_tgkill,
+        libc::SYS_tkill,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_prctl,
+        libc::SYS_sigaltstack,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_restart_syscall,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_getresuid,
+        libc::SYS_getresgid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_getgroups,
+        libc::SYS_capget,
+        libc::SYS_uname,
+        libc::SYS_getrlimit,
+        libc::SYS_prlimit64,
+        libc::SYS_getrusage,
+        libc::SYS_sysinfo,
+        // Time and waiting.
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_pwait2,
+        libc::SYS_eventfd2,
+        libc::SYS_pipe2,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_timerfd_create,
+        libc::SYS_timerfd_settime,
+        libc::SYS_timerfd_gettime,
+        // Sockets, e.g. of the metrics endpoint.
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_shutdown,
+    ];
+
+    // Older calls, replaced by the ones above on newer architectures.
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[
+        libc::SYS_open,
+        libc::SYS_creat,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_getdents,
+        libc::SYS_readlink,
+        libc::SYS_mkdir,
+        libc::SYS_rmdir,
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_link,
+        libc::SYS_symlink,
+        libc::SYS_chmod,
+        libc::SYS_chown,
+        libc::SYS_lchown,
+        libc::SYS_utimes,
+        libc::SYS_dup2,
+        libc::SYS_pipe,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_create,
+        libc::SYS_epoll_wait,
+        libc::SYS_eventfd,
+        libc::SYS_accept,
+        libc::SYS_time,
+        libc::SYS_arch_prctl,
+    ];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+    /// The rules of a [`Sandbox`], ready to be enforced on any number of threads.
+    pub(super) struct Confinement {
+        ruleset: RulesetCreated,
+        filters: [BpfProgram; 2],
+    }
+
+    impl Confinement {
+        pub(super) fn new(sandbox: &Sandbox) -> io::Result<Self> {
+            Ok(Self {
+                ruleset: ruleset(sandbox)?,
+                filters: filters()?,
+            })
+        }
+
+        // Landlock comes first, as the seccomp filter refuses its calls. A thread started by a
+        // confined one is left as it is.
+        pub(super) fn enforce(&self) -> io::Result<()> {
+            if inherited() {
+                return Ok(());
+            }
+            self.ruleset
+                .try_clone()?
+                .restrict_self()
+                .map_err(io::Error::other)?;
+            for filter in &self.filters {
+                seccompiler::apply_filter(filter).map_err(io::Error::other)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    // Whether the calling thread is confined already. Landlock is known to be available, so
+    // asking for its version only fails when the filter refuses it.
+    fn inherited() -> bool {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<u8>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+
+        res < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn ruleset(sandbox: &Sandbox) -> io::Result<RulesetCreated> {
+        // The rights of the first ABI are required, newer ones handled when the kernel knows
+        // them.
+        let ruleset = Ruleset::default()
+            .set_compatibility(CompatLevel::HardRequirement)
+            .handle_access(AccessFs::from_all(ABI::V1))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Landlock is unavailable: {e}"),
+                )
+            })?
+            .set_compatibility(CompatLevel::BestEffort)
+            .handle_access(AccessFs::from_all(LANDLOCK_ABI))
+            .map_err(io::Error::other)?;
+        let mut ruleset = ruleset.create().map_err(io::Error::other)?;
+
+        let read_only = AccessFs::from_read(LANDLOCK_ABI);
+        let rules = sandbox
+            .read_only
+            .iter()
+            .map(|path| (path.as_path(), read_only))
+            .chain(
+                sandbox
+                    .read_write
+                    .iter()
+                    .map(|path| (path.as_path(), AccessFs::from_all(LANDLOCK_ABI))),
+            )
+            // The mount table, read to open file handles of the layers.
+            .chain([(std::path::Path::new("/proc/self"), read_only)]);
+        for (path, access) in rules {
+            let rule = PathFd::new(path)
+                .map(|fd| PathBeneath::new(fd, access))
+                .map_err(|e| {
+                    io::Error::other(format!("can't allow access to {}: {e}", path.display()))
+                })?;
+            ruleset = ruleset.add_rule(rule).map_err(io::Error::other)?;
+        }
+
+        Ok(ruleset)
+    }
+
+    fn filters() -> io::Result<[BpfProgram; 2]> {
+        let arch = TargetArch::try_from(std::env::consts::ARCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e.to_string()))?;
+        let no_namespace = SeccompRule::new(vec![
+            SeccompCondition::new(
+                0,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::MaskedEq(NAMESPACE_FLAGS),
+                0,
+            )
+            .map_err(io::Error::other)?,
+        ])
+        .map_err(io::Error::other)?;
+        // The flags of clone3 can't be checked, without it the C library falls back to clone.
+        // The kernel returns the error of any filter refusing a call, whatever the others say,
+        // and the later filter can't be installed once the allowlist refuses `seccomp`.
+        let no_clone3 = SeccompFilter::new(
+            BTreeMap::from([(libc::SYS_clone3, Vec::new())]),
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::ENOSYS as u32),
+            arch,
+        )
+        .map_err(io::Error::other)?;
+
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> = ALLOWED
+            .iter()
+            .chain(ALLOWED_LEGACY)
+            .chain(&[libc::SYS_clone3])
+            .map(|&nr| (nr, Vec::new()))
+            .collect();
+        rules.insert(libc::SYS_clone, vec![no_namespace]);
+        // Calls of another ABI, like x32 ones, don't match any number and are refused. The
+        // filter kills the process on a call through another architecture's entry point.
+        let allowed = SeccompFilter::new(
+            rules,
+            SeccompAction::Errno(libc::EPERM as u32),
+            SeccompAction::Allow,
+            arch,
+        )
+        .map_err(io::Error::other)?;
+
+        Ok([
+            no_clone3.try_into().map_err(io::Error::other)?,
+            allowed.try_into().map_err(io::Error::other)?,
+        ])
+    }
+
+    // Seccomp filters of thread `tid`, falling back to whether it has any before Linux 5.9.
+    fn seccomp_filters(tid: &str) -> io::Result<Option<u32>> {
+        let status = match fs::read_to_string(format!("/proc/self/task/{tid}/status")) {
+            Ok(status) => status,
+            // Gone already.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|value| value.trim().parse::<u32>().ok())
+        };
+
+        Ok(field("Seccomp_filters:").or_else(|| field("Seccomp:").map(|mode| (mode == 2) as u32)))
+    }
+
+    // Threads other than the calling one which didn't add filters to those the calling thread
+    // has, and so didn't confine themselves.
+    pub(super) fn unconfined_threads() -> io::Result<Vec<i32>> {
+        let me = unsafe { libc::syscall(libc::SYS_gettid) }.to_string();
+        let Some(own) = seccomp_filters(&me)? else {
+            return Err(io::Error::other(
+                "can't read the seccomp status of the thread",
+            ));
+        };
+
+        let mut unconfined = Vec::new();
+        for entry in fs::read_dir("/proc/self/task")? {
+            let name = entry?.file_name();
+            let Some(tid) = name.to_str().filter(|tid| *tid != me) else {
+                continue;
+            };
+            if seccomp_filters(tid)?.is_some_and(|filters| filters <= own) {
+                unconfined.extend(tid.parse::<i32>().ok());
+            }
+        }
+
+        Ok(unconfined)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    use super::Sandbox;
+
+    const DIR_ENV: &str = "LIBFUSE_FS_SANDBOX_TEST_DIR";
+
+    // The sandbox can't be lifted, so it is applied in a copy of the test binary running only
+    // `sandboxed`.
+    #[test]
+    fn test_sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "sandbox::tests::sandboxed", "--ignored"])
+            .args(["--nocapture", "--test-threads=1"])
+            .env(DIR_ENV, dir.path())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(dir.path().join("inside").exists());
+    }
+
+    // Run `f` in a child forked from the test thread, the only thread of the child, while the
+    // test harness keeps a thread of its own.
+    fn in_child(f: impl FnOnce()) {
+        match unsafe { libc::fork() } {
+            0 => {
+                let res = panic::catch_unwind(AssertUnwindSafe(f));
+                unsafe { libc::_exit(res.is_err() as libc::c_int) }
+            }
+            pid => {
+                assert!(pid > 0, "{}", std::io::Error::last_os_error());
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "run by test_sandbox"]
+    fn sandboxed() {
+        let Some(dir) = std::env::var_os(DIR_ENV) else {
+            return;
+        };
+        let dir = Path::new(&dir);
+        let sandbox = Sandbox::new().read_write(dir);
+
+        let on_thread_start = match sandbox.on_thread_start() {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                println!("skipping: {e}");
+                return;
+            }
+            res => res.unwrap(),
+        };
+        in_child(|| {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .on_thread_start(on_thread_start)
+                .build()
+                .unwrap();
+            // The threads of the runtime are confined before the calling thread, including
+            // the blocking thread started by a worker, which inherits its confinement.
+            let err = runtime
+                .block_on(runtime.spawn(async {
+                    tokio::task::spawn_blocking(|| std::fs::read("/etc/passwd").unwrap_err()).await
+                }))
+                .unwrap()
+                .unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+            assert!(std::fs::read("/etc/passwd").is_ok());
+
+            sandbox.apply().unwrap();
+            std::fs::write(dir.join("inside"), b"data").unwrap();
+            let err = std::fs::read("/etc/passwd").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+            // Threads started afterwards are confined as well.
+            let err = std::thread::spawn(|| std::fs::read("/etc/passwd").unwrap_err())
+                .join()
+                .unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+            let err = Command::new("/bin/true").status().unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            let res = unsafe { libc::unshare(libc::CLONE_NEWUSER) };
+            assert_eq!(res, -1);
+            let mut params = [0u8; 120];
+            let res = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1, params.as_mut_ptr()) };
+            assert_eq!(res, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EPERM)
+            );
+        });
+    }
+
+    #[test]
+    fn test_sandbox_refused_with_running_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        // Started before the sandbox, which can't reach it anymore.
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || stopped.recv().unwrap());
+
+        match Sandbox::new().read_write(dir.path()).apply() {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                println!("skipping: {e}");
+            }
+            res => {
+                let err = res.unwrap_err();
+                assert!(err.to_string().contains("before the sandbox"), "{err}");
+            }
+        }
+        // Nothing was applied.
+        assert!(std::fs::read("/etc/passwd").is_ok());
+        assert!(Command::new("/bin/true").status().unwrap().success());
+
+        stop.send(()).unwrap();
+        thread.join().unwrap();
+    }
+}
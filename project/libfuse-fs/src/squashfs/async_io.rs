@@ -0,0 +1,339 @@
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStrExt;
+
+use bytes::Bytes;
+use futures::stream;
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result};
+
+use super::image::InodeData;
+use super::{SquashfsLayer, TTL};
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+impl Filesystem for SquashfsLayer {
+    /// initialize filesystem. Called before any other filesystem method.
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    /// clean up filesystem. Nothing to do for a read-only image.
+    async fn destroy(&self, _req: Request) {}
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let dir = self.get(parent)?;
+        let entry = self
+            .image
+            .read_dir(&dir)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let inode = self.inode_of(entry.inode_ref);
+        let info = self.image.inode(entry.inode_ref)?;
+        if entry.kind == FileType::Directory {
+            self.parents.lock().unwrap().insert(inode, parent);
+        }
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: self.attr(inode, &info),
+            generation: 0,
+        })
+    }
+
+    /// get file attributes.
+    async fn getattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        let info = self.get(inode)?;
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.attr(inode, &info),
+        })
+    }
+
+    /// set file attributes, never allowed on an image.
+    async fn setattr(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: Option<u64>,
+        _set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        Err(erofs().into())
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
+        match self.get(inode)?.data {
+            InodeData::Symlink(target) => Ok(ReplyData {
+                data: Bytes::from(target),
+            }),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL).into()),
+        }
+    }
+
+    /// open a file. Reads don't need any per-open state, so no handle is allocated.
+    async fn open(&self, _req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0 {
+            return Err(erofs().into());
+        }
+        let info = self.get(inode)?;
+        if info.kind == FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read data.
+    async fn read(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let info = self.get(inode)?;
+        let data = self.image.read_file(&info, offset, size as usize)?;
+        Ok(ReplyData {
+            data: Bytes::from(data),
+        })
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// flush method, nothing is ever buffered.
+    async fn flush(&self, _req: Request, _inode: Inode, _fh: u64, _lock_owner: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// get filesystem statistics.
+    async fn statfs(&self, _req: Request, _inode: Inode) -> Result<ReplyStatFs> {
+        let bsize = self.image.block_size();
+        Ok(ReplyStatFs {
+            blocks: self.image.bytes_used().div_ceil(bsize as u64),
+            bfree: 0,
+            bavail: 0,
+            files: self.image.inode_count() as u64,
+            ffree: 0,
+            bsize,
+            namelen: 256,
+            frsize: bsize,
+        })
+    }
+
+    /// get an extended attribute.
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let info = self.get(inode)?;
+        let value = self
+            .image
+            .xattrs(&info)?
+            .into_iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(value.len() as u32));
+        }
+        if value.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(value)))
+    }
+
+    /// list extended attribute names.
+    async fn listxattr(&self, _req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        let info = self.get(inode)?;
+        let mut names = Vec::new();
+        for (name, _) in self.image.xattrs(&info)? {
+            names.extend_from_slice(&name);
+            names.push(0);
+        }
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(names.len() as u32));
+        }
+        if names.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(names)))
+    }
+
+    /// open a directory.
+    async fn opendir(&self, _req: Request, inode: Inode, _flags: u32) -> Result<ReplyOpen> {
+        if self.get(inode)?.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read directory.
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        let dir = self.get(parent)?;
+        let mut entries = vec![
+            (parent, FileType::Directory, ".".into()),
+            (self.parent_of(parent), FileType::Directory, "..".into()),
+        ];
+        for entry in self.image.read_dir(&dir)? {
+            entries.push((self.inode_of(entry.inode_ref), entry.kind, entry.name));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name,
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, _req: Request, _inode: Inode, _fh: u64, _flags: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// check file access permissions. Only writes are refused, permission bits are left to
+    /// the kernel.
+    async fn access(&self, _req: Request, inode: Inode, mask: u32) -> Result<()> {
+        self.get(inode)?;
+        if mask as i32 & libc::W_OK != 0 {
+            return Err(erofs().into());
+        }
+        Ok(())
+    }
+
+    /// test for a POSIX file lock. Locks are not supported on an image.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// acquire, modify or release a POSIX file lock. Locks are not supported on an image.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+        _block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// read directory entries, but with their attribute, like [`readdir`][Filesystem::readdir]
+    /// + [`lookup`][Filesystem::lookup] at the same time.
+    async fn readdirplus<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        let dir = self.get(parent)?;
+        let grandparent = self.parent_of(parent);
+        let mut entries = vec![
+            (parent, ".".into(), dir.clone()),
+            (grandparent, "..".into(), self.get(grandparent)?),
+        ];
+        for entry in self.image.read_dir(&dir)? {
+            let inode = self.inode_of(entry.inode_ref);
+            if entry.kind == FileType::Directory {
+                self.parents.lock().unwrap().insert(inode, parent);
+            }
+            entries.push((inode, entry.name, self.image.inode(entry.inode_ref)?));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, name, info))| {
+                Ok(DirectoryEntryPlus {
+                    inode,
+                    generation: 0,
+                    kind: info.kind,
+                    name,
+                    offset: i as i64 + 1,
+                    attr: self.attr(inode, &info),
+                    entry_ttl: TTL,
+                    attr_ttl: TTL,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+}
@@ -0,0 +1,663 @@
+//! Reader for the on-disk squashfs 4.0 format.
+//!
+//! See <https://dr-emann.github.io/squashfs/> for a description of the layout.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rfuse3::FileType;
+
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_SIZE: usize = 8192;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+const NO_XATTR: u32 = 0xffff_ffff;
+const NO_TABLE: u64 = 0xffff_ffff_ffff_ffff;
+// Set in a data or fragment block size when the block is stored uncompressed.
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+// Set in a metadata block header when the block is stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 1 << 15;
+// Set in an xattr type when the value is stored out of line.
+const XATTR_VALUE_OOL: u16 = 0x100;
+
+const COMPRESSION_ZLIB: u16 = 1;
+
+// Cached decompressed blocks, cleared once full.
+const METADATA_CACHE_SIZE: usize = 1024;
+const FRAGMENT_CACHE_SIZE: usize = 64;
+
+/// Kind specific part of an inode.
+#[derive(Clone, Debug)]
+pub(super) enum InodeData {
+    Dir {
+        start_block: u32,
+        offset: u16,
+        // Listing size plus 3, as in the on-disk inode.
+        size: u32,
+    },
+    File {
+        blocks_start: u64,
+        size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: Vec<u32>,
+    },
+    Symlink(Vec<u8>),
+    Device(u32),
+    Ipc,
+}
+
+/// An inode read from the inode table.
+#[derive(Clone, Debug)]
+pub(super) struct InodeInfo {
+    pub kind: FileType,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub nlink: u32,
+    pub xattr: u32,
+    pub data: InodeData,
+}
+
+impl InodeInfo {
+    pub fn size(&self) -> u64 {
+        match &self.data {
+            InodeData::Dir { size, .. } => *size as u64,
+            InodeData::File { size, .. } => *size,
+            InodeData::Symlink(target) => target.len() as u64,
+            InodeData::Device(_) | InodeData::Ipc => 0,
+        }
+    }
+
+    pub fn rdev(&self) -> u32 {
+        match self.data {
+            InodeData::Device(rdev) => rdev,
+            _ => 0,
+        }
+    }
+}
+
+/// One entry of a directory listing.
+#[derive(Clone, Debug)]
+pub(super) struct DirEntry {
+    pub name: OsString,
+    pub inode_ref: u64,
+    pub kind: FileType,
+}
+
+struct Superblock {
+    inode_count: u32,
+    block_size: u32,
+    fragment_count: u32,
+    compression: u16,
+    id_count: u16,
+    root_inode: u64,
+    bytes_used: u64,
+    id_table: u64,
+    xattr_table: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+fn le_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn corrupted(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted squashfs image: {what}"),
+    )
+}
+
+impl Superblock {
+    fn parse(buf: &[u8; SUPERBLOCK_SIZE]) -> io::Result<Self> {
+        if le_u32(buf, 0) != SQUASHFS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a squashfs image",
+            ));
+        }
+        let (major, minor) = (le_u16(buf, 28), le_u16(buf, 30));
+        if major != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported squashfs version {major}.{minor}"),
+            ));
+        }
+        let compression = le_u16(buf, 20);
+        if compression != COMPRESSION_ZLIB {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported squashfs compression {compression}, only gzip is supported"),
+            ));
+        }
+        Ok(Superblock {
+            inode_count: le_u32(buf, 4),
+            block_size: le_u32(buf, 12),
+            fragment_count: le_u32(buf, 16),
+            compression,
+            id_count: le_u16(buf, 26),
+            root_inode: le_u64(buf, 32),
+            bytes_used: le_u64(buf, 40),
+            id_table: le_u64(buf, 48),
+            xattr_table: le_u64(buf, 56),
+            inode_table: le_u64(buf, 64),
+            directory_table: le_u64(buf, 72),
+            fragment_table: le_u64(buf, 80),
+        })
+    }
+}
+
+// Decompressed metadata block and position of the next one.
+type MetadataBlock = (Arc<Vec<u8>>, u64);
+
+/// An opened squashfs image.
+pub(super) struct Image {
+    file: File,
+    sb: Superblock,
+    ids: Vec<u32>,
+    // (start, size) of every fragment block.
+    fragments: Vec<(u64, u32)>,
+    // Start of the xattr key/value area and (ref, count) of every xattr id.
+    xattr_kv_start: u64,
+    xattr_ids: Vec<(u64, u32)>,
+    metadata_cache: Mutex<HashMap<u64, MetadataBlock>>,
+    fragment_cache: Mutex<HashMap<u32, Arc<Vec<u8>>>>,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut buf = [0u8; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut buf, 0)?;
+        let sb = Superblock::parse(&buf)?;
+        let mut image = Image {
+            file,
+            sb,
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            xattr_kv_start: 0,
+            xattr_ids: Vec::new(),
+            metadata_cache: Mutex::new(HashMap::new()),
+            fragment_cache: Mutex::new(HashMap::new()),
+        };
+
+        let raw = image.read_lookup_table(image.sb.id_table, image.sb.id_count as usize, 4)?;
+        image.ids = raw.chunks_exact(4).map(|c| le_u32(c, 0)).collect();
+
+        if image.sb.fragment_table != NO_TABLE {
+            let raw = image.read_lookup_table(
+                image.sb.fragment_table,
+                image.sb.fragment_count as usize,
+                16,
+            )?;
+            image.fragments = raw
+                .chunks_exact(16)
+                .map(|c| (le_u64(c, 0), le_u32(c, 8)))
+                .collect();
+        }
+
+        if image.sb.xattr_table != NO_TABLE {
+            let mut header = [0u8; 16];
+            image
+                .file
+                .read_exact_at(&mut header, image.sb.xattr_table)?;
+            image.xattr_kv_start = le_u64(&header, 0);
+            let count = le_u32(&header, 8) as usize;
+            let raw = image.read_lookup_table(image.sb.xattr_table + 16, count, 16)?;
+            image.xattr_ids = raw
+                .chunks_exact(16)
+                .map(|c| (le_u64(c, 0), le_u32(c, 8)))
+                .collect();
+        }
+        Ok(image)
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.sb.block_size
+    }
+
+    pub fn root_inode(&self) -> u64 {
+        self.sb.root_inode
+    }
+
+    pub fn inode_count(&self) -> u32 {
+        self.sb.inode_count
+    }
+
+    pub fn bytes_used(&self) -> u64 {
+        self.sb.bytes_used
+    }
+
+    // Read a table of `count` fixed size entries, stored in metadata blocks whose positions
+    // are listed at `start`.
+    fn read_lookup_table(
+        &self,
+        start: u64,
+        count: usize,
+        entry_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let len = count * entry_size;
+        let blocks = len.div_ceil(METADATA_SIZE);
+        let mut pointers = vec![0u8; blocks * 8];
+        self.file.read_exact_at(&mut pointers, start)?;
+        let mut table = Vec::with_capacity(len);
+        for pointer in pointers.chunks_exact(8) {
+            let want = (len - table.len()).min(METADATA_SIZE);
+            let mut cursor = self.cursor(le_u64(pointer, 0), 0)?;
+            table.extend_from_slice(&cursor.read(want)?);
+        }
+        Ok(table)
+    }
+
+    fn decompress(&self, data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        debug_assert_eq!(self.sb.compression, COMPRESSION_ZLIB);
+        let mut out = Vec::with_capacity(max);
+        flate2::read::ZlibDecoder::new(data)
+            .take(max as u64)
+            .read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn metadata_block(&self, pos: u64) -> io::Result<MetadataBlock> {
+        if let Some(cached) = self.metadata_cache.lock().unwrap().get(&pos) {
+            return Ok(cached.clone());
+        }
+        let mut header = [0u8; 2];
+        self.file.read_exact_at(&mut header, pos)?;
+        let header = u16::from_le_bytes(header);
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+        if size == 0 || size > METADATA_SIZE {
+            return Err(corrupted("metadata block size"));
+        }
+        let mut raw = vec![0u8; size];
+        self.file.read_exact_at(&mut raw, pos + 2)?;
+        let data = if header & METADATA_UNCOMPRESSED != 0 {
+            raw
+        } else {
+            self.decompress(&raw, METADATA_SIZE)?
+        };
+        let block = (Arc::new(data), pos + 2 + size as u64);
+        let mut cache = self.metadata_cache.lock().unwrap();
+        if cache.len() >= METADATA_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(pos, block.clone());
+        Ok(block)
+    }
+
+    fn cursor(&self, pos: u64, offset: usize) -> io::Result<MetadataCursor<'_>> {
+        let (block, next) = self.metadata_block(pos)?;
+        if offset > block.len() {
+            return Err(corrupted("metadata offset"));
+        }
+        Ok(MetadataCursor {
+            image: self,
+            block,
+            next,
+            offset,
+        })
+    }
+
+    fn id(&self, index: u16) -> io::Result<u32> {
+        self.ids
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| corrupted("id index"))
+    }
+
+    /// Read the inode at `inode_ref`, `(block << 16) | offset` within the inode table.
+    pub fn inode(&self, inode_ref: u64) -> io::Result<InodeInfo> {
+        let mut c = self.cursor(
+            self.sb.inode_table + (inode_ref >> 16),
+            (inode_ref & 0xffff) as usize,
+        )?;
+        let inode_type = c.u16()?;
+        let perm = c.u16()? & 0o7777;
+        let uid = self.id(c.u16()?)?;
+        let gid = self.id(c.u16()?)?;
+        let mtime = c.u32()?;
+        let _number = c.u32()?;
+
+        let (kind, nlink, xattr, data) = match inode_type {
+            1 | 8 => {
+                let (start_block, nlink, size, offset, xattr);
+                if inode_type == 1 {
+                    start_block = c.u32()?;
+                    nlink = c.u32()?;
+                    size = c.u16()? as u32;
+                    offset = c.u16()?;
+                    let _parent = c.u32()?;
+                    xattr = NO_XATTR;
+                } else {
+                    nlink = c.u32()?;
+                    size = c.u32()?;
+                    start_block = c.u32()?;
+                    let _parent = c.u32()?;
+                    let _index_count = c.u16()?;
+                    offset = c.u16()?;
+                    xattr = c.u32()?;
+                }
+                let data = InodeData::Dir {
+                    start_block,
+                    offset,
+                    size,
+                };
+                (FileType::Directory, nlink, xattr, data)
+            }
+            2 | 9 => {
+                let (blocks_start, size, nlink, fragment, fragment_offset, xattr);
+                if inode_type == 2 {
+                    blocks_start = c.u32()? as u64;
+                    fragment = c.u32()?;
+                    fragment_offset = c.u32()?;
+                    size = c.u32()? as u64;
+                    nlink = 1;
+                    xattr = NO_XATTR;
+                } else {
+                    blocks_start = c.u64()?;
+                    size = c.u64()?;
+                    let _sparse = c.u64()?;
+                    nlink = c.u32()?;
+                    fragment = c.u32()?;
+                    fragment_offset = c.u32()?;
+                    xattr = c.u32()?;
+                }
+                let block_size = self.sb.block_size as u64;
+                let blocks = if fragment == NO_FRAGMENT {
+                    size.div_ceil(block_size)
+                } else {
+                    size / block_size
+                };
+                let raw = c.read(blocks as usize * 4)?;
+                let data = InodeData::File {
+                    blocks_start,
+                    size,
+                    fragment,
+                    fragment_offset,
+                    block_sizes: raw.chunks_exact(4).map(|b| le_u32(b, 0)).collect(),
+                };
+                (FileType::RegularFile, nlink, xattr, data)
+            }
+            3 | 10 => {
+                let nlink = c.u32()?;
+                let len = c.u32()? as usize;
+                let target = c.read(len)?;
+                let xattr = if inode_type == 10 { c.u32()? } else { NO_XATTR };
+                (FileType::Symlink, nlink, xattr, InodeData::Symlink(target))
+            }
+            4 | 5 | 11 | 12 => {
+                let nlink = c.u32()?;
+                let rdev = c.u32()?;
+                let xattr = if inode_type > 7 { c.u32()? } else { NO_XATTR };
+                let kind = if inode_type % 7 == 4 {
+                    FileType::BlockDevice
+                } else {
+                    FileType::CharDevice
+                };
+                (kind, nlink, xattr, InodeData::Device(rdev))
+            }
+            6 | 7 | 13 | 14 => {
+                let nlink = c.u32()?;
+                let xattr = if inode_type > 7 { c.u32()? } else { NO_XATTR };
+                let kind = if inode_type % 7 == 6 {
+                    FileType::NamedPipe
+                } else {
+                    FileType::Socket
+                };
+                (kind, nlink, xattr, InodeData::Ipc)
+            }
+            _ => return Err(corrupted("inode type")),
+        };
+        Ok(InodeInfo {
+            kind,
+            perm,
+            uid,
+            gid,
+            mtime,
+            nlink,
+            xattr,
+            data,
+        })
+    }
+
+    /// List the directory `dir`, without `.` and `..`.
+    pub fn read_dir(&self, dir: &InodeInfo) -> io::Result<Vec<DirEntry>> {
+        let InodeData::Dir {
+            start_block,
+            offset,
+            size,
+        } = dir.data
+        else {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        };
+        let mut entries = Vec::new();
+        // The stored size counts the `.` and `..` entries squashfs doesn't store.
+        let mut remaining = size.saturating_sub(3) as usize;
+        if remaining == 0 {
+            return Ok(entries);
+        }
+        let mut c = self.cursor(
+            self.sb.directory_table + start_block as u64,
+            offset as usize,
+        )?;
+        while remaining >= 12 {
+            let count = c.u32()? as usize + 1;
+            let start = c.u32()? as u64;
+            let _base_number = c.u32()?;
+            remaining -= 12;
+            for _ in 0..count {
+                let offset = c.u16()? as u64;
+                let _number_delta = c.u16()?;
+                let entry_type = c.u16()?;
+                let name_len = c.u16()? as usize + 1;
+                let name = c.read(name_len)?;
+                remaining = remaining
+                    .checked_sub(8 + name_len)
+                    .ok_or_else(|| corrupted("directory size"))?;
+                entries.push(DirEntry {
+                    name: OsString::from_vec(name),
+                    inode_ref: (start << 16) | offset,
+                    kind: match entry_type {
+                        1 => FileType::Directory,
+                        2 => FileType::RegularFile,
+                        3 => FileType::Symlink,
+                        4 => FileType::BlockDevice,
+                        5 => FileType::CharDevice,
+                        6 => FileType::NamedPipe,
+                        7 => FileType::Socket,
+                        _ => return Err(corrupted("directory entry type")),
+                    },
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Decompressed content of data block or fragment block stored at `pos` with `size` as
+    // written in the image.
+    fn data_block(&self, pos: u64, size: u32) -> io::Result<Vec<u8>> {
+        let on_disk = (size & !BLOCK_UNCOMPRESSED) as usize;
+        let mut raw = vec![0u8; on_disk];
+        self.file.read_exact_at(&mut raw, pos)?;
+        if size & BLOCK_UNCOMPRESSED != 0 {
+            Ok(raw)
+        } else {
+            self.decompress(&raw, self.sb.block_size as usize)
+        }
+    }
+
+    fn fragment_block(&self, index: u32) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(block) = self.fragment_cache.lock().unwrap().get(&index) {
+            return Ok(block.clone());
+        }
+        let (start, size) = *self
+            .fragments
+            .get(index as usize)
+            .ok_or_else(|| corrupted("fragment index"))?;
+        let block = Arc::new(self.data_block(start, size)?);
+        let mut cache = self.fragment_cache.lock().unwrap();
+        if cache.len() >= FRAGMENT_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(index, block.clone());
+        Ok(block)
+    }
+
+    /// Read up to `len` bytes of the regular file `inode` starting at `offset`.
+    pub fn read_file(&self, inode: &InodeInfo, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let InodeData::File {
+            blocks_start,
+            size,
+            fragment,
+            fragment_offset,
+            ref block_sizes,
+        } = inode.data
+        else {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        };
+        if offset >= size {
+            return Ok(Vec::new());
+        }
+        let end = size.min(offset + len as u64);
+        let block_size = self.sb.block_size as u64;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+
+        let first = (offset / block_size) as usize;
+        let mut pos = blocks_start
+            + block_sizes[..first.min(block_sizes.len())]
+                .iter()
+                .map(|s| (s & !BLOCK_UNCOMPRESSED) as u64)
+                .sum::<u64>();
+        let mut index = first;
+        while (out.len() as u64) < end - offset {
+            let block_start = index as u64 * block_size;
+            let block_len = block_size.min(size - block_start) as usize;
+            let block = if let Some(&stored) = block_sizes.get(index) {
+                let on_disk = (stored & !BLOCK_UNCOMPRESSED) as u64;
+                let block = if on_disk == 0 {
+                    // Sparse block.
+                    vec![0u8; block_len]
+                } else {
+                    self.data_block(pos, stored)?
+                };
+                pos += on_disk;
+                block
+            } else if fragment != NO_FRAGMENT {
+                let frag = self.fragment_block(fragment)?;
+                let start = fragment_offset as usize;
+                frag.get(start..start + block_len)
+                    .ok_or_else(|| corrupted("fragment offset"))?
+                    .to_vec()
+            } else {
+                return Err(corrupted("file size"));
+            };
+            if block.len() < block_len {
+                return Err(corrupted("data block size"));
+            }
+            let from = (offset + out.len() as u64 - block_start) as usize;
+            let to = ((end - block_start) as usize).min(block_len);
+            out.extend_from_slice(&block[from..to]);
+            index += 1;
+        }
+        Ok(out)
+    }
+
+    /// All xattrs of `inode`, with their full names.
+    pub fn xattrs(&self, inode: &InodeInfo) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if inode.xattr == NO_XATTR {
+            return Ok(Vec::new());
+        }
+        let (xattr_ref, count) = *self
+            .xattr_ids
+            .get(inode.xattr as usize)
+            .ok_or_else(|| corrupted("xattr index"))?;
+        let mut c = self.cursor(
+            self.xattr_kv_start + (xattr_ref >> 16),
+            (xattr_ref & 0xffff) as usize,
+        )?;
+        let mut xattrs = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let xattr_type = c.u16()?;
+            let name_len = c.u16()? as usize;
+            let mut name = match xattr_type & 0xff {
+                0 => b"user.".to_vec(),
+                1 => b"trusted.".to_vec(),
+                2 => b"security.".to_vec(),
+                _ => return Err(corrupted("xattr type")),
+            };
+            name.extend_from_slice(&c.read(name_len)?);
+            let value_len = c.u32()? as usize;
+            let mut value = c.read(value_len)?;
+            if xattr_type & XATTR_VALUE_OOL != 0 {
+                if value.len() != 8 {
+                    return Err(corrupted("xattr value reference"));
+                }
+                let value_ref = le_u64(&value, 0);
+                let mut v = self.cursor(
+                    self.xattr_kv_start + (value_ref >> 16),
+                    (value_ref & 0xffff) as usize,
+                )?;
+                let len = v.u32()? as usize;
+                value = v.read(len)?;
+            }
+            xattrs.push((name, value));
+        }
+        Ok(xattrs)
+    }
+}
+
+// Reads a byte stream spanning consecutive metadata blocks.
+struct MetadataCursor<'a> {
+    image: &'a Image,
+    block: Arc<Vec<u8>>,
+    next: u64,
+    offset: usize,
+}
+
+impl MetadataCursor<'_> {
+    fn read(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            if self.offset == self.block.len() {
+                let (block, next) = self.image.metadata_block(self.next)?;
+                self.block = block;
+                self.next = next;
+                self.offset = 0;
+            }
+            let n = (len - out.len()).min(self.block.len() - self.offset);
+            out.extend_from_slice(&self.block[self.offset..self.offset + n]);
+            self.offset += n;
+        }
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(le_u16(&self.read(2)?, 0))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(le_u32(&self.read(4)?, 0))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(le_u64(&self.read(8)?, 0))
+    }
+}
@@ -0,0 +1,458 @@
+//! Read-only layer serving the content of a squashfs image file.
+//!
+//! The image is parsed in userspace, so pre-packed read-only images can be used as lower
+//! layers of a [`unionfs`](crate::unionfs) without loop devices or root. Only gzip
+//! compressed (and uncompressed) images are supported.
+
+mod async_io;
+mod image;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rfuse3::Inode;
+use rfuse3::raw::reply::FileAttr;
+
+use crate::util::convert_stat64_to_file_attr;
+use image::{Image, InodeInfo};
+
+#[cfg(target_os = "macos")]
+type Stat64 = libc::stat;
+#[cfg(target_os = "linux")]
+type Stat64 = libc::stat64;
+
+const ROOT_INODE: Inode = 1;
+// The image never changes, so the kernel may cache attributes and entries for long.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// A read-only filesystem backed by a squashfs image file.
+///
+/// Inode numbers are derived from the position of the inode in the image, so they are
+/// stable across mounts of the same image.
+pub struct SquashfsLayer {
+    image: Image,
+    // Parent of every directory looked up so far, to answer `..`.
+    parents: Mutex<HashMap<Inode, Inode>>,
+}
+
+impl SquashfsLayer {
+    /// Open the squashfs image at `path`.
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let image = Image::open(path.as_ref())?;
+        // Make sure the root directory can be read before mounting anything on top.
+        image.inode(image.root_inode())?;
+        Ok(SquashfsLayer {
+            image,
+            parents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Inode references are `(block << 16) | offset`, so offsetting them by 2 keeps clear of
+    // the root inode number.
+    fn inode_of(&self, inode_ref: u64) -> Inode {
+        if inode_ref == self.image.root_inode() {
+            ROOT_INODE
+        } else {
+            inode_ref + 2
+        }
+    }
+
+    fn inode_ref(&self, inode: Inode) -> io::Result<u64> {
+        match inode {
+            ROOT_INODE => Ok(self.image.root_inode()),
+            0 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            _ => Ok(inode - 2),
+        }
+    }
+
+    fn get(&self, inode: Inode) -> io::Result<InodeInfo> {
+        self.image.inode(self.inode_ref(inode)?)
+    }
+
+    fn parent_of(&self, inode: Inode) -> Inode {
+        self.parents
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .copied()
+            .unwrap_or(ROOT_INODE)
+    }
+
+    fn stat(&self, inode: Inode, info: &InodeInfo) -> Stat64 {
+        let mut st: Stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = (info.kind.const_into_mode_t() | info.perm as u32) as _;
+        st.st_nlink = info.nlink as _;
+        st.st_uid = info.uid;
+        st.st_gid = info.gid;
+        st.st_rdev = info.rdev() as _;
+        st.st_size = info.size() as _;
+        st.st_blksize = self.image.block_size() as _;
+        st.st_blocks = info.size().div_ceil(512) as _;
+        st.st_atime = info.mtime as _;
+        st.st_mtime = info.mtime as _;
+        st.st_ctime = info.mtime as _;
+        st
+    }
+
+    fn attr(&self, inode: Inode, info: &InodeInfo) -> FileAttr {
+        convert_stat64_to_file_attr(self.stat(inode, info))
+    }
+
+    pub(crate) fn getattr_stat(&self, inode: Inode) -> io::Result<(Stat64, Duration)> {
+        let info = self.get(inode)?;
+        Ok((self.stat(inode, &info), TTL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io::Write;
+
+    use flate2::{Compression, write::ZlibEncoder};
+    use rfuse3::FileType;
+    use rfuse3::raw::reply::ReplyXAttr;
+    use rfuse3::raw::{Filesystem, Request};
+
+    use super::*;
+    use crate::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+    use crate::unionfs::layer::Layer;
+    use crate::unionfs::{BoxedLayer, OverlayFs, config::Config};
+    use std::sync::Arc;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Append `data` as a single compressed metadata block, returning its position.
+    fn metadata_block(image: &mut Vec<u8>, data: &[u8]) -> u64 {
+        assert!(data.len() <= 8192);
+        let pos = image.len() as u64;
+        let compressed = zlib(data);
+        image.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+        image.extend_from_slice(&compressed);
+        pos
+    }
+
+    // Append a lookup table held in one metadata block, returning the position of its
+    // pointer list.
+    fn lookup_table(image: &mut Vec<u8>, data: &[u8]) -> u64 {
+        let block = metadata_block(image, data);
+        let pos = image.len() as u64;
+        image.extend_from_slice(&block.to_le_bytes());
+        pos
+    }
+
+    #[derive(Default)]
+    struct Inodes {
+        table: Vec<u8>,
+        count: u32,
+    }
+
+    impl Inodes {
+        // Start an inode owned by uid index 0 and gid index 1, returning its reference.
+        fn add(&mut self, inode_type: u16, perm: u16, fields: &[&[u8]]) -> (u64, u32) {
+            self.count += 1;
+            let inode_ref = self.table.len() as u64;
+            for field in [
+                &inode_type.to_le_bytes()[..],
+                &perm.to_le_bytes(),
+                &0u16.to_le_bytes(),
+                &1u16.to_le_bytes(),
+                &1_700_000_000u32.to_le_bytes(),
+                &self.count.to_le_bytes(),
+            ]
+            .into_iter()
+            .chain(fields.iter().copied())
+            {
+                self.table.extend_from_slice(field);
+            }
+            (inode_ref, self.count)
+        }
+    }
+
+    // Append the listing of `entries`, sorted by name, to `dirs`, returning its offset and
+    // the size to record in the directory inode.
+    fn listing(dirs: &mut Vec<u8>, entries: &[(&str, u16, (u64, u32))]) -> (u16, u32) {
+        let offset = dirs.len();
+        let base = entries.iter().map(|(_, _, (_, n))| *n).min().unwrap();
+        dirs.extend_from_slice(&(entries.len() as u32 - 1).to_le_bytes());
+        dirs.extend_from_slice(&0u32.to_le_bytes());
+        dirs.extend_from_slice(&base.to_le_bytes());
+        for (name, entry_type, (inode_ref, number)) in entries {
+            dirs.extend_from_slice(&(*inode_ref as u16).to_le_bytes());
+            dirs.extend_from_slice(&((number - base) as i16).to_le_bytes());
+            dirs.extend_from_slice(&entry_type.to_le_bytes());
+            dirs.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            dirs.extend_from_slice(name.as_bytes());
+        }
+        (offset as u16, (dirs.len() - offset + 3) as u32)
+    }
+
+    // Build a gzip compressed image holding:
+    //   /file.txt  10000 bytes, two full blocks and a tail in a fragment
+    //   /dir/      opaque via `trusted.overlay.opaque`
+    //   /dir/small "hello", only in the fragment
+    //   /link      -> file.txt
+    //   /wh        0/0 char device whiteout
+    fn build_image(content: &[u8]) -> Vec<u8> {
+        assert!((2 * BLOCK_SIZE..3 * BLOCK_SIZE).contains(&content.len()));
+        let mut image = vec![0u8; 96];
+
+        let mut block_sizes = Vec::new();
+        for block in content.chunks(BLOCK_SIZE).take(2) {
+            let compressed = zlib(block);
+            block_sizes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            image.extend_from_slice(&compressed);
+        }
+        let tail = &content[2 * BLOCK_SIZE..];
+        let fragment = [tail, b"hello"].concat();
+        let fragment_start = image.len() as u64;
+        let compressed = zlib(&fragment);
+        image.extend_from_slice(&compressed);
+        let fragment_entry = [
+            &fragment_start.to_le_bytes()[..],
+            &(compressed.len() as u32).to_le_bytes(),
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+
+        let mut inodes = Inodes::default();
+        let mut dirs = Vec::new();
+        let file = inodes.add(
+            2,
+            0o644,
+            &[
+                &96u32.to_le_bytes()[..],
+                &0u32.to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &(content.len() as u32).to_le_bytes(),
+                &block_sizes,
+            ],
+        );
+        let small = inodes.add(
+            2,
+            0o600,
+            &[
+                &0u32.to_le_bytes()[..],
+                &0u32.to_le_bytes(),
+                &(tail.len() as u32).to_le_bytes(),
+                &5u32.to_le_bytes(),
+            ],
+        );
+        let link = inodes.add(
+            3,
+            0o777,
+            &[&1u32.to_le_bytes(), &8u32.to_le_bytes(), b"file.txt"],
+        );
+        let wh = inodes.add(5, 0o777, &[&1u32.to_le_bytes(), &0u32.to_le_bytes()]);
+        let (offset, size) = listing(&mut dirs, &[("small", 2, small)]);
+        let dir = inodes.add(
+            8,
+            0o755,
+            &[
+                &2u32.to_le_bytes(),
+                &size.to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &0u16.to_le_bytes(),
+                &offset.to_le_bytes(),
+                &0u32.to_le_bytes(),
+            ],
+        );
+        let entries = [
+            ("dir", 1, dir),
+            ("file.txt", 2, file),
+            ("link", 3, link),
+            ("wh", 5, wh),
+        ];
+        let (offset, size) = listing(&mut dirs, &entries);
+        let root = inodes.add(
+            1,
+            0o755,
+            &[
+                &0u32.to_le_bytes(),
+                &3u32.to_le_bytes(),
+                &(size as u16).to_le_bytes(),
+                &offset.to_le_bytes(),
+                &(inodes.count + 2).to_le_bytes(),
+            ],
+        );
+
+        let inode_table = metadata_block(&mut image, &inodes.table);
+        let directory_table = metadata_block(&mut image, &dirs);
+        let fragment_table = lookup_table(&mut image, &fragment_entry);
+        let id_table = lookup_table(
+            &mut image,
+            &[1000u32.to_le_bytes(), 2000u32.to_le_bytes()].concat(),
+        );
+        let kv = [
+            &1u16.to_le_bytes()[..],
+            &14u16.to_le_bytes(),
+            b"overlay.opaque",
+            &1u32.to_le_bytes(),
+            b"y",
+        ]
+        .concat();
+        let kv_start = metadata_block(&mut image, &kv);
+        let xattr_ids = [
+            &0u64.to_le_bytes()[..],
+            &1u32.to_le_bytes(),
+            &(kv.len() as u32).to_le_bytes(),
+        ]
+        .concat();
+        let xattr_ids = metadata_block(&mut image, &xattr_ids);
+        let xattr_table = image.len() as u64;
+        for field in [
+            &kv_start.to_le_bytes()[..],
+            &1u32.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &xattr_ids.to_le_bytes(),
+        ] {
+            image.extend_from_slice(field);
+        }
+
+        let bytes_used = image.len() as u64;
+        let superblock = [
+            &0x7371_7368u32.to_le_bytes()[..],
+            &inodes.count.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &(BLOCK_SIZE as u32).to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &12u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &2u16.to_le_bytes(),
+            &4u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &root.0.to_le_bytes(),
+            &bytes_used.to_le_bytes(),
+            &id_table.to_le_bytes(),
+            &xattr_table.to_le_bytes(),
+            &inode_table.to_le_bytes(),
+            &directory_table.to_le_bytes(),
+            &fragment_table.to_le_bytes(),
+            &u64::MAX.to_le_bytes(),
+        ]
+        .concat();
+        image[..96].copy_from_slice(&superblock);
+        image
+    }
+
+    #[tokio::test]
+    async fn test_squashfs_layer() {
+        let content: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.sqfs");
+        std::fs::write(&path, build_image(&content)).unwrap();
+        let fs = SquashfsLayer::new(&path).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let attr = fs
+            .lookup(req, 1, OsStr::new("file.txt"))
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!((attr.size, attr.perm), (10000, 0o644));
+        assert_eq!((attr.uid, attr.gid), (1000, 2000));
+        let file = attr.ino;
+        let data = fs.read(req, file, 0, 0, 65536).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[..]);
+        let data = fs.read(req, file, 0, 4000, 5000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[4000..9000]);
+        let err = fs.open(req, file, libc::O_RDWR as u32).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::EROFS));
+
+        let dir = fs.lookup(req, 1, OsStr::new("dir")).await.unwrap().attr.ino;
+        let small = fs.lookup(req, dir, OsStr::new("small")).await.unwrap().attr;
+        let data = fs.read(req, small.ino, 0, 0, 100).await.unwrap().data;
+        assert_eq!(data.as_ref(), b"hello");
+        assert!(fs.is_opaque(req, dir).await.unwrap());
+        assert!(!fs.is_opaque(req, 1).await.unwrap());
+        let size = fs
+            .getxattr(req, dir, OsStr::new("trusted.overlay.opaque"), 0)
+            .await
+            .unwrap();
+        assert!(matches!(size, ReplyXAttr::Size(1)));
+
+        let link = fs
+            .lookup(req, 1, OsStr::new("link"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let target = fs.readlink(req, link).await.unwrap().data;
+        assert_eq!(target.as_ref(), b"file.txt");
+        let wh = fs.lookup(req, 1, OsStr::new("wh")).await.unwrap().attr.ino;
+        assert!(fs.is_whiteout(req, wh).await.unwrap());
+        let err = fs.lookup(req, 1, OsStr::new("missing")).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+
+        let names: Vec<_> =
+            futures::StreamExt::collect::<Vec<_>>(fs.readdir(req, 1, 0, 0).await.unwrap().entries)
+                .await
+                .into_iter()
+                .map(|e| e.unwrap().name)
+                .collect();
+        assert_eq!(names, [".", "..", "dir", "file.txt", "link", "wh"]);
+    }
+
+    #[tokio::test]
+    async fn test_squashfs_lower_layer() {
+        let content = b"image".repeat(2000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.sqfs");
+        std::fs::write(&path, build_image(&content)).unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let upper_layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: upper.path(),
+            mapping: None::<&str>,
+            io_engine: Default::default(),
+        })
+        .await
+        .unwrap();
+        let lower: Arc<BoxedLayer> = Arc::new(SquashfsLayer::new(&path).unwrap());
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(Some(Arc::new(upper_layer)), vec![lower], config, 1).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let file = fs.lookup(req, 1, OsStr::new("file.txt")).await.unwrap();
+        let fh = fs
+            .open(req, file.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let data = fs
+            .read(req, file.attr.ino, fh, 0, 65536)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(data.as_ref(), &content[..]);
+        // The whiteout in the image hides nothing but itself.
+        let err = fs.lookup(req, 1, OsStr::new("wh")).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_not_squashfs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.sqfs");
+        std::fs::write(&path, [0u8; 4096]).unwrap();
+        let err = SquashfsLayer::new(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,345 @@
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStrExt;
+
+use bytes::Bytes;
+use futures::stream;
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result};
+
+use super::{BLOCK_SIZE, TTL, TarLayer};
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+impl Filesystem for TarLayer {
+    /// initialize filesystem. Called before any other filesystem method.
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    /// clean up filesystem. Nothing to do for a read-only archive.
+    async fn destroy(&self, _req: Request) {}
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let index = *dir
+            .children
+            .get(name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let inode = Self::inode_of(index);
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: self.attr(inode, &self.nodes[index]),
+            generation: 0,
+        })
+    }
+
+    /// get file attributes.
+    async fn getattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        let node = self.node(inode)?;
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.attr(inode, node),
+        })
+    }
+
+    /// set file attributes, never allowed on an archive.
+    async fn setattr(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: Option<u64>,
+        _set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        Err(erofs().into())
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
+        let node = self.node(inode)?;
+        if node.kind != FileType::Symlink {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+        Ok(ReplyData {
+            data: Bytes::copy_from_slice(&node.target),
+        })
+    }
+
+    /// open a file. Reads don't need any per-open state, so no handle is allocated.
+    async fn open(&self, _req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0 {
+            return Err(erofs().into());
+        }
+        if self.node(inode)?.kind == FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read data.
+    async fn read(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let node = self.node(inode)?;
+        if node.kind != FileType::RegularFile {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+        let len = node.size.saturating_sub(offset).min(size as u64) as usize;
+        let data = match len {
+            0 => Vec::new(),
+            _ => self.content.read(node.offset, offset, len).await?,
+        };
+        Ok(ReplyData {
+            data: Bytes::from(data),
+        })
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// flush method, nothing is ever buffered.
+    async fn flush(&self, _req: Request, _inode: Inode, _fh: u64, _lock_owner: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// get filesystem statistics.
+    async fn statfs(&self, _req: Request, _inode: Inode) -> Result<ReplyStatFs> {
+        let bytes: u64 = self.nodes.iter().map(|node| node.size).sum();
+        Ok(ReplyStatFs {
+            blocks: bytes.div_ceil(BLOCK_SIZE as u64),
+            bfree: 0,
+            bavail: 0,
+            files: self.nodes.len() as u64,
+            ffree: 0,
+            bsize: BLOCK_SIZE,
+            namelen: 255,
+            frsize: BLOCK_SIZE,
+        })
+    }
+
+    /// get an extended attribute.
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let value = self
+            .node(inode)?
+            .xattrs
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(value.len() as u32));
+        }
+        if value.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(value)))
+    }
+
+    /// list extended attribute names.
+    async fn listxattr(&self, _req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        let mut names = Vec::new();
+        for (name, _) in &self.node(inode)?.xattrs {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(names.len() as u32));
+        }
+        if names.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(names)))
+    }
+
+    /// open a directory.
+    async fn opendir(&self, _req: Request, inode: Inode, _flags: u32) -> Result<ReplyOpen> {
+        if self.node(inode)?.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read directory.
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let mut entries = vec![
+            (parent, FileType::Directory, ".".into()),
+            (Self::inode_of(dir.parent), FileType::Directory, "..".into()),
+        ];
+        for (name, &index) in &dir.children {
+            entries.push((Self::inode_of(index), self.nodes[index].kind, name.clone()));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name,
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, _req: Request, _inode: Inode, _fh: u64, _flags: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// check file access permissions. Only writes are refused, permission bits are left to
+    /// the kernel.
+    async fn access(&self, _req: Request, inode: Inode, mask: u32) -> Result<()> {
+        self.node(inode)?;
+        if mask as i32 & libc::W_OK != 0 {
+            return Err(erofs().into());
+        }
+        Ok(())
+    }
+
+    /// test for a POSIX file lock. Locks are not supported on an archive.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// acquire, modify or release a POSIX file lock. Locks are not supported on an archive.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+        _block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// read directory entries, but with their attribute, like [`readdir`][Filesystem::readdir]
+    /// + [`lookup`][Filesystem::lookup] at the same time.
+    async fn readdirplus<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let mut entries = vec![
+            (parent, ".".into(), dir),
+            (
+                Self::inode_of(dir.parent),
+                "..".into(),
+                &self.nodes[dir.parent],
+            ),
+        ];
+        for (name, &index) in &dir.children {
+            entries.push((Self::inode_of(index), name.clone(), &self.nodes[index]));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, name, node))| {
+                Ok(DirectoryEntryPlus {
+                    inode,
+                    generation: 0,
+                    kind: node.kind,
+                    name,
+                    offset: i as i64 + 1,
+                    attr: self.attr(inode, node),
+                    entry_ttl: TTL,
+                    attr_ttl: TTL,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+}
@@ -0,0 +1,743 @@
+//! Lazy pulling of eStargz and zstd:chunked layers.
+//!
+//! An eStargz blob is a gzip-compressed tarball where every file chunk starts a gzip member of
+//! its own, and whose table of contents (TOC) lists the entries with the compressed offset of
+//! their chunks. The TOC is fetched when the layer is opened, and chunks are fetched with range
+//! requests when first read, so a container can start before the blob is downloaded. See
+//! <https://github.com/containerd/stargz-snapshotter/blob/main/docs/estargz.md>.
+//!
+//! zstd:chunked blobs are the same with zstd frames instead of gzip members. Their TOC is a
+//! zstd-compressed JSON document in a skippable frame, located by a footer of its own and
+//! listing the compressed end of every chunk too. See
+//! <https://github.com/containers/storage/blob/main/pkg/chunked/internal/compression.go>.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::Engine as _;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use moka::future::Cache;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, RANGE};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+
+use super::index::{EntryKind, IndexEntry};
+use super::{Content, TarLayer, build_tree};
+use crate::overlayfs::oci_layer::LayerDigest;
+
+const TOC_NAME: &str = "stargz.index.json";
+// An empty gzip member whose extra field is an `SG` subfield holding "%016xSTARGZ".
+const FOOTER_SIZE: u64 = 51;
+// Same without the subfield framing, written by the original stargz.
+const LEGACY_FOOTER_SIZE: u64 = 47;
+const FOOTER_SUFFIX: &[u8] = b"STARGZ";
+// Little endian offset, compressed and uncompressed lengths of the TOC, the same for the
+// tar-split data, then the TOC type and this magic.
+const ZSTD_FOOTER_SIZE: u64 = 64;
+const ZSTD_CHUNKED_MAGIC: &[u8] = b"GNUlInUx";
+// The only TOC type, the eStargz-like JSON document.
+const ZSTD_MANIFEST_CRFS: u64 = 1;
+// Files the eStargz builder adds to mark the end of prioritized files.
+const LANDMARKS: [&str; 2] = [".prefetch.landmark", ".no.prefetch.landmark"];
+
+/// A blob read by ranges, usually from a registry.
+#[async_trait]
+pub trait RemoteBlob: Send + Sync {
+    async fn size(&self) -> io::Result<u64>;
+
+    /// Read the `len` bytes at `offset`.
+    async fn read_range(&self, offset: u64, len: u64) -> io::Result<Bytes>;
+}
+
+/// A blob of an OCI distribution registry, read with HTTP range requests.
+pub struct RegistryBlob {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl RegistryBlob {
+    /// The blob `digest` of `repository` on `registry`, given as a base URL such as
+    /// `https://registry-1.docker.io`.
+    pub fn new(registry: &str, repository: &str, digest: &str) -> Self {
+        RegistryBlob {
+            client: reqwest::Client::new(),
+            url: format!(
+                "{}/v2/{repository}/blobs/{digest}",
+                registry.trim_end_matches('/')
+            ),
+            token: None,
+        }
+    }
+
+    /// Authenticate requests with the bearer `token`.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> io::Result<reqwest::Response> {
+        let request = match &self.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {token}")),
+            None => request,
+        };
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)
+    }
+}
+
+#[async_trait]
+impl RemoteBlob for RegistryBlob {
+    async fn size(&self) -> io::Result<u64> {
+        let response = self
+            .send(self.client.request(Method::HEAD, &self.url))
+            .await?;
+        // `content_length` is the size of the (empty) body of the HEAD response.
+        response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("no size for blob {}", self.url)))
+    }
+
+    async fn read_range(&self, offset: u64, len: u64) -> io::Result<Bytes> {
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        let range = format!("bytes={offset}-{}", offset + len - 1);
+        let response = self
+            .send(self.client.get(&self.url).header(RANGE, range))
+            .await?;
+        let full = response.status() != StatusCode::PARTIAL_CONTENT;
+        let mut body = response.bytes().await.map_err(io::Error::other)?;
+        // Servers ignoring ranges send the whole blob.
+        if full && body.len() as u64 >= offset + len {
+            body = body.slice(offset as usize..(offset + len) as usize);
+        }
+        if body.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(body)
+    }
+}
+
+#[derive(Deserialize)]
+struct Toc {
+    entries: Vec<TocEntry>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    size: u64,
+    #[serde(rename = "modtime")]
+    mod_time: String,
+    link_name: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    /// Values are base64 encoded.
+    xattrs: HashMap<String, String>,
+    digest: String,
+    /// Compressed offset of the gzip member or zstd frame holding the chunk.
+    offset: u64,
+    /// Compressed end of the chunk, only in zstd:chunked TOCs.
+    end_offset: u64,
+    chunk_offset: u64,
+    chunk_size: u64,
+    chunk_digest: String,
+    /// `zeros` for holes zstd:chunked doesn't store.
+    chunk_type: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Estargz,
+    ZstdChunked,
+}
+
+struct Chunk {
+    // Offset in the file.
+    offset: u64,
+    size: u64,
+    // Range of the gzip member or zstd frame in the blob, empty for a hole.
+    compressed: u64,
+    compressed_len: u64,
+    digest: Option<LayerDigest>,
+}
+
+// File data fetched chunk by chunk, `position` being the index of the file in `files`.
+struct Stargz {
+    blob: Arc<dyn RemoteBlob>,
+    format: Format,
+    files: Vec<Vec<Chunk>>,
+    // Decompressed chunks by compressed offset.
+    cache: Cache<u64, Bytes>,
+}
+
+impl Stargz {
+    async fn chunk(&self, chunk: &Chunk) -> io::Result<Bytes> {
+        if chunk.compressed_len == 0 {
+            return Ok(Bytes::from(vec![0; chunk.size as usize]));
+        }
+        let fetch = async {
+            let raw = self
+                .blob
+                .read_range(chunk.compressed, chunk.compressed_len)
+                .await?;
+            let mut data = Vec::with_capacity(chunk.size as usize);
+            match self.format {
+                Format::Estargz => GzDecoder::new(&raw[..])
+                    .take(chunk.size)
+                    .read_to_end(&mut data)?,
+                Format::ZstdChunked => zstd::Decoder::new(&raw[..])?
+                    .take(chunk.size)
+                    .read_to_end(&mut data)?,
+            };
+            if data.len() as u64 != chunk.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("short chunk at {}", chunk.compressed),
+                ));
+            }
+            if let Some(digest) = &chunk.digest {
+                digest.verify(&data[..])?;
+            }
+            Ok(Bytes::from(data))
+        };
+        // Concurrent reads of a chunk share one fetch.
+        self.cache
+            .try_get_with(chunk.compressed, fetch)
+            .await
+            .map_err(|e: Arc<io::Error>| io::Error::new(e.kind(), e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Content for Stargz {
+    async fn read(&self, position: u64, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let chunks = &self.files[position as usize];
+        let end = offset + len as u64;
+        let first = chunks.partition_point(|c| c.offset + c.size <= offset);
+        let mut out = Vec::with_capacity(len);
+        for chunk in chunks[first..].iter().take_while(|c| c.offset < end) {
+            let data = self.chunk(chunk).await?;
+            let from = offset.max(chunk.offset) - chunk.offset;
+            let to = end.min(chunk.offset + chunk.size) - chunk.offset;
+            out.extend_from_slice(&data[from as usize..to as usize]);
+        }
+        Ok(out)
+    }
+}
+
+fn invalid(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid estargz blob: {what}"),
+    )
+}
+
+// Format, offset and compressed length of the TOC, from the last `ZSTD_FOOTER_SIZE` bytes of
+// a blob of `size` bytes.
+fn parse_footer(tail: &[u8], size: u64) -> io::Result<(Format, u64, u64)> {
+    if tail.ends_with(ZSTD_CHUNKED_MAGIC) {
+        let field = |i: usize| u64::from_le_bytes(tail[i * 8..i * 8 + 8].try_into().unwrap());
+        if field(6) != ZSTD_MANIFEST_CRFS {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("zstd:chunked TOC type {} is not supported", field(6)),
+            ));
+        }
+        let (offset, len) = (field(0), field(1));
+        if offset.saturating_add(len) > size - ZSTD_FOOTER_SIZE {
+            return Err(invalid("TOC out of the blob"));
+        }
+        return Ok((Format::ZstdChunked, offset, len));
+    }
+    let (offset, footer_size) =
+        parse_estargz_footer(&tail[(ZSTD_FOOTER_SIZE - FOOTER_SIZE) as usize..])?;
+    // The TOC is the last gzip member before the footer.
+    if offset > size - footer_size {
+        return Err(invalid("TOC out of the blob"));
+    }
+    Ok((Format::Estargz, offset, size - footer_size - offset))
+}
+
+// Offset of the TOC and size of the footer, from the last `FOOTER_SIZE` bytes of the blob.
+fn parse_estargz_footer(tail: &[u8]) -> io::Result<(u64, u64)> {
+    let toc_offset = |payload: &[u8]| {
+        let hex = payload.strip_suffix(FOOTER_SUFFIX)?;
+        u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+    };
+    let xlen = |header: &[u8]| u16::from_le_bytes([header[10], header[11]]);
+    if tail[..2] == [0x1f, 0x8b]
+        && xlen(tail) == 26
+        && &tail[12..14] == b"SG"
+        && let Some(offset) = toc_offset(&tail[16..38])
+    {
+        return Ok((offset, FOOTER_SIZE));
+    }
+    let legacy = &tail[(FOOTER_SIZE - LEGACY_FOOTER_SIZE) as usize..];
+    if legacy[..2] == [0x1f, 0x8b]
+        && xlen(legacy) == 22
+        && let Some(offset) = toc_offset(&legacy[12..34])
+    {
+        return Ok((offset, LEGACY_FOOTER_SIZE));
+    }
+    Err(invalid("no footer"))
+}
+
+fn parse_digest(digest: &str) -> io::Result<Option<LayerDigest>> {
+    if digest.is_empty() {
+        return Ok(None);
+    }
+    LayerDigest::from_str(digest).map(Some).map_err(invalid)
+}
+
+// The TOC stored at `member`.
+fn parse_toc(format: Format, member: &[u8]) -> io::Result<Toc> {
+    if format == Format::ZstdChunked {
+        return serde_json::from_reader(zstd::Decoder::new(member)?).map_err(invalid);
+    }
+    let mut archive = tar::Archive::new(GzDecoder::new(member));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()?.as_os_str() == TOC_NAME {
+            return serde_json::from_reader(entry).map_err(invalid);
+        }
+    }
+    Err(invalid("no TOC"))
+}
+
+// The entries of the TOC for the tree of the layer, with the chunks of every regular file.
+fn toc_entries(toc: Toc, toc_offset: u64) -> io::Result<(Vec<IndexEntry>, Vec<Vec<Chunk>>)> {
+    // Without an end offset, a chunk ends where the next gzip member listed starts, or at the
+    // TOC.
+    let mut starts: Vec<u64> = toc.entries.iter().map(|e| e.offset).collect();
+    starts.push(toc_offset);
+    starts.sort_unstable();
+    starts.dedup();
+    let member_len = |entry: &TocEntry| {
+        if entry.chunk_type == "zeros" {
+            return 0;
+        }
+        if entry.end_offset > entry.offset {
+            return entry.end_offset - entry.offset;
+        }
+        let next = starts[starts.partition_point(|&start| start <= entry.offset)..]
+            .first()
+            .copied()
+            .unwrap_or(toc_offset);
+        next.saturating_sub(entry.offset)
+    };
+
+    let mut entries = Vec::new();
+    let mut files: Vec<Vec<Chunk>> = Vec::new();
+    for entry in toc.entries {
+        let compressed_len = member_len(&entry);
+        let kind = match entry.kind.as_str() {
+            "reg" if LANDMARKS.contains(&entry.name.as_str()) => continue,
+            "reg" => EntryKind::File,
+            "chunk" => {
+                let chunks = files
+                    .last_mut()
+                    .ok_or_else(|| invalid(format!("chunk of {} first", entry.name)))?;
+                chunks.push(Chunk {
+                    offset: entry.chunk_offset,
+                    size: entry.chunk_size,
+                    compressed: entry.offset,
+                    compressed_len,
+                    digest: parse_digest(&entry.chunk_digest)?,
+                });
+                continue;
+            }
+            "dir" => EntryKind::Directory,
+            "symlink" => EntryKind::Symlink,
+            "hardlink" => EntryKind::Hardlink,
+            "char" => EntryKind::CharDevice,
+            "block" => EntryKind::BlockDevice,
+            "fifo" => EntryKind::Fifo,
+            _ => continue,
+        };
+        let mtime = chrono::DateTime::parse_from_rfc3339(&entry.mod_time)
+            .map(|time| time.timestamp().max(0) as u64)
+            .unwrap_or(0);
+        let mut xattrs = Vec::new();
+        for (name, value) in entry.xattrs {
+            let value = base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(invalid)?;
+            xattrs.push((name, value));
+        }
+        let mut offset = 0;
+        if kind == EntryKind::File {
+            offset = files.len() as u64;
+            let mut chunks = Vec::new();
+            if entry.size > 0 {
+                // The digest of the file is the one of its only chunk when not chunked.
+                let (size, digest) = match entry.chunk_size {
+                    0 => (entry.size, &entry.digest),
+                    size => (size, &entry.chunk_digest),
+                };
+                chunks.push(Chunk {
+                    offset: 0,
+                    size,
+                    compressed: entry.offset,
+                    compressed_len,
+                    digest: parse_digest(digest)?,
+                });
+            }
+            files.push(chunks);
+        }
+        entries.push(IndexEntry {
+            path: entry.name.into(),
+            kind,
+            mode: entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            mtime,
+            size: entry.size,
+            offset,
+            link: (!entry.link_name.is_empty()).then(|| entry.link_name.into()),
+            device: (entry.dev_major, entry.dev_minor),
+            xattrs,
+        });
+    }
+    Ok((entries, files))
+}
+
+impl TarLayer {
+    /// Serve the eStargz or zstd:chunked `blob` without downloading it.
+    ///
+    /// Only the table of contents is fetched here, file data is fetched chunk by chunk when
+    /// first read, and up to `cache_size` bytes of decompressed chunks are kept. Chunks are
+    /// checked against their digest from the table of contents.
+    pub async fn estargz(blob: Arc<dyn RemoteBlob>, cache_size: u64) -> io::Result<Self> {
+        let size = blob.size().await?;
+        if size < ZSTD_FOOTER_SIZE {
+            return Err(invalid("too short"));
+        }
+        let tail = blob
+            .read_range(size - ZSTD_FOOTER_SIZE, ZSTD_FOOTER_SIZE)
+            .await?;
+        let (format, toc_offset, toc_len) = parse_footer(&tail, size)?;
+        let member = blob.read_range(toc_offset, toc_len).await?;
+        let toc = parse_toc(format, &member)?;
+        let (entries, files) = toc_entries(toc, toc_offset)?;
+        let cache = Cache::builder()
+            .weigher(|_, chunk: &Bytes| chunk.len().try_into().unwrap_or(u32::MAX))
+            .max_capacity(cache_size)
+            .build();
+        Ok(TarLayer {
+            nodes: build_tree(&entries)?,
+            content: Box::new(Stargz {
+                blob,
+                format,
+                files,
+                cache,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use flate2::{Compression, write::GzEncoder};
+    use rfuse3::raw::{Filesystem, Request};
+    use serde_json::json;
+    use sha2::Digest as _;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::unionfs::layer::Layer;
+
+    const CHUNK_SIZE: usize = 4096;
+    const MTIME: &str = "2023-11-14T22:13:20Z";
+
+    fn gz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", sha2::Sha256::digest(data))
+    }
+
+    fn tar_header(path: &str, kind: tar::EntryType, size: usize) -> Vec<u8> {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(path).unwrap();
+        header.set_entry_type(kind);
+        header.set_size(size as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header.as_bytes().to_vec()
+    }
+
+    // An eStargz blob holding the directory `etc`, the files `etc/<name>` cut in 4KiB chunks,
+    // and a whiteout of `etc/gone`.
+    fn build_estargz(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut blob = gz(&tar_header("etc/", tar::EntryType::Directory, 0));
+        let mut toc = vec![json!({"name": "etc/", "type": "dir", "mode": 0o755, "modtime": MTIME})];
+        for (name, data) in files {
+            let name = format!("etc/{name}");
+            blob.extend(gz(&tar_header(&name, tar::EntryType::Regular, data.len())));
+            for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+                let mut entry = json!({
+                    "name": name,
+                    "type": if i == 0 { "reg" } else { "chunk" },
+                    "offset": blob.len(),
+                    "chunkOffset": i * CHUNK_SIZE,
+                    "chunkSize": chunk.len(),
+                    "chunkDigest": sha256(chunk),
+                });
+                if i == 0 {
+                    entry["size"] = json!(data.len());
+                    entry["mode"] = json!(0o644);
+                    entry["uid"] = json!(1000);
+                    entry["modtime"] = json!(MTIME);
+                    entry["digest"] = json!(sha256(data));
+                    entry["xattrs"] = json!({"user.origin": "cmVnaXN0cnk="});
+                }
+                toc.push(entry);
+                blob.extend(gz(chunk));
+            }
+            blob.extend(gz(&vec![0; data.len().next_multiple_of(512) - data.len()]));
+        }
+        blob.extend(gz(&tar_header("etc/.wh.gone", tar::EntryType::Regular, 0)));
+        toc.push(json!({"name": "etc/.wh.gone", "type": "reg", "offset": blob.len()}));
+
+        let toc_offset = blob.len();
+        let toc = serde_json::to_vec(&json!({"version": 1, "entries": toc})).unwrap();
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(toc.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, TOC_NAME, &toc[..]).unwrap();
+        blob.extend(gz(&tar.into_inner().unwrap()));
+
+        let payload = format!("{toc_offset:016x}STARGZ");
+        blob.extend([0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff]);
+        blob.extend(26u16.to_le_bytes());
+        blob.extend(b"SG");
+        blob.extend(22u16.to_le_bytes());
+        blob.extend(payload.as_bytes());
+        blob.extend([1, 0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+        blob
+    }
+
+    // Serve `blob` over HTTP at /v2/test/blobs/<digest>, counting the GET requests.
+    async fn serve(blob: Vec<u8>, gets: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let blob = Arc::new(blob);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (blob, gets) = (Arc::clone(&blob), Arc::clone(&gets));
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let request = String::from_utf8(request).unwrap().to_lowercase();
+                    let head = if request.starts_with("head") {
+                        format!(
+                            "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: {}\r\n\r\n",
+                            blob.len()
+                        )
+                    } else {
+                        gets.fetch_add(1, Ordering::Relaxed);
+                        let range = request.split("range: bytes=").nth(1).unwrap();
+                        let range = range.split("\r\n").next().unwrap();
+                        let (start, end) = range.split_once('-').unwrap();
+                        let (start, end): (usize, usize) =
+                            (start.parse().unwrap(), end.parse().unwrap());
+                        let body = &blob[start..=end];
+                        let head = format!(
+                            "HTTP/1.1 206 Partial Content\r\nconnection: close\r\ncontent-length: {}\r\n\r\n",
+                            body.len()
+                        );
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(body).await.unwrap();
+                        return;
+                    };
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_estargz_lazy_pull() {
+        let big: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let blob = build_estargz(&[("big", &big), ("small", b"hello")]);
+        let gets = Arc::new(AtomicUsize::new(0));
+        let registry = serve(blob, Arc::clone(&gets)).await;
+        let remote = RegistryBlob::new(&registry, "test", "sha256:0").with_token("t");
+        let fs = TarLayer::estargz(Arc::new(remote), 1 << 20).await.unwrap();
+        // The footer and the TOC.
+        assert_eq!(gets.load(Ordering::Relaxed), 2);
+        let req = Request::default();
+
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        let attr = fs.lookup(req, etc, OsStr::new("big")).await.unwrap().attr;
+        assert_eq!((attr.size, attr.perm, attr.uid), (20000, 0o644, 1000));
+        assert_eq!(attr.mtime.sec, 1_700_000_000);
+        assert_eq!(gets.load(Ordering::Relaxed), 2);
+
+        // Only the two chunks read are fetched, and only once.
+        let data = fs.read(req, attr.ino, 0, 5000, 4000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &big[5000..9000]);
+        assert_eq!(gets.load(Ordering::Relaxed), 4);
+        let data = fs.read(req, attr.ino, 0, 6000, 100).await.unwrap().data;
+        assert_eq!(data.as_ref(), &big[6000..6100]);
+        assert_eq!(gets.load(Ordering::Relaxed), 4);
+        let data = fs.read(req, attr.ino, 0, 0, 65536).await.unwrap().data;
+        assert_eq!(data.as_ref(), &big[..]);
+
+        let small = fs.lookup(req, etc, OsStr::new("small")).await.unwrap().attr;
+        let data = fs.read(req, small.ino, 0, 0, 100).await.unwrap().data;
+        assert_eq!(data.as_ref(), b"hello");
+        let origin = fs
+            .getxattr(req, small.ino, OsStr::new("user.origin"), 64)
+            .await
+            .unwrap();
+        assert!(
+            matches!(origin, rfuse3::raw::reply::ReplyXAttr::Data(v) if v.as_ref() == b"registry")
+        );
+        let gone = fs.lookup(req, etc, OsStr::new("gone")).await.unwrap().attr;
+        assert!(fs.is_whiteout(req, gone.ino).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_estargz_corrupted_chunk() {
+        let mut blob = build_estargz(&[("file", b"payload")]);
+        // Swap the compressed chunk for the one of other data.
+        let good = gz(b"payload");
+        let bad = gz(b"PAYLOAD");
+        let at = blob
+            .windows(good.len())
+            .position(|w| w == good.as_slice())
+            .unwrap();
+        blob[at..at + bad.len()].copy_from_slice(&bad);
+        let registry = serve(blob, Arc::new(AtomicUsize::new(0))).await;
+        let remote = RegistryBlob::new(&registry, "test", "sha256:0");
+        let fs = TarLayer::estargz(Arc::new(remote), 1 << 20).await.unwrap();
+        let req = Request::default();
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        let file = fs.lookup(req, etc, OsStr::new("file")).await.unwrap().attr;
+        let err = fs.read(req, file.ino, 0, 0, 100).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::EIO));
+    }
+
+    // Append `data` to `blob` in a zstd skippable frame.
+    fn skippable_frame(blob: &mut Vec<u8>, data: &[u8]) {
+        blob.extend(0x184D2A50u32.to_le_bytes());
+        blob.extend((data.len() as u32).to_le_bytes());
+        blob.extend(data);
+    }
+
+    // A zstd:chunked blob holding the file `data`, cut in 4KiB chunks stored as zstd frames of
+    // their own except for the all-zero ones, left as holes.
+    fn build_zstd_chunked(data: &[u8]) -> Vec<u8> {
+        let mut blob = zstd::encode_all(
+            &tar_header("data", tar::EntryType::Regular, data.len())[..],
+            0,
+        )
+        .unwrap();
+        let mut toc = Vec::new();
+        for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let mut entry = json!({
+                "name": "data",
+                "type": if i == 0 { "reg" } else { "chunk" },
+                "chunkOffset": i * CHUNK_SIZE,
+                "chunkSize": chunk.len(),
+                "chunkDigest": sha256(chunk),
+            });
+            if chunk.iter().all(|&b| b == 0) {
+                entry["chunkType"] = json!("zeros");
+            } else {
+                entry["offset"] = json!(blob.len());
+                blob.extend(zstd::encode_all(chunk, 0).unwrap());
+                entry["endOffset"] = json!(blob.len());
+            }
+            if i == 0 {
+                entry["size"] = json!(data.len());
+                entry["mode"] = json!(0o600);
+                entry["modtime"] = json!(MTIME);
+                entry["digest"] = json!(sha256(data));
+            }
+            toc.push(entry);
+        }
+
+        let toc = serde_json::to_vec(&json!({"version": 1, "entries": toc})).unwrap();
+        let toc = zstd::encode_all(&toc[..], 0).unwrap();
+        // The TOC offset is the one of its data, after the frame header.
+        let toc_offset = blob.len() as u64 + 8;
+        skippable_frame(&mut blob, &toc);
+        let mut footer = Vec::new();
+        for field in [toc_offset, toc.len() as u64, 0, 0, 0, 0, ZSTD_MANIFEST_CRFS] {
+            footer.extend(field.to_le_bytes());
+        }
+        footer.extend(ZSTD_CHUNKED_MAGIC);
+        skippable_frame(&mut blob, &footer);
+        blob
+    }
+
+    #[tokio::test]
+    async fn test_zstd_chunked_lazy_pull() {
+        let mut data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        data[CHUNK_SIZE..2 * CHUNK_SIZE].fill(0);
+        let gets = Arc::new(AtomicUsize::new(0));
+        let registry = serve(build_zstd_chunked(&data), Arc::clone(&gets)).await;
+        let remote = RegistryBlob::new(&registry, "test", "sha256:0");
+        let fs = TarLayer::estargz(Arc::new(remote), 1 << 20).await.unwrap();
+        assert_eq!(gets.load(Ordering::Relaxed), 2);
+        let req = Request::default();
+
+        let attr = fs.lookup(req, 1, OsStr::new("data")).await.unwrap().attr;
+        assert_eq!((attr.size, attr.perm), (20000, 0o600));
+        // The hole isn't fetched.
+        let read = fs.read(req, attr.ino, 0, 5000, 100).await.unwrap().data;
+        assert_eq!(read.as_ref(), &data[5000..5100]);
+        assert_eq!(gets.load(Ordering::Relaxed), 2);
+        let read = fs.read(req, attr.ino, 0, 3000, 2000).await.unwrap().data;
+        assert_eq!(read.as_ref(), &data[3000..5000]);
+        assert_eq!(gets.load(Ordering::Relaxed), 3);
+        let read = fs.read(req, attr.ino, 0, 0, 65536).await.unwrap().data;
+        assert_eq!(read.as_ref(), &data[..]);
+    }
+
+    #[test]
+    fn test_zstd_chunked_unknown_toc_type() {
+        let mut tail = vec![0u8; ZSTD_FOOTER_SIZE as usize - 16];
+        tail.extend(2u64.to_le_bytes());
+        tail.extend_from_slice(ZSTD_CHUNKED_MAGIC);
+        let err = parse_footer(&tail, 1 << 20).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use flate2::bufread::{GzDecoder, MultiGzDecoder};
+use serde::{Deserialize, Serialize};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XATTR_PAX_PREFIX: &str = "SCHILY.xattr.";
+
+/// Type of an archive entry, the ones the layer can't serve are left out of the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Hard link to the entry at `link`.
+    Hardlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+}
+
+/// One entry of the archive, in archive order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct IndexEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub size: u64,
+    /// Position of the data in the uncompressed archive.
+    pub offset: u64,
+    /// Target of symlinks and hard links.
+    pub link: Option<PathBuf>,
+    pub device: (u32, u32),
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Start of a gzip member, in the compressed blob and in the uncompressed archive.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Member {
+    compressed: u64,
+    uncompressed: u64,
+}
+
+/// Index of the entries of a plain or gzip-compressed tarball.
+///
+/// Building it needs a full pass over the blob, so it can be [saved](Self::save) next to the
+/// blob and [loaded](Self::load) on the next mount instead. Gzip blobs can't be read at random
+/// positions, the index records where their members start so reads decompress from the
+/// closest one: blobs with a member per file, like eStargz ones, are cheap to read from, while
+/// a single member blob is decompressed from its start for every read.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TarIndex {
+    /// Size of the blob, to catch an index used with another blob.
+    blob_size: u64,
+    gzip: Option<Vec<Member>>,
+    pub(super) entries: Vec<IndexEntry>,
+}
+
+// `Read` at an independent position of a file shared by concurrent reads.
+struct ReadAt<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl TarIndex {
+    /// Index the tarball at `path` with a pass over all of it.
+    pub fn build(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let blob_size = file.metadata()?.len();
+        let mut magic = [0u8; 2];
+        let gzip = file.read_at(&mut magic, 0)? == 2 && magic == GZIP_MAGIC;
+        let (gzip, entries) = if gzip {
+            let members = gzip_members(&mut file)?;
+            let reader = MultiGzDecoder::new(BufReader::new(ReadAt {
+                file: &file,
+                pos: 0,
+            }));
+            (Some(members), index_entries(reader)?)
+        } else {
+            (
+                None,
+                index_entries(ReadAt {
+                    file: &file,
+                    pos: 0,
+                })?,
+            )
+        };
+        Ok(TarIndex {
+            blob_size,
+            gzip,
+            entries,
+        })
+    }
+
+    /// Load an index written by [`save`](Self::save).
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(file, self).map_err(io::Error::other)
+    }
+
+    /// Number of entries indexed.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(super) fn check(&self, blob: &File) -> io::Result<()> {
+        if blob.metadata()?.len() != self.blob_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tar index doesn't match the blob",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes of the uncompressed archive at `offset` from `blob`.
+    pub(super) fn read(&self, blob: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut out = vec![0u8; len];
+        let Some(members) = &self.gzip else {
+            blob.read_exact_at(&mut out, offset)?;
+            return Ok(out);
+        };
+        let member = members[members.partition_point(|m| m.uncompressed <= offset) - 1];
+        let mut decoder = MultiGzDecoder::new(BufReader::new(ReadAt {
+            file: blob,
+            pos: member.compressed,
+        }));
+        let skip = offset - member.uncompressed;
+        if io::copy(&mut (&mut decoder).take(skip), &mut io::sink())? < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        decoder.read_exact(&mut out)?;
+        Ok(out)
+    }
+}
+
+// Where every gzip member of `file` starts.
+fn gzip_members(file: &mut File) -> io::Result<Vec<Member>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut members = Vec::new();
+    let mut uncompressed = 0;
+    while !reader.fill_buf()?.is_empty() {
+        members.push(Member {
+            compressed: reader.stream_position()?,
+            uncompressed,
+        });
+        // The bufread decoder stops right after the trailer of the member.
+        let mut decoder = GzDecoder::new(reader);
+        uncompressed += io::copy(&mut decoder, &mut io::sink())?;
+        reader = decoder.into_inner();
+    }
+    Ok(members)
+}
+
+fn index_entries<R: Read>(reader: R) -> io::Result<Vec<IndexEntry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => EntryKind::File,
+            tar::EntryType::Directory => EntryKind::Directory,
+            tar::EntryType::Symlink => EntryKind::Symlink,
+            tar::EntryType::Link => EntryKind::Hardlink,
+            tar::EntryType::Char => EntryKind::CharDevice,
+            tar::EntryType::Block => EntryKind::BlockDevice,
+            tar::EntryType::Fifo => EntryKind::Fifo,
+            tar::EntryType::GNUSparse => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "sparse tar entries are not supported",
+                ));
+            }
+            // Global headers and the like.
+            _ => continue,
+        };
+        // Other entries may leave the device fields blank.
+        let device = match kind {
+            EntryKind::CharDevice | EntryKind::BlockDevice => (
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            ),
+            _ => (0, 0),
+        };
+        let (mode, uid, gid, mtime) = (
+            header.mode()?,
+            header.uid()? as u32,
+            header.gid()? as u32,
+            header.mtime()?,
+        );
+        let mut xattrs = Vec::new();
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if let Ok(Some(name)) = extension
+                    .key()
+                    .map(|key| key.strip_prefix(XATTR_PAX_PREFIX))
+                {
+                    xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+        entries.push(IndexEntry {
+            path: entry.path()?.into_owned(),
+            kind,
+            mode,
+            uid,
+            gid,
+            mtime,
+            size: entry.size(),
+            offset: entry.raw_file_position(),
+            link: entry.link_name()?.map(|link| link.into_owned()),
+            device,
+            xattrs,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,542 @@
+//! Read-only layer serving the content of a tarball, such as an OCI layer blob.
+//!
+//! The blob is used in place from the content store: a [`TarIndex`] records where the data of
+//! every entry is, and the tree of the layer is built from it in memory. eStargz and
+//! zstd:chunked blobs can also be served straight from a registry, see [`TarLayer::estargz`].
+//! OCI whiteout markers are turned into what the [`unionfs`](crate::unionfs) expects from a
+//! lower layer: `.wh.<name>` shows as a 0/0 char device `<name>`, and `.wh..wh..opq` marks its
+//! directory opaque with `trusted.overlay.opaque`.
+
+mod async_io;
+mod estargz;
+mod index;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rfuse3::raw::reply::FileAttr;
+use rfuse3::{FileType, Inode};
+
+use crate::unionfs::layer::PRIVILEGED_OPAQUE_XATTR;
+use crate::util::convert_stat64_to_file_attr;
+use index::{EntryKind, IndexEntry};
+
+pub use estargz::{RegistryBlob, RemoteBlob};
+pub use index::TarIndex;
+
+#[cfg(target_os = "macos")]
+type Stat64 = libc::stat;
+#[cfg(target_os = "linux")]
+type Stat64 = libc::stat64;
+
+const ROOT_INODE: Inode = 1;
+// The blob never changes, so the kernel may cache attributes and entries for long.
+const TTL: Duration = Duration::from_secs(3600);
+const BLOCK_SIZE: u32 = 4096;
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+struct Node {
+    kind: FileType,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    size: u64,
+    rdev: u32,
+    nlink: u32,
+    // Where the data is, for the `Content` of the layer.
+    offset: u64,
+    target: Vec<u8>,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    parent: usize,
+    children: BTreeMap<OsString, usize>,
+}
+
+impl Node {
+    // A directory missing from the archive but holding some of its entries.
+    fn implicit_dir(parent: usize) -> Self {
+        Node {
+            kind: FileType::Directory,
+            perm: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            rdev: 0,
+            nlink: 2,
+            offset: 0,
+            target: Vec::new(),
+            xattrs: Vec::new(),
+            parent,
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn new(entry: &IndexEntry, parent: usize) -> Self {
+        let mut node = Node::implicit_dir(parent);
+        node.update(entry);
+        node
+    }
+
+    fn whiteout(entry: &IndexEntry, parent: usize) -> Self {
+        Node {
+            kind: FileType::CharDevice,
+            perm: 0,
+            size: 0,
+            rdev: 0,
+            nlink: 1,
+            ..Node::new(entry, parent)
+        }
+    }
+
+    // Take the attributes of `entry`, keeping the children of a directory.
+    fn update(&mut self, entry: &IndexEntry) {
+        self.kind = match entry.kind {
+            EntryKind::File | EntryKind::Hardlink => FileType::RegularFile,
+            EntryKind::Directory => FileType::Directory,
+            EntryKind::Symlink => FileType::Symlink,
+            EntryKind::CharDevice => FileType::CharDevice,
+            EntryKind::BlockDevice => FileType::BlockDevice,
+            EntryKind::Fifo => FileType::NamedPipe,
+        };
+        self.perm = (entry.mode & 0o7777) as u16;
+        (self.uid, self.gid, self.mtime) = (entry.uid, entry.gid, entry.mtime);
+        self.nlink = if self.kind == FileType::Directory {
+            2
+        } else {
+            1
+        };
+        self.offset = entry.offset;
+        self.target = match (&entry.link, entry.kind) {
+            (Some(link), EntryKind::Symlink) => link.as_os_str().as_bytes().to_vec(),
+            _ => Vec::new(),
+        };
+        self.size = match self.kind {
+            FileType::RegularFile => entry.size,
+            FileType::Symlink => self.target.len() as u64,
+            _ => 0,
+        };
+        self.rdev = match self.kind {
+            FileType::CharDevice | FileType::BlockDevice => {
+                libc::makedev(entry.device.0, entry.device.1) as u32
+            }
+            _ => 0,
+        };
+        self.xattrs = entry
+            .xattrs
+            .iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.clone()))
+            .collect();
+    }
+}
+
+// Components of an archive path, None for paths escaping the root.
+fn components(path: &Path) -> Option<Vec<OsString>> {
+    let mut out = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => out.push(name.to_owned()),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(out)
+}
+
+// Index of the directory at `path`, created with its missing ancestors.
+fn make_dirs(nodes: &mut Vec<Node>, path: &[OsString]) -> usize {
+    let mut dir = 0;
+    for name in path {
+        dir = match nodes[dir].children.get(name) {
+            Some(&child) if nodes[child].kind == FileType::Directory => child,
+            _ => {
+                nodes.push(Node::implicit_dir(dir));
+                let child = nodes.len() - 1;
+                nodes[dir].children.insert(name.clone(), child);
+                child
+            }
+        };
+    }
+    dir
+}
+
+fn resolve(nodes: &[Node], path: &[OsString]) -> Option<usize> {
+    path.iter()
+        .try_fold(0, |dir, name| nodes[dir].children.get(name).copied())
+}
+
+// Build the tree of the archive, the root being the first node.
+fn build_tree(entries: &[IndexEntry]) -> io::Result<Vec<Node>> {
+    let mut nodes = vec![Node::implicit_dir(0)];
+    for entry in entries {
+        let Some(path) = components(&entry.path) else {
+            continue;
+        };
+        let Some((name, dirs)) = path.split_last() else {
+            if entry.kind == EntryKind::Directory {
+                nodes[0].update(entry);
+            }
+            continue;
+        };
+        let parent = make_dirs(&mut nodes, dirs);
+        let name_bytes = name.as_bytes();
+        if name_bytes == OPAQUE_MARKER {
+            let opaque = (PRIVILEGED_OPAQUE_XATTR.as_bytes().to_vec(), b"y".to_vec());
+            nodes[parent].xattrs.push(opaque);
+            continue;
+        }
+        let (name, node) = match name_bytes.strip_prefix(WHITEOUT_PREFIX) {
+            Some(hidden) => (OsStr::from_bytes(hidden), Node::whiteout(entry, parent)),
+            None if entry.kind == EntryKind::Hardlink => {
+                let target = entry
+                    .link
+                    .as_deref()
+                    .and_then(components)
+                    .and_then(|path| resolve(&nodes, &path))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("hard link {} to a missing entry", entry.path.display()),
+                        )
+                    })?;
+                nodes[target].nlink += 1;
+                nodes[parent].children.insert(name.clone(), target);
+                continue;
+            }
+            None => (name.as_os_str(), Node::new(entry, parent)),
+        };
+        match nodes[parent].children.get(name) {
+            Some(&existing)
+                if nodes[existing].kind == FileType::Directory
+                    && node.kind == FileType::Directory =>
+            {
+                nodes[existing].update(entry);
+            }
+            _ => {
+                nodes.push(node);
+                let child = nodes.len() - 1;
+                nodes[parent].children.insert(name.to_owned(), child);
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+// Where a layer reads the data of its files from.
+#[async_trait]
+trait Content: Send + Sync {
+    /// Read `len` bytes at `offset` of the file whose data is at `position`.
+    async fn read(&self, position: u64, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+}
+
+// A tarball in a local file, file data being at its position in the uncompressed archive.
+struct LocalBlob {
+    blob: File,
+    index: TarIndex,
+}
+
+#[async_trait]
+impl Content for LocalBlob {
+    async fn read(&self, position: u64, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.index.read(&self.blob, position + offset, len)
+    }
+}
+
+/// A read-only filesystem backed by a plain or gzip-compressed tarball.
+///
+/// Inode numbers follow the order of the entries in the archive, so they are stable across
+/// mounts of the same blob.
+pub struct TarLayer {
+    content: Box<dyn Content>,
+    nodes: Vec<Node>,
+}
+
+impl TarLayer {
+    /// Open the tarball at `path`, indexing it first.
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let index = TarIndex::build(path.as_ref())?;
+        Self::with_index(path, index)
+    }
+
+    /// Open the tarball at `path` with an index built from it earlier.
+    pub fn with_index(path: impl AsRef<Path>, index: TarIndex) -> io::Result<Self> {
+        let blob = File::open(path)?;
+        index.check(&blob)?;
+        let nodes = build_tree(&index.entries)?;
+        Ok(TarLayer {
+            content: Box::new(LocalBlob { blob, index }),
+            nodes,
+        })
+    }
+
+    // Node indexes are offset by one so the root is the root inode.
+    fn node(&self, inode: Inode) -> io::Result<&Node> {
+        inode
+            .checked_sub(ROOT_INODE)
+            .and_then(|i| self.nodes.get(i as usize))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn inode_of(index: usize) -> Inode {
+        index as Inode + ROOT_INODE
+    }
+
+    fn stat(&self, inode: Inode, node: &Node) -> Stat64 {
+        let mut st: Stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = (node.kind.const_into_mode_t() | node.perm as u32) as _;
+        st.st_nlink = node.nlink as _;
+        st.st_uid = node.uid;
+        st.st_gid = node.gid;
+        st.st_rdev = node.rdev as _;
+        st.st_size = node.size as _;
+        st.st_blksize = BLOCK_SIZE as _;
+        st.st_blocks = node.size.div_ceil(512) as _;
+        st.st_atime = node.mtime as _;
+        st.st_mtime = node.mtime as _;
+        st.st_ctime = node.mtime as _;
+        st
+    }
+
+    fn attr(&self, inode: Inode, node: &Node) -> FileAttr {
+        convert_stat64_to_file_attr(self.stat(inode, node))
+    }
+
+    pub(crate) fn getattr_stat(&self, inode: Inode) -> io::Result<(Stat64, Duration)> {
+        let node = self.node(inode)?;
+        Ok((self.stat(inode, node), TTL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+    use rfuse3::raw::reply::ReplyXAttr;
+    use rfuse3::raw::{Filesystem, Request};
+
+    use super::*;
+    use crate::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+    use crate::unionfs::layer::Layer;
+    use crate::unionfs::{BoxedLayer, OverlayFs, config::Config};
+    use std::sync::Arc;
+
+    fn add(tar: &mut tar::Builder<Vec<u8>>, path: &str, kind: tar::EntryType, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(if kind.is_dir() { 0o755 } else { 0o640 });
+        header.set_uid(1000);
+        header.set_gid(2000);
+        header.set_mtime(1_700_000_000);
+        header.set_size(data.len() as u64);
+        tar.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn add_link(tar: &mut tar::Builder<Vec<u8>>, path: &str, kind: tar::EntryType, to: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1_700_000_000);
+        header.set_size(0);
+        tar.append_link(&mut header, path, to).unwrap();
+    }
+
+    // An OCI layer holding:
+    //   /etc/conf   10000 bytes
+    //   /etc/hard   hard link to /etc/conf
+    //   /etc/link   -> conf
+    //   /etc/.wh.gone
+    //   /opaque/.wh..wh..opq and /opaque/new
+    //   /bin/sh     with an implicit /bin
+    fn build_layer(content: &[u8]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        add(&mut tar, "etc/", tar::EntryType::Directory, b"");
+        add(&mut tar, "etc/conf", tar::EntryType::Regular, content);
+        add_link(&mut tar, "etc/hard", tar::EntryType::Link, "etc/conf");
+        add_link(&mut tar, "etc/link", tar::EntryType::Symlink, "conf");
+        add(&mut tar, "etc/.wh.gone", tar::EntryType::Regular, b"");
+        add(&mut tar, "opaque/", tar::EntryType::Directory, b"");
+        add(
+            &mut tar,
+            "opaque/.wh..wh..opq",
+            tar::EntryType::Regular,
+            b"",
+        );
+        add(&mut tar, "opaque/new", tar::EntryType::Regular, b"new");
+        add(&mut tar, "./bin/sh", tar::EntryType::Regular, b"#!");
+        tar.into_inner().unwrap()
+    }
+
+    // Compress `tar` with a gzip member per 4KiB, like seekable formats do.
+    fn gzip_members(tar: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in tar.chunks(4096) {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(chunk).unwrap();
+            out.extend_from_slice(&encoder.finish().unwrap());
+        }
+        out
+    }
+
+    async fn lookup(fs: &TarLayer, parent: Inode, name: &str) -> FileAttr {
+        fs.lookup(Request::default(), parent, OsStr::new(name))
+            .await
+            .unwrap()
+            .attr
+    }
+
+    #[tokio::test]
+    async fn test_tar_layer() {
+        let content: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("layer.tar");
+        std::fs::write(&path, build_layer(&content)).unwrap();
+        let fs = TarLayer::new(&path).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let etc = lookup(&fs, 1, "etc").await.ino;
+        let conf = lookup(&fs, etc, "conf").await;
+        assert_eq!((conf.size, conf.perm, conf.nlink), (10000, 0o640, 2));
+        assert_eq!((conf.uid, conf.gid), (1000, 2000));
+        let data = fs.read(req, conf.ino, 0, 4000, 5000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[4000..9000]);
+        let data = fs.read(req, conf.ino, 0, 9000, 5000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[9000..]);
+        assert_eq!(lookup(&fs, etc, "hard").await.ino, conf.ino);
+        let link = lookup(&fs, etc, "link").await.ino;
+        assert_eq!(fs.readlink(req, link).await.unwrap().data.as_ref(), b"conf");
+        let err = fs
+            .open(req, conf.ino, libc::O_WRONLY as u32)
+            .await
+            .unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::EROFS));
+
+        let gone = lookup(&fs, etc, "gone").await.ino;
+        assert!(fs.is_whiteout(req, gone).await.unwrap());
+        let opaque = lookup(&fs, 1, "opaque").await.ino;
+        assert!(fs.is_opaque(req, opaque).await.unwrap());
+        assert!(!fs.is_opaque(req, etc).await.unwrap());
+        let size = fs
+            .getxattr(req, opaque, OsStr::new(PRIVILEGED_OPAQUE_XATTR), 0)
+            .await
+            .unwrap();
+        assert!(matches!(size, ReplyXAttr::Size(1)));
+
+        let bin = lookup(&fs, 1, "bin").await;
+        assert_eq!((bin.kind, bin.perm), (FileType::Directory, 0o755));
+        let names: Vec<_> = futures::StreamExt::collect::<Vec<_>>(
+            fs.readdir(req, etc, 0, 0).await.unwrap().entries,
+        )
+        .await
+        .into_iter()
+        .map(|e| e.unwrap().name)
+        .collect();
+        assert_eq!(names, [".", "..", "conf", "gone", "hard", "link"]);
+    }
+
+    #[tokio::test]
+    async fn test_tar_gz_stored_index() {
+        let content = b"layer".repeat(3000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("layer.tar.gz");
+        std::fs::write(&path, gzip_members(&build_layer(&content))).unwrap();
+        let index_path = tmp.path().join("layer.index");
+        TarIndex::build(&path).unwrap().save(&index_path).unwrap();
+
+        let index = TarIndex::load(&index_path).unwrap();
+        assert_eq!(index.len(), 9);
+        let fs = TarLayer::with_index(&path, index.clone()).unwrap();
+        let req = Request::default();
+        let etc = lookup(&fs, 1, "etc").await.ino;
+        let conf = lookup(&fs, etc, "conf").await.ino;
+        let data = fs.read(req, conf, 0, 4000, 8000).await.unwrap().data;
+        assert_eq!(data.as_ref(), &content[4000..12000]);
+
+        // The index doesn't describe another blob.
+        std::fs::write(&path, build_layer(&content)).unwrap();
+        let err = TarLayer::with_index(&path, index).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_tar_lower_layer() {
+        let content = b"layer".repeat(100);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("layer.tar");
+        std::fs::write(&path, build_layer(&content)).unwrap();
+        let base = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(base.path().join("etc")).unwrap();
+        std::fs::write(base.path().join("etc/gone"), b"old").unwrap();
+        std::fs::create_dir_all(base.path().join("opaque")).unwrap();
+        std::fs::write(base.path().join("opaque/hidden"), b"old").unwrap();
+        std::fs::write(base.path().join("kept"), b"old").unwrap();
+
+        let layer = |dir: &Path| {
+            let dir = dir.to_path_buf();
+            async move {
+                new_passthroughfs_layer(PassthroughArgs {
+                    root_dir: dir,
+                    mapping: None::<&str>,
+                    io_engine: Default::default(),
+                })
+                .await
+                .unwrap()
+            }
+        };
+        let upper = tempfile::tempdir().unwrap();
+        let lowers: Vec<Arc<BoxedLayer>> = vec![
+            Arc::new(TarLayer::new(&path).unwrap()),
+            Arc::new(layer(base.path()).await),
+        ];
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let upper = Arc::new(layer(upper.path()).await);
+        let fs = OverlayFs::new(Some(upper), lowers, config, 1).unwrap();
+        let req = Request::default();
+        fs.init(req).await.unwrap();
+
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        let err = fs.lookup(req, etc, OsStr::new("gone")).await.unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+        let opaque = fs
+            .lookup(req, 1, OsStr::new("opaque"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let err = fs
+            .lookup(req, opaque, OsStr::new("hidden"))
+            .await
+            .unwrap_err();
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(libc::ENOENT));
+        fs.lookup(req, opaque, OsStr::new("new")).await.unwrap();
+        fs.lookup(req, 1, OsStr::new("kept")).await.unwrap();
+
+        let conf = fs.lookup(req, etc, OsStr::new("conf")).await.unwrap();
+        let fh = fs
+            .open(req, conf.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let data = fs
+            .read(req, conf.attr.ino, fh, 0, 65536)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(data.as_ref(), &content[..]);
+    }
+}
@@ -57,8 +57,7 @@ impl Filesystem for OverlayFs {
 
     /// look up a directory entry by name and get its attributes.
     async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
-        let tmp = name.to_string_lossy().to_string();
-        let result = self.do_lookup(req, parent, tmp.as_str()).await;
+        let result = self.do_lookup(req, parent, name).await;
         match result {
             Ok(e) => Ok(e),
             Err(err) => Err(err.into()),
@@ -102,7 +101,7 @@ impl Filesystem for OverlayFs {
             }
         }
 
-        let node: Arc<super::OverlayInode> = self.lookup_node(req, inode, "").await?;
+        let node: Arc<super::OverlayInode> = self.lookup_node(req, inode, OsStr::new("")).await?;
         let (layer, _, lower_inode) = node.first_layer_inode().await;
         let mut re = layer.getattr(req, lower_inode, None, flags).await?;
         re.attr.ino = inode;
@@ -148,7 +147,7 @@ impl Filesystem for OverlayFs {
             }
         }
 
-        let mut node = self.lookup_node(req, inode, "").await?;
+        let mut node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if !node.in_upper_layer().await {
             node = self.copy_node_up(req, node.clone()).await?
@@ -165,7 +164,7 @@ impl Filesystem for OverlayFs {
     async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
         trace!("READLINK: inode: {inode}\n");
 
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -184,14 +183,10 @@ impl Filesystem for OverlayFs {
         link: &OsStr,
     ) -> Result<ReplyEntry> {
         // soft link
-        let sname = name.to_string_lossy().into_owned().to_owned();
-        let slinkname = link.to_string_lossy().into_owned().to_owned();
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
+        self.do_symlink(req, link, &pnode, name).await?;
 
-        let pnode = self.lookup_node(req, parent, "").await?;
-        self.do_symlink(req, slinkname.as_str(), &pnode, sname.as_str())
-            .await?;
-
-        self.do_lookup(req, parent, sname.as_str())
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
@@ -207,17 +202,14 @@ impl Filesystem for OverlayFs {
         mode: u32,
         rdev: u32,
     ) -> Result<ReplyEntry> {
-        let sname = name.to_string_lossy().to_string();
-
         // Check if parent exists.
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        self.do_mknod(req, &pnode, sname.as_str(), mode, rdev, 0)
-            .await?;
-        self.do_lookup(req, parent, sname.as_str())
+        self.do_mknod(req, &pnode, name, mode, rdev, 0).await?;
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
@@ -231,17 +223,14 @@ impl Filesystem for OverlayFs {
         mode: u32,
         umask: u32,
     ) -> Result<ReplyEntry> {
-        let sname = name.to_string_lossy().to_string();
-
         // no entry or whiteout
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        self.do_mkdir(req, pnode, sname.as_str(), mode, umask)
-            .await?;
-        self.do_lookup(req, parent, sname.as_str())
+        self.do_mkdir(req, pnode, name, mode, umask).await?;
+        self.do_lookup(req, parent, name)
             .await
             .map_err(|e| e.into())
     }
@@ -282,16 +271,15 @@ impl Filesystem for OverlayFs {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<ReplyEntry> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
 
-        let newpnode = self.lookup_node(req, new_parent, "").await?;
+        let newpnode = self.lookup_node(req, new_parent, OsStr::new("")).await?;
         if newpnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
-        let new_name = new_name.to_str().unwrap();
         // trace!(
         //     "LINK: inode: {}, new_parent: {}, trying to do_link: src_inode: {}, newpnode: {}",
         //     inode, new_parent, node.inode, newpnode.inode
@@ -343,7 +331,7 @@ impl Filesystem for OverlayFs {
             }
         }
         // lookup node
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         // whiteout node
         if node.whiteout.load(Ordering::Relaxed) {
@@ -384,6 +372,7 @@ impl Filesystem for OverlayFs {
         Ok(ReplyOpen {
             fh: hd,
             flags: opts.bits(),
+            backing_id: None,
         })
     }
 
@@ -574,7 +563,7 @@ impl Filesystem for OverlayFs {
         flags: u32,
         position: u32,
     ) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -602,7 +591,7 @@ impl Filesystem for OverlayFs {
         name: &OsStr,
         size: u32,
     ) -> Result<ReplyXAttr> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -618,7 +607,7 @@ impl Filesystem for OverlayFs {
     /// If `size` is too small, return `Err<ERANGE>`.  Otherwise, use
     /// [`ReplyXAttr::Data`] to send the attribute list, or return an error.
     async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
@@ -628,7 +617,7 @@ impl Filesystem for OverlayFs {
 
     /// remove an extended attribute.
     async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -662,37 +651,19 @@ impl Filesystem for OverlayFs {
             return Err(Error::from_raw_os_error(libc::ENOSYS).into());
         }
 
-        let node = self.lookup_node(req, inode, "").await;
-        match node {
-            Ok(n) => {
-                if n.whiteout.load(Ordering::Relaxed) {
-                    return Err(Error::from_raw_os_error(libc::ENOENT).into());
-                }
-            }
-            Err(e) => {
-                if e.raw_os_error() == Some(libc::ENOENT) {
-                    trace!("flush: inode {inode} is stale");
-                } else {
-                    return Err(e.into());
-                }
-            }
-        }
-
-        let (layer, real_inode, real_handle) = self.find_real_info_from_handle(fh).await?;
-
-        // FIXME: need to test if inode matches corresponding handle?
-        if inode
-            != self
-                .handles
-                .lock()
-                .await
-                .get(&fh)
-                .map(|h| h.node.inode)
-                .unwrap_or(0)
+        // Go by the handle rather than the node, a file unlinked while open is still flushed
+        // so the write-back errors of its layer reach close(2).
+        if self
+            .handles
+            .lock()
+            .await
+            .get(&fh)
+            .is_none_or(|h| h.node.inode != inode)
         {
-            return Err(Error::other("inode does not match handle").into());
+            return Err(Error::from_raw_os_error(libc::EBADF).into());
         }
 
+        let (layer, real_inode, real_handle) = self.find_real_info_from_handle(fh).await?;
         trace!("flushing, real_inode: {real_inode}, real_handle: {real_handle}");
         layer.flush(req, real_inode, real_handle, lock_owner).await
     }
@@ -711,7 +682,7 @@ impl Filesystem for OverlayFs {
         }
 
         // lookup node
-        let node = self.lookup_node(req, inode, ".").await?;
+        let node = self.lookup_node(req, inode, OsStr::new(".")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -741,7 +712,11 @@ impl Filesystem for OverlayFs {
             }),
         );
 
-        Ok(ReplyOpen { fh: handle, flags })
+        Ok(ReplyOpen {
+            fh: handle,
+            flags,
+            backing_id: None,
+        })
     }
 
     /// read directory. `offset` is used to track the offset of the directory entries. `fh` will
@@ -872,7 +847,7 @@ impl Filesystem for OverlayFs {
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -907,7 +882,7 @@ impl Filesystem for OverlayFs {
         flags: u32,
     ) -> Result<ReplyCreated> {
         // Parent doesn't exist.
-        let pnode = self.lookup_node(req, parent, "").await?;
+        let pnode = self.lookup_node(req, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
         }
@@ -932,7 +907,7 @@ impl Filesystem for OverlayFs {
         let final_handle = self
             .do_create(req, &pnode, name, mode, flags.try_into().unwrap())
             .await?;
-        let entry = self.do_lookup(req, parent, name.to_str().unwrap()).await?;
+        let entry = self.do_lookup(req, parent, name).await?;
         let fh = final_handle
             .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "Handle not found"))?;
 
@@ -949,6 +924,7 @@ impl Filesystem for OverlayFs {
             generation: entry.generation,
             fh,
             flags: opts.bits(),
+            backing_id: None,
         })
     }
 
@@ -1009,7 +985,7 @@ impl Filesystem for OverlayFs {
         offset: u64,
         whence: u32,
     ) -> Result<ReplyLSeek> {
-        let node = self.lookup_node(req, inode, "").await?;
+        let node = self.lookup_node(req, inode, OsStr::new("")).await?;
 
         if node.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT).into());
@@ -1138,6 +1114,7 @@ mod tests {
             let layer = new_passthroughfs_layer(PassthroughArgs {
                 root_dir: lower.clone(),
                 mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await
             .unwrap();
@@ -1148,6 +1125,7 @@ mod tests {
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: upperdir,
                 mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await
             .unwrap(),
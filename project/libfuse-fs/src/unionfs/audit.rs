@@ -0,0 +1,813 @@
+//! Layer wrapper recording the changes made to another.
+//!
+//! An [`AuditLayer`] passes every request to the layer it wraps, and records the mutating
+//! ones with the uid, gid and pid of their requester and their outcome. Records go to a
+//! file opened for appending, one JSON object per line, or to a callback. Reads, lookups and
+//! other requests which change nothing aren't recorded.
+//!
+//! Inodes and names are those of the wrapped layer: as the upper layer of an
+//! [`OverlayFs`](super::OverlayFs), copy-ups and whiteouts show up as the creations they are.
+
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result, SetAttr};
+use serde::Serialize;
+use tracing::error;
+
+use super::layer::Layer;
+
+/// A mutating request made to an [`AuditLayer`].
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    #[serde(flatten)]
+    pub op: AuditOp,
+    /// Error the request failed with, None if it succeeded.
+    pub errno: Option<i32>,
+}
+
+/// What an [`AuditRecord`] changed. Names are lossily converted to UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AuditOp {
+    Create {
+        parent: Inode,
+        name: String,
+        mode: u32,
+    },
+    Mknod {
+        parent: Inode,
+        name: String,
+        mode: u32,
+        rdev: u32,
+    },
+    Mkdir {
+        parent: Inode,
+        name: String,
+        mode: u32,
+    },
+    Symlink {
+        parent: Inode,
+        name: String,
+        target: String,
+    },
+    Link {
+        inode: Inode,
+        new_parent: Inode,
+        new_name: String,
+    },
+    Unlink {
+        parent: Inode,
+        name: String,
+    },
+    Rmdir {
+        parent: Inode,
+        name: String,
+    },
+    Rename {
+        parent: Inode,
+        name: String,
+        new_parent: Inode,
+        new_name: String,
+        flags: u32,
+    },
+    Write {
+        inode: Inode,
+        offset: u64,
+        len: u64,
+    },
+    /// Also recorded for an open truncating the file, with a size of 0.
+    Setattr {
+        inode: Inode,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+    },
+    Fallocate {
+        inode: Inode,
+        offset: u64,
+        len: u64,
+        mode: u32,
+    },
+    Setxattr {
+        inode: Inode,
+        name: String,
+    },
+    Removexattr {
+        inode: Inode,
+        name: String,
+    },
+}
+
+/// Callback receiving the records of an [`AuditLayer`].
+pub type AuditCallback = Box<dyn Fn(&AuditRecord) + Send + Sync>;
+
+enum Sink {
+    File(Mutex<File>),
+    Callback(AuditCallback),
+}
+
+fn lossy(name: &OsStr) -> String {
+    name.to_string_lossy().into_owned()
+}
+
+/// Layer recording the changes made to another, see the [module docs](self).
+pub struct AuditLayer<L> {
+    inner: L,
+    sink: Sink,
+}
+
+impl<L: Layer> AuditLayer<L> {
+    /// Wrap `inner`, appending records to the file at `path`, created if missing.
+    pub fn to_file(inner: L, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLayer {
+            inner,
+            sink: Sink::File(Mutex::new(file)),
+        })
+    }
+
+    /// Wrap `inner`, passing records to `callback` as requests complete.
+    pub fn with_callback(inner: L, callback: AuditCallback) -> Self {
+        AuditLayer {
+            inner,
+            sink: Sink::Callback(callback),
+        }
+    }
+
+    /// The audited layer.
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Record `op` made by `req` with outcome `res`, and pass `res` on.
+    pub(crate) fn record<T>(&self, req: Request, op: AuditOp, res: Result<T>) -> Result<T> {
+        let record = AuditRecord {
+            time: Utc::now(),
+            uid: req.uid,
+            gid: req.gid,
+            pid: req.pid,
+            op,
+            errno: res
+                .as_ref()
+                .err()
+                .and_then(|e| io::Error::from(*e).raw_os_error()),
+        };
+        match &self.sink {
+            Sink::File(file) => {
+                let written = serde_json::to_vec(&record)
+                    .map_err(io::Error::other)
+                    .and_then(|mut line| {
+                        line.push(b'\n');
+                        // The whole line at once, so concurrent records can't interleave.
+                        file.lock().unwrap().write_all(&line)
+                    });
+                if let Err(e) = written {
+                    error!("audit layer: failed to record {:?}: {e}", record.op);
+                }
+            }
+            Sink::Callback(callback) => callback(&record),
+        }
+        res
+    }
+}
+
+impl<L: Layer> Filesystem for AuditLayer<L> {
+    /// initialize filesystem.
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        self.inner.init(req).await
+    }
+
+    /// clean up filesystem.
+    async fn destroy(&self, req: Request) {
+        if let Sink::File(file) = &self.sink
+            && let Err(e) = file.lock().unwrap().sync_all()
+        {
+            error!("audit layer: failed to sync the log: {e}");
+        }
+        self.inner.destroy(req).await
+    }
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        self.inner.lookup(req, parent, name).await
+    }
+
+    /// forget an inode.
+    async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
+        self.inner.forget(req, inode, nlookup).await
+    }
+
+    /// forget more than one inode.
+    async fn batch_forget(&self, req: Request, inodes: &[(Inode, u64)]) {
+        self.inner.batch_forget(req, inodes).await
+    }
+
+    /// get file attributes.
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        self.inner.getattr(req, inode, fh, flags).await
+    }
+
+    /// get extended file attributes.
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        self.inner.statx(req, inode, fh, flags, mask).await
+    }
+
+    /// set file attributes. Only changes of mode, owner and size are recorded, not of times.
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        let op = AuditOp::Setattr {
+            inode,
+            mode: set_attr.mode,
+            uid: set_attr.uid,
+            gid: set_attr.gid,
+            size: set_attr.size,
+        };
+        let recorded = set_attr.mode.is_some()
+            || set_attr.uid.is_some()
+            || set_attr.gid.is_some()
+            || set_attr.size.is_some();
+        let res = self.inner.setattr(req, inode, fh, set_attr).await;
+        if !recorded {
+            return res;
+        }
+        self.record(req, op, res)
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        self.inner.readlink(req, inode).await
+    }
+
+    /// create a symbolic link.
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let res = self.inner.symlink(req, parent, name, link).await;
+        let op = AuditOp::Symlink {
+            parent,
+            name: lossy(name),
+            target: lossy(link),
+        };
+        self.record(req, op, res)
+    }
+
+    /// create file node.
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        let res = self.inner.mknod(req, parent, name, mode, rdev).await;
+        let op = AuditOp::Mknod {
+            parent,
+            name: lossy(name),
+            mode,
+            rdev,
+        };
+        self.record(req, op, res)
+    }
+
+    /// create a directory.
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let res = self.inner.mkdir(req, parent, name, mode, umask).await;
+        let op = AuditOp::Mkdir {
+            parent,
+            name: lossy(name),
+            mode,
+        };
+        self.record(req, op, res)
+    }
+
+    /// remove a file.
+    async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let res = self.inner.unlink(req, parent, name).await;
+        let op = AuditOp::Unlink {
+            parent,
+            name: lossy(name),
+        };
+        self.record(req, op, res)
+    }
+
+    /// remove a directory.
+    async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let res = self.inner.rmdir(req, parent, name).await;
+        let op = AuditOp::Rmdir {
+            parent,
+            name: lossy(name),
+        };
+        self.record(req, op, res)
+    }
+
+    /// rename a file or directory.
+    async fn rename(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        let res = self
+            .inner
+            .rename(req, parent, name, new_parent, new_name)
+            .await;
+        let op = AuditOp::Rename {
+            parent,
+            name: lossy(name),
+            new_parent,
+            new_name: lossy(new_name),
+            flags: 0,
+        };
+        self.record(req, op, res)
+    }
+
+    /// rename a file or directory with flags.
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let res = self
+            .inner
+            .rename2(req, parent, name, new_parent, new_name, flags)
+            .await;
+        let op = AuditOp::Rename {
+            parent,
+            name: lossy(name),
+            new_parent,
+            new_name: lossy(new_name),
+            flags,
+        };
+        self.record(req, op, res)
+    }
+
+    /// create a hard link.
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let res = self.inner.link(req, inode, new_parent, new_name).await;
+        let op = AuditOp::Link {
+            inode,
+            new_parent,
+            new_name: lossy(new_name),
+        };
+        self.record(req, op, res)
+    }
+
+    /// open a file. Truncating it is recorded.
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        let res = self.inner.open(req, inode, flags).await;
+        let write = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        if !write || flags as i32 & libc::O_TRUNC == 0 {
+            return res;
+        }
+        let op = AuditOp::Setattr {
+            inode,
+            mode: None,
+            uid: None,
+            gid: None,
+            size: Some(0),
+        };
+        self.record(req, op, res)
+    }
+
+    /// create and open a file.
+    async fn create(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let res = self.inner.create(req, parent, name, mode, flags).await;
+        let op = AuditOp::Create {
+            parent,
+            name: lossy(name),
+            mode,
+        };
+        self.record(req, op, res)
+    }
+
+    /// read data.
+    async fn read(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        self.inner.read(req, inode, fh, offset, size).await
+    }
+
+    /// write data. The range written is recorded, not the data.
+    async fn write(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<ReplyWrite> {
+        let res = self
+            .inner
+            .write(req, inode, fh, offset, data, write_flags, flags)
+            .await;
+        let len = res.as_ref().map_or(data.len() as u64, |w| w.written as u64);
+        let op = AuditOp::Write { inode, offset, len };
+        self.record(req, op, res)
+    }
+
+    /// get filesystem statistics.
+    async fn statfs(&self, req: Request, inode: Inode) -> Result<ReplyStatFs> {
+        self.inner.statfs(req, inode).await
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        self.inner
+            .release(req, inode, fh, flags, lock_owner, flush)
+            .await
+    }
+
+    /// synchronize file contents.
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.inner.fsync(req, inode, fh, datasync).await
+    }
+
+    /// set an extended attribute. The name is recorded, not the value.
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        let res = self
+            .inner
+            .setxattr(req, inode, name, value, flags, position)
+            .await;
+        let op = AuditOp::Setxattr {
+            inode,
+            name: lossy(name),
+        };
+        self.record(req, op, res)
+    }
+
+    /// get an extended attribute.
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        self.inner.getxattr(req, inode, name, size).await
+    }
+
+    /// list extended attribute names.
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        self.inner.listxattr(req, inode, size).await
+    }
+
+    /// remove an extended attribute.
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        let res = self.inner.removexattr(req, inode, name).await;
+        let op = AuditOp::Removexattr {
+            inode,
+            name: lossy(name),
+        };
+        self.record(req, op, res)
+    }
+
+    /// flush method.
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        self.inner.flush(req, inode, fh, lock_owner).await
+    }
+
+    /// open a directory.
+    async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        self.inner.opendir(req, inode, flags).await
+    }
+
+    /// read directory.
+    async fn readdir<'a>(
+        &'a self,
+        req: Request,
+        parent: Inode,
+        fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        self.inner.readdir(req, parent, fh, offset).await
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        self.inner.releasedir(req, inode, fh, flags).await
+    }
+
+    /// synchronize directory contents.
+    async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.inner.fsyncdir(req, inode, fh, datasync).await
+    }
+
+    /// test for a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        self.inner
+            .getlk(req, inode, fh, lock_owner, start, end, r#type, pid)
+            .await
+    }
+
+    /// acquire, modify or release a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        self.inner
+            .setlk(req, inode, fh, lock_owner, start, end, r#type, pid, block)
+            .await
+    }
+
+    /// check file access permissions.
+    async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
+        self.inner.access(req, inode, mask).await
+    }
+
+    /// allocate space for an open file.
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        let res = self
+            .inner
+            .fallocate(req, inode, fh, offset, length, mode)
+            .await;
+        let op = AuditOp::Fallocate {
+            inode,
+            offset,
+            len: length,
+            mode,
+        };
+        self.record(req, op, res)
+    }
+
+    /// read directory entries with their attributes.
+    async fn readdirplus<'a>(
+        &'a self,
+        req: Request,
+        parent: Inode,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        self.inner
+            .readdirplus(req, parent, fh, offset, lock_owner)
+            .await
+    }
+
+    /// find next data or hole after the specified offset.
+    async fn lseek(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        self.inner.lseek(req, inode, fh, offset, whence).await
+    }
+
+    /// copy a range of data from one file to another. Recorded as a write of the output file.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_file_range(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh_in: u64,
+        off_in: u64,
+        inode_out: Inode,
+        fh_out: u64,
+        off_out: u64,
+        length: u64,
+        flags: u64,
+    ) -> Result<ReplyCopyFileRange> {
+        let res = self
+            .inner
+            .copy_file_range(
+                req, inode, fh_in, off_in, inode_out, fh_out, off_out, length, flags,
+            )
+            .await;
+        let len = res.as_ref().map_or(length, |copied| copied.copied);
+        let op = AuditOp::Write {
+            inode: inode_out,
+            offset: off_out,
+            len,
+        };
+        self.record(req, op, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::memfs::MemoryLayer;
+
+    fn req() -> Request {
+        Request {
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_layer_callback() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let callback: AuditCallback =
+            Box::new(move |record: &AuditRecord| sink.lock().unwrap().push(record.clone()));
+        let fs = AuditLayer::with_callback(MemoryLayer::new(), callback);
+
+        let file = fs
+            .create(req(), 1, OsStr::new("a"), 0o644, 0)
+            .await
+            .unwrap();
+        fs.write(req(), file.attr.ino, file.fh, 4, b"data", 0, 0)
+            .await
+            .unwrap();
+        fs.read(req(), file.attr.ino, file.fh, 0, 8).await.unwrap();
+        fs.lookup(req(), 1, OsStr::new("a")).await.unwrap();
+        fs.rename(req(), 1, OsStr::new("a"), 1, OsStr::new("b"))
+            .await
+            .unwrap();
+        fs.unlink(req(), 1, OsStr::new("missing"))
+            .await
+            .unwrap_err();
+
+        let records = records.lock().unwrap();
+        let ops: Vec<_> = records.iter().map(|r| r.op.clone()).collect();
+        let ino = file.attr.ino;
+        assert_eq!(
+            ops,
+            [
+                AuditOp::Create {
+                    parent: 1,
+                    name: "a".into(),
+                    mode: 0o644
+                },
+                AuditOp::Write {
+                    inode: ino,
+                    offset: 4,
+                    len: 4
+                },
+                AuditOp::Rename {
+                    parent: 1,
+                    name: "a".into(),
+                    new_parent: 1,
+                    new_name: "b".into(),
+                    flags: 0
+                },
+                AuditOp::Unlink {
+                    parent: 1,
+                    name: "missing".into()
+                },
+            ]
+        );
+        assert!(
+            records
+                .iter()
+                .all(|r| (r.uid, r.gid, r.pid) == (1000, 100, 42))
+        );
+        assert_eq!(records[2].errno, None);
+        assert_eq!(records[3].errno, Some(libc::ENOENT));
+    }
+
+    #[tokio::test]
+    async fn test_audit_layer_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        std::fs::write(&path, b"{\"op\":\"earlier\"}\n").unwrap();
+        let fs = AuditLayer::to_file(MemoryLayer::new(), &path).unwrap();
+        fs.mkdir(req(), 1, OsStr::new("d"), 0o755, 0).await.unwrap();
+        let set_attr = SetAttr {
+            mode: Some(0o700),
+            ..Default::default()
+        };
+        let dir_ino = fs.lookup(req(), 1, OsStr::new("d")).await.unwrap().attr.ino;
+        fs.setattr(req(), dir_ino, None, set_attr).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["op"], "earlier");
+        assert_eq!(lines[1]["op"], "mkdir");
+        assert_eq!(lines[1]["name"], "d");
+        assert_eq!(lines[1]["uid"], 1000);
+        assert_eq!(lines[2]["op"], "setattr");
+        assert_eq!(lines[2]["mode"], 0o700);
+        assert_eq!(lines[2]["errno"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,677 @@
+//! Read-only layer merging several lower layers ahead of time.
+//!
+//! Every lookup in the [`OverlayFs`](super::OverlayFs) goes through all of its lowers, which
+//! gets slow for images of many layers. A [`CompositeLayer`] walks its layers once when it is
+//! built and keeps the merged tree in memory, so the overlay sees a single lower and data
+//! requests go straight to the layer holding the file.
+//!
+//! Whiteouts and opaque directories are applied between the merged layers. A whiteout with
+//! nothing above it stays in the merged tree, and a directory hiding what is below it is
+//! reported opaque, so layers under the composite are still masked the same way.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{StreamExt as _, stream};
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result};
+use tracing::debug;
+
+use super::BoxedLayer;
+
+#[cfg(target_os = "macos")]
+type Stat64 = libc::stat;
+#[cfg(target_os = "linux")]
+type Stat64 = libc::stat64;
+
+const ROOT_INODE: Inode = 1;
+// The layers are read-only, the merged tree never changes.
+const TTL: Duration = Duration::from_secs(3600);
+
+struct Node {
+    // Layer and inode of the top-most entry, which is served for this path.
+    layer: usize,
+    inode: Inode,
+    kind: FileType,
+    opaque: bool,
+    parent: usize,
+    children: BTreeMap<OsString, usize>,
+}
+
+// An entry of a directory being merged.
+struct Candidate {
+    layer: usize,
+    entry: ReplyEntry,
+    whiteout: bool,
+    // Directories of this name in the merged layers, top-most first.
+    dirs: Vec<(usize, Inode)>,
+    // Whether the layers below the last one in `dirs` are hidden.
+    closed: bool,
+}
+
+/// Lower layer made of other lower layers, merged once, see the [module docs](self).
+pub struct CompositeLayer {
+    layers: Vec<Arc<BoxedLayer>>,
+    nodes: Vec<Node>,
+}
+
+impl CompositeLayer {
+    /// Merge `layers`, the top-most first like the lowers of an
+    /// [`OverlayFs`](super::OverlayFs).
+    ///
+    /// The entries found are looked up once and never forgotten, the layers must not change
+    /// while the composite is in use.
+    pub async fn new(layers: Vec<Arc<BoxedLayer>>) -> io::Result<Self> {
+        if layers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a composite layer needs at least one layer",
+            ));
+        }
+        let ctx = Request::default();
+        let mut roots = Vec::new();
+        let mut opaque = false;
+        for (index, layer) in layers.iter().enumerate() {
+            let root = layer.root_inode();
+            roots.push((index, root));
+            if layer.is_opaque(ctx, root).await? {
+                opaque = true;
+                break;
+            }
+        }
+        let mut composite = CompositeLayer {
+            nodes: vec![Node {
+                layer: 0,
+                inode: layers[0].root_inode(),
+                kind: FileType::Directory,
+                opaque,
+                parent: 0,
+                children: BTreeMap::new(),
+            }],
+            layers,
+        };
+        let mut pending = vec![(0, roots)];
+        while let Some((index, dirs)) = pending.pop() {
+            for (name, candidate) in composite.merge_dir(ctx, &dirs).await? {
+                let child = composite.nodes.len();
+                let kind = candidate.entry.attr.kind;
+                composite.nodes.push(Node {
+                    layer: candidate.layer,
+                    inode: candidate.entry.attr.ino,
+                    kind,
+                    opaque: kind == FileType::Directory && candidate.closed,
+                    parent: index,
+                    children: BTreeMap::new(),
+                });
+                composite.nodes[index].children.insert(name, child);
+                if kind == FileType::Directory && !candidate.whiteout {
+                    pending.push((child, candidate.dirs));
+                }
+            }
+        }
+        debug!(
+            "composite layer: merged {} layers into {} entries",
+            composite.layers.len(),
+            composite.nodes.len()
+        );
+        Ok(composite)
+    }
+
+    // Merge the entries of the directories `dirs`, the top-most first.
+    async fn merge_dir(
+        &self,
+        ctx: Request,
+        dirs: &[(usize, Inode)],
+    ) -> Result<BTreeMap<OsString, Candidate>> {
+        let mut merged: BTreeMap<OsString, Candidate> = BTreeMap::new();
+        for &(index, dir) in dirs {
+            let layer = &self.layers[index];
+            for name in read_names(layer.as_ref(), ctx, dir).await? {
+                let candidate = merged.get(&name);
+                if candidate.is_some_and(|c| c.closed) {
+                    continue;
+                }
+                let entry = layer.lookup(ctx, dir, &name).await?;
+                let is_dir = entry.attr.kind == FileType::Directory;
+                match merged.get_mut(&name) {
+                    None => {
+                        let whiteout = !is_dir && layer.is_whiteout(ctx, entry.attr.ino).await?;
+                        let opaque = is_dir && layer.is_opaque(ctx, entry.attr.ino).await?;
+                        merged.insert(
+                            name,
+                            Candidate {
+                                layer: index,
+                                dirs: if is_dir {
+                                    vec![(index, entry.attr.ino)]
+                                } else {
+                                    Vec::new()
+                                },
+                                closed: !is_dir || opaque,
+                                entry,
+                                whiteout,
+                            },
+                        );
+                    }
+                    // A directory below a directory is merged with it.
+                    Some(candidate) if is_dir => {
+                        candidate.dirs.push((index, entry.attr.ino));
+                        candidate.closed = layer.is_opaque(ctx, entry.attr.ino).await?;
+                    }
+                    // Anything else ends the merge, and hides the layers below.
+                    Some(candidate) => {
+                        layer.forget(ctx, entry.attr.ino, 1).await;
+                        candidate.closed = true;
+                    }
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    fn node(&self, inode: Inode) -> io::Result<&Node> {
+        inode
+            .checked_sub(ROOT_INODE)
+            .and_then(|i| self.nodes.get(i as usize))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn inode_of(index: usize) -> Inode {
+        index as Inode + ROOT_INODE
+    }
+
+    fn real(&self, inode: Inode) -> io::Result<(&BoxedLayer, Inode)> {
+        let node = self.node(inode)?;
+        Ok((self.layers[node.layer].as_ref(), node.inode))
+    }
+
+    async fn attr(&self, ctx: Request, inode: Inode) -> Result<FileAttr> {
+        let (layer, real) = self.real(inode)?;
+        let mut attr = layer.getattr(ctx, real, None, 0).await?.attr;
+        attr.ino = inode;
+        Ok(attr)
+    }
+
+    pub(crate) fn is_opaque_dir(&self, inode: Inode) -> io::Result<bool> {
+        let node = self.node(inode)?;
+        if node.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(node.opaque)
+    }
+
+    pub(crate) async fn getattr_stat(
+        &self,
+        inode: Inode,
+        handle: Option<u64>,
+        mapping: bool,
+    ) -> io::Result<(Stat64, Duration)> {
+        let (layer, real) = self.real(inode)?;
+        let (mut st, ttl) = layer.getattr_with_mapping(real, handle, mapping).await?;
+        st.st_ino = inode;
+        Ok((st, ttl))
+    }
+}
+
+// Names in directory `dir` of `layer`, without "." and "..".
+async fn read_names(layer: &BoxedLayer, ctx: Request, dir: Inode) -> Result<Vec<OsString>> {
+    let handle = match layer.opendir(ctx, dir, libc::O_RDONLY as u32).await {
+        Ok(handle) => handle,
+        Err(e) if io::Error::from(e).raw_os_error() == Some(libc::ENOSYS) => ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        },
+        Err(e) => return Err(e),
+    };
+    let entries: Vec<_> = layer
+        .readdir(ctx, dir, handle.fh, 0)
+        .await?
+        .entries
+        .collect()
+        .await;
+    if handle.fh > 0 {
+        layer.releasedir(ctx, dir, handle.fh, handle.flags).await?;
+    }
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.name;
+        if name != "." && name != ".." {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+fn erofs() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+impl Filesystem for CompositeLayer {
+    /// initialize filesystem. The layers were set up when merged.
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    /// clean up filesystem.
+    async fn destroy(&self, _req: Request) {}
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let index = *dir
+            .children
+            .get(name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: self.attr(req, Self::inode_of(index)).await?,
+            generation: 0,
+        })
+    }
+
+    /// get file attributes.
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.attr(req, inode).await?,
+        })
+    }
+
+    /// set file attributes, never allowed on a lower layer.
+    async fn setattr(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: Option<u64>,
+        _set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        Err(erofs().into())
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        let (layer, real) = self.real(inode)?;
+        layer.readlink(req, real).await
+    }
+
+    /// open a file in the layer holding it.
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0 {
+            return Err(erofs().into());
+        }
+        let (layer, real) = self.real(inode)?;
+        layer.open(req, real, flags).await
+    }
+
+    /// read data.
+    async fn read(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let (layer, real) = self.real(inode)?;
+        layer.read(req, real, fh, offset, size).await
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        let (layer, real) = self.real(inode)?;
+        layer.release(req, real, fh, flags, lock_owner, flush).await
+    }
+
+    /// flush method.
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        let (layer, real) = self.real(inode)?;
+        layer.flush(req, real, fh, lock_owner).await
+    }
+
+    /// get filesystem statistics, those of the top-most layer.
+    async fn statfs(&self, req: Request, _inode: Inode) -> Result<ReplyStatFs> {
+        self.layers[0].statfs(req, self.nodes[0].inode).await
+    }
+
+    /// get an extended attribute.
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let (layer, real) = self.real(inode)?;
+        layer.getxattr(req, real, name, size).await
+    }
+
+    /// list extended attribute names.
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        let (layer, real) = self.real(inode)?;
+        layer.listxattr(req, real, size).await
+    }
+
+    /// open a directory. Entries come from the merged tree, no handle is needed.
+    async fn opendir(&self, _req: Request, inode: Inode, _flags: u32) -> Result<ReplyOpen> {
+        if self.node(inode)?.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        Ok(ReplyOpen {
+            fh: 0,
+            flags: 0,
+            backing_id: None,
+        })
+    }
+
+    /// read directory.
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let mut entries = vec![
+            (parent, FileType::Directory, ".".into()),
+            (Self::inode_of(dir.parent), FileType::Directory, "..".into()),
+        ];
+        for (name, &index) in &dir.children {
+            entries.push((Self::inode_of(index), self.nodes[index].kind, name.clone()));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name,
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, _req: Request, _inode: Inode, _fh: u64, _flags: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// check file access permissions. Writes are refused, the rest is up to the layer.
+    async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
+        if mask as i32 & libc::W_OK != 0 {
+            return Err(erofs().into());
+        }
+        let (layer, real) = self.real(inode)?;
+        layer.access(req, real, mask).await
+    }
+
+    /// test for a POSIX file lock. Locks are not supported on a lower layer.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// acquire, modify or release a POSIX file lock. Locks are not supported on a lower layer.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        _req: Request,
+        _inode: Inode,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _type: u32,
+        _pid: u32,
+        _block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
+    /// read directory entries, but with their attribute, like [`readdir`][Filesystem::readdir]
+    /// + [`lookup`][Filesystem::lookup] at the same time.
+    async fn readdirplus<'a>(
+        &'a self,
+        req: Request,
+        parent: Inode,
+        _fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let mut inodes = vec![
+            (parent, OsString::from(".")),
+            (Self::inode_of(dir.parent), "..".into()),
+        ];
+        for (name, &index) in &dir.children {
+            inodes.push((Self::inode_of(index), name.clone()));
+        }
+        let mut entries = Vec::new();
+        for (i, (inode, name)) in inodes.into_iter().enumerate().skip(offset as usize) {
+            let attr = self.attr(req, inode).await?;
+            entries.push(Ok(DirectoryEntryPlus {
+                inode,
+                generation: 0,
+                kind: attr.kind,
+                name,
+                offset: i as i64 + 1,
+                attr,
+                entry_ttl: TTL,
+                attr_ttl: TTL,
+            }));
+        }
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+    use crate::memfs::MemoryLayer;
+    use crate::unionfs::config::Config;
+    use crate::unionfs::layer::Layer;
+    use crate::unionfs::{BoxedLayer, OverlayFs};
+
+    async fn file(layer: &MemoryLayer, parent: Inode, name: &str, data: &[u8]) -> Inode {
+        let req = Request::default();
+        let file = layer
+            .create(req, parent, OsStr::new(name), 0o644, 0)
+            .await
+            .unwrap();
+        layer
+            .write(req, file.attr.ino, file.fh, 0, data, 0, 0)
+            .await
+            .unwrap();
+        file.attr.ino
+    }
+
+    async fn dir(layer: &MemoryLayer, parent: Inode, name: &str) -> Inode {
+        layer
+            .mkdir(Request::default(), parent, OsStr::new(name), 0o755, 0)
+            .await
+            .unwrap()
+            .attr
+            .ino
+    }
+
+    async fn read(fs: &impl Filesystem, parent: Inode, name: &str) -> Vec<u8> {
+        let req = Request::default();
+        let entry = fs.lookup(req, parent, OsStr::new(name)).await.unwrap();
+        let fh = fs
+            .open(req, entry.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let data = fs.read(req, entry.attr.ino, fh, 0, 64).await.unwrap();
+        data.data.to_vec()
+    }
+
+    fn errno<T>(res: Result<T>) -> Option<i32> {
+        io::Error::from(res.err().unwrap()).raw_os_error()
+    }
+
+    #[tokio::test]
+    async fn test_composite_layer() {
+        let req = Request::default();
+        let top = MemoryLayer::new();
+        let etc = dir(&top, 1, "etc").await;
+        file(&top, etc, "conf", b"top").await;
+        top.create_whiteout(req, etc, OsStr::new("gone"))
+            .await
+            .unwrap();
+        let opaque = dir(&top, 1, "opaque").await;
+        top.set_opaque(req, opaque).await.unwrap();
+        dir(&top, 1, "blocked").await;
+
+        let middle = MemoryLayer::new();
+        let etc = dir(&middle, 1, "etc").await;
+        file(&middle, etc, "conf", b"middle").await;
+        file(&middle, etc, "gone", b"middle").await;
+        file(&middle, etc, "kept", b"middle").await;
+        file(&middle, 1, "blocked", b"middle").await;
+        file(&middle, 1, "replaced", b"middle").await;
+
+        let bottom = MemoryLayer::new();
+        let opaque = dir(&bottom, 1, "opaque").await;
+        file(&bottom, opaque, "hidden", b"bottom").await;
+        // A file in the middle layer ends the merge of the directories around it.
+        let blocked = dir(&bottom, 1, "blocked").await;
+        file(&bottom, blocked, "hidden", b"bottom").await;
+        dir(&bottom, 1, "replaced").await;
+        file(&bottom, 1, "only", b"bottom").await;
+
+        let layers: Vec<Arc<BoxedLayer>> = vec![Arc::new(top), Arc::new(middle), Arc::new(bottom)];
+        let fs = CompositeLayer::new(layers).await.unwrap();
+
+        let etc = fs.lookup(req, 1, OsStr::new("etc")).await.unwrap().attr.ino;
+        assert_eq!(read(&fs, etc, "conf").await, b"top");
+        assert_eq!(read(&fs, etc, "kept").await, b"middle");
+        assert_eq!(read(&fs, 1, "only").await, b"bottom");
+        let replaced = fs.lookup(req, 1, OsStr::new("replaced")).await.unwrap();
+        assert_eq!(replaced.attr.kind, FileType::RegularFile);
+
+        // The whiteout is kept to mask the layers below the composite.
+        let gone = fs.lookup(req, etc, OsStr::new("gone")).await.unwrap();
+        assert!(fs.is_whiteout(req, gone.attr.ino).await.unwrap());
+        let opaque = fs.lookup(req, 1, OsStr::new("opaque")).await.unwrap();
+        assert!(fs.is_opaque(req, opaque.attr.ino).await.unwrap());
+        assert_eq!(
+            errno(fs.lookup(req, opaque.attr.ino, OsStr::new("hidden")).await),
+            Some(libc::ENOENT)
+        );
+        let blocked = fs.lookup(req, 1, OsStr::new("blocked")).await.unwrap();
+        assert!(fs.is_opaque(req, blocked.attr.ino).await.unwrap());
+        assert_eq!(
+            errno(fs.lookup(req, blocked.attr.ino, OsStr::new("hidden")).await),
+            Some(libc::ENOENT)
+        );
+        assert!(!fs.is_opaque(req, etc).await.unwrap());
+
+        let names: Vec<_> = fs
+            .readdir(req, etc, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|entry| entry.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names, [".", "..", "conf", "gone", "kept"]);
+        assert_eq!(
+            errno(fs.open(req, replaced.attr.ino, libc::O_RDWR as u32).await),
+            Some(libc::EROFS)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_composite_lower_layer() {
+        let req = Request::default();
+        let top = MemoryLayer::new();
+        let dir_top = dir(&top, 1, "dir").await;
+        top.create_whiteout(req, dir_top, OsStr::new("base"))
+            .await
+            .unwrap();
+        file(&top, dir_top, "new", b"top").await;
+        let base = MemoryLayer::new();
+        let dir_base = dir(&base, 1, "dir").await;
+        file(&base, dir_base, "base", b"base").await;
+        file(&base, dir_base, "old", b"base").await;
+
+        let composite: Vec<Arc<BoxedLayer>> = vec![Arc::new(top)];
+        let lowers: Vec<Arc<BoxedLayer>> = vec![
+            Arc::new(CompositeLayer::new(composite).await.unwrap()),
+            Arc::new(base),
+        ];
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let upper = Arc::new(MemoryLayer::new());
+        let fs = OverlayFs::new(Some(upper), lowers, config, 1).unwrap();
+        fs.init(req).await.unwrap();
+
+        let dir = fs.lookup(req, 1, OsStr::new("dir")).await.unwrap().attr.ino;
+        assert_eq!(
+            errno(fs.lookup(req, dir, OsStr::new("base")).await),
+            Some(libc::ENOENT)
+        );
+        fs.lookup(req, dir, OsStr::new("new")).await.unwrap();
+        fs.lookup(req, dir, OsStr::new("old")).await.unwrap();
+    }
+}
@@ -0,0 +1,1278 @@
+//! Layer wrapper keeping file contents and names encrypted at rest.
+//!
+//! An [`EncryptedLayer`] sits between the [`OverlayFs`](super::OverlayFs) and a writable layer,
+//! in the manner of fscrypt but without kernel support. Every directory and regular file of the
+//! wrapped layer gets a random nonce, stored in the [`NONCE_XATTR`] xattr the first time it is
+//! used, and its keys are derived from the master key and that nonce:
+//!
+//! - Contents are sealed with AES-256-GCM in blocks of 4 KiB, each stored with a random nonce
+//!   of its own and authenticated along with its index in the file. A rewritten block is sealed
+//!   anew under a fresh nonce, and a block changed in the wrapped layer or moved to another
+//!   place fails to read with `EIO`. Every block takes 28 more bytes in the wrapped layer, sizes
+//!   are translated back. Writes covering part of a block read and seal it again, and holes and
+//!   extensions are filled with sealed zeros.
+//! - Names are sealed with AES-256-GCM-SIV under the key of their directory and a fixed nonce,
+//!   which makes it deterministic so lookups work, and stored as base64. Entries which don't
+//!   decrypt, such as files left in plaintext, are hidden.
+//! - Symlink targets are sealed the same way with a key of the whole layer.
+//!
+//! Keys are derived with the BLAKE3 key derivation function, and cleared from memory along
+//! with the ciphers holding them when dropped.
+//!
+//! Attributes and xattrs are left as they are, as by fscrypt. The wrapped layer should be
+//! empty when first wrapped and must support user xattrs.
+//!
+//! # Threat model
+//!
+//! The layer is meant for a wrapped layer which others can read or change while it isn't
+//! mounted, such as a lost disk or untrusted storage. It keeps contents, names and symlink
+//! targets secret and detects blocks of contents and names which were changed. It doesn't
+//! protect:
+//!
+//! - attributes, xattrs, sizes and the shape of the tree, nor which names of a directory are
+//!   equal;
+//! - against rollback: an older version of a block, or of a whole file with its nonce, put back
+//!   in its place still reads, and a file cut at a block boundary reads as a shorter file;
+//! - against someone watching the wrapped layer while mounted, who learns which blocks change;
+//! - after a crash, a block torn by a partial write fails to read;
+//! - the master key while mounted, which is kept in the memory of the process;
+//! - blocks of a single file sealed more than 2^32 times, past which random 96-bit nonces
+//!   risk repeating.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::RwLock;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::generic_array::typenum::Unsigned;
+use aes_gcm::aead::rand_core::RngCore as _;
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm_siv::Aes256GcmSiv;
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use futures::{StreamExt as _, future};
+use rfuse3::raw::prelude::*;
+use rfuse3::{Inode, Result, SetAttr};
+use tokio::sync::RwLock as AsyncRwLock;
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
+
+use super::layer::Layer;
+
+/// Xattr holding the nonce the keys of a file or directory are derived from.
+pub const NONCE_XATTR: &str = "user.encrypted.nonce";
+
+const NONCE_LEN: usize = 16;
+// Encrypted names grow by the tag and base64, which leaves 175 bytes of a 255 bytes NAME_MAX.
+const NAME_MAX: usize = 255;
+const PATH_MAX: usize = 4095;
+const NAME_TAG_LEN: usize = <<Aes256GcmSiv as AeadCore>::TagSize as Unsigned>::USIZE;
+// Bytes of contents sealed together, stored after their nonce and before their tag.
+const BLOCK_SIZE: u64 = 4096;
+const BLOCK_NONCE_LEN: usize = <<Aes256Gcm as AeadCore>::NonceSize as Unsigned>::USIZE;
+const BLOCK_TAG_LEN: usize = <<Aes256Gcm as AeadCore>::TagSize as Unsigned>::USIZE;
+const BLOCK_OVERHEAD: u64 = (BLOCK_NONCE_LEN + BLOCK_TAG_LEN) as u64;
+const SEALED_BLOCK_SIZE: u64 = BLOCK_SIZE + BLOCK_OVERHEAD;
+// Size of the zeros sealed at once when filling a hole, a multiple of BLOCK_SIZE.
+const FILL_CHUNK: usize = 128 * 1024;
+// Locks serializing writes with reads, inodes share them modulo their count.
+const LOCK_STRIPES: usize = 64;
+
+const CONTENTS_CONTEXT: &str = "libfuse-fs EncryptedLayer 2 file contents key";
+const NAME_CONTEXT: &str = "libfuse-fs EncryptedLayer 2 name key";
+const LINK_NONCE: [u8; NONCE_LEN] = [0; NONCE_LEN];
+const LINK_CONTEXT: &str = "libfuse-fs EncryptedLayer 2 symlink key";
+
+type Nonce = [u8; NONCE_LEN];
+type Key = [u8; 32];
+
+fn derive(context: &str, master: &Key, nonce: &Nonce) -> Zeroizing<Key> {
+    let mut material = Zeroizing::new([0u8; 32 + NONCE_LEN]);
+    material[..32].copy_from_slice(master);
+    material[32..].copy_from_slice(nonce);
+    Zeroizing::new(blake3::derive_key(context, &*material))
+}
+
+fn random_nonce() -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Size in the wrapped layer of a file holding `size` bytes.
+pub(crate) fn sealed_size(size: u64) -> u64 {
+    size + size.div_ceil(BLOCK_SIZE) * BLOCK_OVERHEAD
+}
+
+/// Size of the contents held by a file of `sealed` bytes in the wrapped layer.
+pub(crate) fn plain_size(sealed: u64) -> u64 {
+    sealed.saturating_sub(sealed.div_ceil(SEALED_BLOCK_SIZE) * BLOCK_OVERHEAD)
+}
+
+// Attributes of the wrapped layer with the size of the contents.
+fn plain_attr(mut attr: FileAttr) -> FileAttr {
+    if attr.kind == FileType::RegularFile {
+        attr.size = plain_size(attr.size);
+    }
+    attr
+}
+
+// Deterministic encryption of names: AES-GCM-SIV with a fixed nonce seals equal names alike
+// and only reveals that they are equal.
+struct NameCipher {
+    cipher: Aes256GcmSiv,
+}
+
+impl NameCipher {
+    fn new(master: &Key, nonce: &Nonce, context: &str) -> Self {
+        let key = derive(context, master, nonce);
+        NameCipher {
+            cipher: Aes256GcmSiv::new((&*key).into()),
+        }
+    }
+
+    fn seal(&self, plain: &[u8]) -> OsString {
+        let mut sealed = vec![0u8; NAME_TAG_LEN];
+        sealed.extend_from_slice(plain);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&Default::default(), b"", &mut sealed[NAME_TAG_LEN..])
+            .expect("names are far below the AES-GCM-SIV limit");
+        sealed[..NAME_TAG_LEN].copy_from_slice(&tag);
+        OsString::from(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    fn open(&self, sealed: &OsStr) -> Option<OsString> {
+        let sealed = URL_SAFE_NO_PAD.decode(sealed.as_bytes()).ok()?;
+        if sealed.len() < NAME_TAG_LEN {
+            return None;
+        }
+        let (tag, data) = sealed.split_at(NAME_TAG_LEN);
+        let mut plain = data.to_vec();
+        self.cipher
+            .decrypt_in_place_detached(&Default::default(), b"", &mut plain, tag.into())
+            .ok()?;
+        Some(OsString::from_vec(plain))
+    }
+}
+
+// Encryption of contents: every block is sealed with AES-GCM under a random nonce stored in
+// front of it, and authenticated along with the index of the block.
+struct ContentCipher {
+    cipher: Aes256Gcm,
+}
+
+impl ContentCipher {
+    fn new(master: &Key, nonce: &Nonce) -> Self {
+        let key = derive(CONTENTS_CONTEXT, master, nonce);
+        ContentCipher {
+            cipher: Aes256Gcm::new((&*key).into()),
+        }
+    }
+
+    // Append `plain`, at most a block, sealed as the block `index` to `sealed`.
+    fn seal(&self, index: u64, plain: &[u8], sealed: &mut Vec<u8>) {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        sealed.extend_from_slice(&nonce);
+        let start = sealed.len();
+        sealed.extend_from_slice(plain);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &index.to_le_bytes(), &mut sealed[start..])
+            .expect("blocks are far below the AES-GCM limit");
+        sealed.extend_from_slice(&tag);
+    }
+
+    // Append the contents of the block `index` to `plain`, false if it was tampered with.
+    fn open(&self, index: u64, sealed: &[u8], plain: &mut Vec<u8>) -> bool {
+        if sealed.len() <= BLOCK_OVERHEAD as usize {
+            return false;
+        }
+        let (nonce, rest) = sealed.split_at(BLOCK_NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - BLOCK_TAG_LEN);
+        let start = plain.len();
+        plain.extend_from_slice(ciphertext);
+        let opened = self.cipher.decrypt_in_place_detached(
+            nonce.into(),
+            &index.to_le_bytes(),
+            &mut plain[start..],
+            tag.into(),
+        );
+        if opened.is_err() {
+            plain.truncate(start);
+            return false;
+        }
+        true
+    }
+}
+
+// Longest plaintext whose sealed form fits in `len` bytes.
+fn max_plain_len(len: usize) -> usize {
+    (len * 3 / 4).saturating_sub(NAME_TAG_LEN)
+}
+
+fn is_dot(name: &OsStr) -> bool {
+    name == "." || name == ".."
+}
+
+fn eio() -> rfuse3::Errno {
+    io::Error::from_raw_os_error(libc::EIO).into()
+}
+
+/// Writable layer encrypting the contents and names of another, see the
+/// [module docs](self).
+pub struct EncryptedLayer<L> {
+    inner: L,
+    master: Zeroizing<Key>,
+    // Nonces already read, by inode of the wrapped layer.
+    nonces: RwLock<HashMap<Inode, Nonce>>,
+    // Shared by reads and held exclusively by writes, so no read sees a block half rewritten.
+    blocks: Vec<AsyncRwLock<()>>,
+}
+
+impl<L: Layer> EncryptedLayer<L> {
+    /// Wrap `inner`, encrypting with keys derived from `master`.
+    pub fn new(inner: L, master: [u8; 32]) -> Self {
+        EncryptedLayer {
+            inner,
+            master: Zeroizing::new(master),
+            nonces: RwLock::new(HashMap::new()),
+            blocks: (0..LOCK_STRIPES).map(|_| AsyncRwLock::new(())).collect(),
+        }
+    }
+
+    /// The wrapped layer, holding the ciphertext.
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Unwrap the layer, leaving its content encrypted.
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+
+    // Nonce of `inode`, given one on first use if it is a directory or an empty file.
+    async fn nonce(&self, req: Request, inode: Inode) -> Result<Nonce> {
+        if let Some(nonce) = self.nonces.read().unwrap().get(&inode) {
+            return Ok(*nonce);
+        }
+        let nonce = match self.read_nonce(req, inode).await? {
+            Some(nonce) => nonce,
+            None => self.assign_nonce(req, inode).await?,
+        };
+        self.nonces.write().unwrap().insert(inode, nonce);
+        Ok(nonce)
+    }
+
+    async fn read_nonce(&self, req: Request, inode: Inode) -> Result<Option<Nonce>> {
+        let name = OsStr::new(NONCE_XATTR);
+        match self
+            .inner
+            .getxattr(req, inode, name, NONCE_LEN as u32)
+            .await
+        {
+            Ok(ReplyXAttr::Data(value)) => match Nonce::try_from(value.as_ref()) {
+                Ok(nonce) => Ok(Some(nonce)),
+                Err(_) => {
+                    warn!("encrypted layer: inode {inode} has a malformed nonce");
+                    Err(eio())
+                }
+            },
+            Ok(ReplyXAttr::Size(_)) => Err(eio()),
+            Err(e) if io::Error::from(e).raw_os_error() == Some(libc::ENODATA) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn assign_nonce(&self, req: Request, inode: Inode) -> Result<Nonce> {
+        let attr = self.inner.getattr(req, inode, None, 0).await?.attr;
+        let empty_file = attr.kind == FileType::RegularFile && attr.size == 0;
+        if attr.kind != FileType::Directory && !empty_file {
+            // Can't tell ciphertext from the plaintext of a file written around the layer.
+            warn!("encrypted layer: inode {inode} has content but no nonce");
+            return Err(eio());
+        }
+        let nonce = random_nonce();
+        let name = OsStr::new(NONCE_XATTR);
+        let flags = libc::XATTR_CREATE as u32;
+        match self
+            .inner
+            .setxattr(req, inode, name, &nonce, flags, 0)
+            .await
+        {
+            Ok(()) => Ok(nonce),
+            // Another request got there first.
+            Err(e) if io::Error::from(e).raw_os_error() == Some(libc::EEXIST) => {
+                self.read_nonce(req, inode).await?.ok_or_else(eio)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn names(&self, req: Request, dir: Inode) -> Result<NameCipher> {
+        let nonce = self.nonce(req, dir).await?;
+        Ok(NameCipher::new(&self.master, &nonce, NAME_CONTEXT))
+    }
+
+    fn links(&self) -> NameCipher {
+        NameCipher::new(&self.master, &LINK_NONCE, LINK_CONTEXT)
+    }
+
+    async fn contents(&self, req: Request, inode: Inode) -> Result<ContentCipher> {
+        let nonce = self.nonce(req, inode).await?;
+        Ok(ContentCipher::new(&self.master, &nonce))
+    }
+
+    fn lock(&self, inode: Inode) -> &AsyncRwLock<()> {
+        &self.blocks[inode as usize % LOCK_STRIPES]
+    }
+
+    /// Name under which `name` of directory `dir` is stored in the wrapped layer.
+    pub(crate) async fn seal_name(
+        &self,
+        req: Request,
+        dir: Inode,
+        name: &OsStr,
+    ) -> Result<OsString> {
+        if is_dot(name) {
+            return Ok(name.to_owned());
+        }
+        if name.len() > max_plain_len(NAME_MAX) {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG).into());
+        }
+        Ok(self.names(req, dir).await?.seal(name.as_bytes()))
+    }
+
+    /// Target under which the symlink target `link` is stored in the wrapped layer.
+    pub(crate) fn seal_link(&self, link: &OsStr) -> Result<OsString> {
+        if link.len() > max_plain_len(PATH_MAX) {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG).into());
+        }
+        Ok(self.links().seal(link.as_bytes()))
+    }
+
+    // Size of the contents of `inode`.
+    async fn size(&self, req: Request, inode: Inode, fh: Option<u64>) -> Result<u64> {
+        let attr = self.inner.getattr(req, inode, fh, 0).await?.attr;
+        Ok(plain_size(attr.size))
+    }
+
+    // Contents of `count` blocks from the block `first`, shorter at the end of the file.
+    async fn read_blocks(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        cipher: &ContentCipher,
+        first: u64,
+        count: u64,
+    ) -> Result<Vec<u8>> {
+        let size = u32::try_from(count * SEALED_BLOCK_SIZE)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let sealed = self
+            .inner
+            .read(req, inode, fh, first * SEALED_BLOCK_SIZE, size)
+            .await?
+            .data;
+        let mut plain = Vec::with_capacity(sealed.len());
+        for (index, block) in (first..).zip(sealed.chunks(SEALED_BLOCK_SIZE as usize)) {
+            if !cipher.open(index, block, &mut plain) {
+                warn!("encrypted layer: block {index} of inode {inode} fails authentication");
+                return Err(eio());
+            }
+        }
+        Ok(plain)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn write_all(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        mut offset: u64,
+        mut data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<()> {
+        while !data.is_empty() {
+            let written = self
+                .inner
+                .write(req, inode, fh, offset, data, write_flags, flags)
+                .await?
+                .written as usize;
+            if written == 0 {
+                return Err(eio());
+            }
+            offset += written as u64;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+
+    // Write `data` at `offset` of contents of `size` bytes, `offset` being at most `size`.
+    // Blocks written in part are read and sealed again with the rest of their contents.
+    #[allow(clippy::too_many_arguments)]
+    async fn write_at(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        cipher: &ContentCipher,
+        size: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<()> {
+        let first = offset / BLOCK_SIZE;
+        let end = offset + data.len() as u64;
+        let mut plain = Vec::with_capacity(data.len() + 2 * BLOCK_SIZE as usize);
+        if offset > first * BLOCK_SIZE {
+            let head = self.read_blocks(req, inode, fh, cipher, first, 1).await?;
+            let len = (offset - first * BLOCK_SIZE) as usize;
+            plain.extend_from_slice(head.get(..len).ok_or_else(eio)?);
+        }
+        plain.extend_from_slice(data);
+        if !end.is_multiple_of(BLOCK_SIZE) && end < size {
+            let last = end / BLOCK_SIZE;
+            let tail = self.read_blocks(req, inode, fh, cipher, last, 1).await?;
+            let len = (end - last * BLOCK_SIZE) as usize;
+            plain.extend_from_slice(tail.get(len..).ok_or_else(eio)?);
+        }
+        let mut sealed = Vec::with_capacity(sealed_size(plain.len() as u64) as usize);
+        for (index, block) in (first..).zip(plain.chunks(BLOCK_SIZE as usize)) {
+            cipher.seal(index, block, &mut sealed);
+        }
+        let offset = first * SEALED_BLOCK_SIZE;
+        self.write_all(req, inode, fh, offset, &sealed, write_flags, flags)
+            .await
+    }
+
+    // Change contents of `size` bytes to `new_size` bytes, sealing the zeros a file grows by or
+    // the block it is cut in. The wrapped file is left to truncate when it shrinks.
+    async fn resize(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        cipher: &ContentCipher,
+        mut size: u64,
+        new_size: u64,
+    ) -> Result<()> {
+        if new_size < size && !new_size.is_multiple_of(BLOCK_SIZE) {
+            let last = new_size / BLOCK_SIZE;
+            let mut plain = self.read_blocks(req, inode, fh, cipher, last, 1).await?;
+            plain.truncate((new_size - last * BLOCK_SIZE) as usize);
+            let mut sealed = Vec::with_capacity(SEALED_BLOCK_SIZE as usize);
+            cipher.seal(last, &plain, &mut sealed);
+            let offset = last * SEALED_BLOCK_SIZE;
+            return self.write_all(req, inode, fh, offset, &sealed, 0, 0).await;
+        }
+        let zeros = vec![0u8; FILL_CHUNK];
+        while size < new_size {
+            let len = (new_size - size).min(FILL_CHUNK as u64);
+            let zeros = &zeros[..len as usize];
+            self.write_at(req, inode, fh, cipher, size, size, zeros, 0, 0)
+                .await?;
+            size += len;
+        }
+        Ok(())
+    }
+
+    // Run `resize` with a handle of its own when the caller has none.
+    async fn resize_unopened(
+        &self,
+        req: Request,
+        inode: Inode,
+        cipher: &ContentCipher,
+        size: u64,
+        new_size: u64,
+    ) -> Result<()> {
+        let opened = self.inner.open(req, inode, libc::O_RDWR as u32).await?;
+        let res = self
+            .resize(req, inode, opened.fh, cipher, size, new_size)
+            .await;
+        let released = self
+            .inner
+            .release(req, inode, opened.fh, opened.flags, 0, true)
+            .await;
+        res.and(released)
+    }
+}
+
+/// Flags to open a file of the wrapped layer with. The kernel picks the offset of appends, a
+/// file opened with `O_APPEND` would write them at its own end, out of the block layout.
+pub(crate) fn inner_open_flags(flags: u32) -> u32 {
+    flags & !(libc::O_APPEND as u32)
+}
+
+impl<L: Layer> Filesystem for EncryptedLayer<L> {
+    /// initialize filesystem.
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        self.inner.init(req).await
+    }
+
+    /// clean up filesystem.
+    async fn destroy(&self, req: Request) {
+        self.inner.destroy(req).await
+    }
+
+    /// look up a directory entry by name and get its attributes.
+    async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        let name = self.seal_name(req, parent, name).await?;
+        let mut entry = self.inner.lookup(req, parent, &name).await?;
+        entry.attr = plain_attr(entry.attr);
+        Ok(entry)
+    }
+
+    /// forget an inode.
+    async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
+        self.nonces.write().unwrap().remove(&inode);
+        self.inner.forget(req, inode, nlookup).await
+    }
+
+    /// forget more than one inode.
+    async fn batch_forget(&self, req: Request, inodes: &[(Inode, u64)]) {
+        {
+            let mut nonces = self.nonces.write().unwrap();
+            for (inode, _) in inodes {
+                nonces.remove(inode);
+            }
+        }
+        self.inner.batch_forget(req, inodes).await
+    }
+
+    /// get file attributes, with the size of the contents.
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        let mut reply = self.inner.getattr(req, inode, fh, flags).await?;
+        reply.attr = plain_attr(reply.attr);
+        Ok(reply)
+    }
+
+    /// get extended file attributes, with the size of the contents.
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        let mut reply = self.inner.statx(req, inode, fh, flags, mask).await?;
+        reply.attr = plain_attr(reply.attr);
+        Ok(reply)
+    }
+
+    /// set file attributes. A file grown by a truncate gets sealed zeros, one cut within a
+    /// block gets that block sealed again.
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        mut set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        let mut reply = match set_attr.size {
+            None => self.inner.setattr(req, inode, fh, set_attr).await?,
+            Some(new_size) => {
+                let cipher = self.contents(req, inode).await?;
+                let _blocks = self.lock(inode).write().await;
+                let size = self.size(req, inode, fh).await?;
+                match fh {
+                    Some(fh) => self.resize(req, inode, fh, &cipher, size, new_size).await?,
+                    None => {
+                        self.resize_unopened(req, inode, &cipher, size, new_size)
+                            .await?
+                    }
+                }
+                set_attr.size = (new_size <= size).then(|| sealed_size(new_size));
+                self.inner.setattr(req, inode, fh, set_attr).await?
+            }
+        };
+        reply.attr = plain_attr(reply.attr);
+        Ok(reply)
+    }
+
+    /// read symbolic link.
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        let sealed = self.inner.readlink(req, inode).await?.data;
+        let link = self
+            .links()
+            .open(OsStr::from_bytes(&sealed))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EIO))?;
+        Ok(ReplyData {
+            data: Bytes::from(link.into_vec()),
+        })
+    }
+
+    /// create a symbolic link.
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let link = self.seal_link(link)?;
+        let name = self.seal_name(req, parent, name).await?;
+        self.inner.symlink(req, parent, &name, &link).await
+    }
+
+    /// create file node.
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        let name = self.seal_name(req, parent, name).await?;
+        self.inner.mknod(req, parent, &name, mode, rdev).await
+    }
+
+    /// create a directory.
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let name = self.seal_name(req, parent, name).await?;
+        self.inner.mkdir(req, parent, &name, mode, umask).await
+    }
+
+    /// remove a file.
+    async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let name = self.seal_name(req, parent, name).await?;
+        self.inner.unlink(req, parent, &name).await
+    }
+
+    /// remove a directory.
+    async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let name = self.seal_name(req, parent, name).await?;
+        self.inner.rmdir(req, parent, &name).await
+    }
+
+    /// rename a file or directory. The name is encrypted again with the key of the new parent.
+    async fn rename(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        let name = self.seal_name(req, parent, name).await?;
+        let new_name = self.seal_name(req, new_parent, new_name).await?;
+        self.inner
+            .rename(req, parent, &name, new_parent, &new_name)
+            .await
+    }
+
+    /// rename a file or directory with flags.
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let name = self.seal_name(req, parent, name).await?;
+        let new_name = self.seal_name(req, new_parent, new_name).await?;
+        self.inner
+            .rename2(req, parent, &name, new_parent, &new_name, flags)
+            .await
+    }
+
+    /// create a hard link. The contents key goes with the inode.
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let new_name = self.seal_name(req, new_parent, new_name).await?;
+        let mut entry = self.inner.link(req, inode, new_parent, &new_name).await?;
+        entry.attr = plain_attr(entry.attr);
+        Ok(entry)
+    }
+
+    /// open a file. The kernel never gets the backing file, it holds ciphertext.
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        let mut opened = self.inner.open(req, inode, inner_open_flags(flags)).await?;
+        opened.backing_id = None;
+        Ok(opened)
+    }
+
+    /// create and open a file.
+    async fn create(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let name = self.seal_name(req, parent, name).await?;
+        let mut created = self
+            .inner
+            .create(req, parent, &name, mode, inner_open_flags(flags))
+            .await?;
+        created.backing_id = None;
+        Ok(created)
+    }
+
+    /// read data, checking and decrypting the blocks it is in.
+    async fn read(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        if size == 0 {
+            return Ok(ReplyData { data: Bytes::new() });
+        }
+        let cipher = self.contents(req, inode).await?;
+        let first = offset / BLOCK_SIZE;
+        let count = (offset + size as u64 - 1) / BLOCK_SIZE - first + 1;
+        let plain = {
+            let _blocks = self.lock(inode).read().await;
+            self.read_blocks(req, inode, fh, &cipher, first, count)
+                .await?
+        };
+        let start = ((offset - first * BLOCK_SIZE) as usize).min(plain.len());
+        let end = (start + size as usize).min(plain.len());
+        Ok(ReplyData {
+            data: Bytes::from(plain).slice(start..end),
+        })
+    }
+
+    /// encrypt and write data, after filling the hole a write past the end leaves.
+    async fn write(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<ReplyWrite> {
+        let cipher = self.contents(req, inode).await?;
+        let _blocks = self.lock(inode).write().await;
+        let size = self.size(req, inode, Some(fh)).await?;
+        self.resize(req, inode, fh, &cipher, size, size.max(offset))
+            .await?;
+        let size = size.max(offset);
+        self.write_at(
+            req,
+            inode,
+            fh,
+            &cipher,
+            size,
+            offset,
+            data,
+            write_flags,
+            flags,
+        )
+        .await?;
+        Ok(ReplyWrite {
+            written: data.len() as u32,
+        })
+    }
+
+    /// get filesystem statistics, with the name length left after encryption.
+    async fn statfs(&self, req: Request, inode: Inode) -> Result<ReplyStatFs> {
+        let mut statfs = self.inner.statfs(req, inode).await?;
+        statfs.namelen = max_plain_len(statfs.namelen as usize) as u32;
+        Ok(statfs)
+    }
+
+    /// release an open file.
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        self.inner
+            .release(req, inode, fh, inner_open_flags(flags), lock_owner, flush)
+            .await
+    }
+
+    /// synchronize file contents.
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.inner.fsync(req, inode, fh, datasync).await
+    }
+
+    /// set an extended attribute. The nonce can't be changed.
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        if name == NONCE_XATTR {
+            return Err(io::Error::from_raw_os_error(libc::EPERM).into());
+        }
+        self.inner
+            .setxattr(req, inode, name, value, flags, position)
+            .await
+    }
+
+    /// get an extended attribute. The nonce is hidden.
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        if name == NONCE_XATTR {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA).into());
+        }
+        self.inner.getxattr(req, inode, name, size).await
+    }
+
+    /// list extended attribute names, without the nonce.
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        let len = match self.inner.listxattr(req, inode, 0).await? {
+            ReplyXAttr::Size(len) => len,
+            ReplyXAttr::Data(names) => names.len() as u32,
+        };
+        let names = match self.inner.listxattr(req, inode, len).await? {
+            ReplyXAttr::Data(names) => names,
+            ReplyXAttr::Size(_) => Bytes::new(),
+        };
+        let names: Vec<u8> = names
+            .split_inclusive(|&b| b == 0)
+            .filter(|name| name.strip_suffix(b"\0").unwrap_or(*name) != NONCE_XATTR.as_bytes())
+            .flatten()
+            .copied()
+            .collect();
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(names.len() as u32));
+        }
+        if names.len() > size as usize {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE).into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(names)))
+    }
+
+    /// remove an extended attribute. The nonce can't be removed.
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        if name == NONCE_XATTR {
+            return Err(io::Error::from_raw_os_error(libc::EPERM).into());
+        }
+        self.inner.removexattr(req, inode, name).await
+    }
+
+    /// flush method.
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        self.inner.flush(req, inode, fh, lock_owner).await
+    }
+
+    /// open a directory.
+    async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        self.inner.opendir(req, inode, flags).await
+    }
+
+    /// read directory, with decrypted names.
+    async fn readdir<'a>(
+        &'a self,
+        req: Request,
+        parent: Inode,
+        fh: u64,
+        offset: i64,
+    ) -> Result<
+        ReplyDirectory<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
+        >,
+    > {
+        let names = self.names(req, parent).await?;
+        let entries = self.inner.readdir(req, parent, fh, offset).await?.entries;
+        Ok(ReplyDirectory {
+            entries: entries.filter_map(move |entry| {
+                future::ready(match entry {
+                    Ok(mut entry) if !is_dot(&entry.name) => {
+                        open_entry(&names, parent, &entry.name).map(|name| {
+                            entry.name = name;
+                            Ok(entry)
+                        })
+                    }
+                    entry => Some(entry),
+                })
+            }),
+        })
+    }
+
+    /// release an open directory.
+    async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        self.inner.releasedir(req, inode, fh, flags).await
+    }
+
+    /// synchronize directory contents.
+    async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.inner.fsyncdir(req, inode, fh, datasync).await
+    }
+
+    /// test for a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        self.inner
+            .getlk(req, inode, fh, lock_owner, start, end, r#type, pid)
+            .await
+    }
+
+    /// acquire, modify or release a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        self.inner
+            .setlk(req, inode, fh, lock_owner, start, end, r#type, pid, block)
+            .await
+    }
+
+    /// check file access permissions.
+    async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
+        self.inner.access(req, inode, mask).await
+    }
+
+    /// allocate space for an open file. Space past the end is filled with sealed zeros,
+    /// punching holes or zeroing ranges would leave zeros which fail authentication.
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        match mode as i32 {
+            libc::FALLOC_FL_KEEP_SIZE => {
+                let start = offset / BLOCK_SIZE * SEALED_BLOCK_SIZE;
+                let length = sealed_size(offset + length) - start;
+                self.inner
+                    .fallocate(req, inode, fh, start, length, mode)
+                    .await
+            }
+            0 => {
+                let cipher = self.contents(req, inode).await?;
+                let _blocks = self.lock(inode).write().await;
+                let size = self.size(req, inode, Some(fh)).await?;
+                let new_size = size.max(offset + length);
+                self.resize(req, inode, fh, &cipher, size, new_size).await
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP).into()),
+        }
+    }
+
+    /// read directory entries with their attributes, with decrypted names.
+    async fn readdirplus<'a>(
+        &'a self,
+        req: Request,
+        parent: Inode,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<
+        ReplyDirectoryPlus<
+            impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
+        >,
+    > {
+        let names = self.names(req, parent).await?;
+        let entries = self
+            .inner
+            .readdirplus(req, parent, fh, offset, lock_owner)
+            .await?
+            .entries;
+        Ok(ReplyDirectoryPlus {
+            entries: entries.filter_map(move |entry| {
+                future::ready(match entry {
+                    Ok(mut entry) if !is_dot(&entry.name) => {
+                        open_entry(&names, parent, &entry.name).map(|name| {
+                            entry.name = name;
+                            entry.attr = plain_attr(entry.attr);
+                            Ok(entry)
+                        })
+                    }
+                    entry => Some(entry),
+                })
+            }),
+        })
+    }
+
+    /// find next data or hole after the specified offset. Holes are filled, the contents are
+    /// data up to their end.
+    async fn lseek(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        let whence = whence as i32;
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            return self
+                .inner
+                .lseek(req, inode, fh, offset, whence as u32)
+                .await;
+        }
+        let size = self.size(req, inode, Some(fh)).await?;
+        if offset >= size {
+            return Err(io::Error::from_raw_os_error(libc::ENXIO).into());
+        }
+        let offset = if whence == libc::SEEK_DATA {
+            offset
+        } else {
+            size
+        };
+        Ok(ReplyLSeek { offset })
+    }
+}
+
+// Decrypted name of a directory entry, None to hide an entry which isn't ours.
+fn open_entry(names: &NameCipher, parent: Inode, sealed: &OsStr) -> Option<OsString> {
+    let name = names.open(sealed);
+    if name.is_none() {
+        debug!("encrypted layer: hiding entry {sealed:?} of {parent}, it doesn't decrypt");
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryLayer;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn errno<T>(res: Result<T>) -> Option<i32> {
+        io::Error::from(res.err().unwrap()).raw_os_error()
+    }
+
+    async fn names(fs: &impl Filesystem, dir: Inode) -> Vec<OsString> {
+        fs.readdir(Request::default(), dir, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|entry| entry.unwrap().name)
+            .filter(|name| future::ready(!is_dot(name)))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_layer_round_trip() {
+        let req = Request::default();
+        let fs = EncryptedLayer::new(MemoryLayer::new(), KEY);
+        let dir = fs
+            .mkdir(req, 1, OsStr::new("secret"), 0o755, 0)
+            .await
+            .unwrap();
+        let file = fs
+            .create(req, dir.attr.ino, OsStr::new("plans.txt"), 0o644, 0)
+            .await
+            .unwrap();
+        let ino = file.attr.ino;
+        fs.write(req, ino, file.fh, 0, b"attack at dawn", 0, 0)
+            .await
+            .unwrap();
+        // A write past the end leaves a hole which must read back as zeros.
+        fs.write(req, ino, file.fh, 20, b"!", 0, 0).await.unwrap();
+        fs.symlink(req, 1, OsStr::new("link"), OsStr::new("secret/plans.txt"))
+            .await
+            .unwrap();
+
+        let data = fs.read(req, ino, file.fh, 0, 64).await.unwrap().data;
+        assert_eq!(&data[..], b"attack at dawn\0\0\0\0\0\0!");
+        let entry = fs.lookup(req, dir.attr.ino, OsStr::new("plans.txt")).await;
+        assert_eq!(entry.unwrap().attr.ino, ino);
+        assert_eq!(names(&fs, dir.attr.ino).await, ["plans.txt"]);
+        let link = fs.lookup(req, 1, OsStr::new("link")).await.unwrap();
+        let target = fs.readlink(req, link.attr.ino).await.unwrap().data;
+        assert_eq!(&target[..], b"secret/plans.txt");
+
+        // Nothing readable is left in the wrapped layer.
+        let inner = fs.inner();
+        let stored = inner.read(req, ino, file.fh, 0, 64).await.unwrap().data;
+        assert_eq!(stored.len() as u64, sealed_size(21));
+        assert!(!stored.windows(6).any(|w| w == b"attack"));
+        assert_eq!(fs.getattr(req, ino, None, 0).await.unwrap().attr.size, 21);
+        let stored_names = names(inner, dir.attr.ino).await;
+        assert_eq!(stored_names.len(), 1);
+        assert_ne!(stored_names[0], "plans.txt");
+        let stored_target = inner.readlink(req, link.attr.ino).await.unwrap().data;
+        assert!(!stored_target.windows(6).any(|w| w == b"secret"));
+
+        // The same tree under another key shows nothing.
+        let fs = EncryptedLayer::new(fs.into_inner(), [8; 32]);
+        assert!(names(&fs, 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_layer_truncate_and_names() {
+        let req = Request::default();
+        let fs = EncryptedLayer::new(MemoryLayer::new(), KEY);
+        let file = fs
+            .create(req, 1, OsStr::new("grown"), 0o644, 0)
+            .await
+            .unwrap();
+        fs.write(req, file.attr.ino, file.fh, 0, b"abc", 0, 0)
+            .await
+            .unwrap();
+        let set_attr = SetAttr {
+            size: Some(6),
+            ..Default::default()
+        };
+        let attr = fs
+            .setattr(req, file.attr.ino, None, set_attr)
+            .await
+            .unwrap();
+        assert_eq!(attr.attr.size, 6);
+        let data = fs
+            .read(req, file.attr.ino, file.fh, 0, 64)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(&data[..], b"abc\0\0\0");
+
+        // Names are sealed per directory, the same name differs between two of them.
+        let a = fs.mkdir(req, 1, OsStr::new("a"), 0o755, 0).await.unwrap();
+        let b = fs.mkdir(req, 1, OsStr::new("b"), 0o755, 0).await.unwrap();
+        fs.mknod(req, a.attr.ino, OsStr::new("x"), libc::S_IFREG | 0o644, 0)
+            .await
+            .unwrap();
+        fs.mknod(req, b.attr.ino, OsStr::new("x"), libc::S_IFREG | 0o644, 0)
+            .await
+            .unwrap();
+        assert_ne!(
+            names(fs.inner(), a.attr.ino).await,
+            names(fs.inner(), b.attr.ino).await
+        );
+        fs.rename(req, a.attr.ino, OsStr::new("x"), 1, OsStr::new("moved"))
+            .await
+            .unwrap();
+        fs.lookup(req, 1, OsStr::new("moved")).await.unwrap();
+
+        let long = OsString::from("n".repeat(max_plain_len(NAME_MAX) + 1));
+        assert_eq!(
+            errno(fs.mkdir(req, 1, &long, 0o755, 0).await),
+            Some(libc::ENAMETOOLONG)
+        );
+        assert_eq!(
+            errno(fs.getxattr(req, 1, OsStr::new(NONCE_XATTR), 0).await),
+            Some(libc::ENODATA)
+        );
+        let listed = match fs.listxattr(req, 1, 1024).await.unwrap() {
+            ReplyXAttr::Data(names) => names,
+            ReplyXAttr::Size(_) => unreachable!(),
+        };
+        assert!(listed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_layer_blocks() {
+        let req = Request::default();
+        let fs = EncryptedLayer::new(MemoryLayer::new(), KEY);
+        let file = fs
+            .create(req, 1, OsStr::new("blocks"), 0o644, 0)
+            .await
+            .unwrap();
+        let (ino, fh) = (file.attr.ino, file.fh);
+        let mut model: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        fs.write(req, ino, fh, 0, &model, 0, 0).await.unwrap();
+        // Writes and truncates within blocks keep the rest of them.
+        fs.write(req, ino, fh, 4090, b"straddling", 0, 0)
+            .await
+            .unwrap();
+        model[4090..4100].copy_from_slice(b"straddling");
+        let set_attr = SetAttr {
+            size: Some(5000),
+            ..Default::default()
+        };
+        let attr = fs.setattr(req, ino, Some(fh), set_attr).await.unwrap();
+        model.truncate(5000);
+        assert_eq!(attr.attr.size, 5000);
+        let data = fs.read(req, ino, fh, 0, 16384).await.unwrap().data;
+        assert_eq!(&data[..], &model[..]);
+        let data = fs.read(req, ino, fh, 4000, 200).await.unwrap().data;
+        assert_eq!(&data[..], &model[4000..4200]);
+        let stored = fs.inner().getattr(req, ino, None, 0).await.unwrap().attr;
+        assert_eq!(stored.size, sealed_size(5000));
+
+        // Writing the same bytes again seals them under another nonce.
+        let before = fs.inner().read(req, ino, fh, 0, 64).await.unwrap().data;
+        fs.write(req, ino, fh, 0, &model[..10], 0, 0).await.unwrap();
+        let after = fs.inner().read(req, ino, fh, 0, 64).await.unwrap().data;
+        assert_ne!(before[BLOCK_NONCE_LEN..], after[BLOCK_NONCE_LEN..]);
+
+        // A block changed or moved in the wrapped layer fails, the others still read.
+        let mut tampered = after.to_vec();
+        tampered[BLOCK_NONCE_LEN] ^= 1;
+        fs.inner()
+            .write(req, ino, fh, 0, &tampered, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(errno(fs.read(req, ino, fh, 0, 16).await), Some(libc::EIO));
+        let data = fs.read(req, ino, fh, BLOCK_SIZE, 16).await.unwrap().data;
+        assert_eq!(&data[..], &model[4096..4112]);
+        let second = fs
+            .inner()
+            .read(req, ino, fh, SEALED_BLOCK_SIZE, SEALED_BLOCK_SIZE as u32)
+            .await
+            .unwrap()
+            .data;
+        fs.inner()
+            .write(req, ino, fh, 0, &second, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(errno(fs.read(req, ino, fh, 0, 16).await), Some(libc::EIO));
+    }
+}
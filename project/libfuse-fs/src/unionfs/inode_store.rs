@@ -2,6 +2,7 @@
 // 2024 From [fuse_backend_rs](https://github.com/cloud-hypervisor/fuse-backend-rs)
 // SPDX-License-Identifier: Apache-2.0
 
+use std::ffi::{OsStr, OsString};
 use std::io::{Error, Result};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::{collections::HashMap, sync::Arc};
@@ -11,7 +12,6 @@ use crate::passthrough::VFS_MAX_INO;
 use super::{Inode, OverlayInode};
 
 use futures::future::join_all;
-use radix_trie::Trie;
 use tracing::{error, trace};
 
 pub struct InodeStore {
@@ -20,7 +20,9 @@ pub struct InodeStore {
     // Deleted inodes which were unlinked but have non zero lookup count.
     deleted: HashMap<Inode, Arc<OverlayInode>>,
     // Path to inode mapping, used to reserve inode number for same path.
-    path_mapping: Trie<String, Inode>,
+    // Only exact-path lookups are needed, so a flat map is used instead of a trie:
+    // it has no per-component recursion and stays cheap for paths beyond PATH_MAX.
+    path_mapping: HashMap<OsString, Inode>,
     next_inode: u64,
     inode_limit: u64,
     // FUSE inode to nlink mapping
@@ -32,7 +34,7 @@ impl InodeStore {
         Self {
             inodes: HashMap::new(),
             deleted: HashMap::new(),
-            path_mapping: Trie::new(),
+            path_mapping: HashMap::new(),
             next_inode: 1,
             inode_limit: VFS_MAX_INO,
             nlinks: HashMap::new(),
@@ -59,7 +61,7 @@ impl InodeStore {
         )))
     }
 
-    pub(crate) fn alloc_inode(&mut self, path: &str) -> Result<Inode> {
+    pub(crate) fn alloc_inode(&mut self, path: &OsStr) -> Result<Inode> {
         match self.path_mapping.get(path) {
             // If the path is already in the mapping, return the reserved inode number.
             Some(v) => Ok(*v),
@@ -90,7 +92,7 @@ impl InodeStore {
     pub(crate) async fn remove_inode(
         &mut self,
         inode: Inode,
-        path_removed: Option<String>,
+        path_removed: Option<OsString>,
     ) -> Option<Arc<OverlayInode>> {
         let old_nlink = self.nlinks.get(&inode)?.fetch_sub(1, Ordering::Relaxed);
 
@@ -188,25 +190,25 @@ mod test {
     async fn test_alloc_existing_path() {
         let mut store = InodeStore::new();
         let mut node_a = OverlayInode::new();
-        node_a.path = tokio::sync::RwLock::new("/a".to_string());
+        node_a.path = tokio::sync::RwLock::new("/a".into());
         store.insert_inode(1, Arc::new(node_a)).await;
         let mut node_b = OverlayInode::new();
-        node_b.path = tokio::sync::RwLock::new("/b".to_string());
+        node_b.path = tokio::sync::RwLock::new("/b".into());
         store.insert_inode(2, Arc::new(node_b)).await;
         let mut node_c = OverlayInode::new();
-        node_c.path = tokio::sync::RwLock::new("/c".to_string());
+        node_c.path = tokio::sync::RwLock::new("/c".into());
         store.insert_inode(VFS_MAX_INO - 1, Arc::new(node_c)).await;
 
-        let inode = store.alloc_inode("/a").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/a")).unwrap();
         assert_eq!(inode, 1);
 
-        let inode = store.alloc_inode("/b").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/b")).unwrap();
         assert_eq!(inode, 2);
 
-        let inode = store.alloc_inode("/c").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/c")).unwrap();
         assert_eq!(inode, VFS_MAX_INO - 1);
 
-        let inode = store.alloc_inode("/notexist").unwrap();
+        let inode = store.alloc_inode(OsStr::new("/notexist")).unwrap();
         assert_eq!(inode, 3);
     }
 }
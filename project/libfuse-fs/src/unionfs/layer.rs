@@ -8,7 +8,14 @@ use std::io::Error;
 use std::time::Duration;
 
 use crate::context::OperationContext;
+use crate::erofs::ErofsLayer;
+use crate::memfs::MemoryLayer;
 use crate::passthrough::PassthroughFs;
+use crate::squashfs::SquashfsLayer;
+use crate::tarfs::TarLayer;
+use crate::unionfs::audit::{AuditLayer, AuditOp};
+use crate::unionfs::composite::CompositeLayer;
+use crate::unionfs::encrypted::{EncryptedLayer, inner_open_flags, plain_size};
 pub const OPAQUE_XATTR_LEN: u32 = 16;
 pub const OPAQUE_XATTR: &str = "user.fuseoverlayfs.opaque";
 pub const UNPRIVILEGED_OPAQUE_XATTR: &str = "user.overlay.opaque";
@@ -314,6 +321,293 @@ impl Layer for PassthroughFs {
         PassthroughFs::do_getattr_inner(self, inode, handle, mapping).await
     }
 }
+#[async_trait]
+impl Layer for MemoryLayer {
+    fn root_inode(&self) -> Inode {
+        1
+    }
+
+    async fn create_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        MemoryLayer::do_create_helper(
+            self,
+            ctx.req,
+            parent,
+            name,
+            mode,
+            flags,
+            ctx.uid.unwrap_or(ctx.req.uid),
+            ctx.gid.unwrap_or(ctx.req.gid),
+        )
+        .await
+    }
+
+    async fn mkdir_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        MemoryLayer::do_mkdir_helper(
+            self,
+            ctx.req,
+            parent,
+            name,
+            mode,
+            umask,
+            ctx.uid.unwrap_or(ctx.req.uid),
+            ctx.gid.unwrap_or(ctx.req.gid),
+        )
+        .await
+    }
+
+    async fn symlink_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        MemoryLayer::do_symlink_helper(
+            self,
+            ctx.req,
+            parent,
+            name,
+            link,
+            ctx.uid.unwrap_or(ctx.req.uid),
+            ctx.gid.unwrap_or(ctx.req.gid),
+        )
+        .await
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        _handle: Option<u64>,
+        _mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.getattr_stat(inode)
+    }
+}
+#[async_trait]
+impl Layer for CompositeLayer {
+    fn root_inode(&self) -> Inode {
+        1
+    }
+
+    /// Answered from the merged tree, a directory is also opaque when a layer of the
+    /// composite ends its merge.
+    async fn is_opaque(&self, _ctx: Request, inode: Inode) -> Result<bool> {
+        Ok(self.is_opaque_dir(inode)?)
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        handle: Option<u64>,
+        mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.getattr_stat(inode, handle, mapping).await
+    }
+}
+#[async_trait]
+impl<L: Layer> Layer for AuditLayer<L> {
+    fn root_inode(&self) -> Inode {
+        self.inner().root_inode()
+    }
+
+    async fn create_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let res = self
+            .inner()
+            .create_with_context(ctx, parent, name, mode, flags)
+            .await;
+        let op = AuditOp::Create {
+            parent,
+            name: name.to_string_lossy().into_owned(),
+            mode,
+        };
+        self.record(ctx.req, op, res)
+    }
+
+    async fn mkdir_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let res = self
+            .inner()
+            .mkdir_with_context(ctx, parent, name, mode, umask)
+            .await;
+        let op = AuditOp::Mkdir {
+            parent,
+            name: name.to_string_lossy().into_owned(),
+            mode,
+        };
+        self.record(ctx.req, op, res)
+    }
+
+    async fn symlink_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let res = self
+            .inner()
+            .symlink_with_context(ctx, parent, name, link)
+            .await;
+        let op = AuditOp::Symlink {
+            parent,
+            name: name.to_string_lossy().into_owned(),
+            target: link.to_string_lossy().into_owned(),
+        };
+        self.record(ctx.req, op, res)
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        handle: Option<u64>,
+        mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.inner()
+            .getattr_with_mapping(inode, handle, mapping)
+            .await
+    }
+}
+#[async_trait]
+impl<L: Layer> Layer for EncryptedLayer<L> {
+    fn root_inode(&self) -> Inode {
+        self.inner().root_inode()
+    }
+
+    async fn create_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let name = self.seal_name(ctx.req, parent, name).await?;
+        let mut created = self
+            .inner()
+            .create_with_context(ctx, parent, &name, mode, inner_open_flags(flags))
+            .await?;
+        // The backing file holds ciphertext, the kernel must not read it.
+        created.backing_id = None;
+        Ok(created)
+    }
+
+    async fn mkdir_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let name = self.seal_name(ctx.req, parent, name).await?;
+        self.inner()
+            .mkdir_with_context(ctx, parent, &name, mode, umask)
+            .await
+    }
+
+    async fn symlink_with_context(
+        &self,
+        ctx: OperationContext,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        let link = self.seal_link(link)?;
+        let name = self.seal_name(ctx.req, parent, name).await?;
+        self.inner()
+            .symlink_with_context(ctx, parent, &name, &link)
+            .await
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        handle: Option<u64>,
+        mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        let (mut st, ttl) = self
+            .inner()
+            .getattr_with_mapping(inode, handle, mapping)
+            .await?;
+        if st.st_mode & libc::S_IFMT == libc::S_IFREG {
+            st.st_size = plain_size(st.st_size as u64) as _;
+        }
+        Ok((st, ttl))
+    }
+}
+#[async_trait]
+impl Layer for SquashfsLayer {
+    fn root_inode(&self) -> Inode {
+        1
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        _handle: Option<u64>,
+        _mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.getattr_stat(inode)
+    }
+}
+#[async_trait]
+impl Layer for ErofsLayer {
+    fn root_inode(&self) -> Inode {
+        1
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        _handle: Option<u64>,
+        _mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.getattr_stat(inode)
+    }
+}
+#[async_trait]
+impl Layer for TarLayer {
+    fn root_inode(&self) -> Inode {
+        1
+    }
+
+    async fn getattr_with_mapping(
+        &self,
+        inode: Inode,
+        _handle: Option<u64>,
+        _mapping: bool,
+    ) -> std::io::Result<(Stat64, Duration)> {
+        self.getattr_stat(inode)
+    }
+}
+
 pub(crate) fn is_dir(st: &FileAttr) -> bool {
     st.kind.const_into_mode_t() & libc::S_IFMT == libc::S_IFDIR
 }
@@ -356,7 +650,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
@@ -388,7 +683,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
@@ -433,7 +729,8 @@ mod test {
         let fs = unwrap_or_skip_eperm!(
             new_passthroughfs_layer(PassthroughArgs {
                 root_dir: rootdir,
-                mapping: None::<&str>
+                mapping: None::<&str>,
+                io_engine: Default::default(),
             })
             .await,
             "init passthrough layer"
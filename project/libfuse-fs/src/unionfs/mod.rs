@@ -4,7 +4,10 @@
 
 #![allow(missing_docs)]
 mod async_io;
+pub mod audit;
+pub mod composite;
 pub mod config;
+pub mod encrypted;
 mod inode_store;
 pub mod layer;
 mod utils;
@@ -15,6 +18,7 @@ use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::future::Future;
 use std::io::{Error, Result};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
 use config::Config;
@@ -30,7 +34,7 @@ use tracing::info;
 use tracing::trace;
 
 use rfuse3::{Errno, FileType, MountOptions, mode_from_kind_and_perm};
-const SLASH_ASCII: char = '/';
+const SLASH_ASCII: u8 = b'/';
 use futures::future::join_all;
 use futures::stream::iter;
 
@@ -67,14 +71,14 @@ pub(crate) struct RealInode {
 // #[derive(Default)]
 pub(crate) struct OverlayInode {
     // Inode hash table, map from 'name' to 'OverlayInode'.
-    pub childrens: Mutex<HashMap<String, Arc<OverlayInode>>>,
+    pub childrens: Mutex<HashMap<OsString, Arc<OverlayInode>>>,
     pub parent: Mutex<Weak<OverlayInode>>,
     // Backend inodes from all layers.
     pub real_inodes: Mutex<Vec<Arc<RealInode>>>,
     // Inode number.
     pub inode: u64,
-    pub path: RwLock<String>,
-    pub name: RwLock<String>,
+    pub path: RwLock<OsString>,
+    pub name: RwLock<OsString>,
     pub lookups: AtomicU64,
     // Node is whiteout-ed.
     pub whiteout: AtomicBool,
@@ -171,10 +175,9 @@ impl RealInode {
             Ok(v1) => Ok(Some(v1)),
             Err(e) => match e.raw_os_error() {
                 Some(raw_error) => {
-                    if raw_error == libc::ENOENT
-                        || raw_error == libc::ENAMETOOLONG
-                        || raw_error == libc::ESTALE
-                    {
+                    // ENAMETOOLONG is a real error here: stat works on an inode, not on a name,
+                    // so it must not be mistaken for a missing entry.
+                    if raw_error == libc::ENOENT || raw_error == libc::ESTALE {
                         return Ok(None);
                     }
                     Err(e)
@@ -188,12 +191,11 @@ impl RealInode {
     async fn lookup_child_ignore_enoent(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
     ) -> Result<Option<ReplyEntry>> {
-        let cname = OsStr::new(name);
         // Real inode must have a layer.
         let layer = self.layer.as_ref();
-        match layer.lookup(ctx, self.inode, cname).await {
+        match layer.lookup(ctx, self.inode, name).await {
             Ok(v) => {
                 // Negative entry also indicates missing entry.
                 if v.attr.ino == 0 {
@@ -216,7 +218,7 @@ impl RealInode {
 
     // Find child inode in same layer under this directory(Self).
     // Return None if not found.
-    async fn lookup_child(&self, ctx: Request, name: &str) -> Result<Option<RealInode>> {
+    async fn lookup_child(&self, ctx: Request, name: &OsStr) -> Result<Option<RealInode>> {
         if self.whiteout {
             return Ok(None);
         }
@@ -250,7 +252,7 @@ impl RealInode {
     }
 
     // Read directory entries from specific RealInode, error out if it's not directory.
-    async fn readdir(&self, ctx: Request) -> Result<HashMap<String, RealInode>> {
+    async fn readdir(&self, ctx: Request) -> Result<HashMap<OsString, RealInode>> {
         // Deleted inode should not be read.
         if self.whiteout {
             return Err(Error::from_raw_os_error(libc::ENOENT));
@@ -282,7 +284,11 @@ impl RealInode {
                     Some(raw_error) => {
                         if raw_error == libc::ENOSYS {
                             // We can still call readdir with inode if opendir is not supported in this layer.
-                            ReplyOpen { fh: 0, flags: 0 }
+                            ReplyOpen {
+                                fh: 0,
+                                flags: 0,
+                                backing_id: None,
+                            }
                         } else {
                             return Err(e.into());
                         }
@@ -309,7 +315,7 @@ impl RealInode {
         let a_map = child_names.entries.map(|entery| async {
             match entery {
                 Ok(dire) => {
-                    let dname = dire.name.into_string().unwrap();
+                    let dname = dire.name;
                     if dname == "." || dname == ".." {
                         // Skip . and .. entries.
                         return Ok(());
@@ -333,17 +339,12 @@ impl RealInode {
         Ok(re)
     }
 
-    async fn create_whiteout(&self, ctx: Request, name: &str) -> Result<RealInode> {
+    async fn create_whiteout(&self, ctx: Request, name: &OsStr) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
 
-        // from &str to &OsStr
-        let name_osstr = OsStr::new(name);
-        let entry = self
-            .layer
-            .create_whiteout(ctx, self.inode, name_osstr)
-            .await?;
+        let entry = self.layer.create_whiteout(ctx, self.inode, name).await?;
 
         // Wrap whiteout to RealInode.
         Ok(RealInode {
@@ -359,16 +360,12 @@ impl RealInode {
         })
     }
 
-    async fn mkdir(&self, ctx: Request, name: &str, mode: u32, umask: u32) -> Result<RealInode> {
+    async fn mkdir(&self, ctx: Request, name: &OsStr, mode: u32, umask: u32) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
 
-        let name_osstr = OsStr::new(name);
-        let entry = self
-            .layer
-            .mkdir(ctx, self.inode, name_osstr, mode, umask)
-            .await?;
+        let entry = self.layer.mkdir(ctx, self.inode, name, mode, umask).await?;
 
         // update node's first_layer
         Ok(RealInode {
@@ -387,14 +384,13 @@ impl RealInode {
     async fn create(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         flags: u32,
     ) -> Result<(RealInode, Option<u64>)> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let create_rep = self
             .layer
             .create(ctx, self.inode, name, mode, flags)
@@ -419,7 +415,7 @@ impl RealInode {
     async fn mknod(
         &self,
         ctx: Request,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         rdev: u32,
         _umask: u32,
@@ -427,7 +423,6 @@ impl RealInode {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let rep = self.layer.mknod(ctx, self.inode, name, mode, rdev).await?;
         Ok(RealInode {
             layer: self.layer.clone(),
@@ -442,11 +437,10 @@ impl RealInode {
         })
     }
 
-    async fn link(&self, ctx: Request, ino: u64, name: &str) -> Result<RealInode> {
+    async fn link(&self, ctx: Request, ino: u64, name: &OsStr) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let name = OsStr::new(name);
         let entry = self.layer.link(ctx, ino, self.inode, name).await?;
 
         let opaque = if utils::is_dir(&entry.attr.kind) {
@@ -468,12 +462,15 @@ impl RealInode {
     }
 
     // Create a symlink in self directory.
-    async fn symlink(&self, ctx: Request, link_name: &str, filename: &str) -> Result<RealInode> {
+    async fn symlink(
+        &self,
+        ctx: Request,
+        link_name: &OsStr,
+        filename: &OsStr,
+    ) -> Result<RealInode> {
         if !self.in_upper_layer {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
-        let link_name = OsStr::new(link_name);
-        let filename = OsStr::new(filename);
         let entry = self
             .layer
             .symlink(ctx, self.inode, filename, link_name)
@@ -511,8 +508,8 @@ impl OverlayInode {
             parent: Mutex::new(Weak::new()),
             real_inodes: Mutex::new(vec![]),
             inode: 0,
-            path: RwLock::new(String::new()),
-            name: RwLock::new(String::new()),
+            path: RwLock::new(OsString::new()),
+            name: RwLock::new(OsString::new()),
             lookups: AtomicU64::new(0),
             whiteout: AtomicBool::new(false),
             loaded: AtomicBool::new(false),
@@ -521,15 +518,15 @@ impl OverlayInode {
     // Allocate new OverlayInode based on one RealInode,
     // inode number is always 0 since only OverlayFs has global unique inode allocator.
     pub async fn new_from_real_inode(
-        name: &str,
+        name: &OsStr,
         ino: u64,
-        path: String,
+        path: OsString,
         real_inode: RealInode,
     ) -> Self {
         let mut new = OverlayInode::new();
         new.inode = ino;
         new.path = path.into();
-        new.name = name.to_string().into();
+        new.name = name.to_os_string().into();
         new.whiteout.store(real_inode.whiteout, Ordering::Relaxed);
         new.lookups = AtomicU64::new(1);
         new.real_inodes = Mutex::new(vec![real_inode.into()]);
@@ -537,9 +534,9 @@ impl OverlayInode {
     }
 
     pub async fn new_from_real_inodes(
-        name: &str,
+        name: &OsStr,
         ino: u64,
-        path: String,
+        path: OsString,
         real_inodes: Vec<RealInode>,
     ) -> Result<Self> {
         if real_inodes.is_empty() {
@@ -655,7 +652,7 @@ impl OverlayInode {
             return Err(Error::from_raw_os_error(libc::ENOTDIR));
         }
 
-        let mut all_layer_inodes: HashMap<String, Vec<RealInode>> = HashMap::new();
+        let mut all_layer_inodes: HashMap<OsString, Vec<RealInode>> = HashMap::new();
         // read out directories from each layer
         // Scan from upper layer to lower layer.
         for ri in self.real_inodes.lock().await.iter() {
@@ -671,13 +668,13 @@ impl OverlayInode {
             };
 
             if !utils::is_dir(&stat.attr.kind) {
-                debug!("{} is not a directory", self.path.read().await);
+                debug!("{:?} is not a directory", self.path.read().await);
                 // not directory
                 break;
             }
 
             // Read all entries from one layer.
-            let entries: HashMap<String, RealInode> = ri.readdir(ctx).await?;
+            let entries: HashMap<OsString, RealInode> = ri.readdir(ctx).await?;
 
             // Merge entries from one layer to all_layer_inodes.
             for (name, inode) in entries {
@@ -694,7 +691,7 @@ impl OverlayInode {
 
             // if opaque, stop here
             if ri.opaque {
-                debug!("directory {} is opaque", self.path.read().await);
+                debug!("directory {:?} is opaque", self.path.read().await);
                 break;
             }
         }
@@ -703,8 +700,8 @@ impl OverlayInode {
         let mut childrens = vec![];
         for (name, real_inodes) in all_layer_inodes {
             // Inode numbers are not allocated yet.
-            let path = format!("{}/{}", self.path.read().await, name);
-            let new = Self::new_from_real_inodes(name.as_str(), 0, path, real_inodes).await?;
+            let path = utils::join_path(&self.path.read().await, &name);
+            let new = Self::new_from_real_inodes(&name, 0, path, real_inodes).await?;
             childrens.push(new);
         }
 
@@ -912,16 +909,19 @@ impl OverlayInode {
         }
     }
 
-    pub async fn child(&self, name: &str) -> Option<Arc<OverlayInode>> {
+    pub async fn child(&self, name: &OsStr) -> Option<Arc<OverlayInode>> {
         self.childrens.lock().await.get(name).cloned()
     }
 
-    pub async fn remove_child(&self, name: &str) -> Option<Arc<OverlayInode>> {
+    pub async fn remove_child(&self, name: &OsStr) -> Option<Arc<OverlayInode>> {
         self.childrens.lock().await.remove(name)
     }
 
-    pub async fn insert_child(&self, name: &str, node: Arc<OverlayInode>) {
-        self.childrens.lock().await.insert(name.to_string(), node);
+    pub async fn insert_child(&self, name: &OsStr, node: Arc<OverlayInode>) {
+        self.childrens
+            .lock()
+            .await
+            .insert(name.to_os_string(), node);
     }
 
     /// Handles operations on the upper layer inode of an `OverlayInode` in a thread-safe manner.
@@ -1014,7 +1014,7 @@ impl OverlayFs {
         self.root_inodes
     }
 
-    async fn alloc_inode(&self, path: &str) -> Result<u64> {
+    async fn alloc_inode(&self, path: &OsStr) -> Result<u64> {
         self.inodes.write().await.alloc_inode(path)
     }
 
@@ -1033,8 +1033,8 @@ impl OverlayFs {
     pub async fn import(&self) -> Result<()> {
         let mut root = OverlayInode::new();
         root.inode = self.root_inode();
-        root.path = String::from("").into();
-        root.name = String::from("").into();
+        root.path = OsString::new().into();
+        root.name = OsString::new().into();
         root.lookups = AtomicU64::new(2);
         root.real_inodes = Mutex::new(vec![]);
         let ctx = Request::default();
@@ -1105,7 +1105,7 @@ impl OverlayFs {
     async fn remove_inode(
         &self,
         inode: u64,
-        path_removed: Option<String>,
+        path_removed: Option<OsString>,
     ) -> Option<Arc<OverlayInode>> {
         self.inodes
             .write()
@@ -1121,11 +1121,12 @@ impl OverlayFs {
         &self,
         ctx: Request,
         parent: Inode,
-        name: &str,
+        name: &OsStr,
     ) -> Result<Arc<OverlayInode>> {
-        if name.contains(SLASH_ASCII) {
+        if name.as_bytes().contains(&SLASH_ASCII) {
             return Err(Error::from_raw_os_error(libc::EINVAL));
         }
+        utils::check_name_len(name)?;
 
         // Parent inode is expected to be loaded before this function is called.
         // TODO: Is this correct?
@@ -1175,7 +1176,7 @@ impl OverlayFs {
             // Child is found.
             Some(v) => Ok(v),
             None => {
-                trace!("lookup_node: child {name} not found");
+                trace!("lookup_node: child {name:?} not found");
                 Err(Error::from_raw_os_error(libc::ENOENT))
             }
         }
@@ -1185,7 +1186,7 @@ impl OverlayFs {
         &self,
         ctx: Request,
         parent: u64,
-        name: &str,
+        name: &OsStr,
     ) -> Result<Option<Arc<OverlayInode>>> {
         match self.lookup_node(ctx, parent, name).await {
             Ok(n) => Ok(Some(Arc::clone(&n))),
@@ -1273,14 +1274,14 @@ impl OverlayFs {
 
         let lookups = v.lookups.load(Ordering::Relaxed);
         trace!(
-            "forget inode: {}, name {}, lookups: {}",
+            "forget inode: {}, name {:?}, lookups: {}",
             inode,
             v.name.read().await,
             lookups
         );
         if lookups == 0 {
             debug!(
-                "inode is forgotten: {}, name {}",
+                "inode is forgotten: {}, name {:?}",
                 inode,
                 v.name.read().await
             );
@@ -1294,7 +1295,7 @@ impl OverlayFs {
         }
     }
 
-    async fn do_lookup(&self, ctx: Request, parent: Inode, name: &str) -> Result<ReplyEntry> {
+    async fn do_lookup(&self, ctx: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
         let node = self.lookup_node(ctx, parent, name).await?;
         debug!("do_lookup: {name:?}, found");
 
@@ -1405,7 +1406,7 @@ impl OverlayFs {
             Some(hd) if hd.node.inode == inode => hd.clone(),
             _ => {
                 // Fallback for cases without a valid handle (e.g. no-opendir)
-                let node = self.lookup_node(ctx, inode, ".").await?;
+                let node = self.lookup_node(ctx, inode, OsStr::new(".")).await?;
                 let st = node.stat64(ctx).await?;
                 if !utils::is_dir(&st.attr.kind) {
                     return Err(Error::from_raw_os_error(libc::ENOTDIR));
@@ -1474,7 +1475,7 @@ impl OverlayFs {
                 inode: child.inode,
                 generation: 0,
                 kind: st_child.attr.kind,
-                name: name.clone().into(),
+                name: name.clone(),
                 offset: (entries.len() + 1) as i64,
                 attr: st_child.attr,
                 entry_ttl: st_child.ttl,
@@ -1499,7 +1500,7 @@ impl OverlayFs {
         &self,
         ctx: Request,
         parent_node: Arc<OverlayInode>,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         umask: u32,
     ) -> Result<()> {
@@ -1536,7 +1537,7 @@ impl OverlayFs {
         // Copy parent node up if necessary.
         let pnode = self.copy_node_up(ctx, parent_node).await?;
 
-        let path = format!("{}/{}", pnode.path.read().await, name);
+        let path = utils::join_path(&pnode.path.read().await, name);
         let path_ref = &path;
         let new_node = Arc::new(Mutex::new(None));
         pnode
@@ -1548,11 +1549,10 @@ impl OverlayFs {
                         return Err(Error::from_raw_os_error(libc::EINVAL));
                     }
                 };
-                let osstr = OsStr::new(name);
                 if delete_whiteout {
                     let _ = parent_real_inode
                         .layer
-                        .delete_whiteout(ctx, parent_real_inode.inode, osstr)
+                        .delete_whiteout(ctx, parent_real_inode.inode, name)
                         .await;
                 }
 
@@ -1585,7 +1585,7 @@ impl OverlayFs {
         &self,
         ctx: Request,
         parent_node: &Arc<OverlayInode>,
-        name: &str,
+        name: &OsStr,
         mode: u32,
         rdev: u32,
         umask: u32,
@@ -1621,11 +1621,10 @@ impl OverlayFs {
                                     return Err(Error::from_raw_os_error(libc::EINVAL));
                                 }
                             };
-                            let osstr = OsStr::new(name);
                             if n.in_upper_layer().await {
                                 let _ = parent_real_inode
                                     .layer
-                                    .delete_whiteout(ctx, parent_real_inode.inode, osstr)
+                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
                                     .await;
                             }
 
@@ -1644,7 +1643,7 @@ impl OverlayFs {
                 // Copy parent node up if necessary.
                 let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
                 let new_node = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name);
+                let path = utils::join_path(&pnode.path.read().await, name);
                 pnode
                     .handle_upper_inode_locked(
                         &mut |parent_real_inode: Option<Arc<RealInode>>| async {
@@ -1693,7 +1692,6 @@ impl OverlayFs {
         mode: u32,
         flags: u32,
     ) -> Result<Option<u64>> {
-        let name_str = name.to_str().unwrap();
         let upper = self
             .upper_layer
             .as_ref()
@@ -1708,7 +1706,7 @@ impl OverlayFs {
         let handle: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
         let real_ino: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
         let new_ovi = match self
-            .lookup_node_ignore_enoent(ctx, parent_node.inode, name_str)
+            .lookup_node_ignore_enoent(ctx, parent_node.inode, name)
             .await?
         {
             Some(n) => {
@@ -1738,7 +1736,7 @@ impl OverlayFs {
                             }
 
                             let (child_ri, hd) =
-                                parent_real_inode.create(ctx, name_str, mode, flags).await?;
+                                parent_real_inode.create(ctx, name, mode, flags).await?;
                             real_ino.lock().await.replace(child_ri.inode);
                             handle.lock().await.replace(hd.unwrap());
 
@@ -1754,7 +1752,7 @@ impl OverlayFs {
                 // Copy parent node up if necessary.
                 let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
                 let new_node = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name_str);
+                let path = utils::join_path(&pnode.path.read().await, name);
                 pnode
                     .handle_upper_inode_locked(
                         &mut |parent_real_inode: Option<Arc<RealInode>>| async {
@@ -1767,13 +1765,13 @@ impl OverlayFs {
                             };
 
                             let (child_ri, hd) =
-                                parent_real_inode.create(ctx, name_str, mode, flags).await?;
+                                parent_real_inode.create(ctx, name, mode, flags).await?;
                             real_ino.lock().await.replace(child_ri.inode);
                             handle.lock().await.replace(hd.unwrap());
                             // Allocate inode number.
                             let ino = self.alloc_inode(&path).await?;
                             let ovi = OverlayInode::new_from_real_inode(
-                                name_str,
+                                name,
                                 ino,
                                 path.clone(),
                                 child_ri,
@@ -1790,7 +1788,7 @@ impl OverlayFs {
                 let nn = new_node.lock().await.take();
                 let arc_node = Arc::new(nn.unwrap());
                 self.insert_inode(arc_node.inode, arc_node.clone()).await;
-                pnode.insert_child(name_str, arc_node.clone()).await;
+                pnode.insert_child(name, arc_node.clone()).await;
                 arc_node
             }
         };
@@ -1831,19 +1829,23 @@ impl OverlayFs {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<()> {
-        let name_str = name.to_str().unwrap();
-        let new_name_str = new_name.to_str().unwrap();
-
-        let parent_node = self.lookup_node(req, parent, "").await?;
-        let new_parent_node = self.lookup_node(req, new_parent, "").await?;
-        let src_node = self.lookup_node(req, parent, name_str).await?;
+        let parent_node = self.lookup_node(req, parent, OsStr::new("")).await?;
+        let new_parent_node = self.lookup_node(req, new_parent, OsStr::new("")).await?;
+        let src_node = self.lookup_node(req, parent, name).await?;
         let dest_node_opt = self
-            .lookup_node_ignore_enoent(req, new_parent, new_name_str)
+            .lookup_node_ignore_enoent(req, new_parent, new_name)
             .await?;
         // trace!("parent_node: {}, new_parent_node: {}, src_node: {}, dest_node_opt: {:?}", parent_node.inode, new_parent_node.inode, src_node.inode, dest_node_opt.as_ref().map(|n| n.inode));
 
+        // Lower entries are found by name and redirects aren't written, so like kernel
+        // overlayfs with redirect_dir=off, only directories that exist in the upper layer
+        // alone can move. Callers like mv copy the others instead.
+        let src_is_dir = src_node.is_dir(req).await?;
+        if src_is_dir && !src_node.upper_layer_only().await {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
         if let Some(dest_node) = &dest_node_opt {
-            let src_is_dir = src_node.is_dir(req).await?;
             let dest_is_dir = dest_node.is_dir(req).await?;
             if src_is_dir != dest_is_dir {
                 return Err(Error::from_raw_os_error(libc::EISDIR));
@@ -1863,9 +1865,12 @@ impl OverlayFs {
 
         let need_whiteout = !s_node.upper_layer_only().await;
 
-        let (p_layer, _, p_inode) = pnode.first_layer_inode().await;
-        let (new_p_layer, _, new_p_inode) = new_pnode.first_layer_inode().await;
-        assert!(Arc::ptr_eq(&p_layer, &new_p_layer));
+        let (p_layer, p_upper, p_inode) = pnode.first_layer_inode().await;
+        let (new_p_layer, new_p_upper, new_p_inode) = new_pnode.first_layer_inode().await;
+        // Both parents were copied up above, still a rename can't cross layers.
+        if !p_upper || !new_p_upper || !Arc::ptr_eq(&p_layer, &new_p_layer) {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
 
         p_layer
             .rename(req, p_inode, name, new_p_inode, new_name)
@@ -1880,14 +1885,14 @@ impl OverlayFs {
         // Update the moved source node's state.
 
         // Remove from old parent.
-        pnode.remove_child(name_str).await;
+        pnode.remove_child(name).await;
         self.remove_inode(s_node.inode, s_node.path.read().await.clone().into())
             .await;
-        let new_path = format!("{}/{}", new_pnode.path.read().await, new_name_str);
+        let new_path = utils::join_path(&new_pnode.path.read().await, new_name);
         *s_node.path.write().await = new_path;
-        *s_node.name.write().await = new_name_str.to_string();
+        *s_node.name.write().await = new_name.to_os_string();
         *s_node.parent.lock().await = Arc::downgrade(&new_pnode);
-        new_pnode.insert_child(new_name_str, s_node.clone()).await;
+        new_pnode.insert_child(new_name, s_node.clone()).await;
         self.insert_inode(s_node.inode, s_node).await;
 
         // Create whiteout at the old location if necessary.
@@ -1903,7 +1908,7 @@ impl OverlayFs {
         ctx: Request,
         src_node: &Arc<OverlayInode>,
         new_parent: &Arc<OverlayInode>,
-        name: &str,
+        name: &OsStr,
     ) -> Result<()> {
         if self.upper_layer.is_none() {
             return Err(Error::from_raw_os_error(libc::EROFS));
@@ -1945,7 +1950,7 @@ impl OverlayFs {
                     if existing_node.in_upper_layer().await {
                         let _ = parent_ri
                             .layer
-                            .delete_whiteout(ctx, parent_ri.inode, OsStr::new(name))
+                            .delete_whiteout(ctx, parent_ri.inode, name)
                             .await;
                     }
                     Ok(false)
@@ -1978,11 +1983,10 @@ impl OverlayFs {
     async fn do_symlink(
         &self,
         ctx: Request,
-        linkname: &str,
+        linkname: &OsStr,
         parent_node: &Arc<OverlayInode>,
-        name: &str,
+        name: &OsStr,
     ) -> Result<()> {
-        let name_os = OsStr::new(name);
         if self.upper_layer.is_none() {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
@@ -2018,7 +2022,7 @@ impl OverlayFs {
                             if n.in_upper_layer().await {
                                 let _ = parent_real_inode
                                     .layer
-                                    .delete_whiteout(ctx, parent_real_inode.inode, name_os)
+                                    .delete_whiteout(ctx, parent_real_inode.inode, name)
                                     .await;
                             }
 
@@ -2035,7 +2039,7 @@ impl OverlayFs {
                 // Copy parent node up if necessary.
                 let pnode = self.copy_node_up(ctx, Arc::clone(parent_node)).await?;
                 let new_node: Arc<Mutex<Option<OverlayInode>>> = Arc::new(Mutex::new(None));
-                let path = format!("{}/{}", pnode.path.read().await, name);
+                let path = utils::join_path(&pnode.path.read().await, name);
                 pnode
                     .handle_upper_inode_locked(
                         &mut |parent_real_inode: Option<Arc<RealInode>>| async {
@@ -2116,9 +2120,8 @@ impl OverlayFs {
 
         // Read the linkname from lower layer.
         let reply_data = self_layer.readlink(ctx, self_inode).await?;
-        // Convert path to &str.
-        let path = std::str::from_utf8(&reply_data.data)
-            .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+        // Link targets are raw bytes just like names.
+        let link_name = OsStr::from_bytes(&reply_data.data);
 
         let new_upper_real: Arc<Mutex<Option<RealInode>>> = Arc::new(Mutex::new(None));
         parent_node
@@ -2131,9 +2134,8 @@ impl OverlayFs {
                 if !parent_real_inode.in_upper_layer {
                     return Err(Error::from_raw_os_error(libc::EROFS));
                 }
-                let link_name = OsStr::new(path);
                 let filename = node.name.read().await;
-                let filename = OsStr::new(filename.as_str());
+                let filename = filename.as_os_str();
                 let op_ctx = crate::context::OperationContext::with_credentials(
                     ctx,
                     st.attr.uid,
@@ -2231,7 +2233,7 @@ impl OverlayFs {
                     return Err(Error::from_raw_os_error(libc::EROFS));
                 }
                 let name = node.name.read().await;
-                let name = OsStr::new(name.as_str());
+                let name = name.as_os_str();
                 let op_ctx = crate::context::OperationContext::with_credentials(
                     ctx,
                     st.attr.uid,
@@ -2428,11 +2430,11 @@ impl OverlayFs {
 
         // 2. Locate the parent Overlay Inode.
         // Find parent Overlay Inode.
-        let pnode = self.lookup_node(ctx, parent, "").await?;
+        let pnode = self.lookup_node(ctx, parent, OsStr::new("")).await?;
         if pnode.whiteout.load(Ordering::Relaxed) {
             return Err(Error::from_raw_os_error(libc::ENOENT));
         }
-        let to_name = name.to_str().unwrap();
+        let to_name = name;
 
         // 3. Locate the child Overlay Inode for the given name
         // Find the Overlay Inode for child with <name>.
@@ -2500,7 +2502,7 @@ impl OverlayFs {
         if node.in_upper_layer().await {
             pnode.handle_upper_inode_locked(&mut df).await?;
         }
-        pnode.remove_child(name.to_str().unwrap()).await;
+        pnode.remove_child(name).await;
         let path = node.path.read().await.clone();
         self.remove_inode(node.inode, Some(path)).await;
 
@@ -2520,7 +2522,7 @@ impl OverlayFs {
                         })?;
 
                         let child_ri = parent_real_inode.create_whiteout(ctx, to_name).await?; //FIXME..............
-                        let path = format!("{}/{}", pnode.path.read().await, to_name);
+                        let path = utils::join_path(&pnode.path.read().await, to_name);
                         let ino: u64 = self.alloc_inode(&path).await?;
                         let ovi = Arc::new(
                             OverlayInode::new_from_real_inode(to_name, ino, path.clone(), child_ri)
@@ -2608,12 +2610,11 @@ impl OverlayFs {
             // We only care about upper layer, ignore lower layers.
             if child.in_upper_layer().await {
                 let child_name = child.name.read().await.clone();
-                let child_name_os = OsStr::new(&child_name);
+                let cname = child_name.as_os_str();
                 if child.whiteout.load(Ordering::Relaxed) {
-                    layer.delete_whiteout(ctx, inode, child_name_os).await?
+                    layer.delete_whiteout(ctx, inode, cname).await?
                 } else {
                     let s = child.stat64(ctx).await?;
-                    let cname: &OsStr = OsStr::new(&child_name_os);
                     if utils::is_dir(&s.attr.kind) {
                         let (count, whiteouts) = child.count_entries_and_whiteout(ctx).await?;
                         if count + whiteouts > 0 {
@@ -2697,7 +2698,7 @@ impl OverlayFs {
                 == 0;
 
             // lookup node
-            let node = self.lookup_node(ctx, inode, "").await?;
+            let node = self.lookup_node(ctx, inode, OsStr::new("")).await?;
 
             // whiteout node
             if node.whiteout.load(Ordering::Relaxed) {
@@ -2792,6 +2793,7 @@ where
         let layer = new_passthroughfs_layer(PassthroughArgs {
             root_dir: lower,
             mapping: args.mapping.as_ref().map(|m| m.as_ref()),
+            io_engine: Default::default(),
         })
         .await
         .expect("Failed to create lower filesystem layer");
@@ -2802,6 +2804,7 @@ where
         new_passthroughfs_layer(PassthroughArgs {
             root_dir: args.upperdir,
             mapping: args.mapping.as_ref().map(|m| m.as_ref()),
+            io_engine: Default::default(),
         })
         .await
         .expect("Failed to create upper filesystem layer"),
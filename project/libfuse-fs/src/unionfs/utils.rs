@@ -2,7 +2,32 @@
 //  2024 From [fuse_backend_rs](https://github.com/cloud-hypervisor/fuse-backend-rs)
 // SPDX-License-Identifier: Apache-2.0
 use rfuse3::FileType;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, Result};
+
+/// Maximum length in bytes of a single path component, same as `NAME_MAX` on Linux.
+pub(super) const NAME_MAX: usize = 255;
 
 pub(super) fn is_dir(st: &FileType) -> bool {
     *st == FileType::Directory
 }
+
+/// Reject names that can never exist in any layer, so every layer reports the
+/// same error instead of each backend failing in its own way.
+pub(super) fn check_name_len(name: &OsStr) -> Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+/// Build the overlay path of `name` inside the directory at `parent`.
+///
+/// Names are raw bytes on Linux, so paths are kept as `OsString` rather than `String`.
+pub(super) fn join_path(parent: &OsStr, name: &OsStr) -> OsString {
+    let mut path = OsString::with_capacity(parent.len() + 1 + name.len());
+    path.push(parent);
+    path.push("/");
+    path.push(name);
+    path
+}
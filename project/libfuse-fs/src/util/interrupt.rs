@@ -0,0 +1,247 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Interruption of requests waiting on a layer.
+//!
+//! When the process a request is served for gets a signal, the kernel sends FUSE_INTERRUPT
+//! with the `unique` of that request. Copy-ups it triggered are cancelled through their
+//! `CopyUpTracker`. Reads register here while they wait on their layer, which may be slow
+//! when network-backed or stuck behind the lock of the handle, and give up with `EINTR` once
+//! interrupted, dropping the layer request and the locks it waited on. Lock requests waiting
+//! on a conflicting lock do the same, in the overlay and in passthrough.
+//!
+//! The interrupt can be handled before the request it targets got to register, so the last
+//! few interrupts that matched nothing are kept for requests registering late.
+//!
+//! Blocking calls, like a lock wait, run on a blocking thread through [`blocking`]. Dropping
+//! their future signals that thread so the call fails with `EINTR`.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use rfuse3::{Errno, Result};
+use tokio::sync::Notify;
+
+/// Unmatched interrupts remembered, the kernel only interrupts requests it already sent so
+/// one arriving early is at most a few requests ahead.
+const EARLY_INTERRUPTS: usize = 64;
+
+#[derive(Default)]
+struct State {
+    waiting: HashMap<u64, Arc<Notify>>,
+    early: VecDeque<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Interrupts {
+    state: Mutex<State>,
+}
+
+impl Interrupts {
+    /// Run `fut` for the request `unique`, `EINTR` if the request is interrupted first.
+    pub async fn run<T>(&self, unique: u64, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let notify = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(pos) = state.early.iter().position(|&u| u == unique) {
+                state.early.remove(pos);
+                return Err(Errno::from(libc::EINTR));
+            }
+            let notify = Arc::new(Notify::new());
+            state.waiting.insert(unique, Arc::clone(&notify));
+            notify
+        };
+        let _registered = Registered {
+            interrupts: self,
+            unique,
+        };
+        tokio::select! {
+            res = fut => res,
+            _ = notify.notified() => Err(Errno::from(libc::EINTR)),
+        }
+    }
+
+    /// Interrupt the request `unique`, false if it isn't waiting on a layer (yet).
+    pub fn interrupt(&self, unique: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(notify) = state.waiting.get(&unique) {
+            // Stores a permit if `run` didn't start waiting yet.
+            notify.notify_one();
+            return true;
+        }
+        if state.early.len() == EARLY_INTERRUPTS {
+            state.early.pop_front();
+        }
+        state.early.push_back(unique);
+        false
+    }
+}
+
+/// Signal waking a blocking thread, ignored by default so its no-op handler changes nothing
+/// for a stray one.
+const WAKE_SIGNAL: libc::c_int = libc::SIGURG;
+
+/// Pause between two wake-ups of a thread that hasn't left its call yet.
+const WAKE_RETRY: Duration = Duration::from_millis(1);
+
+#[derive(Default)]
+struct Waiting {
+    // The thread running the call, until it leaves it.
+    thread: Option<libc::pthread_t>,
+    cancelled: bool,
+}
+
+/// A blocking call the future of [`blocking`] can wake up.
+#[derive(Default)]
+struct Call {
+    waiting: Mutex<Waiting>,
+}
+
+impl Call {
+    fn run<T>(&self, mut call: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting.cancelled {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            // Safe because this only returns the id of the calling thread.
+            waiting.thread = Some(unsafe { libc::pthread_self() });
+        }
+        let res = loop {
+            match call() {
+                Err(e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        && !self.waiting.lock().unwrap().cancelled => {}
+                res => break res,
+            }
+        };
+        self.waiting.lock().unwrap().thread = None;
+        res
+    }
+
+    /// Cancel the call, true while its thread still has to be woken up.
+    fn cancel(&self) -> bool {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.cancelled = true;
+        match waiting.thread {
+            // Safe because the thread can't leave the call and go away while `waiting` is
+            // locked, and the signal has a handler.
+            Some(thread) => unsafe { libc::pthread_kill(thread, WAKE_SIGNAL) == 0 },
+            None => false,
+        }
+    }
+}
+
+struct Cancel(Arc<Call>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        if !self.0.cancel() {
+            return;
+        }
+        // The signal is lost when it lands just before the thread enters the call, repeat it
+        // until the thread is out.
+        let call = Arc::clone(&self.0);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                while call.cancel() {
+                    tokio::time::sleep(WAKE_RETRY).await;
+                }
+            });
+        }
+    }
+}
+
+extern "C" fn wake(_: libc::c_int) {}
+
+fn install_wake_handler() -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    let mut res = 0;
+    INSTALL.call_once(|| {
+        // Safe because the handler does nothing, and `sa` is fully initialized. Without
+        // `SA_RESTART` the call it interrupts fails with `EINTR`.
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = wake as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut sa.sa_mask);
+            res = libc::sigaction(WAKE_SIGNAL, &sa, std::ptr::null_mut());
+        }
+    });
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Run the blocking `call` on a blocking thread, retrying it when a signal interrupts it.
+///
+/// Dropping the future wakes the thread up, the call then fails with `EINTR` and isn't
+/// retried. A call that completed anyway keeps its effect.
+pub(crate) async fn blocking<T: Send + 'static>(
+    call: impl FnMut() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    install_wake_handler()?;
+    let waiter = Arc::new(Call::default());
+    let _cancel = Cancel(Arc::clone(&waiter));
+    tokio::task::spawn_blocking(move || waiter.run(call))
+        .await
+        .map_err(io::Error::other)?
+}
+
+struct Registered<'a> {
+    interrupts: &'a Interrupts,
+    unique: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.interrupts
+            .state
+            .lock()
+            .unwrap()
+            .waiting
+            .remove(&self.unique);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Interrupts;
+
+    #[tokio::test]
+    async fn test_interrupt_waiting_request() {
+        let interrupts = Arc::new(Interrupts::default());
+        let task = tokio::spawn({
+            let interrupts = Arc::clone(&interrupts);
+            async move {
+                interrupts
+                    .run(7, std::future::pending::<rfuse3::Result<()>>())
+                    .await
+            }
+        });
+        // Interrupting before it registers would only let it return early.
+        while !interrupts.state.lock().unwrap().waiting.contains_key(&7) {
+            tokio::task::yield_now().await;
+        }
+        assert!(interrupts.interrupt(7));
+        let err = task.await.unwrap().unwrap_err();
+        assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::EINTR));
+        assert!(interrupts.state.lock().unwrap().waiting.is_empty());
+        // Finished requests aren't interrupted anymore.
+        assert_eq!(interrupts.run(8, async { Ok(1) }).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_before_request_registers() {
+        let interrupts = Interrupts::default();
+        assert!(!interrupts.interrupt(3));
+        let err = interrupts.run(3, async { Ok(()) }).await.unwrap_err();
+        assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::EINTR));
+        assert_eq!(interrupts.run(3, async { Ok(()) }).await, Ok(()));
+    }
+}
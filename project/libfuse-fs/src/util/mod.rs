@@ -1,5 +1,6 @@
 #![allow(clippy::unnecessary_cast)]
 pub mod bind_mount;
+pub(crate) mod interrupt;
 pub mod mapping;
 pub mod open_options;
 
@@ -116,9 +117,60 @@ pub fn filetype_from_mode(st_mode: u32) -> FileType {
     error!("wrong st mode : {st_mode}");
     unreachable!();
 }
+
+/// Check the `access(2)` `mask` of a caller with `uid` against a file owned by `owner` and
+/// `group` with permission bits `mode`, like the kernel does for files without ACLs: the
+/// owner, group or other bits apply, whichever class the caller falls in first. `in_group`
+/// tells whether the caller is a member of a group.
+pub fn check_access(
+    uid: u32,
+    in_group: impl FnOnce(u32) -> bool,
+    owner: u32,
+    group: u32,
+    mode: u32,
+    is_dir: bool,
+    mask: u32,
+) -> std::io::Result<()> {
+    let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+    let granted = if uid == 0 {
+        // Root reads, writes and searches anything, but only executes what someone could.
+        if is_dir || mode & 0o111 != 0 {
+            0o7
+        } else {
+            0o6
+        }
+    } else if uid == owner {
+        mode >> 6
+    } else if in_group(group) {
+        mode >> 3
+    } else {
+        mode
+    };
+    if mask & !granted & 0o7 != 0 {
+        return Err(std::io::Error::from_raw_os_error(libc::EACCES));
+    }
+    Ok(())
+}
+
+/// Supplementary groups of the process `pid`, as listed in `/proc/<pid>/status`.
+pub fn process_groups(pid: u32) -> Vec<u32> {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) else {
+        return Vec::new();
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|g| g.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
 #[cfg(test)]
 mod tests {
-    use super::GPath;
+    use super::{GPath, check_access};
 
     #[test]
     fn test_from_string() {
@@ -126,4 +178,26 @@ mod tests {
         let gapth = GPath::from(path);
         assert_eq!(gapth.to_string(), String::from("release"))
     }
+
+    #[test]
+    fn test_check_access() {
+        let (r, w, x) = (libc::R_OK as u32, libc::W_OK as u32, libc::X_OK as u32);
+        let check = |uid, mode, is_dir, mask| {
+            // Only 2000 is in the group of the file.
+            let in_group = |g| uid == 2000 && g == 100;
+            check_access(uid, in_group, 1000, 100, mode, is_dir, mask).is_ok()
+        };
+        // The first matching class decides, even if a later one grants more.
+        assert!(!check(1000, 0o044, false, r));
+        assert!(check(1000, 0o600, false, r | w));
+        assert!(!check(2000, 0o604, false, r));
+        assert!(check(3000, 0o604, false, r));
+        assert!(!check(3000, 0o604, false, w));
+        assert!(check(0, 0o000, false, r | w));
+        assert!(!check(0, 0o600, false, x));
+        assert!(check(0, 0o010, false, x));
+        assert!(check(0, 0o000, true, x));
+        // An existence check always passes.
+        assert!(check(3000, 0o000, false, libc::F_OK as u32));
+    }
 }
@@ -359,14 +359,21 @@ pub async fn mount_and_copy_bundle<P: AsRef<Path>>(
     // mount with libfuse
     let mnt_handle = mount_fs(OverlayArgs {
         lowerdir: lower_dirs,
-        upperdir: &upper_dir,
+        upperdir: Some(&upper_dir),
         mountpoint: &merged_dir,
         privileged: true,
         mapping: None::<&str>,
         name: None::<String>,
         allow_other: false,
+        force: false,
+        layer_limit: Default::default(),
+        passthrough: false,
+        concurrency: Default::default(),
+        tuning: Default::default(),
+        sandbox: false,
     })
-    .await;
+    .await
+    .with_context(|| format!("Failed to mount overlay on {merged_dir:?}"))?;
 
     debug!("invoke libfuse_fs mount ended");
 
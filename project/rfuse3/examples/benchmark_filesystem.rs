@@ -246,7 +246,11 @@ impl Filesystem for BenchmarkFs {
         if inode != 1 {
             return Err(libc::ENOENT.into());
         }
-        Ok(ReplyOpen { fh: 1, flags: 0 })
+        Ok(ReplyOpen {
+            fh: 1,
+            flags: 0,
+            backing_id: None,
+        })
     }
 
     async fn readdir<'a>(
@@ -323,6 +327,7 @@ impl Filesystem for BenchmarkFs {
         Ok(ReplyOpen {
             fh: inode,
             flags: 0,
+            backing_id: None,
         })
     }
 
@@ -545,6 +550,7 @@ impl Filesystem for BenchmarkFs {
                 generation: 0,
                 fh: inode,
                 flags: 0,
+                backing_id: None,
             });
         }
         let mut state = self.state.write().await;
@@ -577,6 +583,7 @@ impl Filesystem for BenchmarkFs {
             generation: 0,
             fh: inode,
             flags: 0,
+            backing_id: None,
         })
     }
 
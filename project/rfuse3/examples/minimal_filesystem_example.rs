@@ -119,7 +119,11 @@ impl Filesystem for MinimalFileSystem {
         debug!("Opening directory: inode={}", inode);
 
         if inode == 1 {
-            Ok(ReplyOpen { fh: 1, flags: 0 })
+            Ok(ReplyOpen {
+                fh: 1,
+                flags: 0,
+                backing_id: None,
+            })
         } else {
             Err(libc::ENOENT.into())
         }
@@ -239,7 +243,11 @@ impl Filesystem for MinimalFileSystem {
         debug!("Opening file: inode={}", inode);
 
         if inode == 2 {
-            Ok(ReplyOpen { fh: 2, flags: 0 })
+            Ok(ReplyOpen {
+                fh: 2,
+                flags: 0,
+                backing_id: None,
+            })
         } else {
             Err(libc::ENOENT.into())
         }
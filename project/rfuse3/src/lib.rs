@@ -49,6 +49,7 @@ mod errno;
 mod helper;
 mod mount_options;
 pub mod notify;
+pub mod passthrough;
 pub mod path;
 pub mod raw;
 
@@ -49,12 +49,19 @@ pub struct MountOptions {
     pub(crate) write_back: bool,
     pub(crate) direct_io: bool,
     pub(crate) force_readdir_plus: bool,
+    pub(crate) passthrough: bool,
+    pub(crate) splice_read: bool,
+    pub(crate) flock_locks: bool,
 
     // FUSE transfer size options
     /// Maximum size of write requests. Default is 128KB.
     pub(crate) max_write: NonZeroU32,
     /// Maximum readahead size. If None, uses kernel's default.
     pub(crate) max_readahead: Option<u32>,
+    /// Background requests the kernel keeps pending. If None, follows the session workers.
+    pub(crate) max_background: Option<u16>,
+    /// Pending background requests that make the kernel throttle. If None, 3/4 of max_background.
+    pub(crate) congestion_threshold: Option<u16>,
 
     // Other FUSE mount options
     // default 40000
@@ -94,8 +101,13 @@ impl Default for MountOptions {
             write_back: false,
             direct_io: false,
             force_readdir_plus: false,
+            passthrough: false,
+            splice_read: false,
+            flock_locks: false,
             max_write: NonZeroU32::new(DEFAULT_MAX_WRITE).unwrap(),
             max_readahead: None,
+            max_background: None,
+            congestion_threshold: None,
             rootmode: None,
         }
     }
@@ -231,6 +243,48 @@ impl MountOptions {
         self
     }
 
+    /// try to set the `FUSE_PASSTHROUGH`, letting the filesystem hand opened files to the kernel
+    /// with [`BackingFiles`](crate::passthrough::BackingFiles), default is disable.
+    ///
+    /// # Notes:
+    ///
+    /// this needs Linux 6.9 or newer and `CAP_SYS_ADMIN`, and is not negotiated when
+    /// [`write_back`](Self::write_back) is enabled.
+    pub fn passthrough(&mut self, passthrough: bool) -> &mut Self {
+        self.passthrough = passthrough;
+
+        self
+    }
+
+    /// try to serve reads with `splice(2)`, moving the data of
+    /// [`Filesystem::read_fd`](crate::raw::Filesystem::read_fd) replies from the file to the
+    /// kernel without copying it through userspace, default is disable.
+    ///
+    /// # Notes:
+    ///
+    /// this is only supported on Linux, reads are copied as usual elsewhere or when splicing
+    /// fails. Only reads are spliced, the data of write requests is still copied from the
+    /// fuse device.
+    pub fn splice_read(&mut self, splice_read: bool) -> &mut Self {
+        self.splice_read = splice_read;
+
+        self
+    }
+
+    /// try to set the `FUSE_FLOCK_LOCKS`, sending `flock(2)` locks to
+    /// [`Filesystem::flock`](crate::raw::Filesystem::flock) instead of keeping them in the
+    /// kernel, default is disable.
+    ///
+    /// # Notes:
+    ///
+    /// this is supported on enable **`file-lock`** feature. Without it the locks only conflict
+    /// with other locks taken through the same mount.
+    pub fn flock_locks(&mut self, flock_locks: bool) -> &mut Self {
+        self.flock_locks = flock_locks;
+
+        self
+    }
+
     /// set custom options for fuse filesystem, the custom options will be used in mount
     pub fn custom_options(&mut self, custom_options: impl Into<OsString>) -> &mut Self {
         self.custom_options = Some(custom_options.into());
@@ -271,6 +325,33 @@ impl MountOptions {
         self
     }
 
+    /// Set how many background requests, such as readahead and asynchronous direct I/O, the
+    /// kernel keeps pending. If not set, it is the queue depth given to
+    /// [`Session::with_workers`](crate::raw::Session::with_workers) with more than one worker,
+    /// and 12 otherwise.
+    ///
+    /// # Example
+    /// ```
+    /// use rfuse3::MountOptions;
+    ///
+    /// let mut options = MountOptions::default();
+    /// options.max_background(Some(64));
+    /// ```
+    pub fn max_background(&mut self, max_background: Option<u16>) -> &mut Self {
+        self.max_background = max_background;
+
+        self
+    }
+
+    /// Set the number of pending background requests at which the kernel considers the
+    /// filesystem congested and throttles writeback and readahead. If not set, it is 3/4 of
+    /// the max background. It is capped to the max background.
+    pub fn congestion_threshold(&mut self, congestion_threshold: Option<u16>) -> &mut Self {
+        self.congestion_threshold = congestion_threshold;
+
+        self
+    }
+
     #[cfg(target_os = "freebsd")]
     pub(crate) fn build(&self) -> Nmount {
         let mut nmount = Nmount::new();
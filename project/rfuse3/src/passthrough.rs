@@ -0,0 +1,149 @@
+//! hand opened files to the kernel.
+//!
+//! With FUSE passthrough, negotiated with [`MountOptions::passthrough`], the filesystem can
+//! register the file backing an opened file with [`BackingFiles::open`] and return the id in
+//! [`ReplyOpen::backing_id`] or [`ReplyCreated::backing_id`]. The kernel then serves reads,
+//! writes and mmap of that handle from the backing file directly, while every other request
+//! still reaches the filesystem.
+//!
+//! [`MountOptions::passthrough`]: crate::MountOptions::passthrough
+//! [`ReplyOpen::backing_id`]: crate::raw::reply::ReplyOpen::backing_id
+//! [`ReplyCreated::backing_id`]: crate::raw::reply::ReplyCreated::backing_id
+
+use std::io;
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+/// id of a backing file registered with [`BackingFiles::open`].
+pub struct BackingId(u32);
+
+impl BackingId {
+    /// the id given by the kernel.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Dup of the /dev/fuse fd, set once passthrough was negotiated.
+    dev: OnceLock<OwnedFd>,
+    // The kernel refused to register a backing file, usually for lack of `CAP_SYS_ADMIN`.
+    refused: AtomicBool,
+}
+
+#[derive(Debug, Clone, Default)]
+/// register backing files with the kernel. It can be taken before mounting with
+/// [`Session::get_backing_files`] and is usable once the kernel agreed to passthrough.
+///
+/// [`Session::get_backing_files`]: crate::raw::Session::get_backing_files
+pub struct BackingFiles {
+    inner: Arc<Inner>,
+}
+
+impl BackingFiles {
+    pub(crate) fn enable(&self, dev: BorrowedFd<'_>) -> io::Result<()> {
+        let dev = dev.try_clone_to_owned()?;
+        let _ = self.inner.dev.set(dev);
+
+        Ok(())
+    }
+
+    /// whether [`open`](Self::open) may succeed: passthrough was negotiated and the kernel
+    /// didn't refuse a backing file with `EPERM` yet.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.dev.get().is_some() && !self.inner.refused.load(Ordering::Relaxed)
+    }
+
+    /// register `file` as a backing file. The kernel keeps its own reference, `file` can be
+    /// closed once this returns. Fails with `EOPNOTSUPP` when passthrough isn't enabled.
+    pub fn open(&self, file: BorrowedFd<'_>) -> io::Result<BackingId> {
+        let dev = self.dev()?;
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            use crate::raw::abi::{fuse_backing_map, FUSE_DEV_IOC_BACKING_OPEN};
+
+            let map = fuse_backing_map {
+                fd: file.as_raw_fd(),
+                ..Default::default()
+            };
+            // Safe because the kernel only reads `map` and we check the return value.
+            let id = unsafe {
+                libc::ioctl(
+                    dev.as_raw_fd(),
+                    FUSE_DEV_IOC_BACKING_OPEN as _,
+                    &map as *const fuse_backing_map,
+                )
+            };
+            if id < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EPERM) {
+                    tracing::warn!("kernel refused backing file, passthrough disabled: {}", err);
+
+                    self.inner.refused.store(true, Ordering::Relaxed);
+                }
+
+                return Err(err);
+            }
+
+            Ok(BackingId(id as u32))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (dev, file);
+
+            Err(not_supported())
+        }
+    }
+
+    /// drop a backing file. Handles opened with it keep using it until they are released.
+    pub fn close(&self, id: BackingId) -> io::Result<()> {
+        let dev = self.inner.dev.get().ok_or_else(not_supported)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            use crate::raw::abi::FUSE_DEV_IOC_BACKING_CLOSE;
+
+            // Safe because the kernel only reads the id and we check the return value.
+            let res = unsafe {
+                libc::ioctl(
+                    dev.as_raw_fd(),
+                    FUSE_DEV_IOC_BACKING_CLOSE as _,
+                    &id.0 as *const u32,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (dev, id);
+
+            Err(not_supported())
+        }
+    }
+
+    fn dev(&self) -> io::Result<&OwnedFd> {
+        if self.inner.refused.load(Ordering::Relaxed) {
+            return Err(not_supported());
+        }
+
+        self.inner.dev.get().ok_or_else(not_supported)
+    }
+}
+
+fn not_supported() -> io::Error {
+    io::Error::from_raw_os_error(libc::EOPNOTSUPP)
+}
@@ -36,6 +36,11 @@ pub const FUSE_KERNEL_VERSION: u32 = 7;
 
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
 
+/// Protocol minor version replied when [`FUSE_PASSTHROUGH`] is negotiated, the first one with
+/// backing files.
+#[cfg(target_os = "linux")]
+pub const FUSE_PASSTHROUGH_MINOR_VERSION: u32 = 40;
+
 pub const DEFAULT_MAX_BACKGROUND: u16 = 12;
 
 pub const DEFAULT_CONGESTION_THRESHOLD: u16 = DEFAULT_MAX_BACKGROUND * 3 / 4;
@@ -161,6 +166,15 @@ pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
 /// map_alignment field is valid
 pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26;
 
+#[cfg(target_os = "linux")]
+/// init_in.flags2 and init_out.flags2 are valid
+pub const FUSE_INIT_EXT: u32 = 1 << 30;
+
+#[cfg(target_os = "linux")]
+/// files can be backed by a file registered with [`FUSE_DEV_IOC_BACKING_OPEN`], bit 37 of the
+/// init flags so it lives in `flags2`
+pub const FUSE_PASSTHROUGH: u32 = 1 << (37 - 32);
+
 #[cfg(target_os = "macos")]
 pub const FUSE_ALLOCATE: u32 = 1 << 27;
 #[cfg(target_os = "macos")]
@@ -206,6 +220,8 @@ pub const FUSE_READ_LOCKOWNER: u32 = 1 << 1;
 pub const FOPEN_DIRECT_IO: u32 = 1 << 0;
 pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
 pub const FOPEN_NONSEEKABLE: u32 = 1 << 2;
+#[cfg(target_os = "linux")]
+pub const FOPEN_PASSTHROUGH: u32 = 1 << 7;
 
 // IOCTL flags
 #[allow(dead_code)]
@@ -355,6 +371,7 @@ pub enum fuse_opcode {
     FUSE_COPY_FILE_RANGE = 47,
     // FUSE_SETUPMAPPING = 48,
     // FUSE_REMOVEMAPPING = 49,
+    FUSE_STATX = 52,
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
     #[cfg(target_os = "macos")]
@@ -425,6 +442,7 @@ impl TryFrom<u32> for fuse_opcode {
             47 => Ok(fuse_opcode::FUSE_COPY_FILE_RANGE),
             // 48 => Ok(fuse_opcode::FUSE_SETUPMAPPING),
             // 49 => Ok(fuse_opcode::FUSE_REMOVEMAPPING),
+            52 => Ok(fuse_opcode::FUSE_STATX),
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
             #[cfg(target_os = "macos")]
@@ -681,7 +699,7 @@ pub const FUSE_OPEN_OUT_SIZE: usize = mem::size_of::<fuse_open_out>();
 pub struct fuse_open_out {
     pub fh: u64,
     pub open_flags: u32,
-    pub(crate) _padding: u32,
+    pub backing_id: i32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -795,7 +813,7 @@ pub struct fuse_lk_in {
     pub fh: u64,
     pub owner: u64,
     pub lk: fuse_file_lock,
-    pub(crate) _lk_flags: u32,
+    pub(crate) lk_flags: u32,
     _padding: u32,
 }
 
@@ -825,6 +843,8 @@ pub struct fuse_init_in {
     pub flags: u32,
 }
 
+pub const FUSE_INIT_IN_SIZE: usize = mem::size_of::<fuse_init_in>();
+
 pub const FUSE_INIT_OUT_SIZE: usize = mem::size_of::<fuse_init_out>();
 
 #[derive(Debug, Serialize)]
@@ -840,7 +860,9 @@ pub struct fuse_init_out {
     pub time_gran: u32,
     pub max_pages: u16,
     pub map_alignment: u16,
-    pub unused: [u32; 8],
+    pub flags2: u32,
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 
 /*#[derive(Debug)]
@@ -1095,3 +1117,79 @@ pub struct fuse_copy_file_range_in {
     pub len: u64,
     pub flags: u64,
 }
+
+#[derive(Debug, Deserialize)]
+#[allow(non_camel_case_types, dead_code)]
+pub struct fuse_statx_in {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[allow(non_camel_case_types)]
+pub struct fuse_sx_time {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[allow(non_camel_case_types)]
+pub struct fuse_statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub __spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: fuse_sx_time,
+    pub btime: fuse_sx_time,
+    pub ctime: fuse_sx_time,
+    pub mtime: fuse_sx_time,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub __spare2: [u64; 14],
+}
+
+pub const FUSE_STATX_OUT_SIZE: usize = mem::size_of::<fuse_statx_out>();
+
+#[derive(Debug, Serialize)]
+#[allow(non_camel_case_types)]
+pub struct fuse_statx_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: fuse_statx,
+}
+
+/// Argument of [`FUSE_DEV_IOC_BACKING_OPEN`].
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct fuse_backing_map {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+
+/// `_IOW(FUSE_DEV_IOC_MAGIC, 1, struct fuse_backing_map)`, registers a backing file and returns
+/// its id.
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_OPEN: u32 = 0x4010_e501;
+
+/// `_IOW(FUSE_DEV_IOC_MAGIC, 2, uint32_t)`, drops the backing file of an id.
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u32 = 0x4004_e502;
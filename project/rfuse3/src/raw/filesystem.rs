@@ -48,6 +48,19 @@ pub trait Filesystem {
         Err(libc::ENOSYS.into())
     }
 
+    /// get extended file attributes, including the creation time. If `fh` is None, means `fh`
+    /// is not set. `mask` holds the `STATX_*` fields the caller asked for.
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        Err(libc::ENOSYS.into())
+    }
+
     /// set file attributes. If `fh` is None, means `fh` is not set.
     async fn setattr(
         &self,
@@ -168,6 +181,24 @@ pub trait Filesystem {
         Err(libc::ENOSYS.into())
     }
 
+    /// read data from a file instead of a buffer, when enabled with
+    /// [`MountOptions::splice_read`]. The session moves up to `size` bytes of the file at the
+    /// offset of the reply to the kernel with `splice(2)`, stopping at the end of the file, so
+    /// the data isn't copied through userspace. Returning `None` serves the read with
+    /// [`read`][Filesystem::read] instead, which is the default.
+    ///
+    /// [`MountOptions::splice_read`]: crate::MountOptions::splice_read
+    async fn read_fd(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Option<ReplyReadFd>> {
+        Ok(None)
+    }
+
     /// write data. Write should return exactly the number of bytes requested except on error. An
     /// exception to this is when the file has been opened in `direct_io` mode, in which case the
     /// return value of the write system call will reflect the return value of this operation. `fh`
@@ -353,6 +384,28 @@ pub trait Filesystem {
         block: bool,
     ) -> Result<()>;
 
+    #[cfg(feature = "file-lock")]
+    /// acquire, convert or release a BSD `flock(2)` lock on the open file `fh`. `type` is
+    /// `F_RDLCK` for a shared lock, `F_WRLCK` for an exclusive one and `F_UNLCK` to release it.
+    /// When `block` is set the call waits for conflicting locks to go away.
+    ///
+    /// # Notes:
+    ///
+    /// this is supported on enable **`file-lock`** feature, and only called when
+    /// [`MountOptions::flock_locks`](crate::MountOptions::flock_locks) is enabled. The kernel
+    /// drops the lock by releasing the file, `flock` locks follow the open file.
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
     /// check file access permissions. This will be called for the `access()` system call. If the
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
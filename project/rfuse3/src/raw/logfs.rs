@@ -4,29 +4,144 @@ use crate::notify::Notify;
 use crate::Inode;
 use crate::{Result, SetAttr};
 use bytes::Bytes;
+use dashmap::DashMap;
 use std::any::type_name_of_val;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 use tracing::debug;
+
+/// Which operations [`LoggingFileSystem`] logs.
+///
+/// Operations are first sampled, one in `sample_every` of each type, then the sampled ones
+/// go through a token bucket. Every operation of a type that is logged is logged with its
+/// result, and the count of operations the bucket dropped is logged with the next one it
+/// lets through.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Log one operation in this many of each type, 0 and 1 log all of them.
+    pub sample_every: u32,
+    /// Sampling rate of single operation types, e.g. 1 for `"init"` with a high default.
+    pub sample_overrides: HashMap<String, u32>,
+    /// Most operations logged per second on average, unlimited when `None`.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            sample_every: 1,
+            sample_overrides: HashMap::new(),
+            rate_limit: None,
+        }
+    }
+}
+
+/// Token bucket of [`LogConfig::rate_limit`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Operations logged per second once the burst is used up.
+    pub per_second: f64,
+    /// Operations logged at once after a quiet period.
+    pub burst: u32,
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    dropped: u64,
+}
+
+impl Bucket {
+    // Take a token, with the number of operations dropped since the last one taken.
+    fn take(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        if self.tokens < 1.0 {
+            self.dropped += 1;
+            return None;
+        }
+        self.tokens -= 1.0;
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
 // LoggingFileSystem . provide log info for a filesystem trait.
 pub struct LoggingFileSystem<FS: Filesystem> {
     inner: FS,
     fsname: String,
     next_log_id: AtomicU64,
+    config: LogConfig,
+    // Operations seen by type, for sampling.
+    seen: DashMap<&'static str, u64>,
+    bucket: Option<Mutex<Bucket>>,
 }
 
 impl<FS: Filesystem> LoggingFileSystem<FS> {
     pub fn new(fs: FS) -> Self {
+        Self::with_config(fs, LogConfig::default())
+    }
+
+    /// Log only the operations selected by `config`.
+    pub fn with_config(fs: FS, config: LogConfig) -> Self {
         let fsname = type_name_of_val(&fs);
+        let bucket = config.rate_limit.map(|limit| {
+            Mutex::new(Bucket {
+                limit,
+                tokens: limit.burst as f64,
+                refilled: Instant::now(),
+                dropped: 0,
+            })
+        });
         Self {
             inner: fs,
             fsname: String::from(fsname),
             next_log_id: AtomicU64::new(1),
+            config,
+            seen: DashMap::new(),
+            bucket,
         }
     }
 }
 impl<FS: Filesystem> LoggingFileSystem<FS> {
+    // Log id of an operation of type `method`, 0 if it isn't logged.
+    fn log_id(&self, method: &'static str) -> u64 {
+        let every = self
+            .config
+            .sample_overrides
+            .get(method)
+            .copied()
+            .unwrap_or(self.config.sample_every);
+        if every > 1 {
+            let mut seen = self.seen.entry(method).or_insert(0);
+            *seen += 1;
+            if (*seen - 1) % u64::from(every) != 0 {
+                return 0;
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            let Some(dropped) = bucket.lock().unwrap().take() else {
+                return 0;
+            };
+            if dropped > 0 {
+                debug!(
+                    "[{}] {dropped} operations not logged, over the rate limit",
+                    self.fsname
+                );
+            }
+        }
+        self.next_log_id.fetch_add(1, Ordering::Relaxed)
+    }
+
     fn log_start(&self, req: &Request, id: u64, method: &str, args: &[(&str, String)]) {
+        if id == 0 {
+            return;
+        }
         let args_str = args
             .iter()
             .map(|(k, v)| format!("{k}={v}"))
@@ -36,17 +151,27 @@ impl<FS: Filesystem> LoggingFileSystem<FS> {
     }
 
     fn log_result(&self, id: u64, method: &str, result: &Result<impl std::fmt::Debug>) {
+        if id == 0 {
+            return;
+        }
         match result {
             Ok(res) => debug!("ID: {id} | [{method}] - Success: {res:?}"),
             Err(e) => debug!("ID: {id} | [{method}] - Error: {e:?}"),
         }
     }
+
+    // Log `detail` of the operation `id`, if it is logged.
+    fn log_detail(&self, id: u64, method: &str, detail: std::fmt::Arguments) {
+        if id != 0 {
+            debug!("ID: {} [{}] {} - {}", id, self.fsname, method, detail);
+        }
+    }
 }
 
 impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     async fn init(&self, req: Request) -> Result<ReplyInit> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "init";
+        let id = self.log_id(method);
         self.log_start(&req, id, method, &[]);
         let result = self.inner.init(req).await;
         self.log_result(id, method, &result);
@@ -54,16 +179,16 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn destroy(&self, req: Request) {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "destroy";
+        let id = self.log_id(method);
         self.log_start(&req, id, method, &[]);
         self.inner.destroy(req).await;
-        debug!("ID: {} [{}] {} - Completed", id, self.fsname, method);
+        self.log_detail(id, method, format_args!("Completed"));
     }
 
     async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "lookup";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -75,15 +200,15 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "forget";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("nlookup", nlookup.to_string()),
         ];
         self.log_start(&req, id, method, &args);
         self.inner.forget(req, inode, nlookup).await;
-        debug!("ID: {} [{}] {} - Completed", id, self.fsname, method);
+        self.log_detail(id, method, format_args!("Completed"));
     }
 
     async fn getattr(
@@ -93,8 +218,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         fh: Option<u64>,
         flags: u32,
     ) -> Result<ReplyAttr> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "getattr";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.map(|v| v.to_string()).unwrap_or_default()),
@@ -106,6 +231,28 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         result
     }
 
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        let method = "statx";
+        let id = self.log_id(method);
+        let args = vec![
+            ("inode", inode.to_string()),
+            ("fh", fh.map(|v| v.to_string()).unwrap_or_default()),
+            ("flags", flags.to_string()),
+            ("mask", format!("{mask:#x}")),
+        ];
+        self.log_start(&req, id, method, &args);
+        let result = self.inner.statx(req, inode, fh, flags, mask).await;
+        self.log_result(id, method, &result);
+        result
+    }
+
     async fn setattr(
         &self,
         req: Request,
@@ -113,8 +260,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         fh: Option<u64>,
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "setattr";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.map(|v| v.to_string()).unwrap_or_default()),
@@ -138,8 +285,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
         >,
     > {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "readdirplus";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("fh", fh.to_string()),
@@ -156,16 +303,13 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "opendir";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string()), ("flags", flags.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.opendir(req, inode, flags).await;
         if let Ok(ref reply) = result {
-            debug!(
-                "ID: {} [{}] {} - Obtained fh: {}",
-                id, self.fsname, method, reply.fh
-            );
+            self.log_detail(id, method, format_args!("Obtained fh: {}", reply.fh));
         }
         self.log_result(id, method, &result);
         result
@@ -182,8 +326,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
         >,
     > {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "readdir";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("fh", fh.to_string()),
@@ -203,8 +347,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         offset: u64,
         size: u32,
     ) -> Result<ReplyData> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "read";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -214,19 +358,35 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         self.log_start(&req, id, method, &args);
         let result = self.inner.read(req, inode, fh, offset, size).await;
         if let Ok(ref data) = result {
-            debug!(
-                "ID: {} [{}] {} - Read {} bytes",
-                id,
-                self.fsname,
-                method,
-                data.data.len()
-            );
+            self.log_detail(id, method, format_args!("Read {} bytes", data.data.len()));
         }
 
         // self.log_result(id, method, &result);
         result
     }
 
+    async fn read_fd(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Option<ReplyReadFd>> {
+        let method = "read_fd";
+        let id = self.log_id(method);
+        let args = vec![
+            ("inode", inode.to_string()),
+            ("fh", fh.to_string()),
+            ("offset", offset.to_string()),
+            ("size", size.to_string()),
+        ];
+        self.log_start(&req, id, method, &args);
+        let result = self.inner.read_fd(req, inode, fh, offset, size).await;
+        self.log_result(id, method, &result);
+        result
+    }
+
     async fn write(
         &self,
         req: Request,
@@ -237,8 +397,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         write_flags: u32,
         flags: u32,
     ) -> Result<ReplyWrite> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "write";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -253,18 +413,15 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
             .write(req, inode, fh, offset, data, write_flags, flags)
             .await;
         if let Ok(ref reply) = result {
-            debug!(
-                "ID: {} [{}] {} - Wrote {} bytes",
-                id, self.fsname, method, reply.written
-            );
+            self.log_detail(id, method, format_args!("Wrote {} bytes", reply.written));
         }
         self.log_result(id, method, &result);
         result
     }
 
     async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "fsync";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -285,8 +442,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         flags: u32,
         position: u32,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "setxattr";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -312,8 +469,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         new_name: &OsStr,
         flags: u32,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "rename2";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -331,8 +488,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "unlink";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -351,8 +508,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         mode: u32,
         umask: u32,
     ) -> Result<ReplyEntry> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "mkdir";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -366,8 +523,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "access";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string()), ("mask", mask.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.access(req, inode, mask).await;
@@ -382,8 +539,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         name: &OsStr,
         size: u32,
     ) -> Result<ReplyXAttr> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "getxattr";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -403,8 +560,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         mode: u32,
         flags: u32,
     ) -> Result<ReplyCreated> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "create";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -425,8 +582,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         offset: u64,
         whence: u32,
     ) -> Result<ReplyLSeek> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "lseek";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -447,8 +604,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         mode: u32,
         rdev: u32,
     ) -> Result<ReplyEntry> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "mknod";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -469,8 +626,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "rename";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -486,8 +643,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         result
     }
     async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "listxattr";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string()), ("size", size.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.listxattr(req, inode, size).await;
@@ -496,24 +653,21 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "open";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string()), ("flags", flags.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.open(req, inode, flags).await;
         if let Ok(ref reply) = result {
-            debug!(
-                "ID: {} [{}] {} - Obtained fh: {}",
-                id, self.fsname, method, reply.fh
-            );
+            self.log_detail(id, method, format_args!("Obtained fh: {}", reply.fh));
         }
         self.log_result(id, method, &result);
         result
     }
 
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "rmdir";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -525,8 +679,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn statfs(&self, req: Request, inode: Inode) -> Result<ReplyStatFs> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "statfs";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.statfs(req, inode).await;
@@ -541,8 +695,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<ReplyEntry> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "link";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("new_parent", new_parent.to_string()),
@@ -561,8 +715,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         name: &OsStr,
         link: &OsStr,
     ) -> Result<ReplyEntry> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "symlink";
+        let id = self.log_id(method);
         let args = vec![
             ("parent", parent.to_string()),
             ("name", name.to_string_lossy().into_owned()),
@@ -575,8 +729,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn batch_forget(&self, req: Request, inodes: &[(Inode, u64)]) {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "batch_forget";
+        let id = self.log_id(method);
         let args = vec![(
             "inodes",
             inodes
@@ -597,8 +751,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         blocksize: u32,
         idx: u64,
     ) -> Result<ReplyBmap> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "bmap";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("blocksize", blocksize.to_string()),
@@ -622,8 +776,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         length: u64,
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "copy_file_range";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh_in", fh_in.to_string()),
@@ -654,8 +808,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         length: u64,
         mode: u32,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "fallocate";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -673,8 +827,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "flush";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -687,8 +841,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "fsyncdir";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -713,8 +867,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         r#type: u32,
         pid: u32,
     ) -> Result<ReplyLock> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "getlk";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -747,8 +901,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         pid: u32,
         block: bool,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "setlk";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -768,6 +922,34 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         result
     }
 
+    #[cfg(feature = "file-lock")]
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        let method = "flock";
+        let id = self.log_id(method);
+        let args = vec![
+            ("inode", inode.to_string()),
+            ("fh", fh.to_string()),
+            ("lock_owner", lock_owner.to_string()),
+            ("type", r#type.to_string()),
+            ("block", block.to_string()),
+        ];
+        self.log_start(&req, id, method, &args);
+        let result = self
+            .inner
+            .flock(req, inode, fh, lock_owner, r#type, block)
+            .await;
+        self.log_result(id, method, &result);
+        result
+    }
+
     // async  fn interrupt(&self, req: Request, unique: u64) -> Result<()> {
     //     let uuid = Uuid::new_v4();
     //     let method = "interrupt";
@@ -787,8 +969,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         offset: u64,
         data: Bytes,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "notify_reply";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string()), ("offset", offset.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.notify_reply(req, inode, offset, data).await;
@@ -806,8 +988,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         events: u32,
         notify: &Notify,
     ) -> Result<ReplyPoll> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "poll";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -824,8 +1006,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "readlink";
+        let id = self.log_id(method);
         let args = vec![("inode", inode.to_string())];
         self.log_start(&req, id, method, &args);
         let result = self.inner.readlink(req, inode).await;
@@ -842,8 +1024,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         lock_owner: u64,
         flush: bool,
     ) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "release";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -861,8 +1043,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "releasedir";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("fh", fh.to_string()),
@@ -875,8 +1057,8 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
     }
 
     async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
-        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
         let method = "removexattr";
+        let id = self.log_id(method);
         let args = vec![
             ("inode", inode.to_string()),
             ("name", name.to_string_lossy().to_string()),
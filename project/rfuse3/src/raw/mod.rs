@@ -35,6 +35,7 @@ pub mod prelude {
     pub use super::Session;
     pub use super::{DirectoryPlusStream, DirectoryStream};
     pub use crate::notify::Notify;
+    pub use crate::passthrough::{BackingFiles, BackingId};
     pub use crate::FileType;
     pub use crate::SetAttr;
 }
@@ -69,6 +69,19 @@ pub trait ObjectSafeFilesystem: Send + Sync {
         Err(libc::ENOSYS.into())
     }
 
+    /// get extended file attributes, including the creation time. If `fh` is None, means `fh`
+    /// is not set. `mask` holds the `STATX_*` fields the caller asked for.
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        Err(libc::ENOSYS.into())
+    }
+
     /// set file attributes. If `fh` is None, means `fh` is not set.
     async fn setattr(
         &self,
@@ -374,6 +387,24 @@ pub trait ObjectSafeFilesystem: Send + Sync {
         block: bool,
     ) -> Result<()>;
 
+    #[cfg(feature = "file-lock")]
+    /// acquire, convert or release a BSD `flock(2)` lock on the open file `fh`.
+    ///
+    /// # Notes:
+    ///
+    /// this is supported on enable **`file-lock`** feature.
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        Err(libc::ENOSYS.into())
+    }
+
     /// check file access permissions. This will be called for the `access()` system call. If the
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
@@ -567,6 +598,17 @@ where
         Filesystem::getattr(self, req, inode, fh, flags).await
     }
 
+    async fn statx(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<ReplyStatx> {
+        Filesystem::statx(self, req, inode, fh, flags, mask).await
+    }
+
     async fn setattr(
         &self,
         req: Request,
@@ -783,6 +825,19 @@ where
         .await
     }
 
+    #[cfg(feature = "file-lock")]
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        block: bool,
+    ) -> Result<()> {
+        Filesystem::flock(self, req, inode, fh, lock_owner, r#type, block).await
+    }
+
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
         Filesystem::access(self, req, inode, mask).await
     }
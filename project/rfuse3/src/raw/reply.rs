@@ -1,6 +1,7 @@
 //! reply structures.
 use std::ffi::OsString;
 use std::num::NonZeroU32;
+use std::os::fd::OwnedFd;
 use std::time::Duration;
 
 use bytes::Bytes;
@@ -8,9 +9,13 @@ use futures_util::stream::Stream;
 
 use crate::helper::mode_from_kind_and_perm;
 use crate::mount_options::DEFAULT_MAX_WRITE;
+use crate::passthrough::BackingId;
+#[cfg(target_os = "linux")]
+use crate::raw::abi::FOPEN_PASSTHROUGH;
 use crate::raw::abi::{
     fuse_attr, fuse_attr_out, fuse_bmap_out, fuse_entry_out, fuse_kstatfs, fuse_lseek_out,
-    fuse_open_out, fuse_poll_out, fuse_statfs_out, fuse_write_out,
+    fuse_open_out, fuse_poll_out, fuse_statfs_out, fuse_statx, fuse_statx_out, fuse_sx_time,
+    fuse_write_out,
 };
 #[cfg(feature = "file-lock")]
 use crate::raw::abi::{fuse_file_lock, fuse_lk_out};
@@ -146,6 +151,69 @@ impl From<ReplyAttr> for fuse_attr_out {
     }
 }
 
+/// `statx` mask bits of the fields filled from [`FileAttr`].
+const STATX_BASIC_STATS: u32 = 0x7ff;
+const STATX_BTIME: u32 = 0x800;
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+/// statx reply.
+pub struct ReplyStatx {
+    /// the attribute TTL.
+    pub ttl: Duration,
+    /// the attribute.
+    pub attr: FileAttr,
+    /// creation time, None if the filesystem doesn't record it.
+    pub btime: Option<Timestamp>,
+    /// `STATX_ATTR_*` flags set on the file.
+    pub attributes: u64,
+    /// `STATX_ATTR_*` flags the filesystem supports.
+    pub attributes_mask: u64,
+}
+
+impl From<ReplyStatx> for fuse_statx_out {
+    fn from(reply: ReplyStatx) -> Self {
+        let sx_time = |ts: Timestamp| fuse_sx_time {
+            tv_sec: ts.sec,
+            tv_nsec: ts.nsec,
+            __reserved: 0,
+        };
+        let attr = reply.attr;
+        let mut mask = STATX_BASIC_STATS;
+        if reply.btime.is_some() {
+            mask |= STATX_BTIME;
+        }
+        // `rdev` uses the kernel's `new_encode_dev` layout.
+        let rdev = attr.rdev;
+
+        fuse_statx_out {
+            attr_valid: reply.ttl.as_secs(),
+            attr_valid_nsec: reply.ttl.subsec_nanos(),
+            flags: 0,
+            spare: [0; 2],
+            stat: fuse_statx {
+                mask,
+                blksize: attr.blksize,
+                attributes: reply.attributes,
+                nlink: attr.nlink,
+                uid: attr.uid,
+                gid: attr.gid,
+                mode: mode_from_kind_and_perm(attr.kind, attr.perm) as u16,
+                ino: attr.ino,
+                size: attr.size,
+                blocks: attr.blocks,
+                attributes_mask: reply.attributes_mask,
+                atime: sx_time(attr.atime),
+                btime: reply.btime.map(sx_time).unwrap_or_default(),
+                ctime: sx_time(attr.ctime),
+                mtime: sx_time(attr.mtime),
+                rdev_major: (rdev >> 8) & 0xfff,
+                rdev_minor: (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 /// data reply.
 pub struct ReplyData {
@@ -153,6 +221,17 @@ pub struct ReplyData {
     pub data: Bytes,
 }
 
+#[derive(Debug)]
+/// data reply served from a file, see [`Filesystem::read_fd`].
+///
+/// [`Filesystem::read_fd`]: crate::raw::Filesystem::read_fd
+pub struct ReplyReadFd {
+    /// the file holding the data.
+    pub fd: OwnedFd,
+    /// offset of the data in the file.
+    pub offset: u64,
+}
+
 impl From<Bytes> for ReplyData {
     fn from(data: Bytes) -> Self {
         Self { data }
@@ -170,15 +249,30 @@ pub struct ReplyOpen {
     pub fh: u64,
     /// the flags.
     pub flags: u32,
+    /// serve reads and writes of the handle from this backing file in the kernel, see
+    /// [`passthrough`](crate::passthrough).
+    pub backing_id: Option<BackingId>,
 }
 
 impl From<ReplyOpen> for fuse_open_out {
     fn from(opened: ReplyOpen) -> Self {
-        fuse_open_out {
-            fh: opened.fh,
-            open_flags: opened.flags,
-            _padding: 0,
-        }
+        open_out(opened.fh, opened.flags, opened.backing_id)
+    }
+}
+
+fn open_out(fh: u64, flags: u32, backing_id: Option<BackingId>) -> fuse_open_out {
+    match backing_id {
+        #[cfg(target_os = "linux")]
+        Some(id) => fuse_open_out {
+            fh,
+            open_flags: flags | FOPEN_PASSTHROUGH,
+            backing_id: id.get() as i32,
+        },
+        _ => fuse_open_out {
+            fh,
+            open_flags: flags,
+            backing_id: 0,
+        },
     }
 }
 
@@ -318,6 +412,9 @@ pub struct ReplyCreated {
     pub fh: u64,
     /// the flags.
     pub flags: u32,
+    /// serve reads and writes of the handle from this backing file in the kernel, see
+    /// [`passthrough`](crate::passthrough).
+    pub backing_id: Option<BackingId>,
 }
 
 impl From<ReplyCreated> for (fuse_entry_out, fuse_open_out) {
@@ -334,11 +431,7 @@ impl From<ReplyCreated> for (fuse_entry_out, fuse_open_out) {
             attr: attr.into(),
         };
 
-        let open_out = fuse_open_out {
-            fh: created.fh,
-            open_flags: created.flags,
-            _padding: 0,
-        };
+        let open_out = open_out(created.fh, created.flags, created.backing_id);
 
         (entry_out, open_out)
     }
@@ -130,6 +130,68 @@ pub(super) async fn worker_getattr<FS: Filesystem + Send + Sync + 'static>(
     });
 }
 
+pub(super) async fn worker_statx<FS: Filesystem + Send + Sync + 'static>(
+    ctx: &Arc<DispatchCtx<FS>>,
+    item: WorkItem,
+) {
+    let statx_in = match get_bincode_config().deserialize::<fuse_statx_in>(&item.data) {
+        Err(err) => {
+            debug!(
+                unique = item.unique,
+                "deserialize fuse_statx_in failed {}", err
+            );
+            let data = reply_error_in_worker(libc::EINVAL.into(), item.unique)
+                .expect("serialize out_header");
+            let _ = ctx.resp.unbounded_send(Either::Left(data));
+            return;
+        }
+        Ok(v) => v,
+    };
+    let fh = if statx_in.getattr_flags & FUSE_GETATTR_FH > 0 {
+        Some(statx_in.fh)
+    } else {
+        None
+    };
+    let fs = ctx.fs.clone();
+    let resp_sender = ctx.resp.clone();
+    spawn(debug_span!("fuse_statx_worker"), async move {
+        debug!(
+            unique = item.unique,
+            inode = item.in_header.nodeid,
+            "statx (worker)"
+        );
+        let data = match fs
+            .statx(
+                Request::from(&item),
+                item.in_header.nodeid,
+                fh,
+                statx_in.sx_flags,
+                statx_in.sx_mask,
+            )
+            .await
+        {
+            Err(err) => reply_error_in_worker(err, item.unique).expect("serialize out_header"),
+            Ok(statx) => {
+                let statx_out: fuse_statx_out = statx.into();
+                let out_header = fuse_out_header {
+                    len: (FUSE_OUT_HEADER_SIZE + FUSE_STATX_OUT_SIZE) as u32,
+                    error: 0,
+                    unique: item.unique,
+                };
+                let mut data = Vec::with_capacity(FUSE_OUT_HEADER_SIZE + FUSE_STATX_OUT_SIZE);
+                get_bincode_config()
+                    .serialize_into(&mut data, &out_header)
+                    .expect("serialize header");
+                get_bincode_config()
+                    .serialize_into(&mut data, &statx_out)
+                    .expect("serialize statx_out");
+                data
+            }
+        };
+        let _ = resp_sender.unbounded_send(Either::Left(data));
+    });
+}
+
 pub(super) async fn worker_open<FS: Filesystem + Send + Sync + 'static>(
     ctx: &Arc<DispatchCtx<FS>>,
     item: WorkItem,
@@ -203,6 +265,7 @@ pub(super) async fn worker_read<FS: Filesystem + Send + Sync + 'static>(
     };
     let fs = ctx.fs.clone();
     let resp = ctx.resp.clone();
+    let splice = ctx.splice.clone();
     spawn(debug_span!("fuse_read_worker"), async move {
         debug!(
             unique = item.unique,
@@ -211,6 +274,30 @@ pub(super) async fn worker_read<FS: Filesystem + Send + Sync + 'static>(
             offset = read_in.offset,
             "read (worker)"
         );
+        if splice.is_enabled() {
+            match fs
+                .read_fd(
+                    Request::from(&item),
+                    item.in_header.nodeid,
+                    read_in.fh,
+                    read_in.offset,
+                    read_in.size,
+                )
+                .await
+            {
+                Err(err) => {
+                    let data =
+                        reply_error_in_worker(err, item.unique).expect("serialize out_header");
+                    let _ = resp.unbounded_send(Either::Left(data));
+                    return;
+                }
+                Ok(Some(reply)) => {
+                    splice.reply_read(item.unique, reply, read_in.size, &resp);
+                    return;
+                }
+                Ok(None) => {}
+            }
+        }
         let mut reply_data = match fs
             .read(
                 Request::from(&item),
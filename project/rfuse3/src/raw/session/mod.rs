@@ -4,6 +4,7 @@
 //! It supports both legacy single-threaded mode and modern worker pool mode for better concurrency.
 
 mod handlers;
+mod splice;
 mod utils;
 mod worker;
 
@@ -12,6 +13,7 @@ pub use worker::InflightGuard;
 pub(crate) use worker::WorkItem;
 
 // Internal types used across submodules
+use splice::{Splice, SplicedReply};
 use utils::{
     apply_direct_io, is_forget_opcode, reply_error_in_place, spawn, InHeaderLite, ReadResult,
 };
@@ -54,7 +56,7 @@ use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures_util::future::{Either, FutureExt};
 use futures_util::select;
 use futures_util::sink::SinkExt;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{self, StreamExt};
 use nix::mount;
 #[cfg(any(target_os = "freebsd", target_os = "macos"))]
 use nix::mount::MntFlags;
@@ -76,6 +78,7 @@ use tracing::{debug, debug_span, error, instrument, warn};
 use crate::find_fusermount3;
 use crate::helper::*;
 use crate::notify::Notify;
+use crate::passthrough::BackingFiles;
 use crate::raw::abi::*;
 use crate::raw::buffer_pool::AlignedBuffer;
 #[cfg(any(feature = "async-io-runtime", feature = "tokio-runtime"))]
@@ -288,6 +291,9 @@ pub struct Session<FS: Filesystem + Send + Sync + 'static> {
     response_sender: UnboundedSender<FuseData>,
     response_receiver: Option<UnboundedReceiver<FuseData>>,
     mount_options: MountOptions,
+    backing_files: BackingFiles,
+    splice: Splice,
+    splice_receiver: Option<UnboundedReceiver<SplicedReply>>,
     // ---- Concurrency configuration ----
     /// Number of worker tasks to execute FUSE requests. 0 or 1 keeps legacy inline spawn behavior.
     worker_count: usize,
@@ -307,6 +313,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     /// new a fuse filesystem session.
     pub fn new(mount_options: MountOptions) -> Self {
         let (sender, receiver) = unbounded();
+        let (splice_sender, splice_receiver) = unbounded();
 
         Self {
             fuse_connection: None,
@@ -314,6 +321,9 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             response_sender: sender,
             response_receiver: Some(receiver),
             mount_options,
+            backing_files: BackingFiles::default(),
+            splice: Splice::new(splice_sender),
+            splice_receiver: Some(splice_receiver),
             // default to legacy behaviour (no explicit pool)
             worker_count: 0,
             max_background: DEFAULT_MAX_BACKGROUND as usize,
@@ -358,6 +368,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             let ctx = Arc::new(DispatchCtx {
                 fs,
                 resp: self.response_sender.clone(),
+                splice: self.splice.clone(),
                 direct_io: self.mount_options.direct_io,
                 _inflight: self.inflight.clone(),
                 _inflight_notify: self.inflight_notify.clone(),
@@ -372,12 +383,19 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         }
     }
 
-    /// get a [`notify`].
+    /// get a [`notify`]. It can be taken before mounting and used by the filesystem later to
+    /// invalidate kernel caches.
     ///
     /// [`notify`]: Notify
-    fn get_notify(&self) -> Notify {
+    pub fn get_notify(&self) -> Notify {
         Notify::new(self.response_sender.clone())
     }
+
+    /// get the [`BackingFiles`] of the session. It can be taken before mounting, and registers
+    /// files once passthrough was negotiated, see [`MountOptions::passthrough`].
+    pub fn get_backing_files(&self) -> BackingFiles {
+        self.backing_files.clone()
+    }
 }
 
 #[cfg(any(feature = "async-io-runtime", feature = "tokio-runtime"))]
@@ -590,18 +608,24 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         let fuse_write_connection = self.fuse_connection.as_ref().unwrap().clone();
 
         let receiver = self.response_receiver.take().unwrap();
+        let splice_receiver = self.splice_receiver.take().unwrap();
 
         let dispatch_task = self.dispatch().fuse();
         let mut dispatch_task = pin!(dispatch_task);
 
         #[cfg(all(not(feature = "tokio-runtime"), feature = "async-io-runtime"))]
-        let reply_task =
-            task::spawn(async move { Self::reply_fuse(fuse_write_connection, receiver).await })
-                .fuse();
+        let reply_task = task::spawn(async move {
+            Self::reply_fuse(fuse_write_connection, receiver, splice_receiver).await
+        })
+        .fuse();
         #[cfg(all(not(feature = "async-io-runtime"), feature = "tokio-runtime"))]
-        let reply_task = task::spawn(Self::reply_fuse(fuse_write_connection, receiver))
-            .map(Result::unwrap)
-            .fuse();
+        let reply_task = task::spawn(Self::reply_fuse(
+            fuse_write_connection,
+            receiver,
+            splice_receiver,
+        ))
+        .map(Result::unwrap)
+        .fuse();
 
         let mut reply_task = pin!(reply_task);
 
@@ -620,9 +644,36 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
 
     async fn reply_fuse(
         fuse_connection: Arc<FuseConnection>,
-        mut response_receiver: UnboundedReceiver<FuseData>,
+        response_receiver: UnboundedReceiver<FuseData>,
+        splice_receiver: UnboundedReceiver<SplicedReply>,
     ) -> IoResult<()> {
-        while let Some(response) = response_receiver.next().await {
+        let mut responses = stream::select(
+            response_receiver.map(Either::Left),
+            splice_receiver.map(Either::Right),
+        );
+        while let Some(response) = responses.next().await {
+            let response = match response {
+                Either::Left(response) => response,
+                Either::Right(spliced) => {
+                    let unique = spliced.unique;
+                    if let Err(err) = spliced.write_to(fuse_connection.as_fd()) {
+                        if err.kind() == std::io::ErrorKind::NotFound {
+                            warn!(
+                                "may reply interrupted fuse request, ignore this error {}",
+                                err
+                            );
+
+                            continue;
+                        }
+
+                        error!(error = %err, unique, "splice reply fuse failed");
+
+                        return Err(err);
+                    }
+
+                    continue;
+                }
+            };
             let (mut data, extend_data) = match response {
                 Either::Left(data) => (data, None),
                 Either::Right((data, extend_data)) => (data, Some(extend_data)),
@@ -964,6 +1015,10 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                         }
                     }
 
+                    fuse_opcode::FUSE_STATX => {
+                        self.handle_statx(request, in_header, data_ref, &fs).await;
+                    }
+
                     fuse_opcode::FUSE_SETATTR => {
                         self.handle_setattr(request, in_header, data_ref, &fs).await;
                     }
@@ -1284,10 +1339,20 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             reply_flags |= FUSE_SPLICE_READ;
         }
 
-        // posix lock used, maybe we don't need bsd lock
-        /*if init_in.flags&FUSE_FLOCK_LOCKS>0 {
+        // FUSE_SPLICE_WRITE tells the device takes replies spliced into it.
+        #[cfg(target_os = "linux")]
+        if self.mount_options.splice_read && init_in.flags & FUSE_SPLICE_WRITE > 0 {
+            debug!("enable splice read");
+
+            self.splice.enable();
+        }
+
+        #[cfg(feature = "file-lock")]
+        if self.mount_options.flock_locks && init_in.flags & FUSE_FLOCK_LOCKS > 0 {
+            debug!("enable FUSE_FLOCK_LOCKS");
+
             reply_flags |= FUSE_FLOCK_LOCKS;
-        }*/
+        }
 
         /*if init_in.flags & FUSE_HAS_IOCTL_DIR > 0 {
             debug!("enable FUSE_HAS_IOCTL_DIR");
@@ -1402,6 +1467,36 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             reply_flags |= FUSE_XTIMES;
         }
 
+        #[allow(unused_mut)]
+        let (mut minor, mut flags2, mut max_stack_depth) = (FUSE_KERNEL_MINOR_VERSION, 0, 0);
+
+        // flags2 follows the fields of fuse_init_in known to this version.
+        #[cfg(target_os = "linux")]
+        if self.mount_options.passthrough
+            && init_in._minor >= FUSE_PASSTHROUGH_MINOR_VERSION
+            && init_in.flags & FUSE_INIT_EXT > 0
+            && reply_flags & FUSE_WRITEBACK_CACHE == 0
+        {
+            let init_flags2 = data
+                .get(FUSE_INIT_IN_SIZE..FUSE_INIT_IN_SIZE + 4)
+                .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()));
+
+            if init_flags2 & FUSE_PASSTHROUGH > 0 {
+                match self.backing_files.enable(fuse_connection.as_fd()) {
+                    Err(err) => error!("enable FUSE_PASSTHROUGH failed {}", err),
+                    Ok(()) => {
+                        debug!("enable FUSE_PASSTHROUGH");
+
+                        reply_flags |= FUSE_INIT_EXT;
+                        flags2 |= FUSE_PASSTHROUGH;
+                        minor = FUSE_PASSTHROUGH_MINOR_VERSION;
+                        // backing files may not be on a stacked filesystem themselves
+                        max_stack_depth = 1;
+                    }
+                }
+            }
+        }
+
         let init_reply = match fs.init(request).await {
             Ok(reply) => reply,
             Err(err) => {
@@ -1439,18 +1534,37 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             init_reply.max_write
         };
 
+        // Let the kernel queue as many background requests as the workers may have in flight.
+        let (max_background, congestion_threshold) =
+            match (self.mount_options.max_background, self.worker_count > 1) {
+                (Some(max_background), _) => (max_background, max_background - max_background / 4),
+                (None, true) => {
+                    let max_background = self.max_background.min(u16::MAX as usize) as u16;
+                    (max_background, max_background - max_background / 4)
+                }
+                (None, false) => (DEFAULT_MAX_BACKGROUND, DEFAULT_CONGESTION_THRESHOLD),
+            };
+        let congestion_threshold = self
+            .mount_options
+            .congestion_threshold
+            .map_or(congestion_threshold, |threshold| {
+                threshold.min(max_background)
+            });
+
         let init_out = fuse_init_out {
             major: FUSE_KERNEL_VERSION,
-            minor: FUSE_KERNEL_MINOR_VERSION,
+            minor,
             max_readahead,
             flags: reply_flags,
-            max_background: DEFAULT_MAX_BACKGROUND,
-            congestion_threshold: DEFAULT_CONGESTION_THRESHOLD,
+            max_background,
+            congestion_threshold,
             max_write: max_write.get(),
             time_gran: DEFAULT_TIME_GRAN,
             max_pages: DEFAULT_MAX_PAGES,
             map_alignment: DEFAULT_MAP_ALIGNMENT,
-            unused: [0; 8],
+            flags2,
+            max_stack_depth,
+            unused: [0; 6],
         };
 
         debug!("fuse init out {:?}", init_out);
@@ -1676,6 +1790,89 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         });
     }
 
+    #[instrument(skip(self, data, fs))]
+    async fn handle_statx(
+        &mut self,
+        request: Request,
+        in_header: fuse_in_header,
+        data: &[u8],
+        fs: &Arc<FS>,
+    ) {
+        let statx_in = match get_bincode_config().deserialize::<fuse_statx_in>(data) {
+            Err(err) => {
+                error!(
+                    "deserialize fuse_statx_in failed {}, request unique {}",
+                    err, request.unique
+                );
+
+                reply_error_in_place(libc::EINVAL.into(), request, &self.response_sender).await;
+
+                return;
+            }
+
+            Ok(statx_in) => statx_in,
+        };
+
+        let mut resp_sender = self.response_sender.clone();
+        let fs = fs.clone();
+
+        spawn(debug_span!("fuse_statx"), async move {
+            debug!("statx unique {} inode {}", request.unique, in_header.nodeid);
+
+            let fh = if statx_in.getattr_flags & FUSE_GETATTR_FH > 0 {
+                Some(statx_in.fh)
+            } else {
+                None
+            };
+
+            let data = match fs
+                .statx(
+                    request,
+                    in_header.nodeid,
+                    fh,
+                    statx_in.sx_flags,
+                    statx_in.sx_mask,
+                )
+                .await
+            {
+                Err(err) => {
+                    let out_header = fuse_out_header {
+                        len: FUSE_OUT_HEADER_SIZE as u32,
+                        error: err.into(),
+                        unique: request.unique,
+                    };
+
+                    get_bincode_config()
+                        .serialize(&out_header)
+                        .expect("won't happened")
+                }
+
+                Ok(statx) => {
+                    let statx_out: fuse_statx_out = statx.into();
+
+                    let out_header = fuse_out_header {
+                        len: (FUSE_OUT_HEADER_SIZE + FUSE_STATX_OUT_SIZE) as u32,
+                        error: 0,
+                        unique: request.unique,
+                    };
+
+                    let mut data = Vec::with_capacity(FUSE_OUT_HEADER_SIZE + FUSE_STATX_OUT_SIZE);
+
+                    get_bincode_config()
+                        .serialize_into(&mut data, &out_header)
+                        .expect("won't happened");
+                    get_bincode_config()
+                        .serialize_into(&mut data, &statx_out)
+                        .expect("won't happened");
+
+                    data
+                }
+            };
+
+            let _ = resp_sender.send(Either::Left(data)).await;
+        });
+    }
+
     #[instrument(skip(self, data, fs))]
     async fn handle_setattr(
         &mut self,
@@ -2433,6 +2630,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         };
 
         let mut resp_sender = self.response_sender.clone();
+        let splice = self.splice.clone();
         let fs = fs.clone();
 
         spawn(debug_span!("fuse_read"), async move {
@@ -2441,6 +2639,33 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                 request.unique, in_header.nodeid, read_in
             );
 
+            if splice.is_enabled() {
+                match fs
+                    .read_fd(
+                        request,
+                        in_header.nodeid,
+                        read_in.fh,
+                        read_in.offset,
+                        read_in.size,
+                    )
+                    .await
+                {
+                    Err(err) => {
+                        reply_error_in_place(err, request, resp_sender).await;
+
+                        return;
+                    }
+
+                    Ok(Some(reply)) => {
+                        splice.reply_read(request.unique, reply, read_in.size, &resp_sender);
+
+                        return;
+                    }
+
+                    Ok(None) => {}
+                }
+            }
+
             let mut reply_data = match fs
                 .read(
                     request,
@@ -3571,8 +3796,19 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                 request.unique, in_header.nodeid, block, setlk_in
             );
 
-            let resp = if let Err(err) = fs
-                .setlk(
+            // flock(2) locks come as whole file locks flagged with FUSE_LK_FLOCK.
+            let res = if setlk_in.lk_flags & FUSE_LK_FLOCK > 0 {
+                fs.flock(
+                    request,
+                    in_header.nodeid,
+                    setlk_in.fh,
+                    setlk_in.owner,
+                    setlk_in.lk.r#type,
+                    block,
+                )
+                .await
+            } else {
+                fs.setlk(
                     request,
                     in_header.nodeid,
                     setlk_in.fh,
@@ -3584,12 +3820,10 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                     block,
                 )
                 .await
-            {
-                err.into()
-            } else {
-                0
             };
 
+            let resp = if let Err(err) = res { err.into() } else { 0 };
+
             let out_header = fuse_out_header {
                 len: FUSE_OUT_HEADER_SIZE as u32,
                 error: resp,
@@ -0,0 +1,333 @@
+//! Read replies moved from files to the fuse device with `splice(2)`.
+//!
+//! A worker splices the data of a [`ReplyReadFd`] behind the reply header into a pipe, so the
+//! file is read concurrently like any other read, and the reply task splices the pipe into the
+//! fuse device. The data never goes through a userspace buffer unless splicing fails, in which
+//! case it is read and replied with a copy as usual.
+//!
+//! Only reads are spliced. The data of write requests is read from the fuse device into the
+//! request buffer with the rest of the request and handed to [`Filesystem::write`] as bytes,
+//! splicing it into the file would need the request reader itself to move to pipes.
+//!
+//! [`Filesystem::write`]: crate::raw::Filesystem::write
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bincode::Options;
+use bytes::Bytes;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::future::Either;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use tracing::debug;
+
+use super::utils::reply_error_in_worker;
+use crate::helper::get_bincode_config;
+use crate::raw::abi::{fuse_out_header, FUSE_OUT_HEADER_SIZE};
+use crate::raw::reply::ReplyReadFd;
+use crate::raw::FuseData;
+
+// Idle pipes kept for later replies.
+#[cfg(target_os = "linux")]
+const MAX_IDLE_PIPES: usize = 16;
+
+/// Splicing state of a session, shared by the workers and the reply task.
+#[derive(Debug, Clone)]
+pub(crate) struct Splice {
+    enabled: Arc<AtomicBool>,
+    sender: UnboundedSender<SplicedReply>,
+    #[cfg(target_os = "linux")]
+    pipes: Arc<Pipes>,
+}
+
+impl Splice {
+    pub(crate) fn new(sender: UnboundedSender<SplicedReply>) -> Self {
+        Self {
+            enabled: Arc::default(),
+            sender,
+            #[cfg(target_os = "linux")]
+            pipes: Arc::default(),
+        }
+    }
+
+    /// Splice read replies from now on, once the kernel agreed at init.
+    pub(crate) fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Reply to the read `unique` of `size` bytes with the data of `reply`, up to the end of
+    /// the file.
+    pub(crate) fn reply_read(
+        &self,
+        unique: u64,
+        reply: ReplyReadFd,
+        size: u32,
+        resp: &UnboundedSender<FuseData>,
+    ) {
+        let file = File::from(reply.fd);
+        let len = match file.metadata() {
+            Ok(metadata) => metadata.len().saturating_sub(reply.offset).min(size as u64) as usize,
+            Err(err) => {
+                let data = reply_error_in_worker(err.into(), unique).expect("serialize out_header");
+                let _ = resp.unbounded_send(Either::Left(data));
+                return;
+            }
+        };
+
+        #[cfg(target_os = "linux")]
+        if len > 0 {
+            if let Some(pipe) = self.pipes.take(reply.offset, len) {
+                match pipe.fill(unique, &file, reply.offset, len) {
+                    Ok(()) => {
+                        let _ = self.sender.unbounded_send(SplicedReply {
+                            unique,
+                            len: FUSE_OUT_HEADER_SIZE + len,
+                            pipe,
+                            pipes: self.pipes.clone(),
+                        });
+                        return;
+                    }
+                    Err(err) => debug!(unique, "splice read failed, copying it: {}", err),
+                }
+            }
+        }
+
+        let mut data = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match file.read_at(&mut data[read..], reply.offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    let data =
+                        reply_error_in_worker(err.into(), unique).expect("serialize out_header");
+                    let _ = resp.unbounded_send(Either::Left(data));
+                    return;
+                }
+            }
+        }
+        data.truncate(read);
+
+        let _ = resp.unbounded_send(Either::Right((out_header(unique, read), Bytes::from(data))));
+    }
+}
+
+fn out_header(unique: u64, len: usize) -> Vec<u8> {
+    let out_header = fuse_out_header {
+        len: (FUSE_OUT_HEADER_SIZE + len) as u32,
+        error: 0,
+        unique,
+    };
+    let mut data = Vec::with_capacity(FUSE_OUT_HEADER_SIZE);
+    get_bincode_config()
+        .serialize_into(&mut data, &out_header)
+        .expect("serialize out_header");
+
+    data
+}
+
+/// A read reply waiting in a pipe to be spliced into the fuse device.
+#[derive(Debug)]
+pub(crate) struct SplicedReply {
+    pub(crate) unique: u64,
+    #[cfg(target_os = "linux")]
+    len: usize,
+    #[cfg(target_os = "linux")]
+    pipe: Pipe,
+    #[cfg(target_os = "linux")]
+    pipes: Arc<Pipes>,
+}
+
+impl SplicedReply {
+    /// Move the reply into the fuse device `dev`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn write_to(self, dev: BorrowedFd<'_>) -> io::Result<()> {
+        // The device takes a whole reply in one splice.
+        let res = unsafe {
+            libc::splice(
+                self.pipe.read.as_raw_fd(),
+                std::ptr::null_mut(),
+                dev.as_raw_fd(),
+                std::ptr::null_mut(),
+                self.len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if res as usize != self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("spliced {} of {} bytes", res, self.len),
+            ));
+        }
+        self.pipes.put(self.pipe);
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn write_to(self, _dev: std::os::fd::BorrowedFd<'_>) -> io::Result<()> {
+        unreachable!("read replies are only spliced on linux")
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    // Pages the pipe holds.
+    pages: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // Non blocking, so a reply not fitting the pipe fails instead of hanging the worker.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because pipe2 just opened them.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let size = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read,
+            write,
+            pages: size as usize / page_size(),
+        })
+    }
+
+    fn grow(&mut self, pages: usize) -> io::Result<()> {
+        let size = unsafe {
+            libc::fcntl(
+                self.write.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                (pages * page_size()) as libc::c_int,
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.pages = size as usize / page_size();
+
+        Ok(())
+    }
+
+    // Put the reply header and `len` bytes of `file` at `offset` into the empty pipe.
+    fn fill(&self, unique: u64, file: &File, offset: u64, len: usize) -> io::Result<()> {
+        let header = out_header(unique, len);
+        let res = unsafe {
+            libc::write(
+                self.write.as_raw_fd(),
+                header.as_ptr() as *const libc::c_void,
+                header.len(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut off = offset as libc::loff_t;
+        let mut moved = 0;
+        while moved < len {
+            let res = unsafe {
+                libc::splice(
+                    file.as_raw_fd(),
+                    &mut off,
+                    self.write.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    len - moved,
+                    libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if res == 0 {
+                // The file shrank since we looked at its size.
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            moved += res as usize;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+struct Pipes {
+    idle: Mutex<Vec<Pipe>>,
+    // Pipe size refused by the kernel, usually over `/proc/sys/fs/pipe-max-size`.
+    refused_pages: Mutex<Option<usize>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Pipes {
+    // An empty pipe with room for a reply of `len` bytes read at `offset`.
+    fn take(&self, offset: u64, len: usize) -> Option<Pipe> {
+        let page_size = page_size();
+        // The header takes a page of its own, and the data the pages it spans in the file.
+        let pages = 1 + (offset as usize % page_size + len).div_ceil(page_size);
+        if self
+            .refused_pages
+            .lock()
+            .unwrap()
+            .is_some_and(|refused| pages >= refused)
+        {
+            return None;
+        }
+
+        let pipe = self.idle.lock().unwrap().pop();
+        let mut pipe = match pipe {
+            Some(pipe) => pipe,
+            None => match Pipe::new() {
+                Ok(pipe) => pipe,
+                Err(err) => {
+                    debug!("create splice pipe failed: {}", err);
+                    return None;
+                }
+            },
+        };
+        if pipe.pages < pages {
+            if let Err(err) = pipe.grow(pages) {
+                debug!("grow splice pipe to {} pages failed: {}", pages, err);
+                let mut refused = self.refused_pages.lock().unwrap();
+                *refused = Some(refused.map_or(pages, |refused| refused.min(pages)));
+                self.put(pipe);
+                return None;
+            }
+        }
+
+        Some(pipe)
+    }
+
+    // Keep an empty pipe for later. Pipes that failed keep their data and are dropped instead.
+    fn put(&self, pipe: Pipe) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_PIPES {
+            idle.push(pipe);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
@@ -21,6 +21,7 @@ use crate::raw::filesystem::Filesystem;
 use crate::raw::FuseData;
 
 use super::handlers::*;
+use super::splice::Splice;
 use super::utils::InHeaderLite;
 
 #[derive(Debug)]
@@ -63,6 +64,7 @@ impl Drop for InflightGuard {
 pub(crate) struct DispatchCtx<FS: Filesystem + Send + Sync + 'static> {
     pub(crate) fs: Arc<FS>,
     pub(crate) resp: UnboundedSender<FuseData>,
+    pub(crate) splice: Splice,
     pub(crate) direct_io: bool,
     pub(crate) _inflight: Arc<AtomicUsize>,
     pub(crate) _inflight_notify: Arc<async_notify::Notify>,
@@ -139,6 +141,7 @@ async fn process_work_item<FS: Filesystem + Send + Sync + 'static>(
             item => item,
             FUSE_LOOKUP   => worker_lookup,
             FUSE_GETATTR  => worker_getattr,
+            FUSE_STATX    => worker_statx,
             FUSE_OPEN     => worker_open,
             FUSE_READ     => worker_read,
             FUSE_WRITE    => worker_write,
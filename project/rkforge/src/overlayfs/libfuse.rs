@@ -22,14 +22,21 @@ pub(super) fn do_mount(
     block_on(async {
         let mut mount_handle = libfuse_fs::overlayfs::mount_fs(OverlayArgs {
             lowerdir: &lowerdir,
-            upperdir: &cfg.upper_dir,
+            upperdir: Some(&cfg.upper_dir),
             mountpoint: &cfg.mountpoint,
             privileged: CONFIG.is_root,
             mapping: None::<&str>,
             name: None::<String>,
             allow_other: true,
+            force: false,
+            layer_limit: Default::default(),
+            passthrough: false,
+            concurrency: Default::default(),
+            tuning: Default::default(),
+            sandbox: false,
         })
-        .await;
+        .await
+        .context("Failed to mount overlay")?;
 
         // send ready message to parent process
         tx.send("ready".to_string())
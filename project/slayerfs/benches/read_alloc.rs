@@ -0,0 +1,151 @@
+//! Heap allocations per small random read.
+//!
+//! Reads 4 KiB at random offsets of a cached file, once through `VFS::read`, which hands out
+//! a fresh `Vec` per call, and once through `VFS::read_bytes`, which serves the reply from
+//! the read buffer pool. A counting global allocator reports allocations and bytes per read.
+//!
+//! Run with `cargo bench -p slayerfs --bench read_alloc`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+use slayerfs::{ChunkLayout, InMemoryBlockStore, VFS, create_meta_store_from_url};
+
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+const READ_SIZE: usize = 4 * 1024;
+const READS: usize = 20_000;
+
+struct Counting;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Offsets of a fixed pseudo-random sequence, the same for both runs.
+fn offsets() -> impl Iterator<Item = u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..READS).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % (FILE_SIZE - READ_SIZE) as u64) & !(READ_SIZE as u64 - 1)
+    })
+}
+
+#[derive(Clone, Copy)]
+struct Counts {
+    allocs: f64,
+    bytes: f64,
+}
+
+async fn measure<F, Fut>(mut read: F) -> Result<Counts>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<usize>>,
+{
+    // Warm up caches and the buffer pool.
+    for offset in offsets().take(1000) {
+        read(offset).await?;
+    }
+    let (allocs, bytes) = (
+        ALLOCS.load(Ordering::Relaxed),
+        BYTES.load(Ordering::Relaxed),
+    );
+    for offset in offsets() {
+        if read(offset).await? != READ_SIZE {
+            return Err(anyhow!("short read at {offset}"));
+        }
+    }
+    Ok(Counts {
+        allocs: (ALLOCS.load(Ordering::Relaxed) - allocs) as f64 / READS as f64,
+        bytes: (BYTES.load(Ordering::Relaxed) - bytes) as f64 / READS as f64,
+    })
+}
+
+async fn run() -> Result<()> {
+    let meta = create_meta_store_from_url("sqlite::memory:").await?;
+    let fs = VFS::new(
+        ChunkLayout::default(),
+        InMemoryBlockStore::new(),
+        meta.store(),
+    )
+    .await
+    .map_err(|e| anyhow!(e))?;
+    let ino = fs.create_file("/data").await.map_err(|e| anyhow!(e))?;
+    let attr = fs.stat("/data").await.map_err(|e| anyhow!(e))?;
+    let fh = fs
+        .open(ino, attr, true, true)
+        .await
+        .map_err(|e| anyhow!(e))?;
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| i as u8).collect();
+    fs.write(fh, 0, &data).await.map_err(|e| anyhow!(e))?;
+    fs.flush(fh).await.map_err(|e| anyhow!(e))?;
+
+    let vec = measure(|offset| {
+        let fs = &fs;
+        async move {
+            let out = fs
+                .read(fh, offset, READ_SIZE)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            Ok(out.len())
+        }
+    })
+    .await?;
+    let pooled = measure(|offset| {
+        let fs = &fs;
+        async move {
+            let out = fs
+                .read_bytes(fh, offset, READ_SIZE)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            Ok(out.len())
+        }
+    })
+    .await?;
+    fs.close(fh).await.map_err(|e| anyhow!(e))?;
+
+    println!("{READS} reads of {READ_SIZE} bytes:");
+    println!("{:<12} {:>12} {:>14}", "", "allocs/read", "bytes/read");
+    for (name, counts) in [("read", vec), ("read_bytes", pooled)] {
+        println!("{name:<12} {:>12.2} {:>14.0}", counts.allocs, counts.bytes);
+    }
+    if pooled.bytes >= vec.bytes {
+        return Err(anyhow!("pooled reads did not allocate less"));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(run())
+}
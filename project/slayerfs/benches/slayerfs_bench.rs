@@ -90,6 +90,9 @@ struct BenchArgs {
     meta_url: String,
     #[arg(long, env = "SLAYERFS_BENCH_META_ETCD_URLS")]
     meta_etcd_urls: Option<String>,
+    /// Comma separated directory shard counts compared by the hot directory bench (etcd only).
+    #[arg(long, env = "SLAYERFS_BENCH_DIR_SHARDS", default_value = "0,16")]
+    dir_shards: String,
     #[arg(long, env = "SLAYERFS_BENCH_DATA_DIR")]
     data_dir: Option<PathBuf>,
     #[arg(long, env = "SLAYERFS_BENCH_BACKEND", value_enum, default_value_t = BackendKind::Local)]
@@ -131,6 +134,7 @@ struct BenchConfig {
     layout: ChunkLayout,
     backend: BackendMode,
     meta_backend: MetaBackend,
+    dir_shards: Vec<u32>,
     data_dir: Option<PathBuf>,
     flamegraph: bool,
 }
@@ -152,7 +156,7 @@ struct S3BackendOpts {
 #[derive(Clone)]
 enum MetaBackend {
     Sqlx { url: String },
-    Etcd { urls: Vec<String> },
+    Etcd { urls: Vec<String>, dir_shards: u32 },
 }
 
 impl BenchConfig {
@@ -208,9 +212,20 @@ impl BenchConfig {
                 if urls.is_empty() {
                     panic!("SLAYERFS_BENCH_META_ETCD_URLS must be set when meta backend is etcd");
                 }
-                MetaBackend::Etcd { urls }
+                MetaBackend::Etcd {
+                    urls,
+                    dir_shards: 0,
+                }
             }
         };
+        let dir_shards = match meta_backend {
+            MetaBackend::Etcd { .. } => args
+                .dir_shards
+                .split(',')
+                .filter_map(|item| item.trim().parse().ok())
+                .collect(),
+            MetaBackend::Sqlx { .. } => vec![0],
+        };
 
         Self {
             block_size_bytes,
@@ -222,6 +237,7 @@ impl BenchConfig {
             layout,
             backend,
             meta_backend,
+            dir_shards,
             data_dir: args.data_dir,
             flamegraph: parse_env_bool(args.flamegraph.as_deref()),
         }
@@ -234,6 +250,15 @@ impl BenchConfig {
     fn small_total_files(&self) -> u64 {
         (self.small_file_count * self.threads) as u64
     }
+
+    /// Same config with etcd directories split into `shards` from their first entry.
+    fn with_dir_shards(&self, shards: u32) -> Self {
+        let mut cfg = self.clone();
+        if let MetaBackend::Etcd { dir_shards, .. } = &mut cfg.meta_backend {
+            *dir_shards = shards;
+        }
+        cfg
+    }
 }
 
 struct BenchEnv {
@@ -405,14 +430,19 @@ async fn create_meta_store(cfg: &BenchConfig) -> Result<Arc<dyn MetaStore>> {
                 .context("create sqlx meta store")?;
             Ok(handle.store() as Arc<dyn MetaStore>)
         }
-        MetaBackend::Etcd { urls } => {
+        MetaBackend::Etcd { urls, dir_shards } => {
             let client = ClientOptions {
                 no_background_jobs: true,
                 ..ClientOptions::default()
             };
             let config = Config {
                 database: DatabaseConfig {
-                    db_config: DatabaseType::Etcd { urls: urls.clone() },
+                    db_config: DatabaseType::Etcd {
+                        urls: urls.clone(),
+                        dir_shards: *dir_shards,
+                        dir_shard_threshold: 0,
+                        tls: None,
+                    },
                 },
                 cache: CacheConfig::default(),
                 client,
@@ -430,6 +460,7 @@ fn database_type_from_url(url: &str) -> DatabaseType {
     if lower.starts_with("postgres://") || lower.starts_with("postgresql://") {
         DatabaseType::Postgres {
             url: url.to_string(),
+            tls: None,
         }
     } else {
         DatabaseType::Sqlite {
@@ -529,6 +560,21 @@ async fn run_small_stat(cfg: &BenchConfig, iter: usize) -> Result<Duration> {
     Ok(cost)
 }
 
+async fn run_hot_dir_create(cfg: &BenchConfig, iter: usize) -> Result<Duration> {
+    let env = BenchEnv::new(cfg).await?;
+    let fs = env.fs();
+    // etcd outlives the env, keep every run in a fresh directory.
+    let stamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let base = format!("/bench/hot-{stamp}-{iter}");
+    fs.mkdir_p(&base).await.map_err(|e| anyhow!(e))?;
+    let cost = measure_future(create_hot_dir_files(fs, cfg, base)).await?;
+    env.teardown()?;
+    Ok(cost)
+}
+
 async fn write_big_files(fs: SharedFs, cfg: &BenchConfig, base: String) -> Result<()> {
     if cfg.big_file_bytes == 0 {
         return Ok(());
@@ -715,6 +761,28 @@ async fn stat_small_files(fs: SharedFs, cfg: &BenchConfig, base: String) -> Resu
     Ok(())
 }
 
+/// Every thread creates empty files in the same directory.
+async fn create_hot_dir_files(fs: SharedFs, cfg: &BenchConfig, base: String) -> Result<()> {
+    let mut handles = Vec::with_capacity(cfg.threads);
+    for tid in 0..cfg.threads {
+        let fs = fs.clone();
+        let base = base.clone();
+        let file_cnt = cfg.small_file_count;
+        handles.push(tokio::spawn(async move {
+            for idx in 0..file_cnt {
+                fs.create_file(&format!("{base}/file-{tid}-{idx}"))
+                    .await
+                    .map_err(|e| anyhow!(e))?;
+            }
+            Result::<()>::Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.await??;
+    }
+    Ok(())
+}
+
 fn make_block_payload(size: usize, salt: usize) -> Vec<u8> {
     let mut buf = vec![0u8; size];
     for (idx, byte) in buf.iter_mut().enumerate() {
@@ -835,6 +903,33 @@ fn bench_small_stats(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_hot_dir(c: &mut Criterion) {
+    let cfg = BenchConfig::from_env();
+    let runtime = tokio_runtime(cfg.threads);
+    let mut group = c.benchmark_group("slayerfs_hot_dir");
+    group.sample_size(cfg.sample_size);
+    group.throughput(Throughput::Elements(cfg.small_total_files()));
+
+    for shards in cfg.dir_shards.clone() {
+        let cfg = cfg.with_dir_shards(shards);
+        let id = BenchmarkId::new("create", format!("{}t-{shards}shards", cfg.threads));
+        group.bench_function(id, |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for i in 0..iters {
+                    let elapsed = runtime
+                        .block_on(run_hot_dir_create(&cfg, i as usize))
+                        .expect("hot dir create bench");
+                    total += elapsed;
+                }
+                total
+            })
+        });
+    }
+
+    group.finish();
+}
+
 fn build_criterion() -> Criterion {
     let cfg = BenchConfig::from_env();
     let mut crit = Criterion::default().configure_from_args();
@@ -848,6 +943,6 @@ fn build_criterion() -> Criterion {
 criterion_group! {
     name = slayerfs_benches;
     config = build_criterion();
-    targets = bench_big_files, bench_small_files, bench_small_stats
+    targets = bench_big_files, bench_small_files, bench_small_stats, bench_hot_dir
 }
 criterion_main!(slayerfs_benches);
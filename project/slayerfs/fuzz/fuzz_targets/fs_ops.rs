@@ -12,9 +12,9 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use etcd_client::{Client as RawEtcdClient, DeleteOptions};
 use libfuzzer_sys::fuzz_target;
 use slayerfs::{
-    CacheConfig, ChunkLayout, ClientOptions, Config, DatabaseConfig, DatabaseMetaStore,
-    DatabaseType, EtcdMetaStore, LocalFsBackend, MetaClient, MetaStore, ObjectBlockStore,
-    ObjectClient, SetAttrFlags, SetAttrRequest, VFS, VfsFileAttr, VfsFileType,
+    CacheConfig, ChunkLayout, ClientOptions, Config, DEFAULT_DIR_SHARD_THRESHOLD, DatabaseConfig,
+    DatabaseMetaStore, DatabaseType, EtcdMetaStore, LocalFsBackend, MetaClient, MetaStore,
+    ObjectBlockStore, ObjectClient, SetAttrFlags, SetAttrRequest, VFS, VfsFileAttr, VfsFileType,
 };
 use tokio::runtime::Builder;
 use tokio::task::JoinSet;
@@ -1243,7 +1243,12 @@ async fn new_local_vfs(root: &Path, meta: &FuzzMetaConfig) -> io::Result<SlayerV
         FuzzMetaConfig::Etcd { urls } => {
             let cfg = Config {
                 database: DatabaseConfig {
-                    db_config: DatabaseType::Etcd { urls: urls.clone() },
+                    db_config: DatabaseType::Etcd {
+                        urls: urls.clone(),
+                        dir_shards: 0,
+                        dir_shard_threshold: DEFAULT_DIR_SHARD_THRESHOLD,
+                        tls: None,
+                    },
                 },
                 cache: CacheConfig::default(),
                 client,
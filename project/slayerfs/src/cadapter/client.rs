@@ -3,6 +3,16 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
+use std::time::SystemTime;
+
+/// A multipart upload that was started but neither completed nor aborted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingUpload {
+    pub key: String,
+    pub upload_id: String,
+    /// Start time reported by the backend, if any.
+    pub initiated: Option<SystemTime>,
+}
 
 #[async_trait]
 pub trait ObjectBackend: Send + Sync {
@@ -16,6 +26,13 @@ pub trait ObjectBackend: Send + Sync {
 
     async fn put_object(&self, key: &str, data: &[u8]) -> Result<()>;
 
+    /// Like `put_object_vectored`, and return the checksum the backend confirmed it stored,
+    /// None for backends that report nothing to check the upload against.
+    async fn put_object_verified(&self, key: &str, chunks: Vec<Bytes>) -> Result<Option<String>> {
+        self.put_object_vectored(key, chunks).await?;
+        Ok(None)
+    }
+
     async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>>;
 
     /// Get a range of bytes from an object.
@@ -27,6 +44,18 @@ pub trait ObjectBackend: Send + Sync {
 
     #[allow(dead_code)]
     async fn delete_object(&self, key: &str) -> Result<()>;
+
+    /// Multipart uploads left open in the backend, e.g. by writers that crashed mid-upload.
+    /// Backends without multipart uploads have none.
+    async fn list_pending_uploads(&self) -> Result<Vec<PendingUpload>> {
+        Ok(Vec::new())
+    }
+
+    /// Abort a pending multipart upload and free the parts stored for it.
+    async fn abort_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        let _ = (key, upload_id);
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -39,14 +68,24 @@ impl<B: ObjectBackend> ObjectClient<B> {
         Self { backend }
     }
 
+    #[allow(dead_code)]
     pub async fn put_object(&self, key: &str, data: &[u8]) -> Result<()> {
         self.backend.put_object(key, data).await
     }
 
+    #[allow(dead_code)]
     pub async fn put_object_vectored(&self, key: &str, chunks: Vec<Bytes>) -> Result<()> {
         self.backend.put_object_vectored(key, chunks).await
     }
 
+    pub async fn put_object_verified(
+        &self,
+        key: &str,
+        chunks: Vec<Bytes>,
+    ) -> Result<Option<String>> {
+        self.backend.put_object_verified(key, chunks).await
+    }
+
     pub async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
         self.backend.get_object(key).await
     }
@@ -66,4 +105,12 @@ impl<B: ObjectBackend> ObjectClient<B> {
     pub async fn delete_object(&self, key: &str) -> Result<()> {
         self.backend.delete_object(key).await
     }
+
+    pub async fn list_pending_uploads(&self) -> Result<Vec<PendingUpload>> {
+        self.backend.list_pending_uploads().await
+    }
+
+    pub async fn abort_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.backend.abort_upload(key, upload_id).await
+    }
 }
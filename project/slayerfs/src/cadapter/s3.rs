@@ -1,18 +1,24 @@
 //! S3 adapter: simplified aws-sdk-s3 implementation with multipart upload, retries, and validation.
 
-use crate::cadapter::client::ObjectBackend;
-use anyhow::{Context, Result, anyhow};
+use crate::cadapter::client::{ObjectBackend, PendingUpload};
+use crate::utils::tls::TlsConfig;
+use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::SharedHttpClient;
 use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::primitives::{ByteStream, SdkBody};
+use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::{Client, config::Region};
+use aws_smithy_http_client::tls::rustls_provider::CryptoMode;
+use aws_smithy_http_client::tls::{Provider, ServerName, TlsContext, TrustStore};
 use base64::Engine as _;
 use base64::engine::general_purpose::STANDARD as B64;
 use bytes::Bytes;
 use hyper::Body;
 use md5;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tokio::time::{Duration, sleep};
 
 /// S3 backend configuration options
@@ -32,10 +38,17 @@ pub struct S3Config {
     pub retry_base_delay: u64,
     /// Enable MD5 checksums for uploads (default: true)
     pub enable_md5: bool,
+    /// Check the ETag returned for every object and part uploaded against its MD5, and
+    /// upload the parts that don't match again (default: true). ETags that aren't plain
+    /// MD5s, e.g. with SSE-KMS, can't be checked.
+    pub verify_etag: bool,
     /// Custom endpoint URL (e.g. for MinIO or localstack)
     pub endpoint: Option<String>,
     /// Force path-style access (required for some S3-compatible services)
     pub force_path_style: bool,
+    /// TLS settings for an `https` endpoint with a private CA. Client certificates are not
+    /// supported by the SDK's HTTP client.
+    pub tls: Option<TlsConfig>,
 }
 
 impl Default for S3Config {
@@ -48,8 +61,10 @@ impl Default for S3Config {
             max_retries: 3,
             retry_base_delay: 100,
             enable_md5: true,
+            verify_etag: true,
             endpoint: None,
             force_path_style: false,
+            tls: None,
         }
     }
 }
@@ -59,6 +74,8 @@ impl Default for S3Config {
 pub struct S3Backend {
     client: Client,
     config: S3Config,
+    /// Multipart uploads this backend is still running, never reported as pending.
+    live_uploads: Arc<Mutex<HashSet<String>>>,
 }
 
 #[allow(dead_code)]
@@ -84,6 +101,11 @@ impl S3Backend {
             aws_config_loader = aws_config_loader.region(Region::new(region.clone()));
         }
 
+        // Set on the loader so credential providers talk through the same client.
+        if let Some(tls) = &config.tls {
+            aws_config_loader = aws_config_loader.http_client(Self::tls_http_client(tls)?);
+        }
+
         let aws_config = aws_config_loader.load().await;
 
         let mut s3_config_builder = aws_sdk_s3::config::Builder::from(&aws_config);
@@ -98,20 +120,49 @@ impl S3Backend {
 
         let client = Client::from_conf(s3_config_builder.build());
 
-        Ok(Self { client, config })
+        Ok(Self {
+            client,
+            config,
+            live_uploads: Arc::default(),
+        })
     }
 
-    fn md5_base64(data: &[u8]) -> String {
-        let sum = md5::compute(data);
-        B64.encode(sum.0)
+    fn tls_http_client(tls: &TlsConfig) -> Result<SharedHttpClient> {
+        if tls.cert_file.is_some() || tls.key_file.is_some() {
+            return Err(TlsConfig::unsupported("S3", "client certificate").into());
+        }
+        let mut trust_store = TrustStore::default();
+        if let Some(ca) = tls.ca_pem()? {
+            trust_store = trust_store.with_pem_certificate(ca);
+        }
+        let mut context = TlsContext::builder().with_trust_store(trust_store);
+        if let Some(name) = &tls.server_name {
+            context =
+                context.with_additional_server_names(vec![ServerName::try_from(name.as_str())?]);
+        }
+        let context = context
+            .build()
+            .map_err(|e| anyhow!("invalid S3 TLS config: {e}"))?;
+        Ok(aws_smithy_http_client::Builder::new()
+            .tls_provider(Provider::Rustls(CryptoMode::AwsLc))
+            .tls_context(context)
+            .build_https())
     }
 
-    fn md5_base64_chunks(chunks: &[Bytes]) -> String {
+    fn md5_chunks(chunks: &[Bytes]) -> [u8; 16] {
         let mut ctx = md5::Context::new();
         for chunk in chunks {
             ctx.consume(chunk);
         }
-        B64.encode(ctx.compute().0)
+        ctx.compute().0
+    }
+
+    /// Whether `etag` is the MD5 `digest`, None if it isn't a plain MD5 and can't be checked,
+    /// as for multipart objects or with SSE-KMS.
+    fn etag_matches(etag: Option<&str>, digest: &[u8; 16]) -> Option<bool> {
+        let etag = etag?.trim_matches('"');
+        (etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit()))
+            .then(|| etag.eq_ignore_ascii_case(&hex::encode(digest)))
     }
 
     fn stream_from_chunks(chunks: &[Bytes]) -> ByteStream {
@@ -120,13 +171,16 @@ impl S3Backend {
         ByteStream::from_body_0_4(Body::wrap_stream(stream))
     }
 
-    async fn put_object_vectored_simple(&self, key: &str, chunks: Vec<Bytes>) -> Result<()> {
+    /// Put small objects directly (simpler than multipart upload). Returns the MD5 of the
+    /// object in hex if its ETag confirmed it.
+    async fn put_object_vectored_simple(
+        &self,
+        key: &str,
+        chunks: Vec<Bytes>,
+    ) -> Result<Option<String>> {
         let total_size = chunks.iter().map(|c| c.len()).sum::<usize>();
-        let checksum = if self.config.enable_md5 && total_size > 0 {
-            Some(Self::md5_base64_chunks(&chunks))
-        } else {
-            None
-        };
+        let digest =
+            (self.config.enable_md5 || self.config.verify_etag).then(|| Self::md5_chunks(&chunks));
 
         let mut attempt = 0;
         loop {
@@ -141,176 +195,45 @@ impl S3Backend {
                 .body(body)
                 .content_length(total_size as i64);
 
-            if let Some(sum) = checksum.as_ref() {
-                request = request.content_md5(sum.clone());
-            }
-
-            match request.send().await {
-                Ok(_) => return Ok(()),
-                Err(_e) if attempt < self.config.max_retries => {
-                    let delay = self.config.retry_base_delay * (1 << (attempt - 1));
-                    sleep(Duration::from_millis(delay)).await;
-                    continue;
-                }
-                Err(e) => return Err(e.into()),
+            if let Some(sum) = digest.filter(|_| self.config.enable_md5 && total_size > 0) {
+                request = request.content_md5(B64.encode(sum));
             }
-        }
-    }
-
-    /// Put small objects directly (simpler than multipart upload)
-    async fn put_object_simple(&self, key: &str, data: &[u8]) -> Result<()> {
-        let mut attempt = 0;
-        loop {
-            attempt += 1;
-
-            let mut request = self
-                .client
-                .put_object()
-                .bucket(&self.config.bucket)
-                .key(key)
-                .body(SdkBody::from(data.to_vec()).into());
-
-            if self.config.enable_md5 {
-                let checksum = Self::md5_base64(data);
-                request = request.content_md5(checksum);
-            }
-
-            match request.send().await {
-                Ok(_) => return Ok(()),
-                Err(_e) if attempt < self.config.max_retries => {
-                    let delay = self.config.retry_base_delay * (1 << (attempt - 1));
-                    sleep(Duration::from_millis(delay)).await;
-                    continue;
-                }
-                Err(e) => return Err(e.into()),
-            }
-        }
-    }
-
-    /// Handle multipart upload for large objects
-    async fn multipart_upload(&self, key: &str, data: &[u8]) -> Result<()> {
-        // Create multipart upload
-        let create = self
-            .client
-            .create_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(key)
-            .send()
-            .await?;
-
-        let upload_id = create
-            .upload_id()
-            .ok_or_else(|| anyhow!("Missing upload_id in create_multipart_upload response"))?
-            .to_string();
-
-        // Ensure we clean up the multipart upload if it fails
-        let cleanup_on_drop = MultipartCleanupGuard {
-            client: self.client.clone(),
-            bucket: self.config.bucket.clone(),
-            key: key.to_string(),
-            upload_id: upload_id.clone(),
-        };
-
-        let data_arc = Arc::new(data.to_vec());
-        let sem = Arc::new(tokio::sync::Semaphore::new(self.config.max_concurrency));
-
-        // Concurrent upload of parts
-        let mut parts = Vec::new();
-        let total = data.len();
-        let mut idx = 0usize;
-        let mut part_number = 1i32;
-
-        while idx < total {
-            let end = (idx + self.config.part_size).min(total);
-            let chunk_vec = data_arc.as_slice()[idx..end].to_vec();
-            let client = self.client.clone();
-            let bucket = self.config.bucket.clone();
-            let key = key.to_string();
-            let upload_id_cloned = upload_id.clone();
-            let pn = part_number;
-            let sem_cloned = sem.clone();
-            let enable_md5 = self.config.enable_md5;
-            let max_retries = self.config.max_retries;
-            let retry_base_delay = self.config.retry_base_delay;
-
-            let fut = async move {
-                // Concurrency control
-                let _permit = sem_cloned
-                    .acquire_owned()
-                    .await
-                    .with_context(|| "Multipart upload semaphore closed unexpectedly");
-                let mut attempt = 0;
-
-                loop {
-                    attempt += 1;
-                    let mut request = client
-                        .upload_part()
-                        .bucket(&bucket)
-                        .key(&key)
-                        .upload_id(&upload_id_cloned)
-                        .part_number(pn)
-                        .body(SdkBody::from(chunk_vec.clone()).into());
-
-                    if enable_md5 {
-                        let part_md5 = Self::md5_base64(&chunk_vec);
-                        request = request.content_md5(part_md5);
-                    }
 
-                    match request.send().await {
-                        Ok(ok) => break Ok((pn, ok.e_tag().map(|s| s.to_string()))),
-                        Err(_e) if attempt < max_retries => {
-                            let delay = retry_base_delay * (1 << (attempt - 1));
-                            sleep(Duration::from_millis(delay)).await;
-                            continue;
+            let err = match request.send().await {
+                Ok(out) => {
+                    let verified = digest
+                        .filter(|_| self.config.verify_etag)
+                        .and_then(|sum| Some((sum, Self::etag_matches(out.e_tag(), &sum)?)));
+                    match verified {
+                        Some((sum, true)) => return Ok(Some(hex::encode(sum))),
+                        Some((_, false)) => {
+                            anyhow!(
+                                "ETag {:?} of {key} doesn't match the data sent",
+                                out.e_tag()
+                            )
                         }
-                        Err(e) => break Err(e),
+                        None => return Ok(None),
                     }
                 }
+                Err(e) => e.into(),
             };
-            parts.push(fut);
-
-            idx = end;
-            part_number += 1;
+            if attempt >= self.config.max_retries {
+                return Err(err);
+            }
+            tracing::debug!("put of {key} failed, retrying: {err:#}");
+            let delay = self.config.retry_base_delay * (1 << (attempt - 1));
+            sleep(Duration::from_millis(delay)).await;
         }
-
-        // Execute all parts concurrently
-        let results: Vec<(i32, Option<String>)> = match futures::future::try_join_all(parts).await {
-            Ok(v) => v,
-            Err(e) => return Err(e.into()),
-        };
-
-        // Build completed parts
-        let completed_parts = results
-            .into_iter()
-            .map(|(pn, etag)| {
-                aws_sdk_s3::types::CompletedPart::builder()
-                    .part_number(pn)
-                    .set_e_tag(etag)
-                    .build()
-            })
-            .collect::<Vec<_>>();
-
-        let completed = aws_sdk_s3::types::CompletedMultipartUpload::builder()
-            .set_parts(Some(completed_parts))
-            .build();
-
-        // Complete multipart upload
-        self.client
-            .complete_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(key)
-            .upload_id(upload_id)
-            .multipart_upload(completed)
-            .send()
-            .await?;
-
-        // Disarm cleanup guard since upload succeeded
-        std::mem::forget(cleanup_on_drop);
-
-        Ok(())
     }
 
-    async fn multipart_upload_vectored(&self, key: &str, chunks: Vec<Bytes>) -> Result<()> {
+    /// Upload large objects in parts. A part whose ETag doesn't match its MD5 is uploaded
+    /// again on its own. Returns the checksum S3 reports for multipart objects, the MD5 of
+    /// the part MD5s followed by the part count, if every part was confirmed.
+    async fn multipart_upload_vectored(
+        &self,
+        key: &str,
+        chunks: Vec<Bytes>,
+    ) -> Result<Option<String>> {
         let create = self
             .client
             .create_multipart_upload()
@@ -324,12 +247,8 @@ impl S3Backend {
             .ok_or_else(|| anyhow!("Missing upload_id in create_multipart_upload response"))?
             .to_string();
 
-        let cleanup_on_drop = MultipartCleanupGuard {
-            client: self.client.clone(),
-            bucket: self.config.bucket.clone(),
-            key: key.to_string(),
-            upload_id: upload_id.clone(),
-        };
+        // Ensure we clean up the multipart upload if it fails
+        let cleanup_on_drop = MultipartCleanupGuard::new(self, key, &upload_id);
 
         let mut parts: Vec<Vec<Bytes>> = Vec::new();
         let mut cur_part: Vec<Bytes> = Vec::new();
@@ -362,11 +281,9 @@ impl S3Backend {
 
         for (idx, part_chunks) in parts.into_iter().enumerate() {
             let part_len = part_chunks.iter().map(|c| c.len()).sum::<usize>();
-            let part_md5 = if self.config.enable_md5 && part_len > 0 {
-                Some(Self::md5_base64_chunks(&part_chunks))
-            } else {
-                None
-            };
+            let enable_md5 = self.config.enable_md5 && part_len > 0;
+            let verify_etag = self.config.verify_etag;
+            let part_md5 = (enable_md5 || verify_etag).then(|| Self::md5_chunks(&part_chunks));
 
             let client = self.client.clone();
             let bucket = self.config.bucket.clone();
@@ -378,6 +295,7 @@ impl S3Backend {
             let retry_base_delay = self.config.retry_base_delay;
 
             let fut = async move {
+                // Concurrency control
                 let _permit = sem_cloned.acquire_owned().await;
                 let mut attempt = 0;
 
@@ -393,33 +311,57 @@ impl S3Backend {
                         .body(body)
                         .content_length(part_len as i64);
 
-                    if let Some(md5) = part_md5.as_ref() {
-                        request = request.content_md5(md5.clone());
+                    if let Some(md5) = part_md5.filter(|_| enable_md5) {
+                        request = request.content_md5(B64.encode(md5));
                     }
 
-                    match request.send().await {
-                        Ok(ok) => break Ok((pn, ok.e_tag().map(|s| s.to_string()))),
-                        Err(_e) if attempt < max_retries => {
-                            let delay = retry_base_delay * (1 << (attempt - 1));
-                            sleep(Duration::from_millis(delay)).await;
-                            continue;
+                    let err = match request.send().await {
+                        Ok(ok) => {
+                            let etag = ok.e_tag().map(|s| s.to_string());
+                            let checked = part_md5
+                                .filter(|_| verify_etag)
+                                .and_then(|md5| Self::etag_matches(etag.as_deref(), &md5));
+                            match checked {
+                                Some(false) => {
+                                    anyhow!("ETag {etag:?} of part {pn} of {key} doesn't match")
+                                }
+                                Some(true) => break Ok((pn, etag, part_md5)),
+                                None => break Ok((pn, etag, None)),
+                            }
                         }
-                        Err(e) => break Err(e),
+                        Err(e) => e.into(),
+                    };
+                    if attempt >= max_retries {
+                        break Err(err);
                     }
+                    tracing::debug!("upload of part {pn} of {key} failed, retrying: {err:#}");
+                    let delay = retry_base_delay * (1 << (attempt - 1));
+                    sleep(Duration::from_millis(delay)).await;
                 }
             };
             futures.push(fut);
         }
 
-        let results: Vec<(i32, Option<String>)> = match futures::future::try_join_all(futures).await
-        {
-            Ok(v) => v,
-            Err(e) => return Err(e.into()),
-        };
+        // Execute all parts concurrently
+        let results: Vec<(i32, Option<String>, Option<[u8; 16]>)> =
+            futures::future::try_join_all(futures).await?;
+
+        let checksum = results
+            .iter()
+            .map(|(_, _, md5)| *md5)
+            .collect::<Option<Vec<_>>>()
+            .map(|md5s| {
+                let mut ctx = md5::Context::new();
+                for md5 in &md5s {
+                    ctx.consume(md5);
+                }
+                format!("{}-{}", hex::encode(ctx.compute().0), md5s.len())
+            });
 
+        // Build completed parts
         let completed_parts = results
             .into_iter()
-            .map(|(pn, etag)| {
+            .map(|(pn, etag, _)| {
                 aws_sdk_s3::types::CompletedPart::builder()
                     .part_number(pn)
                     .set_e_tag(etag)
@@ -431,6 +373,7 @@ impl S3Backend {
             .set_parts(Some(completed_parts))
             .build();
 
+        // Complete multipart upload
         self.client
             .complete_multipart_upload()
             .bucket(&self.config.bucket)
@@ -440,8 +383,9 @@ impl S3Backend {
             .send()
             .await?;
 
-        std::mem::forget(cleanup_on_drop);
-        Ok(())
+        // Disarm cleanup guard since upload succeeded
+        cleanup_on_drop.disarm();
+        Ok(checksum)
     }
 }
 
@@ -451,10 +395,36 @@ struct MultipartCleanupGuard {
     bucket: String,
     key: String,
     upload_id: String,
+    live_uploads: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MultipartCleanupGuard {
+    /// Track a freshly created upload until it completes or is aborted.
+    fn new(backend: &S3Backend, key: &str, upload_id: &str) -> Self {
+        backend
+            .live_uploads
+            .lock()
+            .unwrap()
+            .insert(upload_id.to_string());
+        Self {
+            client: backend.client.clone(),
+            bucket: backend.config.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            live_uploads: backend.live_uploads.clone(),
+        }
+    }
+
+    /// The upload completed, stop tracking it without aborting.
+    fn disarm(self) {
+        self.live_uploads.lock().unwrap().remove(&self.upload_id);
+        std::mem::forget(self);
+    }
 }
 
 impl Drop for MultipartCleanupGuard {
     fn drop(&mut self) {
+        self.live_uploads.lock().unwrap().remove(&self.upload_id);
         let client = self.client.clone();
         let bucket = self.bucket.clone();
         let key = self.key.clone();
@@ -474,13 +444,15 @@ impl Drop for MultipartCleanupGuard {
 
 #[async_trait]
 impl ObjectBackend for S3Backend {
-    #[tracing::instrument(level = "trace", skip(self, chunks), fields(key, chunk_count = chunks.len()))]
     async fn put_object_vectored(&self, key: &str, chunks: Vec<Bytes>) -> Result<()> {
+        self.put_object_verified(key, chunks).await.map(drop)
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, chunks), fields(key, chunk_count = chunks.len()))]
+    async fn put_object_verified(&self, key: &str, chunks: Vec<Bytes>) -> Result<Option<String>> {
         let total_size = chunks.iter().map(|e| e.len()).sum::<usize>();
 
-        if total_size == 0 {
-            return self.put_object_simple(key, &[]).await;
-        }
+        // Small objects use direct put_object; large objects use multipart upload
         if total_size <= self.config.part_size {
             return self.put_object_vectored_simple(key, chunks).await;
         }
@@ -489,13 +461,8 @@ impl ObjectBackend for S3Backend {
     }
 
     async fn put_object(&self, key: &str, data: &[u8]) -> Result<()> {
-        // Small objects use direct put_object; large objects use multipart upload
-        if data.len() <= self.config.part_size {
-            return self.put_object_simple(key, data).await;
-        }
-
-        // Multipart upload for large objects
-        self.multipart_upload(key, data).await
+        self.put_object_vectored(key, vec![Bytes::copy_from_slice(data)])
+            .await
     }
 
     async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
@@ -594,4 +561,52 @@ impl ObjectBackend for S3Backend {
             }
         }
     }
+
+    /// Uploads still running in this process are left out, whatever their age.
+    async fn list_pending_uploads(&self) -> Result<Vec<PendingUpload>> {
+        let mut pending = Vec::new();
+        let (mut key_marker, mut upload_id_marker) = (None, None);
+        loop {
+            let resp = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.config.bucket)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await?;
+            let live = self.live_uploads.lock().unwrap().clone();
+            for upload in resp.uploads() {
+                let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                    continue;
+                };
+                if live.contains(upload_id) {
+                    continue;
+                }
+                pending.push(PendingUpload {
+                    key: key.to_string(),
+                    upload_id: upload_id.to_string(),
+                    initiated: upload
+                        .initiated()
+                        .and_then(|t| SystemTime::try_from(*t).ok()),
+                });
+            }
+            if !resp.is_truncated().unwrap_or(false) {
+                return Ok(pending);
+            }
+            key_marker = resp.next_key_marker().map(str::to_string);
+            upload_id_marker = resp.next_upload_id_marker().map(str::to_string);
+        }
+    }
+
+    async fn abort_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.config.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -17,6 +17,8 @@ use tokio::fs;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, trace, warn};
 
+use super::cache_limit;
+
 /// Configuration for the intelligent dual-layer cache system.
 ///
 /// This cache implements an adaptive promotion strategy that combines:
@@ -164,6 +166,19 @@ pub struct ChunksCacheConfig {
     /// **When below**: Increases promotion threshold by 30% to prevent cache pollution
     /// **Purpose**: Maintain cache efficiency when hit rate is already low
     pub conservative_promotion_hit_rate_threshold: f64,
+
+    /// Maximum bytes kept in disk storage, least recently used files are removed beyond it
+    ///
+    /// **None**: Unbounded, unless a pod storage limit is detected
+    /// **Note**: Lowered to `storage_limit_ratio` of the pod limit when that is smaller
+    pub disk_capacity: Option<u64>,
+
+    /// Share of the detected pod storage limit that disk storage may use
+    ///
+    /// **Range**: 0.0 - 1.0
+    /// **Purpose**: Leave room for the pod's other files so cache growth doesn't get it evicted
+    /// **See**: [`cache_limit::detect_storage_limit`](super::cache_limit::detect_storage_limit)
+    pub storage_limit_ratio: f64,
 }
 
 impl Default for ChunksCacheConfig {
@@ -181,17 +196,126 @@ impl Default for ChunksCacheConfig {
             enable_adaptive_threshold: true,
             aggressive_promotion_load_threshold: 0.8,
             conservative_promotion_hit_rate_threshold: 0.6,
+            disk_capacity: None,
+            storage_limit_ratio: 0.5,
+        }
+    }
+}
+
+/// Disk usage of the cache, relative to its capacity and the pod storage limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskCacheUsage {
+    /// Bytes of all files in disk storage
+    pub used_bytes: u64,
+    /// Bytes disk storage is capped at, if bounded
+    pub capacity_bytes: Option<u64>,
+    /// Storage limit detected for the pod, if any
+    pub storage_limit_bytes: Option<u64>,
+    /// Files removed to stay within capacity since startup
+    pub evictions: u64,
+}
+
+impl DiskCacheUsage {
+    /// Share of the pod storage limit taken by the cache, or of the capacity when no limit
+    /// is known. 0.0 when unbounded.
+    pub fn pressure(&self) -> f64 {
+        match self.storage_limit_bytes.or(self.capacity_bytes) {
+            Some(limit) if limit > 0 => self.used_bytes as f64 / limit as f64,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Capacity of disk storage: the configured one, capped at `ratio` of the pod storage limit.
+fn negotiate_capacity(
+    configured: Option<u64>,
+    storage_limit: Option<u64>,
+    ratio: f64,
+) -> Option<u64> {
+    let allowed = storage_limit.map(|limit| (limit as f64 * ratio.clamp(0.0, 1.0)) as u64);
+    match (configured, allowed) {
+        (Some(configured), Some(allowed)) if allowed < configured => {
+            warn!(
+                "Disk cache capacity lowered from {} to {} bytes to stay within the pod storage limit",
+                configured, allowed
+            );
+            Some(allowed)
+        }
+        (configured, allowed) => configured.or(allowed),
+    }
+}
+
+/// Files in disk storage, by file name, and their order of last use.
+#[derive(Debug, Default)]
+struct DiskIndex {
+    files: HashMap<String, (u64, u64)>, // file name -> (size, last use)
+    lru: BTreeMap<u64, String>,
+    next_use: u64,
+    used: u64,
+}
+
+impl DiskIndex {
+    fn touch(&mut self, filename: &str) {
+        if let Some((_, last_use)) = self.files.get_mut(filename) {
+            self.lru.remove(last_use);
+            *last_use = self.next_use;
+            self.lru.insert(self.next_use, filename.to_string());
+            self.next_use += 1;
+        }
+    }
+
+    fn insert(&mut self, filename: String, size: u64) {
+        self.forget(&filename);
+        self.files.insert(filename.clone(), (size, self.next_use));
+        self.lru.insert(self.next_use, filename);
+        self.next_use += 1;
+        self.used += size;
+    }
+
+    fn forget(&mut self, filename: &str) {
+        if let Some((size, last_use)) = self.files.remove(filename) {
+            self.lru.remove(&last_use);
+            self.used -= size;
         }
     }
+
+    /// Drop least recently used files until `size` more bytes fit in `capacity`.
+    fn evict_for(&mut self, size: u64, capacity: u64) -> Vec<String> {
+        let mut victims = Vec::new();
+        while self.used + size > capacity {
+            let Some((_, filename)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.files.remove(&filename) {
+                self.used -= size;
+            }
+            victims.push(filename);
+        }
+        victims
+    }
 }
 
 #[derive(Debug, Clone)]
 struct DiskStorage {
     base_dir: PathBuf,
+    capacity: Option<u64>,
+    storage_limit: Option<u64>,
+    index: Arc<parking_lot::Mutex<DiskIndex>>,
+    evictions: Arc<AtomicU64>,
 }
 
 impl DiskStorage {
     pub async fn new<P: AsRef<Path>>(base_dir: P) -> anyhow::Result<Self> {
+        Self::new_with_capacity(base_dir, None, None).await
+    }
+
+    /// Disk storage holding at most `capacity` bytes. Files left by an earlier run are
+    /// counted, and the least recently modified ones removed if they don't fit.
+    pub async fn new_with_capacity<P: AsRef<Path>>(
+        base_dir: P,
+        capacity: Option<u64>,
+        storage_limit: Option<u64>,
+    ) -> anyhow::Result<Self> {
         let base_dir = base_dir.as_ref().to_path_buf();
         debug!("Initializing disk storage at: {:?}", base_dir);
 
@@ -202,7 +326,73 @@ impl DiskStorage {
             debug!("Cache directory already exists: {:?}", base_dir);
         }
 
-        Ok(Self { base_dir })
+        let storage = Self {
+            base_dir,
+            capacity,
+            storage_limit,
+            index: Arc::new(parking_lot::Mutex::new(DiskIndex::default())),
+            evictions: Arc::new(AtomicU64::new(0)),
+        };
+        storage.load_index().await?;
+        Ok(storage)
+    }
+
+    async fn load_index(&self) -> anyhow::Result<()> {
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&self.base_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Only files named by `key_to_filename` are ours, the directory may be shared.
+            if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() {
+                files.push((
+                    metadata.modified().unwrap_or(UNIX_EPOCH),
+                    name,
+                    metadata.len(),
+                ));
+            }
+        }
+        files.sort();
+
+        let victims = {
+            let mut index = self.index.lock();
+            for (_, name, size) in files {
+                index.insert(name, size);
+            }
+            debug!(
+                "Disk storage holds {} files, {} bytes",
+                index.files.len(),
+                index.used
+            );
+            match self.capacity {
+                Some(capacity) => index.evict_for(0, capacity),
+                None => Vec::new(),
+            }
+        };
+        self.remove_files(victims).await;
+        Ok(())
+    }
+
+    async fn remove_files(&self, victims: Vec<String>) {
+        for filename in victims {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            trace!("Evicting cache file: {}", filename);
+            if let Err(e) = fs::remove_file(self.base_dir.join(&filename)).await {
+                warn!("Failed to evict cache file {}: {}", filename, e);
+            }
+        }
+    }
+
+    pub fn usage(&self) -> DiskCacheUsage {
+        DiskCacheUsage {
+            used_bytes: self.index.lock().used,
+            capacity_bytes: self.capacity,
+            storage_limit_bytes: self.storage_limit,
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
     }
 
     pub fn key_to_filename(key: &str) -> String {
@@ -215,7 +405,7 @@ impl DiskStorage {
 
     pub async fn store(&self, key: &str, data: impl AsRef<[u8]>) -> anyhow::Result<()> {
         let filename = Self::key_to_filename(key);
-        let filepath = self.base_dir.join(filename);
+        let filepath = self.base_dir.join(&filename);
         let data_bytes = data.as_ref();
 
         trace!(
@@ -225,7 +415,33 @@ impl DiskStorage {
             filepath
         );
 
-        tokio::fs::write(filepath, data_bytes).await?;
+        let size = data_bytes.len() as u64;
+        // Counted before writing, so concurrent stores don't overshoot the capacity.
+        let victims = {
+            let mut index = self.index.lock();
+            index.forget(&filename);
+            let victims = match self.capacity {
+                Some(capacity) if size > capacity => None,
+                Some(capacity) => Some(index.evict_for(size, capacity)),
+                None => Some(Vec::new()),
+            };
+            if victims.is_some() {
+                index.insert(filename.clone(), size);
+            }
+            victims
+        };
+        let Some(victims) = victims else {
+            debug!("Not storing key '{}', larger than the disk capacity", key);
+            // A stale copy may still be on disk.
+            let _ = fs::remove_file(&filepath).await;
+            return Ok(());
+        };
+        self.remove_files(victims).await;
+
+        if let Err(e) = tokio::fs::write(filepath, data_bytes).await {
+            self.index.lock().forget(&filename);
+            return Err(e.into());
+        }
         debug!(
             "Successfully stored data for key '{}', size: {} bytes",
             key,
@@ -236,7 +452,7 @@ impl DiskStorage {
 
     pub async fn load(&self, key: &str) -> anyhow::Result<Vec<u8>> {
         let filename = Self::key_to_filename(key);
-        let filepath = self.base_dir.join(filename);
+        let filepath = self.base_dir.join(&filename);
 
         trace!("Loading data for key '{}' from file: {:?}", key, filepath);
 
@@ -247,6 +463,7 @@ impl DiskStorage {
 
         match tokio::fs::read(filepath).await {
             Ok(data) => {
+                self.index.lock().touch(&filename);
                 debug!(
                     "Successfully loaded data for key '{}', size: {} bytes",
                     key,
@@ -264,7 +481,7 @@ impl DiskStorage {
     #[allow(dead_code)]
     pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
         let filename = Self::key_to_filename(key);
-        let filepath = self.base_dir.join(filename);
+        let filepath = self.base_dir.join(&filename);
 
         trace!("Removing file for key '{}': {:?}", key, filepath);
 
@@ -278,6 +495,7 @@ impl DiskStorage {
 
         match tokio::fs::remove_file(filepath).await {
             Ok(_) => {
+                self.index.lock().forget(&filename);
                 debug!("Successfully removed file for key '{}'", key);
                 Ok(())
             }
@@ -1230,7 +1448,19 @@ impl ChunksCache {
             .take()
             .unwrap_or_else(|| cache_dir().unwrap());
         debug!("Using cache directory: {:?}", cache_dir);
-        let disk_storage = DiskStorage::new(cache_dir).await?;
+        fs::create_dir_all(&cache_dir).await?;
+        let storage_limit = cache_limit::detect_storage_limit(&cache_dir);
+        let capacity = negotiate_capacity(
+            config.disk_capacity,
+            storage_limit,
+            config.storage_limit_ratio,
+        );
+        info!(
+            "Disk cache capacity: {:?} bytes, pod storage limit: {:?} bytes",
+            capacity, storage_limit
+        );
+        let disk_storage =
+            DiskStorage::new_with_capacity(cache_dir, capacity, storage_limit).await?;
 
         let hot_bytes = Arc::new(AtomicU64::new(0));
         let hot_bytes_evict = hot_bytes.clone();
@@ -1375,6 +1605,11 @@ impl ChunksCache {
         Ok(())
     }
 
+    /// Disk usage and pressure against the pod storage limit, for export as metrics.
+    pub fn disk_usage(&self) -> DiskCacheUsage {
+        self.disk_storage.usage()
+    }
+
     #[allow(dead_code)]
     pub async fn remove(&self, key: &String) -> anyhow::Result<()> {
         info!("Cache REMOVE request for key: {}", key);
@@ -1575,9 +1810,7 @@ mod tests {
 
         // Launch multiple concurrent tasks
         for i in 0..10 {
-            let storage_clone = DiskStorage {
-                base_dir: storage.base_dir.clone(),
-            };
+            let storage_clone = storage.clone();
             let etag = format!("concurrent_etag_{}", i);
             let data = format!("Data for {}", i).into_bytes();
 
@@ -1647,6 +1880,54 @@ mod tests {
         assert!(error_string.contains("does not exist"));
     }
 
+    #[tokio::test]
+    async fn test_disk_capacity_evicts_least_recently_used() {
+        let temp_dir = tempdir().unwrap();
+        let storage = DiskStorage::new_with_capacity(temp_dir.path(), Some(300), Some(600))
+            .await
+            .unwrap();
+
+        storage.store("a", vec![1u8; 100]).await.unwrap();
+        storage.store("b", vec![2u8; 100]).await.unwrap();
+        storage.store("c", vec![3u8; 100]).await.unwrap();
+        // "a" is used again, so "b" is the one to go.
+        storage.load("a").await.unwrap();
+        storage.store("d", vec![4u8; 100]).await.unwrap();
+
+        assert!(storage.load("b").await.is_err());
+        for key in ["a", "c", "d"] {
+            assert_eq!(storage.load(key).await.unwrap().len(), 100);
+        }
+        // Larger than the whole capacity: not kept at all.
+        storage.store("e", vec![5u8; 301]).await.unwrap();
+        assert!(storage.load("e").await.is_err());
+
+        let usage = storage.usage();
+        assert_eq!(usage.used_bytes, 300);
+        assert_eq!(usage.evictions, 1);
+        assert_eq!(usage.pressure(), 0.5);
+
+        // Files of an earlier run count against a smaller capacity.
+        drop(storage);
+        let storage = DiskStorage::new_with_capacity(temp_dir.path(), Some(200), None)
+            .await
+            .unwrap();
+        let usage = storage.usage();
+        assert_eq!(usage.used_bytes, 200);
+        assert_eq!(usage.evictions, 1);
+        assert_eq!(usage.pressure(), 1.0);
+    }
+
+    #[test]
+    fn test_negotiate_capacity() {
+        assert_eq!(negotiate_capacity(None, None, 0.5), None);
+        assert_eq!(negotiate_capacity(Some(100), None, 0.5), Some(100));
+        assert_eq!(negotiate_capacity(None, Some(1000), 0.5), Some(500));
+        assert_eq!(negotiate_capacity(Some(100), Some(1000), 0.5), Some(100));
+        assert_eq!(negotiate_capacity(Some(800), Some(1000), 0.5), Some(500));
+        assert_eq!(negotiate_capacity(None, Some(1000), 2.0), Some(1000));
+    }
+
     // ========== AccessStats tests ==========
 
     #[test]
@@ -0,0 +1,147 @@
+//! Storage limits of the pod the local chunk cache runs in.
+//!
+//! Files in the cache directory count against the pod's ephemeral-storage limit, and
+//! against its memory cgroup when the directory is a memory-backed emptyDir. A pod that
+//! outgrows either is evicted or OOM-killed, so the cache caps its disk usage at a share
+//! of the limit found here.
+//!
+//! The ephemeral-storage limit is not a cgroup setting, it is only known to the kubelet.
+//! Pods hand it to slayerfs through [`STORAGE_LIMIT_ENV`], typically filled by the
+//! downward API from `limits.ephemeral-storage`.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use tracing::debug;
+
+/// Environment variable holding the pod's ephemeral-storage limit, in bytes or as a
+/// Kubernetes quantity like `10Gi`.
+pub const STORAGE_LIMIT_ENV: &str = "SLAYERFS_EPHEMERAL_STORAGE_LIMIT";
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// cgroup v1 reports "no limit" as a huge page-aligned number instead of `max`.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// Bytes the cache directory `dir` may grow to before the pod is in trouble, if limited.
+pub fn detect_storage_limit(dir: &Path) -> Option<u64> {
+    let ephemeral = std::env::var(STORAGE_LIMIT_ENV)
+        .ok()
+        .and_then(|value| parse_quantity(&value));
+    let memory = if is_tmpfs(dir) {
+        std::fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|content| memory_limit(Path::new(CGROUP_ROOT), &content))
+    } else {
+        None
+    };
+    debug!(
+        "Storage limits for {:?}: ephemeral-storage={:?}, memory={:?}",
+        dir, ephemeral, memory
+    );
+    match (ephemeral, memory) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn is_tmpfs(dir: &Path) -> bool {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` is a writable statfs.
+    let ret = unsafe { libc::statfs(path.as_ptr(), &mut stat) };
+    ret == 0 && stat.f_type == libc::TMPFS_MAGIC
+}
+
+/// Memory limit of the cgroup named in `proc_cgroup`, the content of `/proc/<pid>/cgroup`,
+/// below the cgroup filesystem mounted at `root`.
+fn memory_limit(root: &Path, proc_cgroup: &str) -> Option<u64> {
+    let (file, path) = proc_cgroup.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        if id == "0" && controllers.is_empty() {
+            Some((
+                root.join(path.trim_start_matches('/')).join("memory.max"),
+                path,
+            ))
+        } else if controllers.split(',').any(|c| c == "memory") {
+            Some((
+                root.join("memory")
+                    .join(path.trim_start_matches('/'))
+                    .join("memory.limit_in_bytes"),
+                path,
+            ))
+        } else {
+            None
+        }
+    })?;
+    let value = std::fs::read_to_string(&file).ok()?;
+    let limit = value.trim().parse::<u64>().ok()?;
+    debug!("Memory limit of cgroup {}: {} bytes", path, limit);
+    (limit < V1_UNLIMITED).then_some(limit)
+}
+
+/// Parse a byte count given as a plain number or a Kubernetes quantity (`512Mi`, `10G`).
+fn parse_quantity(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let multiplier: u64 = match suffix {
+        "" => 1,
+        "k" => 1000,
+        "M" => 1000u64.pow(2),
+        "G" => 1000u64.pow(3),
+        "T" => 1000u64.pow(4),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_quantity() {
+        assert_eq!(parse_quantity("1073741824"), Some(1 << 30));
+        assert_eq!(parse_quantity(" 512Mi\n"), Some(512 << 20));
+        assert_eq!(parse_quantity("10G"), Some(10_000_000_000));
+        assert_eq!(parse_quantity("1.5Gi"), None);
+        assert_eq!(parse_quantity("10Xi"), None);
+        assert_eq!(parse_quantity(""), None);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let root = tempdir().unwrap();
+        let v2 = root.path().join("kubepods/pod1");
+        std::fs::create_dir_all(&v2).unwrap();
+        std::fs::write(v2.join("memory.max"), "268435456\n").unwrap();
+        assert_eq!(
+            memory_limit(root.path(), "0::/kubepods/pod1\n"),
+            Some(256 << 20)
+        );
+
+        std::fs::write(v2.join("memory.max"), "max\n").unwrap();
+        assert_eq!(memory_limit(root.path(), "0::/kubepods/pod1\n"), None);
+
+        let v1 = root.path().join("memory/docker/b");
+        std::fs::create_dir_all(&v1).unwrap();
+        std::fs::write(v1.join("memory.limit_in_bytes"), "1048576\n").unwrap();
+        let content = "4:cpu,cpuacct:/docker/a\n3:memory:/docker/b\n";
+        assert_eq!(memory_limit(root.path(), content), Some(1 << 20));
+
+        std::fs::write(v1.join("memory.limit_in_bytes"), "9223372036854771712\n").unwrap();
+        assert_eq!(memory_limit(root.path(), content), None);
+        assert_eq!(memory_limit(root.path(), "4:cpu:/docker/a\n"), None);
+    }
+}
@@ -15,6 +15,7 @@
 #![allow(unused_imports)]
 
 pub mod cache;
+pub mod cache_limit;
 pub mod chunk;
 pub mod reader;
 pub mod singleflight;
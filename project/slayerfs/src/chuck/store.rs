@@ -5,7 +5,7 @@ use crate::utils::NumCastExt;
 use crate::utils::zero::make_zero_bytes;
 use crate::{
     cadapter::client::{ObjectBackend, ObjectClient},
-    chuck::cache::{ChunksCache, ChunksCacheConfig},
+    chuck::cache::{ChunksCache, ChunksCacheConfig, DiskCacheUsage},
 };
 use anyhow::{self, Context};
 use async_trait::async_trait;
@@ -60,10 +60,24 @@ pub trait BlockStore {
 
     #[allow(dead_code)]
     async fn delete_range(&self, key: BlockKey, len: u64) -> anyhow::Result<()>;
+
+    /// Checksum the backend confirmed when the block was last written through this store,
+    /// so a scrubber can skip reading freshly written blocks back. None if unconfirmed.
+    #[allow(dead_code)]
+    async fn verified_checksum(&self, key: BlockKey) -> Option<String> {
+        let _ = key;
+        None
+    }
 }
 
 pub type BlockKey = (u64 /*slice_id*/, u32 /*block_index*/);
 
+/// Key of the object holding the block `key` in the object store.
+pub fn block_object_key(key: BlockKey) -> String {
+    let (slice_id, block_index) = key;
+    format!("chunks/{slice_id}/{block_index}")
+}
+
 /// Simple in-memory implementation for local development/testing.
 #[derive(Default)]
 #[allow(dead_code)]
@@ -131,8 +145,13 @@ pub struct ObjectBlockStore<B: ObjectBackend> {
     read_flight: SingleFlight<BlockKey, Bytes>,
     /// Configuration for read strategy
     config: BlockStoreConfig,
+    /// Checksums the backend confirmed for the blocks written, see `verified_checksum`
+    verified: moka::future::Cache<BlockKey, String>,
 }
 
+/// Most blocks whose verified checksum is remembered.
+const VERIFIED_CAPACITY: u64 = 1 << 20;
+
 /// Configuration for ObjectBlockStore read strategy
 #[derive(Debug, Clone)]
 pub struct BlockStoreConfig {
@@ -166,6 +185,7 @@ impl<B: ObjectBackend> ObjectBlockStore<B> {
             block_cache,
             read_flight: SingleFlight::new(),
             config: BlockStoreConfig::default(),
+            verified: moka::future::Cache::new(VERIFIED_CAPACITY),
         }
     }
     /// Creates a new ObjectBlockStore with custom cache configuration
@@ -194,12 +214,33 @@ impl<B: ObjectBackend> ObjectBlockStore<B> {
             block_cache,
             read_flight: SingleFlight::new(),
             config: store_config,
+            verified: moka::future::Cache::new(VERIFIED_CAPACITY),
         })
     }
 
+    /// Usage of the local block cache, see [`DiskCacheUsage::pressure`].
+    #[allow(unused)]
+    pub fn cache_usage(&self) -> DiskCacheUsage {
+        self.block_cache.disk_usage()
+    }
+
     fn key_for(key: BlockKey) -> String {
-        let (chunk_id, block_index) = key;
-        format!("chunks/{chunk_id}/{block_index}")
+        block_object_key(key)
+    }
+
+    // Write the object of `key` and remember the checksum the backend confirmed for it.
+    async fn put_block(&self, key: BlockKey, parts: Vec<Bytes>) -> anyhow::Result<()> {
+        let key_str = Self::key_for(key);
+        let checksum = self
+            .client
+            .put_object_verified(&key_str, parts)
+            .await
+            .map_err(|e| anyhow::anyhow!("object store put failed: {key_str}, {e:?}"))?;
+        match checksum {
+            Some(sum) => self.verified.insert(key, sum).await,
+            None => self.verified.invalidate(&key).await,
+        }
+        Ok(())
     }
 }
 
@@ -220,10 +261,7 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
             buf.resize(end, 0);
         }
         buf[start..end].copy_from_slice(data);
-        self.client
-            .put_object(&key_str, &buf)
-            .await
-            .map_err(|e| anyhow::anyhow!("object store put failed: {key_str}, {e:?}"))?;
+        self.put_block(key, vec![Bytes::from(buf)]).await?;
 
         Ok(data.len() as u64)
     }
@@ -235,7 +273,6 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
         offset: u64,
         chunks: Vec<Bytes>,
     ) -> anyhow::Result<u64> {
-        let key_str = Self::key_for(key);
         let total_len = chunks.iter().map(|c| c.len()).sum::<usize>();
         if total_len == 0 {
             return Ok(0);
@@ -247,11 +284,7 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
             parts.extend(make_zero_bytes(offset_usize));
         }
         parts.extend(chunks);
-
-        self.client
-            .put_object_vectored(&key_str, parts)
-            .await
-            .map_err(|e| anyhow::anyhow!("object store put failed: {key_str}, {e:?}"))?;
+        self.put_block(key, parts).await?;
 
         Ok(total_len as u64)
     }
@@ -262,7 +295,6 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
         offset: u64,
         data: &[u8],
     ) -> anyhow::Result<u64> {
-        let key_str = Self::key_for(key);
         if data.is_empty() {
             return Ok(0);
         }
@@ -273,11 +305,7 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
             parts.extend(make_zero_bytes(offset_usize));
         }
         parts.push(Bytes::copy_from_slice(data));
-
-        self.client
-            .put_object_vectored(&key_str, parts)
-            .await
-            .map_err(|e| anyhow::anyhow!("object store put failed: {key_str}, {e:?}"))?;
+        self.put_block(key, parts).await?;
 
         Ok(data.len() as u64)
     }
@@ -358,9 +386,14 @@ impl<B: ObjectBackend + Send + Sync> BlockStore for ObjectBlockStore<B> {
                 .delete_object(&key_str)
                 .await
                 .map_err(|e| anyhow::anyhow!("object store delete failed: {key_str}, {e:?}"))?;
+            self.verified.invalidate(&(chunk_id, i)).await;
         }
         Ok(())
     }
+
+    async fn verified_checksum(&self, key: BlockKey) -> Option<String> {
+        self.verified.get(&key).await
+    }
 }
 
 /// Convenience alias: BlockStore backed by the real S3 backend.
@@ -398,6 +431,71 @@ mod tests {
         assert_eq!(out, data);
     }
 
+    #[tokio::test]
+    async fn test_verified_checksums() {
+        use async_trait::async_trait;
+
+        // Confirms every upload with the MD5 of the data, the way S3 ETags do.
+        struct CheckedBackend(LocalFsBackend);
+
+        #[async_trait]
+        impl ObjectBackend for CheckedBackend {
+            async fn put_object(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+                self.0.put_object(key, data).await
+            }
+
+            async fn put_object_verified(
+                &self,
+                key: &str,
+                chunks: Vec<Bytes>,
+            ) -> anyhow::Result<Option<String>> {
+                let data = chunks.concat();
+                self.0.put_object(key, &data).await?;
+                Ok(Some(encode(md5::compute(&data).0)))
+            }
+
+            async fn get_object(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+                self.0.get_object(key).await
+            }
+
+            async fn get_object_range(
+                &self,
+                key: &str,
+                offset: u64,
+                buf: &mut [u8],
+            ) -> anyhow::Result<usize> {
+                self.0.get_object_range(key, offset, buf).await
+            }
+
+            async fn get_etag(&self, key: &str) -> anyhow::Result<String> {
+                self.0.get_etag(key).await
+            }
+
+            async fn delete_object(&self, key: &str) -> anyhow::Result<()> {
+                self.0.delete_object(key).await
+            }
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let client = ObjectClient::new(CheckedBackend(LocalFsBackend::new(tmp.path())));
+        let store = ObjectBlockStore::new(client);
+        store.write_fresh_range((7, 0), 2, b"data").await.unwrap();
+        assert_eq!(
+            store.verified_checksum((7, 0)).await,
+            Some(encode(md5::compute(b"\0\0data").0))
+        );
+        assert_eq!(store.verified_checksum((7, 1)).await, None);
+
+        store.delete_range((7, 0), 1).await.unwrap();
+        assert_eq!(store.verified_checksum((7, 0)).await, None);
+
+        // Backends that confirm nothing leave no checksum behind.
+        let client = ObjectClient::new(LocalFsBackend::new(tmp.path()));
+        let store = ObjectBlockStore::new(client);
+        store.write_range((7, 0), 0, b"data").await.unwrap();
+        assert_eq!(store.verified_checksum((7, 0)).await, None);
+    }
+
     #[tokio::test]
     async fn test_cache_effectiveness() -> io::Result<()> {
         let tmp = tempfile::tempdir()?;
@@ -0,0 +1,577 @@
+//! Incremental backups of a volume
+//!
+//! A backup is a directory holding the block objects of the volume under `objects/`, keyed
+//! like in the object store, and one manifest per backup under `manifests/`. A manifest is
+//! a snapshot of the metadata tree: every entry with its attributes, xattrs and, for
+//! regular files, the slices of every chunk. Block objects are immutable once written, so a
+//! backup only copies the objects its parent did not reference, and successive backups
+//! share the ones already there. The directory can then be synced offsite as is.
+//!
+//! [`restore`] rebuilds a volume from a manifest into an empty metadata backend and object
+//! store. Slices get fresh ids there and their blocks are copied under the new keys.
+
+use crate::cadapter::client::{ObjectBackend, ObjectClient};
+use crate::chuck::SliceDesc;
+use crate::chuck::chunk::ChunkLayout;
+use crate::chuck::slice::{SliceOffset, block_span_iter_slice};
+use crate::chuck::store::block_object_key;
+use crate::meta::store::{FileType, MetaError, SetAttrFlags, SetAttrRequest};
+use crate::meta::{MetaStore, SLICE_ID_KEY};
+use crate::utils::NumCastExt;
+use crate::vfs::chunk_id_for;
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+const MANIFEST_VERSION: u32 = 1;
+const MANIFESTS_DIR: &str = "manifests";
+const OBJECTS_DIR: &str = "objects";
+
+/// Kind of a backed up entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// One slice of a chunk, as stored in the metadata backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupSlice {
+    pub slice_id: u64,
+    /// Offset in the chunk (bytes)
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Slices of one chunk of a file, oldest first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupChunk {
+    pub index: u64,
+    pub slices: Vec<BackupSlice>,
+}
+
+/// One entry of the metadata tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupEntry {
+    /// Absolute path in the volume, parents come before their children
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub mtime: i64,
+    /// Earlier path of the same file, for hard links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<BackupChunk>,
+}
+
+/// Snapshot of a volume, see the module documentation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub name: String,
+    /// Creation time, RFC 3339
+    pub created_at: String,
+    /// Backup this one is incremental to
+    pub parent: Option<String>,
+    pub chunk_size: u64,
+    pub block_size: u32,
+    pub entries: Vec<BackupEntry>,
+    /// Keys of every block object the volume references
+    pub objects: BTreeSet<String>,
+    /// Objects copied by this backup, the others were already there
+    pub new_objects: u64,
+    pub new_bytes: u64,
+}
+
+impl BackupManifest {
+    fn layout(&self) -> ChunkLayout {
+        ChunkLayout {
+            chunk_size: self.chunk_size,
+            block_size: self.block_size,
+        }
+    }
+}
+
+/// What [`restore`] recreated
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub entries: u64,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+fn manifest_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(MANIFESTS_DIR).join(format!("{name}.json"))
+}
+
+fn object_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(OBJECTS_DIR).join(key)
+}
+
+/// Read the manifest `name` of the backup directory `dir`.
+pub fn read_manifest(dir: &Path, name: &str) -> anyhow::Result<BackupManifest> {
+    let path = manifest_path(dir, name);
+    let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+    let manifest: BackupManifest = serde_json::from_slice(&data)?;
+    if manifest.version != MANIFEST_VERSION {
+        bail!("unsupported manifest version {}", manifest.version);
+    }
+    Ok(manifest)
+}
+
+/// Most recent manifest of the backup directory `dir`, if any.
+pub fn latest_manifest(dir: &Path) -> anyhow::Result<Option<BackupManifest>> {
+    let entries = match std::fs::read_dir(dir.join(MANIFESTS_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut latest: Option<BackupManifest> = None;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let manifest = read_manifest(dir, name)?;
+        if latest
+            .as_ref()
+            .is_none_or(|l| (&manifest.created_at, &manifest.name) > (&l.created_at, &l.name))
+        {
+            latest = Some(manifest);
+        }
+    }
+    Ok(latest)
+}
+
+fn kind_of(kind: FileType) -> EntryKind {
+    match kind {
+        FileType::File => EntryKind::File,
+        FileType::Dir => EntryKind::Dir,
+        FileType::Symlink => EntryKind::Symlink,
+    }
+}
+
+async fn xattrs_of(meta: &dyn MetaStore, ino: i64) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let names = match meta.list_xattr(ino).await {
+        Ok(names) => names,
+        Err(MetaError::NotImplemented | MetaError::NotSupported(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut xattrs = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(value) = meta.get_xattr(ino, &name).await? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Walk the metadata tree from the root, parents first.
+async fn snapshot(meta: &dyn MetaStore, layout: ChunkLayout) -> anyhow::Result<Vec<BackupEntry>> {
+    let mut entries = Vec::new();
+    let mut seen: HashMap<i64, String> = HashMap::new();
+    let mut pending = vec![(meta.root_ino(), "/".to_string())];
+    while let Some((dir_ino, dir_path)) = pending.pop() {
+        let mut children = meta.readdir(dir_ino).await?;
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        for child in children {
+            if child.name == "." || child.name == ".." {
+                continue;
+            }
+            let path = if dir_path == "/" {
+                format!("/{}", child.name)
+            } else {
+                format!("{dir_path}/{}", child.name)
+            };
+            let Some(attr) = meta.stat(child.ino).await? else {
+                // Removed while walking.
+                continue;
+            };
+            let mut entry = BackupEntry {
+                path: path.clone(),
+                kind: kind_of(attr.kind),
+                size: attr.size,
+                mode: attr.mode,
+                uid: attr.uid,
+                gid: attr.gid,
+                atime: attr.atime,
+                mtime: attr.mtime,
+                link_of: None,
+                target: None,
+                xattrs: Vec::new(),
+                chunks: Vec::new(),
+            };
+            if attr.kind != FileType::Dir {
+                if let Some(first) = seen.get(&attr.ino) {
+                    entry.link_of = Some(first.clone());
+                    entries.push(entry);
+                    continue;
+                }
+                seen.insert(attr.ino, path.clone());
+            }
+            entry.xattrs = xattrs_of(meta, attr.ino).await?;
+            match attr.kind {
+                FileType::Dir => pending.push((attr.ino, path)),
+                FileType::Symlink => entry.target = Some(meta.read_symlink(attr.ino).await?),
+                FileType::File => {
+                    for index in 0..attr.size.div_ceil(layout.chunk_size) {
+                        let slices = meta.get_slices(chunk_id_for(attr.ino, index)?).await?;
+                        if slices.is_empty() {
+                            continue;
+                        }
+                        entry.chunks.push(BackupChunk {
+                            index,
+                            slices: slices
+                                .iter()
+                                .map(|s| BackupSlice {
+                                    slice_id: s.slice_id,
+                                    offset: s.offset,
+                                    length: s.length,
+                                })
+                                .collect(),
+                        });
+                    }
+                }
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Block object keys holding the data of `slice`.
+fn slice_objects(slice: &BackupSlice, layout: ChunkLayout) -> impl Iterator<Item = String> {
+    let slice_id = slice.slice_id;
+    block_span_iter_slice(SliceOffset::new(0), slice.length, layout)
+        .map(move |span| block_object_key((slice_id, span.index.as_u32())))
+}
+
+/// Snapshot the volume of `meta` and `objects` into the backup directory `dir` as `name`,
+/// incremental to the latest backup already there.
+pub async fn backup<B: ObjectBackend>(
+    meta: &dyn MetaStore,
+    objects: &ObjectClient<B>,
+    layout: ChunkLayout,
+    dir: &Path,
+    name: &str,
+) -> anyhow::Result<BackupManifest> {
+    if manifest_path(dir, name).exists() {
+        bail!("backup {name} already exists in {}", dir.display());
+    }
+    let parent = latest_manifest(dir)?;
+    if let Some(parent) = &parent
+        && parent.layout() != layout
+    {
+        bail!("layout differs from the one of backup {}", parent.name);
+    }
+    let entries = snapshot(meta, layout).await?;
+
+    let mut manifest = BackupManifest {
+        version: MANIFEST_VERSION,
+        name: name.to_string(),
+        created_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+        parent: parent.as_ref().map(|p| p.name.clone()),
+        chunk_size: layout.chunk_size,
+        block_size: layout.block_size,
+        objects: entries
+            .iter()
+            .flat_map(|e| e.chunks.iter())
+            .flat_map(|c| c.slices.iter())
+            .flat_map(|s| slice_objects(s, layout))
+            .collect(),
+        entries,
+        new_objects: 0,
+        new_bytes: 0,
+    };
+    let known = parent.map(|p| p.objects).unwrap_or_default();
+    for key in manifest.objects.difference(&known) {
+        let path = object_path(dir, key);
+        if path.exists() {
+            continue;
+        }
+        let Some(data) = objects.get_object(key).await? else {
+            // Blocks never written, like the tail of a truncated slice, read as zeros.
+            debug!("backup: no object {key}");
+            continue;
+        };
+        write_atomic(&path, &data)?;
+        manifest.new_objects += 1;
+        manifest.new_bytes += data.len() as u64;
+    }
+
+    write_atomic(
+        &manifest_path(dir, name),
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    info!(
+        "backup {name}: {} entries, {} objects, {} new ({} bytes)",
+        manifest.entries.len(),
+        manifest.objects.len(),
+        manifest.new_objects,
+        manifest.new_bytes
+    );
+    Ok(manifest)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let parent = path.parent().context("path without parent")?;
+    std::fs::create_dir_all(parent)?;
+    let tmp = parent.join(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Rebuild the volume of the manifest `name` of the backup directory `dir` into the empty
+/// `meta` and `objects`.
+pub async fn restore<B: ObjectBackend>(
+    dir: &Path,
+    name: &str,
+    meta: &dyn MetaStore,
+    objects: &ObjectClient<B>,
+) -> anyhow::Result<RestoreSummary> {
+    let manifest = read_manifest(dir, name)?;
+    let layout = manifest.layout();
+    let root = meta.root_ino();
+    if meta
+        .readdir(root)
+        .await?
+        .iter()
+        .any(|e| e.name != "." && e.name != "..")
+    {
+        bail!("restore needs an empty volume");
+    }
+
+    let mut summary = RestoreSummary::default();
+    let mut inodes: HashMap<String, i64> = HashMap::from([("/".to_string(), root)]);
+    let mut slice_ids: HashMap<u64, u64> = HashMap::new();
+    let mut restored = Vec::with_capacity(manifest.entries.len());
+    for entry in &manifest.entries {
+        let (parent_path, name) = entry
+            .path
+            .rsplit_once('/')
+            .context("relative path in manifest")?;
+        let parent_path = if parent_path.is_empty() {
+            "/"
+        } else {
+            parent_path
+        };
+        let parent = *inodes
+            .get(parent_path)
+            .with_context(|| format!("parent of {} missing", entry.path))?;
+
+        let ino = if let Some(first) = &entry.link_of {
+            let ino = *inodes
+                .get(first)
+                .with_context(|| format!("link target {first} missing"))?;
+            meta.link(ino, parent, name).await?;
+            inodes.insert(entry.path.clone(), ino);
+            summary.entries += 1;
+            continue;
+        } else {
+            match entry.kind {
+                EntryKind::Dir => meta.mkdir(parent, name.to_string()).await?,
+                EntryKind::Symlink => {
+                    let target = entry.target.as_deref().unwrap_or_default();
+                    meta.symlink(parent, name, target).await?.0
+                }
+                EntryKind::File => meta.create_file(parent, name.to_string()).await?,
+            }
+        };
+        inodes.insert(entry.path.clone(), ino);
+
+        for chunk in &entry.chunks {
+            let chunk_id = chunk_id_for(ino, chunk.index)?;
+            for slice in &chunk.slices {
+                let slice_id = match slice_ids.get(&slice.slice_id) {
+                    Some(id) => *id,
+                    None => {
+                        let id = meta.next_id(SLICE_ID_KEY).await? as u64;
+                        slice_ids.insert(slice.slice_id, id);
+                        id
+                    }
+                };
+                let desc = SliceDesc {
+                    slice_id,
+                    chunk_id,
+                    offset: slice.offset,
+                    length: slice.length,
+                };
+                meta.append_slice(chunk_id, desc).await?;
+            }
+        }
+        if entry.kind == EntryKind::File {
+            meta.set_file_size(ino, entry.size).await?;
+        }
+        for (key, value) in &entry.xattrs {
+            meta.set_xattr(ino, key, value, 0).await?;
+        }
+        summary.entries += 1;
+        restored.push((ino, entry));
+    }
+
+    // Blocks of a slice shared by several chunk entries are copied once.
+    let mut copied = BTreeSet::new();
+    for (_, entry) in &restored {
+        for slice in entry.chunks.iter().flat_map(|c| c.slices.iter()) {
+            let new_id = slice_ids[&slice.slice_id];
+            for span in block_span_iter_slice(SliceOffset::new(0), slice.length, layout) {
+                let index = span.index.as_u32();
+                let key = block_object_key((slice.slice_id, index));
+                if !copied.insert(key.clone()) {
+                    continue;
+                }
+                let data = match std::fs::read(object_path(dir, &key)) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                objects
+                    .put_object(&block_object_key((new_id, index)), &data)
+                    .await?;
+                summary.objects += 1;
+                summary.bytes += data.len() as u64;
+            }
+        }
+    }
+
+    // Last, so that creating children doesn't bump the times of their directories.
+    for (ino, entry) in restored.iter().rev() {
+        let req = SetAttrRequest {
+            mode: Some(entry.mode),
+            uid: Some(entry.uid),
+            gid: Some(entry.gid),
+            atime: Some(entry.atime),
+            mtime: Some(entry.mtime),
+            ..Default::default()
+        };
+        meta.set_attr(*ino, &req, SetAttrFlags::empty()).await?;
+    }
+    info!(
+        "restored {name}: {} entries, {} objects ({} bytes)",
+        summary.entries, summary.objects, summary.bytes
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cadapter::localfs::LocalFsBackend;
+    use crate::chuck::store::ObjectBlockStore;
+    use crate::meta::factory::create_meta_store_from_url;
+    use crate::vfs::fs::VFS;
+
+    #[tokio::test]
+    async fn test_incremental_backup_and_restore() {
+        let layout = ChunkLayout {
+            chunk_size: 64 * 1024,
+            block_size: 16 * 1024,
+        };
+        let data = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let meta = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(
+            layout,
+            ObjectBlockStore::new(ObjectClient::new(LocalFsBackend::new(data.path()))),
+            meta.store(),
+        )
+        .await
+        .unwrap();
+        let objects = ObjectClient::new(LocalFsBackend::new(data.path()));
+
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs.mkdir_p("/d/e").await.unwrap();
+        let ino = fs.create_file("/d/big").await.unwrap();
+        let attr = fs.stat("/d/big").await.unwrap();
+        let fh = fs.open(ino, attr, false, true).await.unwrap();
+        fs.write(fh, 0, &big).await.unwrap();
+        fs.close(fh).await.unwrap();
+        fs.link("/d/big", "/d/e/alias").await.unwrap();
+        fs.create_symlink("/link", "d/big").await.unwrap();
+
+        let first = backup(meta.store().as_ref(), &objects, layout, dest.path(), "b1")
+            .await
+            .unwrap();
+        assert_eq!(first.parent, None);
+        assert_eq!(first.entries.len(), 5);
+        assert!(first.new_objects >= 7);
+
+        let ino = fs.create_file("/small").await.unwrap();
+        let attr = fs.stat("/small").await.unwrap();
+        let fh = fs.open(ino, attr, false, true).await.unwrap();
+        fs.write(fh, 0, b"hello").await.unwrap();
+        fs.close(fh).await.unwrap();
+        let second = backup(meta.store().as_ref(), &objects, layout, dest.path(), "b2")
+            .await
+            .unwrap();
+        assert_eq!(second.parent.as_deref(), Some("b1"));
+        // Only the block of the new file is copied.
+        assert_eq!(second.new_objects, 1);
+        assert_eq!(second.new_bytes, 5);
+        assert_eq!(latest_manifest(dest.path()).unwrap().unwrap().name, "b2");
+
+        let target_data = tempfile::tempdir().unwrap();
+        let target_meta = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let target_objects = ObjectClient::new(LocalFsBackend::new(target_data.path()));
+        let summary = restore(
+            dest.path(),
+            "b2",
+            target_meta.store().as_ref(),
+            &target_objects,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.entries, 6);
+        assert_eq!(summary.bytes, big.len() as u64 + 5);
+
+        let restored = snapshot(target_meta.store().as_ref(), layout)
+            .await
+            .unwrap();
+        let by_path: HashMap<_, _> = restored.iter().map(|e| (e.path.as_str(), e)).collect();
+        assert_eq!(by_path["/d/big"].size, big.len() as u64);
+        assert_eq!(by_path["/d/e/alias"].link_of.as_deref(), Some("/d/big"));
+        assert_eq!(by_path["/link"].target.as_deref(), Some("d/big"));
+        let backed_up_d = second.entries.iter().find(|e| e.path == "/d").unwrap();
+        assert_eq!(by_path["/d"].mtime, backed_up_d.mtime);
+        let mut content = Vec::new();
+        for chunk in &by_path["/d/big"].chunks {
+            for slice in &chunk.slices {
+                for key in slice_objects(slice, layout) {
+                    content.extend(target_objects.get_object(&key).await.unwrap().unwrap());
+                }
+            }
+        }
+        assert_eq!(content, big);
+
+        let err = restore(
+            dest.path(),
+            "b1",
+            target_meta.store().as_ref(),
+            &target_objects,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("empty"));
+    }
+}
@@ -15,7 +15,11 @@
 //! Submodules:
 //! - `worker`: background worker implementations (upload, gc, compaction)
 //! - `supervisor`: supervisor utilities for managing worker lifecycles
+//! - `usage`: periodic per-volume usage reports
+//! - `backup`: incremental backups of a volume and their restore
+pub mod backup;
 pub(crate) mod supervisor;
+pub mod usage;
 pub mod worker;
 
 // Module implementation TODOs remain.
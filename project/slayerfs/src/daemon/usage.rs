@@ -0,0 +1,355 @@
+//! Periodic per-volume usage reports for charge-back
+
+use crate::chuck::store::BlockStore;
+use crate::meta::MetaLayer;
+use crate::vfs::fs::VFS;
+use crate::vfs::usage::{OpCounts, UsageSnapshot};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, interval};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+/// Encoding of the reports appended to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One JSON object per line
+    Json,
+    /// Comma-separated values, with a header line when the file is new
+    Csv,
+}
+
+/// Destination of the usage reports
+#[derive(Debug, Clone)]
+pub enum UsageSink {
+    /// Append every report to a local file
+    File { path: PathBuf, format: ReportFormat },
+    /// POST every report as JSON to an HTTP endpoint
+    Webhook { url: String },
+}
+
+/// Usage reporting configuration
+#[derive(Debug, Clone)]
+pub struct UsageReportConfig {
+    /// Volume name the reports are attributed to
+    pub volume: String,
+    /// Report interval (seconds)
+    pub interval_secs: u64,
+    /// Where the reports go, every sink gets every report
+    pub sinks: Vec<UsageSink>,
+}
+
+impl Default for UsageReportConfig {
+    fn default() -> Self {
+        Self {
+            volume: "slayerfs".to_string(),
+            interval_secs: 300,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+/// Usage of a volume over one report interval.
+///
+/// Capacity and object counts are levels at the time of the report, traffic and operation
+/// counts are what happened since the previous report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub volume: String,
+    /// End of the interval, RFC 3339
+    pub timestamp: String,
+    /// Length of the interval (seconds)
+    pub interval_secs: u64,
+    /// Volume capacity in bytes, None when the metadata backend can't tell
+    pub total_space: Option<u64>,
+    /// Bytes in use
+    pub used_space: Option<u64>,
+    /// Files, directories and symlinks stored in the volume
+    pub objects: Option<u64>,
+    /// Whether the volume is over a soft quota threshold
+    pub quota_warn: bool,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub ops: OpCounts,
+}
+
+impl UsageReport {
+    /// Header line matching [`UsageReport::csv_row`]
+    pub fn csv_header() -> String {
+        let mut header = String::from(
+            "volume,timestamp,interval_secs,total_space,used_space,objects,quota_warn,bytes_read,bytes_written",
+        );
+        for (name, _) in OpCounts::default().fields() {
+            let _ = write!(header, ",ops_{name}");
+        }
+        header
+    }
+
+    /// The report as one CSV line, without the line break
+    pub fn csv_row(&self) -> String {
+        let opt = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+        let mut row = format!(
+            "{},{},{},{},{},{},{},{},{}",
+            csv_field(&self.volume),
+            self.timestamp,
+            self.interval_secs,
+            opt(self.total_space),
+            opt(self.used_space),
+            opt(self.objects),
+            u8::from(self.quota_warn),
+            self.bytes_read,
+            self.bytes_written
+        );
+        for (_, count) in self.ops.fields() {
+            let _ = write!(row, ",{count}");
+        }
+        row
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Turns the usage counters of a VFS into [`UsageReport`]s and publishes them.
+pub struct UsageReporter<S, M>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaLayer + Send + Sync + 'static,
+{
+    vfs: VFS<S, M>,
+    config: UsageReportConfig,
+    http: reqwest::Client,
+    last: UsageSnapshot,
+    last_at: Instant,
+}
+
+impl<S, M> UsageReporter<S, M>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaLayer + Send + Sync + 'static,
+{
+    /// The first report covers the usage since this call.
+    pub fn new(vfs: VFS<S, M>, config: UsageReportConfig) -> Self {
+        let last = vfs.usage();
+        Self {
+            vfs,
+            config,
+            http: reqwest::Client::new(),
+            last,
+            last_at: Instant::now(),
+        }
+    }
+
+    /// Run until `token` is cancelled, publishing one report per interval and a last one
+    /// covering the rest when stopped.
+    pub async fn start(mut self, token: CancellationToken) {
+        let mut interval = interval(Duration::from_secs(self.config.interval_secs.max(1)));
+        // The first tick completes immediately, and would report an empty interval.
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => self.report_and_publish().await,
+            }
+        }
+        self.report_and_publish().await;
+    }
+
+    async fn report_and_publish(&mut self) {
+        let report = self.report().await;
+        debug!(
+            "Usage of {}: {} bytes read, {} bytes written, {} ops",
+            report.volume,
+            report.bytes_read,
+            report.bytes_written,
+            report.ops.total()
+        );
+        // Failed sinks are logged by publish, the next report goes out regardless.
+        let _ = self.publish(&report).await;
+    }
+
+    /// Report of the usage since the previous report.
+    pub async fn report(&mut self) -> UsageReport {
+        let now = self.vfs.usage();
+        let delta = now.since(&self.last);
+        let elapsed = self.last_at.elapsed();
+        self.last = now;
+        self.last_at = Instant::now();
+
+        let stat = match self.vfs.stat_fs().await {
+            Ok(stat) => Some(stat),
+            Err(e) => {
+                debug!("Usage report without capacity, stat_fs failed: {}", e);
+                None
+            }
+        };
+        let timestamp: DateTime<Utc> = Utc::now();
+        UsageReport {
+            volume: self.config.volume.clone(),
+            timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            interval_secs: elapsed.as_secs(),
+            total_space: stat.as_ref().map(|s| s.total_space),
+            used_space: stat
+                .as_ref()
+                .map(|s| s.total_space.saturating_sub(s.available_space)),
+            objects: stat.as_ref().map(|s| s.used_inodes),
+            quota_warn: self.vfs.quota_warn(),
+            bytes_read: delta.bytes_read,
+            bytes_written: delta.bytes_written,
+            ops: delta.ops,
+        }
+    }
+
+    /// Send `report` to every sink. All sinks are tried, the first error is returned.
+    pub async fn publish(&self, report: &UsageReport) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for sink in &self.config.sinks {
+            let sent = match sink {
+                UsageSink::File { path, format } => append_report(path, *format, report).await,
+                UsageSink::Webhook { url } => self.post_report(url, report).await,
+            };
+            if let Err(e) = sent {
+                error!("Usage report to {:?} failed: {}", sink, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn post_report(&self, url: &str, report: &UsageReport) -> anyhow::Result<()> {
+        self.http
+            .post(url)
+            .json(report)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+async fn append_report(
+    path: &Path,
+    format: ReportFormat,
+    report: &UsageReport,
+) -> anyhow::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut out = String::new();
+    match format {
+        ReportFormat::Json => out.push_str(&serde_json::to_string(report)?),
+        ReportFormat::Csv => {
+            if file.metadata().await?.len() == 0 {
+                out.push_str(&UsageReport::csv_header());
+                out.push('\n');
+            }
+            out.push_str(&report.csv_row());
+        }
+    }
+    out.push('\n');
+    file.write_all(out.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Start usage reporting for the volume served by `vfs`, stopped by cancelling `token`
+pub async fn start_usage_reporter<S, M>(
+    vfs: VFS<S, M>,
+    config: UsageReportConfig,
+    token: CancellationToken,
+) where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaLayer + Send + Sync + 'static,
+{
+    UsageReporter::new(vfs, config).start(token).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chuck::chunk::ChunkLayout;
+    use crate::chuck::store::InMemoryBlockStore;
+    use crate::meta::factory::create_meta_store_from_url;
+
+    #[tokio::test]
+    async fn test_usage_report_deltas() {
+        let meta = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(
+            ChunkLayout::default(),
+            InMemoryBlockStore::new(),
+            meta.store(),
+        )
+        .await
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("usage.csv");
+        let json = dir.path().join("usage.jsonl");
+        let config = UsageReportConfig {
+            volume: "tenant,a".to_string(),
+            interval_secs: 60,
+            sinks: vec![
+                UsageSink::File {
+                    path: csv.clone(),
+                    format: ReportFormat::Csv,
+                },
+                UsageSink::File {
+                    path: json.clone(),
+                    format: ReportFormat::Json,
+                },
+            ],
+        };
+        let mut reporter = UsageReporter::new(fs.clone(), config);
+
+        let ino = fs.create_file("/a/b.txt").await.unwrap();
+        let attr = fs.stat("/a/b.txt").await.unwrap();
+        let fh = fs.open(ino, attr, true, true).await.unwrap();
+        assert_eq!(fs.write(fh, 0, b"hello world").await.unwrap(), 11);
+        assert_eq!(fs.read(fh, 0, 5).await.unwrap(), b"hello");
+        fs.close(fh).await.unwrap();
+
+        let first = reporter.report().await;
+        assert_eq!(first.bytes_written, 11);
+        assert_eq!(first.bytes_read, 5);
+        assert_eq!(first.ops.mkdir, 1);
+        assert_eq!(first.ops.create, 1);
+        assert_eq!(first.ops.open, 1);
+        reporter.publish(&first).await.unwrap();
+
+        fs.unlink("/a/b.txt").await.unwrap();
+        let second = reporter.report().await;
+        assert_eq!(second.bytes_written, 0);
+        assert_eq!(second.ops.total(), 1);
+        assert_eq!(second.ops.unlink, 1);
+        reporter.publish(&second).await.unwrap();
+
+        let lines: Vec<String> = std::fs::read_to_string(&csv)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], UsageReport::csv_header());
+        assert!(lines[1].starts_with("\"tenant,a\","));
+        assert_eq!(lines[2], second.csv_row());
+
+        let reports: Vec<UsageReport> = std::fs::read_to_string(&json)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(reports, [first, second]);
+    }
+}
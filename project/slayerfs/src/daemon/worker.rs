@@ -1,11 +1,13 @@
-//! Background workers (upload, compaction, gc)
+//! Background workers (upload, compaction, gc, stale upload cleanup)
 
 use crate::cadapter::client::{ObjectBackend, ObjectClient};
 use crate::chuck::ChunkLayout;
-use crate::meta::store::MetaStore;
+use crate::meta::store::{LockName, MetaStore};
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::time::{Duration, interval};
-use tracing::{debug, error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 #[allow(dead_code)]
 pub(crate) fn start_upload_workers() {
@@ -179,3 +181,187 @@ pub async fn start_gc<B: ObjectBackend>(
 
     gc.start().await;
 }
+
+/// Stale multipart upload cleanup configuration
+#[derive(Debug, Clone)]
+pub struct UploadCleanupConfig {
+    /// Cleanup run interval (seconds)
+    pub interval_secs: u64,
+    /// Age after which an unfinished upload is considered abandoned (seconds)
+    pub ttl_secs: u64,
+}
+
+impl Default for UploadCleanupConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 3600,
+            ttl_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Aborts multipart uploads that interrupted writers left behind, so their parts stop
+/// accumulating storage cost.
+///
+/// The backend's own listing of open uploads is the source of truth, which also catches
+/// uploads of writers that crashed before they could record or abort anything.
+pub(crate) struct StaleUploadCleaner<B: ObjectBackend> {
+    object_client: Arc<ObjectClient<B>>,
+    config: UploadCleanupConfig,
+}
+
+impl<B: ObjectBackend> StaleUploadCleaner<B> {
+    pub(crate) fn new(object_client: Arc<ObjectClient<B>>, config: UploadCleanupConfig) -> Self {
+        Self {
+            object_client,
+            config,
+        }
+    }
+
+    /// Run until `token` is cancelled. Only the client holding the cleanup lock in
+    /// `meta_store` works on a given tick, the others skip it.
+    pub(crate) async fn start(&self, meta_store: Arc<dyn MetaStore>, token: CancellationToken) {
+        let mut interval = interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => {
+                    if !meta_store.get_global_lock(LockName::CleanupUploadsLock).await {
+                        continue;
+                    }
+                    match self.run_cleanup_cycle().await {
+                        Ok(0) => debug!("No stale multipart uploads"),
+                        Ok(aborted) => info!("Aborted {} stale multipart uploads", aborted),
+                        Err(e) => error!("Stale upload cleanup failed: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Abort every upload started more than `ttl_secs` ago, returns how many were aborted.
+    pub(crate) async fn run_cleanup_cycle(&self) -> anyhow::Result<usize> {
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        let now = SystemTime::now();
+        let mut aborted = 0;
+        for upload in self.object_client.list_pending_uploads().await? {
+            // Without a start time the age is unknown, so the upload may still be in use.
+            let Some(initiated) = upload.initiated else {
+                continue;
+            };
+            if now.duration_since(initiated).unwrap_or_default() < ttl {
+                continue;
+            }
+            match self
+                .object_client
+                .abort_upload(&upload.key, &upload.upload_id)
+                .await
+            {
+                Ok(()) => aborted += 1,
+                Err(e) => warn!(
+                    "Failed to abort upload {} of {}: {}",
+                    upload.upload_id, upload.key, e
+                ),
+            }
+        }
+        Ok(aborted)
+    }
+}
+
+/// Start stale multipart upload cleanup, stopped by cancelling `token`
+#[allow(dead_code)]
+pub async fn start_upload_cleanup<B: ObjectBackend>(
+    meta_store: Arc<dyn MetaStore>,
+    object_client: Arc<ObjectClient<B>>,
+    config: Option<UploadCleanupConfig>,
+    token: CancellationToken,
+) {
+    let cleaner = StaleUploadCleaner::new(object_client, config.unwrap_or_default());
+    cleaner.start(meta_store, token).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cadapter::client::PendingUpload;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FakeBackend {
+        pending: Mutex<Vec<PendingUpload>>,
+    }
+
+    #[async_trait]
+    impl ObjectBackend for FakeBackend {
+        async fn put_object(&self, _key: &str, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn get_object(&self, _key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        async fn get_object_range(
+            &self,
+            _key: &str,
+            _offset: u64,
+            _buf: &mut [u8],
+        ) -> anyhow::Result<usize> {
+            Ok(0)
+        }
+
+        async fn get_etag(&self, _key: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        async fn delete_object(&self, _key: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn list_pending_uploads(&self) -> anyhow::Result<Vec<PendingUpload>> {
+            Ok(self.pending.lock().unwrap().clone())
+        }
+
+        async fn abort_upload(&self, _key: &str, upload_id: &str) -> anyhow::Result<()> {
+            self.pending
+                .lock()
+                .unwrap()
+                .retain(|u| u.upload_id != upload_id);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_uploads_aborted_after_ttl() {
+        let now = SystemTime::now();
+        let upload = |id: &str, age: Option<u64>| PendingUpload {
+            key: format!("chunks/{id}"),
+            upload_id: id.to_string(),
+            initiated: age.map(|secs| now - Duration::from_secs(secs)),
+        };
+        let backend = FakeBackend::default();
+        *backend.pending.lock().unwrap() = vec![
+            upload("old", Some(7200)),
+            upload("fresh", Some(60)),
+            upload("unknown", None),
+        ];
+        let client = Arc::new(ObjectClient::new(backend));
+        let config = UploadCleanupConfig {
+            interval_secs: 1,
+            ttl_secs: 3600,
+        };
+        let cleaner = StaleUploadCleaner::new(client.clone(), config);
+
+        assert_eq!(cleaner.run_cleanup_cycle().await.unwrap(), 1);
+        let left: Vec<_> = client
+            .list_pending_uploads()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.upload_id)
+            .collect();
+        assert_eq!(left, ["fresh", "unknown"]);
+        assert_eq!(cleaner.run_cleanup_cycle().await.unwrap(), 0);
+    }
+}
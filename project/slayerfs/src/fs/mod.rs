@@ -382,6 +382,7 @@ fn meta_error_to_io(path: &str, err: MetaError) -> io::Error {
         MetaError::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
         MetaError::InvalidPath(_) => io::ErrorKind::InvalidInput,
         MetaError::TooManySymlinks => io::ErrorKind::InvalidInput,
+        MetaError::NameTooLong(_) => io::ErrorKind::InvalidFilename,
         MetaError::NotSupported(_) | MetaError::NotImplemented => io::ErrorKind::Unsupported,
         MetaError::InvalidHandle(_) => io::ErrorKind::InvalidInput,
         MetaError::LockConflict { .. } => io::ErrorKind::WouldBlock,
@@ -1829,7 +1830,7 @@ where
             let offset = self.offset.load(Ordering::Relaxed);
             let data = self
                 .vfs
-                .read(self.fh, offset, buf.len())
+                .read_bytes(self.fh, offset, buf.len())
                 .await
                 .map_err(io::Error::from)?;
 
@@ -1857,7 +1858,7 @@ where
 
             let data = self
                 .vfs
-                .read(self.fh, offset, buf.len())
+                .read_bytes(self.fh, offset, buf.len())
                 .await
                 .map_err(io::Error::from)?;
 
@@ -17,6 +17,7 @@ pub mod mount;
 use crate::chuck::store::BlockStore;
 use crate::meta::MetaLayer;
 use crate::meta::file_lock::{FileLockQuery, FileLockRange, FileLockType};
+use crate::meta::name;
 use crate::meta::store::{MetaError, SetAttrFlags, SetAttrRequest};
 use crate::posix::NAME_MAX;
 use crate::vfs::error::VfsError;
@@ -25,6 +26,7 @@ use bytes::Bytes;
 use rfuse3::Errno;
 use rfuse3::Result as FuseResult;
 use rfuse3::raw::Request;
+use rfuse3::raw::flags::FOPEN_KEEP_CACHE;
 use rfuse3::raw::reply::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCreated, ReplyData, ReplyDirectory,
     ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite,
@@ -193,7 +195,7 @@ where
             name = %name.to_string_lossy(),
             "fuse.lookup"
         );
-        let name_str = name.to_string_lossy();
+        let name_str = name::from_os(name);
         let child = self.child_of(parent as i64, name_str.as_ref()).await;
         let Some(child_ino) = child else {
             return Err(libc::ENOENT.into());
@@ -224,12 +226,18 @@ where
         let accmode = flags & (libc::O_ACCMODE as u32);
         let read = accmode != (libc::O_WRONLY as u32);
         let write = accmode != (libc::O_RDONLY as u32);
-        let fh = self
-            .open(ino as i64, attr.clone(), read, write)
+        let (fh, keep_cache) = self
+            .open_cached(ino as i64, attr.clone(), read, write)
             .await
             .map_err(Into::<Errno>::into)?;
 
-        Ok(ReplyOpen { fh, flags })
+        // The reply carries FOPEN_* flags, not the open flags.
+        let flags = if keep_cache { FOPEN_KEEP_CACHE } else { 0 };
+        Ok(ReplyOpen {
+            fh,
+            flags,
+            backing_id: None,
+        })
     }
 
     // Open directory: create handle for caching
@@ -248,7 +256,11 @@ where
             .await
             .map_err(Into::<Errno>::into)?;
 
-        Ok(ReplyOpen { fh, flags: 0 })
+        Ok(ReplyOpen {
+            fh,
+            flags: 0,
+            backing_id: None,
+        })
     }
 
     // Read file: inode-based read
@@ -267,7 +279,7 @@ where
         };
 
         let data = if fh != 0 {
-            self.read(fh, offset, size as usize)
+            self.read_bytes(fh, offset, size as usize)
                 .await
                 .map_err(Into::<Errno>::into)?
         } else {
@@ -280,16 +292,14 @@ where
                 .await
                 .map_err(Into::<Errno>::into)?;
             let out = self
-                .read(tmp_fh, offset, size as usize)
+                .read_bytes(tmp_fh, offset, size as usize)
                 .await
                 .map_err(Into::<Errno>::into)?;
             let _ = self.close(tmp_fh).await;
             out
         };
 
-        Ok(ReplyData {
-            data: Bytes::from(data),
-        })
+        Ok(ReplyData { data })
     }
 
     async fn readlink(&self, _req: Request, ino: u64) -> FuseResult<ReplyData> {
@@ -461,7 +471,7 @@ where
             all.push(DirectoryEntry {
                 inode: e.ino as u64,
                 kind: vfs_kind_to_fuse(e.kind),
-                name: OsString::from(e.name.clone()),
+                name: name::to_os(&e.name),
                 offset: entry_offset,
             });
         }
@@ -558,7 +568,7 @@ where
                 inode: e.ino as u64,
                 generation: 0,
                 kind: vfs_kind_to_fuse(e.kind),
-                name: OsString::from(e.name.clone()),
+                name: name::to_os(&e.name),
                 offset: (entries_offset + i as u64 + 3) as i64,
                 attr: fattr,
                 entry_ttl: ttl,
@@ -620,7 +630,7 @@ where
             mode,
             "fuse.mknod"
         );
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
 
         // Validate parent
         let Some(pattr) = self.stat_ino(parent as i64).await else {
@@ -697,7 +707,7 @@ where
             umask,
             "fuse.mkdir"
         );
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
         // Parent must be a directory
         let Some(pattr) = self.stat_ino(parent as i64).await else {
             return Err(libc::ENOENT.into());
@@ -751,7 +761,7 @@ where
             flags,
             "fuse.create"
         );
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
         // Validate parent
         let Some(pattr) = self.stat_ino(parent as i64).await else {
             return Err(libc::ENOENT.into());
@@ -789,6 +799,7 @@ where
             generation: 0,
             fh,
             flags: 0,
+            backing_id: None,
         })
     }
 
@@ -820,7 +831,7 @@ where
             return Err(libc::ENOTDIR.into());
         }
 
-        let new_name_str = new_name.to_string_lossy();
+        let new_name_str = name::from_os(new_name);
 
         if self
             .child_of(new_parent as i64, new_name_str.as_ref())
@@ -871,7 +882,7 @@ where
             link = %link.to_string_lossy(),
             "fuse.symlink"
         );
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
         if name.is_empty() {
             return Err(libc::EINVAL.into());
         }
@@ -917,7 +928,7 @@ where
     // Remove a file
     async fn unlink(&self, _req: Request, parent: u64, name: &OsStr) -> FuseResult<()> {
         debug!(parent, name = %name.to_string_lossy(), "fuse.unlink");
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
         // Ensure parent directory exists and has the right type
         let Some(pattr) = self.stat_ino(parent as i64).await else {
             return Err(libc::ENOENT.into());
@@ -948,7 +959,7 @@ where
     // Remove an empty directory
     async fn rmdir(&self, _req: Request, parent: u64, name: &OsStr) -> FuseResult<()> {
         debug!(parent, name = %name.to_string_lossy(), "fuse.rmdir");
-        let name = name.to_string_lossy();
+        let name = name::from_os(name);
         let Some(pattr) = self.stat_ino(parent as i64).await else {
             return Err(libc::ENOENT.into());
         };
@@ -991,8 +1002,8 @@ where
             new_name = %new_name.to_string_lossy(),
             "fuse.rename"
         );
-        let name = name.to_string_lossy();
-        let new_name = new_name.to_string_lossy();
+        let name = name::from_os(name);
+        let new_name = name::from_os(new_name);
 
         // Validate input parameters
         if name.is_empty() || new_name.is_empty() {
@@ -1390,6 +1401,9 @@ impl From<MetaError> for Errno {
             MetaError::AlreadyExists { .. } => libc::EEXIST,
             MetaError::NotSupported(_) | MetaError::NotImplemented => libc::ENOSYS,
             MetaError::InvalidPath(_) => libc::EINVAL,
+            MetaError::InvalidFilename => libc::EINVAL,
+            MetaError::NameTooLong(_) => libc::ENAMETOOLONG,
+            MetaError::TooManySymlinks => libc::ELOOP,
             _ => libc::EIO,
         };
         Errno::from(code)
@@ -1431,12 +1445,15 @@ impl From<VfsError> for Errno {
             VfsError::CrossesDevices => libc::EXDEV,
             VfsError::TooManyLinks => libc::EMLINK,
             VfsError::InvalidFilename => libc::EINVAL,
+            VfsError::FilenameTooLong { .. } => libc::ENAMETOOLONG,
+            VfsError::PathTooDeep { .. } => libc::ELOOP,
             VfsError::ArgumentListTooLong => libc::E2BIG,
             VfsError::Interrupted => libc::EINTR,
             VfsError::Unsupported => libc::ENOSYS,
             VfsError::UnexpectedEof => libc::EIO,
             VfsError::OutOfMemory => libc::ENOMEM,
             VfsError::StaleNetworkFileHandle => libc::ESTALE,
+            VfsError::Meta(err) => return err.into(),
             _ => libc::EIO,
         };
         code.into()
@@ -10,16 +10,20 @@ pub mod fuse;
 pub(crate) mod meta;
 pub(crate) mod posix;
 pub mod sdk_fs;
+pub mod tenant;
 pub(crate) mod vfs;
 
 pub(crate) mod utils;
 
 // Public SDK surface for external users.
 pub use crate::sdk_fs::{
-    AccessMode, Client, ClientBackend, DirEntry as SdkDirEntry, File, FileType as SdkFileType,
-    Metadata, OpenOptions, ReadDir,
+    AccessMode, Client, ClientBackend, CrossVolumeError, DirEntry as SdkDirEntry, File,
+    FileType as SdkFileType, Metadata, OpenOptions, ReadDir,
 };
-pub use crate::vfs::sdk::{LocalClient, VfsClient};
+pub use crate::tenant::{
+    AccessKey, Credentials, Tenant, TenantClient, TenantConfig, TenantQuota, TenantRegistry,
+};
+pub use crate::vfs::sdk::{LocalClient, VfsClient, Volume};
 
 // Re-export core types needed to construct SDK backends.
 pub use crate::cadapter::client::{ObjectBackend, ObjectClient};
@@ -28,7 +32,9 @@ pub use crate::cadapter::s3::{S3Backend, S3Config};
 pub use crate::chuck::chunk::ChunkLayout;
 pub use crate::chuck::store::{BlockKey, BlockStore, InMemoryBlockStore, ObjectBlockStore};
 pub use crate::meta::client::MetaClient;
-pub use crate::meta::config::{CacheConfig, ClientOptions, Config, DatabaseConfig, DatabaseType};
+pub use crate::meta::config::{
+    CacheConfig, ClientOptions, Config, DEFAULT_DIR_SHARD_THRESHOLD, DatabaseConfig, DatabaseType,
+};
 pub use crate::meta::factory::MetaStoreFactory;
 pub use crate::meta::file_lock::{FileLockInfo, FileLockQuery, FileLockRange, FileLockType};
 pub use crate::meta::store::{
@@ -39,4 +45,9 @@ pub use crate::meta::stores::{DatabaseMetaStore, EtcdMetaStore, RedisMetaStore};
 pub use crate::meta::{
     MetaHandle, MetaStore, create_meta_store_from_url, create_redis_meta_store_from_url,
 };
+pub use crate::utils::tls::TlsConfig;
 pub use crate::vfs::fs::{RenameFlags, VFS};
+pub use crate::vfs::io::reader::FileAdvice;
+pub use crate::vfs::journal::{JournalRecovery, UnrecoveredWrite};
+pub use crate::vfs::quota::{QUOTA_WARN_XATTR, QuotaEvent, SoftQuota};
+pub use crate::vfs::usage::{OpCounts, UsageSnapshot};
@@ -25,8 +25,10 @@ use std::path::PathBuf;
 use std::sync::Arc;
 #[cfg(feature = "profiling")]
 use std::sync::{LazyLock, Mutex as StdMutex};
+use std::time::Duration;
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -35,12 +37,19 @@ use crate::cadapter::client::ObjectClient;
 use crate::cadapter::localfs::LocalFsBackend;
 use crate::chuck::chunk::{ChunkLayout, DEFAULT_BLOCK_SIZE, DEFAULT_CHUNK_SIZE};
 use crate::chuck::store::ObjectBlockStore;
+use crate::daemon::backup::{backup, latest_manifest, read_manifest, restore};
+use crate::daemon::usage::{ReportFormat, UsageReportConfig, UsageSink, start_usage_reporter};
 use crate::fuse::mount::mount_vfs_unprivileged;
 use crate::meta::MetaStore;
-use crate::meta::config::{CacheConfig, ClientOptions, Config, DatabaseConfig, DatabaseType};
+use crate::meta::config::{
+    CacheConfig, ClientOptions, Config, DEFAULT_DIR_SHARD_THRESHOLD, DatabaseConfig, DatabaseType,
+};
 use crate::meta::factory::MetaStoreFactory;
-use crate::meta::stores::{DatabaseMetaStore, EtcdMetaStore};
+use crate::meta::stores::{DatabaseMetaStore, EtcdMetaStore, ReplicatedMetaStore};
+use crate::utils::tls::TlsConfig;
+use crate::vfs::append::AppendConfig;
 use crate::vfs::fs::VFS;
+use crate::vfs::quota::SoftQuota;
 
 #[derive(Parser)]
 #[command(name = "slayerfs", version, about = "SlayerFS FUSE CLI")]
@@ -53,14 +62,15 @@ struct Cli {
 enum Command {
     /// Mount SlayerFS via FUSE.
     Mount(MountArgs),
+    /// Back up a volume, incrementally to the latest backup in the destination.
+    Backup(BackupArgs),
+    /// Rebuild a volume from a backup.
+    Restore(RestoreArgs),
 }
 
+/// Where the data and metadata of a volume live.
 #[derive(Args)]
-struct MountArgs {
-    /// Directory to mount the filesystem.
-    #[arg(value_name = "MOUNT_POINT")]
-    mount_point: PathBuf,
-
+struct VolumeArgs {
     /// Local directory used as object storage backend.
     #[arg(long, value_name = "DIR", default_value = "./data")]
     data_dir: PathBuf,
@@ -77,6 +87,43 @@ struct MountArgs {
     #[arg(long, value_name = "URLS", value_delimiter = ',')]
     meta_etcd_urls: Vec<String>,
 
+    /// Shards for the children index of large etcd directories (0 disables sharding).
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    meta_etcd_dir_shards: u32,
+
+    /// CA bundle (PEM) for TLS to the metadata backend.
+    #[arg(long, value_name = "FILE")]
+    meta_tls_ca: Option<PathBuf>,
+
+    /// Client certificate (PEM) for mutual TLS to the metadata backend.
+    #[arg(long, value_name = "FILE", requires = "meta_tls_key")]
+    meta_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --meta-tls-cert.
+    #[arg(long, value_name = "FILE", requires = "meta_tls_cert")]
+    meta_tls_key: Option<PathBuf>,
+
+    /// Name expected in the metadata backend's certificate.
+    #[arg(long, value_name = "NAME")]
+    meta_tls_server_name: Option<String>,
+
+    /// Second metadata backend every metadata change is mirrored to before it returns.
+    #[arg(long, value_enum)]
+    meta_replica_backend: Option<MetaBackendKind>,
+
+    /// URL of --meta-replica-backend (sqlx only).
+    #[arg(long, value_name = "URL", default_value = "sqlite::memory:")]
+    meta_replica_url: String,
+
+    /// Etcd endpoint URLs of --meta-replica-backend (comma-separated).
+    #[arg(long, value_name = "URLS", value_delimiter = ',')]
+    meta_replica_etcd_urls: Vec<String>,
+
+    /// Refuse metadata changes once the replica diverged, instead of applying them to the
+    /// primary alone.
+    #[arg(long, requires = "meta_replica_backend")]
+    meta_replica_strict: bool,
+
     /// Chunk size in bytes.
     #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
     chunk_size: u64,
@@ -86,6 +133,147 @@ struct MountArgs {
     block_size: u32,
 }
 
+#[derive(Args)]
+struct BackupArgs {
+    /// Backup directory, created if missing.
+    #[arg(value_name = "DEST")]
+    dest: PathBuf,
+
+    /// Name of the backup, defaults to the current UTC time.
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+
+    #[command(flatten)]
+    volume: VolumeArgs,
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    /// Backup directory; the metadata backend of the volume must be empty.
+    #[arg(value_name = "SOURCE")]
+    source: PathBuf,
+
+    /// Backup to restore, defaults to the latest one.
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+
+    #[command(flatten)]
+    volume: VolumeArgs,
+}
+
+#[derive(Args)]
+struct MountArgs {
+    /// Directory to mount the filesystem.
+    #[arg(value_name = "MOUNT_POINT")]
+    mount_point: PathBuf,
+
+    #[command(flatten)]
+    volume: VolumeArgs,
+
+    /// Volume name usage reports are attributed to.
+    #[arg(long, value_name = "NAME", default_value = "slayerfs")]
+    volume_name: String,
+
+    /// Append a usage report per interval to this file (CSV if it ends in .csv, else JSON lines).
+    #[arg(long, value_name = "FILE")]
+    usage_report_file: Option<PathBuf>,
+
+    /// POST a JSON usage report per interval to this URL.
+    #[arg(long, value_name = "URL")]
+    usage_report_webhook: Option<String>,
+
+    /// Seconds between usage reports.
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    usage_report_interval: u64,
+
+    /// Bytes in use above which the volume is flagged by the slayerfs.quota.warn xattr.
+    #[arg(long, value_name = "BYTES")]
+    soft_quota_space: Option<u64>,
+
+    /// Inodes in use above which the volume is flagged by the slayerfs.quota.warn xattr.
+    #[arg(long, value_name = "N")]
+    soft_quota_inodes: Option<u64>,
+
+    /// Consecutive small appends after which a file is written in append mode, 0 limits it
+    /// to files with the slayerfs.append xattr set.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    append_detect_after: u32,
+
+    /// Milliseconds the appends of a closed file in append mode may stay buffered, i.e. lost
+    /// on a crash.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    append_window_ms: u64,
+
+    /// Drop the kernel page cache of a file on every open, instead of only when the file
+    /// changed since its last open.
+    #[arg(long)]
+    no_keep_page_cache: bool,
+
+    /// Directory of the node-local journal of buffered writes, replayed on the next mount of
+    /// the volume after a crash.
+    #[arg(long, value_name = "DIR")]
+    write_journal_dir: Option<PathBuf>,
+}
+
+impl VolumeArgs {
+    /// TLS to the metadata backend, on as soon as any --meta-tls-* flag is given.
+    fn meta_tls(&self) -> Option<TlsConfig> {
+        let tls = TlsConfig {
+            ca_file: self.meta_tls_ca.clone(),
+            cert_file: self.meta_tls_cert.clone(),
+            key_file: self.meta_tls_key.clone(),
+            server_name: self.meta_tls_server_name.clone(),
+        };
+        (tls != TlsConfig::default()).then_some(tls)
+    }
+
+    fn layout(&self) -> anyhow::Result<ChunkLayout> {
+        if self.chunk_size < self.block_size as u64 {
+            anyhow::bail!("chunk_size must be >= block_size");
+        }
+        Ok(ChunkLayout {
+            chunk_size: self.chunk_size,
+            block_size: self.block_size,
+        })
+    }
+
+    fn object_client(&self) -> anyhow::Result<ObjectClient<LocalFsBackend>> {
+        if !self.data_dir.exists() {
+            std::fs::create_dir_all(&self.data_dir)?;
+        }
+        if !self.data_dir.is_dir() {
+            anyhow::bail!("data dir must be a directory");
+        }
+        Ok(ObjectClient::new(LocalFsBackend::new(&self.data_dir)))
+    }
+}
+
+impl MountArgs {
+    /// Usage reporting, on when a report file or webhook is given.
+    fn usage_report(&self) -> Option<UsageReportConfig> {
+        let mut sinks = Vec::new();
+        if let Some(path) = &self.usage_report_file {
+            let format = if path.extension().is_some_and(|ext| ext == "csv") {
+                ReportFormat::Csv
+            } else {
+                ReportFormat::Json
+            };
+            sinks.push(UsageSink::File {
+                path: path.clone(),
+                format,
+            });
+        }
+        if let Some(url) = &self.usage_report_webhook {
+            sinks.push(UsageSink::Webhook { url: url.clone() });
+        }
+        (!sinks.is_empty()).then(|| UsageReportConfig {
+            volume: self.volume_name.clone(),
+            interval_secs: self.usage_report_interval,
+            sinks,
+        })
+    }
+}
+
 #[derive(ValueEnum, Clone, Copy)]
 enum MetaBackendKind {
     Sqlx,
@@ -99,6 +287,8 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let result = match cli.cmd {
         Command::Mount(args) => mount_cmd(args).await,
+        Command::Backup(args) => backup_cmd(args).await,
+        Command::Restore(args) => restore_cmd(args).await,
     };
     shutdown_flame();
     shutdown_chrome();
@@ -174,35 +364,115 @@ async fn mount_cmd(args: MountArgs) -> anyhow::Result<()> {
         anyhow::bail!("mount point must be a directory");
     }
 
-    if !args.data_dir.exists() {
-        std::fs::create_dir_all(&args.data_dir)?;
-    }
-    if !args.data_dir.is_dir() {
-        anyhow::bail!("data dir must be a directory");
-    }
-
-    if args.chunk_size < args.block_size as u64 {
-        anyhow::bail!("chunk_size must be >= block_size");
-    }
-
-    let layout = ChunkLayout {
-        chunk_size: args.chunk_size,
-        block_size: args.block_size,
-    };
-
-    let client = ObjectClient::new(LocalFsBackend::new(&args.data_dir));
-    let store = ObjectBlockStore::new(client);
-    let meta_store = create_meta_store(&args).await?;
+    let layout = args.volume.layout()?;
+    let store = ObjectBlockStore::new(args.volume.object_client()?);
+    let meta_store = create_meta_store(&args.volume).await?;
+    let replication = meta_store
+        .as_any()
+        .downcast_ref::<ReplicatedMetaStore>()
+        .cloned();
 
     let fs = VFS::new(layout, store, meta_store)
         .await
         .map_err(anyhow::Error::from)?;
+    fs.set_replication(replication);
+    fs.set_soft_quota(SoftQuota {
+        space: args.soft_quota_space,
+        inodes: args.soft_quota_inodes,
+    });
+    fs.set_append_config(AppendConfig {
+        window: Duration::from_millis(args.append_window_ms),
+        detect_after: args.append_detect_after,
+        ..Default::default()
+    });
+    fs.set_keep_page_cache(!args.no_keep_page_cache);
+    if let Some(dir) = &args.write_journal_dir {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.journal", args.volume_name));
+        let recovery = fs
+            .recover_write_journal(&path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        if recovery.replayed > 0 {
+            println!(
+                "replayed {} writes ({} bytes) from {}",
+                recovery.replayed,
+                recovery.replayed_bytes,
+                path.display()
+            );
+        }
+        for write in &recovery.failed {
+            eprintln!(
+                "lost write of {} bytes at offset {} of inode {}: {}",
+                write.len, write.offset, write.ino, write.error
+            );
+        }
+        if let Some(saved) = &recovery.unrecovered_path {
+            eprintln!("unrecovered writes saved to {}", saved.display());
+        }
+        if recovery.truncated_tail {
+            eprintln!("{} ended in a partial record", path.display());
+        }
+    }
+    let token = CancellationToken::new();
+    let reporter = args
+        .usage_report()
+        .map(|config| tokio::spawn(start_usage_reporter(fs.clone(), config, token.clone())));
     let handle = mount_vfs_unprivileged(fs, &args.mount_point).await?;
 
     println!("mounted at {}", args.mount_point.display());
     tokio::signal::ctrl_c().await?;
     println!("unmounting...");
     handle.unmount().await?;
+    token.cancel();
+    if let Some(reporter) = reporter {
+        let _ = reporter.await;
+    }
+    Ok(())
+}
+
+async fn backup_cmd(args: BackupArgs) -> anyhow::Result<()> {
+    let layout = args.volume.layout()?;
+    let objects = args.volume.object_client()?;
+    let meta_store = create_meta_store(&args.volume).await?;
+    let name = args
+        .name
+        .unwrap_or_else(|| chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+    let manifest = backup(meta_store.as_ref(), &objects, layout, &args.dest, &name).await?;
+    println!(
+        "backup {} of {} entries: {} new objects ({} bytes), {} in total",
+        manifest.name,
+        manifest.entries.len(),
+        manifest.new_objects,
+        manifest.new_bytes,
+        manifest.objects.len()
+    );
+    Ok(())
+}
+
+async fn restore_cmd(args: RestoreArgs) -> anyhow::Result<()> {
+    let name = match args.name {
+        Some(name) => name,
+        None => latest_manifest(&args.source)?
+            .map(|m| m.name)
+            .ok_or_else(|| anyhow::anyhow!("no backup in {}", args.source.display()))?,
+    };
+    let manifest = read_manifest(&args.source, &name)?;
+    if args.volume.layout()?
+        != (ChunkLayout {
+            chunk_size: manifest.chunk_size,
+            block_size: manifest.block_size,
+        })
+    {
+        anyhow::bail!("--chunk-size and --block-size must match the ones of backup {name}");
+    }
+    let objects = args.volume.object_client()?;
+    let meta_store = create_meta_store(&args.volume).await?;
+    let summary = restore(&args.source, &name, meta_store.as_ref(), &objects).await?;
+    println!(
+        "restored {name}: {} entries, {} objects ({} bytes)",
+        summary.entries, summary.objects, summary.bytes
+    );
     Ok(())
 }
 
@@ -250,14 +520,44 @@ fn shutdown_chrome() {
 #[cfg(not(feature = "profiling"))]
 fn shutdown_chrome() {}
 
-async fn create_meta_store(args: &MountArgs) -> anyhow::Result<Arc<dyn MetaStore>> {
-    match args.meta_backend {
+async fn create_meta_store(args: &VolumeArgs) -> anyhow::Result<Arc<dyn MetaStore>> {
+    let primary = open_meta_store(
+        args,
+        args.meta_backend,
+        &args.meta_url,
+        &args.meta_etcd_urls,
+    )
+    .await?;
+    let Some(backend) = args.meta_replica_backend else {
+        return Ok(primary);
+    };
+    let secondary = open_meta_store(
+        args,
+        backend,
+        &args.meta_replica_url,
+        &args.meta_replica_etcd_urls,
+    )
+    .await?;
+    Ok(Arc::new(ReplicatedMetaStore::new(
+        primary,
+        secondary,
+        args.meta_replica_strict,
+    )))
+}
+
+async fn open_meta_store(
+    args: &VolumeArgs,
+    backend: MetaBackendKind,
+    url: &str,
+    etcd_urls: &[String],
+) -> anyhow::Result<Arc<dyn MetaStore>> {
+    match backend {
         MetaBackendKind::Sqlx => {
             let client = ClientOptions::default();
 
             let config = Config {
                 database: DatabaseConfig {
-                    db_config: database_type_from_url(&args.meta_url),
+                    db_config: database_type_from_url(url, args.meta_tls()),
                 },
                 cache: CacheConfig::default(),
                 client,
@@ -266,15 +566,18 @@ async fn create_meta_store(args: &MountArgs) -> anyhow::Result<Arc<dyn MetaStore
             Ok(handle.store() as Arc<dyn MetaStore>)
         }
         MetaBackendKind::Etcd => {
-            if args.meta_etcd_urls.is_empty() {
-                anyhow::bail!("--meta-etcd-urls must be set when --meta-backend etcd");
+            if etcd_urls.is_empty() {
+                anyhow::bail!("etcd endpoint URLs must be set for the etcd metadata backend");
             }
             let client = ClientOptions::default();
 
             let config = Config {
                 database: DatabaseConfig {
                     db_config: DatabaseType::Etcd {
-                        urls: args.meta_etcd_urls.clone(),
+                        urls: etcd_urls.to_vec(),
+                        dir_shards: args.meta_etcd_dir_shards,
+                        dir_shard_threshold: DEFAULT_DIR_SHARD_THRESHOLD,
+                        tls: args.meta_tls(),
                     },
                 },
                 cache: CacheConfig::default(),
@@ -286,11 +589,12 @@ async fn create_meta_store(args: &MountArgs) -> anyhow::Result<Arc<dyn MetaStore
     }
 }
 
-fn database_type_from_url(url: &str) -> DatabaseType {
+fn database_type_from_url(url: &str, tls: Option<TlsConfig>) -> DatabaseType {
     let lower = url.to_ascii_lowercase();
     if lower.starts_with("postgres://") || lower.starts_with("postgresql://") {
         DatabaseType::Postgres {
             url: url.to_string(),
+            tls,
         }
     } else {
         DatabaseType::Sqlite {
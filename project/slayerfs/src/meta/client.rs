@@ -7,6 +7,7 @@ use crate::chuck::SliceDesc;
 use crate::meta::config::{CacheCapacity, CacheTtl};
 use crate::meta::file_lock::{FileLockInfo, FileLockQuery, FileLockRange, FileLockType};
 use crate::meta::layer::MetaLayer;
+use crate::meta::name;
 use crate::meta::store::{
     AclRule, DirEntry, FileAttr, MetaError, MetaStore, OpenFlags, SetAttrFlags, SetAttrRequest,
     StatFsSnapshot,
@@ -1093,6 +1094,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn lookup(&self, parent: i64, name: &str) -> Result<Option<i64>, MetaError> {
+        name::check_len(name)?;
         self.cached_lookup(parent, name).await
     }
 
@@ -1198,12 +1200,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         self.ensure_writable()?;
         let parent = self.check_root(parent);
 
-        if name.is_empty() || name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-        if name.contains('/') || name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(&name)?;
 
         info!("MetaClient: mkdir operation for ({}, '{}')", parent, name);
 
@@ -1236,6 +1233,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn rmdir(&self, parent: i64, name: &str) -> Result<(), MetaError> {
         self.ensure_writable()?;
+        name::check_len(name)?;
         let parent = self.check_root(parent);
         info!("MetaClient: rmdir operation for ({}, '{}')", parent, name);
 
@@ -1254,6 +1252,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
     async fn create_file(&self, parent: i64, name: String) -> Result<i64, MetaError> {
         self.ensure_writable()?;
         let parent = self.check_root(parent);
+        name::validate(&name)?;
         info!(
             "MetaClient: create_file operation for ({}, '{}')",
             parent, name
@@ -1294,12 +1293,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         let inode = self.check_root(ino);
         let parent = self.check_root(parent);
 
-        if name.is_empty() || name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-        if name.contains('/') || name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(name)?;
 
         info!(
             "MetaClient: link operation for inode {} into ({}, '{}')",
@@ -1340,12 +1334,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         self.ensure_writable()?;
         let parent = self.check_root(parent);
 
-        if name.is_empty() || name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-        if name.contains('/') || name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(name)?;
 
         // POSIX: symlink target path component must also respect NAME_MAX
         if target.len() > NAME_MAX {
@@ -1391,12 +1380,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         self.ensure_writable()?;
 
         // Validate filename length BEFORE lookup to return ENAMETOOLONG instead of ENOENT
-        if name.is_empty() || name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-        if name.contains('/') || name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(name)?;
 
         let parent = self.check_root(parent);
         info!("MetaClient: unlink operation for ({}, '{}')", parent, name);
@@ -1457,13 +1441,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         }
 
         // Validate name constraints
-        if new_name.is_empty() || new_name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-
-        if new_name.contains('/') || new_name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(&new_name)?;
 
         // Execute the store-level rename with atomic cache updates
         let old_name_for_store = old_name.to_string();
@@ -1683,13 +1661,7 @@ impl<T: MetaStore + 'static> MetaLayer for MetaClient<T> {
         }
 
         // Validate name constraints
-        if new_name.is_empty() || new_name.len() > NAME_MAX {
-            return Err(MetaError::InvalidFilename);
-        }
-
-        if new_name.contains('/') || new_name.contains('\0') {
-            return Err(MetaError::InvalidFilename);
-        }
+        name::validate(new_name)?;
 
         // Check destination constraints
         if let Some(dest_ino) = self.cached_lookup(new_parent, new_name).await? {
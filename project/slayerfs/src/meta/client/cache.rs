@@ -4,6 +4,7 @@ use std::time::Duration;
 
 use crate::chuck::SliceDesc;
 use crate::meta::entities::etcd::EtcdEntryInfo;
+use crate::meta::name;
 use crate::meta::store::{DirEntry, FileAttr, MetaError, MetaStore};
 use crate::vfs::fs::FileType;
 use dashmap::{DashMap, Entry};
@@ -250,6 +251,8 @@ impl InodeCache {
                 kind,
             });
         }
+        // String order only differs from byte order for names with escaped bytes.
+        entries.sort_by(|a, b| name::cmp(&a.name, &b.name));
 
         Some(entries)
     }
@@ -3,6 +3,7 @@
 //! Database connection configuration supporting SQLite, PostgreSQL and Etcd
 
 use crate::meta::client::MetaClientOptions;
+use crate::utils::tls::TlsConfig;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::time::Duration;
@@ -39,17 +40,46 @@ pub enum DatabaseType {
         url: String,
     },
     #[serde(rename = "postgres")]
-    Postgres { url: String },
+    Postgres {
+        url: String,
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
     #[serde(rename = "etcd")]
-    Etcd { urls: Vec<String> },
+    Etcd {
+        /// Endpoints, IPv6 addresses in brackets (`[::1]:2379`). Without a scheme `https://`
+        /// is assumed when `tls` is set.
+        urls: Vec<String>,
+        /// Split the children index of a directory into this many hash-partitioned keys once
+        /// it holds `dir_shard_threshold` entries, so creates in a hot directory stop
+        /// contending on one key. 0 or 1 disables sharding.
+        #[serde(default)]
+        dir_shards: u32,
+        #[serde(default = "default_dir_shard_threshold")]
+        dir_shard_threshold: usize,
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
+    /// TLS needs a `rediss://` URL.
     #[serde(rename = "redis")]
-    Redis { url: String },
+    Redis {
+        url: String,
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
 }
 
 fn default_sqlite_url() -> String {
     "sqlite:///tmp/slayerfs/metadata.db".to_string()
 }
 
+/// Entries an etcd directory holds before its children index is sharded.
+pub const DEFAULT_DIR_SHARD_THRESHOLD: usize = 1024;
+
+fn default_dir_shard_threshold() -> usize {
+    DEFAULT_DIR_SHARD_THRESHOLD
+}
+
 #[allow(dead_code)]
 impl Config {
     /// Load configuration from YAML file
@@ -50,12 +50,20 @@ pub struct EtcdForwardEntry {
 pub struct EtcdDirChildren {
     pub inode: i64,
     pub children: HashMap<String, i64>,
+    /// Number of shard keys holding the children when the directory is sharded, `children`
+    /// is empty then. 0 for a directory kept in this single key.
+    #[serde(default)]
+    pub shards: u32,
 }
 
 impl EtcdDirChildren {
     /// Create new children collection with name->inode mapping
     pub fn new(inode: i64, children: HashMap<String, i64>) -> Self {
-        Self { inode, children }
+        Self {
+            inode,
+            children,
+            shards: 0,
+        }
     }
 }
 #[allow(dead_code)]
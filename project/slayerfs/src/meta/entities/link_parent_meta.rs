@@ -10,8 +10,9 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub parent_inode: i64,
 
-    #[sea_orm(primary_key, auto_increment = false, column_type = "Text")]
-    pub entry_name: String,
+    /// Raw name bytes, see [`crate::meta::name`].
+    #[sea_orm(primary_key, auto_increment = false, column_type = "Blob")]
+    pub entry_name: Vec<u8>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
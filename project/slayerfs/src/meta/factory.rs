@@ -252,6 +252,7 @@ pub async fn create_redis_meta_store_from_url(
         database: DatabaseConfig {
             db_config: DatabaseType::Redis {
                 url: url.to_string(),
+                tls: None,
             },
         },
         cache: CacheConfig::default(),
@@ -1,6 +1,168 @@
-//! DB migration helpers (placeholder)
+//! Schema migrations of the SQL metadata store.
+//!
+//! `DatabaseMetaStore::init_schema` creates missing tables from the entities and leaves
+//! existing ones alone, so tables written by an older slayerfs are brought up to date here,
+//! before the schema is initialized.
 
-#[allow(dead_code)]
-pub(crate) fn ensure_migrations() {
-    // TODO: implement migration runner
+use crate::meta::entities::{ContentMeta, LinkParentMeta};
+use crate::meta::store::MetaError;
+use log::info;
+use sea_orm::sea_query::TableCreateStatement;
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbBackend, Schema, Statement, TransactionTrait,
+};
+
+/// Run the migrations a database created by an older slayerfs needs.
+pub(crate) async fn run(db: &DatabaseConnection) -> Result<(), MetaError> {
+    let schema = Schema::new(db.get_database_backend());
+    migrate_byte_names(
+        db,
+        "content_meta",
+        &["parent_inode", "entry_name", "inode", "entry_type"],
+        schema.create_table_from_entity(ContentMeta),
+    )
+    .await?;
+    migrate_byte_names(
+        db,
+        "link_parent_meta",
+        &["inode", "parent_inode", "entry_name"],
+        schema.create_table_from_entity(LinkParentMeta),
+    )
+    .await
+}
+
+/// Declared type of `column` in `table`, `None` if the table doesn't exist.
+async fn column_type(
+    db: &DatabaseConnection,
+    table: &str,
+    column: &str,
+) -> Result<Option<String>, MetaError> {
+    let backend = db.get_database_backend();
+    let stmt = match backend {
+        DbBackend::Sqlite => Statement::from_sql_and_values(
+            backend,
+            "SELECT type AS column_type FROM pragma_table_info(?) WHERE name = ?",
+            [table.into(), column.into()],
+        ),
+        DbBackend::Postgres => Statement::from_sql_and_values(
+            backend,
+            "SELECT data_type AS column_type FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = $1 AND column_name = $2",
+            [table.into(), column.into()],
+        ),
+        _ => return Ok(None),
+    };
+    let row = db.query_one(stmt).await.map_err(MetaError::Database)?;
+    row.map(|row| row.try_get::<String>("", "column_type"))
+        .transpose()
+        .map_err(MetaError::Database)
+}
+
+/// Entry names used to be stored as text, which can't hold names that aren't UTF-8. Convert
+/// the `entry_name` column of `table` to raw bytes, keeping the UTF-8 encoding of the names
+/// already stored.
+async fn migrate_byte_names(
+    db: &DatabaseConnection,
+    table: &str,
+    columns: &[&str],
+    create: TableCreateStatement,
+) -> Result<(), MetaError> {
+    let backend = db.get_database_backend();
+    let Some(ty) = column_type(db, table, "entry_name").await? else {
+        return Ok(());
+    };
+    let stmts = match backend {
+        DbBackend::Sqlite if !ty.eq_ignore_ascii_case("blob") => {
+            // SQLite can't change the type of a column, rebuild the table instead. Its
+            // indexes go with the old table and are recreated by `init_schema`.
+            let old = format!("{table}_text_names");
+            let select = columns
+                .iter()
+                .map(|&col| match col {
+                    "entry_name" => "CAST(entry_name AS BLOB)".to_string(),
+                    col => col.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            vec![
+                Statement::from_string(backend, format!("ALTER TABLE {table} RENAME TO {old}")),
+                backend.build(&create),
+                Statement::from_string(
+                    backend,
+                    format!(
+                        "INSERT INTO {table} ({}) SELECT {select} FROM {old}",
+                        columns.join(", ")
+                    ),
+                ),
+                Statement::from_string(backend, format!("DROP TABLE {old}")),
+            ]
+        }
+        DbBackend::Postgres if ty != "bytea" => vec![Statement::from_string(
+            backend,
+            format!(
+                "ALTER TABLE {table} ALTER COLUMN entry_name TYPE bytea \
+                 USING convert_to(entry_name, 'UTF8')"
+            ),
+        )],
+        _ => return Ok(()),
+    };
+
+    info!("Migrating {table}.entry_name from {ty} to bytes");
+    let txn = db.begin().await.map_err(MetaError::Database)?;
+    for stmt in stmts {
+        txn.execute(stmt).await.map_err(MetaError::Database)?;
+    }
+    txn.commit().await.map_err(MetaError::Database)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::entities::content_meta;
+    use sea_orm::{ColumnTrait, Database, EntityTrait, QueryFilter};
+
+    #[tokio::test]
+    async fn test_migrate_text_entry_names() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            temp_dir.path().join("old.db").display()
+        );
+        let db = Database::connect(&url).await.expect("connect");
+        for sql in [
+            "CREATE TABLE content_meta (parent_inode bigint NOT NULL, \
+             entry_name varchar NOT NULL, inode bigint NOT NULL, entry_type integer NOT NULL, \
+             PRIMARY KEY (parent_inode, entry_name))",
+            "INSERT INTO content_meta VALUES (1, 'caf\u{e9}', 2, 0)",
+        ] {
+            db.execute(Statement::from_string(DbBackend::Sqlite, sql))
+                .await
+                .expect("old schema");
+        }
+
+        run(&db).await.expect("migrate");
+        assert_eq!(
+            column_type(&db, "content_meta", "entry_name")
+                .await
+                .unwrap()
+                .map(|ty| ty.to_ascii_lowercase()),
+            Some("blob".to_string())
+        );
+        let entry = ContentMeta::find()
+            .filter(content_meta::Column::EntryName.eq("caf\u{e9}".as_bytes().to_vec()))
+            .one(&db)
+            .await
+            .unwrap()
+            .expect("entry kept");
+        assert_eq!(entry.inode, 2);
+
+        // Tables already storing bytes, or not created yet, are left alone.
+        run(&db).await.expect("migrate again");
+        assert!(
+            column_type(&db, "link_parent_meta", "entry_name")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
 }
@@ -8,7 +8,7 @@
 //!   and cleanup.
 //!
 //! Important notes / TODOs:
-//! - Implement DB migrations and schema versioning.
+//! - Version the schema; migrations only detect outdated columns for now.
 //! - Ensure critical write-path updates (blocks + slice_blocks + slices + inode.size)
 //!   are committed atomically.
 //!
@@ -20,6 +20,7 @@ pub mod factory;
 pub mod file_lock;
 pub mod layer;
 pub(crate) mod migrations;
+pub mod name;
 pub mod permission;
 pub(crate) mod serialization;
 pub mod store;
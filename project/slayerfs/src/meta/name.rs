@@ -0,0 +1,167 @@
+//! Lossless encoding of raw directory entry names.
+//!
+//! POSIX names are arbitrary bytes (except `/` and NUL), while the metadata API passes
+//! names around as `String`. Bytes that are not valid UTF-8 are mapped one-to-one onto
+//! the last 128 code points of the supplementary private use area (`U+10FF80..=U+10FFFF`),
+//! so every name has exactly one `String` form and can be turned back into its original
+//! bytes. A name that already contains one of those code points has it escaped byte by
+//! byte as well, which keeps the mapping unambiguous.
+//!
+//! The SQL backend stores the decoded bytes directly. The KV backends keep the encoded
+//! form in their dentry keys and hash fields, so a prefix scan over etcd forward keys runs
+//! in encoded order, which puts escaped bytes after every valid 4-byte UTF-8 sequence.
+//! Their readdir sorts with [`cmp`] so that its order matches plain byte order everywhere.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use crate::meta::store::MetaError;
+use crate::posix::NAME_MAX;
+
+/// First code point of the escape range, byte `b` maps to `ESCAPE_BASE + b`.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+fn escape(byte: u8) -> char {
+    debug_assert!(byte >= 0x80);
+    char::from_u32(ESCAPE_BASE + byte as u32).expect("escape range is valid")
+}
+
+fn unescape(c: char) -> Option<u8> {
+    let c = c as u32;
+    (c >= ESCAPE_BASE + 0x80).then(|| (c - ESCAPE_BASE) as u8)
+}
+
+fn needs_escape(name: &str) -> bool {
+    name.chars().any(|c| unescape(c).is_some())
+}
+
+/// Encode raw name bytes into their `String` form.
+pub fn from_bytes(raw: &[u8]) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for chunk in raw.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if unescape(c).is_some() {
+                let mut buf = [0u8; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .for_each(|b| out.push(escape(b)));
+            } else {
+                out.push(c);
+            }
+        }
+        chunk.invalid().iter().for_each(|&b| out.push(escape(b)));
+    }
+    out
+}
+
+/// Decode a name produced by [`from_bytes`] back into its raw bytes.
+pub fn to_bytes(name: &str) -> Cow<'_, [u8]> {
+    if !needs_escape(name) {
+        return Cow::Borrowed(name.as_bytes());
+    }
+    let mut out = Vec::with_capacity(name.len());
+    let mut buf = [0u8; 4];
+    for c in name.chars() {
+        match unescape(c) {
+            Some(b) => out.push(b),
+            None => out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Encode a name received from the kernel.
+pub fn from_os(name: &OsStr) -> String {
+    from_bytes(name.as_bytes())
+}
+
+/// Decode a name for handing back to the kernel.
+pub fn to_os(name: &str) -> OsString {
+    OsString::from_vec(to_bytes(name).into_owned())
+}
+
+/// Compare two encoded names by their raw bytes.
+pub fn cmp(a: &str, b: &str) -> Ordering {
+    to_bytes(a).cmp(&to_bytes(b))
+}
+
+/// Check a name before it becomes a directory entry: not empty, without `/` or NUL, and
+/// at most [`NAME_MAX`] raw bytes.
+pub fn validate(name: &str) -> Result<(), MetaError> {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        return Err(MetaError::InvalidFilename);
+    }
+    check_len(name)
+}
+
+/// Reject a name longer than [`NAME_MAX`] raw bytes. Escaped bytes count once, so this
+/// is what the kernel checked, not the length of the encoded form.
+pub fn check_len(name: &str) -> Result<(), MetaError> {
+    let len = to_bytes(name).len();
+    if len > NAME_MAX {
+        return Err(MetaError::NameTooLong(len));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf8_names_unchanged() {
+        for name in ["", "hello.txt", "目录", "emoji-🦀"] {
+            assert_eq!(from_bytes(name.as_bytes()), name);
+            assert!(matches!(to_bytes(name), Cow::Borrowed(_)));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let latin1 = b"caf\xe9.txt".to_vec();
+        let escape_char = "\u{10FFAB}".as_bytes().to_vec();
+        let mixed = [b"a\xff".as_slice(), "\u{10FF80}".as_bytes(), b"\x80z"].concat();
+        for raw in [latin1, escape_char, mixed, (0x80..=0xff).collect()] {
+            let name = from_bytes(&raw);
+            assert_eq!(to_bytes(&name).as_ref(), raw.as_slice());
+            assert_eq!(to_os(&name).as_bytes(), raw.as_slice());
+        }
+        // Distinct byte strings never share an encoding.
+        assert_ne!(from_bytes("\u{10FF80}".as_bytes()), from_bytes(b"\x80"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&"a".repeat(NAME_MAX)).is_ok());
+        assert!(matches!(
+            validate(&"a".repeat(NAME_MAX + 1)),
+            Err(MetaError::NameTooLong(256))
+        ));
+        // Non-UTF-8 bytes take 4 bytes each once encoded, but count as one.
+        assert!(validate(&from_bytes(&[0xff; NAME_MAX])).is_ok());
+        for bad in ["", "a/b", "a\0b"] {
+            assert!(matches!(validate(bad), Err(MetaError::InvalidFilename)));
+        }
+    }
+
+    #[test]
+    fn test_byte_order() {
+        let mut names: Vec<String> = [b"\xe9".as_slice(), b"z", "é".as_bytes(), b"a"]
+            .iter()
+            .map(|raw| from_bytes(raw))
+            .collect();
+        names.sort_by(|a, b| cmp(a, b));
+        let raw: Vec<Vec<u8>> = names.iter().map(|n| to_bytes(n).into_owned()).collect();
+        assert_eq!(
+            raw,
+            vec![
+                b"a".to_vec(),
+                b"z".to_vec(),
+                "é".as_bytes().to_vec(),
+                b"\xe9".to_vec()
+            ]
+        );
+    }
+}
@@ -211,12 +211,14 @@ pub struct LoadOption {
 #[derive(Debug)]
 pub enum LockName {
     CleanupSessionsLock,
+    CleanupUploadsLock,
 }
 
 impl fmt::Display for LockName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LockName::CleanupSessionsLock => write!(f, "CleanupSessionsLock"),
+            LockName::CleanupUploadsLock => write!(f, "CleanupUploadsLock"),
         }
     }
 }
@@ -266,6 +268,9 @@ pub enum MetaError {
     #[error("Invalid filename")]
     InvalidFilename,
 
+    #[error("Name too long: {0} bytes")]
+    NameTooLong(usize),
+
     #[error(
         "More than max_symlinks symbolic links were encountered during resolution of the path."
     )]
@@ -18,13 +18,15 @@ use crate::meta::store::{
     DirEntry, FileAttr, LockName, MetaError, MetaStore, SetAttrFlags, SetAttrRequest,
 };
 use crate::meta::stores::pool::IdPool;
-use crate::meta::{INODE_ID_KEY, Permission};
+use crate::meta::{INODE_ID_KEY, Permission, name};
+use crate::utils::tls::TlsConfig;
 use crate::vfs::chunk_id_for;
 use crate::vfs::fs::FileType;
 use async_trait::async_trait;
 use chrono::{Duration, Utc};
 use etcd_client::{
-    Client as EtcdClient, Compare, CompareOp, LeaseKeeper, PutOptions, Txn, TxnOp, TxnOpResponse,
+    Certificate, Client as EtcdClient, Compare, CompareOp, ConnectOptions, Identity, LeaseKeeper,
+    PutOptions, TlsOptions, Txn, TxnOp, TxnOpResponse,
 };
 use serde::Serialize;
 use serde::de::DeserializeOwned;
@@ -302,6 +304,10 @@ pub struct EtcdMetaStore {
     id_pools: IdPool,
     sid: OnceLock<Uuid>,
     lease: OnceLock<i64>,
+    /// Shards a directory's children index is split into, 0 or 1 to never shard
+    dir_shards: u32,
+    /// Entries at which a directory gets sharded
+    dir_shard_threshold: usize,
 }
 
 #[allow(dead_code)]
@@ -321,6 +327,35 @@ impl EtcdMetaStore {
         format!("c:{}", inode)
     }
 
+    /// Etcd helper method: generate the key of one shard of a sharded children index
+    fn etcd_children_shard_key(inode: i64, shard: u32) -> String {
+        format!("c:{}:{}", inode, shard)
+    }
+
+    /// Shard of a sharded directory that holds `name`.
+    ///
+    /// FNV-1a rather than the std hasher, every client has to agree on the placement.
+    fn children_shard_of(name: &str, shards: u32) -> u32 {
+        let mut hash: u32 = 0x811c_9dc5;
+        for byte in name.as_bytes() {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        hash % shards
+    }
+
+    /// Directory sharding settings of an etcd config, `(dir_shards, dir_shard_threshold)`
+    fn dir_shard_settings(config: &Config) -> (u32, usize) {
+        match &config.database.db_config {
+            DatabaseType::Etcd {
+                dir_shards,
+                dir_shard_threshold,
+                ..
+            } => (*dir_shards, *dir_shard_threshold),
+            _ => (0, 0),
+        }
+    }
+
     fn etcd_session_key(session_id: Option<Uuid>) -> String {
         match session_id {
             Some(id) => format!("session:{}", id),
@@ -359,12 +394,15 @@ impl EtcdMetaStore {
         info!("Backend path: {}", backend_path.display());
 
         let client = Self::create_client(&_config).await?;
+        let (dir_shards, dir_shard_threshold) = Self::dir_shard_settings(&_config);
         let store = Self {
             client,
             _config,
             id_pools: IdPool::default(),
             sid: OnceLock::new(),
             lease: OnceLock::new(),
+            dir_shards,
+            dir_shard_threshold,
         };
         store.init_root_directory().await?;
 
@@ -377,12 +415,15 @@ impl EtcdMetaStore {
         info!("Initializing EtcdMetaStore from config");
 
         let client = Self::create_client(&_config).await?;
+        let (dir_shards, dir_shard_threshold) = Self::dir_shard_settings(&_config);
         let store = Self {
             client,
             _config,
             id_pools: IdPool::default(),
             sid: OnceLock::new(),
             lease: OnceLock::new(),
+            dir_shards,
+            dir_shard_threshold,
         };
         store.init_root_directory().await?;
 
@@ -393,9 +434,10 @@ impl EtcdMetaStore {
     /// Create etcd client
     async fn create_client(config: &Config) -> Result<EtcdClient, MetaError> {
         match &config.database.db_config {
-            DatabaseType::Etcd { urls } => {
+            DatabaseType::Etcd { urls, tls, .. } => {
                 info!("Connecting to Etcd cluster: {:?}", urls);
-                let client = EtcdClient::connect(urls, None)
+                let options = tls.as_ref().map(Self::connect_options).transpose()?;
+                let client = EtcdClient::connect(urls, options)
                     .await
                     .map_err(|e| MetaError::Config(format!("Failed to connect to Etcd: {}", e)))?;
                 Ok(client)
@@ -411,6 +453,26 @@ impl EtcdMetaStore {
         }
     }
 
+    /// Etcd connect options for `tls`.
+    ///
+    /// The etcd client has no system roots enabled, so `ca_file` is required.
+    fn connect_options(tls: &TlsConfig) -> Result<ConnectOptions, MetaError> {
+        let invalid =
+            |e: std::io::Error| MetaError::Config(format!("Invalid etcd TLS config: {e}"));
+        let ca = tls
+            .ca_pem()
+            .map_err(invalid)?
+            .ok_or_else(|| MetaError::Config("Etcd TLS requires ca_file".to_string()))?;
+        let mut options = TlsOptions::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some((cert, key)) = tls.identity_pem().map_err(invalid)? {
+            options = options.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(name) = &tls.server_name {
+            options = options.domain_name(name.clone());
+        }
+        Ok(ConnectOptions::new().with_tls(options))
+    }
+
     /// Helper: get key from etcd and deserialize JSON into T.
     ///
     /// Strict variant: returns Err(MetaError::Internal) when etcd client returns error.
@@ -730,11 +792,8 @@ impl EtcdMetaStore {
         &self,
         parent_inode: i64,
     ) -> Result<Option<Vec<ContentMetaModel>>, MetaError> {
-        let children_key = Self::etcd_children_key(parent_inode);
         // strict read of children list
-        let dir_children_opt = self
-            .etcd_get_json_lenient::<EtcdDirChildren>(&children_key)
-            .await?;
+        let dir_children_opt = self.load_dir_children(parent_inode).await?;
         let dir_children = match dir_children_opt {
             Some(dc) => dc,
             None => return Ok(None),
@@ -782,9 +841,9 @@ impl EtcdMetaStore {
         };
 
         let mut content_list = Vec::new();
-        // Sort children names to ensure consistent order (matching BTreeMap in cache)
+        // Sort children by raw name bytes so readdir order matches the other backends
         let mut sorted_names: Vec<_> = dir_children.children.keys().collect();
-        sorted_names.sort();
+        sorted_names.sort_by(|a, b| name::cmp(a, b));
 
         for child_name in sorted_names {
             if let Some(forward_entry) = forward_entries_map.get(child_name.as_str()) {
@@ -793,7 +852,7 @@ impl EtcdMetaStore {
                 content_list.push(ContentMetaModel {
                     inode: forward_entry.inode,
                     parent_inode,
-                    entry_name: child_name.clone(),
+                    entry_name: name::to_bytes(child_name).into_owned(),
                     entry_type,
                 });
             }
@@ -806,6 +865,39 @@ impl EtcdMetaStore {
         }
     }
 
+    /// Read the children index of a directory.
+    ///
+    /// For a sharded directory the shard keys are merged into `children`, `shards` is kept so
+    /// callers can still address them.
+    async fn load_dir_children(&self, ino: i64) -> Result<Option<EtcdDirChildren>, MetaError> {
+        let children_key = Self::etcd_children_key(ino);
+        let Some(mut dir) = self
+            .etcd_get_json_lenient::<EtcdDirChildren>(&children_key)
+            .await?
+        else {
+            return Ok(None);
+        };
+        if dir.shards == 0 {
+            return Ok(Some(dir));
+        }
+
+        let mut client = self.client.clone();
+        let resp = client
+            .get(
+                format!("{children_key}:"),
+                Some(etcd_client::GetOptions::new().with_prefix()),
+            )
+            .await
+            .map_err(|e| {
+                MetaError::Internal(format!("Failed to fetch children shards of {ino}: {e}"))
+            })?;
+        for kv in resp.kvs() {
+            let shard: EtcdDirChildren = crate::meta::serialization::deserialize_meta(kv.value())?;
+            dir.children.extend(shard.children);
+        }
+        Ok(Some(dir))
+    }
+
     /// Get file metadata
     async fn get_file_meta(&self, inode: i64) -> Result<Option<FileMetaModel>, MetaError> {
         let reverse_key = Self::etcd_reverse_key(inode);
@@ -841,17 +933,6 @@ impl EtcdMetaStore {
         }
         let parent_meta = parent_meta.unwrap();
 
-        if let Some(contents) = self.get_content_meta(parent_inode).await? {
-            for content in contents {
-                if content.entry_name == name {
-                    return Err(MetaError::AlreadyExists {
-                        parent: parent_inode,
-                        name,
-                    });
-                }
-            }
-        }
-
         let inode = self.generate_id(INODE_ID_KEY).await?;
 
         let now = Utc::now().timestamp_nanos_opt().unwrap_or(0);
@@ -927,16 +1008,8 @@ impl EtcdMetaStore {
         // If this fails, forward/reverse/children keys are created
         // but parent's children map is not updated. Consider using compensation or
         // background reconciliation.
-        let name_for_closure = name.clone();
-        let inode_for_closure = inode;
         match self
-            .update_parent_children(
-                parent_inode,
-                move |children| {
-                    children.insert(name_for_closure.clone(), inode_for_closure);
-                },
-                10,
-            )
+            .update_parent_children(parent_inode, &[(&name, Some(inode))], 10)
             .await
         {
             Ok(_) => {
@@ -989,17 +1062,6 @@ impl EtcdMetaStore {
         }
         let parent_meta = parent_meta.unwrap();
 
-        if let Some(contents) = self.get_content_meta(parent_inode).await? {
-            for content in contents {
-                if content.entry_name == name {
-                    return Err(MetaError::AlreadyExists {
-                        parent: parent_inode,
-                        name,
-                    });
-                }
-            }
-        }
-
         let inode = self.generate_id(INODE_ID_KEY).await?;
 
         let now = Utc::now().timestamp_nanos_opt().unwrap_or(0);
@@ -1061,16 +1123,8 @@ impl EtcdMetaStore {
         // Step 3: Update parent's children set
         // If this fails, forward/reverse keys are created
         // but parent's children map is not updated. Rollback is attempted below.
-        let name_for_closure = name.clone();
-        let inode_for_closure = inode;
         match self
-            .update_parent_children(
-                parent_inode,
-                move |children| {
-                    children.insert(name_for_closure.clone(), inode_for_closure);
-                },
-                10,
-            )
+            .update_parent_children(parent_inode, &[(&name, Some(inode))], 10)
             .await
         {
             Ok(_) => {
@@ -1317,34 +1371,200 @@ impl EtcdMetaStore {
 
     /// Update parent directory children
     ///
-    /// Uses optimistic concurrency control to safely update the children map
-    /// in multi-client scenarios. Retries on conflicts up to max_retries.
+    /// Each change inserts (`Some(ino)`) or removes (`None`) one name. Uses optimistic
+    /// concurrency control to safely update the children map in multi-client scenarios,
+    /// retrying on conflicts up to max_retries. Only the shards holding the changed names are
+    /// rewritten when the directory is sharded, and an unsharded directory growing past the
+    /// configured threshold gets sharded afterwards.
     async fn update_parent_children(
         &self,
         parent_ino: i64,
-        updater: impl Fn(&mut HashMap<String, i64>) + Send + Sync,
+        changes: &[(&str, Option<i64>)],
         max_retries: usize,
     ) -> Result<(), MetaError> {
+        fn apply(children: &mut HashMap<String, i64>, changes: &[(&str, Option<i64>)]) {
+            for (name, ino) in changes {
+                match ino {
+                    Some(ino) => children.insert(name.to_string(), *ino),
+                    None => children.remove(*name),
+                };
+            }
+        }
+
         let key = Self::etcd_children_key(parent_ino);
+        let client = self.client.clone();
 
-        self.atomic_update(
-            &key,
-            |dir: EtcdDirChildren| {
-                let mut children = dir.children;
-                updater(&mut children);
-                Ok((EtcdDirChildren::new(parent_ino, children), ()))
-            },
-            || {
-                let mut children = HashMap::new();
-                updater(&mut children);
-                Ok((EtcdDirChildren::new(parent_ino, children), ()))
-            },
-            max_retries as u64,
-            &None,
-        )
-        .await
-        .map(|_| ())
-        .map_err(|e| MetaError::Internal(format!("Update parent children failed: {e}")))
+        let attempt = || {
+            let mut client = client.clone();
+            let key = key.as_str();
+
+            async move {
+                let resp = client
+                    .get(key, None)
+                    .await
+                    .map_err(|e| MetaError::Config(format!("Failed to get key: {e}")))?;
+                let (dir, root_compare) = match resp.kvs().first() {
+                    Some(kv) => (
+                        crate::meta::serialization::deserialize_meta::<EtcdDirChildren>(
+                            kv.value(),
+                        )?,
+                        Compare::mod_revision(key, CompareOp::Equal, kv.mod_revision()),
+                    ),
+                    None => (
+                        EtcdDirChildren::new(parent_ino, HashMap::new()),
+                        Compare::version(key, CompareOp::Equal, 0),
+                    ),
+                };
+
+                let (compares, ops, len) = if dir.shards == 0 {
+                    let mut children = dir.children;
+                    apply(&mut children, changes);
+                    let len = children.len();
+                    let value = crate::meta::serialization::serialize_meta(&EtcdDirChildren::new(
+                        parent_ino, children,
+                    ))?;
+                    (
+                        vec![root_compare],
+                        vec![TxnOp::put(key, value, None)],
+                        Some(len),
+                    )
+                } else {
+                    let mut by_shard: HashMap<u32, Vec<(&str, Option<i64>)>> = HashMap::new();
+                    for change in changes {
+                        by_shard
+                            .entry(Self::children_shard_of(change.0, dir.shards))
+                            .or_default()
+                            .push(*change);
+                    }
+                    let shard_keys: Vec<String> = by_shard
+                        .keys()
+                        .map(|shard| Self::etcd_children_shard_key(parent_ino, *shard))
+                        .collect();
+                    let ctx = TxnContext::fetch(&mut client, &shard_keys).await?;
+
+                    // The root is compared too, so a concurrent re-shard can't be raced.
+                    let mut compares = vec![root_compare];
+                    let mut ops = Vec::new();
+                    for (shard_changes, shard_key) in by_shard.values().zip(&shard_keys) {
+                        let mut children = match ctx.value(shard_key) {
+                            Some(value) => {
+                                crate::meta::serialization::deserialize_meta::<EtcdDirChildren>(
+                                    value,
+                                )?
+                                .children
+                            }
+                            None => HashMap::new(),
+                        };
+                        apply(&mut children, shard_changes);
+                        let value = crate::meta::serialization::serialize_meta(
+                            &EtcdDirChildren::new(parent_ino, children),
+                        )?;
+                        compares.push(ctx.compare_for(shard_key)?);
+                        ops.push(TxnOp::put(shard_key.as_str(), value, None));
+                    }
+                    (compares, ops, None)
+                };
+
+                match client.txn(Txn::new().when(compares).and_then(ops)).await {
+                    Ok(resp) if resp.succeeded() => Ok(len),
+                    Ok(_) => Err(MetaError::ContinueRetry),
+                    Err(e) => Err(MetaError::Internal(format!(
+                        "Failed to execute transaction: {e}"
+                    ))),
+                }
+            }
+        };
+
+        let len = backoff(max_retries as u64, attempt)
+            .await
+            .map_err(|e| MetaError::Internal(format!("Update parent children failed: {e}")))?;
+
+        if let Some(len) = len
+            && self.dir_shards > 1
+            && len >= self.dir_shard_threshold
+            && let Err(e) = self.shard_directory(parent_ino, self.dir_shards).await
+        {
+            warn!(
+                "Failed to shard children index: inode={}, entries={}, error={}",
+                parent_ino, len, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Split the children index of directory `ino` into `shards` hash-partitioned keys.
+    ///
+    /// Creates and removes in the directory then only rewrite the shard holding the name
+    /// instead of the whole map, so they stop conflicting with each other. A directory that is
+    /// already sharded is left as it is. `shards` is bounded by etcd's limit on operations in
+    /// one transaction.
+    pub async fn shard_directory(&self, ino: i64, shards: u32) -> Result<(), MetaError> {
+        if !(2..=64).contains(&shards) {
+            return Err(MetaError::Internal(format!(
+                "Invalid children shard count {shards}, expected 2..=64"
+            )));
+        }
+
+        let key = Self::etcd_children_key(ino);
+        let client = self.client.clone();
+
+        let attempt = || {
+            let mut client = client.clone();
+            let key = key.as_str();
+
+            async move {
+                let resp = client
+                    .get(key, None)
+                    .await
+                    .map_err(|e| MetaError::Config(format!("Failed to get key: {e}")))?;
+                let Some(kv) = resp.kvs().first() else {
+                    return Err(MetaError::NotFound(ino));
+                };
+                let dir: EtcdDirChildren =
+                    crate::meta::serialization::deserialize_meta(kv.value())?;
+                if dir.shards != 0 {
+                    return Ok(());
+                }
+
+                let mut split: Vec<HashMap<String, i64>> = vec![HashMap::new(); shards as usize];
+                for (name, child) in dir.children {
+                    split[Self::children_shard_of(&name, shards) as usize].insert(name, child);
+                }
+
+                let mut ops = Vec::with_capacity(shards as usize + 1);
+                for (shard, children) in split.into_iter().enumerate() {
+                    let value = crate::meta::serialization::serialize_meta(&EtcdDirChildren::new(
+                        ino, children,
+                    ))?;
+                    ops.push(TxnOp::put(
+                        Self::etcd_children_shard_key(ino, shard as u32),
+                        value,
+                        None,
+                    ));
+                }
+                let mut root = EtcdDirChildren::new(ino, HashMap::new());
+                root.shards = shards;
+                ops.push(TxnOp::put(
+                    key,
+                    crate::meta::serialization::serialize_meta(&root)?,
+                    None,
+                ));
+
+                let compare = Compare::mod_revision(key, CompareOp::Equal, kv.mod_revision());
+                match client.txn(Txn::new().when([compare]).and_then(ops)).await {
+                    Ok(resp) if resp.succeeded() => {
+                        info!("Sharded children index: inode={}, shards={}", ino, shards);
+                        Ok(())
+                    }
+                    Ok(_) => Err(MetaError::ContinueRetry),
+                    Err(e) => Err(MetaError::Internal(format!(
+                        "Failed to execute transaction: {e}"
+                    ))),
+                }
+            }
+        };
+
+        backoff(10, attempt).await
     }
 
     /// Check file is existing
@@ -1739,6 +1959,7 @@ impl MetaStore for EtcdMetaStore {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn lookup(&self, parent: i64, name: &str) -> Result<Option<i64>, MetaError> {
+        name::check_len(name)?;
         let forward_key = Self::etcd_forward_key(parent, name);
         if let Some(entry) = self.etcd_get_json::<EtcdForwardEntry>(&forward_key).await? {
             Ok(Some(entry.inode))
@@ -1764,7 +1985,9 @@ impl MetaStore for EtcdMetaStore {
             let contents = self.get_content_meta(current_inode).await?;
 
             let found_entry = match contents {
-                Some(entries) => entries.into_iter().find(|entry| entry.entry_name == *part),
+                Some(entries) => entries
+                    .into_iter()
+                    .find(|entry| entry.entry_name == *name::to_bytes(part)),
                 None => return Ok(None),
             };
 
@@ -1820,7 +2043,7 @@ impl MetaStore for EtcdMetaStore {
                 EntryType::Symlink => FileType::Symlink,
             };
             entries.push(DirEntry {
-                name: content.entry_name,
+                name: name::from_bytes(&content.entry_name),
                 ino: content.inode,
                 kind,
             });
@@ -1831,6 +2054,7 @@ impl MetaStore for EtcdMetaStore {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn mkdir(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        name::validate(&name)?;
         self.create_directory(parent, name).await
     }
 
@@ -1849,8 +2073,8 @@ impl MetaStore for EtcdMetaStore {
             return Err(MetaError::Internal("Not a directory".to_string()));
         }
 
-        let children_key = Self::etcd_children_key(child_ino);
-        if let Some(children) = self.etcd_get_json::<EtcdDirChildren>(&children_key).await?
+        let children = self.load_dir_children(child_ino).await?;
+        if let Some(children) = &children
             && !children.children.is_empty()
         {
             return Err(MetaError::DirectoryNotEmpty(child_ino));
@@ -1865,11 +2089,15 @@ impl MetaStore for EtcdMetaStore {
         // This ensures the directory is properly deleted before updating parent
         let reverse_key = Self::etcd_reverse_key(child_ino);
         let children_key = Self::etcd_children_key(child_ino);
-        let delete_keys = vec![
+        let shard_keys: Vec<String> = (0..children.map_or(0, |c| c.shards))
+            .map(|shard| Self::etcd_children_shard_key(child_ino, shard))
+            .collect();
+        let mut delete_keys = vec![
             forward_key.as_str(),
             reverse_key.as_str(),
             children_key.as_str(),
         ];
+        delete_keys.extend(shard_keys.iter().map(String::as_str));
 
         match self
             .delete_entry(&forward_key, &delete_keys, child_ino)
@@ -1879,16 +2107,9 @@ impl MetaStore for EtcdMetaStore {
                 // Step 2: Directory deleted successfully, now update parent children map
                 // If this fails, forward key is deleted but parent still references it
                 // This is acceptable: lookup will fail (forward key gone), no dangling data
-                let name_for_closure = name.to_string();
 
                 match self
-                    .update_parent_children(
-                        parent,
-                        move |children| {
-                            children.remove(&name_for_closure);
-                        },
-                        10,
-                    )
+                    .update_parent_children(parent, &[(name, None)], 10)
                     .await
                 {
                     Ok(_) => {
@@ -1925,11 +2146,13 @@ impl MetaStore for EtcdMetaStore {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn create_file(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        name::validate(&name)?;
         self.create_file_internal(parent, name).await
     }
 
     #[tracing::instrument(level = "trace", skip(self), fields(ino, parent, name))]
     async fn link(&self, ino: i64, parent: i64, name: &str) -> Result<FileAttr, MetaError> {
+        name::validate(name)?;
         if ino == 1 {
             return Err(MetaError::NotSupported(
                 "cannot create hard links to the root inode".into(),
@@ -2134,16 +2357,8 @@ impl MetaStore for EtcdMetaStore {
             return Err(MetaError::ContinueRetry);
         }
 
-        let name_for_closure = name.to_string();
-        let ino_for_closure = ino;
         if let Err(e) = self
-            .update_parent_children(
-                parent,
-                move |children| {
-                    children.insert(name_for_closure.clone(), ino_for_closure);
-                },
-                10,
-            )
+            .update_parent_children(parent, &[(name, Some(ino))], 10)
             .await
         {
             warn!(
@@ -2162,6 +2377,7 @@ impl MetaStore for EtcdMetaStore {
         name: &str,
         target: &str,
     ) -> Result<(i64, FileAttr), MetaError> {
+        name::validate(name)?;
         let parent_meta = self
             .get_access_meta(parent)
             .await?
@@ -2228,16 +2444,8 @@ impl MetaStore for EtcdMetaStore {
         self.create_entry(&forward_key, &operations, parent, name)
             .await?;
 
-        let name_for_closure = name.to_string();
-        let inode_for_closure = inode;
         if let Err(e) = self
-            .update_parent_children(
-                parent,
-                move |children| {
-                    children.insert(name_for_closure.clone(), inode_for_closure);
-                },
-                10,
-            )
+            .update_parent_children(parent, &[(name, Some(inode))], 10)
             .await
         {
             error!(
@@ -2410,16 +2618,9 @@ impl MetaStore for EtcdMetaStore {
                 // Step 2: Atomic transaction succeeded, now update parent children map
                 // If this fails, forward key is deleted but parent still references it
                 // This is acceptable: lookup will fail (forward key gone), no dangling data
-                let name_for_closure = name.to_string();
 
                 match self
-                    .update_parent_children(
-                        parent,
-                        move |children| {
-                            children.remove(&name_for_closure);
-                        },
-                        10,
-                    )
+                    .update_parent_children(parent, &[(name, None)], 10)
                     .await
                 {
                     Ok(_) => {
@@ -2477,6 +2678,7 @@ impl MetaStore for EtcdMetaStore {
         new_parent: i64,
         new_name: String,
     ) -> Result<(), MetaError> {
+        name::validate(&new_name)?;
         let old_forward_key = Self::etcd_forward_key(old_parent, old_name);
         let forward_entry = self
             .etcd_get_json::<EtcdForwardEntry>(&old_forward_key)
@@ -2600,15 +2802,8 @@ impl MetaStore for EtcdMetaStore {
 
         if old_parent != new_parent {
             // Different parents: remove from old, add to new
-            let old_name_for_closure = old_name.to_string();
             match self
-                .update_parent_children(
-                    old_parent,
-                    move |children| {
-                        children.remove(&old_name_for_closure);
-                    },
-                    10,
-                )
+                .update_parent_children(old_parent, &[(old_name, None)], 10)
                 .await
             {
                 Ok(_) => {}
@@ -2621,16 +2816,8 @@ impl MetaStore for EtcdMetaStore {
                 }
             }
 
-            let new_name_for_closure = new_name.clone();
-            let entry_ino_for_closure = entry_ino;
             match self
-                .update_parent_children(
-                    new_parent,
-                    move |children| {
-                        children.insert(new_name_for_closure.clone(), entry_ino_for_closure);
-                    },
-                    10,
-                )
+                .update_parent_children(new_parent, &[(&new_name, Some(entry_ino))], 10)
                 .await
             {
                 Ok(_) => {}
@@ -2644,16 +2831,10 @@ impl MetaStore for EtcdMetaStore {
             }
         } else if old_name != new_name {
             // Same parent, different name: single atomic update
-            let old_name_for_closure = old_name.to_string();
-            let new_name_for_closure = new_name.clone();
-            let entry_ino_for_closure = entry_ino;
             match self
                 .update_parent_children(
                     new_parent,
-                    move |children| {
-                        children.remove(&old_name_for_closure);
-                        children.insert(new_name_for_closure.clone(), entry_ino_for_closure);
-                    },
+                    &[(old_name, None), (&new_name, Some(entry_ino))],
                     10,
                 )
                 .await
@@ -2703,6 +2884,8 @@ impl MetaStore for EtcdMetaStore {
         new_parent: i64,
         new_name: &str,
     ) -> Result<(), MetaError> {
+        name::check_len(old_name)?;
+        name::check_len(new_name)?;
         // For distributed stores like etcd, we need to implement exchange using transactions
         // Get both entries
         let old_forward_key = Self::etcd_forward_key(old_parent, old_name);
@@ -3502,7 +3685,10 @@ impl MetaStore for EtcdMetaStore {
 mod tests {
     use crate::meta::MetaStore;
     use crate::meta::config::Config;
-    use crate::meta::config::{CacheConfig, ClientOptions, DatabaseConfig, DatabaseType};
+    use crate::meta::config::{
+        CacheConfig, ClientOptions, DEFAULT_DIR_SHARD_THRESHOLD, DatabaseConfig, DatabaseType,
+    };
+    use crate::meta::entities::etcd::EtcdDirChildren;
     use crate::meta::file_lock::{FileLockQuery, FileLockRange, FileLockType};
     use crate::meta::store::MetaError;
     use crate::meta::stores::EtcdMetaStore;
@@ -3544,6 +3730,9 @@ mod tests {
             database: DatabaseConfig {
                 db_config: DatabaseType::Etcd {
                     urls: vec!["127.0.0.1:2379".to_string()],
+                    dir_shards: 0,
+                    dir_shard_threshold: DEFAULT_DIR_SHARD_THRESHOLD,
+                    tls: None,
                 },
             },
             cache: CacheConfig::default(),
@@ -3557,6 +3746,9 @@ mod tests {
             database: DatabaseConfig {
                 db_config: DatabaseType::Etcd {
                     urls: vec!["127.0.0.1:2379".to_string()],
+                    dir_shards: 0,
+                    dir_shard_threshold: DEFAULT_DIR_SHARD_THRESHOLD,
+                    tls: None,
                 },
             },
             cache: CacheConfig::default(),
@@ -3686,6 +3878,56 @@ mod tests {
         assert_eq!(store.lookup(dir_b, "z").await.unwrap(), Some(ino));
     }
 
+    #[serial]
+    #[tokio::test]
+    #[ignore]
+    async fn test_readdir_byte_order_latin1_name() {
+        use crate::meta::name;
+        use etcd_client::GetOptions;
+
+        let store = new_test_store().await;
+        let root = store.root_ino();
+        let dir = store.mkdir(root, "latin1".to_string()).await.unwrap();
+        let raw: [&[u8]; 4] = [b"a", "é".as_bytes(), b"\xe9", "🦀".as_bytes()];
+        for raw_name in raw.iter().rev() {
+            store
+                .create_file(dir, name::from_bytes(raw_name))
+                .await
+                .unwrap();
+        }
+
+        // Forward keys hold the escaped name, a prefix scan puts the latin-1 byte, escaped
+        // into U+10FFE9, after the emoji.
+        let mut client =
+            crate::meta::stores::etcd_store::EtcdClient::connect(vec!["127.0.0.1:2379"], None)
+                .await
+                .unwrap();
+        let prefix = format!("f:{dir}:");
+        let resp = client
+            .get(prefix.as_str(), Some(GetOptions::new().with_prefix()))
+            .await
+            .unwrap();
+        let scanned: Vec<Vec<u8>> = resp
+            .kvs()
+            .iter()
+            .map(|kv| {
+                let key = std::str::from_utf8(kv.key()).unwrap();
+                name::to_bytes(key.strip_prefix(&prefix).unwrap()).into_owned()
+            })
+            .collect();
+        assert_eq!(scanned, [raw[0], raw[1], raw[3], raw[2]]);
+
+        // readdir sorts by the raw bytes instead.
+        let listed: Vec<Vec<u8>> = store
+            .readdir(dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| name::to_bytes(&e.name).into_owned())
+            .collect();
+        assert_eq!(listed, raw);
+    }
+
     #[serial]
     #[tokio::test]
     #[ignore]
@@ -3718,6 +3960,76 @@ mod tests {
         assert_eq!(store.lookup(dir_a, "x").await.unwrap(), Some(ino));
     }
 
+    #[serial]
+    #[tokio::test]
+    #[ignore]
+    async fn test_sharded_directory() {
+        if let Err(e) = cleanup_test_data().await {
+            eprintln!("Failed to cleanup etcd test data: {}", e);
+        }
+        let mut config = test_config();
+        config.database.db_config = DatabaseType::Etcd {
+            urls: vec!["127.0.0.1:2379".to_string()],
+            dir_shards: 4,
+            dir_shard_threshold: 8,
+            tls: None,
+        };
+        let store = EtcdMetaStore::from_config(config).await.unwrap();
+        let root = store.root_ino();
+
+        let dir = store.mkdir(root, "hot".to_string()).await.unwrap();
+        let mut inodes = Vec::new();
+        for i in 0..16 {
+            let ino = store.create_file(dir, format!("f{i}")).await.unwrap();
+            inodes.push(ino);
+        }
+        let root_children = store
+            .etcd_get_json::<EtcdDirChildren>(&EtcdMetaStore::etcd_children_key(dir))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(root_children.shards, 4);
+        assert!(root_children.children.is_empty());
+
+        assert!(matches!(
+            store.create_file(dir, "f3".to_string()).await,
+            Err(MetaError::AlreadyExists { .. })
+        ));
+        store.unlink(dir, "f0").await.unwrap();
+        store
+            .rename(dir, "f1", dir, "g1".to_string())
+            .await
+            .unwrap();
+
+        let mut names: Vec<_> = store
+            .readdir(dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        names.sort();
+        let mut expected: Vec<_> = (2..16).map(|i| format!("f{i}")).collect();
+        expected.push("g1".to_string());
+        expected.sort();
+        assert_eq!(names, expected);
+        assert_eq!(store.lookup(dir, "g1").await.unwrap(), Some(inodes[1]));
+
+        assert!(matches!(
+            store.rmdir(root, "hot").await,
+            Err(MetaError::DirectoryNotEmpty(_))
+        ));
+        for name in names {
+            store.unlink(dir, &name).await.unwrap();
+        }
+        store.rmdir(root, "hot").await.unwrap();
+        let shard = store
+            .etcd_get_json::<EtcdDirChildren>(&EtcdMetaStore::etcd_children_shard_key(dir, 0))
+            .await
+            .unwrap();
+        assert!(shard.is_none());
+    }
+
     #[serial]
     #[tokio::test]
     #[ignore]
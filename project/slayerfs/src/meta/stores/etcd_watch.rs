@@ -297,6 +297,7 @@ impl EtcdWatchWorker {
     /// - `f:{parent}:{name}` - Forward index (parent, name) → inode
     /// - `r:{inode}` - Reverse index inode → metadata
     /// - `c:{inode}` - Children index inode → children set
+    /// - `c:{inode}:{shard}` - One shard of a sharded children index
     ///
     /// # Event Generation Rules
     /// - `f:*` PUT → Parse value to get child_ino, generate AddChild event
@@ -305,6 +306,8 @@ impl EtcdWatchWorker {
     /// - `r:*` DELETE → Invalidate inode cache (coarse-grained)
     /// - `c:*` PUT → Parse EtcdDirChildren JSON (HashMap<String, i64>), generate UpdateChildren event
     /// - `c:*` DELETE → Invalidate parent children (coarse-grained)
+    /// - `c:*` PUT of a sharded index or of a shard → Invalidate parent children, a single key
+    ///   only holds part of the children
     ///
     /// # Arguments
     /// - `key`: etcd key string
@@ -398,10 +401,16 @@ impl EtcdWatchWorker {
                                 EtcdDirChildren,
                             >(value)
                             {
-                                events.push(CacheInvalidationEvent::UpdateChildren {
-                                    parent_ino,
-                                    children: dir_children.children,
-                                });
+                                if parts.len() > 2 || dir_children.shards > 0 {
+                                    events.push(CacheInvalidationEvent::InvalidateParentChildren(
+                                        parent_ino,
+                                    ));
+                                } else {
+                                    events.push(CacheInvalidationEvent::UpdateChildren {
+                                        parent_ino,
+                                        children: dir_children.children,
+                                    });
+                                }
                                 return events;
                             }
 
@@ -575,4 +584,24 @@ mod tests {
             CacheInvalidationEvent::InvalidateParentChildren(50)
         ));
     }
+
+    #[test]
+    fn test_parse_sharded_children_keys_put() {
+        let root = r#"{"inode":50,"children":{},"shards":4}"#;
+        let events = EtcdWatchWorker::parse_key_to_events("c:50", EventType::Put, root.as_bytes());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            CacheInvalidationEvent::InvalidateParentChildren(50)
+        ));
+
+        let shard = r#"{"inode":50,"children":{"file.txt":100}}"#;
+        let events =
+            EtcdWatchWorker::parse_key_to_events("c:50:3", EventType::Put, shard.as_bytes());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            CacheInvalidationEvent::InvalidateParentChildren(50)
+        ));
+    }
 }
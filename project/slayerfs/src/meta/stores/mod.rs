@@ -5,17 +5,20 @@
 //!
 //! - `DatabaseMetaStore`: SQL databases (PostgreSQL, SQLite)
 //! - `EtcdMetaStore`: Distributed etcd cluster
+//! - `ReplicatedMetaStore`: Any two of the above, mirrored synchronously
 pub mod database_store;
 pub mod etcd_store;
 pub(crate) mod etcd_watch;
 pub(crate) mod pool;
 pub mod redis_store;
+pub mod replicated;
 
 // Re-export main types for convenience
 pub use database_store::DatabaseMetaStore;
 pub use etcd_store::EtcdMetaStore;
 pub(crate) use etcd_watch::{CacheInvalidationEvent, EtcdWatchWorker, WatchConfig};
 pub use redis_store::RedisMetaStore;
+pub use replicated::ReplicatedMetaStore;
 
 struct TruncatePlan {
     cutoff_chunk: u64,
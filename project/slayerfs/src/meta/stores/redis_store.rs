@@ -16,7 +16,8 @@ use crate::meta::file_lock::{
 use crate::meta::store::{
     DirEntry, FileAttr, FileType, LockName, MetaError, MetaStore, SetAttrFlags, SetAttrRequest,
 };
-use crate::meta::{INODE_ID_KEY, SLICE_ID_KEY};
+use crate::meta::{INODE_ID_KEY, SLICE_ID_KEY, name};
+use crate::utils::tls::TlsConfig;
 use async_trait::async_trait;
 use chrono::Utc;
 use redis::AsyncCommands;
@@ -670,10 +671,13 @@ impl RedisMetaStore {
 
     async fn create_connection(config: &Config) -> Result<ConnectionManager, MetaError> {
         match &config.database.db_config {
-            DatabaseType::Redis { url } => {
-                let client = redis::Client::open(url.as_str()).map_err(|e| {
-                    MetaError::Config(format!("Failed to parse Redis URL {url}: {e}"))
-                })?;
+            DatabaseType::Redis { url, tls } => {
+                let client = match tls {
+                    Some(tls) => Self::tls_client(url, tls)?,
+                    None => redis::Client::open(url.as_str()).map_err(|e| {
+                        MetaError::Config(format!("Failed to parse Redis URL {url}: {e}"))
+                    })?,
+                };
                 ConnectionManager::new(client).await.map_err(|e| {
                     MetaError::Config(format!("Failed to connect to Redis backend: {e}"))
                 })
@@ -683,6 +687,28 @@ impl RedisMetaStore {
             )),
         }
     }
+    /// Redis client for a `rediss://` URL with the certificates of `tls`. A `ca_file`
+    /// replaces the system roots rather than adding to them.
+    fn tls_client(url: &str, tls: &TlsConfig) -> Result<redis::Client, MetaError> {
+        let invalid =
+            |e: std::io::Error| MetaError::Config(format!("Invalid Redis TLS config: {e}"));
+        if tls.server_name.is_some() {
+            return Err(invalid(TlsConfig::unsupported("Redis", "server_name")));
+        }
+        let certs = redis::TlsCertificates {
+            client_tls: tls
+                .identity_pem()
+                .map_err(invalid)?
+                .map(|(client_cert, client_key)| redis::ClientTlsConfig {
+                    client_cert,
+                    client_key,
+                }),
+            root_cert: tls.ca_pem().map_err(invalid)?,
+        };
+        redis::Client::build_with_tls(url, certs)
+            .map_err(|e| MetaError::Config(format!("Failed to set up Redis TLS for {url}: {e}")))
+    }
+
     fn node_key(&self, ino: i64) -> String {
         format!("{NODE_KEY_PREFIX}{ino}")
     }
@@ -1297,6 +1323,7 @@ impl MetaStore for RedisMetaStore {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn lookup(&self, parent: i64, name: &str) -> Result<Option<i64>, MetaError> {
+        name::check_len(name)?;
         self.directory_child(parent, name).await
     }
 
@@ -1341,11 +1368,13 @@ impl MetaStore for RedisMetaStore {
                 });
             }
         }
+        result.sort_by(|a, b| name::cmp(&a.name, &b.name));
         Ok(result)
     }
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn mkdir(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        name::validate(&name)?;
         self.create_entry(parent, name, FileType::Dir).await
     }
 
@@ -1398,11 +1427,13 @@ impl MetaStore for RedisMetaStore {
 
     #[tracing::instrument(level = "trace", skip(self), fields(parent, name))]
     async fn create_file(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        name::validate(&name)?;
         self.create_entry(parent, name, FileType::File).await
     }
 
     #[tracing::instrument(level = "trace", skip(self), fields(ino, parent, name))]
     async fn link(&self, ino: i64, parent: i64, name: &str) -> Result<FileAttr, MetaError> {
+        name::validate(name)?;
         self.ensure_parent_dir(parent).await?;
 
         let node_key = self.node_key(ino);
@@ -1536,6 +1567,7 @@ impl MetaStore for RedisMetaStore {
         new_parent: i64,
         new_name: String,
     ) -> Result<(), MetaError> {
+        name::validate(&new_name)?;
         // Self-rename optimization: no-op if same location
         if old_parent == new_parent && old_name == new_name {
             return Ok(());
@@ -1603,6 +1635,8 @@ impl MetaStore for RedisMetaStore {
         new_parent: i64,
         new_name: &str,
     ) -> Result<(), MetaError> {
+        name::check_len(old_name)?;
+        name::check_len(new_name)?;
         if old_parent == new_parent && old_name == new_name {
             return Ok(());
         }
@@ -2342,6 +2376,7 @@ mod tests {
             database: DatabaseConfig {
                 db_config: DatabaseType::Redis {
                     url: "redis://127.0.0.1:6379/0".to_string(),
+                    tls: None,
                 },
             },
             cache: CacheConfig::default(),
@@ -2355,6 +2390,7 @@ mod tests {
             database: DatabaseConfig {
                 db_config: DatabaseType::Redis {
                     url: "redis://127.0.0.1:6379/0".to_string(),
+                    tls: None,
                 },
             },
             cache: CacheConfig::default(),
@@ -2484,6 +2520,34 @@ mod tests {
         assert_eq!(store.lookup(dir_b, "z").await.unwrap(), Some(ino));
     }
 
+    #[serial]
+    #[tokio::test]
+    #[ignore]
+    async fn test_readdir_byte_order_latin1_name() {
+        use crate::meta::name;
+
+        let store = new_test_store().await;
+        let root = store.root_ino();
+        let dir = store.mkdir(root, "latin1".to_string()).await.unwrap();
+        // Escaped, the latin-1 byte would sort after the emoji.
+        let raw: [&[u8]; 4] = [b"a", "é".as_bytes(), b"\xe9", "🦀".as_bytes()];
+        for raw_name in raw.iter().rev() {
+            store
+                .create_file(dir, name::from_bytes(raw_name))
+                .await
+                .unwrap();
+        }
+
+        let listed: Vec<Vec<u8>> = store
+            .readdir(dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| name::to_bytes(&e.name).into_owned())
+            .collect();
+        assert_eq!(listed, raw);
+    }
+
     #[serial]
     #[tokio::test]
     #[ignore]
@@ -0,0 +1,1075 @@
+//! Synchronous replication of the metadata to a second store
+//!
+//! For volumes whose metadata must survive the loss of its store, [`ReplicatedMetaStore`]
+//! applies every mutation to a primary store and, before returning, to a secondary one, e.g.
+//! Postgres shadowed by etcd. Reads are served by the primary alone. Mutations are serialized
+//! so both stores see them in the same order and allocate the same ids, and their results are
+//! compared: a secondary that fails or answers differently has diverged and is no longer
+//! written to. It can only be brought back by rebuilding it and remounting.
+//!
+//! Sessions, opens and locks belong to the store serving the mount and are not mirrored.
+//!
+//! Failover is an admin operation, also reachable through the [`REPLICATION_XATTR`] virtual
+//! xattr: [`ReplicatedMetaStore::promote`] swaps the roles of the stores, and
+//! [`ReplicatedMetaStore::demote`] stops mirroring to the secondary, e.g. while it is down.
+
+use crate::chuck::SliceDesc;
+use crate::meta::client::session::{Session, SessionInfo};
+use crate::meta::file_lock::{FileLockInfo, FileLockQuery, FileLockRange, FileLockType};
+use crate::meta::store::{
+    AclRule, DirEntry, DirEntryPlus, DirStat, DumpOption, DumpRecord, FileAttr, FileType,
+    LoadOption, LockName, MetaError, MetaStore, OpenFlags, Quota, QuotaDelta, SetAttrFlags,
+    SetAttrRequest, StatFsSnapshot, Visitor, VolumeStat,
+};
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Virtual xattr reporting the replication status of a volume. Setting it to `promote`,
+/// `promote-force` or `demote` runs the matching admin operation.
+pub const REPLICATION_XATTR: &str = "slayerfs.replication";
+
+/// Whether the secondary store still mirrors the primary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// Every mutation was applied to both stores.
+    InSync,
+    /// A mutation failed on the secondary or gave another result there.
+    Diverged,
+    /// Mirroring was stopped by [`ReplicatedMetaStore::demote`] or a forced promotion.
+    Detached,
+}
+
+impl fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ReplicaState::InSync => "in-sync",
+            ReplicaState::Diverged => "diverged",
+            ReplicaState::Detached => "detached",
+        })
+    }
+}
+
+/// Snapshot of a [`ReplicatedMetaStore`].
+#[derive(Clone, Debug)]
+pub struct ReplicationStatus {
+    pub primary: &'static str,
+    pub secondary: &'static str,
+    pub state: ReplicaState,
+    /// Mutations applied to both stores.
+    pub mirrored: u64,
+    /// First mutation the secondary diverged on.
+    pub divergence: Option<String>,
+}
+
+impl fmt::Display for ReplicationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "primary={} secondary={} state={} mirrored={}",
+            self.primary, self.secondary, self.state, self.mirrored
+        )?;
+        if let Some(divergence) = &self.divergence {
+            write!(f, " divergence={divergence}")?;
+        }
+        Ok(())
+    }
+}
+
+struct Roles {
+    primary: Arc<dyn MetaStore>,
+    secondary: Arc<dyn MetaStore>,
+    state: ReplicaState,
+    divergence: Option<String>,
+}
+
+struct Inner {
+    roles: RwLock<Roles>,
+    /// Refuse mutations that cannot be mirrored instead of applying them to the primary only.
+    strict: bool,
+    mirrored: AtomicU64,
+    /// Held across both applications of a mutation, keeping the stores in the same order.
+    order: tokio::sync::Mutex<()>,
+}
+
+/// Metadata store mirroring its mutations to a secondary store, see the module documentation.
+#[derive(Clone)]
+pub struct ReplicatedMetaStore {
+    inner: Arc<Inner>,
+}
+
+impl ReplicatedMetaStore {
+    /// Mirror `primary` to `secondary`, which must hold the same metadata, usually both empty.
+    /// With `strict`, mutations fail once the secondary stopped mirroring, so nothing is
+    /// acknowledged that a single store holds; the mutation it diverged on fails too, though
+    /// the primary applied it.
+    pub fn new(primary: Arc<dyn MetaStore>, secondary: Arc<dyn MetaStore>, strict: bool) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                roles: RwLock::new(Roles {
+                    primary,
+                    secondary,
+                    state: ReplicaState::InSync,
+                    divergence: None,
+                }),
+                strict,
+                mirrored: AtomicU64::new(0),
+                order: tokio::sync::Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        let roles = self.inner.roles.read();
+        ReplicationStatus {
+            primary: roles.primary.name(),
+            secondary: roles.secondary.name(),
+            state: roles.state,
+            mirrored: self.inner.mirrored.load(Ordering::Relaxed),
+            divergence: roles.divergence.clone(),
+        }
+    }
+
+    /// Fail over: make the secondary the primary and the primary its mirror. A secondary that
+    /// is not in sync is only promoted with `force`, and the old primary is then detached.
+    pub async fn promote(&self, force: bool) -> Result<(), MetaError> {
+        let _order = self.inner.order.lock().await;
+        let mut guard = self.inner.roles.write();
+        let roles = &mut *guard;
+        if roles.state != ReplicaState::InSync && !force {
+            return Err(MetaError::NotSupported(format!(
+                "secondary metadata store is {}, only promoted with force",
+                roles.state
+            )));
+        }
+        std::mem::swap(&mut roles.primary, &mut roles.secondary);
+        if roles.state != ReplicaState::InSync {
+            // The old primary holds mutations the new one lacks.
+            roles.state = ReplicaState::Detached;
+        }
+        info!(
+            "promoted {} metadata store to primary, {} is {}",
+            roles.primary.name(),
+            roles.secondary.name(),
+            roles.state
+        );
+        Ok(())
+    }
+
+    /// Stop mirroring to the secondary.
+    pub async fn demote(&self) {
+        let _order = self.inner.order.lock().await;
+        let mut roles = self.inner.roles.write();
+        if roles.state == ReplicaState::InSync {
+            roles.state = ReplicaState::Detached;
+            info!("detached {} metadata store", roles.secondary.name());
+        }
+    }
+
+    fn primary(&self) -> Arc<dyn MetaStore> {
+        Arc::clone(&self.inner.roles.read().primary)
+    }
+
+    fn diverged(&self, reason: String) {
+        let mut roles = self.inner.roles.write();
+        error!(
+            "{} metadata store diverged from the primary: {reason}",
+            roles.secondary.name()
+        );
+        roles.state = ReplicaState::Diverged;
+        roles.divergence.get_or_insert(reason);
+    }
+
+    /// Apply the mutation `call` to the primary and, once it succeeded, to the secondary,
+    /// whose result must be the `same` as the primary's.
+    async fn mirror<T, F, Fut, E>(&self, op: &str, call: F, same: E) -> Result<T, MetaError>
+    where
+        T: Send,
+        F: Fn(Arc<dyn MetaStore>) -> Fut + Send,
+        Fut: Future<Output = Result<T, MetaError>> + Send,
+        E: Fn(&T, &T) -> bool + Send,
+    {
+        let _order = self.inner.order.lock().await;
+        let (primary, secondary) = {
+            let roles = self.inner.roles.read();
+            let in_sync = roles.state == ReplicaState::InSync;
+            if self.inner.strict && !in_sync {
+                return Err(MetaError::Internal(format!(
+                    "{op} refused: secondary metadata store is {}",
+                    roles.state
+                )));
+            }
+            (
+                Arc::clone(&roles.primary),
+                in_sync.then(|| Arc::clone(&roles.secondary)),
+            )
+        };
+        let result = call(primary).await;
+        let (Ok(value), Some(secondary)) = (&result, secondary) else {
+            return result;
+        };
+        let reason = match call(secondary).await {
+            Ok(mirrored) if same(value, &mirrored) => {
+                self.inner.mirrored.fetch_add(1, Ordering::Relaxed);
+                return result;
+            }
+            Ok(_) => format!("{op} returned another result"),
+            Err(e) => format!("{op} failed: {e}"),
+        };
+        self.diverged(reason.clone());
+        if self.inner.strict {
+            return Err(MetaError::Internal(format!(
+                "secondary metadata store diverged: {reason}"
+            )));
+        }
+        result
+    }
+}
+
+fn any<T>(_: &T, _: &T) -> bool {
+    true
+}
+
+fn same_attr(a: &FileAttr, b: &FileAttr) -> bool {
+    // Times are taken by each store.
+    a.ino == b.ino
+        && a.kind == b.kind
+        && a.size == b.size
+        && a.mode == b.mode
+        && a.uid == b.uid
+        && a.gid == b.gid
+        && a.nlink == b.nlink
+}
+
+fn copy_flags(flags: &SetAttrFlags) -> SetAttrFlags {
+    SetAttrFlags::from_bits_retain(flags.bits())
+}
+
+#[async_trait]
+impl MetaStore for ReplicatedMetaStore {
+    fn name(&self) -> &'static str {
+        "replicated"
+    }
+
+    async fn stat(&self, ino: i64) -> Result<Option<FileAttr>, MetaError> {
+        self.primary().stat(ino).await
+    }
+
+    async fn lookup(&self, parent: i64, name: &str) -> Result<Option<i64>, MetaError> {
+        self.primary().lookup(parent, name).await
+    }
+
+    async fn lookup_path(&self, path: &str) -> Result<Option<(i64, FileType)>, MetaError> {
+        self.primary().lookup_path(path).await
+    }
+
+    async fn readdir(&self, ino: i64) -> Result<Vec<DirEntry>, MetaError> {
+        self.primary().readdir(ino).await
+    }
+
+    async fn batch_stat(&self, inodes: &[i64]) -> Result<Vec<Option<FileAttr>>, MetaError> {
+        self.primary().batch_stat(inodes).await
+    }
+
+    async fn mkdir(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        self.mirror(
+            "mkdir",
+            |s| {
+                let name = name.clone();
+                async move { s.mkdir(parent, name).await }
+            },
+            i64::eq,
+        )
+        .await
+    }
+
+    async fn rmdir(&self, parent: i64, name: &str) -> Result<(), MetaError> {
+        self.mirror("rmdir", |s| async move { s.rmdir(parent, name).await }, any)
+            .await
+    }
+
+    async fn create_file(&self, parent: i64, name: String) -> Result<i64, MetaError> {
+        self.mirror(
+            "create_file",
+            |s| {
+                let name = name.clone();
+                async move { s.create_file(parent, name).await }
+            },
+            i64::eq,
+        )
+        .await
+    }
+
+    async fn unlink(&self, parent: i64, name: &str) -> Result<(), MetaError> {
+        self.mirror(
+            "unlink",
+            |s| async move { s.unlink(parent, name).await },
+            any,
+        )
+        .await
+    }
+
+    async fn rename(
+        &self,
+        old_parent: i64,
+        old_name: &str,
+        new_parent: i64,
+        new_name: String,
+    ) -> Result<(), MetaError> {
+        self.mirror(
+            "rename",
+            |s| {
+                let new_name = new_name.clone();
+                async move { s.rename(old_parent, old_name, new_parent, new_name).await }
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn rename_exchange(
+        &self,
+        old_parent: i64,
+        old_name: &str,
+        new_parent: i64,
+        new_name: &str,
+    ) -> Result<(), MetaError> {
+        self.mirror(
+            "rename_exchange",
+            |s| async move {
+                s.rename_exchange(old_parent, old_name, new_parent, new_name)
+                    .await
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn set_file_size(&self, ino: i64, size: u64) -> Result<(), MetaError> {
+        self.mirror(
+            "set_file_size",
+            |s| async move { s.set_file_size(ino, size).await },
+            any,
+        )
+        .await
+    }
+
+    async fn extend_file_size(&self, ino: i64, size: u64) -> Result<(), MetaError> {
+        self.mirror(
+            "extend_file_size",
+            |s| async move { s.extend_file_size(ino, size).await },
+            any,
+        )
+        .await
+    }
+
+    async fn truncate(&self, ino: i64, size: u64, chunk_size: u64) -> Result<(), MetaError> {
+        self.mirror(
+            "truncate",
+            |s| async move { s.truncate(ino, size, chunk_size).await },
+            any,
+        )
+        .await
+    }
+
+    async fn get_dentries(&self, ino: i64) -> Result<Vec<(i64, String)>, MetaError> {
+        self.primary().get_dentries(ino).await
+    }
+
+    async fn get_dir_parent(&self, dir_ino: i64) -> Result<Option<i64>, MetaError> {
+        self.primary().get_dir_parent(dir_ino).await
+    }
+
+    async fn get_names(&self, ino: i64) -> Result<Vec<(Option<i64>, String)>, MetaError> {
+        self.primary().get_names(ino).await
+    }
+
+    async fn get_paths(&self, ino: i64) -> Result<Vec<String>, MetaError> {
+        self.primary().get_paths(ino).await
+    }
+
+    fn root_ino(&self) -> i64 {
+        self.primary().root_ino()
+    }
+
+    async fn initialize(&self) -> Result<(), MetaError> {
+        self.mirror("initialize", |s| async move { s.initialize().await }, any)
+            .await
+    }
+
+    async fn get_deleted_files(&self) -> Result<Vec<i64>, MetaError> {
+        self.primary().get_deleted_files().await
+    }
+
+    async fn remove_file_metadata(&self, ino: i64) -> Result<(), MetaError> {
+        self.mirror(
+            "remove_file_metadata",
+            |s| async move { s.remove_file_metadata(ino).await },
+            any,
+        )
+        .await
+    }
+
+    async fn get_slices(&self, chunk_id: u64) -> Result<Vec<SliceDesc>, MetaError> {
+        self.primary().get_slices(chunk_id).await
+    }
+
+    async fn append_slice(&self, chunk_id: u64, slice: SliceDesc) -> Result<(), MetaError> {
+        self.mirror(
+            "append_slice",
+            |s| async move { s.append_slice(chunk_id, slice).await },
+            any,
+        )
+        .await
+    }
+
+    async fn write(
+        &self,
+        ino: i64,
+        chunk_id: u64,
+        slice: SliceDesc,
+        new_size: u64,
+    ) -> Result<(), MetaError> {
+        self.mirror(
+            "write",
+            |s| async move { s.write(ino, chunk_id, slice, new_size).await },
+            any,
+        )
+        .await
+    }
+
+    async fn next_id(&self, key: &str) -> Result<i64, MetaError> {
+        self.mirror("next_id", |s| async move { s.next_id(key).await }, i64::eq)
+            .await
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    async fn get_counter(&self, name: &str) -> Result<i64, MetaError> {
+        self.primary().get_counter(name).await
+    }
+
+    async fn incr_counter(&self, name: &str, delta: i64) -> Result<i64, MetaError> {
+        self.mirror(
+            "incr_counter",
+            |s| async move { s.incr_counter(name, delta).await },
+            i64::eq,
+        )
+        .await
+    }
+
+    async fn set_counter_if_small(
+        &self,
+        name: &str,
+        value: i64,
+        diff: i64,
+    ) -> Result<bool, MetaError> {
+        self.mirror(
+            "set_counter_if_small",
+            |s| async move { s.set_counter_if_small(name, value, diff).await },
+            bool::eq,
+        )
+        .await
+    }
+
+    async fn update_volume_stat(&self, delta: DirStat) -> Result<(), MetaError> {
+        self.mirror(
+            "update_volume_stat",
+            |s| {
+                let delta = delta.clone();
+                async move { s.update_volume_stat(delta).await }
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn flush_volume_stat(&self) -> Result<VolumeStat, MetaError> {
+        self.mirror(
+            "flush_volume_stat",
+            |s| async move { s.flush_volume_stat().await },
+            any,
+        )
+        .await
+    }
+
+    async fn start_session(
+        &self,
+        session_info: SessionInfo,
+        token: CancellationToken,
+    ) -> Result<Session, MetaError> {
+        self.primary().start_session(session_info, token).await
+    }
+
+    async fn shutdown_session(&self) -> Result<(), MetaError> {
+        self.primary().shutdown_session().await
+    }
+
+    async fn cleanup_sessions(&self) -> Result<(), MetaError> {
+        self.primary().cleanup_sessions().await
+    }
+
+    async fn get_global_lock(&self, lock_name: LockName) -> bool {
+        self.primary().get_global_lock(lock_name).await
+    }
+
+    async fn set_attr(
+        &self,
+        ino: i64,
+        req: &SetAttrRequest,
+        flags: SetAttrFlags,
+    ) -> Result<FileAttr, MetaError> {
+        let flags = &flags;
+        self.mirror(
+            "set_attr",
+            |s| async move { s.set_attr(ino, req, copy_flags(flags)).await },
+            same_attr,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: i64, flags: OpenFlags) -> Result<FileAttr, MetaError> {
+        self.primary().open(ino, flags).await
+    }
+
+    async fn close(&self, ino: i64) -> Result<(), MetaError> {
+        self.primary().close(ino).await
+    }
+
+    async fn link(&self, ino: i64, parent: i64, name: &str) -> Result<FileAttr, MetaError> {
+        self.mirror(
+            "link",
+            |s| async move { s.link(ino, parent, name).await },
+            same_attr,
+        )
+        .await
+    }
+
+    async fn symlink(
+        &self,
+        parent: i64,
+        name: &str,
+        target: &str,
+    ) -> Result<(i64, FileAttr), MetaError> {
+        self.mirror(
+            "symlink",
+            |s| async move { s.symlink(parent, name, target).await },
+            |a, b| a.0 == b.0 && same_attr(&a.1, &b.1),
+        )
+        .await
+    }
+
+    async fn read_symlink(&self, ino: i64) -> Result<String, MetaError> {
+        self.primary().read_symlink(ino).await
+    }
+
+    async fn stat_fs(&self) -> Result<StatFsSnapshot, MetaError> {
+        self.primary().stat_fs().await
+    }
+
+    async fn delete_sustained_inode(&self, session_id: u64, inode: i64) -> Result<(), MetaError> {
+        self.primary()
+            .delete_sustained_inode(session_id, inode)
+            .await
+    }
+
+    async fn delete_file_data(&self, inode: i64, length: u64) -> Result<(), MetaError> {
+        self.mirror(
+            "delete_file_data",
+            |s| async move { s.delete_file_data(inode, length).await },
+            any,
+        )
+        .await
+    }
+
+    async fn cleanup_slices(&self) -> Result<(), MetaError> {
+        self.mirror(
+            "cleanup_slices",
+            |s| async move { s.cleanup_slices().await },
+            any,
+        )
+        .await
+    }
+
+    async fn cleanup_delayed_slices(&self, edge_ts: i64) -> Result<i32, MetaError> {
+        self.mirror(
+            "cleanup_delayed_slices",
+            |s| async move { s.cleanup_delayed_slices(edge_ts).await },
+            any,
+        )
+        .await
+    }
+
+    async fn delete_slice(&self, slice_id: u64, size: u32) -> Result<(), MetaError> {
+        self.mirror(
+            "delete_slice",
+            |s| async move { s.delete_slice(slice_id, size).await },
+            any,
+        )
+        .await
+    }
+
+    async fn clone_entry(
+        &self,
+        src: i64,
+        parent: i64,
+        name: &str,
+        ino: i64,
+        attr: &mut FileAttr,
+        cmode: u8,
+        cumask: u16,
+        top: bool,
+    ) -> Result<(), MetaError> {
+        let initial = attr.clone();
+        let initial = &initial;
+        *attr = self
+            .mirror(
+                "clone_entry",
+                |s| async move {
+                    let mut attr = initial.clone();
+                    s.clone_entry(src, parent, name, ino, &mut attr, cmode, cumask, top)
+                        .await?;
+                    Ok(attr)
+                },
+                same_attr,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn attach_dir_node(&self, parent: i64, dst: i64, name: &str) -> Result<(), MetaError> {
+        self.mirror(
+            "attach_dir_node",
+            |s| async move { s.attach_dir_node(parent, dst, name).await },
+            any,
+        )
+        .await
+    }
+
+    async fn find_detached_nodes(&self, since: SystemTime) -> Result<Vec<i64>, MetaError> {
+        self.primary().find_detached_nodes(since).await
+    }
+
+    async fn cleanup_detached_node(&self, inode: i64) -> Result<(), MetaError> {
+        self.mirror(
+            "cleanup_detached_node",
+            |s| async move { s.cleanup_detached_node(inode).await },
+            any,
+        )
+        .await
+    }
+
+    async fn get_parents(&self, inode: i64) -> Result<HashMap<i64, i32>, MetaError> {
+        self.primary().get_parents(inode).await
+    }
+
+    async fn update_dir_stat(&self, batch: HashMap<i64, DirStat>) -> Result<(), MetaError> {
+        self.mirror(
+            "update_dir_stat",
+            |s| {
+                let batch = batch.clone();
+                async move { s.update_dir_stat(batch).await }
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn get_dir_stat(&self, inode: i64, try_sync: bool) -> Result<Option<DirStat>, MetaError> {
+        self.primary().get_dir_stat(inode, try_sync).await
+    }
+
+    async fn sync_dir_stat(&self, inode: i64) -> Result<Option<DirStat>, MetaError> {
+        self.mirror(
+            "sync_dir_stat",
+            |s| async move { s.sync_dir_stat(inode).await },
+            any,
+        )
+        .await
+    }
+
+    async fn sync_volume_stat(&self) -> Result<VolumeStat, MetaError> {
+        self.mirror(
+            "sync_volume_stat",
+            |s| async move { s.sync_volume_stat().await },
+            any,
+        )
+        .await
+    }
+
+    async fn get_quota(&self, qtype: u32, key: u64) -> Result<Option<Quota>, MetaError> {
+        self.primary().get_quota(qtype, key).await
+    }
+
+    async fn set_quota(&self, qtype: u32, key: u64, quota: Quota) -> Result<bool, MetaError> {
+        self.mirror(
+            "set_quota",
+            |s| {
+                let quota = quota.clone();
+                async move { s.set_quota(qtype, key, quota).await }
+            },
+            bool::eq,
+        )
+        .await
+    }
+
+    async fn delete_quota(&self, qtype: u32, key: u64) -> Result<(), MetaError> {
+        self.mirror(
+            "delete_quota",
+            |s| async move { s.delete_quota(qtype, key).await },
+            any,
+        )
+        .await
+    }
+
+    async fn load_quotas(
+        &self,
+    ) -> Result<
+        (
+            HashMap<u64, Quota>,
+            HashMap<u64, Quota>,
+            HashMap<u64, Quota>,
+        ),
+        MetaError,
+    > {
+        self.primary().load_quotas().await
+    }
+
+    async fn flush_quotas(&self, deltas: &[QuotaDelta]) -> Result<(), MetaError> {
+        self.mirror(
+            "flush_quotas",
+            |s| async move { s.flush_quotas(deltas).await },
+            any,
+        )
+        .await
+    }
+
+    async fn readdir_plus(
+        &self,
+        ino: i64,
+        limit: Option<usize>,
+    ) -> Result<Vec<DirEntryPlus>, MetaError> {
+        self.primary().readdir_plus(ino, limit).await
+    }
+
+    async fn dump(
+        &self,
+        opt: DumpOption,
+        visitor: &mut dyn Visitor<DumpRecord>,
+    ) -> Result<(), MetaError> {
+        self.primary().dump(opt, visitor).await
+    }
+
+    async fn load(&self, opt: LoadOption, data: &[u8]) -> Result<(), MetaError> {
+        self.mirror(
+            "load",
+            |s| {
+                let opt = opt.clone();
+                async move { s.load(opt, data).await }
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn set_xattr(
+        &self,
+        inode: i64,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), MetaError> {
+        self.mirror(
+            "set_xattr",
+            |s| async move { s.set_xattr(inode, name, value, flags).await },
+            any,
+        )
+        .await
+    }
+
+    async fn get_xattr(&self, inode: i64, name: &str) -> Result<Option<Vec<u8>>, MetaError> {
+        self.primary().get_xattr(inode, name).await
+    }
+
+    async fn list_xattr(&self, inode: i64) -> Result<Vec<String>, MetaError> {
+        self.primary().list_xattr(inode).await
+    }
+
+    async fn remove_xattr(&self, inode: i64, name: &str) -> Result<(), MetaError> {
+        self.mirror(
+            "remove_xattr",
+            |s| async move { s.remove_xattr(inode, name).await },
+            any,
+        )
+        .await
+    }
+
+    async fn cache_acls(&self) -> Result<(), MetaError> {
+        self.primary().cache_acls().await
+    }
+
+    async fn set_acl(&self, inode: i64, rule: AclRule) -> Result<(), MetaError> {
+        self.mirror(
+            "set_acl",
+            |s| {
+                let rule = rule.clone();
+                async move { s.set_acl(inode, rule).await }
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn get_acl(
+        &self,
+        inode: i64,
+        acl_type: u8,
+        acl_id: u32,
+    ) -> Result<Option<AclRule>, MetaError> {
+        self.primary().get_acl(inode, acl_type, acl_id).await
+    }
+
+    async fn read_slices(&self, inode: i64, chunk_index: u32) -> Result<Vec<SliceDesc>, MetaError> {
+        self.primary().read_slices(inode, chunk_index).await
+    }
+
+    async fn write_slice(
+        &self,
+        inode: i64,
+        chunk_index: u32,
+        offset: u32,
+        slice: SliceDesc,
+        mtime: SystemTime,
+        num_slices: &mut i32,
+        delta: &mut DirStat,
+        attr: &mut FileAttr,
+    ) -> Result<(), MetaError> {
+        let initial = (*num_slices, delta.clone(), attr.clone());
+        let initial = &initial;
+        (*num_slices, *delta, *attr) = self
+            .mirror(
+                "write_slice",
+                |s| async move {
+                    let (mut num_slices, mut delta, mut attr) = initial.clone();
+                    s.write_slice(
+                        inode,
+                        chunk_index,
+                        offset,
+                        slice,
+                        mtime,
+                        &mut num_slices,
+                        &mut delta,
+                        &mut attr,
+                    )
+                    .await?;
+                    Ok((num_slices, delta, attr))
+                },
+                |a, b| same_attr(&a.2, &b.2),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn truncate_file(
+        &self,
+        inode: i64,
+        flags: u8,
+        length: u64,
+        delta: &mut DirStat,
+        attr: &mut FileAttr,
+        skip_perm_check: bool,
+    ) -> Result<(), MetaError> {
+        let initial = (delta.clone(), attr.clone());
+        let initial = &initial;
+        (*delta, *attr) = self
+            .mirror(
+                "truncate_file",
+                |s| async move {
+                    let (mut delta, mut attr) = initial.clone();
+                    s.truncate_file(inode, flags, length, &mut delta, &mut attr, skip_perm_check)
+                        .await?;
+                    Ok((delta, attr))
+                },
+                |a, b| same_attr(&a.1, &b.1),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fallocate_file(
+        &self,
+        inode: i64,
+        mode: u8,
+        offset: u64,
+        size: u64,
+        delta: &mut DirStat,
+        attr: &mut FileAttr,
+    ) -> Result<(), MetaError> {
+        let initial = (delta.clone(), attr.clone());
+        let initial = &initial;
+        (*delta, *attr) = self
+            .mirror(
+                "fallocate_file",
+                |s| async move {
+                    let (mut delta, mut attr) = initial.clone();
+                    s.fallocate_file(inode, mode, offset, size, &mut delta, &mut attr)
+                        .await?;
+                    Ok((delta, attr))
+                },
+                |a, b| same_attr(&a.1, &b.1),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn compact_chunk(
+        &self,
+        inode: i64,
+        index: u32,
+        origin: &[u8],
+        slices: &[SliceDesc],
+        skipped: i32,
+        pos: u32,
+        id: u64,
+        size: u32,
+        delayed: &[u8],
+    ) -> Result<(), MetaError> {
+        self.mirror(
+            "compact_chunk",
+            |s| async move {
+                s.compact_chunk(
+                    inode, index, origin, slices, skipped, pos, id, size, delayed,
+                )
+                .await
+            },
+            any,
+        )
+        .await
+    }
+
+    async fn get_plock(
+        &self,
+        inode: i64,
+        query: &FileLockQuery,
+    ) -> Result<FileLockInfo, MetaError> {
+        self.primary().get_plock(inode, query).await
+    }
+
+    async fn set_plock(
+        &self,
+        inode: i64,
+        owner: i64,
+        block: bool,
+        lock_type: FileLockType,
+        range: FileLockRange,
+        pid: u32,
+    ) -> Result<(), MetaError> {
+        self.primary()
+            .set_plock(inode, owner, block, lock_type, range, pid)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::config::{CacheConfig, ClientOptions, Config, DatabaseConfig, DatabaseType};
+    use crate::meta::stores::DatabaseMetaStore;
+
+    async fn new_store(dir: &std::path::Path, name: &str) -> Arc<dyn MetaStore> {
+        let config = Config {
+            database: DatabaseConfig {
+                db_config: DatabaseType::Sqlite {
+                    url: format!("sqlite://{}?mode=rwc", dir.join(name).display()),
+                },
+            },
+            cache: CacheConfig::default(),
+            client: ClientOptions::default(),
+        };
+        let store = DatabaseMetaStore::from_config(config)
+            .await
+            .expect("create store");
+        Arc::new(store)
+    }
+
+    async fn new_replicated(
+        dir: &std::path::Path,
+        strict: bool,
+    ) -> (ReplicatedMetaStore, Arc<dyn MetaStore>, Arc<dyn MetaStore>) {
+        let primary = new_store(dir, "primary.db").await;
+        let secondary = new_store(dir, "secondary.db").await;
+        let replicated = ReplicatedMetaStore::new(primary.clone(), secondary.clone(), strict);
+        replicated.initialize().await.unwrap();
+        (replicated, primary, secondary)
+    }
+
+    #[tokio::test]
+    async fn test_mirror_and_promote() {
+        let dir = tempfile::tempdir().unwrap();
+        let (replicated, primary, secondary) = new_replicated(dir.path(), false).await;
+        let root = replicated.root_ino();
+
+        let sub = replicated.mkdir(root, "sub".into()).await.unwrap();
+        let file = replicated.create_file(sub, "f".into()).await.unwrap();
+        replicated.set_file_size(file, 42).await.unwrap();
+        for store in [&primary, &secondary] {
+            assert_eq!(store.lookup(sub, "f").await.unwrap(), Some(file));
+            assert_eq!(store.stat(file).await.unwrap().unwrap().size, 42);
+        }
+        let status = replicated.status();
+        assert_eq!(status.state, ReplicaState::InSync);
+        assert_eq!(status.mirrored, 4);
+
+        // Failover keeps both stores in sync.
+        replicated.promote(false).await.unwrap();
+        replicated.unlink(sub, "f").await.unwrap();
+        for store in [&primary, &secondary] {
+            assert_eq!(store.lookup(sub, "f").await.unwrap(), None);
+        }
+
+        replicated.demote().await;
+        replicated.mkdir(root, "only".into()).await.unwrap();
+        assert!(secondary.lookup(root, "only").await.unwrap().is_some());
+        assert_eq!(primary.lookup(root, "only").await.unwrap(), None);
+        assert_eq!(replicated.status().state, ReplicaState::Detached);
+    }
+
+    #[tokio::test]
+    async fn test_divergence() {
+        let dir = tempfile::tempdir().unwrap();
+        let (replicated, primary, secondary) = new_replicated(dir.path(), false).await;
+        let root = replicated.root_ino();
+
+        secondary.mkdir(root, "taken".into()).await.unwrap();
+        replicated.mkdir(root, "taken".into()).await.unwrap();
+        let status = replicated.status();
+        assert_eq!(status.state, ReplicaState::Diverged);
+        assert!(status.divergence.unwrap().starts_with("mkdir"));
+
+        // No longer mirrored, and only promoted with force.
+        replicated.mkdir(root, "later".into()).await.unwrap();
+        assert_eq!(secondary.lookup(root, "later").await.unwrap(), None);
+        assert!(replicated.promote(false).await.is_err());
+        replicated.promote(true).await.unwrap();
+        assert_eq!(replicated.status().state, ReplicaState::Detached);
+        replicated.mkdir(root, "after".into()).await.unwrap();
+        assert_eq!(primary.lookup(root, "after").await.unwrap(), None);
+
+        // Strict replication refuses what it cannot mirror.
+        let dir = tempfile::tempdir().unwrap();
+        let (replicated, _, secondary) = new_replicated(dir.path(), true).await;
+        secondary.mkdir(root, "taken".into()).await.unwrap();
+        assert!(replicated.mkdir(root, "taken".into()).await.is_err());
+        assert!(replicated.mkdir(root, "other".into()).await.is_err());
+        assert!(replicated.stat(root).await.unwrap().is_some());
+    }
+}
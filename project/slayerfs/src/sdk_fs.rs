@@ -88,6 +88,9 @@ pub trait ClientBackend: Send + Sync + 'static {
 
 pub type DynClient = Arc<dyn ClientBackend>;
 
+/// Bytes moved per read when copying across volumes.
+const COPY_CHUNK: u64 = 1024 * 1024;
+
 /// High-level std-like filesystem wrapper that owns a client.
 #[derive(Clone)]
 pub struct Client {
@@ -317,6 +320,76 @@ impl Client {
         self.client.readlink(&path).await
     }
 
+    /// Whether `other` works on the same volume, i.e. shares this client's backend.
+    pub fn same_volume(&self, other: &Client) -> bool {
+        Arc::ptr_eq(&self.client, &other.client)
+    }
+
+    /// Rename `old` of this volume to `new` of the volume of `dst`. Fails with
+    /// `ErrorKind::CrossesDevices` carrying a [`CrossVolumeError`] when the volumes differ,
+    /// see [`Self::move_to`] for a fallback.
+    pub async fn rename_to(
+        &self,
+        old: impl AsRef<Path>,
+        dst: &Client,
+        new: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let old = path_to_str(old)?;
+        let new = path_to_str(new)?;
+        if !self.same_volume(dst) {
+            return Err(CrossVolumeError::new("rename", old, new).into());
+        }
+        self.client.rename(&old, &new).await
+    }
+
+    /// Hard link `original` of this volume as `link` of the volume of `dst`. Links can't
+    /// span volumes, that fails with `ErrorKind::CrossesDevices` carrying a
+    /// [`CrossVolumeError`].
+    pub async fn hard_link_to(
+        &self,
+        original: impl AsRef<Path>,
+        dst: &Client,
+        link: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let original = path_to_str(original)?;
+        let link = path_to_str(link)?;
+        if !self.same_volume(dst) {
+            return Err(CrossVolumeError::new("link", original, link).into());
+        }
+        self.client.link(&original, &link).await?;
+        Ok(())
+    }
+
+    /// Move `old` of this volume to `new` of the volume of `dst`: a rename within a volume,
+    /// else a recursive copy followed by the removal of `old`, like `mv` across mounts.
+    ///
+    /// Hard links within the moved tree are not preserved across volumes, every link becomes
+    /// a separate copy. If the copy fails, `old` is left untouched along with what was
+    /// copied to `new` so far.
+    pub async fn move_to(
+        &self,
+        old: impl AsRef<Path>,
+        dst: &Client,
+        new: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let old = path_to_str(old)?;
+        let new = path_to_str(new)?;
+        if self.same_volume(dst) {
+            return self.client.rename(&old, &new).await;
+        }
+        if dst.client.exists(&new).await {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, new));
+        }
+
+        let attr = self.client.lstat(&old).await?;
+        copy_tree(self, old.clone(), dst, new).await?;
+        if attr.kind == MetaFileType::Dir {
+            self.client.remove_dir_all(&old).await
+        } else {
+            self.client.unlink(&old).await
+        }
+    }
+
     /// Check user's permissions for a file.
     pub async fn access(&self, path: impl AsRef<Path>, mode: AccessMode) -> io::Result<()> {
         let path = path_to_str(path)?;
@@ -445,6 +518,91 @@ where
     }
 }
 
+/// Operation refused because its paths are on different volumes, the payload of the
+/// `ErrorKind::CrossesDevices` errors of [`Client::rename_to`] and [`Client::hard_link_to`].
+///
+/// ```ignore
+/// if let Some(e) = err.get_ref().and_then(|e| e.downcast_ref::<CrossVolumeError>()) {
+///     eprintln!("{} needs a copy", e.from);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossVolumeError {
+    /// `rename` or `link`.
+    pub op: &'static str,
+    pub from: String,
+    pub to: String,
+}
+
+impl CrossVolumeError {
+    fn new(op: &'static str, from: String, to: String) -> Self {
+        Self { op, from, to }
+    }
+}
+
+impl std::fmt::Display for CrossVolumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot {} {} to {}: paths are on different volumes",
+            self.op, self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for CrossVolumeError {}
+
+impl From<CrossVolumeError> for io::Error {
+    fn from(e: CrossVolumeError) -> Self {
+        io::Error::new(io::ErrorKind::CrossesDevices, e)
+    }
+}
+
+// Copy `src_path` of `src` to `dst_path` of `dst` with the modes, recursing into directories.
+// Symlinks are copied as links.
+async fn copy_tree(
+    src: &Client,
+    src_path: String,
+    dst: &Client,
+    dst_path: String,
+) -> io::Result<()> {
+    let mut pending = vec![(src_path, dst_path)];
+    while let Some((from, to)) = pending.pop() {
+        let attr = src.client.lstat(&from).await?;
+        match attr.kind {
+            MetaFileType::Dir => {
+                dst.client.mkdir(&to).await?;
+                for entry in src.client.readdir(&from).await? {
+                    pending.push((
+                        format!("{}/{}", from.trim_end_matches('/'), entry.name),
+                        format!("{}/{}", to.trim_end_matches('/'), entry.name),
+                    ));
+                }
+            }
+            MetaFileType::Symlink => {
+                let target = src.client.readlink(&from).await?;
+                dst.client.symlink(&to, &target).await?;
+                continue;
+            }
+            MetaFileType::File => {
+                dst.client.create_file(&to, true).await?;
+                let mut offset = 0;
+                while offset < attr.size {
+                    let len = (attr.size - offset).min(COPY_CHUNK) as usize;
+                    let data = src.client.read_at(&from, offset, len).await?;
+                    if data.is_empty() {
+                        break;
+                    }
+                    dst.client.write_at(&to, offset, &data).await?;
+                    offset += data.len() as u64;
+                }
+            }
+        }
+        dst.set_permissions(&to, attr.mode & 0o7777).await?;
+    }
+    Ok(())
+}
+
 fn path_to_str(path: impl AsRef<Path>) -> io::Result<String> {
     let s = path.as_ref().to_string_lossy().to_string();
     if s.is_empty() {
@@ -1236,22 +1394,15 @@ bitflags::bitflags! {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::chuck::chunk::ChunkLayout;
-    use crate::fs::{CallerIdentity, FileSystemConfig};
-    use crate::vfs::sdk::LocalClient;
+    use crate::vfs::sdk::Volume;
     use futures::task::noop_waker;
     use std::task::Context;
-    use tempfile::tempdir;
     use tokio::sync::Notify;
 
-    async fn local_client() -> (tempfile::TempDir, Client) {
-        let tmp = tempdir().expect("tempdir");
-        let layout = ChunkLayout::default();
-        let config = FileSystemConfig::default().with_caller(CallerIdentity::root());
-        let cli = LocalClient::new_local_with_config(tmp.path(), layout, config)
-            .await
-            .expect("init LocalClient");
-        (tmp, Client::new(Arc::new(cli)))
+    async fn local_client() -> (Volume, Client) {
+        let volume = Volume::ephemeral().await.expect("init volume");
+        let client = Client::new(volume.client());
+        (volume, client)
     }
 
     struct MockClient {
@@ -1404,7 +1555,7 @@ mod tests {
 
     #[tokio::test]
     async fn open_create_new_fails_if_exists() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         let mut a = OpenOptions::new();
         a.write(true).create(true);
@@ -1421,7 +1572,7 @@ mod tests {
 
     #[tokio::test]
     async fn open_truncate_zeros_file() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         let mut a = OpenOptions::new();
         a.write(true).create(true).truncate(true);
@@ -1438,7 +1589,7 @@ mod tests {
 
     #[tokio::test]
     async fn append_writes_to_end() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         let mut a = OpenOptions::new();
         a.write(true).create(true).truncate(true);
@@ -1456,7 +1607,7 @@ mod tests {
 
     #[tokio::test]
     async fn seek_and_read_to_end() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         let mut a = OpenOptions::new();
         a.write(true).create(true).truncate(true);
@@ -1474,7 +1625,7 @@ mod tests {
 
     #[tokio::test]
     async fn dir_ops_and_error_kinds() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.create_dir_all("/d/e").await.unwrap();
         fs.write("/d/e/f.txt", b"x").await.unwrap();
@@ -1505,7 +1656,7 @@ mod tests {
 
     #[tokio::test]
     async fn permission_denied_on_wrong_mode() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
         fs.write("/p.txt", b"hi").await.unwrap();
 
         let mut ro = OpenOptions::new();
@@ -1517,7 +1668,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_exists() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         assert!(!fs.exists("/noexist.txt").await);
         fs.write("/exist.txt", b"data").await.unwrap();
@@ -1528,7 +1679,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_metadata_extended() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.write("/meta.txt", b"hello").await.unwrap();
         let meta = fs.metadata("/meta.txt").await.unwrap();
@@ -1542,7 +1693,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_remove_dir_all() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.create_dir_all("/rm/a/b").await.unwrap();
         fs.write("/rm/a/b/f1.txt", b"1").await.unwrap();
@@ -1559,7 +1710,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_remove_dir_all_refuses_root() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.write("/keep.txt", b"keep").await.unwrap();
         fs.create_dir_all("/keepdir").await.unwrap();
@@ -1575,7 +1726,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_create_dir_already_exists() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.create_dir("/dir").await.unwrap();
         let err = fs.create_dir("/dir").await.unwrap_err();
@@ -1584,7 +1735,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_symlink_operations() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.write("/orig.txt", b"original").await.unwrap();
 
@@ -1600,7 +1751,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_hard_link_operations() {
-        let (_tmp, fs) = local_client().await;
+        let (_volume, fs) = local_client().await;
 
         fs.write("/source.txt", b"content").await.unwrap();
 
@@ -1613,4 +1764,47 @@ mod tests {
             assert!(link_meta.nlink() >= 2);
         }
     }
+
+    #[tokio::test]
+    async fn test_cross_volume_operations() {
+        let (_volume_a, a) = local_client().await;
+        let (_volume_b, b) = local_client().await;
+        assert!(a.same_volume(&a.clone()));
+        assert!(!a.same_volume(&b));
+
+        a.create_dir_all("/dir/sub").await.unwrap();
+        a.write("/dir/sub/f", b"payload").await.unwrap();
+        a.set_permissions("/dir/sub/f", 0o600).await.unwrap();
+        a.symlink("sub/f", "/dir/l").await.unwrap();
+
+        for err in [
+            a.rename_to("/dir", &b, "/dir").await.unwrap_err(),
+            a.hard_link_to("/dir/sub/f", &b, "/f").await.unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), io::ErrorKind::CrossesDevices);
+            let cross = err
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<CrossVolumeError>())
+                .unwrap();
+            assert!(cross.from.starts_with("/dir"));
+        }
+
+        // Within a volume a move is a rename, across volumes a copy and delete.
+        a.move_to("/dir", &a, "/moved").await.unwrap();
+        a.move_to("/moved", &b, "/dir").await.unwrap();
+        assert!(!a.exists("/moved").await);
+        assert_eq!(b.read("/dir/sub/f").await.unwrap(), b"payload");
+        assert_eq!(
+            b.metadata("/dir/sub/f").await.unwrap().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(b.read_link("/dir/l").await.unwrap(), "sub/f");
+
+        b.move_to("/dir", &a, "/dir").await.unwrap();
+        a.write("/x", b"x").await.unwrap();
+        b.write("/x", b"y").await.unwrap();
+        let err = a.move_to("/x", &b, "/x").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(a.read("/x").await.unwrap(), b"x");
+    }
 }
@@ -0,0 +1,716 @@
+//! Tenants sharing one volume.
+//!
+//! A [`TenantRegistry`] lists the tenants of a volume, each confined to its own root
+//! directory with its own quota, access keys and bearer tokens. Gateways and CSI
+//! provisioners authenticate a request with [`TenantRegistry::client`] and serve it through
+//! the returned [`TenantClient`], which resolves every path below the tenant root, refuses
+//! symlinks pointing out of it and enforces the tenant quota. One volume and metadata
+//! backend can then serve many teams without them seeing each other's files.
+//!
+//! Isolation only holds for access through tenant clients: a FUSE mount of the whole
+//! volume still sees every tenant.
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::OnceCell;
+
+use crate::meta::store::{
+    DirEntry, FileAttr, FileType, SetAttrFlags, SetAttrRequest, StatFsSnapshot,
+};
+use crate::sdk_fs::{ClientBackend, DynClient};
+
+/// Hard limits of a tenant, unset fields are not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct TenantQuota {
+    /// Bytes of regular files below the tenant root.
+    pub space: Option<u64>,
+    /// Files, directories and symlinks below the tenant root.
+    pub inodes: Option<u64>,
+}
+
+/// Access key pair of a tenant, as used by S3-style gateways.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AccessKey {
+    pub id: String,
+    pub secret: String,
+}
+
+/// One tenant of a [`TenantRegistry`], as read from its configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Directory of the volume the tenant is confined to, e.g. `/tenants/team-a`.
+    pub root: String,
+    #[serde(default)]
+    pub quota: TenantQuota,
+    #[serde(default)]
+    pub access_keys: Vec<AccessKey>,
+    /// Bearer tokens accepted for the tenant.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+/// What a request presents to be mapped to a tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    AccessKey { id: String, secret: String },
+    Token(String),
+}
+
+impl Credentials {
+    /// Credentials from CSI request secrets: `accessKeyId` with `secretAccessKey`, or
+    /// `token`.
+    pub fn from_secrets(secrets: &HashMap<String, String>) -> Option<Self> {
+        if let (Some(id), Some(secret)) =
+            (secrets.get("accessKeyId"), secrets.get("secretAccessKey"))
+        {
+            return Some(Self::AccessKey {
+                id: id.clone(),
+                secret: secret.clone(),
+            });
+        }
+        secrets.get("token").map(|token| Self::Token(token.clone()))
+    }
+
+    /// Credentials from an HTTP `Authorization: Bearer <token>` header value.
+    pub fn from_bearer(header: &str) -> Option<Self> {
+        let token = header.strip_prefix("Bearer ")?.trim();
+        (!token.is_empty()).then(|| Self::Token(token.to_string()))
+    }
+}
+
+type Digest32 = [u8; 32];
+
+fn digest(secret: &str) -> Digest32 {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+/// A tenant of a volume and what it currently uses.
+#[derive(Debug)]
+pub struct Tenant {
+    name: String,
+    root: String,
+    quota: TenantQuota,
+    // Counted once by the first client, then kept up to date by every client.
+    usage: OnceCell<TenantUsage>,
+}
+
+#[derive(Debug, Default)]
+struct TenantUsage {
+    space: AtomicU64,
+    inodes: AtomicU64,
+}
+
+impl Tenant {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn quota(&self) -> TenantQuota {
+        self.quota
+    }
+
+    /// Bytes and inodes in use below the tenant root, once a client was created.
+    pub fn usage(&self) -> Option<(u64, u64)> {
+        self.usage.get().map(|usage| {
+            (
+                usage.space.load(Ordering::Relaxed),
+                usage.inodes.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
+
+/// Tenants of a volume and the credentials selecting them.
+#[derive(Debug, Default)]
+pub struct TenantRegistry {
+    tenants: HashMap<String, Arc<Tenant>>,
+    // Access key id to the tenant name and the digest of the secret.
+    access_keys: HashMap<String, (String, Digest32)>,
+    // Token digest to the tenant name.
+    tokens: HashMap<Digest32, String>,
+}
+
+impl TenantRegistry {
+    /// Registry of `configs`. Names, access key ids and tokens must be unique, and no tenant
+    /// root may lie inside another.
+    pub fn new(configs: Vec<TenantConfig>) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut registry = Self::default();
+        let mut roots: Vec<(String, String)> = Vec::new();
+        for config in configs {
+            let root = normalize(&config.root);
+            if root == "/" {
+                return Err(invalid(format!(
+                    "tenant {} can't use the volume root",
+                    config.name
+                )));
+            }
+            if let Some((other, _)) = roots
+                .iter()
+                .find(|(_, r)| is_within(&root, r) || is_within(r, &root))
+            {
+                return Err(invalid(format!(
+                    "roots of tenants {} and {} overlap",
+                    config.name, other
+                )));
+            }
+            if registry.tenants.contains_key(&config.name) {
+                return Err(invalid(format!("duplicate tenant {}", config.name)));
+            }
+            for key in &config.access_keys {
+                let value = (config.name.clone(), digest(&key.secret));
+                if registry.access_keys.insert(key.id.clone(), value).is_some() {
+                    return Err(invalid(format!("duplicate access key {}", key.id)));
+                }
+            }
+            for token in &config.tokens {
+                if registry
+                    .tokens
+                    .insert(digest(token), config.name.clone())
+                    .is_some()
+                {
+                    return Err(invalid(format!(
+                        "token of tenant {} is used twice",
+                        config.name
+                    )));
+                }
+            }
+            roots.push((config.name.clone(), root.clone()));
+            registry.tenants.insert(
+                config.name.clone(),
+                Arc::new(Tenant {
+                    name: config.name,
+                    root,
+                    quota: config.quota,
+                    usage: OnceCell::new(),
+                }),
+            );
+        }
+        Ok(registry)
+    }
+
+    /// Registry of the tenants listed in a YAML file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let configs: Vec<TenantConfig> = serde_yaml::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Self::new(configs)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Tenant>> {
+        self.tenants.get(name).cloned()
+    }
+
+    /// The tenant selected by `credentials`, failing with `PermissionDenied` if none is.
+    pub fn authenticate(&self, credentials: &Credentials) -> io::Result<Arc<Tenant>> {
+        let name = match credentials {
+            Credentials::AccessKey { id, secret } => self
+                .access_keys
+                .get(id)
+                .filter(|(_, expected)| *expected == digest(secret))
+                .map(|(name, _)| name),
+            Credentials::Token(token) => self.tokens.get(&digest(token)),
+        };
+        name.and_then(|name| self.get(name)).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "unknown tenant credentials",
+            )
+        })
+    }
+
+    /// Authenticate `credentials` and serve the tenant from `backend`, the whole volume.
+    pub async fn client(
+        &self,
+        credentials: &Credentials,
+        backend: DynClient,
+    ) -> io::Result<TenantClient> {
+        TenantClient::new(self.authenticate(credentials)?, backend).await
+    }
+}
+
+/// Lexically normalized absolute form of `path`, `..` stops at the root.
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Whether the normalized `path` is `root` or below it.
+fn is_within(path: &str, root: &str) -> bool {
+    path == root
+        || path
+            .strip_prefix(root)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn quota_exceeded() -> io::Error {
+    io::Error::from_raw_os_error(libc::EDQUOT)
+}
+
+/// A [`ClientBackend`] confined to the root of one tenant. Paths are relative to that root,
+/// `/` being the root itself.
+///
+/// The quota is checked before each operation against usage counted when the first client
+/// of the tenant was created. Concurrent writers can overshoot it by what they have in
+/// flight.
+pub struct TenantClient {
+    tenant: Arc<Tenant>,
+    backend: DynClient,
+}
+
+impl TenantClient {
+    /// Serve `tenant` from `backend`, creating its root if missing.
+    pub async fn new(tenant: Arc<Tenant>, backend: DynClient) -> io::Result<Self> {
+        backend.mkdir_p(&tenant.root).await?;
+        let client = Self { tenant, backend };
+        client
+            .tenant
+            .usage
+            .get_or_try_init(|| client.count_usage())
+            .await?;
+        Ok(client)
+    }
+
+    pub fn tenant(&self) -> &Arc<Tenant> {
+        &self.tenant
+    }
+
+    fn usage(&self) -> &TenantUsage {
+        // Set by `new`.
+        self.tenant.usage.get().unwrap()
+    }
+
+    async fn count_usage(&self) -> io::Result<TenantUsage> {
+        let usage = TenantUsage::default();
+        let mut seen = HashSet::new();
+        let mut pending = vec![self.tenant.root.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in self.backend.readdir(&dir).await? {
+                let path = format!("{}/{}", dir, entry.name);
+                if !seen.insert(entry.ino) {
+                    continue;
+                }
+                usage.inodes.fetch_add(1, Ordering::Relaxed);
+                match entry.kind {
+                    FileType::Dir => pending.push(path),
+                    FileType::File => {
+                        let attr = self.backend.lstat(&path).await?;
+                        usage.space.fetch_add(attr.size, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Path of the volume for the tenant path `path`.
+    fn resolve(&self, path: &str) -> String {
+        let path = normalize(path);
+        if path == "/" {
+            self.tenant.root.clone()
+        } else {
+            format!("{}{}", self.tenant.root, path)
+        }
+    }
+
+    fn reserve_inode(&self) -> io::Result<()> {
+        let usage = self.usage();
+        if self
+            .tenant
+            .quota
+            .inodes
+            .is_some_and(|limit| usage.inodes.load(Ordering::Relaxed) >= limit)
+        {
+            return Err(quota_exceeded());
+        }
+        Ok(())
+    }
+
+    fn reserve_space(&self, bytes: u64) -> io::Result<()> {
+        let usage = self.usage();
+        if bytes > 0
+            && self.tenant.quota.space.is_some_and(|limit| {
+                usage.space.load(Ordering::Relaxed).saturating_add(bytes) > limit
+            })
+        {
+            return Err(quota_exceeded());
+        }
+        Ok(())
+    }
+
+    fn add(&self, inodes: i64, space: i64) {
+        let usage = self.usage();
+        let apply = |counter: &AtomicU64, delta: i64| {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_add_signed(delta))
+            });
+        };
+        apply(&usage.inodes, inodes);
+        apply(&usage.space, space);
+    }
+
+    // Inodes and bytes freed by removing the last link of `attr`.
+    fn freed_by(attr: &FileAttr) -> (i64, i64) {
+        match attr.kind {
+            _ if attr.nlink > 1 && attr.kind != FileType::Dir => (0, 0),
+            FileType::File => (-1, -(attr.size as i64)),
+            _ => (-1, 0),
+        }
+    }
+
+    async fn resize(&self, path: &str, size: u64) -> io::Result<i64> {
+        let old = self.backend.stat(path).await?.size;
+        self.reserve_space(size.saturating_sub(old))?;
+        Ok(size as i64 - old as i64)
+    }
+}
+
+#[async_trait]
+impl ClientBackend for TenantClient {
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.reserve_inode()?;
+        self.backend.mkdir(&self.resolve(path)).await?;
+        self.add(1, 0);
+        Ok(())
+    }
+
+    async fn mkdir_p(&self, path: &str) -> io::Result<()> {
+        let mut dir = String::new();
+        for part in normalize(path).split('/').filter(|p| !p.is_empty()) {
+            dir = format!("{dir}/{part}");
+            match self.backend.stat(&self.resolve(&dir)).await {
+                Ok(attr) if attr.kind == FileType::Dir => continue,
+                Ok(_) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => self.mkdir(&dir).await?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_file(&self, path: &str, create_new: bool) -> io::Result<()> {
+        let resolved = self.resolve(path);
+        let exists = self.backend.exists(&resolved).await;
+        if !exists {
+            self.reserve_inode()?;
+        }
+        self.backend.create_file(&resolved, create_new).await?;
+        if !exists {
+            self.add(1, 0);
+        }
+        Ok(())
+    }
+
+    async fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let resolved = self.resolve(path);
+        let end = offset.saturating_add(data.len() as u64);
+        let old = self.backend.stat(&resolved).await?.size;
+        self.reserve_space(end.saturating_sub(old))?;
+        let written = self.backend.write_at(&resolved, offset, data).await?;
+        let end = offset.saturating_add(written as u64);
+        self.add(0, end.saturating_sub(old) as i64);
+        Ok(written)
+    }
+
+    async fn read_at(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.backend.read_at(&self.resolve(path), offset, len).await
+    }
+
+    async fn readdir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.backend.readdir(&self.resolve(path)).await
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<FileAttr> {
+        self.backend.stat(&self.resolve(path)).await
+    }
+
+    async fn unlink(&self, path: &str) -> io::Result<()> {
+        let resolved = self.resolve(path);
+        let attr = self.backend.lstat(&resolved).await?;
+        self.backend.unlink(&resolved).await?;
+        let (inodes, space) = Self::freed_by(&attr);
+        self.add(inodes, space);
+        Ok(())
+    }
+
+    async fn rmdir(&self, path: &str) -> io::Result<()> {
+        let resolved = self.resolve(path);
+        if resolved == self.tenant.root {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot remove tenant root",
+            ));
+        }
+        self.backend.rmdir(&resolved).await?;
+        self.add(-1, 0);
+        Ok(())
+    }
+
+    async fn rename(&self, old: &str, new: &str) -> io::Result<()> {
+        let (old, new) = (self.resolve(old), self.resolve(new));
+        if old == self.tenant.root || new == self.tenant.root {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot rename tenant root",
+            ));
+        }
+        let replaced = self.backend.lstat(&new).await.ok();
+        self.backend.rename(&old, &new).await?;
+        if let Some(attr) = replaced {
+            let (inodes, space) = Self::freed_by(&attr);
+            self.add(inodes, space);
+        }
+        Ok(())
+    }
+
+    async fn truncate(&self, path: &str, size: u64) -> io::Result<()> {
+        let resolved = self.resolve(path);
+        let delta = self.resize(&resolved, size).await?;
+        self.backend.truncate(&resolved, size).await?;
+        self.add(0, delta);
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> bool {
+        self.backend.exists(&self.resolve(path)).await
+    }
+
+    async fn set_attr(
+        &self,
+        path: &str,
+        req: &SetAttrRequest,
+        flags: SetAttrFlags,
+    ) -> io::Result<FileAttr> {
+        let resolved = self.resolve(path);
+        let delta = match req.size {
+            Some(size) => self.resize(&resolved, size).await?,
+            None => 0,
+        };
+        let attr = self.backend.set_attr(&resolved, req, flags).await?;
+        self.add(0, delta);
+        Ok(attr)
+    }
+
+    async fn lstat(&self, path: &str) -> io::Result<FileAttr> {
+        self.backend.lstat(&self.resolve(path)).await
+    }
+
+    async fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        if path == "/" {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot remove tenant root",
+            ));
+        }
+        // Through this client, so every removal is accounted.
+        for entry in self.readdir(&path).await? {
+            let child = format!("{}/{}", path, entry.name);
+            match entry.kind {
+                FileType::Dir => Box::pin(self.remove_dir_all(&child)).await?,
+                _ => self.unlink(&child).await?,
+            }
+        }
+        self.rmdir(&path).await
+    }
+
+    async fn stat_fs(&self) -> io::Result<StatFsSnapshot> {
+        let volume = self.backend.stat_fs().await?;
+        let usage = self.usage();
+        let (space, inodes) = (
+            usage.space.load(Ordering::Relaxed),
+            usage.inodes.load(Ordering::Relaxed),
+        );
+        let quota = self.tenant.quota;
+        Ok(StatFsSnapshot {
+            total_space: quota
+                .space
+                .map_or(volume.total_space, |l| l.min(volume.total_space)),
+            available_space: quota.space.map_or(volume.available_space, |l| {
+                l.saturating_sub(space).min(volume.available_space)
+            }),
+            used_inodes: inodes,
+            available_inodes: quota.inodes.map_or(volume.available_inodes, |l| {
+                l.saturating_sub(inodes).min(volume.available_inodes)
+            }),
+        })
+    }
+
+    async fn link(&self, existing: &str, link_path: &str) -> io::Result<FileAttr> {
+        self.backend
+            .link(&self.resolve(existing), &self.resolve(link_path))
+            .await
+    }
+
+    async fn symlink(&self, link_path: &str, target: &str) -> io::Result<FileAttr> {
+        let resolved = self.resolve(link_path);
+        // Targets are stored as seen by the volume, so they resolve inside the tenant root
+        // whoever follows them.
+        let stored = if target.starts_with('/') {
+            self.resolve(target)
+        } else {
+            let dir = resolved.rsplit_once('/').map_or("", |(dir, _)| dir);
+            if !is_within(&normalize(&format!("{dir}/{target}")), &self.tenant.root) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "symlink target escapes the tenant root",
+                ));
+            }
+            target.to_string()
+        };
+        self.reserve_inode()?;
+        let attr = self.backend.symlink(&resolved, &stored).await?;
+        self.add(1, 0);
+        Ok(attr)
+    }
+
+    async fn readlink(&self, path: &str) -> io::Result<String> {
+        let target = self.backend.readlink(&self.resolve(path)).await?;
+        let root = &self.tenant.root;
+        Ok(match target.strip_prefix(root.as_str()) {
+            Some("") => "/".to_string(),
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            _ => target,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::sdk::Volume;
+
+    fn config(name: &str, root: &str) -> TenantConfig {
+        TenantConfig {
+            name: name.to_string(),
+            root: root.to_string(),
+            quota: TenantQuota::default(),
+            access_keys: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_registry_validation() {
+        let overlapping = vec![config("a", "/t/a"), config("b", "/t/a/../a/b")];
+        assert!(TenantRegistry::new(overlapping).is_err());
+        assert!(TenantRegistry::new(vec![config("a", "/..")]).is_err());
+        assert!(TenantRegistry::new(vec![config("a", "/t/a"), config("a", "/t/b")]).is_err());
+        // Siblings sharing a name prefix don't overlap.
+        assert!(TenantRegistry::new(vec![config("a", "/t/a"), config("b", "/t/ab")]).is_ok());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let mut a = config("a", "/t/a");
+        a.access_keys.push(AccessKey {
+            id: "AKA".to_string(),
+            secret: "s3cr3t".to_string(),
+        });
+        let mut b = config("b", "/t/b");
+        b.tokens.push("tok-b".to_string());
+        let registry = TenantRegistry::new(vec![a, b]).unwrap();
+
+        let secrets = HashMap::from([
+            ("accessKeyId".to_string(), "AKA".to_string()),
+            ("secretAccessKey".to_string(), "s3cr3t".to_string()),
+        ]);
+        let creds = Credentials::from_secrets(&secrets).unwrap();
+        assert_eq!(registry.authenticate(&creds).unwrap().name(), "a");
+        let creds = Credentials::from_bearer("Bearer tok-b").unwrap();
+        assert_eq!(registry.authenticate(&creds).unwrap().name(), "b");
+
+        let wrong = Credentials::AccessKey {
+            id: "AKA".to_string(),
+            secret: "guess".to_string(),
+        };
+        let err = registry.authenticate(&wrong).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(
+            registry
+                .authenticate(&Credentials::Token("tok-a".to_string()))
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenant_isolation_and_quota() {
+        let ephemeral = Volume::ephemeral().await.unwrap();
+        let volume: DynClient = ephemeral.client();
+        volume.mkdir_p("/t/b").await.unwrap();
+        volume.create_file("/t/b/secret", false).await.unwrap();
+
+        let mut a = config("a", "/t/a");
+        a.tokens.push("tok-a".to_string());
+        a.quota = TenantQuota {
+            space: Some(100),
+            inodes: Some(3),
+        };
+        let registry = TenantRegistry::new(vec![a]).unwrap();
+        let creds = Credentials::Token("tok-a".to_string());
+        let client = registry.client(&creds, volume.clone()).await.unwrap();
+
+        client.mkdir_p("/docs").await.unwrap();
+        client.create_file("/docs/f", true).await.unwrap();
+        assert!(volume.exists("/t/a/docs/f").await);
+        // `..` stops at the tenant root.
+        assert!(!client.exists("/../b/secret").await);
+        assert!(client.exists("/../docs/f").await);
+
+        client.write_at("/docs/f", 0, &[1; 60]).await.unwrap();
+        let err = client.write_at("/docs/f", 60, &[1; 41]).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+        client.write_at("/docs/f", 20, &[2; 80]).await.unwrap();
+        assert_eq!(registry.get("a").unwrap().usage(), Some((100, 2)));
+
+        let err = client
+            .symlink("/docs/out", "../../../b/secret")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        client.symlink("/docs/abs", "/docs/f").await.unwrap();
+        assert_eq!(
+            volume.readlink("/t/a/docs/abs").await.unwrap(),
+            "/t/a/docs/f"
+        );
+        assert_eq!(client.readlink("/docs/abs").await.unwrap(), "/docs/f");
+        let err = client.create_file("/docs/g", true).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+
+        let statfs = client.stat_fs().await.unwrap();
+        assert_eq!(statfs.total_space, 100);
+        assert_eq!(statfs.available_space, 0);
+        assert_eq!(statfs.available_inodes, 0);
+
+        client.remove_dir_all("/docs").await.unwrap();
+        assert_eq!(registry.get("a").unwrap().usage(), Some((0, 0)));
+        assert!(client.remove_dir_all("/").await.is_err());
+
+        // A second client of the tenant starts from the shared usage.
+        client.create_file("/g", true).await.unwrap();
+        client.write_at("/g", 0, &[3; 10]).await.unwrap();
+        let again = registry.client(&creds, volume).await.unwrap();
+        assert_eq!(again.tenant().usage(), Some((10, 1)));
+    }
+}
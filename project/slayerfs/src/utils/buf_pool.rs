@@ -0,0 +1,160 @@
+//! Reusable read buffers.
+//!
+//! Every read used to allocate a fresh buffer for its reply, which dominated the CPU time of
+//! small random reads. [`BufferPool`] keeps buffers freed by earlier reads, grouped by
+//! power-of-two size classes, and hands them out again. A [`PooledBuf`] goes back to its
+//! pool when dropped, also after being turned into [`Bytes`] for a FUSE reply.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// Smallest size class, requests below it get a buffer of this size.
+const MIN_CLASS: usize = 4 * 1024;
+/// Largest size class, bigger buffers are allocated per request and not kept.
+const MAX_CLASS: usize = 1024 * 1024;
+const CLASSES: usize = (MAX_CLASS / MIN_CLASS).ilog2() as usize + 1;
+/// Free buffers kept per size class.
+const KEEP_PER_CLASS: usize = 8;
+
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    free: [Mutex<Vec<Vec<u8>>>; CLASSES],
+}
+
+impl BufferPool {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn class_of(len: usize) -> Option<usize> {
+        let size = len.max(MIN_CLASS).next_power_of_two();
+        (size <= MAX_CLASS).then(|| (size / MIN_CLASS).ilog2() as usize)
+    }
+
+    /// A zero-filled buffer of `len` bytes.
+    pub(crate) fn get(self: &Arc<Self>, len: usize) -> PooledBuf {
+        let Some(class) = Self::class_of(len) else {
+            return PooledBuf {
+                buf: vec![0; len],
+                pool: None,
+            };
+        };
+        let mut buf = self.free[class]
+            .lock()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(MIN_CLASS << class));
+        buf.resize(len, 0);
+        PooledBuf {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        // Only buffers of exactly a class size came from the pool.
+        let Some(class) =
+            Self::class_of(buf.capacity()).filter(|&class| buf.capacity() == MIN_CLASS << class)
+        else {
+            return;
+        };
+        let mut free = self.free[class].lock();
+        if free.len() < KEEP_PER_CLASS {
+            buf.clear();
+            free.push(buf);
+        }
+    }
+}
+
+/// A buffer from a [`BufferPool`], returned to it when dropped.
+pub(crate) struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl PooledBuf {
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+    }
+
+    /// Take the data out, the buffer is not returned to the pool.
+    pub(crate) fn into_vec(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Share the data without copying, the buffer returns to the pool once every clone of
+    /// the `Bytes` is dropped.
+    pub(crate) fn into_bytes(self) -> Bytes {
+        Bytes::from_owner(self)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get(100);
+        assert_eq!(&buf[..], &[0u8; 100][..]);
+        buf[..5].copy_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        // Same size class: the buffer comes back zero-filled.
+        let buf = pool.get(3000);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Still returned after being handed out as Bytes.
+        let bytes = buf.into_bytes();
+        assert_eq!(bytes.len(), 3000);
+        let clone = bytes.clone();
+        drop(bytes);
+        assert_ne!(pool.get(10).as_ptr(), ptr);
+        drop(clone);
+        assert_eq!(pool.get(10).as_ptr(), ptr);
+
+        // Taken out for good.
+        let vec = pool.get(10).into_vec();
+        assert_eq!(vec.as_ptr(), ptr);
+        assert_ne!(pool.get(10).as_ptr(), ptr);
+
+        // Too big to be kept.
+        let big = pool.get(MAX_CLASS + 1);
+        assert_eq!(big.len(), MAX_CLASS + 1);
+        let ptr = big.as_ptr();
+        drop(big);
+        assert_ne!(pool.get(MAX_CLASS + 1).as_ptr(), ptr);
+    }
+}
@@ -1,5 +1,7 @@
+pub(crate) mod buf_pool;
 pub(crate) mod intervals;
 pub(crate) mod num;
+pub(crate) mod tls;
 pub(crate) mod usage;
 pub(crate) mod zero;
 
@@ -0,0 +1,149 @@
+//! TLS settings shared by the network backends (etcd, PostgreSQL, Redis and S3).
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// TLS settings of the connection to a backend service.
+///
+/// Configuring it at all turns TLS on. The server certificate is checked against `ca_file`,
+/// or the system roots without it, and `cert_file` plus `key_file` enable mutual TLS. A
+/// client that can't honour one of the fields rejects the config when connecting instead of
+/// silently ignoring it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM bundle of the CA certificates trusted for the server certificate.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate presented to the server.
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key of `cert_file`.
+    pub key_file: Option<PathBuf>,
+    /// Name expected in the server certificate when it isn't the host connected to, e.g.
+    /// when connecting by IP address.
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Contents of `ca_file`, if set.
+    pub(crate) fn ca_pem(&self) -> io::Result<Option<Vec<u8>>> {
+        self.ca_file.as_deref().map(read_pem).transpose()
+    }
+
+    /// Contents of `cert_file` and `key_file`, if mutual TLS is configured.
+    pub(crate) fn identity_pem(&self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.identity_files()? {
+            Some((cert, key)) => Ok(Some((read_pem(cert)?, read_pem(key)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn identity_files(&self) -> io::Result<Option<(&Path, &Path)>> {
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS cert_file and key_file must be set together",
+            )),
+        }
+    }
+
+    /// Error for a field `backend` has no way to apply.
+    pub(crate) fn unsupported(backend: &str, field: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("TLS {field} is not supported by the {backend} client"),
+        )
+    }
+
+    /// `url` with the `sslmode`, `sslrootcert`, `sslcert` and `sslkey` parameters PostgreSQL
+    /// clients read, requiring a verified connection.
+    pub(crate) fn postgres_url(&self, url: &str) -> io::Result<String> {
+        if self.server_name.is_some() {
+            return Err(Self::unsupported("PostgreSQL", "server_name"));
+        }
+        let mut params = vec![("sslmode", "verify-full".to_string())];
+        if let Some(ca) = &self.ca_file {
+            params.push(("sslrootcert", ca.to_string_lossy().into_owned()));
+        }
+        if let Some((cert, key)) = self.identity_files()? {
+            params.push(("sslcert", cert.to_string_lossy().into_owned()));
+            params.push(("sslkey", key.to_string_lossy().into_owned()));
+        }
+
+        let mut url = url.to_string();
+        for (name, value) in params {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(name);
+            url.push('=');
+            url.push_str(&percent_encode(&value));
+        }
+        Ok(url)
+    }
+}
+
+fn read_pem(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to read {}: {e}", path.display())))
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_postgres_url() {
+        let tls = TlsConfig {
+            ca_file: Some("/etc/ssl/my ca.pem".into()),
+            cert_file: Some("/etc/ssl/client.pem".into()),
+            key_file: Some("/etc/ssl/client.key".into()),
+            server_name: None,
+        };
+        assert_eq!(
+            tls.postgres_url("postgres://u@[::1]:5432/db").unwrap(),
+            "postgres://u@[::1]:5432/db?sslmode=verify-full&sslrootcert=/etc/ssl/my%20ca.pem\
+             &sslcert=/etc/ssl/client.pem&sslkey=/etc/ssl/client.key"
+        );
+        assert_eq!(
+            TlsConfig::default()
+                .postgres_url("postgres://db/x?application_name=fs")
+                .unwrap(),
+            "postgres://db/x?application_name=fs&sslmode=verify-full"
+        );
+    }
+
+    #[test]
+    fn test_identity_needs_cert_and_key() {
+        let tls = TlsConfig {
+            cert_file: Some("/etc/ssl/client.pem".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            tls.identity_pem().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(tls.postgres_url("postgres://db/x").is_err());
+
+        let tls = TlsConfig {
+            server_name: Some("db.internal".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            tls.postgres_url("postgres://db/x").unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Append-optimized write mode for log files.
+//!
+//! Every `close` (and `flush`) of a written file commits its buffered data as a new slice,
+//! so loggers appending a line per open, like `echo ... >> log`, produce a slice per line.
+//! Files in append mode skip that commit: their appends stay buffered and are coalesced
+//! into larger slices, written at the latest [`AppendConfig::window`] after the last close.
+//! That window is what a crash can lose, `fsync` still commits at once.
+//!
+//! A file is in append mode while its [`APPEND_XATTR`] is `1`, or, without the xattr,
+//! after [`AppendConfig::detect_after`] consecutive small writes at its end.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Xattr forcing append mode on (`1`) or off (`0`) for a file, detection applies when unset.
+pub const APPEND_XATTR: &str = "slayerfs.append";
+
+/// Files whose state is kept, past it the ones only counting appends are dropped.
+const MAX_TRACKED: usize = 64 * 1024;
+
+/// Append mode settings of a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppendConfig {
+    /// Longest appends of a closed file stay buffered.
+    pub window: Duration,
+    /// Consecutive small writes at the end of a file switching it to append mode, 0 leaves
+    /// it to the xattr.
+    pub detect_after: u32,
+    /// Largest write counted as a small append (bytes).
+    pub small_write: usize,
+}
+
+impl Default for AppendConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(1),
+            detect_after: 0,
+            small_write: 4096,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FileState {
+    /// Value of the xattr, `None` while unknown or unset.
+    forced: Option<bool>,
+    /// Whether the xattr was read from the metadata backend.
+    loaded: bool,
+    streak: u32,
+    /// A deferred flush is scheduled.
+    scheduled: bool,
+}
+
+pub(crate) struct AppendTracker {
+    config: Mutex<AppendConfig>,
+    files: Mutex<HashMap<i64, FileState>>,
+}
+
+impl AppendTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            config: Mutex::new(AppendConfig::default()),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn config(&self) -> AppendConfig {
+        *self.config.lock()
+    }
+
+    pub(crate) fn set_config(&self, config: AppendConfig) {
+        *self.config.lock() = config;
+    }
+
+    /// Whether the xattr of `ino` still has to be read, see [`Self::set_forced`].
+    pub(crate) fn needs_load(&self, ino: i64) -> bool {
+        !self.files.lock().get(&ino).is_some_and(|f| f.loaded)
+    }
+
+    /// Record the xattr value of `ino`, `None` when unset.
+    pub(crate) fn set_forced(&self, ino: i64, forced: Option<bool>) {
+        let mut files = self.files.lock();
+        Self::prune(&mut files);
+        let file = files.entry(ino).or_default();
+        file.forced = forced;
+        file.loaded = true;
+    }
+
+    /// Account a write of `len` bytes at `offset` to `ino`, whose size was `size` before.
+    pub(crate) fn record_write(&self, ino: i64, offset: u64, len: usize, size: u64) {
+        let config = self.config();
+        if config.detect_after == 0 {
+            return;
+        }
+        let mut files = self.files.lock();
+        let small_append = offset >= size && len <= config.small_write;
+        match files.get_mut(&ino) {
+            Some(file) if small_append => file.streak = file.streak.saturating_add(1),
+            Some(file) => file.streak = 0,
+            None if small_append => {
+                Self::prune(&mut files);
+                files.entry(ino).or_default().streak = 1;
+            }
+            None => {}
+        }
+    }
+
+    pub(crate) fn is_append(&self, ino: i64) -> bool {
+        let detect_after = self.config().detect_after;
+        self.files.lock().get(&ino).is_some_and(|f| {
+            f.forced
+                .unwrap_or(detect_after > 0 && f.streak >= detect_after)
+        })
+    }
+
+    /// Mark a deferred flush of `ino` scheduled, false if one already was.
+    pub(crate) fn schedule(&self, ino: i64) -> bool {
+        let mut files = self.files.lock();
+        let file = files.entry(ino).or_default();
+        !std::mem::replace(&mut file.scheduled, true)
+    }
+
+    pub(crate) fn unschedule(&self, ino: i64) {
+        if let Some(file) = self.files.lock().get_mut(&ino) {
+            file.scheduled = false;
+        }
+    }
+
+    pub(crate) fn is_scheduled(&self, ino: i64) -> bool {
+        self.files.lock().get(&ino).is_some_and(|f| f.scheduled)
+    }
+
+    /// Forget `ino`, e.g. once it was removed.
+    pub(crate) fn remove(&self, ino: i64) {
+        self.files.lock().remove(&ino);
+    }
+
+    fn prune(files: &mut HashMap<i64, FileState>) {
+        if files.len() >= MAX_TRACKED {
+            files.retain(|_, f| f.scheduled || f.forced.is_some());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_detection() {
+        let tracker = AppendTracker::new();
+        tracker.record_write(7, 0, 10, 0);
+        assert!(!tracker.is_append(7));
+
+        tracker.set_config(AppendConfig {
+            detect_after: 3,
+            ..Default::default()
+        });
+        for i in 0..3 {
+            assert!(!tracker.is_append(7));
+            tracker.record_write(7, i * 10, 10, i * 10);
+        }
+        assert!(tracker.is_append(7));
+
+        // A rewrite or a large write ends the streak.
+        tracker.record_write(7, 0, 10, 30);
+        assert!(!tracker.is_append(7));
+        tracker.record_write(7, 30, 1 << 20, 30);
+        assert!(!tracker.is_append(7));
+
+        // The xattr wins over detection.
+        tracker.set_forced(7, Some(true));
+        assert!(tracker.is_append(7));
+        assert!(!tracker.needs_load(7));
+        tracker.set_forced(8, Some(false));
+        for i in 0..5 {
+            tracker.record_write(8, i, 1, i);
+        }
+        assert!(!tracker.is_append(8));
+
+        assert!(tracker.schedule(7));
+        assert!(!tracker.schedule(7));
+        tracker.unschedule(7);
+        assert!(!tracker.is_scheduled(7));
+    }
+}
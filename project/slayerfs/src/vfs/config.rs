@@ -7,6 +7,8 @@ pub const DEFAULT_MAX_AHEAD: u64 = 32 * 1024 * 1024; // 32MB
 pub const DEFAULT_BUFFER_SIZE: u64 = 1024 * 1024 * 300; // 300MB
 pub const DEFAULT_WRITE_BUFFER_SIZE: u64 = 1024 * 1024 * 300; // 300MB
 pub const DEFAULT_FLUSH_ALL_INTERVAL: Duration = Duration::from_secs(5);
+/// Components allowed in a path given to the path-based VFS API.
+pub const DEFAULT_MAX_PATH_DEPTH: usize = 1024;
 
 #[derive(Clone)]
 pub struct ReadConfig {
@@ -150,6 +150,9 @@ pub enum VfsError {
     #[error("filename too long{path}")]
     FilenameTooLong { path: PathHint },
 
+    #[error("path too deep{path}")]
+    PathTooDeep { path: PathHint },
+
     #[error("argument list too long")]
     ArgumentListTooLong,
 
@@ -187,6 +190,7 @@ impl VfsError {
             MetaError::NotDirectory(_) => VfsError::NotADirectory { path },
             MetaError::DirectoryNotEmpty(_) => VfsError::DirectoryNotEmpty { path },
             MetaError::InvalidFilename => VfsError::InvalidFilename,
+            MetaError::NameTooLong(_) => VfsError::FilenameTooLong { path },
             MetaError::InvalidPath(_) => VfsError::InvalidInput,
             MetaError::TooManySymlinks => VfsError::InvalidInput,
             MetaError::NotSupported(_) | MetaError::NotImplemented => VfsError::Unsupported,
@@ -302,6 +306,7 @@ impl From<VfsError> for std::io::Error {
             VfsError::TooManyLinks => ErrorKind::TooManyLinks,
             VfsError::InvalidFilename => ErrorKind::InvalidFilename,
             VfsError::FilenameTooLong { .. } => ErrorKind::InvalidFilename,
+            VfsError::PathTooDeep { .. } => ErrorKind::InvalidFilename,
             VfsError::ArgumentListTooLong => ErrorKind::ArgumentListTooLong,
             VfsError::Interrupted => ErrorKind::Interrupted,
             VfsError::Unsupported => ErrorKind::Unsupported,
@@ -6,15 +6,20 @@ use crate::meta::MetaLayer;
 use crate::meta::client::MetaClient;
 use crate::meta::config::MetaClientConfig;
 use crate::meta::file_lock::{FileLockInfo, FileLockQuery, FileLockRange, FileLockType};
+use crate::meta::name;
 use crate::meta::store::{
     AclRule, MetaError, MetaStore, SetAttrFlags, SetAttrRequest, StatFsSnapshot,
 };
+use crate::meta::stores::ReplicatedMetaStore;
+use crate::meta::stores::replicated::REPLICATION_XATTR;
+use bytes::Bytes;
 use dashmap::{DashMap, Entry};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast};
 
 // Re-export types from meta::store for convenience
 pub use crate::meta::store::{DirEntry, FileAttr, FileType};
@@ -30,12 +35,19 @@ pub struct RenameFlags {
     pub whiteout: bool,
 }
 
+use crate::utils::buf_pool::PooledBuf;
 use crate::vfs::Inode;
+use crate::vfs::append::{APPEND_XATTR, AppendConfig, AppendTracker};
 use crate::vfs::backend::Backend;
-use crate::vfs::config::VFSConfig;
+use crate::vfs::config::{DEFAULT_MAX_PATH_DEPTH, VFSConfig};
 use crate::vfs::error::{PathHint, VfsError};
 use crate::vfs::handles::{DirHandle, FileHandle, HandleFlags};
+use crate::vfs::io::reader::FileAdvice;
 use crate::vfs::io::{DataReader, DataWriter};
+use crate::vfs::journal::{self, JournalRecovery, UnrecoveredWrite, WriteJournal};
+use crate::vfs::page_cache::PageCacheTracker;
+use crate::vfs::quota::{QUOTA_WARN_XATTR, QuotaEvent, QuotaMonitor, SoftQuota};
+use crate::vfs::usage::{UsageCounters, UsageOp, UsageSnapshot};
 
 struct HandleRegistry<B, M>
 where
@@ -197,6 +209,12 @@ where
     reader: Arc<DataReader<S, M>>,
     writer: Arc<DataWriter<S, M>>,
     modified: ModifiedTracker,
+    usage: UsageCounters,
+    quota: QuotaMonitor,
+    append: AppendTracker,
+    page_cache: PageCacheTracker,
+    replication: parking_lot::Mutex<Option<ReplicatedMetaStore>>,
+    max_path_depth: AtomicUsize,
 }
 
 impl<S, M> VfsState<S, M>
@@ -218,6 +236,12 @@ where
             reader,
             writer,
             modified: ModifiedTracker::new(),
+            usage: UsageCounters::default(),
+            quota: QuotaMonitor::new(),
+            append: AppendTracker::new(),
+            page_cache: PageCacheTracker::new(),
+            replication: parking_lot::Mutex::new(None),
+            max_path_depth: AtomicUsize::new(DEFAULT_MAX_PATH_DEPTH),
         }
     }
 }
@@ -543,6 +567,25 @@ where
         if out.is_empty() { "/".into() } else { out }
     }
 
+    /// Normalize `p` and check it against the name length and depth limits, see
+    /// [`set_max_path_depth`](Self::set_max_path_depth).
+    fn check_path(&self, p: &str) -> Result<String, VfsError> {
+        let path = Self::norm_path(p);
+        let mut depth = 0;
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            if name::check_len(part).is_err() {
+                return Err(VfsError::FilenameTooLong {
+                    path: path.clone().into(),
+                });
+            }
+            depth += 1;
+        }
+        if depth > self.state.max_path_depth.load(Ordering::Relaxed) {
+            return Err(VfsError::PathTooDeep { path: path.into() });
+        }
+        Ok(path)
+    }
+
     /// Split a normalized path into parent directory and basename.
     fn split_dir_file(path: &str) -> (String, String) {
         let n = path.rfind('/').unwrap_or(0);
@@ -559,7 +602,7 @@ where
     /// - Returns the inode of the target directory.
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn mkdir_p(&self, path: &str) -> Result<i64, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         if &path == "/" {
             return Ok(self.core.root);
         }
@@ -608,12 +651,14 @@ where
                         .mkdir(cur_ino, part.to_string())
                         .await
                         .map_err(VfsError::from)?;
+                    self.state.usage.record(UsageOp::Mkdir);
                     self.state.modified.touch(cur_ino).await;
                     self.state.modified.touch(ino).await;
                     cur_ino = ino;
                 }
             }
         }
+        self.maybe_check_soft_quota().await;
         Ok(cur_ino)
     }
 
@@ -624,7 +669,7 @@ where
     /// - If the target exists as a non-directory, returns `AlreadyExists`.
     /// - If parent does not exist, returns `NotFound`.
     pub async fn mkdir_err(&self, path: &str) -> Result<i64, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         if path == "/" {
             return Ok(self.core.root);
         }
@@ -686,6 +731,8 @@ where
             .mkdir(parent_ino, name.to_string())
             .await
             .map_err(|e| VfsError::from_meta(path.clone(), e))?;
+        self.state.usage.record(UsageOp::Mkdir);
+        self.maybe_check_soft_quota().await;
         Ok(ino)
     }
 
@@ -699,7 +746,7 @@ where
         path: &str,
         create_new: bool,
     ) -> Result<i64, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         if path == "/" {
             return Err(VfsError::IsADirectory { path: path.into() });
         }
@@ -758,8 +805,10 @@ where
             .create_file(parent_ino, name)
             .await
             .map_err(|e| VfsError::from_meta(path.clone(), e))?;
+        self.state.usage.record(UsageOp::Create);
         self.state.modified.touch(parent_ino).await;
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
         Ok(ino)
     }
 
@@ -768,7 +817,7 @@ where
     /// - If the file already exists, returns its inode instead of creating a new one.
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn create_file(&self, path: &str) -> Result<i64, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         let (dir, name) = Self::split_dir_file(&path);
         let dir_ino = self.mkdir_p(&dir).await?;
 
@@ -804,16 +853,18 @@ where
             .create_file(dir_ino, name.clone())
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Create);
         self.state.modified.touch(dir_ino).await;
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
         Ok(ino)
     }
 
     /// Create a hard link at `link_path` that references `existing_path`.
     #[tracing::instrument(level = "trace", skip(self), fields(existing_path, link_path))]
     pub async fn link(&self, existing_path: &str, link_path: &str) -> Result<FileAttr, VfsError> {
-        let existing_path = Self::norm_path(existing_path);
-        let link_path = Self::norm_path(link_path);
+        let existing_path = self.check_path(existing_path)?;
+        let link_path = self.check_path(link_path)?;
 
         if existing_path == "/" {
             return Err(VfsError::IsADirectory {
@@ -893,6 +944,7 @@ where
             .link(src_ino, parent_ino, &name)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Link);
 
         self.state.modified.touch(parent_ino).await;
         self.state.modified.touch(src_ino).await;
@@ -907,7 +959,7 @@ where
         link_path: &str,
         target: &str,
     ) -> Result<(i64, FileAttr), VfsError> {
-        let link_path = Self::norm_path(link_path);
+        let link_path = self.check_path(link_path)?;
         if link_path == "/" {
             return Err(VfsError::InvalidFilename);
         }
@@ -964,9 +1016,11 @@ where
             .symlink(parent_ino, &name, target)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Symlink);
 
         self.state.modified.touch(parent_ino).await;
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
 
         Ok((ino, attr))
     }
@@ -974,7 +1028,7 @@ where
     /// Fetch a file's attributes (kind/size come from the metadata layer).
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn stat(&self, path: &str) -> Result<FileAttr, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
 
         let (ino, _) = self
             .core
@@ -1035,7 +1089,7 @@ where
     /// Read a symlink target by path.
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn readlink(&self, path: &str) -> Result<String, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
 
         let (ino, kind) = self
             .core
@@ -1055,14 +1109,16 @@ where
 
     /// Check whether a path exists.
     pub async fn exists(&self, path: &str) -> bool {
-        let path = Self::norm_path(path);
+        let Ok(path) = self.check_path(path) else {
+            return false;
+        };
         matches!(self.core.meta_layer.lookup_path(&path).await, Ok(Some(_)))
     }
 
     /// Remove a regular file or symlink (directories are not supported here).
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn unlink(&self, path: &str) -> Result<(), VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         let (dir, name) = Self::split_dir_file(&path);
 
         let parent_ino = if &dir == "/" {
@@ -1110,8 +1166,10 @@ where
             .unlink(parent_ino, &name)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Unlink);
         self.state.modified.touch(parent_ino).await;
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
 
         Ok(())
     }
@@ -1119,7 +1177,7 @@ where
     /// Remove an empty directory (root cannot be removed; non-empty dirs error out).
     #[tracing::instrument(level = "trace", skip(self), fields(path))]
     pub async fn rmdir(&self, path: &str) -> Result<(), VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         if path == "/" {
             return Err(VfsError::PermissionDenied {
                 path: PathHint::some(path),
@@ -1185,8 +1243,10 @@ where
             .rmdir(parent_ino, &name)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Rmdir);
         self.state.modified.touch(parent_ino).await;
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
 
         Ok(())
     }
@@ -1426,6 +1486,7 @@ where
             .rename(parent_ino, old_name, parent_ino, new_name.to_string())
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Rename);
 
         // Update cache
         self.state.modified.touch(parent_ino).await;
@@ -1540,6 +1601,7 @@ where
             .rename(old_parent_ino, old_name, new_parent_ino, new_name)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Rename);
 
         // Update modification tracking
         self.state.modified.touch(old_parent_ino).await;
@@ -1553,8 +1615,8 @@ where
     #[tracing::instrument(level = "trace", skip(self), fields(old, new))]
     pub async fn rename(&self, old: &str, new: &str) -> Result<(), VfsError> {
         // Step 1: Normalize and parse paths
-        let old = Self::norm_path(old);
-        let new = Self::norm_path(new);
+        let old = self.check_path(old)?;
+        let new = self.check_path(new)?;
         let (old_dir, old_name) = Self::split_dir_file(&old);
         let (new_dir, new_name) = Self::split_dir_file(&new);
 
@@ -1622,8 +1684,8 @@ where
     /// Rename without replacing the destination (RENAME_NOREPLACE).
     /// Returns an error if the destination already exists.
     pub async fn rename_noreplace(&self, old: &str, new: &str) -> Result<(), VfsError> {
-        let old = Self::norm_path(old);
-        let new = Self::norm_path(new);
+        let old = self.check_path(old)?;
+        let new = self.check_path(new)?;
 
         // Check if destination exists
         if self.core.meta_layer.lookup_path(&new).await?.is_some() {
@@ -1639,8 +1701,8 @@ where
     /// Atomically exchange the source and destination (RENAME_EXCHANGE).
     /// Both source and destination must exist.
     pub async fn rename_exchange(&self, old: &str, new: &str) -> Result<(), VfsError> {
-        let old = Self::norm_path(old);
-        let new = Self::norm_path(new);
+        let old = self.check_path(old)?;
+        let new = self.check_path(new)?;
 
         // Both source and destination must exist
         let (old_dir, old_name) = Self::split_dir_file(&old);
@@ -1702,6 +1764,7 @@ where
             .rename_exchange(old_parent_ino, &old_name, new_parent_ino, &new_name)
             .await
             .map_err(VfsError::from)?;
+        self.state.usage.record(UsageOp::Rename);
 
         // Update cache
         self.state.modified.touch(old_parent_ino).await;
@@ -1714,8 +1777,8 @@ where
 
     /// Check if a rename operation would be allowed without actually performing it.
     pub async fn can_rename(&self, old: &str, new: &str) -> Result<(), VfsError> {
-        let old = Self::norm_path(old);
-        let new = Self::norm_path(new);
+        let old = self.check_path(old)?;
+        let new = self.check_path(new)?;
         let (old_dir, old_name) = Self::split_dir_file(&old);
         let (new_dir, new_name) = Self::split_dir_file(&new);
 
@@ -1847,7 +1910,7 @@ where
     /// Shrinking does not eagerly reclaim block data.
     #[tracing::instrument(level = "trace", skip(self), fields(path, size))]
     pub async fn truncate(&self, path: &str, size: u64) -> Result<(), VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         let (ino, _) = self
             .core
             .meta_layer
@@ -1892,8 +1955,11 @@ where
             self.state.handles.update_attr_for_inode(ino, &attr);
         }
 
+        self.state.usage.record(UsageOp::SetAttr);
+        self.state.page_cache.changed(ino);
         self.state.modified.touch(ino).await;
         drop(guards);
+        self.maybe_check_soft_quota().await;
         Ok(())
     }
 
@@ -1921,12 +1987,14 @@ where
             .await
             .map_err(VfsError::from)?;
 
-        if let Some(size) = req.size
-            && let Some(inode) = self.state.inodes.get(&ino)
-        {
-            inode.update_size(size);
+        if let Some(size) = req.size {
+            if let Some(inode) = self.state.inodes.get(&ino) {
+                inode.update_size(size);
+            }
+            self.state.page_cache.changed(ino);
         }
 
+        self.state.usage.record(UsageOp::SetAttr);
         self.state.modified.touch(ino).await;
         self.state.handles.update_attr_for_inode(ino, &attr);
 
@@ -1944,7 +2012,20 @@ where
         if len == 0 {
             return Ok(Vec::new());
         }
+        Ok(self.read_pooled(fh, offset, len).await?.into_vec())
+    }
 
+    /// Like [`VFS::read`], but the data stays in a pooled buffer that is reused by later
+    /// reads once the returned `Bytes` are dropped. Preferred for replies that are only
+    /// copied out, like FUSE reads.
+    pub async fn read_bytes(&self, fh: u64, offset: u64, len: usize) -> Result<Bytes, VfsError> {
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        Ok(self.read_pooled(fh, offset, len).await?.into_bytes())
+    }
+
+    async fn read_pooled(&self, fh: u64, offset: u64, len: usize) -> Result<PooledBuf, VfsError> {
         let handle = self
             .state
             .handles
@@ -1958,7 +2039,9 @@ where
 
         // Before reading, it is needed to flush all cached data.
         self.state.writer.flush_if_exists(handle.ino as u64).await;
-        handle.read(offset, len).await.map_err(VfsError::from)
+        let data = handle.read(offset, len).await.map_err(VfsError::from)?;
+        self.state.usage.record_read(data.len());
+        Ok(data)
     }
 
     /// Write data by file handle and offset.
@@ -1980,8 +2063,19 @@ where
         }
 
         tracing::trace!(fh, ino = handle.ino, offset, len = data.len(), "vfs.write");
+        let size = self
+            .state
+            .inodes
+            .get(&handle.ino)
+            .map_or(0, |inode| inode.file_size());
         let written = handle.write(offset, data).await?;
+        self.state
+            .append
+            .record_write(handle.ino, offset, written, size);
+        self.state.usage.record_write(written);
+        self.state.page_cache.changed(handle.ino);
         self.state.modified.touch(handle.ino).await;
+        self.maybe_check_soft_quota().await;
         tracing::trace!(fh, ino = handle.ino, written, "vfs.write_done");
         Ok(written)
     }
@@ -2011,13 +2105,18 @@ where
         }
 
         let inode = self.ensure_inode_registered(ino).await?;
+        let size = inode.file_size();
         let writer = self.state.writer.ensure_file(inode);
         let written = writer
             .write_at(offset, data)
             .await
             .map_err(VfsError::from)?;
+        self.state.append.record_write(ino, offset, written, size);
+        self.state.usage.record_write(written);
+        self.state.page_cache.changed(ino);
 
         self.state.modified.touch(ino).await;
+        self.maybe_check_soft_quota().await;
         Ok(written)
     }
 
@@ -2030,6 +2129,20 @@ where
         read: bool,
         write: bool,
     ) -> Result<u64, VfsError> {
+        self.open_cached(ino, attr, read, write)
+            .await
+            .map(|(fh, _)| fh)
+    }
+
+    /// Like [`Self::open`], also telling whether the kernel may keep the pages it cached of
+    /// the file, see [`crate::vfs::page_cache`].
+    pub(crate) async fn open_cached(
+        &self,
+        ino: i64,
+        attr: FileAttr,
+        read: bool,
+        write: bool,
+    ) -> Result<(u64, bool), VfsError> {
         let mut latest_attr = attr;
 
         // Retrieve the latest attr for close-to-open semantics.
@@ -2052,6 +2165,7 @@ where
         }
 
         let inode = guard.clone();
+        let keep_cache = self.state.page_cache.open(&latest_attr);
         let handle = self
             .state
             .handles
@@ -2062,8 +2176,10 @@ where
         if write {
             let writer = self.state.writer.ensure_file(inode.clone());
             handle.writer(writer);
+            self.load_append_xattr(ino).await;
         }
-        Ok(handle.fh)
+        self.state.usage.record(UsageOp::Open);
+        Ok((handle.fh, keep_cache))
     }
 
     /// Allocate a file handle and return a guard that auto-closes on drop.
@@ -2093,8 +2209,13 @@ where
             write = handle.flags.write,
             "vfs.close"
         );
+        let deferred = handle.flags.write && self.state.append.is_append(handle.ino);
         if handle.flags.write {
-            handle.flush().await.map_err(|_| VfsError::Other)?;
+            if deferred {
+                self.flush_later(handle.ino);
+            } else {
+                handle.flush().await.map_err(|_| VfsError::Other)?;
+            }
             self.update_mtime_ctime(handle.ino).await?;
         }
 
@@ -2107,11 +2228,12 @@ where
                 self.state.handles.release(fh);
                 self.state.reader.close_for_handle(handle.ino as u64, fh);
 
-                if !self.state.handles.has_write_handle(handle.ino) {
+                // With a deferred flush, the writer and inode are released by it.
+                if !deferred && !self.state.handles.has_write_handle(handle.ino) {
                     self.state.writer.release(handle.ino as u64);
                 }
 
-                if self.state.handles.has_no_handle(handle.ino) {
+                if !deferred && self.state.handles.has_no_handle(handle.ino) {
                     entry.remove();
                 }
             }
@@ -2141,7 +2263,11 @@ where
             "vfs.flush"
         );
         if handle.flags.write {
-            handle.flush().await.map_err(|_| VfsError::Other)?;
+            if self.state.append.is_append(handle.ino) {
+                self.flush_later(handle.ino);
+            } else {
+                handle.flush().await.map_err(|_| VfsError::Other)?;
+            }
         }
 
         self.update_timestamps_on_flush(handle.ino).await?;
@@ -2172,6 +2298,36 @@ where
         Ok(())
     }
 
+    /// Apply a `posix_fadvise` hint for `len` bytes at `offset` of the file open as `fh`, a
+    /// zero `len` meaning up to the end of the file. See [`FileAdvice`].
+    ///
+    /// The kernel doesn't forward `posix_fadvise` to FUSE filesystems, so this serves
+    /// embedders and tools that read through the VFS directly.
+    pub async fn fadvise(
+        &self,
+        fh: u64,
+        offset: u64,
+        len: u64,
+        advice: FileAdvice,
+    ) -> Result<(), VfsError> {
+        let handle = self
+            .state
+            .handles
+            .get(fh)
+            .ok_or(VfsError::StaleNetworkFileHandle)?;
+        tracing::trace!(fh, ino = handle.ino, offset, len, ?advice, "vfs.fadvise");
+
+        if advice == FileAdvice::WillNeed {
+            // Prefetch what a read would see.
+            self.state.writer.flush_if_exists(handle.ino as u64).await;
+        }
+        self.state
+            .reader
+            .advise(handle.ino as u64, fh, offset, len, advice)
+            .await;
+        Ok(())
+    }
+
     /// Open a directory handle for reading. Returns the file handle ID.
     /// This pre-loads all directory entries and starts background batch prefetch for attributes.
     #[tracing::instrument(level = "trace", skip(self), fields(ino))]
@@ -2300,10 +2456,28 @@ where
         value: &[u8],
         flags: u32,
     ) -> Result<(), MetaError> {
+        if name == QUOTA_WARN_XATTR {
+            return Err(MetaError::NotSupported(format!("{name} is read-only")));
+        }
+        if name == REPLICATION_XATTR {
+            return self.replication_admin(value).await;
+        }
+        let append = if name == APPEND_XATTR {
+            Some(
+                parse_append_xattr(value)
+                    .ok_or_else(|| MetaError::NotSupported(format!("{name} must be 0 or 1")))?,
+            )
+        } else {
+            None
+        };
         self.core
             .meta_layer
             .set_xattr(inode, name, value, flags)
-            .await
+            .await?;
+        if append.is_some() {
+            self.state.append.set_forced(inode, append);
+        }
+        Ok(())
     }
 
     /// Get xattr for a given inode.
@@ -2312,6 +2486,15 @@ where
         inode: i64,
         name: &str,
     ) -> Result<Option<Vec<u8>>, MetaError> {
+        if name == QUOTA_WARN_XATTR {
+            let warn = if self.state.quota.warn() { b"1" } else { b"0" };
+            return Ok(Some(warn.to_vec()));
+        }
+        if name == REPLICATION_XATTR {
+            return Ok(self
+                .replication()
+                .map(|r| r.status().to_string().into_bytes()));
+        }
         self.core.meta_layer.get_xattr(inode, name).await
     }
 
@@ -2322,7 +2505,32 @@ where
 
     /// Remove xattr for a given inode.
     pub async fn remove_xattr_ino(&self, inode: i64, name: &str) -> Result<(), MetaError> {
-        self.core.meta_layer.remove_xattr(inode, name).await
+        if name == QUOTA_WARN_XATTR || name == REPLICATION_XATTR {
+            return Err(MetaError::NotSupported(format!("{name} is read-only")));
+        }
+        self.core.meta_layer.remove_xattr(inode, name).await?;
+        if name == APPEND_XATTR {
+            self.state.append.set_forced(inode, None);
+        }
+        Ok(())
+    }
+
+    // Run the replication admin operation written to REPLICATION_XATTR.
+    async fn replication_admin(&self, value: &[u8]) -> Result<(), MetaError> {
+        let replication = self.replication().ok_or_else(|| {
+            MetaError::NotSupported("the metadata of the volume is not replicated".into())
+        })?;
+        match value {
+            b"promote" => replication.promote(false).await,
+            b"promote-force" => replication.promote(true).await,
+            b"demote" => {
+                replication.demote().await;
+                Ok(())
+            }
+            _ => Err(MetaError::NotSupported(format!(
+                "{REPLICATION_XATTR} must be promote, promote-force or demote"
+            ))),
+        }
     }
 
     /// Set ACL rule for a given inode.
@@ -2346,7 +2554,7 @@ where
         path: &str,
         query: &FileLockQuery,
     ) -> Result<FileLockInfo, VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         let (inode, _) = self
             .core
             .meta_layer
@@ -2373,7 +2581,7 @@ where
         range: FileLockRange,
         pid: u32,
     ) -> Result<(), VfsError> {
-        let path = Self::norm_path(path);
+        let path = self.check_path(path)?;
         let (inode, _) = self
             .core
             .meta_layer
@@ -2410,6 +2618,196 @@ where
         self.core.meta_layer.stat_fs().await
     }
 
+    /// Bytes moved and operations served since this VFS was created.
+    pub fn usage(&self) -> UsageSnapshot {
+        self.state.usage.snapshot()
+    }
+
+    /// Limit the number of components of the paths taken by the path-based API, deeper
+    /// paths fail with [`VfsError::PathTooDeep`]. Inode-based operations, as used by FUSE,
+    /// are not limited.
+    pub fn set_max_path_depth(&self, depth: usize) {
+        self.state.max_path_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn max_path_depth(&self) -> usize {
+        self.state.max_path_depth.load(Ordering::Relaxed)
+    }
+
+    /// Set the soft thresholds of the volume, `SoftQuota::default()` removes them.
+    pub fn set_soft_quota(&self, quota: SoftQuota) {
+        self.state.quota.set_quota(quota);
+    }
+
+    pub fn soft_quota(&self) -> SoftQuota {
+        self.state.quota.quota()
+    }
+
+    /// Set how files in append mode are detected and how long their appends may stay
+    /// buffered after a close, see [`crate::vfs::append`].
+    pub fn set_append_config(&self, config: AppendConfig) {
+        self.state.append.set_config(config);
+    }
+
+    pub fn append_config(&self) -> AppendConfig {
+        self.state.append.config()
+    }
+
+    /// Let the kernel keep the cached pages of files unchanged since their last open, on by
+    /// default, see [`crate::vfs::page_cache`].
+    pub fn set_keep_page_cache(&self, enabled: bool) {
+        self.state.page_cache.set_enabled(enabled);
+    }
+
+    pub fn keep_page_cache(&self) -> bool {
+        self.state.page_cache.enabled()
+    }
+
+    /// Expose the admin operations of the replicated metadata store of the volume through
+    /// the [`REPLICATION_XATTR`] virtual xattr.
+    pub fn set_replication(&self, replication: Option<ReplicatedMetaStore>) {
+        *self.state.replication.lock() = replication;
+    }
+
+    pub fn replication(&self) -> Option<ReplicatedMetaStore> {
+        self.state.replication.lock().clone()
+    }
+
+    /// Whether the volume was over a soft threshold at the last check.
+    pub fn quota_warn(&self) -> bool {
+        self.state.quota.warn()
+    }
+
+    /// Events published whenever the soft quota state changes.
+    pub fn subscribe_quota_events(&self) -> broadcast::Receiver<QuotaEvent> {
+        self.state.quota.subscribe()
+    }
+
+    /// Evaluate the soft thresholds against the current usage now, returns the new state.
+    pub async fn check_soft_quota(&self) -> Result<bool, VfsError> {
+        let stat = self.stat_fs().await.map_err(VfsError::from)?;
+        let used_space = stat.total_space.saturating_sub(stat.available_space);
+        Ok(self.state.quota.update(used_space, stat.used_inodes))
+    }
+
+    /// Replay the writes left uncommitted in the journal at `path` by a crashed client, then
+    /// journal all writes to it from now on. Meant to be called once, before the volume
+    /// serves requests.
+    ///
+    /// Writes that can't be replayed, e.g. because their file was removed since, are listed
+    /// in the returned report and saved next to the journal with a `.unrecovered` suffix.
+    pub async fn recover_write_journal(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<JournalRecovery, VfsError> {
+        let path = path.as_ref();
+        if self.state.writer.has_journal() {
+            return Err(VfsError::AlreadyExists {
+                path: PathHint::some(path.display().to_string()),
+            });
+        }
+
+        // The pending writes are moved aside until replayed, in case this recovery is
+        // interrupted too. Leftovers of an interrupted one go first.
+        let replay_path = journal::sibling(path, "replay");
+        let (mut pending, replay_truncated) = journal::read_pending(&replay_path)?;
+        let (current, truncated_tail) = journal::read_pending(path)?;
+        pending.extend(current);
+        if !pending.is_empty() {
+            let staged = journal::sibling(path, "replay.tmp");
+            let _ = std::fs::remove_file(&staged);
+            journal::save(&staged, &pending)?;
+            std::fs::rename(&staged, &replay_path)?;
+        }
+        // Replayed writes are journaled again like any other.
+        self.state.writer.set_journal(WriteJournal::create(path)?);
+
+        let mut report = JournalRecovery {
+            truncated_tail: truncated_tail || replay_truncated,
+            ..Default::default()
+        };
+        let mut replayed = BTreeMap::<u64, Vec<usize>>::new();
+        let mut failed = Vec::new();
+        for (idx, write) in pending.iter().enumerate() {
+            match self
+                .write_ino(write.ino as i64, write.offset, &write.data)
+                .await
+            {
+                Ok(_) => replayed.entry(write.ino).or_default().push(idx),
+                Err(e) => failed.push((idx, e.to_string())),
+            }
+        }
+        for (ino, writes) in replayed {
+            let flushed = match self.ensure_inode_registered(ino as i64).await {
+                Ok(inode) => self.state.writer.ensure_file(inode).flush().await,
+                Err(e) => Err(e.into()),
+            };
+            match flushed {
+                Ok(()) => {
+                    report.replayed += writes.len();
+                    report.replayed_bytes += writes
+                        .iter()
+                        .map(|&idx| pending[idx].data.len() as u64)
+                        .sum::<u64>();
+                }
+                Err(e) => failed.extend(writes.into_iter().map(|idx| (idx, e.to_string()))),
+            }
+            if !self.state.handles.has_write_handle(ino as i64) {
+                self.state.writer.release(ino);
+            }
+        }
+
+        failed.sort_by_key(|(idx, _)| *idx);
+        if !failed.is_empty() {
+            let unrecovered = journal::sibling(path, "unrecovered");
+            let writes: Vec<_> = failed
+                .iter()
+                .map(|(idx, _)| pending[*idx].clone())
+                .collect();
+            match journal::save(&unrecovered, &writes) {
+                Ok(()) => report.unrecovered_path = Some(unrecovered),
+                Err(e) => tracing::error!(
+                    "failed to save unrecovered writes to {}: {}",
+                    unrecovered.display(),
+                    e
+                ),
+            }
+        }
+        if let Err(e) = std::fs::remove_file(&replay_path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("failed to remove {}: {}", replay_path.display(), e);
+        }
+        report.failed = failed
+            .into_iter()
+            .map(|(idx, error)| UnrecoveredWrite {
+                ino: pending[idx].ino as i64,
+                offset: pending[idx].offset,
+                len: pending[idx].data.len() as u64,
+                error,
+            })
+            .collect();
+        if !report.is_clean() {
+            tracing::warn!(
+                replayed = report.replayed,
+                failed = report.failed.len(),
+                truncated_tail = report.truncated_tail,
+                "write journal recovery incomplete"
+            );
+        }
+        Ok(report)
+    }
+
+    /// Throttled [`Self::check_soft_quota`] run after operations that change usage.
+    async fn maybe_check_soft_quota(&self) {
+        if !self.state.quota.check_due() {
+            return;
+        }
+        if let Err(e) = self.check_soft_quota().await {
+            tracing::debug!("soft quota check failed: {}", e);
+        }
+    }
+
     async fn ensure_inode_registered(&self, ino: i64) -> Result<Arc<Inode>, VfsError> {
         // Fast path to check whether there is an existing inode.
         if let Some(inode) = self.state.inodes.get(&ino) {
@@ -2448,6 +2846,64 @@ where
     fn lock_inode(&self, ino: i64) -> Entry<'_, i64, Arc<Inode>> {
         self.state.inodes.entry(ino)
     }
+
+    // Read the append mode xattr of `ino` the first time it is opened for writing.
+    async fn load_append_xattr(&self, ino: i64) {
+        if !self.state.append.needs_load(ino) {
+            return;
+        }
+        let forced = match self.core.meta_layer.get_xattr(ino, APPEND_XATTR).await {
+            Ok(Some(value)) => parse_append_xattr(&value),
+            _ => None,
+        };
+        self.state.append.set_forced(ino, forced);
+    }
+
+    // Commit the buffered appends of `ino` once the append window elapsed, instead of on
+    // the close or flush that just happened.
+    fn flush_later(&self, ino: i64) {
+        if !self.state.append.schedule(ino) {
+            return;
+        }
+        let window = self.state.append.config().window;
+        let fs = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            fs.state.append.unschedule(ino);
+            fs.state.writer.flush_if_exists(ino as u64).await;
+            fs.release_deferred(ino).await;
+        });
+    }
+
+    // What `close` skipped for a file with a deferred flush: drop its writer and inode once
+    // nothing uses them anymore.
+    async fn release_deferred(&self, ino: i64) {
+        if self.state.writer.has_pending(ino as u64).await {
+            // The flush failed, try again later rather than dropping the data.
+            self.flush_later(ino);
+            return;
+        }
+        if self.state.append.is_scheduled(ino) {
+            return;
+        }
+        let entry = self.lock_inode(ino);
+        if !self.state.handles.has_write_handle(ino) {
+            self.state.writer.release(ino as u64);
+        }
+        if let Entry::Occupied(entry) = entry
+            && self.state.handles.has_no_handle(ino)
+        {
+            entry.remove();
+        }
+    }
+}
+
+fn parse_append_xattr(value: &[u8]) -> Option<bool> {
+    match value.trim_ascii() {
+        b"1" => Some(true),
+        b"0" => Some(false),
+        _ => None,
+    }
 }
 
 /// RAII guard for file handles that ensures close on drop.
@@ -785,3 +785,275 @@ mod io_tests {
         }
     }
 }
+
+#[cfg(test)]
+mod quota_tests {
+    use super::*;
+    use crate::vfs::quota::{QUOTA_WARN_XATTR, QuotaEvent, SoftQuota};
+
+    #[tokio::test]
+    async fn test_soft_quota_warns_without_failing_writes() {
+        let layout = ChunkLayout::default();
+        let store = InMemoryBlockStore::new();
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(layout, store, meta_handle.store()).await.unwrap();
+        let mut events = fs.subscribe_quota_events();
+
+        let root = fs.root_ino();
+        let warn = |fs: &VFS<_, _>| {
+            let fs = fs.clone();
+            async move { fs.get_xattr_ino(root, QUOTA_WARN_XATTR).await.unwrap() }
+        };
+        assert_eq!(warn(&fs).await.as_deref(), Some(&b"0"[..]));
+
+        let used = fs.stat_fs().await.unwrap().used_inodes;
+        fs.set_soft_quota(SoftQuota {
+            space: None,
+            inodes: Some(used + 1),
+        });
+        fs.create_file("/a").await.unwrap();
+        assert!(!fs.check_soft_quota().await.unwrap());
+        fs.create_file("/b").await.unwrap();
+        assert!(fs.check_soft_quota().await.unwrap());
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            QuotaEvent::SoftExceeded { .. }
+        ));
+        let ino = fs.create_file("/c").await.unwrap();
+        assert_eq!(warn(&fs).await.as_deref(), Some(&b"1"[..]));
+        assert!(
+            fs.set_xattr_ino(ino, QUOTA_WARN_XATTR, b"0", 0)
+                .await
+                .is_err()
+        );
+
+        // Unlinked files keep counting until GC, so lift the threshold instead.
+        fs.set_soft_quota(SoftQuota {
+            space: None,
+            inodes: Some(used + 10),
+        });
+        assert!(!fs.check_soft_quota().await.unwrap());
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            QuotaEvent::SoftCleared { .. }
+        ));
+        assert_eq!(warn(&fs).await.as_deref(), Some(&b"0"[..]));
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use crate::vfs::journal::WriteJournal;
+
+    #[tokio::test]
+    async fn test_recover_write_journal() {
+        let layout = ChunkLayout::default();
+        let store = InMemoryBlockStore::new();
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(layout, store, meta_handle.store()).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vol.journal");
+
+        let a = fs.create_file("/a").await.unwrap();
+        // Never created, like a file purged since the crash.
+        let gone = a + 1000;
+        {
+            // What a client crashing before committing its buffered writes leaves behind.
+            let journal = WriteJournal::create(&path).unwrap();
+            let seq = journal.append(a as u64, 0, b"stale").await.unwrap();
+            journal.append(gone as u64, 0, b"lost").await.unwrap();
+            journal.checkpoint(a as u64, seq).await.unwrap();
+            journal.append(a as u64, 0, b"hello").await.unwrap();
+        }
+
+        let recovery = fs.recover_write_journal(&path).await.unwrap();
+        assert_eq!(recovery.replayed, 1);
+        assert_eq!(recovery.replayed_bytes, 5);
+        assert_eq!(recovery.failed.len(), 1);
+        assert_eq!(recovery.failed[0].ino, gone);
+        assert!(!recovery.is_clean());
+        let unrecovered = recovery.unrecovered_path.unwrap();
+        let (saved, _) = crate::vfs::journal::read_pending(&unrecovered).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].data, b"lost");
+        assert!(fs.recover_write_journal(&path).await.is_err());
+
+        let attr = fs.stat("/a").await.unwrap();
+        let fh = fs.open(a, attr, true, true).await.unwrap();
+        assert_eq!(fs.read(fh, 0, 5).await.unwrap(), b"hello");
+
+        // Writes are journaled until committed.
+        fs.write(fh, 5, b" world").await.unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        fs.close(fh).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use super::*;
+    use crate::meta::store::MetaError;
+    use crate::vfs::error::VfsError;
+
+    #[tokio::test]
+    async fn test_name_and_depth_limits() {
+        let layout = ChunkLayout::default();
+        let store = InMemoryBlockStore::new();
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(layout, store, meta_handle.store()).await.unwrap();
+
+        let long = "a".repeat(256);
+        assert!(matches!(
+            fs.create_file(&format!("/{long}")).await,
+            Err(VfsError::FilenameTooLong { .. })
+        ));
+        assert!(matches!(
+            fs.stat(&format!("/{long}/x")).await,
+            Err(VfsError::FilenameTooLong { .. })
+        ));
+        fs.create_file(&format!("/{}", &long[1..])).await.unwrap();
+
+        // The metadata layer refuses it too, before reaching the store.
+        let root = fs.root_ino();
+        assert!(matches!(
+            fs.meta_layer().mkdir(root, long.clone()).await,
+            Err(MetaError::NameTooLong(256))
+        ));
+        assert!(matches!(
+            fs.meta_layer().lookup(root, &long).await,
+            Err(MetaError::NameTooLong(256))
+        ));
+
+        fs.set_max_path_depth(3);
+        assert_eq!(fs.max_path_depth(), 3);
+        fs.mkdir_p("/a/b/c").await.unwrap();
+        assert!(matches!(
+            fs.mkdir_p("/a/b/c/d").await,
+            Err(VfsError::PathTooDeep { .. })
+        ));
+        assert!(!fs.exists("/a/b/c/d").await);
+        assert!(fs.exists("/a/b/c").await);
+    }
+}
+
+#[cfg(test)]
+mod append_tests {
+    use super::*;
+    use crate::meta::MetaStore;
+    use crate::vfs::append::{APPEND_XATTR, AppendConfig};
+    use crate::vfs::chunk_id_for;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_append_mode_coalesces_closes() {
+        let layout = ChunkLayout::default();
+        let store = InMemoryBlockStore::new();
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let meta = meta_handle.store();
+        let fs = VFS::new(layout, store, meta.clone()).await.unwrap();
+        fs.set_append_config(AppendConfig {
+            window: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let slices = |ino: i64| {
+            let meta = meta.clone();
+            async move {
+                meta.get_slices(chunk_id_for(ino, 0).unwrap())
+                    .await
+                    .unwrap()
+                    .len()
+            }
+        };
+
+        let log = fs.create_file("/log").await.unwrap();
+        assert!(
+            fs.set_xattr_ino(log, APPEND_XATTR, b"yes", 0)
+                .await
+                .is_err()
+        );
+        fs.set_xattr_ino(log, APPEND_XATTR, b"1", 0).await.unwrap();
+        let mut expected = Vec::new();
+        for i in 0..10 {
+            let line = format!("line {i}\n");
+            let attr = fs.stat("/log").await.unwrap();
+            let fh = fs.open(log, attr, false, true).await.unwrap();
+            fs.write(fh, expected.len() as u64, line.as_bytes())
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.close(fh).await.unwrap();
+            expected.extend_from_slice(line.as_bytes());
+        }
+        // Nothing was committed by the closes, the window commits all lines at once.
+        assert_eq!(slices(log).await, 0);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(slices(log).await, 1);
+        let attr = fs.stat("/log").await.unwrap();
+        let fh = fs.open(log, attr, true, false).await.unwrap();
+        assert_eq!(fs.read(fh, 0, expected.len()).await.unwrap(), expected);
+        fs.close(fh).await.unwrap();
+
+        // fsync commits at once, and without append mode every close commits.
+        let attr = fs.stat("/log").await.unwrap();
+        let fh = fs.open(log, attr, false, true).await.unwrap();
+        fs.write(fh, expected.len() as u64, b"synced\n")
+            .await
+            .unwrap();
+        fs.fsync(fh, false).await.unwrap();
+        assert_eq!(slices(log).await, 2);
+        fs.close(fh).await.unwrap();
+        fs.remove_xattr_ino(log, APPEND_XATTR).await.unwrap();
+        let plain = fs.create_file("/plain").await.unwrap();
+        for i in 0..3u64 {
+            let attr = fs.stat("/plain").await.unwrap();
+            let fh = fs.open(plain, attr, false, true).await.unwrap();
+            fs.write(fh, i, b"x").await.unwrap();
+            fs.close(fh).await.unwrap();
+        }
+        assert_eq!(slices(plain).await, 3);
+    }
+}
+
+mod page_cache_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open_keeps_cache_of_unchanged_files() {
+        let layout = ChunkLayout::default();
+        let store = InMemoryBlockStore::new();
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let fs = VFS::new(layout, store, meta_handle.store().clone())
+            .await
+            .unwrap();
+        let ino = fs.create_file("/data").await.unwrap();
+        fs.write_ino(ino, 0, b"dataset").await.unwrap();
+
+        let open = || {
+            let fs = fs.clone();
+            async move {
+                let attr = fs.stat("/data").await.unwrap();
+                let (fh, keep) = fs.open_cached(ino, attr, true, false).await.unwrap();
+                fs.close(fh).await.unwrap();
+                keep
+            }
+        };
+        assert!(!open().await);
+        assert!(open().await);
+
+        // A write through the VFS, like a truncate, drops the cache at the next open.
+        let attr = fs.stat("/data").await.unwrap();
+        let fh = fs.open(ino, attr, false, true).await.unwrap();
+        fs.write(fh, 7, b"!").await.unwrap();
+        fs.close(fh).await.unwrap();
+        assert!(!open().await);
+        assert!(open().await);
+        fs.truncate_inode(ino, 2).await.unwrap();
+        assert!(!open().await);
+
+        fs.set_keep_page_cache(false);
+        assert!(!open().await);
+        assert!(!open().await);
+    }
+}
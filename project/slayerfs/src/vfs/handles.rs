@@ -3,6 +3,7 @@
 use crate::chuck::BlockStore;
 use crate::meta::MetaLayer;
 use crate::meta::store::FileAttr;
+use crate::utils::buf_pool::PooledBuf;
 use crate::vfs::fs::DirEntry;
 use crate::vfs::io::{FileReader, FileWriter};
 use anyhow::anyhow;
@@ -237,7 +238,7 @@ where
     }
 
     #[tracing::instrument(name = "Handle.read", level = "trace", skip(self))]
-    pub(crate) async fn read(&self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+    pub(crate) async fn read(&self, offset: u64, len: usize) -> anyhow::Result<PooledBuf> {
         let _guard = self.gate.read_lock().await;
         let reader = {
             let guard = self.state.lock().unwrap();
@@ -9,6 +9,7 @@
 use crate::chuck::reader::DataFetcher;
 use crate::chuck::{BlockStore, ChunkLayout};
 use crate::meta::MetaLayer;
+use crate::utils::buf_pool::{BufferPool, PooledBuf};
 use crate::utils::{Intervals, NumCastExt, UsageGuard};
 use crate::vfs::Inode;
 use crate::vfs::backend::Backend;
@@ -28,11 +29,27 @@ use tracing::Instrument;
 const MAX_WAIT: Duration = Duration::from_secs(30);
 const DEFAULT_TOTAL_AHEAD_LIMIT: u64 = 256 * 1024 * 1024;
 const READ_SESSIONS: usize = 2;
+/// Slices and `WillNeed` hints not accessed for this long are evicted.
+const SLICE_TTL: Duration = Duration::from_secs(30);
+
+/// Access pattern hint for a range of a file, as given to `posix_fadvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAdvice {
+    /// No particular pattern, nothing is changed.
+    Normal,
+    /// The range will be read soon: it is fetched in the background and kept in the read
+    /// buffer until read or evicted by age.
+    WillNeed,
+    /// The range won't be read again: buffered data for it is dropped.
+    DontNeed,
+}
 
 #[allow(clippy::type_complexity)]
 pub(crate) struct DataReader<B, M> {
     config: Arc<ReadConfig>,
     buffer_usage: Arc<AtomicU64>,
+    /// Reply buffers shared by all readers.
+    buffers: Arc<BufferPool>,
     /// Per-handle readers, grouped by inode
     files: DashMap<u64, Vec<(u64, Arc<FileReader<B, M>>)>>, // ino -> (fh, reader)
     backend: Arc<Backend<B, M>>,
@@ -47,6 +64,7 @@ where
         Self {
             config,
             buffer_usage: Arc::new(AtomicU64::new(0)),
+            buffers: BufferPool::new(),
             files: DashMap::new(),
             backend,
         }
@@ -57,6 +75,7 @@ where
         let reader = Arc::new(FileReader::new(
             self.config.clone(),
             self.buffer_usage.clone(),
+            self.buffers.clone(),
             ino,
             self.backend.clone(),
         ));
@@ -94,7 +113,6 @@ where
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn reader_for_handle(&self, ino: u64, fh: u64) -> Option<Arc<FileReader<B, M>>> {
         self.files.get(&ino).and_then(|entry| {
             entry
@@ -123,6 +141,32 @@ where
             reader.invalidate_all().await;
         }
     }
+
+    /// Apply `advice` for `len` bytes at `offset` of `ino`, read through handle `fh`. A zero
+    /// `len` extends to the end of the file. `WillNeed` prefetches into the buffer of `fh`,
+    /// `DontNeed` drops the range from the buffers of every handle of the file.
+    pub(crate) async fn advise(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        len: u64,
+        advice: FileAdvice,
+    ) {
+        match advice {
+            FileAdvice::Normal => {}
+            FileAdvice::WillNeed => {
+                if let Some(reader) = self.reader_for_handle(ino, fh) {
+                    reader.will_need(offset, len).await;
+                }
+            }
+            FileAdvice::DontNeed => {
+                for reader in self.collect_readers(ino) {
+                    reader.dont_need(offset, len).await;
+                }
+            }
+        }
+    }
 }
 
 /// A Session tracks the read pattern of a specific handle to guide slice eviction.
@@ -405,9 +449,12 @@ impl Drop for SlicePinGuard {
 pub(crate) struct FileReader<B, M> {
     config: Arc<ReadConfig>,
     buffer_usage: Arc<AtomicU64>,
+    buffers: Arc<BufferPool>,
     inode: Arc<Inode>,
     slices: Mutex<VecDeque<Arc<ParkingMutex<SliceState>>>>,
     sessions: ParkingMutex<[Session; READ_SESSIONS]>,
+    /// File ranges advised with `WillNeed`, kept from eviction like session windows.
+    hints: ParkingMutex<Vec<(u64, u64, Instant)>>,
     backend: Arc<Backend<B, M>>,
 }
 
@@ -419,6 +466,7 @@ where
     pub(crate) fn new(
         config: Arc<ReadConfig>,
         buffer_usage: Arc<AtomicU64>,
+        buffers: Arc<BufferPool>,
         inode: Arc<Inode>,
         backend: Arc<Backend<B, M>>,
     ) -> Self {
@@ -426,19 +474,21 @@ where
             config,
             inode,
             buffer_usage,
+            buffers,
             slices: Mutex::new(VecDeque::new()),
             sessions: ParkingMutex::new([Session::default(); READ_SESSIONS]),
+            hints: ParkingMutex::new(Vec::new()),
             backend,
         }
     }
 
     #[tracing::instrument(name = "FileReader.read", level = "trace", skip(self))]
-    pub(crate) async fn read(&self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+    pub(crate) async fn read(&self, offset: u64, len: usize) -> anyhow::Result<PooledBuf> {
+        let mut buf = self.buffers.get(len);
         if len == 0 {
-            return Ok(Vec::new());
+            return Ok(buf);
         }
 
-        let mut buf = vec![0u8; len];
         let read = self.read_at(offset, &mut buf).await?;
         buf.truncate(read);
         Ok(buf)
@@ -562,11 +612,16 @@ where
 
     async fn clean_evictable_slices(&self, offset: u64, len: usize) {
         let sessions = *self.sessions.lock();
-        let windows = sessions
+        let mut windows = sessions
             .iter()
             .filter(|s| s.total > 0)
             .map(|s| s.window(self.config.layout.block_size as u64))
             .collect::<Vec<_>>();
+        {
+            let mut hints = self.hints.lock();
+            hints.retain(|(_, _, at)| at.elapsed() <= SLICE_TTL);
+            windows.extend(hints.iter().map(|(start, end, _)| (*start, *end)));
+        }
 
         let slice_limit = self.max_slice_amount();
 
@@ -586,7 +641,7 @@ where
             let needed_by_session = windows
                 .iter()
                 .any(|(win_start, win_end)| slice_start < *win_end && *win_start < slice_end);
-            let expired = now.duration_since(state.last_access) > Duration::from_secs(30);
+            let expired = now.duration_since(state.last_access) > SLICE_TTL;
 
             let mut keep = true;
             if (matches!(state.state, SliceStatus::Invalid) && state.refs == 0)
@@ -664,6 +719,56 @@ where
         }
     }
 
+    // Clamp `len` bytes at `offset` to the file, a zero `len` meaning up to its end.
+    fn advised_range(&self, offset: u64, len: u64) -> Option<(u64, u64)> {
+        let file_size = self.inode.file_size();
+        let end = match len {
+            0 => file_size,
+            len => offset.saturating_add(len).min(file_size),
+        };
+        (offset < end).then_some((offset, end))
+    }
+
+    /// Start fetching a range expected to be read soon, capped to what readahead may buffer.
+    pub(crate) async fn will_need(&self, offset: u64, len: u64) {
+        let Some((start, end)) = self.advised_range(offset, len) else {
+            return;
+        };
+        let end = end.min(start.saturating_add(self.total_ahead_limit()));
+        self.hints.lock().push((start, end, Instant::now()));
+
+        let spans = split_chunk_spans(self.config.layout, start, (end - start).as_usize());
+        for span in spans {
+            // Fetches go on in the background once the pins are dropped.
+            self.prepare_slices(span.index, (span.offset, span.offset + span.len))
+                .await;
+        }
+    }
+
+    /// Forget a range that won't be read again, dropping the buffered slices it overlaps.
+    pub(crate) async fn dont_need(&self, offset: u64, len: u64) {
+        let Some((start, end)) = self.advised_range(offset, len) else {
+            return;
+        };
+        self.hints
+            .lock()
+            .retain(|(hint_start, hint_end, _)| *hint_end <= start || end <= *hint_start);
+
+        let chunk_size = self.config.layout.chunk_size;
+        self.slices.lock().await.retain(|slice| {
+            let mut state = slice.lock();
+            let (slice_start, slice_end) = state.range_to_file(chunk_size);
+            if slice_end <= start || end <= slice_start || state.refs > 0 || state.in_flight() {
+                return true;
+            }
+            state.generation = state.generation.saturating_add(1);
+            state.state = SliceStatus::Invalid;
+            state.page = Vec::new();
+            state.usage.update_bytes(0);
+            false
+        });
+    }
+
     #[tracing::instrument(name = "FileReader.read_at", level = "trace", skip(self, buf), fields(offset, len = buf.len()))]
     pub(crate) async fn read_at(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<usize> {
         if buf.is_empty() {
@@ -1085,7 +1190,7 @@ mod tests {
         let reader = DataReader::new(Arc::new(ReadConfig::new(layout)), backend.clone());
         let file_reader = reader.open_for_handle(inode, 1);
         let out = file_reader.read(offset, data.len()).await.unwrap();
-        assert_eq!(out, data);
+        assert_eq!(&*out, data);
     }
 
     #[tokio::test]
@@ -1131,7 +1236,7 @@ mod tests {
         let reader = DataReader::new(Arc::new(ReadConfig::new(layout)), backend.clone());
         let file_reader = reader.open_for_handle(inode, 1);
         let out1 = file_reader.read(0, data1.len()).await.unwrap();
-        assert_eq!(out1, data1);
+        assert_eq!(&*out1, data1);
 
         let slice_id2 = meta_store.next_id(SLICE_ID_KEY).await.unwrap();
         uploader
@@ -1157,7 +1262,79 @@ mod tests {
 
         reader.invalidate(ino as u64, 0, data2.len()).await.unwrap();
         let out2 = file_reader.read(0, data2.len()).await.unwrap();
-        assert_eq!(out2, data2);
+        assert_eq!(&*out2, data2);
+    }
+
+    #[tokio::test]
+    async fn test_reader_advice() {
+        let layout = small_layout();
+        let block_store = Arc::new(InMemoryBlockStore::new());
+        let meta_handle = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+        let meta_store = meta_handle.store();
+        let meta = meta_handle.layer();
+        let backend = Arc::new(Backend::new(block_store.clone(), meta.clone()));
+
+        let ino: i64 = 33;
+        let data = vec![7u8; 4096];
+        let slice_id = meta_store.next_id(SLICE_ID_KEY).await.unwrap();
+        let uploader = DataUploader::new(layout, backend.as_ref());
+        uploader
+            .write_at_vectored(slice_id as u64, 0u64.into(), &[Bytes::from(data.clone())])
+            .await
+            .unwrap();
+        meta_store
+            .append_slice(
+                chunk_id_for(ino, 0).unwrap(),
+                SliceDesc {
+                    slice_id: slice_id as u64,
+                    chunk_id: chunk_id_for(ino, 0).unwrap(),
+                    offset: 0,
+                    length: data.len() as u64,
+                },
+            )
+            .await
+            .unwrap();
+
+        let inode = Inode::new(ino, data.len() as u64);
+        let reader = DataReader::new(Arc::new(ReadConfig::new(layout)), backend.clone());
+        let file_reader = reader.open_for_handle(inode, 1);
+        let buffered = || async {
+            let slices = file_reader.slices.lock().await;
+            slices
+                .iter()
+                .filter(|s| matches!(s.lock().state, SliceStatus::Ready))
+                .map(|s| s.lock().page.len())
+                .sum::<usize>()
+        };
+
+        // WillNeed fetches the whole file before it is read.
+        reader
+            .advise(ino as u64, 1, 0, 0, FileAdvice::WillNeed)
+            .await;
+        timeout(Duration::from_secs(1), async {
+            while buffered().await < data.len() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("advised range should be prefetched");
+
+        // Served from the buffer once the blocks are gone.
+        block_store
+            .delete_range((slice_id as u64, 0), 1)
+            .await
+            .unwrap();
+        assert_eq!(&*file_reader.read(0, data.len()).await.unwrap(), data);
+
+        // DontNeed drops it, the next read goes to the store again.
+        reader
+            .advise(ino as u64, 1, 0, 0, FileAdvice::DontNeed)
+            .await;
+        assert_eq!(buffered().await, 0);
+        assert_eq!(
+            &*file_reader.read(0, data.len()).await.unwrap(),
+            vec![0u8; data.len()]
+        );
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -1190,6 +1367,7 @@ mod tests {
             backend.clone(),
             reader,
             Arc::new(AtomicU64::new(0)),
+            Arc::default(),
         ));
 
         let write_task = {
@@ -1204,7 +1382,7 @@ mod tests {
         timeout(Duration::from_secs(1), async {
             loop {
                 let out = file_reader.read(0, data.len()).await.unwrap();
-                if out == data {
+                if *out == data {
                     break;
                 }
                 sleep(Duration::from_millis(10)).await;
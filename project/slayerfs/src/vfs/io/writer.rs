@@ -24,13 +24,14 @@ use crate::vfs::chunk_id_for;
 use crate::vfs::config::WriteConfig;
 use crate::vfs::extract_ino_and_chunk_index;
 use crate::vfs::io::split_chunk_spans;
+use crate::vfs::journal::WriteJournal;
 use bytes::Bytes;
 use dashmap::DashMap;
 use parking_lot::Mutex as ParkingMutex;
 use rand::RngCore;
 use std::collections::{BTreeMap, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, OnceLock, Weak};
 use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, Notify};
 use tokio::time::{interval, timeout};
@@ -547,6 +548,7 @@ struct Shared<B, M> {
     flush_notify: Notify,
     backend: Arc<Backend<B, M>>,
     reader: Arc<DataReader<B, M>>,
+    journal: Arc<OnceLock<WriteJournal>>,
 }
 
 impl<B, M> Shared<B, M>
@@ -560,6 +562,7 @@ where
         backend: Arc<Backend<B, M>>,
         reader: Arc<DataReader<B, M>>,
         buffer_usage: Arc<AtomicU64>,
+        journal: Arc<OnceLock<WriteJournal>>,
     ) -> Self {
         Self {
             inode,
@@ -568,12 +571,29 @@ where
             inner: Mutex::new(Inner {
                 flush_waiting: 0,
                 write_waiting: 0,
+                writing: 0,
+                journaled: 0,
                 chunks: BTreeMap::default(),
             }),
             write_notify: Notify::new(),
             flush_notify: Notify::new(),
             backend,
             reader,
+            journal,
+        }
+    }
+
+    /// Checkpoint the journaled writes once everything buffered was committed.
+    async fn checkpoint_if_drained(&self, inner: &Inner) {
+        if inner.has_chunks() || inner.writing > 0 || inner.journaled == 0 {
+            return;
+        }
+        if let Some(journal) = self.journal.get()
+            && let Err(err) = journal
+                .checkpoint(self.inode.ino() as u64, inner.journaled)
+                .await
+        {
+            warn!(ino = self.inode.ino(), error = ?err, "write journal checkpoint failed");
         }
     }
 }
@@ -581,6 +601,10 @@ where
 struct Inner {
     flush_waiting: u16,
     write_waiting: u16,
+    /// Writes between being journaled and fully buffered.
+    writing: u16,
+    /// Sequence number of the last journaled write.
+    journaled: u64,
     chunks: BTreeMap<u64, ChunkState>,
 }
 
@@ -665,8 +689,16 @@ where
         backend: Arc<Backend<B, M>>,
         reader: Arc<DataReader<B, M>>,
         buffer_usage: Arc<AtomicU64>,
+        journal: Arc<OnceLock<WriteJournal>>,
     ) -> Self {
-        let shared = Arc::new(Shared::new(inode, config, backend, reader, buffer_usage));
+        let shared = Arc::new(Shared::new(
+            inode,
+            config,
+            backend,
+            reader,
+            buffer_usage,
+            journal,
+        ));
         let flush_shared = Arc::downgrade(&shared);
         tokio::spawn(async move { Self::auto_flush(flush_shared).await });
         Self { shared }
@@ -688,6 +720,13 @@ where
         }
         guard.write_waiting -= 1;
 
+        if let Some(journal) = self.shared.journal.get() {
+            guard.journaled = journal
+                .append(self.shared.inode.ino() as u64, offset, buf)
+                .await?;
+        }
+        guard.writing += 1;
+
         let mut position = 0;
 
         let spans = split_chunk_spans(self.shared.config.layout, offset, buf.len());
@@ -704,7 +743,13 @@ where
             // Alternatively, the API signature could be modified or added to request "Bytes" from users. However,
             // this would break POSIX compatibility and is not supported by FUSE.
             let span_len = span.len.as_usize();
-            let action = handle.write_at(span.offset, &buf[position..position + span_len])?;
+            let action = match handle.write_at(span.offset, &buf[position..position + span_len]) {
+                Ok(action) => action,
+                Err(err) => {
+                    guard.writing -= 1;
+                    return Err(err);
+                }
+            };
             drop(guard);
 
             for slice in action.flush {
@@ -720,6 +765,7 @@ where
             position += span_len;
         }
 
+        guard.writing -= 1;
         drop(guard);
         let new_len = offset + buf.len() as u64;
         if new_len > self.shared.inode.file_size() {
@@ -813,6 +859,8 @@ where
 
         let mut guard = self.shared.inner.lock().await;
         guard.chunks.clear();
+        // Discarded data must not come back on replay.
+        self.shared.checkpoint_if_drained(&guard).await;
 
         if guard.flush_waiting > 0 {
             self.shared.flush_notify.notify_waiters();
@@ -952,6 +1000,7 @@ where
             let Some(slice) = slice else {
                 let mut guard = shared.inner.lock().await;
                 guard.chunks.remove(&chunk_id);
+                shared.checkpoint_if_drained(&guard).await;
 
                 if !guard.has_chunks() && guard.flush_waiting > 0 {
                     shared.flush_notify.notify_waiters();
@@ -1098,6 +1147,7 @@ where
                 .unwrap_or(true);
             if empty {
                 guard.chunks.remove(&chunk_id);
+                shared.checkpoint_if_drained(&guard).await;
                 if !guard.has_chunks() && guard.flush_waiting > 0 {
                     shared.flush_notify.notify_waiters();
                 }
@@ -1189,6 +1239,7 @@ pub(crate) struct DataWriter<B, M> {
     reader: Arc<DataReader<B, M>>,
     files: DashMap<u64, Arc<FileWriter<B, M>>>,
     buffer_usage: Arc<AtomicU64>,
+    journal: Arc<OnceLock<WriteJournal>>,
 }
 
 impl<B, M> DataWriter<B, M>
@@ -1207,9 +1258,19 @@ where
             reader,
             files: DashMap::new(),
             buffer_usage: Arc::new(AtomicU64::new(0)),
+            journal: Arc::default(),
         }
     }
 
+    pub(crate) fn has_journal(&self) -> bool {
+        self.journal.get().is_some()
+    }
+
+    /// Journal all writes from now on, a journal set before is kept.
+    pub(crate) fn set_journal(&self, journal: WriteJournal) {
+        let _ = self.journal.set(journal);
+    }
+
     pub(crate) fn ensure_file(&self, inode: Arc<Inode>) -> Arc<FileWriter<B, M>> {
         self.files
             .entry(inode.ino() as u64)
@@ -1220,6 +1281,7 @@ where
                     self.backend.clone(),
                     self.reader.clone(),
                     self.buffer_usage.clone(),
+                    self.journal.clone(),
                 ))
             })
             .clone()
@@ -1250,6 +1312,14 @@ where
         }
     }
 
+    pub(crate) async fn has_pending(&self, ino: u64) -> bool {
+        let writer = self.files.get(&ino).map(|entry| entry.value().clone());
+        match writer {
+            Some(writer) => writer.has_pending().await,
+            None => false,
+        }
+    }
+
     pub(crate) async fn clear(&self, ino: u64) {
         let writer = self.files.get(&ino).map(|entry| entry.value().clone());
         if let Some(writer) = writer {
@@ -1435,6 +1505,7 @@ mod tests {
             backend.clone(),
             reader,
             Arc::new(AtomicU64::new(0)),
+            Arc::default(),
         );
 
         let len = (layout.block_size / 2) as usize;
@@ -1481,6 +1552,7 @@ mod tests {
             backend.clone(),
             reader,
             Arc::new(AtomicU64::new(0)),
+            Arc::default(),
         );
 
         let len = (layout.block_size / 4) as usize;
@@ -1527,6 +1599,7 @@ mod tests {
             backend.clone(),
             reader.clone(),
             Arc::new(AtomicU64::new(0)),
+            Arc::default(),
         );
 
         let len = layout.chunk_size as usize + 1024;
@@ -1540,7 +1613,7 @@ mod tests {
 
         let file_reader = reader.open_for_handle(inode, 1);
         let out = file_reader.read(0, len).await.unwrap();
-        assert_eq!(out, data);
+        assert_eq!(&*out, data);
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -1570,6 +1643,7 @@ mod tests {
             backend.clone(),
             reader,
             Arc::new(AtomicU64::new(0)),
+            Arc::default(),
         ));
 
         let data = vec![3u8; 2048];
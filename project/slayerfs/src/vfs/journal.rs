@@ -0,0 +1,540 @@
+//! Node-local journal of buffered writes.
+//!
+//! Writes sit in the write-back buffer until their slices are committed to the metadata
+//! layer, so a client crash used to drop them and leave files silently truncated. With a
+//! journal, every write is appended to a local file before it is buffered, and a checkpoint
+//! is appended once everything buffered for the inode was committed. The writes of a file
+//! that never got a checkpoint are replayed by the next mount of the volume on this node,
+//! see [`crate::vfs::fs::VFS::recover_write_journal`].
+//!
+//! The journal is not synced to disk per write: it survives a crash of the client process,
+//! not a crash of the node. Records are written by a dedicated thread, callers wait for
+//! theirs without blocking the runtime.
+//!
+//! The journal is split into segments. Records go to the file at the journal path, which is
+//! sealed as `<path>.<n>` once it reaches [`SEGMENT_SIZE`]. A sealed segment is removed once
+//! none of its writes is uncommitted, so a client writing to many files keeps a bounded
+//! journal, and a new segment starts with the checkpoints covering writes still in sealed
+//! ones. Segments are read in order, the file at the journal path last.
+//!
+//! Record layout, little-endian, each followed by the first 4 bytes of the BLAKE3 hash of
+//! the record:
+//! - write: `1`, ino `u64`, seq `u64`, offset `u64`, len `u32`, data
+//! - checkpoint: `2`, ino `u64`, seq `u64`, covering the writes of ino up to seq
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use tokio::sync::{mpsc, oneshot};
+
+const TAG_WRITE: u8 = 1;
+const TAG_CHECKPOINT: u8 = 2;
+const CHECKSUM_LEN: usize = 4;
+
+/// Size past which the segment written is sealed and a new one started.
+const SEGMENT_SIZE: u64 = 64 << 20;
+
+/// A write found in the journal without a checkpoint covering it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct JournaledWrite {
+    pub ino: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Outcome of replaying the journal left by a previous mount.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JournalRecovery {
+    /// Writes applied to their files again.
+    pub replayed: usize,
+    pub replayed_bytes: u64,
+    /// Writes that could not be applied, e.g. because the file was removed since.
+    pub failed: Vec<UnrecoveredWrite>,
+    /// Where the failed writes were saved, in the journal format.
+    pub unrecovered_path: Option<PathBuf>,
+    /// Whether a journal segment ended in a partial or corrupt record, which was dropped
+    /// along with the rest of the segment.
+    pub truncated_tail: bool,
+}
+
+impl JournalRecovery {
+    /// Whether every journaled write made it back into its file.
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty() && !self.truncated_tail
+    }
+}
+
+/// A journaled write [`JournalRecovery`] could not replay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnrecoveredWrite {
+    pub ino: i64,
+    pub offset: u64,
+    pub len: u64,
+    pub error: String,
+}
+
+struct SealedSegment {
+    path: PathBuf,
+    // Last seq of every inode with uncommitted writes in the segment.
+    pending: HashMap<u64, u64>,
+    // Every inode with writes in the segment.
+    written: HashSet<u64>,
+}
+
+struct JournalState {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    segment_size: u64,
+    next_seq: u64,
+    next_segment: u64,
+    // Last journaled seq of every inode without a covering checkpoint, in the segment
+    // written.
+    pending: HashMap<u64, u64>,
+    written: HashSet<u64>,
+    sealed: VecDeque<SealedSegment>,
+    // Latest checkpoint of the inodes with writes in any segment, repeated at the start of
+    // every new segment.
+    checkpoints: HashMap<u64, u64>,
+}
+
+impl JournalState {
+    fn append(&mut self, ino: u64, offset: u64, data: &[u8]) -> io::Result<u64> {
+        if self.len >= self.segment_size {
+            self.rotate()?;
+        }
+        let seq = self.next_seq;
+        self.write_record(write_record(ino, seq, offset, data))?;
+        self.next_seq += 1;
+        self.pending.insert(ino, seq);
+        self.written.insert(ino);
+        Ok(seq)
+    }
+
+    fn checkpoint(&mut self, ino: u64, seq: u64) -> io::Result<()> {
+        let mut journaled = false;
+        let segments = std::iter::once(&mut self.pending)
+            .chain(self.sealed.iter_mut().map(|segment| &mut segment.pending));
+        for pending in segments {
+            if let Some(&last) = pending.get(&ino) {
+                journaled = true;
+                if last <= seq {
+                    pending.remove(&ino);
+                }
+            }
+        }
+        if !journaled {
+            return Ok(());
+        }
+
+        while let Some(idx) = self.sealed.iter().position(|s| s.pending.is_empty()) {
+            fs::remove_file(&self.sealed[idx].path)?;
+            self.sealed.remove(idx);
+        }
+        if self.sealed.is_empty() && self.pending.is_empty() {
+            // Nothing left to replay.
+            self.file.set_len(0)?;
+            self.len = 0;
+            self.written.clear();
+            self.checkpoints.clear();
+            return Ok(());
+        }
+
+        let covered = self.checkpoints.entry(ino).or_default();
+        *covered = (*covered).max(seq);
+        let written: HashSet<u64> = self
+            .sealed
+            .iter()
+            .flat_map(|segment| segment.written.iter().copied())
+            .chain(self.written.iter().copied())
+            .collect();
+        self.checkpoints.retain(|ino, _| written.contains(ino));
+        self.write_record(checkpoint_record(ino, seq))
+    }
+
+    // Seal the segment written and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = sibling(&self.path, &self.next_segment.to_string());
+        fs::rename(&self.path, &path)?;
+        self.next_segment += 1;
+        let segment = SealedSegment {
+            path,
+            pending: std::mem::take(&mut self.pending),
+            written: std::mem::take(&mut self.written),
+        };
+        if segment.pending.is_empty() {
+            fs::remove_file(&segment.path)?;
+        } else {
+            self.sealed.push_back(segment);
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = 0;
+        let checkpoints: Vec<(u64, u64)> = self
+            .checkpoints
+            .iter()
+            .map(|(&ino, &seq)| (ino, seq))
+            .collect();
+        for (ino, seq) in checkpoints {
+            self.write_record(checkpoint_record(ino, seq))?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: Vec<u8>) -> io::Result<()> {
+        let record = with_checksum(record);
+        self.file.write_all(&record)?;
+        self.len += record.len() as u64;
+        Ok(())
+    }
+}
+
+enum Op {
+    Write {
+        ino: u64,
+        offset: u64,
+        data: Vec<u8>,
+        done: oneshot::Sender<io::Result<u64>>,
+    },
+    Checkpoint {
+        ino: u64,
+        seq: u64,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+pub(crate) struct WriteJournal {
+    ops: Option<mpsc::UnboundedSender<Op>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WriteJournal {
+    /// Start an empty journal at `path`, replacing the one there.
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        Self::with_segment_size(path, SEGMENT_SIZE)
+    }
+
+    fn with_segment_size(path: &Path, segment_size: u64) -> io::Result<Self> {
+        for (_, segment) in sealed_segments(path)? {
+            fs::remove_file(segment)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(0)?;
+        let mut state = JournalState {
+            path: path.to_path_buf(),
+            file,
+            len: 0,
+            segment_size,
+            next_seq: 1,
+            next_segment: 1,
+            pending: HashMap::new(),
+            written: HashSet::new(),
+            sealed: VecDeque::new(),
+            checkpoints: HashMap::new(),
+        };
+        let (ops, mut queued) = mpsc::unbounded_channel();
+        let thread = std::thread::Builder::new()
+            .name("slayerfs-journal".to_string())
+            .spawn(move || {
+                while let Some(op) = queued.blocking_recv() {
+                    match op {
+                        Op::Write {
+                            ino,
+                            offset,
+                            data,
+                            done,
+                        } => {
+                            let _ = done.send(state.append(ino, offset, &data));
+                        }
+                        Op::Checkpoint { ino, seq, done } => {
+                            let _ = done.send(state.checkpoint(ino, seq));
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            ops: Some(ops),
+            thread: Some(thread),
+        })
+    }
+
+    /// Record a write of `data` at `offset` of `ino`, returns its sequence number.
+    pub(crate) async fn append(&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<u64> {
+        u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let (done, written) = oneshot::channel();
+        self.send(Op::Write {
+            ino,
+            offset,
+            data: data.to_vec(),
+            done,
+        })?;
+        written.await.map_err(|_| closed())?
+    }
+
+    /// Mark the writes of `ino` up to `seq` as committed. Segments left without uncommitted
+    /// writes are removed, the journal is emptied once no inode has any left.
+    pub(crate) async fn checkpoint(&self, ino: u64, seq: u64) -> io::Result<()> {
+        let (done, written) = oneshot::channel();
+        self.send(Op::Checkpoint { ino, seq, done })?;
+        written.await.map_err(|_| closed())?
+    }
+
+    fn send(&self, op: Op) -> io::Result<()> {
+        self.ops
+            .as_ref()
+            .and_then(|ops| ops.send(op).ok())
+            .ok_or_else(closed)
+    }
+}
+
+impl Drop for WriteJournal {
+    fn drop(&mut self) {
+        // The thread writes the records already queued before it stops.
+        self.ops.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "write journal closed")
+}
+
+fn write_record(ino: u64, seq: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(29 + data.len() + CHECKSUM_LEN);
+    record.push(TAG_WRITE);
+    record.extend_from_slice(&ino.to_le_bytes());
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&offset.to_le_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+fn checkpoint_record(ino: u64, seq: u64) -> Vec<u8> {
+    let mut record = Vec::with_capacity(17 + CHECKSUM_LEN);
+    record.push(TAG_CHECKPOINT);
+    record.extend_from_slice(&ino.to_le_bytes());
+    record.extend_from_slice(&seq.to_le_bytes());
+    record
+}
+
+fn checksum(record: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = blake3::hash(record);
+    hash.as_bytes()[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+fn with_checksum(mut record: Vec<u8>) -> Vec<u8> {
+    let checksum = checksum(&record);
+    record.extend_from_slice(&checksum);
+    record
+}
+
+/// Writes of the journal at `path` without a covering checkpoint, in journal order, and
+/// whether a segment ended in a partial or corrupt record. A missing journal has nothing
+/// pending.
+pub(crate) fn read_pending(path: &Path) -> io::Result<(Vec<JournaledWrite>, bool)> {
+    let mut segments: Vec<PathBuf> = sealed_segments(path)?
+        .into_iter()
+        .map(|(_, segment)| segment)
+        .collect();
+    segments.push(path.to_path_buf());
+
+    let mut writes: Vec<(u64, JournaledWrite)> = Vec::new();
+    let mut checkpoints: HashMap<u64, u64> = HashMap::new();
+    let mut truncated = false;
+    for segment in segments {
+        let file = match File::open(&segment) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+        truncated |= read_segment(&mut reader, &mut writes, &mut checkpoints)?;
+    }
+
+    let pending = writes
+        .into_iter()
+        .filter(|(seq, w)| checkpoints.get(&w.ino).is_none_or(|covered| seq > covered))
+        .map(|(_, w)| w)
+        .collect();
+    Ok((pending, truncated))
+}
+
+// Collect the records of a segment, true if it ends in a partial or corrupt record. What
+// follows a corrupt record can't be trusted to start at a record boundary, so it's dropped.
+fn read_segment(
+    reader: &mut impl Read,
+    writes: &mut Vec<(u64, JournaledWrite)>,
+    checkpoints: &mut HashMap<u64, u64>,
+) -> io::Result<bool> {
+    loop {
+        let mut record = vec![0u8; 17];
+        if reader.read(&mut record[..1])? == 0 {
+            return Ok(false);
+        }
+        if read_full(reader, &mut record[1..])?.is_none() {
+            return Ok(true);
+        }
+        match record[0] {
+            TAG_WRITE => {
+                let mut rest = [0u8; 12];
+                if read_full(reader, &mut rest)?.is_none() {
+                    return Ok(true);
+                }
+                record.extend_from_slice(&rest);
+                let len = u32::from_le_bytes(rest[8..].try_into().unwrap());
+                // Read through `take` so a corrupt length can't allocate up front.
+                let read = (&mut *reader)
+                    .take(u64::from(len))
+                    .read_to_end(&mut record)?;
+                if read < len as usize {
+                    return Ok(true);
+                }
+            }
+            TAG_CHECKPOINT => {}
+            _ => return Ok(true),
+        }
+        let mut sum = [0u8; CHECKSUM_LEN];
+        if read_full(reader, &mut sum)?.is_none() || sum != checksum(&record) {
+            return Ok(true);
+        }
+
+        let ino = u64::from_le_bytes(record[1..9].try_into().unwrap());
+        let seq = u64::from_le_bytes(record[9..17].try_into().unwrap());
+        if record[0] == TAG_WRITE {
+            let offset = u64::from_le_bytes(record[17..25].try_into().unwrap());
+            let data = record.split_off(29);
+            writes.push((seq, JournaledWrite { ino, offset, data }));
+        } else {
+            let covered = checkpoints.entry(ino).or_default();
+            *covered = (*covered).max(seq);
+        }
+    }
+}
+
+/// Append `writes` to a journal file at `path`, synced to disk.
+pub(crate) fn save(path: &Path, writes: &[JournaledWrite]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for (seq, w) in (1u64..).zip(writes) {
+        u32::try_from(w.data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        file.write_all(&with_checksum(write_record(w.ino, seq, w.offset, &w.data)))?;
+    }
+    file.sync_all()
+}
+
+// Sealed segments of the journal at `path`, oldest first.
+fn sealed_segments(path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{prefix}.");
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let index = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .filter(|index| index.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|index| index.parse::<u64>().ok());
+        if let Some(index) = index {
+            segments.push((index, entry.path()));
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// `path` with `.suffix` appended.
+pub(crate) fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Fill `buf`, None if the reader ends first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<Option<()>> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(Some(())),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_segments_removed_once_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vol.journal");
+        let journal = WriteJournal::with_segment_size(&path, 64).unwrap();
+
+        // Every write of inode 2 fills a segment, inode 1 stays uncommitted in the first.
+        journal.append(1, 0, b"kept").await.unwrap();
+        let mut last = 0;
+        for i in 0..8 {
+            last = journal.append(2, i * 32, &[b'x'; 32]).await.unwrap();
+        }
+        assert_eq!(sealed_segments(&path).unwrap().len(), 7);
+
+        journal.checkpoint(2, last).await.unwrap();
+        let sealed: Vec<u64> = sealed_segments(&path)
+            .unwrap()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(sealed, [1]);
+        let (pending, truncated) = read_pending(&path).unwrap();
+        assert!(!truncated);
+        assert_eq!(
+            pending,
+            [JournaledWrite {
+                ino: 1,
+                offset: 0,
+                data: b"kept".to_vec(),
+            }]
+        );
+
+        journal.checkpoint(1, 1).await.unwrap();
+        assert!(sealed_segments(&path).unwrap().is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_record_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vol.journal");
+        let journal = WriteJournal::create(&path).unwrap();
+        journal.append(3, 0, b"first").await.unwrap();
+        journal.append(3, 5, b"second").await.unwrap();
+        drop(journal);
+
+        let mut bytes = fs::read(&path).unwrap();
+        let last_data = bytes.len() - CHECKSUM_LEN - 1;
+        bytes[last_data] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let (pending, truncated) = read_pending(&path).unwrap();
+        assert!(truncated);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].data, b"first");
+    }
+}
@@ -15,6 +15,7 @@
 //! Submodules:
 //! - `handles`: file and directory handle management
 //! - `cache`: caching helpers and policies
+pub(crate) mod append;
 pub(crate) mod backend;
 pub(crate) mod cache;
 pub(crate) mod config;
@@ -23,7 +24,11 @@ pub mod fs;
 pub(crate) mod handles;
 pub(crate) mod inode;
 pub(crate) mod io;
+pub(crate) mod journal;
+pub(crate) mod page_cache;
+pub(crate) mod quota;
 pub mod sdk;
+pub(crate) mod usage;
 // Module implementation TODOs remain.
 
 pub(crate) use inode::Inode;
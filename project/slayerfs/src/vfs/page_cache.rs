@@ -0,0 +1,124 @@
+//! Reuse of the kernel page cache across opens.
+//!
+//! Unless an open is replied with `FOPEN_KEEP_CACHE`, the kernel drops the cached pages of
+//! the file, so datasets read over and over are fetched from the chunk store on every open.
+//! An open keeps the cache when the file didn't change since it was last opened: its
+//! version, the size, mtime and ctime from the metadata store plus a counter of the changes
+//! made through this VFS, is the one recorded then. Changes made elsewhere while the file
+//! stays open are still noticed by the kernel through `FUSE_AUTO_INVAL_DATA`, once a
+//! getattr returns another size or mtime.
+
+use crate::meta::store::FileAttr;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Files whose version is kept, past it all are forgotten and lose their cache once.
+const MAX_TRACKED: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Version {
+    size: u64,
+    mtime: i64,
+    ctime: i64,
+    changes: u64,
+}
+
+#[derive(Default)]
+struct FileState {
+    /// Version at the last open, the one the kernel cache holds.
+    opened: Option<Version>,
+    changes: u64,
+}
+
+pub(crate) struct PageCacheTracker {
+    enabled: AtomicBool,
+    files: Mutex<HashMap<i64, FileState>>,
+}
+
+impl PageCacheTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.files.lock().clear();
+        }
+    }
+
+    /// Record a change of the data of `ino` made through this VFS.
+    pub(crate) fn changed(&self, ino: i64) {
+        // Files never opened have no cache to drop.
+        if let Some(file) = self.files.lock().get_mut(&ino) {
+            file.changes = file.changes.wrapping_add(1);
+        }
+    }
+
+    /// Record an open of the file at `attr`, fresh from the metadata store, and tell whether
+    /// the kernel may keep what it cached of it.
+    pub(crate) fn open(&self, attr: &FileAttr) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        let mut files = self.files.lock();
+        if files.len() >= MAX_TRACKED && !files.contains_key(&attr.ino) {
+            files.clear();
+        }
+        let file = files.entry(attr.ino).or_default();
+        let version = Version {
+            size: attr.size,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            changes: file.changes,
+        };
+        file.opened.replace(version) == Some(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::store::FileType;
+
+    fn attr(size: u64, mtime: i64) -> FileAttr {
+        FileAttr {
+            ino: 7,
+            size,
+            kind: FileType::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime,
+            ctime: mtime,
+            nlink: 1,
+        }
+    }
+
+    #[test]
+    fn test_keep_cache() {
+        let tracker = PageCacheTracker::new();
+        assert!(!tracker.open(&attr(10, 1)));
+        assert!(tracker.open(&attr(10, 1)));
+
+        // Changed elsewhere, or through this VFS.
+        assert!(!tracker.open(&attr(20, 2)));
+        assert!(tracker.open(&attr(20, 2)));
+        tracker.changed(7);
+        assert!(!tracker.open(&attr(20, 2)));
+        assert!(tracker.open(&attr(20, 2)));
+
+        tracker.set_enabled(false);
+        assert!(!tracker.open(&attr(20, 2)));
+        assert!(!tracker.open(&attr(20, 2)));
+    }
+}
@@ -0,0 +1,134 @@
+//! Soft quota thresholds of a volume.
+//!
+//! Crossing a soft threshold fails nothing. It publishes a [`QuotaEvent`] and flips the
+//! [`QUOTA_WARN_XATTR`] virtual xattr, so agents inside the mount can warn users before
+//! writes start failing.
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Virtual xattr readable on every inode, `1` while the volume is over a soft threshold
+/// and `0` otherwise.
+pub const QUOTA_WARN_XATTR: &str = "slayerfs.quota.warn";
+
+/// Usage is re-evaluated at most this often by the mutating operations, since the metadata
+/// backends compute it by scanning.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const EVENT_CAPACITY: usize = 16;
+
+/// Soft limits of a volume, unset fields are not checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SoftQuota {
+    /// Bytes in use above which the volume is in warning.
+    pub space: Option<u64>,
+    /// Inodes in use above which the volume is in warning.
+    pub inodes: Option<u64>,
+}
+
+impl SoftQuota {
+    pub fn is_set(&self) -> bool {
+        self.space.is_some() || self.inodes.is_some()
+    }
+
+    fn exceeded(&self, used_space: u64, used_inodes: u64) -> bool {
+        self.space.is_some_and(|limit| used_space > limit)
+            || self.inodes.is_some_and(|limit| used_inodes > limit)
+    }
+}
+
+/// Change of the soft quota state of a volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaEvent {
+    /// Usage went over a soft threshold.
+    SoftExceeded { used_space: u64, used_inodes: u64 },
+    /// Usage is back under all soft thresholds, or they were removed.
+    SoftCleared { used_space: u64, used_inodes: u64 },
+}
+
+pub(crate) struct QuotaMonitor {
+    quota: Mutex<SoftQuota>,
+    warn: AtomicBool,
+    last_check: Mutex<Option<Instant>>,
+    events: broadcast::Sender<QuotaEvent>,
+}
+
+impl QuotaMonitor {
+    pub(crate) fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            quota: Mutex::new(SoftQuota::default()),
+            warn: AtomicBool::new(false),
+            last_check: Mutex::new(None),
+            events,
+        }
+    }
+
+    pub(crate) fn quota(&self) -> SoftQuota {
+        *self.quota.lock()
+    }
+
+    /// Replace the thresholds, the state is re-evaluated by the next check.
+    pub(crate) fn set_quota(&self, quota: SoftQuota) {
+        *self.quota.lock() = quota;
+        *self.last_check.lock() = None;
+    }
+
+    pub(crate) fn warn(&self) -> bool {
+        self.warn.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<QuotaEvent> {
+        self.events.subscribe()
+    }
+
+    /// Whether thresholds are set and no check ran within [`CHECK_INTERVAL`]. Claims the
+    /// check when it returns true.
+    pub(crate) fn check_due(&self) -> bool {
+        if !self.quota().is_set() && !self.warn() {
+            return false;
+        }
+        let mut last = self.last_check.lock();
+        let now = Instant::now();
+        if last.is_some_and(|at| now.duration_since(at) < CHECK_INTERVAL) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+
+    /// Evaluate the thresholds against the usage of the volume, publishing an event when the
+    /// state changes. Returns the new state.
+    pub(crate) fn update(&self, used_space: u64, used_inodes: u64) -> bool {
+        let quota = self.quota();
+        let warn = quota.exceeded(used_space, used_inodes);
+        if self.warn.swap(warn, Ordering::Relaxed) == warn {
+            return warn;
+        }
+
+        let event = if warn {
+            tracing::warn!(
+                used_space,
+                used_inodes,
+                space_limit = ?quota.space,
+                inode_limit = ?quota.inodes,
+                "soft quota exceeded"
+            );
+            QuotaEvent::SoftExceeded {
+                used_space,
+                used_inodes,
+            }
+        } else {
+            tracing::info!(used_space, used_inodes, "soft quota cleared");
+            QuotaEvent::SoftCleared {
+                used_space,
+                used_inodes,
+            }
+        };
+        // No subscribers is fine, the xattr still carries the state.
+        let _ = self.events.send(event);
+        warn
+    }
+}
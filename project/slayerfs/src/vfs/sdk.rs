@@ -3,7 +3,7 @@
 //! Goals:
 //! - Path-level APIs: mkdir_p/create/read/write/readdir/stat
 //! - Pluggable backend: reuse Fs-level BlockStore and MetaStore
-//! - Provide a convenient LocalFs constructor
+//! - Provide a convenient LocalFs constructor, and a throwaway [`Volume`] for tests
 //!
 //! All methods return `io::Result<T>` for consistent error handling.
 
@@ -240,7 +240,9 @@ impl<S: BlockStore + Send + Sync + 'static, M: MetaStore + 'static> VfsClient<S,
 use crate::cadapter::client::ObjectClient;
 use crate::cadapter::localfs::LocalFsBackend;
 use crate::chuck::store::ObjectBlockStore;
+use crate::fs::CallerIdentity;
 use std::sync::Arc;
+use tempfile::TempDir;
 
 #[allow(dead_code)]
 pub type LocalClient = VfsClient<ObjectBlockStore<LocalFsBackend>, DatabaseMetaStore>;
@@ -271,9 +273,18 @@ impl LocalClient {
         root: P,
         layout: ChunkLayout,
         config: FileSystemConfig,
+    ) -> io::Result<Self> {
+        Self::with_meta_url(root, "sqlite::memory:", layout, config).await
+    }
+
+    async fn with_meta_url<P: AsRef<Path>>(
+        root: P,
+        meta_url: &str,
+        layout: ChunkLayout,
+        config: FileSystemConfig,
     ) -> io::Result<Self> {
         let client = ObjectClient::new(LocalFsBackend::new(root));
-        let meta_handle = create_meta_store_from_url("sqlite::memory:")
+        let meta_handle = create_meta_store_from_url(meta_url)
             .await
             .map_err(io::Error::other)?;
         let meta = meta_handle.store();
@@ -284,6 +295,58 @@ impl LocalClient {
     }
 }
 
+/// A loopback volume for development and tests of the layers built on top of SlayerFS.
+///
+/// Blocks are stored by a [`LocalFsBackend`] and metadata in a SQLite database, both in a
+/// temporary directory removed when the volume is dropped, so no external service nor FUSE
+/// mount is needed. Path-level operations are reached through `Deref` to [`LocalClient`].
+pub struct Volume {
+    client: Arc<LocalClient>,
+    // Dropped last, once nothing uses its content anymore.
+    dir: TempDir,
+}
+
+impl Volume {
+    /// A volume with the default chunk layout, whose operations run as root.
+    pub async fn ephemeral() -> io::Result<Self> {
+        let config = FileSystemConfig::default().with_caller(CallerIdentity::root());
+        Self::ephemeral_with_config(ChunkLayout::default(), config).await
+    }
+
+    pub async fn ephemeral_with_config(
+        layout: ChunkLayout,
+        config: FileSystemConfig,
+    ) -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("slayerfs-").tempdir()?;
+        let meta_url = format!("sqlite://{}?mode=rwc", dir.path().join("meta.db").display());
+        let client =
+            LocalClient::with_meta_url(dir.path().join("data"), &meta_url, layout, config).await?;
+        Ok(Volume {
+            client: Arc::new(client),
+            dir,
+        })
+    }
+
+    /// Directory holding the blocks (`data/`) and the metadata database (`meta.db`).
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// A shared handle on the client of the volume, e.g. to build a `Client` of the std-like
+    /// API. It must not be used once the volume is dropped.
+    pub fn client(&self) -> Arc<LocalClient> {
+        Arc::clone(&self.client)
+    }
+}
+
+impl std::ops::Deref for Volume {
+    type Target = LocalClient;
+
+    fn deref(&self) -> &LocalClient {
+        &self.client
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -329,11 +392,7 @@ mod tests {
     #[tokio::test]
     async fn test_sdk_local_ops_extras() {
         let layout = ChunkLayout::default();
-        let tmp = tempdir().unwrap();
-        let config = FileSystemConfig::default().with_caller(CallerIdentity::root());
-        let cli = LocalClient::new_local_with_config(tmp.path(), layout, config)
-            .await
-            .expect("init LocalClient");
+        let cli = Volume::ephemeral().await.expect("init volume");
 
         cli.mkdir_p("/x/y").await.unwrap();
         cli.create_file("/x/y/a.txt", false).await.unwrap();
@@ -350,12 +409,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_sdk_local_links() {
-        let layout = ChunkLayout::default();
-        let tmp = tempdir().unwrap();
-        let config = FileSystemConfig::default().with_caller(CallerIdentity::root());
-        let cli = LocalClient::new_local_with_config(tmp.path(), layout, config)
-            .await
-            .expect("init LocalClient");
+        let cli = Volume::ephemeral().await.expect("init volume");
 
         cli.mkdir_p("/links").await.unwrap();
         cli.create_file("/links/original.txt", false).await.unwrap();
@@ -420,4 +474,21 @@ mod tests {
         }
         cli.rmdir("/links").await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_ephemeral_volume() {
+        let volume = Volume::ephemeral().await.expect("init volume");
+        let dir = volume.path().to_path_buf();
+        volume.mkdir_p("/a").await.unwrap();
+        volume.create_file("/a/f", false).await.unwrap();
+        volume.write_at("/a/f", 0, b"payload").await.unwrap();
+        assert!(dir.join("meta.db").exists());
+
+        let client = volume.client();
+        assert_eq!(client.read_at("/a/f", 0, 64).await.unwrap(), b"payload");
+        drop(client);
+
+        drop(volume);
+        assert!(!dir.exists());
+    }
 }
@@ -0,0 +1,143 @@
+//! Per-volume usage counters for reporting and charge-back.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operations counted by [`UsageCounters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UsageOp {
+    Read,
+    Write,
+    Create,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Rename,
+    Link,
+    Symlink,
+    SetAttr,
+    Open,
+}
+
+const OP_COUNT: usize = UsageOp::Open as usize + 1;
+
+/// Running totals of the data moved and operations served by a VFS since it was created.
+#[derive(Default)]
+pub(crate) struct UsageCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    ops: [AtomicU64; OP_COUNT],
+}
+
+impl UsageCounters {
+    pub(crate) fn record(&self, op: UsageOp) {
+        self.ops[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_read(&self, bytes: usize) {
+        self.record(UsageOp::Read);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write(&self, bytes: usize) {
+        self.record(UsageOp::Write);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> UsageSnapshot {
+        let op = |op: UsageOp| self.ops[op as usize].load(Ordering::Relaxed);
+        UsageSnapshot {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            ops: OpCounts {
+                read: op(UsageOp::Read),
+                write: op(UsageOp::Write),
+                create: op(UsageOp::Create),
+                mkdir: op(UsageOp::Mkdir),
+                unlink: op(UsageOp::Unlink),
+                rmdir: op(UsageOp::Rmdir),
+                rename: op(UsageOp::Rename),
+                link: op(UsageOp::Link),
+                symlink: op(UsageOp::Symlink),
+                setattr: op(UsageOp::SetAttr),
+                open: op(UsageOp::Open),
+            },
+        }
+    }
+}
+
+/// Number of successful operations of each kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpCounts {
+    pub read: u64,
+    pub write: u64,
+    pub create: u64,
+    pub mkdir: u64,
+    pub unlink: u64,
+    pub rmdir: u64,
+    pub rename: u64,
+    pub link: u64,
+    pub symlink: u64,
+    pub setattr: u64,
+    pub open: u64,
+}
+
+impl OpCounts {
+    /// Sum over all kinds.
+    pub fn total(&self) -> u64 {
+        self.fields().iter().map(|(_, count)| count).sum()
+    }
+
+    /// Names and counts of all kinds, in a fixed order.
+    pub fn fields(&self) -> [(&'static str, u64); OP_COUNT] {
+        [
+            ("read", self.read),
+            ("write", self.write),
+            ("create", self.create),
+            ("mkdir", self.mkdir),
+            ("unlink", self.unlink),
+            ("rmdir", self.rmdir),
+            ("rename", self.rename),
+            ("link", self.link),
+            ("symlink", self.symlink),
+            ("setattr", self.setattr),
+            ("open", self.open),
+        ]
+    }
+
+    fn saturating_sub(&self, earlier: &Self) -> Self {
+        OpCounts {
+            read: self.read.saturating_sub(earlier.read),
+            write: self.write.saturating_sub(earlier.write),
+            create: self.create.saturating_sub(earlier.create),
+            mkdir: self.mkdir.saturating_sub(earlier.mkdir),
+            unlink: self.unlink.saturating_sub(earlier.unlink),
+            rmdir: self.rmdir.saturating_sub(earlier.rmdir),
+            rename: self.rename.saturating_sub(earlier.rename),
+            link: self.link.saturating_sub(earlier.link),
+            symlink: self.symlink.saturating_sub(earlier.symlink),
+            setattr: self.setattr.saturating_sub(earlier.setattr),
+            open: self.open.saturating_sub(earlier.open),
+        }
+    }
+}
+
+/// Usage counters of a VFS at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageSnapshot {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub ops: OpCounts,
+}
+
+impl UsageSnapshot {
+    /// Usage between `earlier` and this snapshot.
+    pub fn since(&self, earlier: &Self) -> Self {
+        UsageSnapshot {
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(earlier.bytes_written),
+            ops: self.ops.saturating_sub(&earlier.ops),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Consistency and durability conformance matrix.
+//!
+//! Every MetaStore/BlockStore combination declares the guarantees it offers and the
+//! shared suite checks each declaration both ways: a declared guarantee must hold, and an
+//! undeclared one must observably not hold, so the table below stays the documentation of
+//! record. A new backend is added by appending a row and declaring its semantics.
+//!
+//! Rows on external services only run when the service is configured:
+//! `SLAYERFS_TEST_ETCD_ENDPOINTS` (comma separated), `SLAYERFS_TEST_REDIS_URL` and
+//! `SLAYERFS_TEST_S3_BUCKET` (with the usual AWS environment). Each check works under a
+//! fresh directory, so they can share a service with earlier runs.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use sea_orm::{ConnectionTrait, Database, Statement};
+use slayerfs::{
+    BlockStore, CacheConfig, ChunkLayout, ClientOptions, Config, DEFAULT_DIR_SHARD_THRESHOLD,
+    DatabaseConfig, DatabaseMetaStore, DatabaseType, EtcdMetaStore, InMemoryBlockStore,
+    LocalFsBackend, MetaClient, MetaStore, MetaStoreFactory, ObjectBlockStore, ObjectClient,
+    RedisMetaStore, S3Backend, S3Config, VFS, create_meta_store_from_url,
+    create_redis_meta_store_from_url,
+};
+
+const ETCD_ENV: &str = "SLAYERFS_TEST_ETCD_ENDPOINTS";
+const REDIS_ENV: &str = "SLAYERFS_TEST_REDIS_URL";
+const S3_ENV: &str = "SLAYERFS_TEST_S3_BUCKET";
+
+/// Guarantees a backend combination promises to its clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Guarantees {
+    /// Renaming over an existing file replaces it in one step, a rename failing part way
+    /// leaves the destination in place.
+    ///
+    /// No combination provides this yet: the VFS removes an existing destination before
+    /// moving the source, so a lookup racing the rename can see neither.
+    atomic_rename: bool,
+    /// Data written through one handle is readable through another before any flush.
+    read_after_write: bool,
+    /// Data and metadata made durable by `fsync` survive reopening the filesystem.
+    fsync_durable: bool,
+}
+
+type Fs<S, M> = VFS<S, MetaClient<M>>;
+type Meta = Arc<DatabaseMetaStore>;
+type Open<S, M> = fn(PathBuf) -> Pin<Box<dyn Future<Output = Mounted<S, M>> + Send>>;
+
+/// A filesystem opened by a row.
+struct Mounted<S, M>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    fs: Fs<S, M>,
+    /// SQLite database holding the metadata, which faults are injected into. Rows without
+    /// one take `atomic_rename` on trust, the rename proved on the others is the VFS's own.
+    sqlite_url: Option<String>,
+}
+
+/// One row of the matrix: how to (re)open a filesystem over state kept in a directory.
+struct Backend<S, M>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    name: &'static str,
+    open: Open<S, M>,
+    guarantees: Guarantees,
+}
+
+fn sqlite_url(dir: &Path) -> String {
+    std::fs::create_dir_all(dir).unwrap();
+    format!("sqlite://{}?mode=rwc", dir.join("meta.db").display())
+}
+
+fn local_store(dir: &Path) -> ObjectBlockStore<LocalFsBackend> {
+    ObjectBlockStore::new(ObjectClient::new(LocalFsBackend::new(dir.join("blocks"))))
+}
+
+async fn s3_store() -> ObjectBlockStore<S3Backend> {
+    let config = S3Config {
+        bucket: std::env::var(S3_ENV).unwrap(),
+        ..Default::default()
+    };
+    let backend = S3Backend::with_config(config).await.unwrap();
+    ObjectBlockStore::new(ObjectClient::new(backend))
+}
+
+async fn open_vfs<S, M>(store: S, meta: M) -> Fs<S, M>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    VFS::new(ChunkLayout::default(), store, meta).await.unwrap()
+}
+
+async fn mount_sqlite<S>(store: S, url: String) -> Mounted<S, Meta>
+where
+    S: BlockStore + Send + Sync + 'static,
+{
+    let meta = create_meta_store_from_url(&url).await.unwrap();
+    Mounted {
+        fs: open_vfs(store, meta.store()).await,
+        sqlite_url: Some(url),
+    }
+}
+
+fn env_config(db_config: DatabaseType) -> Config {
+    Config {
+        database: DatabaseConfig { db_config },
+        cache: CacheConfig::default(),
+        client: ClientOptions::default(),
+    }
+}
+
+fn memory_sqlite_memory_blocks() -> Backend<InMemoryBlockStore, Meta> {
+    Backend {
+        name: "sqlite::memory: + InMemoryBlockStore",
+        open: |_| {
+            Box::pin(async {
+                // The anonymous database can't be reached from a second connection.
+                let meta = create_meta_store_from_url("sqlite::memory:").await.unwrap();
+                Mounted {
+                    fs: open_vfs(InMemoryBlockStore::new(), meta.store()).await,
+                    sqlite_url: None,
+                }
+            })
+        },
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: false,
+        },
+    }
+}
+
+fn file_sqlite_memory_blocks() -> Backend<InMemoryBlockStore, Meta> {
+    Backend {
+        name: "sqlite file + InMemoryBlockStore",
+        open: |dir| Box::pin(mount_sqlite(InMemoryBlockStore::new(), sqlite_url(&dir))),
+        // Metadata survives, but the blocks it points at do not.
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: false,
+        },
+    }
+}
+
+fn file_sqlite_local_blocks() -> Backend<ObjectBlockStore<LocalFsBackend>, Meta> {
+    Backend {
+        name: "sqlite file + ObjectBlockStore<LocalFsBackend>",
+        open: |dir| Box::pin(mount_sqlite(local_store(&dir), sqlite_url(&dir))),
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: true,
+        },
+    }
+}
+
+fn file_sqlite_s3_blocks() -> Option<Backend<ObjectBlockStore<S3Backend>, Meta>> {
+    std::env::var_os(S3_ENV)?;
+    Some(Backend {
+        name: "sqlite file + ObjectBlockStore<S3Backend>",
+        open: |dir| Box::pin(async move { mount_sqlite(s3_store().await, sqlite_url(&dir)).await }),
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: true,
+        },
+    })
+}
+
+fn etcd_local_blocks() -> Option<Backend<ObjectBlockStore<LocalFsBackend>, Arc<EtcdMetaStore>>> {
+    std::env::var_os(ETCD_ENV)?;
+    Some(Backend {
+        name: "etcd + ObjectBlockStore<LocalFsBackend>",
+        open: |dir| {
+            Box::pin(async move {
+                let urls = std::env::var(ETCD_ENV).unwrap();
+                let config = env_config(DatabaseType::Etcd {
+                    urls: urls.split(',').map(str::to_string).collect(),
+                    dir_shards: 0,
+                    dir_shard_threshold: DEFAULT_DIR_SHARD_THRESHOLD,
+                    tls: None,
+                });
+                let meta = MetaStoreFactory::<EtcdMetaStore>::create_from_config(config)
+                    .await
+                    .unwrap();
+                Mounted {
+                    fs: open_vfs(local_store(&dir), meta.store()).await,
+                    sqlite_url: None,
+                }
+            })
+        },
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: true,
+        },
+    })
+}
+
+fn redis_local_blocks() -> Option<Backend<ObjectBlockStore<LocalFsBackend>, Arc<RedisMetaStore>>> {
+    std::env::var_os(REDIS_ENV)?;
+    Some(Backend {
+        name: "redis + ObjectBlockStore<LocalFsBackend>",
+        open: |dir| {
+            Box::pin(async move {
+                let url = std::env::var(REDIS_ENV).unwrap();
+                let meta = create_redis_meta_store_from_url(&url).await.unwrap();
+                Mounted {
+                    fs: open_vfs(local_store(&dir), meta.store()).await,
+                    sqlite_url: None,
+                }
+            })
+        },
+        guarantees: Guarantees {
+            atomic_rename: false,
+            read_after_write: true,
+            fsync_durable: true,
+        },
+    })
+}
+
+/// Make the metadata database at `url` reject any entry named `name` from now on, so a
+/// rename onto `name` fails after the VFS dealt with the existing destination.
+async fn reject_entries_named(url: &str, name: &str) {
+    let db = Database::connect(url).await.unwrap();
+    let backend = db.get_database_backend();
+    let sql = format!(
+        "CREATE TRIGGER reject_{name} BEFORE INSERT ON content_meta \
+         WHEN NEW.entry_name = CAST('{name}' AS BLOB) \
+         BEGIN SELECT RAISE(ABORT, 'injected fault'); END"
+    );
+    db.execute(Statement::from_string(backend, sql))
+        .await
+        .unwrap();
+}
+
+async fn prove_atomic_rename<S, M>(mounted: &Mounted<S, M>, root: &str) -> Option<bool>
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    let url = mounted.sqlite_url.as_deref()?;
+    let fs = &mounted.fs;
+    let (src, dst) = (format!("{root}/rename/src"), format!("{root}/rename/dst"));
+    fs.create_file(&dst).await.unwrap();
+    fs.create_file(&src).await.unwrap();
+    reject_entries_named(url, "dst").await;
+    assert!(
+        fs.rename(&src, &dst).await.is_err(),
+        "the injected fault must fail the rename"
+    );
+    Some(fs.exists(&dst).await && fs.exists(&src).await)
+}
+
+async fn prove_read_after_write<S, M>(fs: &Fs<S, M>, root: &str) -> bool
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    let path = format!("{root}/raw");
+    let ino = fs.create_file(&path).await.unwrap();
+    let attr = fs.stat(&path).await.unwrap();
+    let writer = fs.open(ino, attr.clone(), false, true).await.unwrap();
+    fs.write(writer, 0, b"visible").await.unwrap();
+    let reader = fs.open(ino, attr, true, false).await.unwrap();
+    let data = fs.read(reader, 0, 7).await.unwrap();
+    fs.close(reader).await.unwrap();
+    fs.close(writer).await.unwrap();
+    data == b"visible"
+}
+
+async fn prove_fsync_durable<S, M>(backend: &Backend<S, M>, dir: &Path, root: &str) -> bool
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    let path = format!("{root}/durable");
+    {
+        let fs = (backend.open)(dir.to_path_buf()).await.fs;
+        let ino = fs.create_file(&path).await.unwrap();
+        let attr = fs.stat(&path).await.unwrap();
+        let fh = fs.open(ino, attr, false, true).await.unwrap();
+        fs.write(fh, 0, b"synced").await.unwrap();
+        fs.fsync(fh, false).await.unwrap();
+        // Dropped without close: only what fsync persisted may be relied upon.
+    }
+    let fs = (backend.open)(dir.to_path_buf()).await.fs;
+    let Ok(attr) = fs.stat(&path).await else {
+        return false;
+    };
+    let fh = fs.open(attr.ino, attr, true, false).await.unwrap();
+    let data = fs.read(fh, 0, 6).await.unwrap();
+    data == b"synced"
+}
+
+async fn check<S, M>(backend: Backend<S, M>)
+where
+    S: BlockStore + Send + Sync + 'static,
+    M: MetaStore + Send + Sync + 'static,
+{
+    let dir = tempfile::tempdir().unwrap();
+    let root = format!("/conformance-{}", uuid::Uuid::now_v7());
+    let mounted = (backend.open)(dir.path().join("live")).await;
+    mounted.fs.mkdir_p(&format!("{root}/rename")).await.unwrap();
+    let observed = Guarantees {
+        atomic_rename: prove_atomic_rename(&mounted, &root)
+            .await
+            .unwrap_or(backend.guarantees.atomic_rename),
+        read_after_write: prove_read_after_write(&mounted.fs, &root).await,
+        fsync_durable: prove_fsync_durable(&backend, &dir.path().join("durable"), &root).await,
+    };
+    assert_eq!(
+        observed, backend.guarantees,
+        "{} does not match its declared guarantees",
+        backend.name
+    );
+}
+
+#[tokio::test]
+async fn conformance_sqlite_memory_with_memory_blocks() {
+    check(memory_sqlite_memory_blocks()).await;
+}
+
+#[tokio::test]
+async fn conformance_sqlite_file_with_memory_blocks() {
+    check(file_sqlite_memory_blocks()).await;
+}
+
+#[tokio::test]
+async fn conformance_sqlite_file_with_local_blocks() {
+    check(file_sqlite_local_blocks()).await;
+}
+
+#[tokio::test]
+async fn conformance_sqlite_file_with_s3_blocks() {
+    let Some(backend) = file_sqlite_s3_blocks() else {
+        eprintln!("{S3_ENV} not set, skipping");
+        return;
+    };
+    check(backend).await;
+}
+
+#[tokio::test]
+async fn conformance_etcd_with_local_blocks() {
+    let Some(backend) = etcd_local_blocks() else {
+        eprintln!("{ETCD_ENV} not set, skipping");
+        return;
+    };
+    check(backend).await;
+}
+
+#[tokio::test]
+async fn conformance_redis_with_local_blocks() {
+    let Some(backend) = redis_local_blocks() else {
+        eprintln!("{REDIS_ENV} not set, skipping");
+        return;
+    };
+    check(backend).await;
+}